@@ -0,0 +1,294 @@
+use crate::{disconnect::CloseHandler, output::Output};
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::{
+    core::{connection::ConnectionId, ConnectedPoint, Multiaddr, PeerId},
+    swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters},
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// How often the book is written out, if anything in it changed.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// The most addresses to keep for a peer (the ones that were heard of, or
+// reached, last win).
+const MAX_ADDRS: usize = 8;
+
+// Every peer the node heard of (through mDNS, PEX, rendezvous, ...) or
+// was connected to, with the addresses it may be reached at, when it was
+// last connected and when a dial of it last worked. Unlike the routing
+// table, it doesn't forget the addresses that a dial failed to reach, or
+// the peers that fell out of their bucket, and with --address-book it is
+// kept across runs: one JSON object per peer and line, like
+//   {"peer": "12D3Koo...", "addrs": ["/ip4/10.0.0.2/tcp/4001"],
+//    "via": "mdns", "seen": 1602670000, "dialed": 1602670000}
+// Every dial of a peer (by kademlia, or DIAL) tries the addresses in it
+// too, the ones that worked last first.
+#[derive(Debug)]
+pub struct AddressBook {
+    path: Option<PathBuf>,
+    peers: HashMap<PeerId, Entry>,
+    // Whether there is anything to save
+    changed: bool,
+    timer: Delay,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Entry {
+    // The last one heard of, or reached, first
+    pub addrs: Vec<Multiaddr>,
+    // How the node first heard of the peer
+    pub via: Option<String>,
+    // When the node was last connected to the peer, and when it last
+    // dialed it and got through (in seconds since the epoch)
+    pub seen: Option<u64>,
+    pub dialed: Option<u64>,
+}
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        AddressBook {
+            path: None,
+            peers: HashMap::new(),
+            changed: false,
+            timer: Delay::new(SAVE_INTERVAL),
+        }
+    }
+}
+
+impl AddressBook {
+    // Keep the book in `path` (with --address-book), starting with what
+    // was saved there, if anything was yet. Returns how many peers that
+    // was.
+    pub fn open(&mut self, path: PathBuf) -> io::Result<usize> {
+        let loaded = load(&path)?;
+        let n = loaded.len();
+        self.peers.extend(loaded);
+        self.path = Some(path);
+        Ok(n)
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    // The node heard of `peer` at `addr`, `via` some way of discovery.
+    pub fn add(&mut self, peer: &PeerId, addr: Multiaddr, via: &str) {
+        let entry = self.peers.entry(peer.clone()).or_default();
+        if entry.via.is_none() {
+            entry.via = Some(via.to_string());
+        }
+        if !entry.addrs.contains(&addr) {
+            entry.addrs.insert(0, addr);
+            entry.addrs.truncate(MAX_ADDRS);
+        }
+        self.changed = true;
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<&Entry> {
+        self.peers.get(peer)
+    }
+
+    // Every peer in the book, the last one seen first.
+    pub fn entries(&self) -> Vec<(&PeerId, &Entry)> {
+        let mut entries: Vec<_> = self.peers.iter().collect();
+        entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.seen));
+        entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    // Write the book out, if it is kept in a file. Returns how many peers
+    // were written.
+    pub fn save(&mut self) -> io::Result<Option<usize>> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        save(path, &self.peers)?;
+        self.changed = false;
+        Ok(Some(self.peers.len()))
+    }
+}
+
+impl NetworkBehaviour for AddressBook {
+    type ProtocolsHandler = CloseHandler;
+    type OutEvent = Infallible;
+
+    // It is never told to close anything
+    fn new_handler(&mut self) -> CloseHandler {
+        CloseHandler::default()
+    }
+
+    fn addresses_of_peer(&mut self, peer: &PeerId) -> Vec<Multiaddr> {
+        self.peers
+            .get(peer)
+            .map(|entry| entry.addrs.clone())
+            .unwrap_or_default()
+    }
+
+    fn inject_connected(&mut self, _: &PeerId) {}
+
+    fn inject_disconnected(&mut self, _: &PeerId) {}
+
+    fn inject_connection_established(
+        &mut self,
+        peer: &PeerId,
+        _: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        let now = now();
+        let entry = self.peers.entry(peer.clone()).or_default();
+        entry.seen = Some(now);
+        // The address of a peer that dialed us is where it dialed from,
+        // not where it listens
+        if let ConnectedPoint::Dialer { address } = endpoint {
+            entry.dialed = Some(now);
+            entry.addrs.retain(|addr| addr != address);
+            entry.addrs.insert(0, address.clone());
+            entry.addrs.truncate(MAX_ADDRS);
+            if entry.via.is_none() {
+                entry.via = Some("dial".to_string());
+            }
+        }
+        self.changed = true;
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer: &PeerId,
+        _: &ConnectionId,
+        _: &ConnectedPoint,
+    ) {
+        if let Some(entry) = self.peers.get_mut(peer) {
+            entry.seen = Some(now());
+            self.changed = true;
+        }
+    }
+
+    fn inject_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: Infallible,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<(), Infallible>> {
+        while self.timer.poll_unpin(cx).is_ready() {
+            self.timer = Delay::new(SAVE_INTERVAL);
+            if !self.changed {
+                continue;
+            }
+            if let Err(err) = self.save() {
+                Output::Terminal.error(format!(
+                    "Couldn't save the address book: {}",
+                    err
+                ));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// How long ago `time` (in seconds since the epoch) was.
+pub fn ago(time: u64) -> Duration {
+    Duration::from_secs(now().saturating_sub(time))
+}
+
+// Write `peers` to `path` (through a temporary file, like the peerstore).
+fn save(path: &Path, peers: &HashMap<PeerId, Entry>) -> io::Result<()> {
+    let partial = path.with_extension("partial");
+    let mut file = BufWriter::new(File::create(&partial)?);
+    for (peer, entry) in peers {
+        let line = json!({
+            "peer": peer.to_string(),
+            "addrs": entry.addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+            "via": entry.via,
+            "seen": entry.seen,
+            "dialed": entry.dialed,
+        });
+        writeln!(file, "{}", line)?;
+    }
+    file.flush()?;
+    drop(file);
+    fs::rename(&partial, path)
+}
+
+fn load(path: &Path) -> io::Result<Vec<(PeerId, Entry)>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(Vec::new())
+        }
+        Err(err) => return Err(err),
+    };
+    let mut peers = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let peer = parse_entry(&line).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", n + 1, err),
+            )
+        })?;
+        peers.push(peer);
+    }
+    Ok(peers)
+}
+
+fn parse_entry(line: &str) -> Result<(PeerId, Entry), String> {
+    let line: Value =
+        serde_json::from_str(line).map_err(|err| err.to_string())?;
+    let peer = line["peer"].as_str().ok_or("expected a peer")?;
+    let peer = peer
+        .parse::<PeerId>()
+        .map_err(|_| format!("invalid peer id {:?}", peer))?;
+    let addrs = line["addrs"]
+        .as_array()
+        .ok_or("expected a list of addresses")?
+        .iter()
+        .map(|addr| {
+            let addr = addr.as_str().ok_or("addresses should be text")?;
+            addr.parse::<Multiaddr>()
+                .map_err(|_| format!("invalid address {:?}", addr))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let entry = Entry {
+        addrs,
+        via: line["via"].as_str().map(String::from),
+        seen: line["seen"].as_u64(),
+        dialed: line["dialed"].as_u64(),
+    };
+    Ok((peer, entry))
+}
@@ -0,0 +1,105 @@
+use crate::{namespace::Namespace, seed};
+use clap::ValueEnum;
+use libp2p::{
+    identity::Keypair,
+    kad::{handler::KademliaHandlerIn, kbucket, QueryId},
+    PeerId,
+};
+use std::{collections::HashSet, fmt};
+
+// How many identities are tried for every adversary that is wanted: the
+// closest of them are a thousandth of the key space away from the
+// target, which beats the honest peers of any network that fits in a
+// simulation.
+const TRIES_PER_ADVERSARY: usize = 1000;
+
+// What the adversaries of --adversary do.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdversaryKind {
+    // Take the place of the closest peers of the target, and never give
+    // out a record (or a provider) stored there, or any peer but the
+    // other adversaries, so that lookups of the target end up with
+    // nothing
+    Eclipse,
+}
+
+impl fmt::Display for AdversaryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdversaryKind::Eclipse => f.write_str("eclipse"),
+        }
+    }
+}
+
+// What the target of --target is: a peer id, or else a record key (in
+// the namespace of the nodes, as GET uses it).
+pub fn target_bytes(target: &str, namespace: &Namespace) -> Vec<u8> {
+    match target.parse::<PeerId>() {
+        Ok(peer) => peer.into_bytes(),
+        Err(_) => namespace.key(target).to_vec(),
+    }
+}
+
+// Where `peer` is in the key space, from the target (`target_bytes`).
+pub fn distance(target: &[u8], peer: &PeerId) -> kbucket::Distance {
+    kbucket::Key::new(target.to_vec())
+        .distance(&kbucket::Key::from(peer.clone()))
+}
+
+// Come up with `count` identities that are close to `target` (the
+// closest of many random ones), closest first.
+pub fn grind(target: &[u8], count: usize) -> Vec<Keypair> {
+    let mut keys: Vec<(kbucket::Distance, Keypair)> = (0..count
+        * TRIES_PER_ADVERSARY)
+        .map(|_| {
+            let key = seed::keypair();
+            (distance(target, &PeerId::from(key.public())), key)
+        })
+        .collect();
+    keys.sort_by_key(|(distance, _)| *distance);
+    keys.into_iter().take(count).map(|(_, key)| key).collect()
+}
+
+// A node that works against the others (--adversary). Its `allies` are
+// the other adversaries, which it points lookups to.
+#[derive(Debug, Clone)]
+pub struct Adversary {
+    pub kind: AdversaryKind,
+    pub allies: HashSet<PeerId>,
+}
+
+impl Adversary {
+    // Change `reply`, which is on its way to a peer, the way this kind
+    // of adversary does. Returns whether anything was left out.
+    pub fn tamper(&self, reply: &mut KademliaHandlerIn<QueryId>) -> bool {
+        match self.kind {
+            AdversaryKind::Eclipse => self.eclipse(reply),
+        }
+    }
+
+    fn eclipse(&self, reply: &mut KademliaHandlerIn<QueryId>) -> bool {
+        let (closer_peers, withheld) = match reply {
+            KademliaHandlerIn::FindNodeRes { closer_peers, .. } => {
+                (closer_peers, false)
+            }
+            KademliaHandlerIn::GetProvidersRes {
+                closer_peers,
+                provider_peers,
+                ..
+            } => {
+                let withheld = !provider_peers.is_empty();
+                provider_peers.clear();
+                (closer_peers, withheld)
+            }
+            KademliaHandlerIn::GetRecordRes {
+                record,
+                closer_peers,
+                ..
+            } => (closer_peers, record.take().is_some()),
+            _ => return false,
+        };
+        let before = closer_peers.len();
+        closer_peers.retain(|peer| self.allies.contains(&peer.node_id));
+        withheld || closer_peers.len() < before
+    }
+}
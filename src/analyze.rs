@@ -0,0 +1,114 @@
+use crate::{output::Message, routing};
+use libp2p::{kad::record::Key, PeerId};
+use std::{collections::HashMap, fs, io, path::Path};
+
+// How much more than the average a peer has to be responsible for to
+// count as a hot spot.
+const HOT: f64 = 1.5;
+
+// How the keys spread over the peers (ANALYZE): every key is the
+// responsibility of the `replication` peers closest to it, the way
+// kademlia stores it. In a small network, a few peers that happen to sit
+// close to many keys can end up storing most of the records.
+#[derive(Debug)]
+pub struct Analysis {
+    keys: usize,
+    replication: usize,
+    // The peers (this node among them), with how many keys each is
+    // responsible for, the busiest first
+    load: Vec<(PeerId, usize)>,
+    local: PeerId,
+}
+
+pub fn analyze(
+    keys: &[Key],
+    local: PeerId,
+    mut peers: Vec<PeerId>,
+    replication: usize,
+) -> Analysis {
+    peers.push(local.clone());
+    let mut load: HashMap<PeerId, usize> =
+        peers.iter().map(|peer| (peer.clone(), 0)).collect();
+    for key in keys {
+        let closest = routing::by_distance(key.as_ref(), peers.clone());
+        for (peer, _) in closest.into_iter().take(replication) {
+            *load.entry(peer).or_default() += 1;
+        }
+    }
+    let mut load: Vec<(PeerId, usize)> = load.into_iter().collect();
+    load.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Analysis {
+        keys: keys.len(),
+        replication,
+        load,
+        local,
+    }
+}
+
+impl Analysis {
+    pub fn report(&self) -> Vec<Message> {
+        let peers = self.load.len();
+        let counts: Vec<f64> =
+            self.load.iter().map(|(_, n)| *n as f64).collect();
+        let mean = counts.iter().sum::<f64>() / peers as f64;
+        let stddev =
+            (counts.iter().map(|n| (n - mean).powi(2)).sum::<f64>()
+                / peers as f64)
+                .sqrt();
+        let mut messages = vec![
+            Message::Info(format!(
+                "ANALYZE: {} keys over {} known peers, each on the {} closest",
+                self.keys,
+                peers,
+                self.replication.min(peers)
+            )),
+            Message::Info(format!(
+                "  keys per peer: min {}, max {}, mean {:.1}, stddev {:.1}",
+                self.load.last().map_or(0, |(_, n)| *n),
+                self.load.first().map_or(0, |(_, n)| *n),
+                mean,
+                stddev
+            )),
+        ];
+        if self.replication >= peers {
+            messages.push(Message::Info(
+                "  (there are no more peers than the replication factor, \
+                 so every peer is responsible for every key)"
+                    .to_string(),
+            ));
+        }
+        for (peer, n) in &self.load {
+            let mut notes = Vec::new();
+            if *peer == self.local {
+                notes.push("this node");
+            }
+            if self.keys > 0 && *n as f64 > mean * HOT {
+                notes.push("hot spot");
+            }
+            let share = match self.keys {
+                0 => 0.0,
+                keys => *n as f64 * 100.0 / keys as f64,
+            };
+            let notes = match notes.is_empty() {
+                true => String::new(),
+                false => format!(" ({})", notes.join(", ")),
+            };
+            messages.push(Message::Info(format!(
+                "  {}: {} keys, {:.0}% of them{}",
+                peer, n, share, notes
+            )));
+        }
+        messages
+    }
+}
+
+// The keys in a file, one per line (empty lines, and the ones that start
+// with `#`, don't count).
+pub fn read_keys(path: &Path) -> io::Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
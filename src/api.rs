@@ -0,0 +1,840 @@
+use crate::{
+    auth::{Authenticate, Tokens},
+    behaviour::MyBehavior,
+    cache::{CacheStats, RecordCache},
+    coalesce::Waiter,
+    handle::NodeHandle,
+    handler,
+    latency::{self, Histogram, PeerLatency},
+    namespace::NamespaceUsage,
+    output::{Message, Output},
+    routing::BucketStats,
+    rpc,
+    stats::ConnectionEvents,
+    validate::GcStats,
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    StreamExt,
+};
+use libp2p::{
+    kad::{
+        record::{store::RecordStore, Key},
+        AddProviderOk, GetProvidersOk, PeerRecord, PutRecordOk,
+        QueryResult, QueryStats, Quorum, K_VALUE,
+    },
+    Multiaddr, PeerId, Swarm,
+};
+use serde_json::{json, Value};
+use std::{io, net::SocketAddr};
+use tide::{Body, Request, Response, StatusCode};
+use tide_websockets::WebSocket;
+
+// Something the HTTP server wants the node to do. The node does it, and
+// sends the reply back over the oneshot channel.
+#[derive(Debug)]
+pub enum ApiRequest {
+    GetRecord(Key, oneshot::Sender<ApiReply>),
+    PutRecord(Key, Vec<u8>, oneshot::Sender<ApiReply>),
+    Provide(Key, oneshot::Sender<ApiReply>),
+    GetProviders(Key, oneshot::Sender<ApiReply>),
+    Peers(oneshot::Sender<ApiReply>),
+    Bandwidth(oneshot::Sender<ApiReply>),
+    Health(oneshot::Sender<ApiReply>),
+    Buckets(oneshot::Sender<ApiReply>),
+    Metrics(oneshot::Sender<ApiReply>),
+    // Start pushing events to a websocket client (see `rpc`)
+    Subscribe(mpsc::UnboundedSender<String>),
+}
+
+// What the node sends back to the HTTP server.
+#[derive(Debug)]
+pub enum ApiReply {
+    // A query finished (the behaviour hands over the raw result)
+    Query(QueryResult, QueryStats),
+    // The peers in the routing table, and their addresses
+    Peers(Vec<(PeerId, Vec<Multiaddr>)>),
+    // The bytes in and out, all together and for each peer
+    Bandwidth((u64, u64), Vec<(PeerId, u64, u64)>),
+    // Whether the node is listening, whether it finished bootstrapping,
+    // and how many peers are in its routing table
+    Health(bool, bool, usize),
+    // How full the buckets of the routing table are
+    Buckets(Vec<BucketStats>),
+    // What /metrics shows
+    // With the counters, the expired records collected so far, the
+    // latencies of the peers, and the namespaces of --namespaces
+    Metrics(
+        Vec<BucketStats>,
+        ConnectionEvents,
+        Counters,
+        GcStats,
+        Vec<(PeerId, PeerLatency)>,
+        Vec<NamespaceUsage>,
+    ),
+    // The request failed before a query could even be started
+    Error(String),
+}
+
+// What /metrics counts besides the connections and the store.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counters {
+    // Peers culled for not answering pings
+    pub peers_culled: u64,
+    pub slow_queries: u64,
+    // GETs that joined another (see --coalesce-gets)
+    pub gets_coalesced: u64,
+    // The records in the local store, and how the GETs and PUTs went
+    pub records: usize,
+    pub gets_succeeded: u64,
+    pub gets_failed: u64,
+    pub puts_succeeded: u64,
+    pub puts_failed: u64,
+    // How the cache of --cache-size does, if there is one
+    pub cache: Option<CacheStats>,
+}
+
+// What the HTTP server hands the requests to the node with.
+pub type ApiSender = NodeHandle;
+
+// Start the HTTP server. It only translates HTTP requests into
+// `ApiRequest`s for the node (see `handle_request`), and the replies into
+// JSON responses.
+//
+// Routes:
+//   GET  /records/:key    fetch a record
+//   PUT  /records/:key    store a record (the body is the value)
+//   POST /providers/:key  start providing a key
+//   GET  /providers/:key  find the providers of a key
+//   GET  /peers           list the peers in the routing table
+//   GET  /bandwidth       the bytes sent and received, for each peer
+//   GET  /buckets         how full the buckets of the routing table are
+//   GET  /metrics         the same, as Prometheus gauges
+//   GET  /livez           200 for as long as the node runs
+//   GET  /healthz         200 once the node is ready: it is listening, it
+//                         finished bootstrapping and it has at least
+//                         `min_peers` peers in its routing table (503
+//                         until then, with what is missing)
+//   GET  /rpc             JSON-RPC over a websocket (see `rpc`)
+//
+// With `tokens` (--auth-tokens), every route but /livez and /healthz
+// takes one (see `auth::Authenticate`).
+//
+// There is no gRPC service: tonic needs Tokio 1, and the node runs on
+// async-std. /rpc has the same operations, and pushes the events.
+pub fn serve(
+    addr: SocketAddr,
+    requests: ApiSender,
+    min_peers: usize,
+    tokens: Option<Tokens>,
+) -> io::Result<()> {
+    let mut app = tide::with_state(requests);
+    if let Some(tokens) = tokens {
+        app.with(Authenticate(tokens));
+    }
+    app.at("/records/:key")
+        .get(|req: Request<ApiSender>| async move {
+            let key = key_param(&req)?;
+            Ok(ask(&req, |reply| ApiRequest::GetRecord(key, reply)).await)
+        });
+    app.at("/records/:key").put(
+        |mut req: Request<ApiSender>| async move {
+            let key = key_param(&req)?;
+            let value = req.body_bytes().await?;
+            Ok(ask(&req, |reply| ApiRequest::PutRecord(key, value, reply))
+                .await)
+        },
+    );
+    app.at("/providers/:key")
+        .post(|req: Request<ApiSender>| async move {
+            let key = key_param(&req)?;
+            Ok(ask(&req, |reply| ApiRequest::Provide(key, reply)).await)
+        });
+    app.at("/providers/:key")
+        .get(|req: Request<ApiSender>| async move {
+            let key = key_param(&req)?;
+            Ok(ask(&req, |reply| ApiRequest::GetProviders(key, reply))
+                .await)
+        });
+    app.at("/peers").get(|req: Request<ApiSender>| async move {
+        Ok(ask(&req, ApiRequest::Peers).await)
+    });
+    app.at("/bandwidth")
+        .get(|req: Request<ApiSender>| async move {
+            Ok(ask(&req, ApiRequest::Bandwidth).await)
+        });
+    app.at("/buckets")
+        .get(|req: Request<ApiSender>| async move {
+            Ok(ask(&req, ApiRequest::Buckets).await)
+        });
+    app.at("/metrics")
+        .get(|req: Request<ApiSender>| async move {
+            match node_reply(req.state(), ApiRequest::Metrics).await {
+                Some(ApiReply::Metrics(
+                    buckets,
+                    connections,
+                    counters,
+                    gc,
+                    latencies,
+                    namespaces,
+                )) => {
+                    let mut response = Response::new(StatusCode::Ok);
+                    response.set_body(metrics(
+                        &buckets,
+                        &connections,
+                        &counters,
+                        &gc,
+                        &latencies,
+                        &namespaces,
+                    ));
+                    response.set_content_type("text/plain; version=0.0.4");
+                    Ok(response)
+                }
+                Some(reply) => {
+                    let (status, body) = reply_to_json(reply);
+                    Ok(respond(status, body))
+                }
+                None => Ok(respond(
+                    StatusCode::ServiceUnavailable,
+                    error("node stopped"),
+                )),
+            }
+        });
+    app.at("/livez").get(|req: Request<ApiSender>| async move {
+        let (status, body) =
+            ask_node(req.state(), ApiRequest::Health).await;
+        let status = match status {
+            StatusCode::ServiceUnavailable => status,
+            _ => StatusCode::Ok,
+        };
+        Ok(respond(status, body))
+    });
+    app.at("/healthz")
+        .get(move |req: Request<ApiSender>| async move {
+            let (status, mut body) =
+                ask_node(req.state(), ApiRequest::Health).await;
+            if status != StatusCode::Ok {
+                return Ok(respond(status, body));
+            }
+            let ready = body["listening"] == true
+                && body["bootstrapped"] == true
+                && body["peers"].as_u64().unwrap_or(0) >= min_peers as u64;
+            body["min_peers"] = json!(min_peers);
+            body["ready"] = json!(ready);
+            let status = match ready {
+                true => StatusCode::Ok,
+                false => StatusCode::ServiceUnavailable,
+            };
+            Ok(respond(status, body))
+        });
+    app.at("/rpc").get(WebSocket::new(rpc::handle_socket));
+
+    // Bind first, so that a bad address is reported at startup
+    let listener = async_std::task::block_on(
+        async_std::net::TcpListener::bind(addr),
+    )?;
+    async_std::task::spawn(async move {
+        if let Err(err) = app.listen(listener).await {
+            Output::Terminal
+                .error(format!("api: server stopped: {}", err));
+        }
+    });
+
+    Ok(())
+}
+
+// Get the `:key` part of the url.
+fn key_param(req: &Request<ApiSender>) -> tide::Result<Key> {
+    Ok(Key::new(&req.param("key")?))
+}
+
+// Send a request to the node, wait for its reply, and turn that into a
+// response.
+async fn ask(
+    req: &Request<ApiSender>,
+    request: impl FnOnce(oneshot::Sender<ApiReply>) -> ApiRequest,
+) -> Response {
+    let (status, body) = ask_node(req.state(), request).await;
+    respond(status, body)
+}
+
+// Send a request to the node and wait for its reply, as JSON along with
+// the HTTP status that goes with it.
+pub async fn ask_node(
+    requests: &ApiSender,
+    request: impl FnOnce(oneshot::Sender<ApiReply>) -> ApiRequest,
+) -> (StatusCode, Value) {
+    match node_reply(requests, request).await {
+        Some(reply) => reply_to_json(reply),
+        None => (StatusCode::ServiceUnavailable, error("node stopped")),
+    }
+}
+
+// Send a request to the node and wait for its reply (there is none once
+// the node stops).
+async fn node_reply(
+    requests: &ApiSender,
+    request: impl FnOnce(oneshot::Sender<ApiReply>) -> ApiRequest,
+) -> Option<ApiReply> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    requests.api(request(reply_tx)).await.ok()?;
+    reply_rx.await.ok()
+}
+
+// The buckets in the Prometheus text format: how many peers each one
+// holds, connected or not, and whether a peer is waiting to get in. And
+// what happened to the connections and the expired records, as counters.
+// And the latencies of each peer, as histograms.
+fn metrics(
+    buckets: &[BucketStats],
+    events: &ConnectionEvents,
+    counters: &Counters,
+    gc: &GcStats,
+    latencies: &[(PeerId, PeerLatency)],
+    namespaces: &[NamespaceUsage],
+) -> String {
+    let mut text = String::from(
+        "# HELP nettest_kbucket_entries Peers in a k-bucket.\n\
+         # TYPE nettest_kbucket_entries gauge\n",
+    );
+    for bucket in buckets {
+        for (status, n) in &[
+            ("connected", bucket.connected),
+            ("disconnected", bucket.disconnected),
+        ] {
+            text.push_str(&format!(
+                "nettest_kbucket_entries{{bucket=\"{}\",status=\"{}\"}} {}\n",
+                bucket.index, status, n
+            ));
+        }
+    }
+    text.push_str(
+        "# HELP nettest_kbucket_pending Peers waiting for a place in a \
+         full k-bucket.\n\
+         # TYPE nettest_kbucket_pending gauge\n",
+    );
+    for bucket in buckets {
+        text.push_str(&format!(
+            "nettest_kbucket_pending{{bucket=\"{}\"}} {}\n",
+            bucket.index, bucket.pending as u8
+        ));
+    }
+    text.push_str(&format!(
+        "# HELP nettest_kbucket_size The places in a k-bucket.\n\
+         # TYPE nettest_kbucket_size gauge\n\
+         nettest_kbucket_size {}\n",
+        K_VALUE
+    ));
+    text.push_str(&format!(
+        "# HELP nettest_connections_established_total Connections \
+         established.\n\
+         # TYPE nettest_connections_established_total counter\n\
+         nettest_connections_established_total{{direction=\"inbound\"}} {}\n\
+         nettest_connections_established_total{{direction=\"outbound\"}} {}\n\
+         # HELP nettest_connections_closed_total Connections closed, by \
+         why.\n\
+         # TYPE nettest_connections_closed_total counter\n\
+         nettest_connections_closed_total{{cause=\"io\"}} {}\n\
+         nettest_connections_closed_total{{cause=\"idle\"}} {}\n\
+         nettest_connections_closed_total{{cause=\"handler\"}} {}\n\
+         # HELP nettest_incoming_connection_errors_total Incoming \
+         connections that failed before they were established.\n\
+         # TYPE nettest_incoming_connection_errors_total counter\n\
+         nettest_incoming_connection_errors_total {}\n\
+         # HELP nettest_dials_total Dials of peers.\n\
+         # TYPE nettest_dials_total counter\n\
+         nettest_dials_total {}\n\
+         # HELP nettest_dial_failures_total Addresses that dials couldn't \
+         reach.\n\
+         # TYPE nettest_dial_failures_total counter\n\
+         nettest_dial_failures_total {}\n\
+         # HELP nettest_peers_culled_total Peers taken out of the routing \
+         table for not answering pings.\n\
+         # TYPE nettest_peers_culled_total counter\n\
+         nettest_peers_culled_total {}\n\
+         # HELP nettest_slow_queries_total Queries that took longer than \
+         --slow-query.\n\
+         # TYPE nettest_slow_queries_total counter\n\
+         nettest_slow_queries_total {}\n\
+         # HELP nettest_gets_coalesced_total GETs that waited for a GET of \
+         the same key instead of starting a query (--coalesce-gets).\n\
+         # TYPE nettest_gets_coalesced_total counter\n\
+         nettest_gets_coalesced_total {}\n",
+        events.established_inbound,
+        events.established_outbound,
+        events.closed_io,
+        events.closed_idle,
+        events.closed_handler,
+        events.incoming_errors,
+        events.dials,
+        events.dial_failures,
+        counters.peers_culled,
+        counters.slow_queries,
+        counters.gets_coalesced
+    ));
+    text.push_str(&format!(
+        "# HELP nettest_records Records in the local store.\n\
+         # TYPE nettest_records gauge\n\
+         nettest_records {}\n\
+         # HELP nettest_queries_total GETs and PUTs, by how they went.\n\
+         # TYPE nettest_queries_total counter\n\
+         nettest_queries_total{{kind=\"get\",outcome=\"ok\"}} {}\n\
+         nettest_queries_total{{kind=\"get\",outcome=\"failed\"}} {}\n\
+         nettest_queries_total{{kind=\"put\",outcome=\"ok\"}} {}\n\
+         nettest_queries_total{{kind=\"put\",outcome=\"failed\"}} {}\n",
+        counters.records,
+        counters.gets_succeeded,
+        counters.gets_failed,
+        counters.puts_succeeded,
+        counters.puts_failed
+    ));
+    text.push_str(&format!(
+        "# HELP nettest_store_gc_records_total Expired records removed \
+         from the store.\n\
+         # TYPE nettest_store_gc_records_total counter\n\
+         nettest_store_gc_records_total {}\n\
+         # HELP nettest_store_gc_providers_total Expired provider records \
+         removed from the store.\n\
+         # TYPE nettest_store_gc_providers_total counter\n\
+         nettest_store_gc_providers_total {}\n",
+        gc.records, gc.providers
+    ));
+    for (name, help, of) in [
+        (
+            "nettest_peer_ping_seconds",
+            "Round trip times of pings, by peer.",
+            (|latency: &PeerLatency| &latency.ping)
+                as fn(&PeerLatency) -> &Histogram,
+        ),
+        (
+            "nettest_peer_rpc_seconds",
+            "How long peers took to answer kademlia requests.",
+            |latency| &latency.rpc,
+        ),
+    ] {
+        text.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} histogram\n",
+            name, help, name
+        ));
+        for (peer, latency) in latencies {
+            histogram(&mut text, name, peer, of(latency));
+        }
+    }
+    if let Some(cache) = counters.cache {
+        text.push_str(&format!(
+            "# HELP nettest_cache_hits_total GETs answered from the cache \
+             (--cache-size).\n\
+             # TYPE nettest_cache_hits_total counter\n\
+             nettest_cache_hits_total {}\n\
+             # HELP nettest_cache_misses_total GETs the cache had no fresh \
+             record for.\n\
+             # TYPE nettest_cache_misses_total counter\n\
+             nettest_cache_misses_total {}\n\
+             # HELP nettest_cache_records Records in the cache.\n\
+             # TYPE nettest_cache_records gauge\n\
+             nettest_cache_records {}\n",
+            cache.hits, cache.misses, cache.entries
+        ));
+    }
+    if !namespaces.is_empty() {
+        namespace_metrics(&mut text, namespaces);
+    }
+    text
+}
+
+// The lines of `metrics` for the namespaces of --namespaces.
+fn namespace_metrics(text: &mut String, namespaces: &[NamespaceUsage]) {
+    text.push_str(
+        "# HELP nettest_namespace_records Records of a namespace in the \
+         local store.\n\
+         # TYPE nettest_namespace_records gauge\n",
+    );
+    for usage in namespaces {
+        text.push_str(&format!(
+            "nettest_namespace_records{{namespace=\"{}\"}} {}\n",
+            usage.quota.name, usage.records
+        ));
+    }
+    text.push_str(
+        "# HELP nettest_namespace_bytes Bytes of the values of a \
+         namespace in the local store.\n\
+         # TYPE nettest_namespace_bytes gauge\n",
+    );
+    for usage in namespaces {
+        text.push_str(&format!(
+            "nettest_namespace_bytes{{namespace=\"{}\"}} {}\n",
+            usage.quota.name, usage.bytes
+        ));
+    }
+    text.push_str(
+        "# HELP nettest_namespace_refused_total Records of a namespace \
+         the store refused for being over its quota.\n\
+         # TYPE nettest_namespace_refused_total counter\n",
+    );
+    for usage in namespaces {
+        text.push_str(&format!(
+            "nettest_namespace_refused_total{{namespace=\"{}\"}} {}\n",
+            usage.quota.name, usage.refused
+        ));
+    }
+    text.push_str(
+        "# HELP nettest_namespace_queries_total PUTs and GETs of a \
+         namespace, by how they ended.\n\
+         # TYPE nettest_namespace_queries_total counter\n",
+    );
+    for usage in namespaces {
+        let q = &usage.queries;
+        for (kind, result, n) in [
+            ("put", "ok", q.puts - q.puts_failed),
+            ("put", "failed", q.puts_failed),
+            ("get", "ok", q.gets - q.gets_failed),
+            ("get", "failed", q.gets_failed),
+        ] {
+            text.push_str(&format!(
+                "nettest_namespace_queries_total{{namespace=\"{}\",\
+                 kind=\"{}\",result=\"{}\"}} {}\n",
+                usage.quota.name, kind, result, n
+            ));
+        }
+    }
+}
+
+// The lines of one histogram of `metrics`.
+fn histogram(
+    text: &mut String,
+    name: &str,
+    peer: &PeerId,
+    of: &Histogram,
+) {
+    let mut cumulative = 0;
+    for (i, count) in of.counts.iter().enumerate() {
+        cumulative += count;
+        let le = match latency::BUCKETS_MS.get(i) {
+            Some(ms) => (*ms as f64 / 1000.0).to_string(),
+            None => "+Inf".to_string(),
+        };
+        text.push_str(&format!(
+            "{}_bucket{{peer=\"{}\",le=\"{}\"}} {}\n",
+            name, peer, le, cumulative
+        ));
+    }
+    text.push_str(&format!(
+        "{}_sum{{peer=\"{}\"}} {}\n\
+         {}_count{{peer=\"{}\"}} {}\n",
+        name,
+        peer,
+        of.sum.as_secs_f64(),
+        name,
+        peer,
+        cumulative
+    ));
+}
+
+fn respond(status: StatusCode, body: Value) -> Response {
+    let mut response = Response::new(status);
+    response.set_body(
+        Body::from_json(&body).unwrap_or_else(|_| Body::empty()),
+    );
+    response
+}
+
+fn error(message: impl Into<String>) -> Value {
+    json!({ "error": message.into() })
+}
+
+// Turn a key or value into something printable.
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+pub fn stats_json(stats: &QueryStats) -> Value {
+    json!({
+        "requests": stats.num_requests(),
+        "successes": stats.num_successes(),
+        "failures": stats.num_failures(),
+        "duration_ms": stats.duration().map(|d| d.as_millis() as u64),
+    })
+}
+
+fn reply_to_json(reply: ApiReply) -> (StatusCode, Value) {
+    match reply {
+        ApiReply::Query(result, stats) => query_to_json(result, &stats),
+        ApiReply::Peers(peers) => {
+            let peers: Vec<Value> = peers
+                .into_iter()
+                .map(|(peer_id, addrs)| {
+                    json!({
+                        "peer_id": peer_id.to_string(),
+                        "addresses": addrs
+                            .iter()
+                            .map(|a| a.to_string())
+                            .collect::<Vec<_>>(),
+                    })
+                })
+                .collect();
+            (StatusCode::Ok, json!({ "peers": peers }))
+        }
+        ApiReply::Bandwidth((inbound, outbound), peers) => {
+            let peers: Vec<Value> = peers
+                .into_iter()
+                .map(|(peer_id, inbound, outbound)| {
+                    json!({
+                        "peer_id": peer_id.to_string(),
+                        "inbound": inbound,
+                        "outbound": outbound,
+                    })
+                })
+                .collect();
+            (
+                StatusCode::Ok,
+                json!({
+                    "inbound": inbound,
+                    "outbound": outbound,
+                    "peers": peers,
+                }),
+            )
+        }
+        ApiReply::Health(listening, bootstrapped, peers) => (
+            StatusCode::Ok,
+            json!({
+                "listening": listening,
+                "bootstrapped": bootstrapped,
+                "peers": peers,
+            }),
+        ),
+        ApiReply::Buckets(buckets) => {
+            let buckets: Vec<Value> = buckets
+                .iter()
+                .map(|bucket| {
+                    json!({
+                        "index": bucket.index,
+                        "connected": bucket.connected,
+                        "disconnected": bucket.disconnected,
+                        "pending": bucket.pending,
+                    })
+                })
+                .collect();
+            (
+                StatusCode::Ok,
+                json!({ "size": K_VALUE.get(), "buckets": buckets }),
+            )
+        }
+        ApiReply::Metrics(buckets, ..) => {
+            reply_to_json(ApiReply::Buckets(buckets))
+        }
+        ApiReply::Error(message) => {
+            (StatusCode::InternalServerError, error(message))
+        }
+    }
+}
+
+fn query_to_json(
+    result: QueryResult,
+    stats: &QueryStats,
+) -> (StatusCode, Value) {
+    let stats = stats_json(stats);
+    match result {
+        QueryResult::GetRecord(Ok(ok)) => {
+            let records: Vec<Value> = ok
+                .records
+                .into_iter()
+                .map(|PeerRecord { peer, record }| {
+                    json!({
+                        "value": text(&record.value),
+                        "publisher": record.publisher.map(|p| p.to_string()),
+                        "from": peer.map(|p| p.to_string()),
+                    })
+                })
+                .collect();
+            (
+                StatusCode::Ok,
+                json!({ "records": records, "stats": stats }),
+            )
+        }
+        QueryResult::GetRecord(Err(err)) => {
+            let status = match err {
+                libp2p::kad::GetRecordError::NotFound { .. } => {
+                    StatusCode::NotFound
+                }
+                _ => StatusCode::GatewayTimeout,
+            };
+            (
+                status,
+                json!({ "error": format!("{:?}", err), "stats": stats }),
+            )
+        }
+        QueryResult::PutRecord(Ok(PutRecordOk { key })) => (
+            StatusCode::Ok,
+            json!({ "key": text(key.as_ref()), "stats": stats }),
+        ),
+        QueryResult::StartProviding(Ok(AddProviderOk { key })) => (
+            StatusCode::Ok,
+            json!({ "key": text(key.as_ref()), "stats": stats }),
+        ),
+        QueryResult::GetProviders(Ok(GetProvidersOk {
+            key,
+            providers,
+            ..
+        })) => (
+            StatusCode::Ok,
+            json!({
+                "key": text(key.as_ref()),
+                "providers": providers
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>(),
+                "stats": stats,
+            }),
+        ),
+        // Everything else is a failed (timed out) query
+        other => (
+            StatusCode::GatewayTimeout,
+            json!({ "error": format!("{:?}", other), "stats": stats }),
+        ),
+    }
+}
+
+// Answer a PUT of the api once it is over, by what it told `messages`:
+// the errors, if there were any. (The output of the PUT goes away once
+// it is.)
+async fn reply_with_output(
+    key: Key,
+    messages: mpsc::UnboundedReceiver<Message>,
+    reply: oneshot::Sender<ApiReply>,
+) {
+    let messages: Vec<Message> = messages.collect().await;
+    let errors: Vec<String> = messages
+        .into_iter()
+        .filter_map(|message| match message {
+            Message::Error(error) => Some(error),
+            Message::Info(_) => None,
+        })
+        .collect();
+    let answer = match errors.is_empty() {
+        true => ApiReply::Query(
+            QueryResult::PutRecord(Ok(PutRecordOk { key })),
+            QueryStats::empty(),
+        ),
+        false => ApiReply::Error(errors.join(", ")),
+    };
+    let _ = reply.send(answer);
+}
+
+// Act on an API request, on the node's side. Requests that start a query
+// are answered by the behaviour once the query finishes.
+pub fn handle_request(swarm: &mut Swarm<MyBehavior>, request: ApiRequest) {
+    match request {
+        ApiRequest::GetRecord(key, reply) => {
+            let key = swarm.namespace.key(key);
+            if let Some(answer) = swarm.api_get_cached(&key) {
+                let _ = reply.send(answer);
+                return;
+            }
+            // With --coalesce-gets, a GET of the key that is running
+            // already answers this one too
+            if let Some(id) =
+                swarm.coalescer.running(Waiter::Api, &key, Quorum::One)
+            {
+                swarm.coalescer.join_reply(id, reply);
+                swarm.stats.gets_coalesced += 1;
+                return;
+            }
+            let id = swarm.kademlia.get_record(&key, Quorum::One);
+            swarm.stats.gets_issued += 1;
+            swarm.coalescer.started(Waiter::Api, key, Quorum::One, id);
+            swarm.api_pending.insert(id, reply);
+        }
+        // A PUT of the api is the PUT command, but its result goes back
+        // raw (as with a GET)
+        ApiRequest::PutRecord(name, value, reply) => {
+            let key = swarm.namespace.key(name.clone());
+            let compress = swarm.codec.compress;
+            let version = swarm.next_version(&key);
+            let (output, messages) = Output::channel();
+            match swarm.put_value(
+                key,
+                value,
+                compress,
+                version,
+                Quorum::One,
+                output,
+            ) {
+                Some(id) => {
+                    swarm.pending.remove(&id);
+                    swarm.api_pending.insert(id, reply);
+                }
+                // The record was refused, or it went out in chunks: only
+                // the output hears how that went
+                None => {
+                    async_std::task::spawn(reply_with_output(
+                        name, messages, reply,
+                    ));
+                }
+            }
+        }
+        ApiRequest::Provide(key, reply) => {
+            let key = swarm.namespace.key(key);
+            match swarm.kademlia.start_providing(key) {
+                Ok(id) => {
+                    swarm.api_pending.insert(id, reply);
+                }
+                Err(err) => {
+                    let _ = reply.send(ApiReply::Error(format!(
+                        "failed to store provider record locally: {:?}",
+                        err
+                    )));
+                }
+            }
+        }
+        ApiRequest::GetProviders(key, reply) => {
+            let key = swarm.namespace.key(key);
+            let id = swarm.kademlia.get_providers(key);
+            swarm.api_pending.insert(id, reply);
+        }
+        ApiRequest::Peers(reply) => {
+            let _ = reply.send(ApiReply::Peers(swarm.known_peers()));
+        }
+        ApiRequest::Bandwidth(reply) => {
+            let _ = reply.send(ApiReply::Bandwidth(
+                swarm.bandwidth.total(),
+                swarm.bandwidth.peers(),
+            ));
+        }
+        ApiRequest::Health(reply) => {
+            let _ = reply.send(ApiReply::Health(
+                !handler::listen_addrs(swarm).is_empty(),
+                swarm.bootstrapped,
+                swarm.known_peers().len(),
+            ));
+        }
+        ApiRequest::Buckets(reply) => {
+            let _ = reply.send(ApiReply::Buckets(swarm.buckets()));
+        }
+        ApiRequest::Metrics(reply) => {
+            let _ = reply.send(ApiReply::Metrics(
+                swarm.buckets(),
+                swarm.stats.connection_events,
+                Counters {
+                    peers_culled: swarm.stats.peers_culled,
+                    slow_queries: swarm.stats.slow_queries,
+                    gets_coalesced: swarm.stats.gets_coalesced,
+                    records: swarm.kademlia.store_mut().records().count(),
+                    gets_succeeded: swarm.stats.gets_succeeded,
+                    gets_failed: swarm.stats.gets_failed,
+                    puts_succeeded: swarm.stats.puts_succeeded,
+                    puts_failed: swarm.stats.puts_failed,
+                    cache: swarm.cache.as_ref().map(RecordCache::stats),
+                },
+                swarm.stats.store_gc,
+                swarm
+                    .kademlia
+                    .latencies
+                    .iter()
+                    .map(|(peer, latency)| (peer.clone(), latency.clone()))
+                    .collect(),
+                swarm.namespace_usage(),
+            ));
+        }
+        ApiRequest::Subscribe(events) => swarm.subscribers.add(events),
+    }
+}
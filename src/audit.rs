@@ -0,0 +1,222 @@
+use crate::{behaviour::MyBehaviorWith, output::Output, seed};
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::{
+    kad::{
+        record::{store::RecordStore, Key},
+        GetRecordError, QueryId, QueryResult, Quorum,
+    },
+    swarm::NetworkBehaviour as Behaviour,
+};
+use rand::seq::SliceRandom;
+use serde_json::json;
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt,
+    num::NonZeroUsize,
+    task::Context,
+    time::{Duration, Instant},
+};
+
+// How many of our records a round looks up, unless --audit-sample says
+// otherwise.
+pub const DEFAULT_SAMPLE: usize = 10;
+
+// How many rounds are kept, for AUDIT.
+const HISTORY: usize = 100;
+
+// What --audit-interval, --audit-sample and --audit-quorum ask for.
+#[derive(Debug, Clone, Copy)]
+pub struct AuditConfig {
+    pub interval: Duration,
+    pub sample: usize,
+    // How many other peers have to hold a record for it to count
+    pub quorum: usize,
+}
+
+// Checks how durable our records are: every so often it looks a sample
+// of the records this node published up in the network, and counts how
+// many of them other peers still hold (at least `quorum` copies).
+// The copy in our own store doesn't count, since it is always there.
+#[derive(Debug)]
+pub struct Auditor {
+    config: AuditConfig,
+    timer: Delay,
+    // The lookups of the current round, and what they found so far
+    queries: HashSet<QueryId>,
+    round: Option<Round>,
+    // The finished rounds, oldest first
+    history: VecDeque<Round>,
+}
+
+// A round of lookups, and how it went.
+#[derive(Debug, Clone)]
+pub struct Round {
+    pub started: Instant,
+    pub sampled: usize,
+    pub available: usize,
+}
+
+impl Round {
+    pub fn percent(&self) -> f64 {
+        match self.sampled {
+            0 => 100.0,
+            n => 100.0 * self.available as f64 / n as f64,
+        }
+    }
+}
+
+impl fmt::Display for Round {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} records available ({:.0}%)",
+            self.available,
+            self.sampled,
+            self.percent()
+        )
+    }
+}
+
+impl Auditor {
+    pub fn new(config: AuditConfig) -> Self {
+        Auditor {
+            config,
+            timer: Delay::new(config.interval),
+            queries: HashSet::new(),
+            round: None,
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> &AuditConfig {
+        &self.config
+    }
+
+    // Whether it is time for the next round (and the last one is over).
+    // The timer wakes the task once it is.
+    pub fn due(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.timer.poll_unpin(cx).is_pending() {
+            return false;
+        }
+        self.timer = Delay::new(self.config.interval);
+        let _ = self.timer.poll_unpin(cx);
+        self.round.is_none()
+    }
+
+    // Start a round of `queries`.
+    pub fn start(&mut self, queries: Vec<QueryId>) {
+        self.round = match queries.is_empty() {
+            true => None,
+            false => Some(Round {
+                started: Instant::now(),
+                sampled: queries.len(),
+                available: 0,
+            }),
+        };
+        self.queries = queries.into_iter().collect();
+    }
+
+    pub fn owns(&self, query: &QueryId) -> bool {
+        self.queries.contains(query)
+    }
+
+    // Count the result of one of the lookups. Once the last one of the
+    // round is in, this returns the round.
+    pub fn finish(
+        &mut self,
+        query: QueryId,
+        result: &QueryResult,
+    ) -> Option<Round> {
+        if !self.queries.remove(&query) {
+            return None;
+        }
+        let records = match result {
+            QueryResult::GetRecord(Ok(ok)) => &ok.records[..],
+            QueryResult::GetRecord(Err(
+                GetRecordError::QuorumFailed { records, .. },
+            )) => &records[..],
+            _ => &[],
+        };
+        let copies = records.iter().filter(|r| r.peer.is_some()).count();
+        let round = self.round.as_mut()?;
+        if copies >= self.config.quorum {
+            round.available += 1;
+        }
+        if !self.queries.is_empty() {
+            return None;
+        }
+        let round = self.round.take()?;
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(round.clone());
+        Some(round)
+    }
+
+    // The finished rounds, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &Round> {
+        self.history.iter()
+    }
+}
+
+impl<B: Behaviour> MyBehaviorWith<B> {
+    // Look a sample of our records up (with --audit-interval), and return
+    // how many.
+    pub fn audit(&mut self) -> usize {
+        let config = match &self.auditor {
+            Some(auditor) => *auditor.config(),
+            None => return 0,
+        };
+        let local_peer_id = &self.local_peer_id;
+        let keys: Vec<Key> = self
+            .kademlia
+            .store_mut()
+            .records()
+            .filter(|r| r.publisher.as_ref() == Some(local_peer_id))
+            .map(|r| r.key.clone())
+            .collect();
+        // Our own copy counts toward the quorum too
+        let quorum = NonZeroUsize::new(config.quorum + 1)
+            .expect("one more than something is never zero");
+        let queries: Vec<QueryId> = keys
+            .choose_multiple(&mut seed::rng(), config.sample)
+            .map(|key| self.kademlia.get_record(key, Quorum::N(quorum)))
+            .collect();
+        let count = queries.len();
+        if let Some(auditor) = &mut self.auditor {
+            auditor.start(queries);
+        }
+        count
+    }
+
+    // Say how a round of the auditor went.
+    fn report_audit(&mut self, round: &Round) {
+        Output::Terminal.info(format!("audit: {}", round));
+        self.subscribers.notify(
+            "audit_finished",
+            json!({
+                "sampled": round.sampled,
+                "available": round.available,
+                "percent": round.percent(),
+            }),
+        );
+    }
+
+    // Count the result of `id`, if it is a lookup of the auditor.
+    // Returns whether it was.
+    pub fn finish_audit_lookup(
+        &mut self,
+        id: QueryId,
+        result: &QueryResult,
+    ) -> bool {
+        let auditor = match self.auditor.as_mut().filter(|a| a.owns(&id)) {
+            Some(auditor) => auditor,
+            None => return false,
+        };
+        if let Some(round) = auditor.finish(id, result) {
+            self.report_audit(&round);
+        }
+        true
+    }
+}
@@ -0,0 +1,172 @@
+use crate::{api::ApiSender, command::Aliases};
+use serde_json::json;
+use std::{
+    collections::HashMap, fmt, fs, path::Path, str::FromStr, sync::Arc,
+};
+use tide::{
+    http::Method, utils::async_trait, Middleware, Next, Request, Response,
+    StatusCode,
+};
+
+// What a token lets its holder do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    // Look, but not touch: GETs, the routing table, /metrics and the
+    // like
+    Read,
+    // Anything, PUTs and dials and bans included
+    Write,
+}
+
+impl FromStr for Access {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "read" | "read-only" => Ok(Access::Read),
+            "write" | "read-write" => Ok(Access::Write),
+            _ => Err(format!("expected `read` or `write`, not {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read-only"),
+            Access::Write => write!(f, "read-write"),
+        }
+    }
+}
+
+// The tokens of --auth-tokens, which the HTTP api and the control socket
+// take instead of letting anyone in. A file of lines like
+//   3f9c0a7d1e  write
+//   metrics-b8  read
+// (empty lines, and the ones that start with `#`, don't count).
+//
+// The api takes no client certificates: it is plain HTTP, so checking
+// them (mTLS) is up to a TLS proxy in front of it.
+#[derive(Debug, Clone, Default)]
+pub struct Tokens(Arc<HashMap<String, Access>>);
+
+impl Tokens {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        let mut tokens = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let at = |err: String| {
+                format!("{}:{}: {}", path.display(), i + 1, err)
+            };
+            let mut words = line.split_whitespace();
+            let (token, access) = match (words.next(), words.next()) {
+                (Some(token), Some(access)) => (token, access),
+                _ => {
+                    return Err(at("expected a token and its access, \
+                                   like `TOKEN read`"
+                        .to_string()))
+                }
+            };
+            if words.next().is_some() {
+                return Err(at(
+                    "expected a token and its access only".to_string()
+                ));
+            }
+            let access = access.parse().map_err(at)?;
+            if tokens.insert(token.to_string(), access).is_some() {
+                return Err(at(format!("{:?} comes twice", token)));
+            }
+        }
+        if tokens.is_empty() {
+            return Err(format!("{}: no tokens", path.display()));
+        }
+        Ok(Tokens(Arc::new(tokens)))
+    }
+
+    // What `token` lets its holder do, if it is one of ours at all.
+    pub fn access(&self, token: &str) -> Option<Access> {
+        // Compared to every token the whole way through, so how long a
+        // refusal takes doesn't tell how much of a token was right
+        self.0
+            .iter()
+            .filter(|(known, _)| same(known.as_bytes(), token.as_bytes()))
+            .map(|(_, access)| *access)
+            .max()
+    }
+}
+
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Whether a command only looks. Without write access, the control socket
+// only takes these, and only the commands and built-in aliases themselves
+// (the node's own aliases may stand for anything).
+pub fn allows(access: Access, line: &str) -> Result<(), String> {
+    if access == Access::Write {
+        return Ok(());
+    }
+    let line = line.strip_prefix("TIME ").unwrap_or(line);
+    match Aliases::default().parse(line) {
+        Ok(command) if command.is_read_only() => Ok(()),
+        Ok(_) => Err("this token is read-only".to_string()),
+        Err(err) => Err(format!("read-only token: {}", err)),
+    }
+}
+
+// Checks the `Authorization: Bearer TOKEN` of every request to the HTTP
+// api, but the ones of /livez and /healthz (which the probes of
+// orchestrators make, and which tell nothing but whether the node is up).
+// Requests that change anything (PUT and POST) take write access; the
+// access of the rest is left in the request, for /rpc to tell which
+// methods it may call.
+pub struct Authenticate(pub Tokens);
+
+#[async_trait]
+impl Middleware<ApiSender> for Authenticate {
+    async fn handle(
+        &self,
+        mut req: Request<ApiSender>,
+        next: Next<'_, ApiSender>,
+    ) -> tide::Result {
+        if matches!(req.url().path(), "/livez" | "/healthz") {
+            return Ok(next.run(req).await);
+        }
+        let token = req
+            .header("Authorization")
+            .and_then(|values| {
+                values.last().as_str().strip_prefix("Bearer ")
+            })
+            .map(str::trim);
+        let access = match token.and_then(|token| self.0.access(token)) {
+            Some(access) => access,
+            None => {
+                return Ok(refuse(
+                    StatusCode::Unauthorized,
+                    "missing or unknown token (see --auth-tokens)",
+                ))
+            }
+        };
+        let writes = !matches!(req.method(), Method::Get | Method::Head);
+        if writes && access < Access::Write {
+            return Ok(refuse(
+                StatusCode::Forbidden,
+                "this token is read-only",
+            ));
+        }
+        req.set_ext(access);
+        Ok(next.run(req).await)
+    }
+}
+
+fn refuse(status: StatusCode, message: &str) -> Response {
+    let mut response = Response::new(status);
+    response.set_body(json!({ "error": message }));
+    response
+}
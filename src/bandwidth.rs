@@ -0,0 +1,208 @@
+use libp2p::{
+    core::muxing::{StreamMuxer, StreamMuxerBox, StreamMuxerEvent},
+    PeerId,
+};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+// How many bytes went to and came from one peer, over all of its
+// connections.
+#[derive(Debug, Default)]
+pub struct Counters {
+    inbound: AtomicU64,
+    outbound: AtomicU64,
+}
+
+impl Counters {
+    pub fn inbound(&self) -> u64 {
+        self.inbound.load(Ordering::Relaxed)
+    }
+
+    pub fn outbound(&self) -> u64 {
+        self.outbound.load(Ordering::Relaxed)
+    }
+}
+
+// The traffic of every peer we have been connected to. This counts what
+// the protocols (kademlia, FETCH and the others) send and receive, so
+// it leaves out the encryption and multiplexing around that.
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    peers: Mutex<HashMap<PeerId, Arc<Counters>>>,
+}
+
+// The transport counts the bytes, and the behaviour reports them, so
+// the counters are shared.
+pub type SharedBandwidth = Arc<Bandwidth>;
+
+impl Bandwidth {
+    // Count the traffic of a freshly authenticated connection to `peer`.
+    pub fn count(
+        &self,
+        peer: &PeerId,
+        muxer: StreamMuxerBox,
+    ) -> StreamMuxerBox {
+        let counters = self
+            .peers
+            .lock()
+            .unwrap()
+            .entry(peer.clone())
+            .or_default()
+            .clone();
+        StreamMuxerBox::new(CountingMuxer {
+            inner: muxer,
+            counters,
+        })
+    }
+
+    // Every peer's bytes in and out, the busiest peers first.
+    pub fn peers(&self) -> Vec<(PeerId, u64, u64)> {
+        let mut peers: Vec<_> = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(peer, c)| (peer.clone(), c.inbound(), c.outbound()))
+            .collect();
+        peers.sort_by_key(|(_, inbound, outbound)| {
+            std::cmp::Reverse(inbound + outbound)
+        });
+        peers
+    }
+
+    // The bytes in and out of one peer, all together.
+    pub fn bytes(&self, peer: &PeerId) -> u64 {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(peer)
+            .map_or(0, |c| c.inbound() + c.outbound())
+    }
+
+    // The bytes in and out, all together.
+    pub fn total(&self) -> (u64, u64) {
+        self.peers.lock().unwrap().values().fold(
+            (0, 0),
+            |(inbound, outbound), c| {
+                (inbound + c.inbound(), outbound + c.outbound())
+            },
+        )
+    }
+}
+
+// A muxer that counts what goes through its substreams.
+struct CountingMuxer {
+    inner: StreamMuxerBox,
+    counters: Arc<Counters>,
+}
+
+impl StreamMuxer for CountingMuxer {
+    type Substream = <StreamMuxerBox as StreamMuxer>::Substream;
+    type OutboundSubstream =
+        <StreamMuxerBox as StreamMuxer>::OutboundSubstream;
+    type Error = io::Error;
+
+    fn poll_event(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent<Self::Substream>, io::Error>> {
+        self.inner.poll_event(cx)
+    }
+
+    fn open_outbound(&self) -> Self::OutboundSubstream {
+        self.inner.open_outbound()
+    }
+
+    fn poll_outbound(
+        &self,
+        cx: &mut Context<'_>,
+        s: &mut Self::OutboundSubstream,
+    ) -> Poll<Result<Self::Substream, io::Error>> {
+        self.inner.poll_outbound(cx, s)
+    }
+
+    fn destroy_outbound(&self, s: Self::OutboundSubstream) {
+        self.inner.destroy_outbound(s)
+    }
+
+    fn read_substream(
+        &self,
+        cx: &mut Context<'_>,
+        s: &mut Self::Substream,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let read = self.inner.read_substream(cx, s, buf);
+        if let Poll::Ready(Ok(n)) = read {
+            self.counters.inbound.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        read
+    }
+
+    fn write_substream(
+        &self,
+        cx: &mut Context<'_>,
+        s: &mut Self::Substream,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let written = self.inner.write_substream(cx, s, buf);
+        if let Poll::Ready(Ok(n)) = written {
+            self.counters
+                .outbound
+                .fetch_add(n as u64, Ordering::Relaxed);
+        }
+        written
+    }
+
+    fn flush_substream(
+        &self,
+        cx: &mut Context<'_>,
+        s: &mut Self::Substream,
+    ) -> Poll<Result<(), io::Error>> {
+        self.inner.flush_substream(cx, s)
+    }
+
+    fn shutdown_substream(
+        &self,
+        cx: &mut Context<'_>,
+        s: &mut Self::Substream,
+    ) -> Poll<Result<(), io::Error>> {
+        self.inner.shutdown_substream(cx, s)
+    }
+
+    fn destroy_substream(&self, s: Self::Substream) {
+        self.inner.destroy_substream(s)
+    }
+
+    fn close(&self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.inner.close(cx)
+    }
+
+    fn flush_all(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        self.inner.flush_all(cx)
+    }
+}
+
+// Print a number of bytes the way people read them.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
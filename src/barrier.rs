@@ -0,0 +1,124 @@
+use crate::output::Output;
+use futures::FutureExt;
+use futures_timer::Delay;
+use std::{
+    fmt,
+    task::Context,
+    time::{Duration, Instant},
+};
+
+// How long WAIT_PEERS and WAIT_BOOTSTRAP wait, unless told otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// What a barrier waits for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    // This many peers in the routing table
+    Peers(usize),
+    // A bootstrap that went all the way through
+    Bootstrap,
+}
+
+impl Condition {
+    fn holds(&self, peers: usize, bootstrapped: bool) -> bool {
+        match self {
+            Condition::Peers(n) => peers >= *n,
+            Condition::Bootstrap => bootstrapped,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Peers(_) => write!(f, "WAIT_PEERS"),
+            Condition::Bootstrap => write!(f, "WAIT_BOOTSTRAP"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Barrier {
+    condition: Condition,
+    started: Instant,
+    timeout: Duration,
+    timer: Delay,
+    output: Output,
+}
+
+// The WAIT_PEERS and WAIT_BOOTSTRAP that are waiting. While there are
+// any, the node reads no more commands from the terminal, so that a
+// script doesn't get ahead of the network (like PUTs into an empty
+// routing table). Each of them ends once its condition holds, or it
+// times out, which it reports as an error.
+#[derive(Debug, Default)]
+pub struct Barriers {
+    barriers: Vec<Barrier>,
+}
+
+impl Barriers {
+    pub fn wait(
+        &mut self,
+        condition: Condition,
+        timeout: Duration,
+        output: Output,
+    ) {
+        self.barriers.push(Barrier {
+            condition,
+            started: Instant::now(),
+            timeout,
+            timer: Delay::new(timeout),
+            output,
+        });
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        !self.barriers.is_empty()
+    }
+
+    // Release the barriers whose condition holds now (with `peers` in
+    // the routing table), and the ones that timed out. Returns whether
+    // any were.
+    pub fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        peers: usize,
+        bootstrapped: bool,
+    ) -> bool {
+        let before = self.barriers.len();
+        self.barriers.retain_mut(|barrier| {
+            let condition = barrier.condition;
+            if condition.holds(peers, bootstrapped) {
+                let took = barrier.started.elapsed();
+                barrier.output.info(match condition {
+                    Condition::Peers(_) => format!(
+                        "{}: {} peers in the routing table after {:.1?}",
+                        condition, peers, took
+                    ),
+                    Condition::Bootstrap => {
+                        format!(
+                            "{}: bootstrapped after {:.1?}",
+                            condition, took
+                        )
+                    }
+                });
+                return false;
+            }
+            if barrier.timer.poll_unpin(cx).is_pending() {
+                return true;
+            }
+            barrier.output.error(match condition {
+                Condition::Peers(n) => format!(
+                    "{}: timed out after {:.1?}, with {} of {} peers",
+                    condition, barrier.timeout, peers, n
+                ),
+                Condition::Bootstrap => format!(
+                    "{}: timed out after {:.1?}, not bootstrapped",
+                    condition, barrier.timeout
+                ),
+            });
+            false
+        });
+        self.barriers.len() < before
+    }
+}
@@ -0,0 +1,189 @@
+use crate::output::{Message, Output};
+use libp2p::kad::QueryId;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+// How a key of a batch went: what it found (or stored), or why it
+// didn't, and how long its query took (if it asked any peers).
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub ok: bool,
+    pub detail: String,
+    pub took: Option<Duration>,
+}
+
+impl Status {
+    fn took(&self) -> String {
+        match self.took {
+            Some(took) => format!("{:.1?}", took),
+            None => "no peers asked".to_string(),
+        }
+    }
+}
+
+// The queries of an MGET (or MPUT), which all run at the same time, and
+// get reported together once they are all done, like
+//   MGET: 3 keys in 41.2ms (2 ok, 1 failed)
+//     a: "1" (no peers asked)
+//     b: "2" (40.9ms)
+//     c: not found (38.3ms)
+#[derive(Debug)]
+struct Batch {
+    command: &'static str,
+    started: Instant,
+    keys: Vec<(String, Option<Status>)>,
+    left: usize,
+    output: Output,
+}
+
+impl Batch {
+    fn report(&self) -> Vec<Message> {
+        let ok = self
+            .keys
+            .iter()
+            .filter(|(_, status)| status.as_ref().is_some_and(|s| s.ok))
+            .count();
+        let mut messages = vec![Message::Info(format!(
+            "{}: {} keys in {:.1?} ({} ok, {} failed)",
+            self.command,
+            self.keys.len(),
+            self.started.elapsed(),
+            ok,
+            self.keys.len() - ok
+        ))];
+        for (key, status) in &self.keys {
+            messages.push(match status {
+                Some(status) if status.ok => Message::Info(format!(
+                    "  {}: {} ({})",
+                    key,
+                    status.detail,
+                    status.took()
+                )),
+                Some(status) => Message::Error(format!(
+                    "  {}: {} ({})",
+                    key,
+                    status.detail,
+                    status.took()
+                )),
+                None => Message::Error(format!("  {}: no result", key)),
+            });
+        }
+        messages
+    }
+}
+
+// The batches that are running.
+#[derive(Debug, Default)]
+pub struct Batches {
+    batches: HashMap<u64, Batch>,
+    // Which key of which batch each query is for
+    queries: HashMap<QueryId, (u64, usize)>,
+    next: u64,
+}
+
+impl Batches {
+    // Start a batch of `command`, for `output`. Its keys are added with
+    // `add` (or `skip`), and then it is `close`d.
+    pub fn start(&mut self, command: &'static str, output: Output) -> u64 {
+        let id = self.next;
+        self.next += 1;
+        self.batches.insert(
+            id,
+            Batch {
+                command,
+                started: Instant::now(),
+                keys: Vec::new(),
+                left: 0,
+                output,
+            },
+        );
+        id
+    }
+
+    // Add `key`, which `query` looks up (or stores).
+    pub fn add(&mut self, batch: u64, key: String, query: QueryId) {
+        if let Some(b) = self.batches.get_mut(&batch) {
+            self.queries.insert(query, (batch, b.keys.len()));
+            b.keys.push((key, None));
+            b.left += 1;
+        }
+    }
+
+    // Add `key`, which didn't get a query, for `why`.
+    pub fn skip(&mut self, batch: u64, key: String, why: String) {
+        if let Some(b) = self.batches.get_mut(&batch) {
+            let status = Status {
+                ok: false,
+                detail: why,
+                took: None,
+            };
+            b.keys.push((key, Some(status)));
+        }
+    }
+
+    // All the keys are in. A batch whose queries are all done (because
+    // it has none) is reported right away.
+    pub fn close(&mut self, batch: u64) {
+        if self.batches.get(&batch).is_some_and(|b| b.left == 0) {
+            self.report(batch);
+        }
+    }
+
+    pub fn owns(&self, query: &QueryId) -> bool {
+        self.queries.contains_key(query)
+    }
+
+    // `query` is done. Once it was the last of its batch, that gets
+    // reported.
+    pub fn finish(&mut self, query: &QueryId, status: Status) {
+        let (batch, i) = match self.queries.remove(query) {
+            Some(key) => key,
+            None => return,
+        };
+        let b = match self.batches.get_mut(&batch) {
+            Some(b) => b,
+            None => return,
+        };
+        b.keys[i].1 = Some(status);
+        b.left -= 1;
+        if b.left == 0 {
+            self.report(batch);
+        }
+    }
+
+    fn report(&mut self, batch: u64) {
+        if let Some(b) = self.batches.remove(&batch) {
+            b.output.show_all(b.report());
+        }
+    }
+}
+
+// The records of an MPUT file: a key and its value on every line, like
+// `foo some value` (lines that are empty, or start with `#`, don't
+// count).
+pub fn read_records(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let text = fs::read_to_string(path)?;
+    let mut records = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((key, value)) => {
+                records.push((key.to_string(), value.trim().to_string()))
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: expected a key and a value", i + 1),
+                ))
+            }
+        }
+    }
+    Ok(records)
+}
@@ -0,0 +1,164 @@
+use crate::bootstrap;
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::{
+    kad::{record::Key, QueryId},
+    multiaddr::Protocol,
+    Multiaddr, PeerId,
+};
+use serde_json::{json, Value};
+use std::{collections::HashSet, task::Context, time::Duration};
+
+// How often a beacon publishes the peers it knows, unless
+// --beacon-interval says otherwise.
+pub const DEFAULT_INTERVAL: &str = "30s";
+
+// The most peers a beacon lists.
+const MAX_PEERS: usize = 64;
+
+// How long to wait before trying again, while there is nothing to
+// publish (or fetch) yet.
+const RETRY: Duration = Duration::from_secs(1);
+
+// The well-known key the beacons publish under (outside of any
+// namespace, so that every node finds it).
+pub fn key() -> Key {
+    Key::new(&"/nettest/beacon")
+}
+
+// What a beacon publishes: some of the peers it knows, with an address
+// each. The value is JSON, like
+//   {"peers": ["/ip4/1.2.3.4/tcp/4001/p2p/12D3Koo...", ...]}
+pub fn to_value(peers: &[(PeerId, Multiaddr)]) -> Vec<u8> {
+    let peers: Vec<String> = peers
+        .iter()
+        .take(MAX_PEERS)
+        .map(|(peer, addr)| {
+            addr.clone()
+                .with(Protocol::P2p(peer.clone().into()))
+                .to_string()
+        })
+        .collect();
+    json!({ "peers": peers }).to_string().into_bytes()
+}
+
+pub fn from_value(
+    value: &[u8],
+) -> Result<Vec<(PeerId, Multiaddr)>, String> {
+    let value: Value = serde_json::from_slice(value)
+        .map_err(|err| format!("not JSON: {}", err))?;
+    value["peers"]
+        .as_array()
+        .ok_or("there are no peers")?
+        .iter()
+        .map(|peer| {
+            peer.as_str()
+                .ok_or_else(|| format!("{} isn't an address", peer))
+                .and_then(bootstrap::parse_peer_addr)
+        })
+        .collect()
+}
+
+// Publishes the peers that the node knows under the beacon key every so
+// often (--beacon), for the nodes that only know the beacon to join the
+// network through: a bootstrap that goes through the record path of the
+// dht itself.
+#[derive(Debug)]
+pub struct Beacon {
+    interval: Duration,
+    timer: Delay,
+    // The puts that haven't finished
+    queries: HashSet<QueryId>,
+}
+
+impl Beacon {
+    pub fn new(interval: Duration) -> Self {
+        Beacon {
+            interval,
+            timer: Delay::new(RETRY),
+            queries: HashSet::new(),
+        }
+    }
+
+    // Whether it is time to publish. The timer wakes the task once it
+    // is.
+    pub fn due(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.timer.poll_unpin(cx).is_pending() {
+            return false;
+        }
+        self.timer = Delay::new(self.interval);
+        let _ = self.timer.poll_unpin(cx);
+        true
+    }
+
+    // There was nobody to list: try again shortly, instead of after a
+    // whole interval.
+    pub fn retry_soon(&mut self, cx: &mut Context<'_>) {
+        self.timer = Delay::new(RETRY);
+        let _ = self.timer.poll_unpin(cx);
+    }
+
+    pub fn add_query(&mut self, query: QueryId) {
+        self.queries.insert(query);
+    }
+
+    // Whether `query` was one of the puts (it isn't anymore, after).
+    pub fn finish(&mut self, query: &QueryId) -> bool {
+        self.queries.remove(query)
+    }
+}
+
+// Joins the network through a beacon (--join-beacon): the node only
+// knows the beacon, fetches the record it publishes, and bootstraps from
+// the peers it lists. Until that works (the beacon may not know anybody
+// yet), it tries again.
+#[derive(Debug)]
+pub struct BeaconJoin {
+    beacon: (PeerId, Multiaddr),
+    timer: Delay,
+    query: Option<QueryId>,
+    joined: bool,
+}
+
+impl BeaconJoin {
+    pub fn new(beacon: (PeerId, Multiaddr)) -> Self {
+        BeaconJoin {
+            beacon,
+            timer: Delay::new(Duration::ZERO),
+            query: None,
+            joined: false,
+        }
+    }
+
+    pub fn beacon(&self) -> &(PeerId, Multiaddr) {
+        &self.beacon
+    }
+
+    // Whether it is time to fetch the beacon record: not once the node
+    // joined, or while a fetch is running.
+    pub fn due(&mut self, cx: &mut Context<'_>) -> bool {
+        !self.joined
+            && self.query.is_none()
+            && self.timer.poll_unpin(cx).is_ready()
+    }
+
+    pub fn started(&mut self, query: QueryId) {
+        self.query = Some(query);
+    }
+
+    // Whether `query` is the fetch of the beacon record. A fetch that
+    // didn't work is tried again in a while.
+    pub fn finish(&mut self, query: &QueryId) -> bool {
+        if self.query.as_ref() != Some(query) {
+            return false;
+        }
+        self.query = None;
+        self.timer = Delay::new(RETRY);
+        true
+    }
+
+    // The node has the peers of the beacon now: no more fetches.
+    pub fn joined(&mut self) {
+        self.joined = true;
+    }
+}
@@ -0,0 +1,3863 @@
+use crate::{
+    addrbook::AddressBook,
+    analyze::{self, Analysis},
+    api::{self, ApiReply},
+    audit::{AuditConfig, Auditor},
+    bandwidth::SharedBandwidth,
+    barrier::Barriers,
+    batch::{Batches, Status},
+    beacon::{self, Beacon, BeaconJoin},
+    cache::RecordCache,
+    capture::Capture,
+    cas::Swap,
+    chunk::{self, Direction, Transfer, Transfers, ValueLimits},
+    coalesce::{self, Coalescer},
+    command::Aliases,
+    conflict::{self, MergeStrategy},
+    deadline::Deadlines,
+    dials::DialLimits,
+    disconnect::Disconnect,
+    eventlog::EventLog,
+    events::{EventStreams, NodeEvent},
+    expiry::{Expiry, ExpiryConfig, ExpiryWatch},
+    extension::{Extension, ExtensionEvent},
+    fault::FaultConfig,
+    fetch::{self, Fetch},
+    file::{self, Chunk, ChunkAck, FileTransfer, FileTransfers},
+    filter::SharedFilter,
+    index::{self, KeyIndex},
+    inflight::InFlight,
+    input::InputFormat,
+    limits::{ConnectionLimits, Connections},
+    liveness::Liveness,
+    load::Load,
+    msg::{self, Ack, Messaging},
+    namespace::{Namespace, NamespaceQuota, NamespaceUsage, Namespaces},
+    negotiation::SharedNegotiations,
+    observed::{Confirmed, ObservedAddrs},
+    output::{Message, Output},
+    peerinfo::{self, Announcer, PeerInfo},
+    pex::{self, Pex, PexTimer, Sample},
+    progress::{Progress, ProgressConfig},
+    provenance::Hops,
+    rendezvous::{self, Registrations, Rendezvous},
+    republish::Republisher,
+    retry::{Retries, RetryPolicy},
+    routing::{self, BucketStats, RoutingChange, RoutingLog},
+    rpc::Subscribers,
+    score::{BanPolicy, Offense, Scores},
+    session,
+    snapshot::Snapshot,
+    stats::SessionStats,
+    storechurn::{Churned, StoreChurn, StoreChurnConfig},
+    throttle::{InboundLimits, Throttled},
+    timing::Timings,
+    topology::Edge,
+    trace::{QuerySpan, Tracer},
+    updates::{self, ProviderWatches},
+    validate::{StoreConfig, ValidatingStore, Validator},
+    value::{Compression, Decoded, Signature, ValueCodec},
+    verify::{Holding, Verifications},
+    watch::Watches,
+    Error,
+};
+use futures::{channel::oneshot, FutureExt, Stream};
+use futures_timer::Delay;
+use libp2p::{
+    core::{connection::ConnectionError, ConnectedPoint},
+    identity::PublicKey,
+    kad::{
+        handler::KademliaHandlerConfig,
+        kbucket::NodeStatus,
+        record::{store::RecordStore, Key},
+        BootstrapError, BootstrapOk, BootstrapResult,
+        GetClosestPeersError, GetClosestPeersOk, GetRecordError,
+        GetRecordOk, Kademlia, KademliaConfig, KademliaEvent, PeerRecord,
+        PutRecordError, PutRecordOk, QueryId, QueryInfo, QueryResult,
+        QueryStats, Quorum, Record,
+    },
+    mdns::{Mdns, MdnsEvent},
+    multiaddr::Protocol,
+    ping::{Ping, PingConfig, PingEvent, PingSuccess},
+    request_response::{
+        RequestId, RequestResponseEvent, RequestResponseMessage,
+    },
+    swarm::{
+        protocols_handler::NodeHandlerWrapperError, toggle::Toggle,
+        DummyBehaviour, NetworkBehaviour as Behaviour,
+        NetworkBehaviourAction, NetworkBehaviourEventProcess,
+        PollParameters, SwarmEvent,
+    },
+    Multiaddr, NetworkBehaviour, PeerId,
+};
+use serde_json::json;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    fmt,
+    num::{NonZeroU32, NonZeroUsize},
+    path::PathBuf,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+// The behaviour of a node, with nothing else running next to it.
+pub type MyBehavior = MyBehaviorWith<DummyBehaviour>;
+
+// Create a custom network behavior, combining Kademlia and mDNS (and `B`,
+// the extension of whoever embeds the node)
+#[derive(NetworkBehaviour)]
+#[behaviour(poll_method = "poll")]
+pub struct MyBehaviorWith<B: Behaviour> {
+    pub kademlia: Throttled,
+    // Turned off for nodes that shouldn't touch the real network
+    pub mdns: Toggle<Mdns>, // TODO: Use bootstrapping here as well (for testing)
+    // Direct transfers of values between two peers
+    pub fetch: Fetch,
+    // Discovery through rendezvous servers
+    pub rendezvous: Rendezvous,
+    // Closes connections on request
+    pub disconnect: Disconnect,
+    // Where every peer we heard of may be reached (BOOK)
+    pub book: AddressBook,
+    // Text messages straight to a peer (SEND)
+    pub messaging: Messaging,
+    // Files straight to a peer (SENDFILE)
+    pub files: FileTransfer,
+    // Samples of the routing table, to and from connected peers
+    pub pex: Pex,
+    // Round trip times, for TOPOLOGY
+    pub ping: Ping,
+    // Where peers see us, to tell the external addresses (behind a NAT)
+    pub observed: ObservedAddrs,
+    // Whatever the embedder brings along (see NodeBuilder::with_behaviour)
+    pub extension: Extension<B>,
+
+    // The allow/deny rules (not a behaviour, so the derive ignores it)
+    #[behaviour(ignore)]
+    pub filter: SharedFilter,
+
+    // Counters for the session summary
+    #[behaviour(ignore)]
+    pub stats: SessionStats,
+
+    // How well each peer behaves, and who to ban for it
+    #[behaviour(ignore)]
+    pub scores: Scores,
+
+    // The established connections, and the ones over the limits
+    #[behaviour(ignore)]
+    pub connections: Connections,
+
+    // The traffic of each peer (counted by the transport)
+    #[behaviour(ignore)]
+    pub bandwidth: SharedBandwidth,
+
+    // What the transport negotiated with each peer
+    #[behaviour(ignore)]
+    pub negotiations: SharedNegotiations,
+
+    // Where to send the result of each query that a command started
+    #[behaviour(ignore)]
+    pub pending: HashMap<QueryId, Output>,
+
+    // The same, for queries whose starter wants the raw result (the HTTP
+    // api, and the benchmark)
+    #[behaviour(ignore)]
+    pub api_pending: HashMap<QueryId, oneshot::Sender<ApiReply>>,
+
+    // Where to send the value of each FETCH
+    #[behaviour(ignore)]
+    pub fetching: HashMap<RequestId, (Key, Output)>,
+
+    // Who each SEND went to, when, and where to report the
+    // acknowledgement
+    #[behaviour(ignore)]
+    pub sending: HashMap<RequestId, (PeerId, Instant, Output)>,
+
+    // The files on their way to or from a peer
+    #[behaviour(ignore)]
+    pub file_transfers: FileTransfers,
+
+    // When to exchange peers next
+    #[behaviour(ignore)]
+    pex_timer: PexTimer,
+
+    // Where the listeners are listening, as they reported it (see
+    // `observe`)
+    #[behaviour(ignore)]
+    pub listen_addrs: Vec<Multiaddr>,
+
+    // Where we listen, for the samples (as of the last exchange)
+    #[behaviour(ignore)]
+    own_addrs: Vec<Multiaddr>,
+
+    // The round trip time to each connected peer, as ping last measured
+    // it
+    #[behaviour(ignore)]
+    rtts: HashMap<PeerId, Duration>,
+
+    // Where the spans of finished queries go, if anywhere
+    #[behaviour(ignore)]
+    tracer: Option<Tracer>,
+
+    // Whether a bootstrap went all the way through (for /healthz)
+    #[behaviour(ignore)]
+    pub bootstrapped: bool,
+
+    // Where to send the answer to each REGISTER and DISCOVER, and the
+    // namespace it was about
+    #[behaviour(ignore)]
+    pub rendezvous_pending: HashMap<RequestId, (String, Output)>,
+
+    // The rendezvous servers to REGISTER with and DISCOVER peers through
+    #[behaviour(ignore)]
+    pub rendezvous_points: Vec<PeerId>,
+
+    // Who registered with us, if we are a rendezvous server
+    #[behaviour(ignore)]
+    pub registrations: Option<Registrations>,
+
+    // Websocket clients that get told about swarm events
+    #[behaviour(ignore)]
+    pub subscribers: Subscribers,
+    // Programs embedding the node that get told about it (see `events`)
+    #[behaviour(ignore)]
+    event_streams: EventStreams,
+
+    // The latest changes to the routing table
+    #[behaviour(ignore)]
+    pub routing_log: RoutingLog,
+
+    // The DIALs that haven't connected (or failed) yet, by address, with
+    // the peer they are meant to reach (if the address says)
+    #[behaviour(ignore)]
+    pub dials: HashMap<Multiaddr, VecDeque<(Option<PeerId>, Output)>>,
+
+    // The queries that were running last time we looked (see
+    // `track_queries`)
+    #[behaviour(ignore)]
+    queries: HashSet<QueryId>,
+
+    // The latest queries that finished, newest last (for --tui)
+    #[behaviour(ignore)]
+    pub recent_queries: VecDeque<FinishedQuery>,
+
+    // How big values may get, and whether to chunk the bigger ones
+    #[behaviour(ignore)]
+    pub limits: ValueLimits,
+
+    // What to do to values before storing them (and after fetching
+    // them)
+    #[behaviour(ignore)]
+    pub codec: ValueCodec,
+
+    // The prefix of every key that commands use (see NS)
+    #[behaviour(ignore)]
+    pub namespace: Namespace,
+
+    // The namespaces the node serves (--namespaces), and their queries
+    #[behaviour(ignore)]
+    pub namespaces: Namespaces,
+
+    // Chunked values being stored or fetched
+    #[behaviour(ignore)]
+    transfers: Transfers,
+
+    // Keys that are looked up every so often (see WATCH)
+    #[behaviour(ignore)]
+    pub watches: Watches,
+
+    // Keys whose announced versions are looked for (see WATCH_PROVIDERS),
+    // and whether we announce the versions we store
+    #[behaviour(ignore)]
+    pub provider_watches: ProviderWatches,
+    #[behaviour(ignore)]
+    announce_updates: bool,
+    // The keys that PUTs added to the indexes of their namespaces, with
+    // --key-index, and the lookups of the indexes (see LIST)
+    #[behaviour(ignore)]
+    key_index: KeyIndex,
+    // The MGETs and MPUTs that are running
+    #[behaviour(ignore)]
+    pub batches: Batches,
+    // What WAIT_PEERS and WAIT_BOOTSTRAP wait for
+    #[behaviour(ignore)]
+    pub barriers: Barriers,
+    // The workload of LOAD
+    #[behaviour(ignore)]
+    pub load: Load,
+    // The VERIFYs that are running
+    #[behaviour(ignore)]
+    pub verifications: Verifications,
+    #[behaviour(ignore)]
+    index_keys: bool,
+    #[behaviour(ignore)]
+    slow_query: Option<Duration>,
+    #[behaviour(ignore)]
+    replication_factor: NonZeroUsize,
+
+    // How long the GETs that have a timeout= may take
+    #[behaviour(ignore)]
+    pub deadlines: Deadlines,
+
+    // The queries of commands that are still running, for CANCEL
+    #[behaviour(ignore)]
+    pub in_flight: InFlight,
+
+    // The GETs that others of the same key may join (--coalesce-gets)
+    #[behaviour(ignore)]
+    pub coalescer: Coalescer,
+
+    // The records GETs fetched lately (--cache-size)
+    #[behaviour(ignore)]
+    pub cache: Option<RecordCache>,
+
+    // The records of ours that are about to expire (--expiry-warning)
+    #[behaviour(ignore)]
+    expiry: Option<ExpiryWatch>,
+
+    // What hits the local store on purpose (--store-churn)
+    #[behaviour(ignore)]
+    store_churn: Option<StoreChurn>,
+
+    // The commands that are timed, and whether they all are
+    #[behaviour(ignore)]
+    pub timings: Timings,
+    #[behaviour(ignore)]
+    pub timing: bool,
+
+    // What commands are parsed with (see --alias), and what they look
+    // like (see --input)
+    #[behaviour(ignore)]
+    pub aliases: Aliases,
+    #[behaviour(ignore)]
+    pub input: InputFormat,
+
+    // How the queries of commands that take long are getting on
+    #[behaviour(ignore)]
+    progress: Option<Progress>,
+
+    // Which peers of the routing table answer pings, with --cull-after
+    #[behaviour(ignore)]
+    liveness: Option<Liveness>,
+    // The stale peers to dial, to see whether they are still there
+    #[behaviour(ignore)]
+    probes: VecDeque<PeerId>,
+
+    // The PUTs that get tried again if they fail, with --put-retries
+    #[behaviour(ignore)]
+    retries: Option<Retries>,
+
+    // Looks our records up every so often, with --audit-interval
+    #[behaviour(ignore)]
+    pub auditor: Option<Auditor>,
+
+    // Compare-and-swaps waiting for the current version of their record
+    #[behaviour(ignore)]
+    pub swaps: HashMap<QueryId, Swap>,
+
+    // Keeps our own records from expiring
+    #[behaviour(ignore)]
+    republisher: Republisher,
+
+    // Publishes the peer info of this node
+    #[behaviour(ignore)]
+    announcer: Announcer,
+
+    // Publishes the peers that the node knows, with --beacon
+    #[behaviour(ignore)]
+    beacon: Option<Beacon>,
+
+    // Fetches the peers of the beacon, with --join-beacon
+    #[behaviour(ignore)]
+    beacon_join: Option<BeaconJoin>,
+
+    // Where to send the peer info of each LOOKUP_PEER, and whose it is
+    #[behaviour(ignore)]
+    pub peer_lookups: HashMap<QueryId, (PeerId, Output)>,
+
+    #[behaviour(ignore)]
+    pub local_peer_id: PeerId,
+
+    #[behaviour(ignore)]
+    pub public_key: PublicKey,
+
+    // When to bootstrap again (see --refresh-interval)
+    #[behaviour(ignore)]
+    refresh_interval: Option<Duration>,
+    #[behaviour(ignore)]
+    refresh_timer: Option<Delay>,
+
+    // When to remove what expired from the store (see --store-gc-interval)
+    #[behaviour(ignore)]
+    gc_interval: Option<Duration>,
+    #[behaviour(ignore)]
+    gc_timer: Option<Delay>,
+}
+
+// Everything about a node's behaviour that can be configured.
+#[derive(Debug, Clone)]
+pub struct BehaviourConfig {
+    // How big values may get, and whether to chunk the bigger ones
+    pub limits: ValueLimits,
+    // What to do to values before storing them
+    pub codec: ValueCodec,
+    // What the local store accepts
+    pub validators: Vec<Validator>,
+    // The prefix of every key
+    pub namespace: Namespace,
+    // The namespaces of --namespaces, with their quotas
+    pub namespaces: Vec<NamespaceQuota>,
+    // How often to store our own records again
+    pub republish_interval: Option<Duration>,
+    // How often to check that our records are still out there
+    pub audit: Option<AuditConfig>,
+    // Whether (and when) to try failed PUTs again
+    pub put_retries: Option<RetryPolicy>,
+    // When the queries of commands report how far they got
+    pub progress: Option<ProgressConfig>,
+    // Timeouts, replication and the rest (the packet size follows from
+    // the value limits)
+    pub kademlia: KademliaConfig,
+    // In client mode, how kademlia's connection handlers are set up, to
+    // turn down the requests of other peers (the packet size follows
+    // from the value limits here too)
+    pub kademlia_client: Option<KademliaHandlerConfig>,
+    // Whether GETs cache what they find at the closest peers
+    pub caching: bool,
+    // Which replies to get wrong on purpose
+    pub faults: Option<FaultConfig>,
+    // Take registrations from other peers
+    pub rendezvous_server: bool,
+    // The rendezvous servers to use, and where they are
+    pub rendezvous_points: Vec<(PeerId, Multiaddr)>,
+    // When to ban peers for misbehaving
+    pub ban_policy: Option<BanPolicy>,
+    // How many connections there may be
+    pub connection_limits: ConnectionLimits,
+    // Where the transport counts the traffic of each peer
+    pub bandwidth: SharedBandwidth,
+    // Where the transport records what each connection negotiated
+    pub negotiations: SharedNegotiations,
+    // How long connections stay open with nothing going on (the kademlia
+    // config has its own, which it keeps to)
+    pub idle_timeout: Option<Duration>,
+    // Where to log events, besides telling the websocket clients
+    pub event_log: Option<EventLog>,
+    // Where to write down the kademlia messages
+    pub capture: Option<EventLog>,
+    // The node's public key, for WHOAMI
+    pub public_key: PublicKey,
+    // How often to bootstrap again
+    pub refresh_interval: Option<Duration>,
+    // How much the local store holds
+    pub store: StoreConfig,
+    // Where to save the files peers send us
+    pub receive_dir: Option<PathBuf>,
+    // How often to exchange peers with the connected ones
+    pub pex_interval: Option<Duration>,
+    // How often to publish the peer info of the node
+    pub peer_info_interval: Option<Duration>,
+    // How often to publish the peers the node knows, as a beacon
+    pub beacon_interval: Option<Duration>,
+    // The beacon to join the network through
+    pub join_beacon: Option<(PeerId, Multiaddr)>,
+    // The OpenTelemetry collector to send query spans to
+    pub otlp_endpoint: Option<String>,
+    // How many kademlia requests peers may send us
+    pub inbound_limits: InboundLimits,
+    // How many dials may go on at once, and wait
+    pub dial_limits: DialLimits,
+    // How long peers may go without answering a ping before they are
+    // taken out of the routing table
+    pub cull_after: Option<Duration>,
+    // Whether to time every command (see --timing)
+    pub timing: bool,
+    pub aliases: Aliases,
+    pub input: InputFormat,
+    // Whether to announce new versions of records (see --announce-updates)
+    pub announce_updates: bool,
+    // Whether GETs of a key wait for the one that runs already (see
+    // --coalesce-gets)
+    pub coalesce_gets: bool,
+    // How many of the records that GETs fetch to keep, and for how long
+    // (see --cache-size)
+    pub cache: Option<(NonZeroUsize, Duration)>,
+    // What to do about the records of ours that are about to expire
+    // (see --expiry-warning)
+    pub expiry: Option<ExpiryConfig>,
+    // What to do to the local store on purpose (--store-churn)
+    pub store_churn: Option<StoreChurnConfig>,
+    // Whether PUTs add their keys to the index (see --key-index)
+    pub key_index: bool,
+    // How long a query may take before it gets logged (--slow-query)
+    pub slow_query: Option<Duration>,
+    // How many peers store each record (the k of the kademlia config,
+    // which doesn't tell), for ANALYZE
+    pub replication_factor: NonZeroUsize,
+    // How many peers have to see the node at an address before it is
+    // taken for an external one (--addr-confirmations)
+    pub addr_confirmations: NonZeroUsize,
+}
+
+impl MyBehavior {
+    // Create the behaviour of a node, with mDNS discovery if `mdns` is
+    // given.
+    pub fn new(
+        local_peer_id: PeerId,
+        mdns: Option<Mdns>,
+        filter: SharedFilter,
+        config: BehaviourConfig,
+    ) -> Self {
+        MyBehavior::with_extension(
+            local_peer_id,
+            mdns,
+            filter,
+            config,
+            Extension::default(),
+        )
+    }
+}
+
+impl<B: Behaviour> MyBehaviorWith<B> {
+    // The same, with `extension` running next to the node's own
+    // behaviours.
+    pub fn with_extension(
+        local_peer_id: PeerId,
+        mdns: Option<Mdns>,
+        filter: SharedFilter,
+        config: BehaviourConfig,
+        extension: Extension<B>,
+    ) -> Self {
+        let BehaviourConfig {
+            limits,
+            codec,
+            validators,
+            namespace,
+            namespaces,
+            republish_interval,
+            audit,
+            put_retries,
+            progress,
+            kademlia,
+            kademlia_client,
+            caching,
+            faults,
+            rendezvous_server,
+            rendezvous_points,
+            ban_policy,
+            connection_limits,
+            bandwidth,
+            negotiations,
+            idle_timeout,
+            event_log,
+            capture,
+            public_key,
+            refresh_interval,
+            store: store_config,
+            receive_dir,
+            pex_interval,
+            peer_info_interval,
+            beacon_interval,
+            join_beacon,
+            otlp_endpoint,
+            inbound_limits,
+            dial_limits,
+            cull_after,
+            timing,
+            aliases,
+            input,
+            announce_updates,
+            coalesce_gets,
+            cache,
+            expiry,
+            store_churn,
+            key_index: index_keys,
+            slow_query,
+            replication_factor,
+            addr_confirmations,
+        } = config;
+        let gc_interval = store_config.gc_interval;
+        let event_log = event_log.map(|log| log.for_node(&local_peer_id));
+
+        // Create a Kademlia behavior, which stores (and lets through)
+        // values up to the maximum size, as long as the validators accept
+        // them
+        let kademlia = {
+            let mut store = ValidatingStore::new(
+                local_peer_id.clone(),
+                &store_config,
+                // The store only takes values *smaller* than this
+                limits.max_value_size + 1,
+            );
+            for validator in validators {
+                store.add_validator(validator);
+            }
+            store.set_namespaces(namespaces.clone());
+            if let Some(log) = &event_log {
+                store.set_event_log(log.clone());
+            }
+            let mut config = kademlia;
+            config.set_max_packet_size(limits.max_packet_size());
+            let mut kademlia = Kademlia::with_config(
+                local_peer_id.clone(),
+                store,
+                config,
+            );
+            // The servers are dialed through the routing table, like any
+            // peer
+            for (peer_id, addr) in &rendezvous_points {
+                kademlia.add_address(peer_id, addr.clone());
+            }
+            let mut kademlia = Throttled::new(kademlia, inbound_limits);
+            kademlia.set_caching(caching);
+            kademlia.set_dial_limits(dial_limits);
+            kademlia.set_faults(faults);
+            kademlia
+                .set_capture(capture.map(|log| {
+                    Capture::new(log.for_node(&local_peer_id))
+                }));
+            if let Some(mut handler) = kademlia_client {
+                handler
+                    .protocol_config
+                    .set_max_packet_size(limits.max_packet_size());
+                kademlia.client_mode(handler);
+            }
+            kademlia
+        };
+
+        let tracer = otlp_endpoint.and_then(|endpoint| {
+            Tracer::new(&endpoint, &local_peer_id)
+                .map_err(|err| {
+                    Output::Terminal
+                        .error(format!("--otlp-endpoint {}", err))
+                })
+                .ok()
+        });
+
+        let mut subscribers = Subscribers::default();
+        if let Some(log) = event_log {
+            subscribers.set_log(log);
+        }
+        if let Some(recorder) = session::recorder() {
+            subscribers.set_recorder(recorder.for_node(&local_peer_id));
+        }
+
+        MyBehaviorWith {
+            kademlia,
+            mdns: Toggle::from(mdns),
+            extension,
+            fetch: fetch::new(idle_timeout),
+            rendezvous: rendezvous::new(idle_timeout),
+            disconnect: Disconnect::default(),
+            book: AddressBook::default(),
+            messaging: msg::new(idle_timeout),
+            files: file::new(idle_timeout),
+            pex: pex::new(idle_timeout),
+            // Only to measure, so it neither keeps connections open nor
+            // closes them when the peer is slow to answer
+            ping: Ping::new(
+                PingConfig::new()
+                    .with_keep_alive(false)
+                    .with_max_failures(NonZeroU32::new(u32::MAX).unwrap()),
+            ),
+            observed: ObservedAddrs::new(
+                public_key.clone(),
+                addr_confirmations,
+            ),
+            filter,
+            stats: SessionStats::new(),
+            scores: Scores::new(ban_policy),
+            connections: Connections::new(connection_limits, idle_timeout),
+            bandwidth,
+            negotiations,
+            pending: HashMap::new(),
+            api_pending: HashMap::new(),
+            fetching: HashMap::new(),
+            sending: HashMap::new(),
+            file_transfers: FileTransfers::new(receive_dir),
+            pex_timer: PexTimer::new(pex_interval),
+            listen_addrs: Vec::new(),
+            own_addrs: Vec::new(),
+            rtts: HashMap::new(),
+            recent_queries: VecDeque::new(),
+            tracer,
+            bootstrapped: false,
+            rendezvous_pending: HashMap::new(),
+            rendezvous_points: rendezvous_points
+                .into_iter()
+                .map(|(peer_id, _)| peer_id)
+                .collect(),
+            registrations: rendezvous_server.then(Registrations::default),
+            subscribers,
+            event_streams: EventStreams::default(),
+            routing_log: RoutingLog::default(),
+            dials: HashMap::new(),
+            queries: HashSet::new(),
+            limits,
+            codec,
+            namespace,
+            namespaces: Namespaces::new(namespaces),
+            transfers: Transfers::default(),
+            watches: Watches::default(),
+            deadlines: Deadlines::default(),
+            in_flight: InFlight::default(),
+            coalescer: Coalescer::new(coalesce_gets),
+            cache: cache.map(|(size, ttl)| RecordCache::new(size, ttl)),
+            expiry: expiry.map(ExpiryWatch::new),
+            store_churn: store_churn.map(StoreChurn::new),
+            progress: progress.map(Progress::new),
+            liveness: cull_after.map(Liveness::new),
+            probes: VecDeque::new(),
+            retries: put_retries.map(Retries::new),
+            auditor: audit.map(Auditor::new),
+            swaps: HashMap::new(),
+            announcer: Announcer::new(peer_info_interval),
+            beacon: beacon_interval.map(Beacon::new),
+            beacon_join: join_beacon.map(BeaconJoin::new),
+            peer_lookups: HashMap::new(),
+            republisher: Republisher::new(republish_interval),
+            local_peer_id,
+            public_key,
+            refresh_interval,
+            refresh_timer: refresh_interval.map(Delay::new),
+            gc_interval,
+            gc_timer: gc_interval.map(Delay::new),
+            timings: Timings::default(),
+            timing,
+            aliases,
+            input,
+            provider_watches: ProviderWatches::default(),
+            announce_updates,
+            key_index: KeyIndex::default(),
+            batches: Batches::default(),
+            barriers: Barriers::default(),
+            load: Load::default(),
+            verifications: Verifications::default(),
+            index_keys,
+            slow_query,
+            replication_factor,
+        }
+    }
+
+    // Store a value under `key` (with `version`, if it has one), as PUT
+    // does: it is encoded, and then stored as a record at `quorum` peers,
+    // or as chunks if it is too big for one. The result goes to `output`.
+    // Returns the query that stores the record, if it is a single one.
+    pub fn put_value(
+        &mut self,
+        key: Key,
+        value: Vec<u8>,
+        compress: Option<Compression>,
+        version: Option<u64>,
+        quorum: Quorum,
+        output: Output,
+    ) -> Option<QueryId> {
+        // What the cache has of the key is old now
+        if let Some(cache) = &mut self.cache {
+            cache.remove(&key);
+        }
+        let size = value.len();
+        let value = self.codec.encode(&key, value, compress, version);
+        let mut how = Vec::new();
+        if let Some(compression) = compress {
+            how.push(format!("compressed with {:?}", compression));
+        }
+        if self.codec.key.is_some() {
+            how.push("encrypted".to_string());
+        }
+        if self.codec.signer.is_some() {
+            how.push("signed".to_string());
+        }
+        if !how.is_empty() {
+            output.info(format!(
+                "Encoded {} bytes as {} ({})",
+                size,
+                value.len(),
+                how.join(", ")
+            ));
+        }
+
+        // Values that don't fit in a record either get chunked, or
+        // refused (remote stores would just drop them, and GETs refuse to
+        // decompress them)
+        if value.len().max(size) > self.limits.max_value_size {
+            if self.limits.chunk {
+                self.index_key(&key);
+                self.put_chunked(key, value, output);
+                self.stats.puts_issued += 1;
+            } else {
+                output.error(format!(
+                    "The value is {} bytes, more than the maximum of {} \
+                     (see --max-value-size and --chunk)",
+                    value.len().max(size),
+                    self.limits.max_value_size
+                ));
+            }
+            return None;
+        }
+
+        let announce = version
+            .filter(|_| self.announce_updates)
+            .map(|version| (key.clone(), version));
+        let record = Record {
+            key: key.clone(),
+            value,
+            publisher: None,
+            expires: None,
+        };
+
+        // Our own store has to take the record first
+        if let Err(why) = self.kademlia.store_mut().check(&record) {
+            output.error(format!("Refused record: {}", why));
+            return None;
+        }
+
+        let retry = self.retries.as_ref().map(|_| record.clone());
+        let id = match self.kademlia.put_record(record, quorum) {
+            Ok(id) => id,
+            Err(err) => {
+                output.error(format!(
+                    "Failed to store record locally: {}",
+                    Error::from(err)
+                ));
+                return None;
+            }
+        };
+        if let (Some(retries), Some(record)) = (&mut self.retries, retry) {
+            retries.track(id, record, quorum, 1);
+        }
+        self.stats.puts_issued += 1;
+        self.pending.insert(id, output);
+        self.index_key(&key);
+        if let Some((key, version)) = announce {
+            self.announce_update(&key, version);
+        }
+        Some(id)
+    }
+
+    // The version a PUT of `key` stores, with --announce-updates: the
+    // one after the version in the local store (which is where the last
+    // PUT of this node went). Without it, PUTs don't have versions.
+    pub fn next_version(&mut self, key: &Key) -> Option<u64> {
+        if !self.announce_updates {
+            return None;
+        }
+        let record = match self.kademlia.store_mut().get(key) {
+            Some(record) => record.into_owned(),
+            None => return Some(1),
+        };
+        let version = self
+            .codec
+            .decode(&record.key, record.publisher.as_ref(), record.value)
+            .ok()
+            .and_then(|decoded| decoded.version)
+            .unwrap_or(0);
+        Some(version + 1)
+    }
+
+    // Provide the key that says `version` of `key` is out. Its result is
+    // only reported if it fails.
+    fn announce_update(&mut self, key: &Key, version: u64) {
+        let announcement = updates::key_for(key, version);
+        if let Err(err) = self.kademlia.start_providing(announcement) {
+            Output::Terminal.error(format!(
+                "Failed to announce version {} of {:?}: {:?}",
+                version,
+                self.namespace.display(key),
+                err
+            ));
+        }
+    }
+
+    // Try the failed PUTs again whose wait is over, and return whether
+    // there were any.
+    fn retry_puts(&mut self, cx: &mut Context<'_>) -> bool {
+        let due = match &mut self.retries {
+            Some(retries) => retries.due(cx),
+            None => return false,
+        };
+        let retried = !due.is_empty();
+        for retry in due {
+            let record = retry.record.clone();
+            match self.kademlia.put_record(retry.record, retry.quorum) {
+                Ok(id) => {
+                    if let Some(retries) = &mut self.retries {
+                        retries.track(
+                            id,
+                            record,
+                            retry.quorum,
+                            retry.attempt,
+                        );
+                    }
+                    match retry.reply {
+                        Some(reply) => {
+                            self.api_pending.insert(id, reply);
+                        }
+                        None => {
+                            self.pending.insert(id, retry.output);
+                        }
+                    }
+                }
+                Err(err) => {
+                    let err = format!(
+                        "Failed to store record locally: {}",
+                        Error::from(err)
+                    );
+                    match retry.reply {
+                        Some(reply) => {
+                            let _ = reply.send(ApiReply::Error(err));
+                        }
+                        None => retry.output.error(err),
+                    }
+                }
+            }
+        }
+        retried
+    }
+
+    // Add the key of a PUT to the index of the namespace, with
+    // --key-index.
+    fn index_key(&mut self, key: &Key) {
+        if !self.index_keys {
+            return;
+        }
+        let index = index::key_for(&self.namespace);
+        if self.key_index.add(&index, self.namespace.display(key)) {
+            self.read_index(index, None);
+        }
+    }
+
+    // Look up (every copy of) `index`, to update it, or for a LIST.
+    fn read_index(
+        &mut self,
+        index: Key,
+        list: Option<(String, Output)>,
+    ) -> QueryId {
+        let quorum = NonZeroUsize::new(index::QUORUM).expect("not 0");
+        let id = self.kademlia.get_record(&index, Quorum::N(quorum));
+        let pending = match list {
+            Some((name, output)) => index::Pending::List(name, output),
+            None => index::Pending::Read(index),
+        };
+        self.key_index.add_query(id, pending);
+        id
+    }
+
+    // Start a LIST: look up the index of `namespace`, and show the keys
+    // in it.
+    pub fn list(
+        &mut self,
+        namespace: &Namespace,
+        output: Output,
+    ) -> QueryId {
+        let name = match namespace.prefix() {
+            Some(prefix) => format!("namespace {:?}", prefix),
+            None => "no namespace".to_string(),
+        };
+        self.read_index(index::key_for(namespace), Some((name, output)))
+    }
+
+    // The keys in all the copies of an index that a lookup found, and
+    // whether it found any.
+    fn index_records(
+        &self,
+        result: &QueryResult,
+    ) -> (BTreeSet<String>, bool) {
+        let records = match result {
+            QueryResult::GetRecord(Ok(GetRecordOk { records }))
+            | QueryResult::GetRecord(Err(
+                GetRecordError::QuorumFailed { records, .. }
+                | GetRecordError::Timeout { records, .. },
+            )) => records,
+            _ => return (BTreeSet::new(), false),
+        };
+        let mut keys = BTreeSet::new();
+        for PeerRecord { record, .. } in records {
+            match self.codec.decode(
+                &record.key,
+                record.publisher.as_ref(),
+                record.value.clone(),
+            ) {
+                Ok(decoded) => keys.extend(index::parse(&decoded.value)),
+                Err(err) => Output::Terminal.error(format!(
+                    "key index: failed to decode a copy of the index: {}",
+                    err
+                )),
+            }
+        }
+        (keys, !records.is_empty())
+    }
+
+    fn finish_index(
+        &mut self,
+        pending: index::Pending,
+        result: QueryResult,
+    ) {
+        match pending {
+            // Store the index again, with our keys, unless they are all
+            // in there already
+            index::Pending::Read(index) => {
+                let (mut keys, _) = self.index_records(&result);
+                let ours = self.key_index.keys(&index).cloned();
+                let ours = ours.unwrap_or_default();
+                if ours.is_subset(&keys) {
+                    self.index_updated(index);
+                    return;
+                }
+                keys.extend(ours);
+                let value = self.codec.encode(
+                    &index,
+                    index::encode(&keys),
+                    None,
+                    None,
+                );
+                let record = Record {
+                    key: index.clone(),
+                    value,
+                    publisher: None,
+                    expires: None,
+                };
+                match self.kademlia.put_record(record, Quorum::One) {
+                    Ok(id) => self
+                        .key_index
+                        .add_query(id, index::Pending::Write(index)),
+                    Err(err) => {
+                        Output::Terminal.error(format!(
+                            "key index: failed to store the index: {}",
+                            Error::from(err)
+                        ));
+                        self.index_updated(index);
+                    }
+                }
+            }
+            // Another node may have stored its own copy meanwhile, so
+            // look again, until the index has all of our keys
+            index::Pending::Write(index) => {
+                if let QueryResult::PutRecord(Err(err)) = &result {
+                    Output::Terminal.error(format!(
+                        "key index: failed to store the index: {:?}",
+                        err
+                    ));
+                }
+                self.read_index(index, None);
+            }
+            index::Pending::List(name, output) => {
+                let (keys, found) = self.index_records(&result);
+                if !found {
+                    output.error(format!(
+                        "list: found no index of {} (PUTs only add their \
+                         keys to it with --key-index)",
+                        name
+                    ));
+                    return;
+                }
+                output.info(format!(
+                    "list: {} keys in {}",
+                    keys.len(),
+                    name
+                ));
+                for key in keys {
+                    output.info(format!("  {}", key));
+                }
+            }
+        }
+    }
+
+    // Update `index` again if it got more keys meanwhile.
+    fn index_updated(&mut self, index: Key) {
+        if self.key_index.updated(&index) {
+            self.read_index(index, None);
+        }
+    }
+
+    // Store a value that is too big for a single record: as chunk records
+    // of the maximum size, plus an index record under `key` that says how
+    // many chunks there are. The result goes to `output` once all of them
+    // are stored.
+    pub fn put_chunked(
+        &mut self,
+        key: Key,
+        value: Vec<u8>,
+        output: Output,
+    ) {
+        let chunks: Vec<&[u8]> =
+            value.chunks(self.limits.max_value_size.max(1)).collect();
+        let index = chunk::index_value(chunks.len(), value.len());
+        if index.len() > self.limits.max_value_size {
+            output.error(format!(
+                "--max-value-size is too small to chunk with (the index \
+                 record alone is {} bytes)",
+                index.len()
+            ));
+            return;
+        }
+        let transfer = self.transfers.start(Transfer::new(
+            Direction::Put,
+            key.clone(),
+            output,
+            chunks.len(),
+            value.len(),
+        ));
+
+        let index = (None, key.clone(), index);
+        let records = chunks.iter().enumerate().map(|(i, chunk)| {
+            (Some(i), chunk::chunk_key(&key, i), chunk.to_vec())
+        });
+        for (i, key, value) in records.chain(std::iter::once(index)) {
+            let record = Record {
+                key,
+                value,
+                publisher: None,
+                expires: None,
+            };
+            match self.kademlia.put_record(record, Quorum::One) {
+                Ok(id) => self.transfers.add_query(transfer, id, i),
+                Err(err) => {
+                    if let Some(t) = self.transfers.abort(transfer) {
+                        t.output.error(format!(
+                            "kad dht: failed to put record: {}",
+                            Error::from(err)
+                        ));
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    // Fetch the chunks of a value whose index record was just found.
+    fn get_chunked(
+        &mut self,
+        key: Key,
+        publisher: Option<PeerId>,
+        (chunks, len): (usize, usize),
+        output: Output,
+    ) {
+        let mut transfer = Transfer::new(
+            Direction::Get,
+            key.clone(),
+            output,
+            chunks,
+            len,
+        );
+        transfer.publisher = publisher;
+        let transfer = self.transfers.start(transfer);
+        for i in 0..chunks {
+            let id = self
+                .kademlia
+                .get_record(&chunk::chunk_key(&key, i), Quorum::One);
+            self.transfers.add_query(transfer, id, Some(i));
+        }
+    }
+
+    // Report a chunked transfer whose queries are all done.
+    fn finish_transfer(&mut self, mut transfer: Transfer) {
+        let key = self.namespace.display(&transfer.key);
+        match transfer.direction {
+            Direction::Put => match transfer.error.take() {
+                None => {
+                    self.stats.puts_succeeded += 1;
+                    transfer.output.info(format!(
+                        "kad dht: successfully put record {:?} in {} chunks",
+                        key,
+                        transfer.chunks.len()
+                    ));
+                }
+                Some(err) => {
+                    self.stats.puts_failed += 1;
+                    transfer.output.error(format!(
+                        "kad dht: failed to put record {:?}: {}",
+                        key, err
+                    ));
+                }
+            },
+            Direction::Get => {
+                let decoded = match transfer.error.take() {
+                    Some(err) => Err(err),
+                    None => transfer.reassemble().and_then(|value| {
+                        self.codec.decode_up_to(
+                            &transfer.key,
+                            transfer.publisher.as_ref(),
+                            value,
+                            usize::MAX,
+                        )
+                    }),
+                };
+                match decoded {
+                    Ok(decoded) => {
+                        transfer.output.info(format!(
+                            "kad dht: got record {:?} {:?} from {} chunks",
+                            key,
+                            String::from_utf8_lossy(&decoded.value),
+                            transfer.chunks.len()
+                        ));
+                        transfer.output.show_all(report_decoded(
+                            "kad dht", &key, &decoded,
+                        ));
+                    }
+                    Err(err) => {
+                        // Finding the index record counted as a success
+                        self.stats.gets_succeeded -= 1;
+                        self.stats.gets_failed += 1;
+                        transfer.output.error(format!(
+                            "kad dht: failed to get record {:?}: {}",
+                            key, err
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    // Called by the swarm after polling kademlia and the rest, to start
+    // the lookups of the watched keys that are due, and republishing.
+    fn poll<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<T, ()>> {
+        self.notify_cached();
+        self.notify_stored();
+        let watched = self.look_up_watched(cx);
+        let polled = self.look_up_provider_watches(cx);
+        let started = watched || polled;
+        for (id, key, timeout) in self.deadlines.expired(cx) {
+            self.give_up(id, &key, timeout);
+        }
+        let republished = self.republisher.due(cx) && self.republish() > 0;
+        let retried = self.retry_puts(cx);
+        let audited =
+            self.auditor.as_mut().is_some_and(|auditor| auditor.due(cx))
+                && self.audit() > 0;
+        let refreshed =
+            self.refresh_due(cx) && self.kademlia.bootstrap().is_ok();
+        let announced = self.announcer.due(cx) && {
+            let announced = self.announce(advertised_addrs(params));
+            if !announced {
+                self.announcer.retry_soon(cx);
+            }
+            announced
+        };
+        let beaconed = self.beacon.as_mut().is_some_and(|b| b.due(cx))
+            && {
+                let beaconed = self.publish_beacon();
+                if !beaconed {
+                    if let Some(beacon) = &mut self.beacon {
+                        beacon.retry_soon(cx);
+                    }
+                }
+                beaconed
+            };
+        let fetched =
+            self.beacon_join.as_mut().is_some_and(|join| join.due(cx))
+                && {
+                    self.fetch_beacon();
+                    true
+                };
+        if self.progress.as_mut().is_some_and(|p| p.due(cx)) {
+            self.report_progress();
+        }
+        // Once a barrier is gone, the terminal has commands to read again
+        if self.barriers.is_waiting() {
+            let peers = self.known_peers().len();
+            if self.barriers.poll(cx, peers, self.bootstrapped) {
+                cx.waker().wake_by_ref();
+            }
+        }
+        if self.liveness.as_mut().is_some_and(|l| l.due(cx)) {
+            self.cull_stale();
+        }
+        if interval_due(&mut self.gc_timer, self.gc_interval, cx) {
+            self.collect_expired();
+        }
+        if self.expiry.as_mut().is_some_and(|e| e.due(cx)) {
+            self.check_expiry();
+        }
+        if self.store_churn.as_mut().is_some_and(|c| c.due(cx)) {
+            self.churn_store();
+        }
+        if self.pex_timer.due(cx) {
+            // Clients keep their addresses to themselves
+            if !self.kademlia.is_client() {
+                self.own_addrs = advertised_addrs(params);
+            }
+            self.exchange_peers();
+        }
+
+        // Kademlia has to be polled again to get the new queries going
+        if started
+            || republished
+            || retried
+            || audited
+            || refreshed
+            || announced
+            || beaconed
+            || fetched
+        {
+            cx.waker().wake_by_ref();
+        }
+        // The probes dial in turn with kademlia
+        if !self.probes.is_empty() {
+            for peer_id in self.probes.drain(..) {
+                self.kademlia.dial(peer_id);
+            }
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+
+    // Dial the peers of the routing table that went quiet, and take the
+    // ones that stayed quiet after that out of it.
+    fn cull_stale(&mut self) {
+        let peers: Vec<PeerId> = self
+            .known_peers()
+            .into_iter()
+            .map(|(peer_id, _)| peer_id)
+            .collect();
+        let liveness = match &mut self.liveness {
+            Some(liveness) => liveness,
+            None => return,
+        };
+        let sweep = liveness.sweep(peers.into_iter());
+        self.probes.extend(sweep.probe);
+        for (peer_id, silent) in sweep.cull {
+            if self.remove_peer(&peer_id).is_none() {
+                continue;
+            }
+            self.stats.peers_culled += 1;
+            Output::Terminal.info(format!(
+                "Took peer {} out of the routing table (no answer for {:.0?})",
+                peer_id, silent
+            ));
+            self.subscribers.notify(
+                "peer_culled",
+                json!({
+                    "peer_id": peer_id.to_string(),
+                    "silent_ms": silent.as_millis() as u64,
+                }),
+            );
+        }
+    }
+
+    // List the peers closest to `key`, closest first.
+    fn report_closest(
+        &self,
+        key: &[u8],
+        peers: Vec<PeerId>,
+        output: &Output,
+    ) {
+        let name = match PeerId::from_bytes(key.to_vec()) {
+            Ok(peer_id) => peer_id.to_string(),
+            Err(_) => {
+                format!("{:?}", self.namespace.display(&Key::new(&key)))
+            }
+        };
+        output.info(format!(
+            "kad dht: {} closest peers to {}",
+            peers.len(),
+            name
+        ));
+        for (peer, bits) in routing::by_distance(key, peers) {
+            output.info(format!("  {} ({} bits in common)", peer, bits));
+        }
+    }
+
+    // Tell the starter of a bootstrap how far along it is.
+    fn report_bootstrap(
+        &mut self,
+        id: QueryId,
+        result: &BootstrapResult,
+        output: Output,
+    ) {
+        let remaining = match result {
+            Ok(BootstrapOk { num_remaining, .. }) => Some(*num_remaining),
+            Err(BootstrapError::Timeout { num_remaining, .. }) => {
+                output.error("kad dht: a bootstrap query timed out");
+                *num_remaining
+            }
+        };
+        match remaining {
+            Some(remaining) if remaining > 0 => {
+                output.info(format!(
+                    "kad dht: bootstrapping, {} queries to go",
+                    remaining
+                ));
+                self.pending.insert(id, output);
+            }
+            _ => {
+                let peers: usize = self
+                    .kademlia
+                    .kbuckets()
+                    .map(|bucket| bucket.num_entries())
+                    .sum();
+                output.info(format!(
+                    "kad dht: bootstrapped, {} peers in the routing table",
+                    peers
+                ));
+            }
+        }
+    }
+
+    // Whether it is time to bootstrap again. The timer wakes the task once
+    // it is.
+    fn refresh_due(&mut self, cx: &mut Context<'_>) -> bool {
+        interval_due(&mut self.refresh_timer, self.refresh_interval, cx)
+    }
+
+    // Remove the records and provider records that expired from the
+    // store, and tell the subscribers, if there were any.
+    fn collect_expired(&mut self) {
+        let store = self.kademlia.store_mut();
+        let (records, providers) = store.collect_expired();
+        self.stats.store_gc = store.gc;
+        let took = store.gc.took;
+        if records + providers > 0 {
+            self.subscribers.notify(
+                "store_gc",
+                json!({
+                    "records": records,
+                    "providers": providers,
+                    "took_ms": took.as_secs_f64() * 1000.0,
+                }),
+            );
+        }
+    }
+
+    // Report the records of ours that are about to expire, or did, and
+    // store the ones that --renew says to again.
+    fn check_expiry(&mut self) {
+        let (events, renew) = match &mut self.expiry {
+            Some(expiry) => expiry.check(
+                &self.local_peer_id,
+                self.kademlia.store_mut().records(),
+            ),
+            None => return,
+        };
+        for event in events {
+            match event {
+                Expiry::Expiring { key, left, renew } => {
+                    let name = self.namespace.display(&key);
+                    Output::Terminal.info(format!(
+                        "expiry: record {:?} expires in {:.1?}{}",
+                        name,
+                        left,
+                        match renew {
+                            true => ", storing it again",
+                            false => "",
+                        }
+                    ));
+                    let renewals = self
+                        .expiry
+                        .as_ref()
+                        .map_or(0, |expiry| expiry.renewals(&key));
+                    self.subscribers.notify(
+                        "record_expiring",
+                        json!({
+                            "key": name,
+                            "expires_in_ms": left.as_millis() as u64,
+                            "renew": renew,
+                            "renewals": renewals,
+                        }),
+                    );
+                }
+                Expiry::Expired(key) => {
+                    let name = self.namespace.display(&key);
+                    Output::Terminal.info(format!(
+                        "expiry: record {:?} expired",
+                        name
+                    ));
+                    self.subscribers
+                        .notify("record_expired", json!({ "key": name }));
+                }
+            }
+        }
+        for record in renew {
+            let key = record.key.clone();
+            match self.kademlia.put_record(record, Quorum::One) {
+                Ok(id) => {
+                    if let Some(expiry) = &mut self.expiry {
+                        expiry.add_query(id, key);
+                    }
+                }
+                Err(err) => Output::Terminal.error(format!(
+                    "expiry: failed to store {:?} again: {}",
+                    self.namespace.display(&key),
+                    Error::from(err)
+                )),
+            }
+        }
+    }
+
+    // Garble and remove records of the local store, as --store-churn
+    // says.
+    fn churn_store(&mut self) {
+        let churn = match &mut self.store_churn {
+            Some(churn) => churn,
+            None => return,
+        };
+        let churned = self.kademlia.store_mut().churn(|| churn.pick());
+        for (key, churned) in churned {
+            let name = self.namespace.display(&key);
+            Output::Terminal
+                .info(format!("store churn: {} {:?}", churned, name));
+            self.subscribers.notify(
+                "record_churned",
+                json!({
+                    "key": name,
+                    "churn": match churned {
+                        Churned::Overwritten => "overwritten",
+                        Churned::Deleted => "deleted",
+                    },
+                }),
+            );
+        }
+    }
+
+    // A put of `key` went through: if the record is ours, the expiry
+    // watch counts its time to live from now.
+    fn put_done(&mut self, key: &Key) {
+        let local_peer_id = &self.local_peer_id;
+        let ours =
+            self.kademlia.store_mut().get(key).is_some_and(|r| {
+                r.publisher.as_ref() == Some(local_peer_id)
+            });
+        if let Some(expiry) = self.expiry.as_mut().filter(|_| ours) {
+            expiry.published(key.clone());
+        }
+    }
+
+    // Let `by` pass, as far as the records, the provider records and
+    // the republishing go (see ADVANCE, in simulations). Returns how many
+    // records, and provider records, expired.
+    pub fn advance(&mut self, by: Duration) -> (usize, usize) {
+        self.republisher.advance(by);
+        self.kademlia.store_mut().age(by)
+    }
+
+    // Store the records that we published again, so that they expire
+    // later (this includes the chunks of chunked values). Returns how many
+    // there are.
+    pub fn republish(&mut self) -> usize {
+        let local_peer_id = &self.local_peer_id;
+        let records: Vec<Record> = self
+            .kademlia
+            .store_mut()
+            .records()
+            .filter(|r| r.publisher.as_ref() == Some(local_peer_id))
+            .map(|r| Record {
+                // Kademlia only sets a new expiry on records without one
+                expires: None,
+                ..r.into_owned()
+            })
+            .collect();
+        let count = records.len();
+        for record in records {
+            match self.kademlia.put_record(record, Quorum::One) {
+                Ok(id) => self.republisher.add_query(id),
+                Err(err) => Output::Terminal.error(format!(
+                    "republish: failed to put: {}",
+                    Error::from(err)
+                )),
+            }
+        }
+        count
+    }
+
+    // Publish the peer info of this node, with `addrs` as where it
+    // listens. Returns whether it did: not before there is an address,
+    // and a peer to store it at (and never on clients, which keep their
+    // addresses to themselves).
+    fn announce(&mut self, addrs: Vec<Multiaddr>) -> bool {
+        if self.kademlia.is_client()
+            || addrs.is_empty()
+            || self.known_peers().is_empty()
+        {
+            return false;
+        }
+        let record = Record {
+            key: peerinfo::key_for(&self.local_peer_id),
+            value: self.announcer.info(addrs).to_value(),
+            publisher: None,
+            expires: None,
+        };
+        match self.kademlia.put_record(record, Quorum::One) {
+            Ok(id) => self.announcer.add_query(id),
+            Err(err) => Output::Terminal.error(format!(
+                "peer info: failed to put: {}",
+                Error::from(err)
+            )),
+        }
+        true
+    }
+
+    // Publish the peers that this node knows under the beacon key.
+    // Returns whether it did: not before it knows some.
+    fn publish_beacon(&mut self) -> bool {
+        let peers: Vec<(PeerId, Multiaddr)> = self
+            .known_peers()
+            .into_iter()
+            .filter_map(|(peer, addrs)| {
+                addrs.into_iter().next().map(|addr| (peer, addr))
+            })
+            .collect();
+        if peers.is_empty() {
+            return false;
+        }
+        let record = Record {
+            key: beacon::key(),
+            value: beacon::to_value(&peers),
+            publisher: None,
+            expires: None,
+        };
+        match self.kademlia.put_record(record, Quorum::One) {
+            Ok(id) => {
+                if let Some(beacon) = &mut self.beacon {
+                    beacon.add_query(id);
+                }
+            }
+            Err(err) => Output::Terminal.error(format!(
+                "beacon: failed to put: {}",
+                Error::from(err)
+            )),
+        }
+        true
+    }
+
+    // Ask for the record of the beacon we join through, which is all the
+    // node knows of the network so far.
+    fn fetch_beacon(&mut self) {
+        let (peer, addr) = match &self.beacon_join {
+            Some(join) => join.beacon().clone(),
+            None => return,
+        };
+        if self.kademlia.addresses_of_peer(&peer).is_empty() {
+            self.discovered(peer, addr, "beacon");
+        }
+        let id = self.kademlia.get_record(&beacon::key(), Quorum::One);
+        if let Some(join) = &mut self.beacon_join {
+            join.started(id);
+        }
+    }
+
+    // The record of the beacon arrived (or not): bootstrap from the peers
+    // it lists.
+    fn finish_beacon(&mut self, result: QueryResult) {
+        let record = match result {
+            QueryResult::GetRecord(Ok(GetRecordOk { records })) => {
+                records.into_iter().next().map(|r| r.record)
+            }
+            _ => None,
+        };
+        let peers = match record.map(|r| beacon::from_value(&r.value)) {
+            Some(Ok(peers)) => peers,
+            Some(Err(err)) => {
+                Output::Terminal
+                    .error(format!("beacon: bad beacon record: {}", err));
+                return;
+            }
+            // The beacon knows nobody yet: ask again in a while
+            None => return,
+        };
+        let mut found = 0;
+        for (peer, addr) in peers {
+            if peer != self.local_peer_id {
+                self.discovered(peer, addr, "beacon");
+                found += 1;
+            }
+        }
+        Output::Terminal.info(format!(
+            "beacon: joined through the beacon, which listed {} peers",
+            found
+        ));
+        self.kademlia.bootstrap().ok();
+        if let Some(join) = &mut self.beacon_join {
+            join.joined();
+        }
+    }
+
+    // Tell whoever asked what the peer info of `peer` is.
+    fn finish_lookup(
+        &self,
+        peer: PeerId,
+        result: QueryResult,
+        output: Output,
+    ) {
+        let record = match result {
+            QueryResult::GetRecord(Ok(GetRecordOk { records })) => {
+                match records.into_iter().next() {
+                    Some(PeerRecord { record, .. }) => record,
+                    None => {
+                        output.error(format!("No peer info for {}", peer));
+                        return;
+                    }
+                }
+            }
+            QueryResult::GetRecord(Err(GetRecordError::NotFound {
+                ..
+            })) => {
+                output.error(format!("No peer info for {}", peer));
+                return;
+            }
+            QueryResult::GetRecord(Err(err)) => {
+                output.error(format!(
+                    "LOOKUP_PEER: failed to get the peer info of {}: {:?}",
+                    peer, err
+                ));
+                return;
+            }
+            _ => return,
+        };
+        // Anybody could put a record under the key, but only the peer
+        // itself can be its publisher
+        if record.publisher.as_ref() != Some(&peer) {
+            output.error(format!(
+                "The peer info of {} wasn't published by it",
+                peer
+            ));
+            return;
+        }
+        match PeerInfo::from_value(&record.value) {
+            Ok(info) => {
+                output.info(format!("Peer {}: {}", peer, info));
+                for addr in info.listen_addrs {
+                    output.info(format!(
+                        "Listening on {}",
+                        addr.with(Protocol::P2p(peer.clone().into()))
+                    ));
+                }
+            }
+            Err(err) => output.error(format!(
+                "The peer info of {} is invalid: {}",
+                peer, err
+            )),
+        }
+    }
+
+    // Stop waiting for a GET that took longer than its timeout=, and stop
+    // the query too.
+    fn give_up(&mut self, id: QueryId, key: &Key, timeout: Duration) {
+        if let Some(mut query) = self.kademlia.query_mut(&id) {
+            query.finish();
+        }
+        self.in_flight.finish(&id, true);
+        self.stats.gets_failed += 1;
+        let output = self.pending.remove(&id).unwrap_or(Output::Terminal);
+        output.error(format!(
+            "kad dht: GET {:?} timed out after {:?}",
+            self.namespace.display(key),
+            timeout
+        ));
+    }
+
+    // Tell whoever started the queries of commands that run long how far
+    // they got (see --progress-after).
+    fn report_progress(&mut self) {
+        let progress = match &mut self.progress {
+            Some(progress) => progress,
+            None => return,
+        };
+        let mut running = HashSet::new();
+        for (seq, query) in self.in_flight.iter() {
+            running.insert(query.id);
+            let elapsed = query.started.elapsed();
+            if elapsed < progress.after() {
+                continue;
+            }
+            let (stats, output) = match (
+                self.kademlia.query(&query.id),
+                self.pending.get(&query.id),
+            ) {
+                (Some(running), Some(output)) => {
+                    (running.stats().clone(), output)
+                }
+                _ => continue,
+            };
+            let moving = progress.update(query.id, &stats);
+            output.info(format!(
+                "kad dht: query {} ({}) still running after {:.1?}: asked \
+                 {} peers, {} answered, {} failed, {} to go{}",
+                seq,
+                query.command,
+                elapsed,
+                stats.num_requests(),
+                stats.num_successes(),
+                stats.num_failures(),
+                stats.num_pending(),
+                match (moving, stats.num_successes() + stats.num_failures())
+                {
+                    (true, _) => "",
+                    (false, 0) => " (no answers yet)",
+                    (false, _) => " (no answers since the last report)",
+                }
+            ));
+            self.subscribers.notify(
+                "query_progress",
+                json!({
+                    "id": format!("{:?}", query.id),
+                    "seq": seq,
+                    "command": query.command,
+                    "elapsed_ms": elapsed.as_millis() as u64,
+                    "stats": api::stats_json(&stats),
+                    "moving": moving,
+                }),
+            );
+        }
+        progress.retain(|query| running.contains(query));
+    }
+
+    // Stop query `seq` of CANCEL, and tell whoever started it. Returns
+    // the command that started it, if it was running.
+    pub fn cancel(&mut self, seq: u64) -> Option<String> {
+        let query = self.in_flight.cancel(seq)?;
+        if let Some(mut running) = self.kademlia.query_mut(&query.id) {
+            running.finish();
+        }
+        self.deadlines.finish(&query.id);
+        if let Some(retries) = &mut self.retries {
+            retries.finish(&query.id, None);
+        }
+        let output = self
+            .pending
+            .remove(&query.id)
+            .or_else(|| self.swaps.remove(&query.id).map(|s| s.output))
+            .or_else(|| self.verifications.cancel(&query.id));
+        if let Some(output) = output {
+            output.error(format!(
+                "kad dht: query {} ({}) cancelled",
+                seq, query.command
+            ));
+        }
+        Some(query.command)
+    }
+
+    // The session stats, with the latest counts of the local store.
+    pub fn summary(&mut self) -> &SessionStats {
+        let store = self.kademlia.store_mut();
+        self.stats.records_stored = store.records().count();
+        self.stats.records_rejected = store.rejected;
+        self.stats.records_refused_full = store.refused_full;
+        self.stats.records_evicted = store.evicted;
+        self.stats.records_refused_quota = store.refused_quota;
+        self.stats.inbound_rejected = self.kademlia.rejected;
+        self.stats.inbound_held_back = self.kademlia.held_back;
+        self.stats.replies_dropped = self.kademlia.replies_dropped;
+        self.stats.replies_corrupted = self.kademlia.replies_corrupted;
+        self.stats.replies_tampered = self.kademlia.replies_tampered;
+        if let Some(churn) = &self.store_churn {
+            self.stats.records_overwritten = churn.overwritten;
+            self.stats.records_deleted = churn.deleted;
+        }
+        &self.stats
+    }
+
+    // The fresh record of `key` in the cache (of --cache-size), if there
+    // is one, and how old it is.
+    pub fn cached(&mut self, key: &Key) -> Option<(Record, Duration)> {
+        let cached = self.cache.as_mut()?.get(key);
+        if cached.is_some() {
+            self.stats.gets_cached += 1;
+        }
+        cached
+    }
+
+    // A GET command of `key` (of quorum 1), answered from the cache, if
+    // it can be.
+    pub fn get_cached(&mut self, key: &Key) -> Option<Vec<Message>> {
+        let (record, age) = self.cached(key)?;
+        let name = self.namespace.display(key);
+        let mut messages = Vec::new();
+        match self.codec.decode(
+            key,
+            record.publisher.as_ref(),
+            record.value,
+        ) {
+            Ok(decoded) => {
+                messages.extend(report_decoded("cache", &name, &decoded));
+                messages.push(Message::Info(format!(
+                    "kad dht: got record {:?} {:?} from the cache ({:.1?} \
+                     old)",
+                    name,
+                    String::from_utf8_lossy(&decoded.value),
+                    age
+                )));
+            }
+            Err(err) => messages.push(Message::Error(format!(
+                "kad dht: failed to decode record {:?}: {}",
+                name, err
+            ))),
+        }
+        Some(messages)
+    }
+
+    // A GET of the HTTP api, answered from the cache, if it can be.
+    pub fn api_get_cached(&mut self, key: &Key) -> Option<ApiReply> {
+        let (record, _) = self.cached(key)?;
+        let mut result = QueryResult::GetRecord(Ok(GetRecordOk {
+            records: vec![PeerRecord { peer: None, record }],
+        }));
+        let (errors, _) = self.decode_records(&mut result);
+        if !errors.is_empty() {
+            return Some(ApiReply::Error(errors.join(", ")));
+        }
+        self.namespace.strip(&mut result);
+        Some(ApiReply::Query(result, QueryStats::empty()))
+    }
+
+    // Keep what a GET fetched in the cache (when all the answers agree:
+    // there is no telling which one to keep otherwise). The indexes of
+    // chunked values aren't kept, since only their chunks make the
+    // value.
+    fn cache_result(&mut self, result: &QueryResult) {
+        let (cache, records) = match (&mut self.cache, result) {
+            (Some(cache), QueryResult::GetRecord(Ok(ok))) => {
+                (cache, &ok.records)
+            }
+            _ => return,
+        };
+        let first = match records.first() {
+            Some(first) => &first.record,
+            None => return,
+        };
+        if records.iter().all(|r| r.record.value == first.value)
+            && chunk::parse_index(&first.value).is_none()
+        {
+            cache.insert(first.clone());
+        }
+    }
+
+    // What each namespace of --namespaces holds, and what its queries
+    // did.
+    pub fn namespace_usage(&mut self) -> Vec<NamespaceUsage> {
+        let store = self.kademlia.store_mut();
+        self.namespaces
+            .counts()
+            .map(|(quota, queries)| {
+                let (records, bytes) = store.usage(quota);
+                NamespaceUsage {
+                    quota: quota.clone(),
+                    records,
+                    bytes,
+                    refused: store
+                        .refused_namespace
+                        .get(&quota.name)
+                        .copied()
+                        .unwrap_or(0),
+                    queries,
+                }
+            })
+            .collect()
+    }
+
+    // What the local store holds, records and provider records, for
+    // SNAPSHOT.
+    pub fn snapshot(&mut self) -> Snapshot {
+        let store = self.kademlia.store_mut();
+        Snapshot {
+            records: store.records().map(|r| r.into_owned()).collect(),
+            providers: store.all_providers(),
+        }
+    }
+
+    // Put the records of a snapshot into the local store (the validators
+    // still get to refuse them). Returns how many records, and provider
+    // records, were stored.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> (usize, usize) {
+        let store = self.kademlia.store_mut();
+        let records = snapshot
+            .records
+            .iter()
+            .filter(|&record| store.put(record.clone()).is_ok())
+            .count();
+        let providers = snapshot
+            .providers
+            .iter()
+            .filter(|&provider| {
+                store.add_provider(provider.clone()).is_ok()
+            })
+            .count();
+        (records, providers)
+    }
+
+    // List the records in the local store (for LOCAL), without asking
+    // the network.
+    pub fn list_local(&mut self) -> Vec<Message> {
+        let mut records: Vec<Record> = self
+            .kademlia
+            .store_mut()
+            .records()
+            .map(|r| r.into_owned())
+            .collect();
+        records.sort_by(|a, b| a.key.as_ref().cmp(b.key.as_ref()));
+        let mut messages =
+            vec![Message::Info(format!("{} records", records.len()))];
+        for record in records {
+            messages.push(Message::Info(format!(
+                "{:?}: {}",
+                self.namespace.display(&record.key),
+                describe_record(&record)
+            )));
+        }
+        messages
+    }
+
+    // How `keys` (or, without any, those of the local store) spread over
+    // the peers of the routing table, for ANALYZE.
+    pub fn analyze(&mut self, keys: Option<Vec<String>>) -> Analysis {
+        let keys: Vec<Key> = match keys {
+            Some(keys) => {
+                keys.iter().map(|key| self.namespace.key(key)).collect()
+            }
+            None => self
+                .kademlia
+                .store_mut()
+                .records()
+                .map(|r| r.key.clone())
+                .collect(),
+        };
+        let peers = self
+            .known_peers()
+            .into_iter()
+            .map(|(peer, _)| peer)
+            .collect();
+        analyze::analyze(
+            &keys,
+            self.local_peer_id.clone(),
+            peers,
+            self.replication_factor.get(),
+        )
+    }
+
+    // Show one record of the local store (for LOCAL <key>), decoded the
+    // way GET would.
+    pub fn show_local(&mut self, key: &Key) -> Vec<Message> {
+        let name = self.namespace.display(key);
+        let record = match self.kademlia.store_mut().get(key) {
+            Some(record) => record.into_owned(),
+            None => {
+                return vec![Message::Error(format!(
+                    "No record {:?} here",
+                    name
+                ))]
+            }
+        };
+        let mut messages = vec![Message::Info(format!(
+            "{:?}: {}",
+            name,
+            describe_record(&record)
+        ))];
+        if let Some((chunks, len)) = chunk::parse_index(&record.value) {
+            messages.push(Message::Info(format!(
+                "{:?} is the index of a value of {} bytes, in {} chunks",
+                name, len, chunks
+            )));
+            return messages;
+        }
+        match self.codec.decode(
+            key,
+            record.publisher.as_ref(),
+            record.value,
+        ) {
+            Ok(decoded) => {
+                messages.extend(report_decoded("local", &name, &decoded));
+                messages.push(Message::Info(format!(
+                    "{:?} is {:?}",
+                    name,
+                    String::from_utf8_lossy(&decoded.value)
+                )));
+            }
+            Err(err) => messages.push(Message::Error(format!(
+                "failed to decode record {:?}: {}",
+                name, err
+            ))),
+        }
+        messages
+    }
+
+    // Take in what happened to the connections of the swarm.
+    pub fn observe<T, E: fmt::Display>(
+        &mut self,
+        event: &SwarmEvent<T, E>,
+    ) {
+        self.scores.observe(event);
+        self.connections.observe(event, &mut self.stats);
+        self.count_connection_event(event);
+
+        // Let the websocket clients know about connections
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+            } => {
+                if let Some(liveness) = &mut self.liveness {
+                    liveness.alive(peer_id);
+                }
+                let address = remote_address(endpoint);
+                self.subscribers.notify(
+                    "connection_established",
+                    json!({
+                        "peer_id": peer_id.to_string(),
+                        "address": address.to_string(),
+                        "direction": direction(endpoint),
+                        "connections": num_established.get(),
+                    }),
+                );
+                self.subscribers.notify(
+                    "peer_connected",
+                    json!({
+                        "peer_id": peer_id.to_string(),
+                        "address": address.to_string(),
+                    }),
+                );
+                if endpoint.is_dialer() {
+                    self.finish_dial(peer_id, address);
+                }
+            }
+            SwarmEvent::UnknownPeerUnreachableAddr { address, error } => {
+                self.subscribers.notify(
+                    "dial_failed",
+                    json!({
+                        "address": address.to_string(),
+                        "error": error.to_string(),
+                    }),
+                );
+                if let Some((_, output)) = self.take_dial(address) {
+                    output.error(format!(
+                        "Couldn't dial {}: {}",
+                        address, error
+                    ));
+                }
+            }
+            SwarmEvent::UnreachableAddr {
+                peer_id,
+                address,
+                error,
+                attempts_remaining,
+            } => self.subscribers.notify(
+                "dial_failed",
+                json!({
+                    "peer_id": peer_id.to_string(),
+                    "address": address.to_string(),
+                    "error": error.to_string(),
+                    "attempts_remaining": attempts_remaining,
+                }),
+            ),
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                endpoint,
+                num_established,
+                cause,
+            } => {
+                self.event_streams.emit(NodeEvent::ConnectionClosed {
+                    peer: peer_id.clone(),
+                    address: remote_address(endpoint).clone(),
+                    connections_left: *num_established,
+                    cause: close_cause(cause),
+                    error: cause.to_string(),
+                });
+                self.subscribers.notify(
+                    "connection_closed",
+                    json!({
+                        "peer_id": peer_id.to_string(),
+                        "address": remote_address(endpoint).to_string(),
+                        "direction": direction(endpoint),
+                        "connections": num_established,
+                        "cause": close_cause(cause),
+                        "error": cause.to_string(),
+                    }),
+                );
+                if *num_established == 0 {
+                    self.rtts.remove(peer_id);
+                    self.subscribers.notify(
+                        "peer_disconnected",
+                        json!({ "peer_id": peer_id.to_string() }),
+                    )
+                }
+            }
+            SwarmEvent::IncomingConnectionError {
+                local_addr,
+                send_back_addr,
+                error,
+            } => self.subscribers.notify(
+                "incoming_connection_error",
+                json!({
+                    "local_address": local_addr.to_string(),
+                    "address": send_back_addr.to_string(),
+                    "error": error.to_string(),
+                }),
+            ),
+            SwarmEvent::Dialing(peer_id) => self.subscribers.notify(
+                "dialing",
+                json!({ "peer_id": peer_id.to_string() }),
+            ),
+            SwarmEvent::NewListenAddr(addr)
+                if !self.listen_addrs.contains(addr) =>
+            {
+                self.listen_addrs.push(addr.clone())
+            }
+            SwarmEvent::ExpiredListenAddr(addr) => {
+                self.listen_addrs.retain(|listening| listening != addr)
+            }
+            SwarmEvent::ListenerClosed { addresses, .. } => self
+                .listen_addrs
+                .retain(|listening| !addresses.contains(listening)),
+            _ => {}
+        }
+    }
+
+    fn count_connection_event<T, E>(&mut self, event: &SwarmEvent<T, E>) {
+        let events = &mut self.stats.connection_events;
+        match event {
+            SwarmEvent::ConnectionEstablished { endpoint, .. } => {
+                match endpoint.is_listener() {
+                    true => events.established_inbound += 1,
+                    false => events.established_outbound += 1,
+                }
+            }
+            SwarmEvent::ConnectionClosed { cause, .. } => {
+                match close_cause(cause) {
+                    "io" => events.closed_io += 1,
+                    "idle" => events.closed_idle += 1,
+                    _ => events.closed_handler += 1,
+                }
+            }
+            SwarmEvent::IncomingConnectionError { .. } => {
+                events.incoming_errors += 1
+            }
+            SwarmEvent::Dialing(_) => events.dials += 1,
+            SwarmEvent::UnreachableAddr { .. }
+            | SwarmEvent::UnknownPeerUnreachableAddr { .. } => {
+                events.dial_failures += 1
+            }
+            _ => {}
+        }
+    }
+
+    // A DIAL connected: the peer goes into the routing table, unless it
+    // isn't the one the address named.
+    fn finish_dial(&mut self, peer_id: &PeerId, address: &Multiaddr) {
+        let (expected, output) = match self.take_dial(address) {
+            Some(dial) => dial,
+            None => return,
+        };
+        match expected {
+            Some(expected) if expected != *peer_id => {
+                output.error(format!(
+                    "{} is peer {}, not {}",
+                    address, peer_id, expected
+                ));
+            }
+            _ => {
+                self.kademlia.add_address(peer_id, address.clone());
+                output.info(format!(
+                    "Connected to peer {} at {}",
+                    peer_id, address
+                ));
+            }
+        }
+    }
+
+    // The oldest DIAL of `address` that is still running.
+    fn take_dial(
+        &mut self,
+        address: &Multiaddr,
+    ) -> Option<(Option<PeerId>, Output)> {
+        let dials = self.dials.get_mut(address)?;
+        let dial = dials.pop_front();
+        if dials.is_empty() {
+            self.dials.remove(address);
+        }
+        dial
+    }
+
+    // Our connections, for TOPOLOGY.
+    pub fn edges(&self) -> Vec<Edge> {
+        self.connections
+            .connected()
+            .map(|peer| Edge {
+                from: self.local_peer_id.clone(),
+                to: peer.clone(),
+                rtt: self.rtts.get(peer).copied(),
+            })
+            .collect()
+    }
+
+    // Send every connected peer a sample of the routing table.
+    fn exchange_peers(&mut self) {
+        let known = self.known_peers();
+        let connected =
+            self.connections.connected().cloned().collect::<Vec<_>>();
+        for peer in connected {
+            let sample = self.pex_sample(&known, &peer);
+            self.pex.send_request(&peer, sample);
+        }
+    }
+
+    // A sample of `known` for `to`, starting with ourselves (the peers
+    // that dialed us aren't in our routing table until they say where
+    // they listen).
+    fn pex_sample(
+        &self,
+        known: &[(PeerId, Vec<Multiaddr>)],
+        to: &PeerId,
+    ) -> Sample {
+        let mut sample = pex::sample(known, to);
+        if !self.own_addrs.is_empty() {
+            sample.insert(
+                0,
+                (self.local_peer_id.clone(), self.own_addrs.clone()),
+            );
+        }
+        sample
+    }
+
+    // Add the peers that `from` told us about to the routing table (the
+    // ones the filter allows).
+    fn learn_peers(&mut self, from: &PeerId, sample: Sample) {
+        let known = self
+            .known_peers()
+            .into_iter()
+            .map(|(peer_id, _)| peer_id)
+            .collect::<HashSet<_>>();
+        let mut learned = 0;
+        for (peer_id, addrs) in sample {
+            if peer_id == self.local_peer_id
+                || self.kademlia.is_foreign(&peer_id)
+            {
+                continue;
+            }
+            let mut added = false;
+            for addr in addrs {
+                if !self.filter.read().unwrap().allows(&peer_id, &addr) {
+                    continue;
+                }
+                self.notify_discovered(&peer_id, &addr, "pex");
+                self.kademlia.add_address(&peer_id, addr);
+                added = true;
+            }
+            if added && !known.contains(&peer_id) {
+                self.stats.peers_discovered.insert(peer_id);
+                learned += 1;
+            }
+        }
+        if learned > 0 {
+            Output::Terminal.info(format!(
+                "pex: learned {} new peers from {}",
+                learned, from
+            ));
+        }
+    }
+
+    // List the peers in the routing table, along with their addresses.
+    pub fn known_peers(&mut self) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let mut peers = Vec::new();
+        for bucket in self.kademlia.kbuckets() {
+            for entry in bucket.iter() {
+                peers.push((
+                    entry.node.key.preimage().clone(),
+                    entry.node.value.iter().cloned().collect(),
+                ));
+            }
+        }
+        peers
+    }
+
+    // Take `peer` out of the routing table (for REMOVE_PEER), returning
+    // the addresses it had there. Kademlia doesn't tell about it itself.
+    pub fn remove_peer(
+        &mut self,
+        peer: &PeerId,
+    ) -> Option<Vec<Multiaddr>> {
+        let entry = self.kademlia.remove_peer(peer)?;
+        self.log_routing(RoutingChange::Removed { peer: peer.clone() });
+        Some(entry.node.value.iter().cloned().collect())
+    }
+
+    fn log_routing(&mut self, change: RoutingChange) {
+        let (method, params) = change.notification();
+        self.subscribers.notify(method, params);
+        self.routing_log.push(change);
+    }
+
+    // How full the (non-empty) buckets of the routing table are, the
+    // farthest first.
+    pub fn buckets(&mut self) -> Vec<BucketStats> {
+        let local = self.local_peer_id.clone();
+        let mut buckets: Vec<_> = self
+            .kademlia
+            .kbuckets()
+            .map(|bucket| {
+                let mut stats = BucketStats {
+                    index: 0,
+                    connected: 0,
+                    disconnected: 0,
+                    pending: bucket.has_pending(),
+                };
+                for entry in bucket.iter() {
+                    stats.index = routing::bucket_index(
+                        &local,
+                        entry.node.key.preimage(),
+                    );
+                    match entry.status {
+                        NodeStatus::Connected => stats.connected += 1,
+                        NodeStatus::Disconnected => {
+                            stats.disconnected += 1
+                        }
+                    }
+                }
+                stats
+            })
+            .collect();
+        buckets.sort_by_key(|bucket| std::cmp::Reverse(bucket.index));
+        buckets
+    }
+
+    // The value stored under `key` in the local store, for a peer that
+    // fetches it. A chunked value is put back together, if all of its
+    // chunks happen to be stored here too.
+    pub fn local_value(&mut self, key: &Key) -> Option<Vec<u8>> {
+        let store = self.kademlia.store_mut();
+        let value = store.get(key)?.value.clone();
+        let (chunks, len) = match chunk::parse_index(&value) {
+            Some(index) => index,
+            None => return Some(value),
+        };
+        let mut whole = Vec::with_capacity(len);
+        for i in 0..chunks {
+            match store.get(&chunk::chunk_key(key, i)) {
+                Some(chunk) => whole.extend_from_slice(&chunk.value),
+                None => return Some(value),
+            }
+        }
+        Some(whole)
+    }
+
+    // Undo what was done to the fetched values before they were stored
+    // (except for the index records of chunked values, whose chunks get
+    // decoded as a whole). Records that can't be decoded are taken out
+    // of the result, and what went wrong with them is returned instead,
+    // along with what else there is to know about the others (their
+    // values stay in the result), record for record.
+    fn decode_records(
+        &mut self,
+        result: &mut QueryResult,
+    ) -> (Vec<String>, Vec<(String, Decoded)>) {
+        let mut errors = Vec::new();
+        let mut decoded_records = Vec::new();
+        let ok = match result {
+            QueryResult::GetRecord(Ok(ok)) => ok,
+            _ => return (errors, decoded_records),
+        };
+        // The peers that sent records that don't decode
+        let mut bad_peers = Vec::new();
+        ok.records.retain_mut(|PeerRecord { peer, record }| {
+            let key = self.namespace.display(&record.key);
+            if chunk::parse_index(&record.value).is_some() {
+                decoded_records.push((key, Decoded::plain(Vec::new())));
+                return true;
+            }
+            match self.codec.decode(
+                &record.key,
+                record.publisher.as_ref(),
+                std::mem::take(&mut record.value),
+            ) {
+                Ok(mut decoded) => {
+                    record.value = std::mem::take(&mut decoded.value);
+                    decoded_records.push((key, decoded));
+                    true
+                }
+                Err(err) => {
+                    errors.push(format!(
+                        "failed to decode record {:?}: {}",
+                        key, err
+                    ));
+                    bad_peers.extend(peer.clone());
+                    false
+                }
+            }
+        });
+        for peer in bad_peers {
+            self.scores.penalize(&peer, Offense::BadRecord);
+        }
+        (errors, decoded_records)
+    }
+
+    // Tell the websocket clients that a query finished, and about any
+    // records it fetched.
+    fn notify_query(
+        &mut self,
+        id: QueryId,
+        result: &QueryResult,
+        stats: &QueryStats,
+        hops: &Hops,
+    ) {
+        let (kind, ok) = query_kind(result);
+        if let Some(key) = query_key(result) {
+            self.namespaces.count(&key, result);
+        }
+
+        // A query that finished before `track_queries` saw it running
+        // (like a GET that the local store answers) starts as it ends
+        if !self.queries.remove(&id) && self.subscribers.is_listening() {
+            self.notify_started(id, kind, None);
+        }
+
+        if let QueryResult::GetRecord(Ok(ok)) = result {
+            for PeerRecord { peer, record } in &ok.records {
+                let provenance = hops.provenance(peer.as_ref());
+                self.subscribers.notify(
+                    "record_received",
+                    json!({
+                        "key": self.namespace.display(&record.key),
+                        "value": String::from_utf8_lossy(&record.value),
+                        "from": peer.as_ref().map(|p| p.to_string()),
+                        "source": provenance.source(),
+                        "hops": provenance.hops(),
+                    }),
+                );
+            }
+        }
+        self.event_streams.emit(NodeEvent::QueryCompleted {
+            id,
+            kind,
+            ok,
+            duration: stats.duration().unwrap_or_default(),
+        });
+        self.subscribers.notify(
+            "query_finished",
+            json!({
+                "id": format!("{:?}", id),
+                "kind": kind,
+                "ok": ok,
+                "stats": api::stats_json(stats),
+            }),
+        );
+    }
+
+    fn remember_query(
+        &mut self,
+        result: &QueryResult,
+        stats: &QueryStats,
+    ) {
+        let (kind, ok) = query_kind(result);
+        if self.recent_queries.len() == RECENT_QUERIES {
+            self.recent_queries.pop_front();
+        }
+        self.recent_queries.push_back(FinishedQuery {
+            kind,
+            ok,
+            duration: stats.duration().unwrap_or_default(),
+            finished: Instant::now(),
+        });
+    }
+
+    // Warn about a query that took longer than --slow-query.
+    fn check_slow(
+        &mut self,
+        id: QueryId,
+        result: &QueryResult,
+        stats: &QueryStats,
+    ) {
+        let took = stats.duration().unwrap_or_default();
+        if self.slow_query.is_none_or(|limit| took <= limit) {
+            return;
+        }
+        self.stats.slow_queries += 1;
+        let (kind, ok) = query_kind(result);
+        let key =
+            query_key(result).map(|key| self.namespace.display(&key));
+        Output::Terminal.info(format!(
+            "Slow query {:?}: {}{} took {:.1?} ({} requests, {} failed)",
+            id,
+            kind,
+            key.as_deref()
+                .map(|key| format!(" of {}", key))
+                .unwrap_or_default(),
+            took,
+            stats.num_requests(),
+            stats.num_failures()
+        ));
+        self.subscribers.notify(
+            "slow_query",
+            json!({
+                "id": format!("{:?}", id),
+                "kind": kind,
+                "key": key,
+                "ok": ok,
+                "stats": api::stats_json(stats),
+            }),
+        );
+    }
+
+    // The round trip time to `peer`, if it is connected and answered a
+    // ping.
+    pub fn rtt(&self, peer: &PeerId) -> Option<Duration> {
+        self.rtts.get(peer).copied()
+    }
+
+    // Make a span of a finished query, for --otlp-endpoint.
+    fn trace_query(&self, result: &QueryResult, stats: &QueryStats) {
+        let tracer = match &self.tracer {
+            Some(tracer) => tracer,
+            None => return,
+        };
+        let record_peers = |records: &[PeerRecord]| {
+            records
+                .iter()
+                .filter_map(|record| record.peer.clone())
+                .collect::<Vec<_>>()
+        };
+        let (key, peers, error) = match result {
+            QueryResult::GetRecord(Ok(ok)) => (
+                ok.records.first().map(|record| {
+                    self.namespace.display(&record.record.key)
+                }),
+                record_peers(&ok.records),
+                None,
+            ),
+            QueryResult::GetRecord(Err(err)) => {
+                let (peers, error) = match err {
+                    GetRecordError::NotFound { closest_peers, .. } => {
+                        (closest_peers.clone(), "not found")
+                    }
+                    GetRecordError::QuorumFailed { records, .. } => {
+                        (record_peers(records), "quorum failed")
+                    }
+                    GetRecordError::Timeout { records, .. } => {
+                        (record_peers(records), "timed out")
+                    }
+                };
+                (
+                    Some(self.namespace.display(err.key())),
+                    peers,
+                    Some(error),
+                )
+            }
+            QueryResult::PutRecord(Ok(ok)) => {
+                (Some(self.namespace.display(&ok.key)), Vec::new(), None)
+            }
+            QueryResult::PutRecord(Err(err)) => {
+                let (peers, error) = match err {
+                    PutRecordError::QuorumFailed { success, .. } => {
+                        (success.clone(), "quorum failed")
+                    }
+                    PutRecordError::Timeout { success, .. } => {
+                        (success.clone(), "timed out")
+                    }
+                };
+                (
+                    Some(self.namespace.display(err.key())),
+                    peers,
+                    Some(error),
+                )
+            }
+            QueryResult::GetClosestPeers(Ok(GetClosestPeersOk {
+                key,
+                peers,
+            }))
+            | QueryResult::GetClosestPeers(Err(
+                GetClosestPeersError::Timeout { key, peers },
+            )) => (
+                Some(match PeerId::from_bytes(key.clone()) {
+                    Ok(peer_id) => peer_id.to_string(),
+                    Err(_) => self.namespace.display(&Key::new(key)),
+                }),
+                peers.clone(),
+                result_error(result),
+            ),
+            QueryResult::Bootstrap(Ok(BootstrapOk { peer, .. }))
+            | QueryResult::Bootstrap(Err(BootstrapError::Timeout {
+                peer,
+                ..
+            })) => {
+                (Some(peer.to_string()), Vec::new(), result_error(result))
+            }
+            _ => (None, Vec::new(), result_error(result)),
+        };
+        tracer.record(QuerySpan {
+            kind: query_kind(result).0,
+            key,
+            peers,
+            error: error.map(str::to_string),
+            requests: stats.num_requests(),
+            successes: stats.num_successes(),
+            failures: stats.num_failures(),
+            duration: stats.duration().unwrap_or_default(),
+        });
+    }
+
+    // Tell the websocket clients about the queries that started since
+    // the last time (however they were started).
+    pub fn track_queries(&mut self) {
+        if !self.subscribers.is_listening() {
+            return;
+        }
+        let mut running = HashSet::new();
+        let mut started = Vec::new();
+        for query in self.kademlia.iter_queries() {
+            let id = query.id();
+            running.insert(id);
+            if self.queries.contains(&id) {
+                continue;
+            }
+            let (kind, key) = match query.info() {
+                QueryInfo::Bootstrap { .. } => ("bootstrap", None),
+                QueryInfo::GetClosestPeers { key } => (
+                    "get_closest_peers",
+                    Some(String::from_utf8_lossy(key).into_owned()),
+                ),
+                QueryInfo::GetProviders { key, .. } => {
+                    ("get_providers", Some(self.namespace.display(key)))
+                }
+                QueryInfo::AddProvider { key, .. } => {
+                    ("start_providing", Some(self.namespace.display(key)))
+                }
+                QueryInfo::PutRecord { record, .. } => (
+                    "put_record",
+                    Some(self.namespace.display(&record.key)),
+                ),
+                QueryInfo::GetRecord { key, .. } => {
+                    ("get_record", Some(self.namespace.display(key)))
+                }
+            };
+            started.push((id, kind, key));
+        }
+        self.queries = running;
+        for (id, kind, key) in started {
+            self.notify_started(id, kind, key);
+        }
+    }
+
+    // Tell the websocket clients (and the event log) how caching the
+    // records GETs found went.
+    fn notify_cached(&mut self) {
+        for outcome in self.kademlia.cache_outcomes() {
+            self.subscribers.notify(
+                "record_cached",
+                json!({
+                    "key": self.namespace.display(&outcome.key),
+                    "peer": outcome.peer.map(|peer| peer.to_string()),
+                    "ok": outcome.error.is_none(),
+                    "error": outcome.error,
+                }),
+            );
+        }
+    }
+
+    // Tell the event streams about the records that went into the store.
+    fn notify_stored(&mut self) {
+        for (key, publisher) in self.kademlia.store_mut().take_stored() {
+            self.event_streams.emit(NodeEvent::RecordStored {
+                key: self.namespace.display(&key),
+                publisher,
+            });
+        }
+    }
+
+    // Subscribe to the events of the node. Events are buffered for each
+    // subscriber (EVENT_BUFFER of them), and one that falls behind gets a
+    // `Lagged` event for the ones it missed. Connection events only come
+    // from the swarm events passed to `observe`, which whoever polls the
+    // swarm has to do.
+    pub fn events(
+        &mut self,
+    ) -> impl Stream<Item = NodeEvent> + Send + Unpin {
+        self.kademlia.store_mut().track_stored();
+        self.event_streams.subscribe()
+    }
+
+    fn notify_started(
+        &mut self,
+        id: QueryId,
+        kind: &str,
+        key: Option<String>,
+    ) {
+        self.subscribers.notify(
+            "query_started",
+            json!({ "id": format!("{:?}", id), "kind": kind, "key": key }),
+        );
+    }
+
+    // Add a peer that was found on the local network (by `via`) to the
+    // routing table.
+    pub fn discovered(
+        &mut self,
+        peer_id: PeerId,
+        addr: Multiaddr,
+        via: &str,
+    ) {
+        // Don't even remember peers that we aren't allowed to talk to, or
+        // that are in another DHT.
+        if !self.filter.read().unwrap().allows(&peer_id, &addr)
+            || self.kademlia.is_foreign(&peer_id)
+        {
+            return;
+        }
+        self.stats.peers_discovered.insert(peer_id.clone());
+        self.notify_discovered(&peer_id, &addr, via);
+        self.kademlia.add_address(&peer_id, addr);
+    }
+
+    // Tell the websocket clients about a peer we learned of (and note it
+    // in the address book).
+    pub fn notify_discovered(
+        &mut self,
+        peer_id: &PeerId,
+        addr: &Multiaddr,
+        via: &str,
+    ) {
+        self.book.add(peer_id, addr.clone(), via);
+        self.event_streams.emit(NodeEvent::PeerDiscovered {
+            peer: peer_id.clone(),
+            address: addr.clone(),
+            via: via.into(),
+        });
+        self.subscribers.notify(
+            "peer_discovered",
+            json!({
+                "peer_id": peer_id.to_string(),
+                "address": addr.to_string(),
+                "via": via,
+            }),
+        );
+    }
+}
+
+// How many finished queries `recent_queries` keeps.
+// How a query of an MGET (or MPUT) went, for its report.
+fn batch_status(
+    result: &QueryResult,
+    errors: &[String],
+    stats: &QueryStats,
+) -> Status {
+    let (ok, detail) = match result {
+        // The records hold the decoded values by now
+        QueryResult::GetRecord(Ok(GetRecordOk { records })) => {
+            match records.first() {
+                Some(PeerRecord { record, .. }) => (
+                    true,
+                    format!(
+                        "{:?}",
+                        String::from_utf8_lossy(&record.value)
+                    ),
+                ),
+                None => (false, errors.join(", ")),
+            }
+        }
+        QueryResult::GetRecord(Err(GetRecordError::NotFound {
+            ..
+        })) => (false, "not found".to_string()),
+        QueryResult::GetRecord(Err(GetRecordError::QuorumFailed {
+            records,
+            quorum,
+            ..
+        })) => (
+            false,
+            format!("{} of {} records found", records.len(), quorum),
+        ),
+        QueryResult::PutRecord(Ok(_)) => (true, "stored".to_string()),
+        QueryResult::PutRecord(Err(PutRecordError::QuorumFailed {
+            success,
+            quorum,
+            ..
+        })) => (
+            false,
+            format!("{} of {} peers took it", success.len(), quorum),
+        ),
+        QueryResult::GetRecord(Err(GetRecordError::Timeout {
+            ..
+        }))
+        | QueryResult::PutRecord(Err(PutRecordError::Timeout {
+            ..
+        })) => (false, "timed out".to_string()),
+        result => (false, format!("{:?}", result)),
+    };
+    Status {
+        ok,
+        detail,
+        took: stats.duration().filter(|_| stats.num_requests() > 0),
+    }
+}
+
+// The address of the other end of a connection.
+fn remote_address(endpoint: &ConnectedPoint) -> &Multiaddr {
+    match endpoint {
+        ConnectedPoint::Dialer { address } => address,
+        ConnectedPoint::Listener { send_back_addr, .. } => send_back_addr,
+    }
+}
+
+// The addresses to tell peers about: the external ones first, since
+// behind a NAT the ones we listen on are private, and peers try them in
+// order.
+fn advertised_addrs(params: &impl PollParameters) -> Vec<Multiaddr> {
+    let mut addrs: Vec<Multiaddr> = params.external_addresses().collect();
+    for addr in params.listened_addresses() {
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    addrs
+}
+
+fn direction(endpoint: &ConnectedPoint) -> &'static str {
+    match endpoint.is_listener() {
+        true => "inbound",
+        false => "outbound",
+    }
+}
+
+// Why a connection closed: it failed ("io"), went idle ("idle"), or one
+// of the protocols gave up on it ("handler").
+fn close_cause<E>(
+    cause: &ConnectionError<NodeHandlerWrapperError<E>>,
+) -> &'static str {
+    match cause {
+        ConnectionError::IO(_) => "io",
+        ConnectionError::Handler(
+            NodeHandlerWrapperError::KeepAliveTimeout,
+        ) => "idle",
+        ConnectionError::Handler(NodeHandlerWrapperError::Handler(_)) => {
+            "handler"
+        }
+    }
+}
+
+const RECENT_QUERIES: usize = 20;
+
+// A query that finished, and how it went.
+#[derive(Debug, Clone)]
+pub struct FinishedQuery {
+    pub kind: &'static str,
+    pub ok: bool,
+    pub duration: Duration,
+    pub finished: Instant,
+}
+
+// Why a query failed, as far as a span goes.
+fn result_error(result: &QueryResult) -> Option<&'static str> {
+    match query_kind(result).1 {
+        true => None,
+        false => Some("failed"),
+    }
+}
+
+// What kind of query a result is of, and whether it succeeded.
+fn query_kind(result: &QueryResult) -> (&'static str, bool) {
+    match result {
+        QueryResult::Bootstrap(r) => ("bootstrap", r.is_ok()),
+        QueryResult::GetClosestPeers(r) => {
+            ("get_closest_peers", r.is_ok())
+        }
+        QueryResult::GetProviders(r) => ("get_providers", r.is_ok()),
+        QueryResult::StartProviding(r) => ("start_providing", r.is_ok()),
+        QueryResult::RepublishProvider(r) => {
+            ("republish_provider", r.is_ok())
+        }
+        QueryResult::GetRecord(r) => ("get_record", r.is_ok()),
+        QueryResult::PutRecord(r) => ("put_record", r.is_ok()),
+        QueryResult::RepublishRecord(r) => ("republish_record", r.is_ok()),
+    }
+}
+
+// The key a query was about, if it was about one.
+fn query_key(result: &QueryResult) -> Option<Key> {
+    match result {
+        QueryResult::GetRecord(Ok(ok)) => {
+            ok.records.first().map(|r| r.record.key.clone())
+        }
+        QueryResult::GetRecord(Err(err)) => Some(err.key().clone()),
+        QueryResult::PutRecord(Ok(ok)) => Some(ok.key.clone()),
+        QueryResult::PutRecord(Err(err)) => Some(err.key().clone()),
+        QueryResult::GetProviders(Ok(ok)) => Some(ok.key.clone()),
+        QueryResult::GetProviders(Err(err)) => Some(err.key().clone()),
+        QueryResult::StartProviding(Ok(ok)) => Some(ok.key.clone()),
+        QueryResult::StartProviding(Err(err)) => Some(err.key().clone()),
+        _ => None,
+    }
+}
+
+// The size, publisher and expiry of a record.
+fn describe_record(record: &Record) -> String {
+    let publisher = match &record.publisher {
+        Some(publisher) => format!("published by {}", publisher),
+        None => "no publisher".to_string(),
+    };
+    let expires = match record.expires {
+        Some(at) => format!(
+            "expires in {:.0?}",
+            at.saturating_duration_since(Instant::now())
+        ),
+        None => "never expires".to_string(),
+    };
+    format!("{} bytes, {}, {}", record.value.len(), publisher, expires)
+}
+
+// Say which version a fetched record is at, and who signed it (or what is
+// wrong with its signature).
+fn report_decoded(
+    source: &str,
+    key: &str,
+    decoded: &Decoded,
+) -> Vec<Message> {
+    let mut messages = Vec::new();
+    if let Some(version) = decoded.version {
+        messages.push(Message::Info(format!(
+            "{}: record {:?} is at version {}",
+            source, key, version
+        )));
+    }
+    match &decoded.signature {
+        Signature::Unsigned => {}
+        Signature::Valid(signer) => messages.push(Message::Info(format!(
+            "{}: record {:?} is signed by {}",
+            source, key, signer
+        ))),
+        Signature::Invalid(why) => messages.push(Message::Error(format!(
+            "{}: record {:?} has a bad signature: {}",
+            source, key, why
+        ))),
+    }
+    messages
+}
+
+// Start implementing the necessary handlers for `MyBehavior`,
+// which includes handlers for both mDNS and Kademlia
+impl<B: Behaviour> NetworkBehaviourEventProcess<MdnsEvent>
+    for MyBehaviorWith<B>
+{
+    // Called when `mdns` (in a MyBehavior instance) produces an event.
+    fn inject_event(&mut self, event: MdnsEvent) {
+        // If the event is a discovery event (that is, if the event
+        // represents (a) peer(s) getting discovered, then do something with
+        // the IDs of the discovered peers). In this case, "something" is
+        // adding the addresses of those peers to the kademila dht (which
+        // is necessary for the dht to work properly).
+        if let MdnsEvent::Discovered(list_of_peers) = event {
+            // for every peer in the list of the peers that were just
+            // discovered, add that peer's identity information to the
+            // kad dht's list of identities.
+            for (peer_id, multiaddr) in list_of_peers {
+                // println!(
+                //     "mDNS: discovered peer {:?} {:?}",
+                //     &peer_id, &multiaddr
+                // );
+                self.discovered(peer_id, multiaddr, "mdns");
+            }
+        }
+    }
+}
+
+impl<B: Behaviour>
+    NetworkBehaviourEventProcess<
+        RequestResponseEvent<Key, Option<Vec<u8>>>,
+    > for MyBehaviorWith<B>
+{
+    // Called when `fetch` produces an event: a peer wants a value from
+    // us, or a peer sent us the value we asked for.
+    fn inject_event(
+        &mut self,
+        event: RequestResponseEvent<Key, Option<Vec<u8>>>,
+    ) {
+        match event {
+            RequestResponseEvent::Message {
+                message:
+                    RequestResponseMessage::Request { request, channel },
+                ..
+            } => {
+                let value = self.local_value(&request);
+                self.fetch.send_response(channel, value);
+            }
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+            } => {
+                let holding = match &response {
+                    Some(value) => Holding::Holds(value.clone()),
+                    None => Holding::Missing,
+                };
+                if self.verifications.answer(&request_id, holding) {
+                    return;
+                }
+                let (key, output) = match self.fetching.remove(&request_id)
+                {
+                    Some(fetching) => fetching,
+                    None => return,
+                };
+                // Whoever serves the value didn't necessarily publish it,
+                // so only the signature itself can be checked
+                let decoded = response
+                    .map(|value| self.codec.decode(&key, None, value));
+                let key = self.namespace.display(&key);
+                match decoded {
+                    Some(Ok(decoded)) => {
+                        output.info(format!(
+                            "fetch: got record {:?} {:?} ({} bytes) from {}",
+                            key,
+                            String::from_utf8_lossy(&decoded.value),
+                            decoded.value.len(),
+                            peer
+                        ));
+                        output.show_all(report_decoded(
+                            "fetch", &key, &decoded,
+                        ));
+                    }
+                    Some(Err(err)) => {
+                        self.scores.penalize(&peer, Offense::BadRecord);
+                        output.error(format!(
+                            "fetch: failed to decode record {:?}: {}",
+                            key, err
+                        ))
+                    }
+                    None => output.error(format!(
+                        "fetch: {} has no record {:?}",
+                        peer, key
+                    )),
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                self.scores.penalize(&peer, Offense::FailedRequest);
+                let holding = Holding::Unreachable(format!("{:?}", error));
+                if self.verifications.answer(&request_id, holding) {
+                    return;
+                }
+                if let Some((_, output)) =
+                    self.fetching.remove(&request_id)
+                {
+                    output.error(format!(
+                        "fetch: failed to fetch from {}: {:?}",
+                        peer, error
+                    ));
+                }
+            }
+            // The peer that asked will find out on its own
+            RequestResponseEvent::InboundFailure { .. } => {}
+        }
+    }
+}
+
+impl<B: Behaviour>
+    NetworkBehaviourEventProcess<RequestResponseEvent<String, Ack>>
+    for MyBehaviorWith<B>
+{
+    // Called when `messaging` produces an event: a peer sent us a
+    // message, or acknowledged ours.
+    fn inject_event(&mut self, event: RequestResponseEvent<String, Ack>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request { request, channel },
+            } => {
+                Output::Terminal
+                    .info(format!("msg: from {}: {}", peer, request));
+                self.subscribers.notify(
+                    "message_received",
+                    json!({
+                        "peer_id": peer.to_string(),
+                        "text": request,
+                    }),
+                );
+                self.messaging.send_response(channel, Ack);
+            }
+            RequestResponseEvent::Message {
+                message:
+                    RequestResponseMessage::Response { request_id, .. },
+                ..
+            } => {
+                if let Some((peer, sent, output)) =
+                    self.sending.remove(&request_id)
+                {
+                    output.info(format!(
+                        "msg: {} got it (after {:.1?})",
+                        peer,
+                        sent.elapsed()
+                    ));
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                if let Some((_, _, output)) =
+                    self.sending.remove(&request_id)
+                {
+                    output.error(format!(
+                        "msg: failed to send to {}: {:?}",
+                        peer, error
+                    ));
+                }
+            }
+            // The peer that sent it will find out on its own
+            RequestResponseEvent::InboundFailure { .. } => {}
+        }
+    }
+}
+
+impl<B: Behaviour> NetworkBehaviourEventProcess<PingEvent>
+    for MyBehaviorWith<B>
+{
+    // Called when `ping` produces an event: remember how long the round
+    // trip took (and that the peer is still there).
+    fn inject_event(&mut self, event: PingEvent) {
+        if event.result.is_ok() {
+            if let Some(liveness) = &mut self.liveness {
+                liveness.alive(&event.peer);
+            }
+        }
+        if let Ok(PingSuccess::Ping { rtt }) = event.result {
+            self.kademlia.latencies.ping(&event.peer, rtt);
+            self.rtts.insert(event.peer, rtt);
+        }
+    }
+}
+
+impl<B: Behaviour> NetworkBehaviourEventProcess<Confirmed>
+    for MyBehaviorWith<B>
+{
+    // Called when enough peers saw us at the same address, which the
+    // swarm now takes for an external one.
+    fn inject_event(&mut self, event: Confirmed) {
+        Output::Terminal.info(format!(
+            "Observed address: reachable at {} (seen by {} peers)",
+            event.address, event.peers
+        ));
+        self.subscribers.notify(
+            "external_address",
+            json!({
+                "address": event.address.to_string(),
+                "peers": event.peers,
+            }),
+        );
+    }
+}
+
+impl<B: Behaviour>
+    NetworkBehaviourEventProcess<RequestResponseEvent<Sample, Sample>>
+    for MyBehaviorWith<B>
+{
+    // Called when `pex` produces an event: a peer sent us a sample of its
+    // routing table (and gets one back), or answered ours.
+    fn inject_event(
+        &mut self,
+        event: RequestResponseEvent<Sample, Sample>,
+    ) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request { request, channel },
+            } => {
+                self.learn_peers(&peer, request);
+                let known = self.known_peers();
+                let sample = self.pex_sample(&known, &peer);
+                self.pex.send_response(channel, sample);
+            }
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Response { response, .. },
+            } => self.learn_peers(&peer, response),
+            // Peers that don't speak it (or went away) just miss out, and
+            // there's another round soon
+            RequestResponseEvent::OutboundFailure { .. }
+            | RequestResponseEvent::InboundFailure { .. } => {}
+        }
+    }
+}
+
+impl<B: Behaviour>
+    NetworkBehaviourEventProcess<RequestResponseEvent<Chunk, ChunkAck>>
+    for MyBehaviorWith<B>
+{
+    // Called when `files` produces an event: a peer sent us a piece of a
+    // file, or answered one of ours.
+    fn inject_event(
+        &mut self,
+        event: RequestResponseEvent<Chunk, ChunkAck>,
+    ) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request { request, channel },
+            } => {
+                let ack = self.file_transfers.receive(&peer, request);
+                self.files.send_response(channel, ack);
+            }
+            RequestResponseEvent::Message {
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => {
+                self.file_transfers.acked(
+                    &mut self.files,
+                    &request_id,
+                    response,
+                );
+            }
+            RequestResponseEvent::OutboundFailure {
+                request_id,
+                error,
+                ..
+            } => {
+                self.file_transfers.failed(&request_id, &error);
+            }
+            // The peer that sent it will find out on its own
+            RequestResponseEvent::InboundFailure { .. } => {}
+        }
+    }
+}
+
+// The events of the extension go to the subscribers.
+impl<B: Behaviour> NetworkBehaviourEventProcess<ExtensionEvent>
+    for MyBehaviorWith<B>
+{
+    fn inject_event(&mut self, event: ExtensionEvent) {
+        self.subscribers.notify(&event.name, event.params);
+    }
+}
+
+// Disconnect has nothing to tell.
+impl<B: Behaviour> NetworkBehaviourEventProcess<Infallible>
+    for MyBehaviorWith<B>
+{
+    fn inject_event(&mut self, event: Infallible) {
+        match event {}
+    }
+}
+
+impl<B: Behaviour> NetworkBehaviourEventProcess<KademliaEvent>
+    for MyBehaviorWith<B>
+{
+    // Called when `kademila` (in MyBehavior) produces an event. The time
+    // of a timed command goes after its result (a CAS is timed until its
+    // PUT is done, see `kademlia_event`).
+    fn inject_event(&mut self, message: KademliaEvent) {
+        let timed = match &message {
+            KademliaEvent::QueryResult { id, .. }
+                if self.swaps.contains_key(id) =>
+            {
+                None
+            }
+            KademliaEvent::QueryResult { id, result, stats } => {
+                self.timings.finish(id, result, stats)
+            }
+            _ => None,
+        };
+        self.kademlia_event(message);
+        if let Some((output, time)) = timed {
+            output.info(time);
+        }
+    }
+}
+
+impl<B: Behaviour> MyBehaviorWith<B> {
+    fn kademlia_event(&mut self, message: KademliaEvent) {
+        // Kademlia DHTs have a few different "messages." A message is just
+        // the type of action that is being acted on the dht, such as getting
+        // a record or storing a record. Simply put, its just an event.
+        //
+        // If the event is a `QueryResult`, do something.
+        // A `QueryResult` is an event representing when a query to the
+        // dht has produced a result. Check out libp2p::kad::KademliaEvent
+        // for all the variants. Right now, we only care about the QueryResult
+        // event because that is all that this simple dht needs to support:
+        // putting and retrieving records. The other events are about
+        // the routing table, and only get logged.
+        if let Some(change) = RoutingChange::from_event(&message) {
+            self.log_routing(change);
+            return;
+        }
+        if let KademliaEvent::QueryResult { id, result, stats } = message {
+            self.trace_query(&result, &stats);
+            self.remember_query(&result, &stats);
+            self.check_slow(id, &result, &stats);
+            let hops = self.kademlia.paths.finish(&id);
+            // The api requests that joined this GET (see --coalesce-gets)
+            let joined = self.coalescer.finish(&id);
+            if self.pending.contains_key(&id)
+                || self.api_pending.contains_key(&id)
+            {
+                self.cache_result(&result);
+            }
+            if let QueryResult::PutRecord(Ok(PutRecordOk { key })) =
+                &result
+            {
+                self.put_done(key);
+            }
+            if let QueryResult::Bootstrap(Ok(BootstrapOk {
+                num_remaining: 0,
+                ..
+            })) = result
+            {
+                self.bootstrapped = true;
+            }
+            // The result here is an enum
+            // with its own variants representing the types of query results
+            // that are possible, such as the query being a PUT or a GET.
+            // There are many things that you can do with a kad dht,
+            // and queries are simply one of those things
+            // (and there are different types of them!).
+
+            // The queries of a chunked value only count once they are
+            // all done (and it is the whole value that gets decoded)
+            if self.transfers.owns(&id) {
+                self.notify_query(id, &result, &stats, &hops);
+                if let Some(transfer) = self.transfers.finish(id, result) {
+                    self.finish_transfer(transfer);
+                }
+                return;
+            }
+
+            // Republished records are only reported as a whole
+            if self.republisher.owns(&id) {
+                if let Some((refreshed, failed)) =
+                    self.republisher.finish(id, &result)
+                {
+                    Output::Terminal.info(format!(
+                        "republish: refreshed {} records ({} failed)",
+                        refreshed, failed
+                    ));
+                }
+                return;
+            }
+
+            // So are the renewals of --renew
+            if let Some(key) =
+                self.expiry.as_mut().and_then(|expiry| expiry.finish(&id))
+            {
+                let name = self.namespace.display(&key);
+                let error = match &result {
+                    QueryResult::PutRecord(Err(err)) => {
+                        Output::Terminal.error(format!(
+                            "expiry: failed to store {:?} again: {:?}",
+                            name, err
+                        ));
+                        Some(format!("{:?}", err))
+                    }
+                    _ => None,
+                };
+                self.subscribers.notify(
+                    "record_renewed",
+                    json!({
+                        "key": name,
+                        "ok": error.is_none(),
+                        "error": error,
+                    }),
+                );
+                return;
+            }
+
+            // The peer info is published quietly, unless that fails
+            if self.announcer.finish(&id) {
+                if let QueryResult::PutRecord(Err(err)) = &result {
+                    Output::Terminal.error(format!(
+                        "peer info: failed to publish: {:?}",
+                        err
+                    ));
+                }
+                return;
+            }
+
+            // So are the beacon records, and fetching them is part of
+            // joining
+            if self.beacon.as_mut().is_some_and(|b| b.finish(&id)) {
+                if let QueryResult::PutRecord(Err(err)) = &result {
+                    Output::Terminal.error(format!(
+                        "beacon: failed to publish: {:?}",
+                        err
+                    ));
+                }
+                return;
+            }
+            if self.beacon_join.as_mut().is_some_and(|j| j.finish(&id)) {
+                self.finish_beacon(result);
+                return;
+            }
+
+            if self.finish_swap_lookup(id, &result, &stats) {
+                return;
+            }
+
+            // Whether the query of a command was cancelled (and what it
+            // found is dropped). A bootstrap goes on with its next step
+            // (under the same id), so that gets finished too, until there
+            // are no more.
+            let last = !matches!(
+                &result,
+                QueryResult::Bootstrap(Ok(BootstrapOk { num_remaining, .. }))
+                    if *num_remaining > 0
+            ) && !matches!(
+                &result,
+                QueryResult::Bootstrap(Err(BootstrapError::Timeout {
+                    num_remaining: Some(remaining),
+                    ..
+                })) if *remaining > 0
+            );
+            let cancelled = self.in_flight.finish(&id, last);
+
+            // A LOOKUP_PEER reads the record as it is (it isn't encoded)
+            if let Some((peer, output)) = self.peer_lookups.remove(&id) {
+                if !cancelled {
+                    self.finish_lookup(peer, result, output);
+                }
+                return;
+            }
+
+            // A VERIFY asks the closest peers it found for the record
+            if self.verifications.owns(&id) {
+                self.notify_query(id, &result, &stats, &hops);
+                let peers = match &result {
+                    QueryResult::GetClosestPeers(Ok(ok)) => &ok.peers[..],
+                    QueryResult::GetClosestPeers(Err(
+                        GetClosestPeersError::Timeout { peers, .. },
+                    )) => &peers[..],
+                    _ => &[],
+                };
+                if let Some((verification, key)) =
+                    self.verifications.found(&id, peers)
+                {
+                    for (i, peer) in peers.iter().enumerate() {
+                        let request =
+                            self.fetch.send_request(peer, key.clone());
+                        self.verifications.add_request(
+                            verification,
+                            i,
+                            request,
+                        );
+                    }
+                }
+                return;
+            }
+
+            // So does the update of an index (or a LIST)
+            if let Some(pending) = self.key_index.finish(&id) {
+                if !cancelled {
+                    self.finish_index(pending, result);
+                }
+                return;
+            }
+
+            let mut result = result;
+            let (errors, decoded) = self.decode_records(&mut result);
+
+            // Neither are the lookups of the auditor
+            if self.finish_audit_lookup(id, &result) {
+                return;
+            }
+
+            // Nobody waits for a GET that timed out anymore
+            if self.deadlines.finish(&id) {
+                return;
+            }
+
+            // Nor for a query that was cancelled
+            if cancelled {
+                if let Some(mut step) = self.kademlia.query_mut(&id) {
+                    step.finish();
+                }
+                return;
+            }
+
+            // The queries of an MGET (or MPUT) are reported together,
+            // and they aren't tried again (which would hide how long
+            // they took)
+            if self.batches.owns(&id) {
+                self.pending.remove(&id);
+                if let Some(retries) = &mut self.retries {
+                    retries.finish(&id, None);
+                }
+                self.notify_query(id, &result, &stats, &hops);
+                self.stats.count_result(&result);
+                self.stats.count_lookup(&result, &stats);
+                let status = batch_status(&result, &errors, &stats);
+                self.batches.finish(&id, status);
+                return;
+            }
+
+            // A PUT that failed may get another try
+            if let (QueryResult::PutRecord(put), Some(retries)) =
+                (&result, &mut self.retries)
+            {
+                if let Some((record, put_quorum, attempt, wait)) =
+                    retries.finish(&id, put.as_ref().err())
+                {
+                    let key = self.namespace.display(&record.key);
+                    let why = match put {
+                        Err(PutRecordError::QuorumFailed {
+                            success,
+                            quorum,
+                            ..
+                        }) => format!(
+                            "{} of {} peers took it",
+                            success.len(),
+                            quorum
+                        ),
+                        _ => "it timed out".to_string(),
+                    };
+                    let output = self
+                        .pending
+                        .remove(&id)
+                        .unwrap_or(Output::Terminal);
+                    // The api request (if it came from the api) waits
+                    // for the next try
+                    let reply = self.api_pending.remove(&id);
+                    output.error(format!(
+                        "kad dht: failed to put record {:?} ({}), trying \
+                         again in {:?} ({} of {})",
+                        key,
+                        why,
+                        wait,
+                        attempt,
+                        retries.max_attempts()
+                    ));
+                    self.subscribers.notify(
+                        "put_retry",
+                        json!({
+                            "key": key,
+                            "attempt": attempt,
+                            "error": why,
+                            "wait_ms": wait.as_millis() as u64,
+                        }),
+                    );
+                    retries.schedule(
+                        record, put_quorum, attempt, wait, output, reply,
+                    );
+                    return;
+                }
+            }
+
+            // The lookups of watched keys aren't anybody's GETs
+            if self.finish_watch_lookup(id, &result)
+                || self.finish_provider_watch_lookup(id, &result, &decoded)
+            {
+                return;
+            }
+
+            self.notify_query(id, &result, &stats, &hops);
+            self.stats.count_result(&result);
+            self.stats.count_lookup(&result, &stats);
+
+            // BOOTSTRAP hears about every step (bootstrapping refreshes
+            // the buckets one query at a time, all with the same id)
+            if let QueryResult::Bootstrap(result) = &result {
+                if let Some(output) = self.pending.remove(&id) {
+                    self.report_bootstrap(id, result, output);
+                }
+                return;
+            }
+
+            // Queries started through the HTTP api get the raw result,
+            // which the api turns into JSON itself (and the benchmark just
+            // checks).
+            if let Some(reply) = self.api_pending.remove(&id) {
+                self.namespace.strip(&mut result);
+                let answer = match &result {
+                    QueryResult::GetRecord(Ok(ok))
+                        if ok.records.is_empty() && !errors.is_empty() =>
+                    {
+                        ApiReply::Error(errors.join(", "))
+                    }
+                    _ => ApiReply::Query(result, stats),
+                };
+                for reply in joined {
+                    if let Some(answer) = coalesce::copy_reply(&answer) {
+                        let _ = reply.send(answer);
+                    }
+                }
+                let _ = reply.send(answer);
+                return;
+            }
+
+            // Otherwise, the result goes to whoever issued the command
+            // that started the query (queries that kademlia started on its
+            // own, and anything we don't know about, go to the terminal).
+            let output =
+                self.pending.remove(&id).unwrap_or(Output::Terminal);
+            for error in errors {
+                output.error(format!("kad dht: {}", error));
+            }
+            for (key, decoded) in &decoded {
+                output.show_all(report_decoded("kad dht", key, decoded));
+            }
+            match result {
+                // If the query was a record being fetched (and it succeeded),
+                QueryResult::GetRecord(Ok(mut ok)) => {
+                    // Different peers may have different values, and then
+                    // there is no telling which one is right (unless the
+                    // merge strategy can pick one). Otherwise, the same
+                    // value from several peers only gets printed once.
+                    let decoded: Vec<Decoded> =
+                        decoded.into_iter().map(|(_, d)| d).collect();
+                    let candidates =
+                        conflict::candidates(&ok.records, &decoded);
+                    let mut keep: Vec<usize> =
+                        candidates.iter().map(|c| c.record).collect();
+                    let key = ok
+                        .records
+                        .first()
+                        .map(|r| self.namespace.display(&r.record.key));
+                    if let ([candidate], Some(key)) =
+                        (&candidates[..], &key)
+                    {
+                        if candidate.peers.len() > 1 {
+                            output.info(format!(
+                                "kad dht: {} answers agree on {:?}: {}",
+                                candidate.peers.len(),
+                                key,
+                                conflict::describe(candidate)
+                            ));
+                        }
+                    }
+                    if candidates.len() > 1 {
+                        output.error(format!(
+                            "kad dht: {} answers disagree on {:?}, with {} \
+                             different values:",
+                            ok.records.len(),
+                            key.unwrap_or_default(),
+                            candidates.len()
+                        ));
+                        for candidate in &candidates {
+                            output.error(format!(
+                                "  {}",
+                                conflict::describe(candidate)
+                            ));
+                        }
+                        match conflict::winner(
+                            self.codec.merge,
+                            &candidates,
+                        ) {
+                            Some(winner) => {
+                                output.info(format!(
+                                    "kad dht: picking {:?}, the last one stored",
+                                    String::from_utf8_lossy(&winner.value)
+                                ));
+                                keep = vec![winner.record];
+                            }
+                            None => {
+                                if self.codec.merge
+                                    == MergeStrategy::LastWriterWins
+                                {
+                                    output.error(
+                                        "kad dht: there is no telling which \
+                                         one was stored last",
+                                    );
+                                }
+                                return;
+                            }
+                        }
+                    }
+                    ok.records = ok
+                        .records
+                        .into_iter()
+                        .enumerate()
+                        .filter(|(i, _)| keep.contains(i))
+                        .map(|(_, record)| record)
+                        .collect();
+
+                    // For each record that was fetched in all of the fetched
+                    // records...
+                    for PeerRecord {
+                        peer,
+                        record:
+                            Record {
+                                key,
+                                value,
+                                publisher,
+                                ..
+                            },
+                    } in ok.records
+                    {
+                        // An index record stands for a chunked value,
+                        // which still has to be fetched
+                        if let Some(index) = chunk::parse_index(&value) {
+                            self.get_chunked(
+                                key, publisher, index, output,
+                            );
+                            return;
+                        }
+
+                        // ... do something with the record (print it, in this case)
+                        output.info(format!(
+                                "kad dht: got record {:?} {:?} from {} with id {:?} and stats {:?}",
+                                self.namespace.display(&key),
+                                String::from_utf8_lossy(&value),
+                                hops.provenance(peer.as_ref()),
+                                id, stats,
+                            ));
+                    }
+                }
+
+                // If the query was a record being fetched (and it failed)
+                QueryResult::GetRecord(Err(err)) => {
+                    output.error(format!(
+                        "kad dht: failed to get record: {:?}",
+                        err
+                    ));
+                }
+
+                // If the query was a record being stored (a put)
+                QueryResult::PutRecord(Ok(PutRecordOk { key })) => {
+                    output.info(format!(
+                        "kad dht: successfully put record {:?}",
+                        self.namespace.display(&key)
+                    ));
+                }
+
+                // If the query was a record being stored (and it failed)
+                QueryResult::PutRecord(Err(err)) => {
+                    output.error(format!(
+                        "kad dht: failed to put record: {:?}",
+                        err
+                    ));
+                }
+                // The peers closest to a key (even when the query timed
+                // out, the ones it got to are worth seeing)
+                QueryResult::GetClosestPeers(Ok(GetClosestPeersOk {
+                    key,
+                    peers,
+                })) => self.report_closest(&key, peers, &output),
+                QueryResult::GetClosestPeers(Err(
+                    GetClosestPeersError::Timeout { key, peers },
+                )) => {
+                    output.error("kad dht: the query for the closest peers timed out");
+                    self.report_closest(&key, peers, &output);
+                }
+                _ => {} // We only care about getting and putting
+            }
+        } // We only need to worry about queries to this dht
+    } // end method
+} // end impl
+
+// Whether `timer` (which fires every `interval`) fired, in which case it
+// is set again. It wakes the task once it fires.
+fn interval_due(
+    timer: &mut Option<Delay>,
+    interval: Option<Duration>,
+    cx: &mut Context<'_>,
+) -> bool {
+    let (timer, interval) = match (timer, interval) {
+        (Some(timer), Some(interval)) => (timer, interval),
+        _ => return false,
+    };
+    if timer.poll_unpin(cx).is_pending() {
+        return false;
+    }
+    *timer = Delay::new(interval);
+    let _ = timer.poll_unpin(cx);
+    true
+}
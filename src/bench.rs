@@ -0,0 +1,263 @@
+use crate::{
+    api::ApiReply,
+    behaviour::MyBehavior,
+    config::{BenchArgs, Opts},
+    results::{self, OpResult},
+    simulate,
+};
+use async_std::task;
+use futures::{channel::oneshot, future, FutureExt};
+use libp2p::{
+    kad::{record::Key, QueryId, QueryResult, Quorum, Record},
+    Swarm,
+};
+use serde_json::json;
+use std::{
+    error::Error,
+    fmt,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+// Benchmark a simulated network: first PUT `puts` records, then GET them
+// `gets` times, keeping `concurrency` queries running at once, and print
+// the throughput and latency of both.
+//
+// Queries are spread over the nodes round robin, and every GET runs on a
+// different node than the PUT of its record did.
+pub fn run(opts: &Opts, args: &BenchArgs) -> Result<(), Box<dyn Error>> {
+    let outcome =
+        measure(opts, args, Quorum::One, |line| println!("{}", line))?;
+    if let Some(path) = &args.results {
+        let params = json!({
+            "nodes": args.nodes,
+            "puts": args.puts,
+            "gets": args.gets,
+            "value_size": args.value_size,
+            "concurrency": args.concurrency,
+        });
+        let ops = [outcome.puts.result, outcome.gets.result];
+        results::write(path, "bench", params, &ops)?;
+        println!("Wrote the results to {}", path.display());
+    }
+    Ok(())
+}
+
+// What a benchmark found.
+#[derive(Debug)]
+pub struct Outcome {
+    pub puts: Phase,
+    pub gets: Phase,
+}
+
+// Run the benchmark (with `quorum` for both the PUTs and the GETs), and
+// tell `say` how it goes.
+pub fn measure(
+    opts: &Opts,
+    args: &BenchArgs,
+    quorum: Quorum,
+    say: impl Fn(String),
+) -> Result<Outcome, Box<dyn Error>> {
+    if args.concurrency == 0 {
+        return Err("--concurrency must be at least 1".into());
+    }
+    let mut swarms = simulate::spawn(opts, args.nodes)?;
+    if opts.disjoint_paths {
+        say("Looking up keys over disjoint paths".into());
+    }
+
+    say(format!("Bootstrapping {} nodes", args.nodes));
+    task::block_on(future::poll_fn(|cx| {
+        simulate::poll_all(&mut swarms, cx);
+        let bootstrapping = swarms
+            .iter_mut()
+            .any(|swarm| swarm.kademlia.iter_queries().next().is_some());
+        if bootstrapping {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }));
+
+    let value = vec![b'x'; args.value_size];
+    say(format!(
+        "Running {} PUTs of {} bytes, {} at a time",
+        args.puts, args.value_size, args.concurrency
+    ));
+    let puts = Phase::new("PUTs", args.puts, 0);
+    let puts = puts.run(&mut swarms, args.concurrency, |swarm, n| {
+        let record = Record {
+            key: key(n),
+            value: value.clone(),
+            publisher: None,
+            expires: None,
+        };
+        swarm.kademlia.put_record(record, quorum).ok()
+    });
+    say(puts.to_string());
+
+    say(format!(
+        "Running {} GETs, {} at a time",
+        args.gets, args.concurrency
+    ));
+    // GETs are shifted by one node, so that they don't just hit the
+    // store of the node that did the PUT
+    let gets = Phase::new("GETs", args.gets, 1);
+    let gets = gets.run(&mut swarms, args.concurrency, |swarm, n| {
+        // There is nothing to get if nothing was put, but the GETs still
+        // run (and fail)
+        let n = n % args.puts.max(1);
+        Some(swarm.kademlia.get_record(&key(n), quorum))
+    });
+    say(gets.to_string());
+
+    Ok(Outcome { puts, gets })
+}
+
+// The key of the `n`th record.
+fn key(n: usize) -> Key {
+    Key::new(&format!("bench-{}", n))
+}
+
+// A query that is running, and when it started.
+struct Running {
+    started: Instant,
+    reply: oneshot::Receiver<ApiReply>,
+}
+
+// One half of the benchmark (the PUTs or the GETs), and its results.
+#[derive(Debug)]
+pub struct Phase {
+    total: usize,
+    // Query `n` runs on node `n + shift`
+    shift: usize,
+    pub result: OpResult,
+    // The requests the queries sent to peers, and how many of those
+    // failed (which is where disjoint paths make a difference)
+    requests: u64,
+    request_failures: u64,
+}
+
+impl Phase {
+    fn new(name: &'static str, total: usize, shift: usize) -> Self {
+        Phase {
+            total,
+            shift,
+            result: OpResult::new(name),
+            requests: 0,
+            request_failures: 0,
+        }
+    }
+
+    // Run `total` queries, started by `start` (which gets the node to run
+    // the query on, and the number of the query). `start` returns None if
+    // the query couldn't even be started.
+    fn run(
+        mut self,
+        swarms: &mut [Swarm<MyBehavior>],
+        concurrency: usize,
+        mut start: impl FnMut(&mut Swarm<MyBehavior>, usize) -> Option<QueryId>,
+    ) -> Self {
+        let began = Instant::now();
+        let mut issued = 0;
+        let mut running: Vec<Running> = Vec::with_capacity(concurrency);
+
+        task::block_on(future::poll_fn(|cx: &mut Context<'_>| loop {
+            // Keep the pipeline full
+            while running.len() < concurrency && issued < self.total {
+                let node = (issued + self.shift) % swarms.len();
+                let swarm = &mut swarms[node];
+                match start(swarm, issued) {
+                    Some(id) => {
+                        let (tx, rx) = oneshot::channel();
+                        swarm.api_pending.insert(id, tx);
+                        running.push(Running {
+                            started: Instant::now(),
+                            reply: rx,
+                        });
+                    }
+                    None => self.result.failed += 1,
+                }
+                issued += 1;
+            }
+
+            simulate::poll_all(swarms, cx);
+
+            // Collect whatever finished
+            let before = running.len();
+            let mut i = 0;
+            while i < running.len() {
+                match running[i].reply.poll_unpin(cx) {
+                    Poll::Ready(reply) => {
+                        let done = running.swap_remove(i);
+                        if let Ok(ApiReply::Query(_, stats)) = &reply {
+                            self.requests += stats.num_requests() as u64;
+                            self.request_failures +=
+                                stats.num_failures() as u64;
+                        }
+                        let ok = matches!(
+                            reply,
+                            Ok(ApiReply::Query(result, _)) if ok(&result)
+                        );
+                        self.result.count(ok, done.started.elapsed());
+                    }
+                    Poll::Pending => i += 1,
+                }
+            }
+
+            if issued == self.total && running.is_empty() {
+                return Poll::Ready(());
+            }
+            if running.len() == before {
+                return Poll::Pending;
+            }
+        }));
+
+        self.result.elapsed = began.elapsed();
+        self
+    }
+
+    // The requests to peers per query, and the failed ones.
+    pub fn requests_per_query(&self) -> (f64, f64) {
+        let per_query = |n: u64| n as f64 / self.total.max(1) as f64;
+        (per_query(self.requests), per_query(self.request_failures))
+    }
+}
+
+// Whether a query did what it was asked to.
+fn ok(result: &QueryResult) -> bool {
+    match result {
+        QueryResult::PutRecord(result) => result.is_ok(),
+        QueryResult::GetRecord(result) => result.is_ok(),
+        _ => false,
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let result = &self.result;
+        writeln!(
+            f,
+            "{}: {} succeeded, {} failed in {:.2?} ({:.1} ops/s)",
+            result.op,
+            result.succeeded,
+            result.failed,
+            result.elapsed,
+            result.throughput()
+        )?;
+        writeln!(
+            f,
+            "  latency: p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+            result.percentile(0.5),
+            result.percentile(0.9),
+            result.percentile(0.99),
+            result.percentile(1.0)
+        )?;
+        let (requests, failed) = self.requests_per_query();
+        write!(
+            f,
+            "  requests: {:.1} per query ({:.1} failed)",
+            requests, failed
+        )
+    }
+}
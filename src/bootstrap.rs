@@ -0,0 +1,227 @@
+use async_std::{
+    io::{self, ReadExt, WriteExt},
+    net::{TcpStream, UdpSocket},
+    task,
+};
+use dns_parser::{Packet, QueryClass, QueryType, RData, ResponseCode};
+use futures::channel::mpsc;
+use futures_timer::Delay;
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use std::{fs, net::SocketAddr, time::Duration};
+
+// How often /dnsaddr entries are resolved again, unless
+// --bootstrap-interval says otherwise.
+pub const DEFAULT_INTERVAL: &str = "10m";
+
+// How long a DNS server gets to answer.
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How deep /dnsaddr entries may point to other /dnsaddr entries.
+const MAX_DEPTH: usize = 8;
+
+// The DNS servers to ask when /etc/resolv.conf names none.
+const FALLBACK_DNS_SERVERS: [&str; 2] = ["127.0.0.1:53", "[::1]:53"];
+
+// What resolving a --bootstrap entry came up with.
+pub type Resolved = (Multiaddr, Result<Vec<(PeerId, Multiaddr)>, String>);
+
+// Resolve the --bootstrap entries in the background, and again every
+// `interval` (if there is one, and there are /dnsaddr entries, whose
+// peers may move).
+pub fn spawn(
+    entries: Vec<Multiaddr>,
+    interval: Option<Duration>,
+) -> mpsc::UnboundedReceiver<Resolved> {
+    let (tx, rx) = mpsc::unbounded();
+    let again = interval.filter(|_| entries.iter().any(is_dnsaddr));
+    task::spawn(async move {
+        loop {
+            for entry in &entries {
+                let peers = resolve(entry).await;
+                if tx.unbounded_send((entry.clone(), peers)).is_err() {
+                    return;
+                }
+            }
+            match again {
+                Some(interval) => Delay::new(interval).await,
+                None => return,
+            }
+        }
+    });
+    rx
+}
+
+pub fn is_dnsaddr(addr: &Multiaddr) -> bool {
+    matches!(addr.iter().next(), Some(Protocol::Dnsaddr(_)))
+}
+
+// Split an address like `/ip4/1.2.3.4/tcp/4001/p2p/12D3Koo...` into the
+// peer and the address of the peer.
+pub fn parse_peer_addr(s: &str) -> Result<(PeerId, Multiaddr), String> {
+    let addr: Multiaddr = s
+        .parse()
+        .map_err(|_| format!("{:?} is not an address", s))?;
+    split_peer_addr(addr)
+}
+
+// The same, for an address that is parsed already.
+pub fn split_peer_addr(
+    mut addr: Multiaddr,
+) -> Result<(PeerId, Multiaddr), String> {
+    match addr.pop() {
+        Some(Protocol::P2p(hash)) => {
+            let peer_id = PeerId::from_multihash(hash)
+                .map_err(|_| format!("{} has a bad peer id", addr))?;
+            Ok((peer_id, addr))
+        }
+        _ => Err(format!("{} doesn't end with /p2p/<peer id>", addr)),
+    }
+}
+
+// The peers (and their addresses) a --bootstrap entry stands for. An
+// entry is either the address of one peer, or a `/dnsaddr/<domain>`
+// whose TXT records (under `_dnsaddr.<domain>`) list the addresses, as
+// `dnsaddr=<address>`. Those may be /dnsaddr entries again. A /dnsaddr
+// entry ending with `/p2p/<peer id>` only stands for that peer.
+pub async fn resolve(
+    entry: &Multiaddr,
+) -> Result<Vec<(PeerId, Multiaddr)>, String> {
+    let mut peers = Vec::new();
+    let mut todo = vec![(entry.clone(), 0)];
+    while let Some((addr, depth)) = todo.pop() {
+        let domain = match addr.iter().next() {
+            Some(Protocol::Dnsaddr(domain)) => domain.to_string(),
+            _ => {
+                peers.push(split_peer_addr(addr)?);
+                continue;
+            }
+        };
+        if depth >= MAX_DEPTH {
+            return Err(format!("{} points too deep", entry));
+        }
+
+        // Only the peer the entry ends with, if it ends with one
+        let wanted = match addr.iter().last() {
+            Some(Protocol::P2p(hash)) => Some(hash),
+            _ => None,
+        };
+        let records =
+            txt_records(&format!("_dnsaddr.{}", domain))
+                .await
+                .map_err(|err| format!("resolving {}: {}", domain, err))?;
+        for record in records {
+            let found = match record.strip_prefix("dnsaddr=") {
+                Some(found) => found,
+                None => continue,
+            };
+            let found: Multiaddr = match found.parse() {
+                Ok(found) => found,
+                Err(_) => continue,
+            };
+            let matches = match (&wanted, found.iter().last()) {
+                (None, _) => true,
+                (Some(wanted), Some(Protocol::P2p(hash))) => {
+                    hash == *wanted
+                }
+                (Some(_), _) => false,
+            };
+            if matches {
+                todo.push((found, depth + 1));
+            }
+        }
+    }
+    Ok(peers)
+}
+
+// Ask the DNS servers for the TXT records of `name`, until one answers.
+async fn txt_records(name: &str) -> io::Result<Vec<String>> {
+    let mut query = dns_parser::Builder::new_query(rand::random(), true);
+    query.add_question(name, false, QueryType::TXT, QueryClass::IN);
+    let query = query.build().unwrap_or_else(|query| query);
+
+    let mut last_err =
+        io::Error::new(io::ErrorKind::NotFound, "no servers");
+    for server in dns_servers() {
+        match io::timeout(DNS_TIMEOUT, ask(server, &query)).await {
+            Ok(records) => return Ok(records),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+// Ask one server over UDP, or over TCP if the answer doesn't fit in a
+// datagram.
+async fn ask(server: SocketAddr, query: &[u8]) -> io::Result<Vec<String>> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0; 4096];
+    let len = socket.recv(&mut buf).await?;
+    buf.truncate(len);
+    if !Packet::parse(&buf).map_err(invalid)?.header.truncated {
+        return txt_answers(&buf);
+    }
+
+    // Messages over TCP have a length prefix
+    let mut stream = TcpStream::connect(server).await?;
+    stream
+        .write_all(&(query.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(query).await?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf).await?;
+    txt_answers(&buf)
+}
+
+// The TXT records in a DNS response (each one's strings joined).
+fn txt_answers(response: &[u8]) -> io::Result<Vec<String>> {
+    let packet = Packet::parse(response).map_err(invalid)?;
+    match packet.header.response_code {
+        ResponseCode::NoError => {}
+        // There just are no addresses
+        ResponseCode::NameError => return Ok(Vec::new()),
+        code => return Err(invalid(format!("{:?}", code))),
+    }
+    Ok(packet
+        .answers
+        .iter()
+        .filter_map(|answer| match &answer.data {
+            RData::TXT(txt) => Some(
+                txt.iter()
+                    .map(String::from_utf8_lossy)
+                    .collect::<String>(),
+            ),
+            _ => None,
+        })
+        .collect())
+}
+
+// The servers in /etc/resolv.conf.
+fn dns_servers() -> Vec<SocketAddr> {
+    let servers: Vec<_> = fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|server| server.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect();
+    if servers.is_empty() {
+        FALLBACK_DNS_SERVERS
+            .iter()
+            .map(|server| server.parse().unwrap())
+            .collect()
+    } else {
+        servers
+    }
+}
+
+fn invalid(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
@@ -0,0 +1,171 @@
+use async_std::{net::UdpSocket, task};
+use futures::channel::mpsc;
+use futures_timer::Delay;
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use serde_json::{json, Value};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    os::unix::io::FromRawFd,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+// The UDP port announcements go to, unless --broadcast-port says
+// otherwise.
+pub const DEFAULT_PORT: u16 = 47474;
+
+// How often a node announces itself.
+const INTERVAL: Duration = Duration::from_secs(5);
+
+// The biggest announcement we take (a few dozen addresses).
+const MAX_ANNOUNCEMENT: usize = 8192;
+
+// A peer that announced itself, and where it listens.
+pub type Announced = (PeerId, Vec<Multiaddr>);
+
+// --broadcast-discovery: finding the peers on the local network without
+// mDNS, which many networks block. Every node sends its peer id and
+// listen addresses to the broadcast address every few seconds, as one
+// JSON object per datagram, like
+//   {"nettest": 1, "peer_id": "12D3Koo...",
+//    "addrs": ["/ip4/192.168.1.2/tcp/4001"]}
+// and listens for what the others send. Every node (of every process on
+// the host) binds the same port, which the kernel allows with
+// SO_REUSEPORT, while still giving each of them every broadcast.
+//
+// What comes in goes to kademlia, like what mDNS discovers. Loopback
+// addresses are only believed from the same host.
+#[derive(Debug, Clone)]
+pub struct Announcer {
+    addrs: Arc<RwLock<Vec<Multiaddr>>>,
+}
+
+impl Announcer {
+    // Start announcing `local_peer_id` on `port`. The peers that other
+    // nodes announce arrive on the channel.
+    pub fn spawn(
+        local_peer_id: PeerId,
+        port: u16,
+    ) -> io::Result<(Self, mpsc::UnboundedReceiver<Announced>)> {
+        let socket = Arc::new(bind(port)?);
+        let announcer = Announcer {
+            addrs: Arc::new(RwLock::new(Vec::new())),
+        };
+        let (tx, rx) = mpsc::unbounded();
+
+        let sender = socket.clone();
+        let addrs = announcer.addrs.clone();
+        let peer_id = local_peer_id.clone();
+        task::spawn(async move {
+            let to = SocketAddrV4::new(Ipv4Addr::BROADCAST, port);
+            loop {
+                let message =
+                    announcement(&peer_id, &addrs.read().unwrap());
+                // Without a broadcast route (yet), try again next time
+                let _ = sender.send_to(message.as_bytes(), to).await;
+                Delay::new(INTERVAL).await;
+            }
+        });
+
+        task::spawn(async move {
+            let mut buf = vec![0; MAX_ANNOUNCEMENT];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let announced = match parse(&buf[..len], from) {
+                    Some(announced) => announced,
+                    None => continue,
+                };
+                if announced.0 == local_peer_id {
+                    continue;
+                }
+                if tx.unbounded_send(announced).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok((announcer, rx))
+    }
+
+    // Announce these addresses from now on.
+    pub fn set_addrs(&self, addrs: Vec<Multiaddr>) {
+        *self.addrs.write().unwrap() = addrs;
+    }
+}
+
+fn announcement(peer_id: &PeerId, addrs: &[Multiaddr]) -> String {
+    let addrs: Vec<String> = addrs.iter().map(|a| a.to_string()).collect();
+    json!({
+        "nettest": 1,
+        "peer_id": peer_id.to_string(),
+        "addrs": addrs,
+    })
+    .to_string()
+}
+
+// What an announcement from `from` says, if it is one.
+fn parse(datagram: &[u8], from: SocketAddr) -> Option<Announced> {
+    let message: Value = serde_json::from_slice(datagram).ok()?;
+    if message["nettest"] != 1 {
+        return None;
+    }
+    let peer_id = message["peer_id"].as_str()?.parse().ok()?;
+    let addrs = message["addrs"]
+        .as_array()?
+        .iter()
+        .filter_map(|addr| addr.as_str()?.parse::<Multiaddr>().ok())
+        .filter(|addr| from.ip().is_loopback() || !is_loopback(addr))
+        .collect();
+    Some((peer_id, addrs))
+}
+
+fn is_loopback(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => ip.is_loopback(),
+        Some(Protocol::Ip6(ip)) => ip.is_loopback(),
+        _ => false,
+    }
+}
+
+// A UDP socket on `port` of every interface, which may send broadcasts
+// and shares the port with the other nodes on the host.
+fn bind(port: u16) -> io::Result<UdpSocket> {
+    // SAFETY: plain socket calls, on a descriptor that is ours until it
+    // is handed to the UdpSocket (which closes it)
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = std::net::UdpSocket::from_raw_fd(fd);
+        let on: libc::c_int = 1;
+        for option in &[libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+            let set = libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                *option,
+                &on as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+            if set != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let addr = libc::sockaddr_in {
+            sin_family: libc::AF_INET as libc::sa_family_t,
+            sin_port: port.to_be(),
+            sin_addr: libc::in_addr { s_addr: 0 },
+            sin_zero: [0; 8],
+        };
+        let bound = libc::bind(
+            fd,
+            &addr as *const _ as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        );
+        if bound != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        socket.set_broadcast(true)?;
+        Ok(UdpSocket::from(socket))
+    }
+}
@@ -0,0 +1,118 @@
+use libp2p::kad::{record::Key, Record};
+use std::{
+    collections::{HashMap, VecDeque},
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+// How long a record stays in the cache, unless told otherwise.
+pub const DEFAULT_TTL: &str = "30s";
+
+// How the cache did so far, for CACHE and /metrics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+// The records that GETs fetched recently (--cache-size), which GETs use
+// instead of asking the network again, for as long as they are fresh
+// (--cache-ttl). As a client library with a cache in front of the DHT
+// would. Records stay as they came, encoded; the oldest ones make room
+// for new ones.
+#[derive(Debug)]
+pub struct RecordCache {
+    capacity: usize,
+    ttl: Duration,
+    records: HashMap<Key, (Record, Instant)>,
+    // The keys, the earliest fetched first
+    order: VecDeque<Key>,
+    hits: u64,
+    misses: u64,
+}
+
+impl RecordCache {
+    pub fn new(capacity: NonZeroUsize, ttl: Duration) -> Self {
+        RecordCache {
+            capacity: capacity.get(),
+            ttl,
+            records: HashMap::new(),
+            order: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // The record of `key`, and how long ago it was fetched, if there is
+    // a fresh one. Counts as a hit or a miss.
+    pub fn get(&mut self, key: &Key) -> Option<(Record, Duration)> {
+        let fresh = match self.records.get(key) {
+            Some((record, fetched)) if fetched.elapsed() < self.ttl => {
+                let expired = record
+                    .expires
+                    .is_some_and(|expires| expires <= Instant::now());
+                (!expired).then(|| (record.clone(), fetched.elapsed()))
+            }
+            _ => None,
+        };
+        match fresh {
+            Some(fresh) => {
+                self.hits += 1;
+                Some(fresh)
+            }
+            None => {
+                self.remove(key);
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    // A GET fetched `record`.
+    pub fn insert(&mut self, record: Record) {
+        let key = record.key.clone();
+        self.remove(&key);
+        while self.records.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    self.records.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        self.records.insert(key.clone(), (record, Instant::now()));
+        self.order.push_back(key);
+    }
+
+    // Forget the record of `key` (the node stored a new version).
+    pub fn remove(&mut self, key: &Key) {
+        if self.records.remove(key).is_some() {
+            self.order.retain(|k| k != key);
+        }
+    }
+
+    // Forget all the records. Returns how many there were.
+    pub fn clear(&mut self) -> usize {
+        let cleared = self.records.len();
+        self.records.clear();
+        self.order.clear();
+        cleared
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.records.len(),
+        }
+    }
+}
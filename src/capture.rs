@@ -0,0 +1,310 @@
+use crate::eventlog::EventLog;
+use libp2p::{
+    core::connection::ConnectionId,
+    kad::{
+        handler::{KademliaHandlerEvent, KademliaHandlerIn},
+        protocol::{KadConnectionType, KadPeer},
+        record::Key,
+        QueryId, Record,
+    },
+    PeerId,
+};
+use serde_json::{json, Value};
+use std::time::Instant;
+
+// The --capture: every kademlia message the node sends or receives, as
+// the handlers decoded it (so after noise, and after the protobuf),
+// appended to a file as one JSON object per line, like
+//   {"time": 1602678000123, "node": "12D3Koo...", "event": "in",
+//    "peer": "12D3Koo...", "connection": 3, "message": "FIND_NODE",
+//    "kind": "request", "key": "12D3Koo..."}
+// The messages are named as in the protobuf of the kademlia spec (which
+// go-libp2p uses too), and the responses to our requests carry the query
+// they belong to. Keys and values come as text (with unprintable bytes
+// replaced) and as hex. A failed request is captured as an ERROR, and a
+// request we didn't answer as a RESET.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    log: EventLog,
+}
+
+impl Capture {
+    pub fn new(log: EventLog) -> Self {
+        Capture { log }
+    }
+
+    // A message from `peer`.
+    pub fn inbound(
+        &self,
+        peer: &PeerId,
+        connection: ConnectionId,
+        event: &KademliaHandlerEvent<QueryId>,
+    ) {
+        let mut message = describe_inbound(event);
+        add_peer(&mut message, peer, Some(connection));
+        self.log.write("in", &message);
+    }
+
+    // A message to `peer` (on `connection`, if the reply has to go on the
+    // one the request came on).
+    pub fn outbound(
+        &self,
+        peer: &PeerId,
+        connection: Option<ConnectionId>,
+        event: &KademliaHandlerIn<QueryId>,
+    ) {
+        let mut message = describe_outbound(event);
+        add_peer(&mut message, peer, connection);
+        self.log.write("out", &message);
+    }
+}
+
+fn add_peer(
+    message: &mut Value,
+    peer: &PeerId,
+    connection: Option<ConnectionId>,
+) {
+    if let Some(message) = message.as_object_mut() {
+        message.insert("peer".into(), json!(peer.to_string()));
+        if let Some(connection) = connection {
+            message.insert(
+                "connection".into(),
+                json!(connection_id(connection)),
+            );
+        }
+    }
+}
+
+fn describe_inbound(event: &KademliaHandlerEvent<QueryId>) -> Value {
+    use KademliaHandlerEvent::*;
+    match event {
+        FindNodeReq { key, .. } => json!({
+            "message": "FIND_NODE",
+            "kind": "request",
+            "key": node_key(key),
+        }),
+        FindNodeRes {
+            closer_peers,
+            user_data,
+        } => json!({
+            "message": "FIND_NODE",
+            "kind": "response",
+            "query": query(user_data),
+            "closer_peers": peers(closer_peers),
+        }),
+        GetProvidersReq { key, .. } => json!({
+            "message": "GET_PROVIDERS",
+            "kind": "request",
+            "key": text(key.as_ref()),
+            "key_hex": hex(key.as_ref()),
+        }),
+        GetProvidersRes {
+            closer_peers,
+            provider_peers,
+            user_data,
+        } => json!({
+            "message": "GET_PROVIDERS",
+            "kind": "response",
+            "query": query(user_data),
+            "closer_peers": peers(closer_peers),
+            "provider_peers": peers(provider_peers),
+        }),
+        QueryError { error, user_data } => json!({
+            "message": "ERROR",
+            "kind": "response",
+            "query": query(user_data),
+            "error": error.to_string(),
+        }),
+        AddProvider { key, provider } => json!({
+            "message": "ADD_PROVIDER",
+            "kind": "request",
+            "key": text(key.as_ref()),
+            "key_hex": hex(key.as_ref()),
+            "provider": peer(provider),
+        }),
+        GetRecord { key, .. } => get_value_request(key),
+        GetRecordRes {
+            record,
+            closer_peers,
+            user_data,
+        } => get_value_response(
+            record.as_ref(),
+            closer_peers,
+            Some(user_data),
+        ),
+        PutRecord { record, .. } => put_value(record, None),
+        PutRecordRes {
+            key,
+            value,
+            user_data,
+        } => json!({
+            "message": "PUT_VALUE",
+            "kind": "response",
+            "query": query(user_data),
+            "key": text(key.as_ref()),
+            "key_hex": hex(key.as_ref()),
+            "value_len": value.len(),
+            "value_hex": hex(value),
+        }),
+    }
+}
+
+fn describe_outbound(event: &KademliaHandlerIn<QueryId>) -> Value {
+    use KademliaHandlerIn::*;
+    match event {
+        Reset(_) => json!({ "message": "RESET", "kind": "response" }),
+        FindNodeReq { key, user_data } => json!({
+            "message": "FIND_NODE",
+            "kind": "request",
+            "query": query(user_data),
+            "key": node_key(key),
+        }),
+        FindNodeRes { closer_peers, .. } => json!({
+            "message": "FIND_NODE",
+            "kind": "response",
+            "closer_peers": peers(closer_peers),
+        }),
+        GetProvidersReq { key, user_data } => json!({
+            "message": "GET_PROVIDERS",
+            "kind": "request",
+            "query": query(user_data),
+            "key": text(key.as_ref()),
+            "key_hex": hex(key.as_ref()),
+        }),
+        GetProvidersRes {
+            closer_peers,
+            provider_peers,
+            ..
+        } => json!({
+            "message": "GET_PROVIDERS",
+            "kind": "response",
+            "closer_peers": peers(closer_peers),
+            "provider_peers": peers(provider_peers),
+        }),
+        AddProvider { key, provider } => json!({
+            "message": "ADD_PROVIDER",
+            "kind": "request",
+            "key": text(key.as_ref()),
+            "key_hex": hex(key.as_ref()),
+            "provider": peer(provider),
+        }),
+        GetRecord { key, user_data } => {
+            let mut message = get_value_request(key);
+            message["query"] = query(user_data);
+            message
+        }
+        GetRecordRes {
+            record,
+            closer_peers,
+            ..
+        } => get_value_response(record.as_ref(), closer_peers, None),
+        PutRecord { record, user_data } => {
+            put_value(record, Some(user_data))
+        }
+        PutRecordRes { key, value, .. } => json!({
+            "message": "PUT_VALUE",
+            "kind": "response",
+            "key": text(key.as_ref()),
+            "key_hex": hex(key.as_ref()),
+            "value_len": value.len(),
+            "value_hex": hex(value),
+        }),
+    }
+}
+
+fn get_value_request(key: &Key) -> Value {
+    json!({
+        "message": "GET_VALUE",
+        "kind": "request",
+        "key": text(key.as_ref()),
+        "key_hex": hex(key.as_ref()),
+    })
+}
+
+fn get_value_response(
+    record: Option<&Record>,
+    closer_peers: &[KadPeer],
+    user_data: Option<&QueryId>,
+) -> Value {
+    json!({
+        "message": "GET_VALUE",
+        "kind": "response",
+        "query": user_data.map(query),
+        "record": record.map(describe_record),
+        "closer_peers": peers(closer_peers),
+    })
+}
+
+fn put_value(record: &Record, user_data: Option<&QueryId>) -> Value {
+    json!({
+        "message": "PUT_VALUE",
+        "kind": "request",
+        "query": user_data.map(query),
+        "record": describe_record(record),
+    })
+}
+
+fn describe_record(record: &Record) -> Value {
+    // How long the record has left, as the message says (the protobuf
+    // carries a TTL, which the handler turned into a deadline)
+    let ttl_s = record.expires.map(|expires| {
+        expires.saturating_duration_since(Instant::now()).as_secs()
+    });
+    json!({
+        "key": text(record.key.as_ref()),
+        "key_hex": hex(record.key.as_ref()),
+        "value_len": record.value.len(),
+        "value": text(&record.value),
+        "value_hex": hex(&record.value),
+        "publisher": record.publisher.as_ref().map(|p| p.to_string()),
+        "ttl_s": ttl_s,
+    })
+}
+
+fn peers(peers: &[KadPeer]) -> Vec<Value> {
+    peers.iter().map(peer).collect()
+}
+
+fn peer(peer: &KadPeer) -> Value {
+    json!({
+        "id": peer.node_id.to_string(),
+        "addrs": peer.multiaddrs.iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+        "connection": match peer.connection_ty {
+            KadConnectionType::NotConnected => "not_connected",
+            KadConnectionType::Connected => "connected",
+            KadConnectionType::CanConnect => "can_connect",
+            KadConnectionType::CannotConnect => "cannot_connect",
+        },
+    })
+}
+
+// The key of a FIND_NODE is a peer id, normally.
+fn node_key(key: &[u8]) -> Value {
+    match PeerId::from_bytes(key.to_vec()) {
+        Ok(peer) => json!(peer.to_string()),
+        Err(_) => json!(hex(key)),
+    }
+}
+
+fn query(id: &QueryId) -> Value {
+    json!(format!("{:?}", id))
+}
+
+// The number of a connection (ConnectionId doesn't give it out, but
+// shows it).
+fn connection_id(connection: ConnectionId) -> Option<u64> {
+    format!("{:?}", connection)
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>()
+        .parse()
+        .ok()
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,176 @@
+use crate::{
+    behaviour::MyBehaviorWith, chunk, output::Output, value::Compression,
+};
+use libp2p::{
+    kad::{
+        record::Key, GetRecordError, GetRecordOk, PeerRecord, QueryId,
+        QueryResult, QueryStats, Quorum,
+    },
+    multihash::Sha2_256,
+    swarm::NetworkBehaviour as Behaviour,
+};
+
+// Content-addressed records are stored under the hash of their value: a
+// sha2-256 multihash, in base58 (which is also how a version 0 CID looks,
+// like `QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG`).
+const SHA2_256: u8 = 0x12;
+const DIGEST_SIZE: u8 = 32;
+
+// The key of a value, as text.
+pub fn key_for(value: &[u8]) -> String {
+    bs58::encode(Sha2_256::digest(value).into_bytes()).into_string()
+}
+
+// The hash in a content-addressed key, if it is one (in a namespace, the
+// hash is the last part of the key).
+fn content_hash(key: &Key) -> Option<&str> {
+    let hash =
+        std::str::from_utf8(key.as_ref()).ok()?.rsplit('/').next()?;
+    let multihash = bs58::decode(hash).into_vec().ok()?;
+    let is_sha2_256 = multihash.len() == 2 + DIGEST_SIZE as usize
+        && multihash[..2] == [SHA2_256, DIGEST_SIZE];
+    Some(hash).filter(|_| is_sha2_256)
+}
+
+// Check that a value found under a content-addressed key is the one the
+// key was made from (values under any other key pass).
+pub fn check(key: &Key, value: &[u8]) -> Result<(), String> {
+    match content_hash(key) {
+        Some(hash) if hash != key_for(value) => {
+            Err("the value doesn't match the hash in its key".into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// A CAS: store `value` under `key` as the next version, but only if the
+// record is at the `expected` version now.
+#[derive(Debug)]
+pub struct Swap {
+    pub key: Key,
+    pub expected: u64,
+    pub value: Vec<u8>,
+    pub compress: Option<Compression>,
+    pub quorum: Quorum,
+    pub output: Output,
+}
+
+impl<B: Behaviour> MyBehaviorWith<B> {
+    // Start a CAS, by looking up the current version of the record. This
+    // is only as good as that lookup: two CASes that run at the same time
+    // can both see the same version, and both go through.
+    pub fn compare_and_swap(&mut self, swap: Swap) -> QueryId {
+        let id = self.kademlia.get_record(&swap.key, Quorum::One);
+        self.swaps.insert(id, swap);
+        id
+    }
+
+    // Store the new version of a CAS, if the lookup found the record at
+    // the expected version. Records that don't exist are at version 0, as
+    // are the ones that weren't stored with CAS. Returns the PUT, if it
+    // started one.
+    fn finish_swap(
+        &mut self,
+        swap: Swap,
+        result: &QueryResult,
+    ) -> Option<QueryId> {
+        let name = self.namespace.display(&swap.key);
+        let current = match result {
+            QueryResult::GetRecord(Ok(GetRecordOk { records })) => {
+                match records.first() {
+                    Some(PeerRecord { record, .. })
+                        if chunk::parse_index(&record.value).is_some() =>
+                    {
+                        swap.output.error(format!(
+                            "CAS: {:?} is a chunked value, which has no \
+                             version",
+                            name
+                        ));
+                        return None;
+                    }
+                    Some(PeerRecord { record, .. }) => {
+                        match self.codec.decode(
+                            &record.key,
+                            record.publisher.as_ref(),
+                            record.value.clone(),
+                        ) {
+                            Ok(decoded) => decoded.version.unwrap_or(0),
+                            Err(err) => {
+                                swap.output.error(format!(
+                                    "CAS: failed to decode record {:?}: {}",
+                                    name, err
+                                ));
+                                return None;
+                            }
+                        }
+                    }
+                    None => 0,
+                }
+            }
+            QueryResult::GetRecord(Err(GetRecordError::NotFound {
+                ..
+            })) => 0,
+            QueryResult::GetRecord(Err(err)) => {
+                swap.output.error(format!(
+                    "CAS: failed to get record {:?}: {:?}",
+                    name, err
+                ));
+                return None;
+            }
+            _ => return None,
+        };
+
+        if current != swap.expected {
+            swap.output.error(format!(
+                "CAS: record {:?} is at version {}, not {}",
+                name, current, swap.expected
+            ));
+            return None;
+        }
+        swap.output.info(format!(
+            "CAS: record {:?} is at version {}, storing version {}",
+            name,
+            current,
+            current + 1
+        ));
+        self.put_value(
+            swap.key,
+            swap.value,
+            swap.compress,
+            Some(current + 1),
+            swap.quorum,
+            swap.output,
+        )
+    }
+
+    // Go on with the CAS whose lookup `id` is, if it is one: as the PUT
+    // of the new version, which takes over its number (for CANCEL) and
+    // its time. (A CAS that is cancelled is dropped right away, see
+    // `cancel`.) Returns whether it was.
+    pub fn finish_swap_lookup(
+        &mut self,
+        id: QueryId,
+        result: &QueryResult,
+        stats: &QueryStats,
+    ) -> bool {
+        let swap = match self.swaps.remove(&id) {
+            Some(swap) => swap,
+            None => return false,
+        };
+        match self.finish_swap(swap, result) {
+            Some(put) => {
+                self.in_flight.hand_over(&id, put);
+                self.timings.hand_over(&id, put);
+            }
+            None => {
+                self.in_flight.finish(&id, true);
+                if let Some((output, time)) =
+                    self.timings.finish(&id, result, stats)
+                {
+                    output.info(time);
+                }
+            }
+        }
+        true
+    }
+}
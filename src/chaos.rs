@@ -0,0 +1,281 @@
+use crate::seed;
+use futures::{future, ready, AsyncRead, AsyncWrite, FutureExt};
+use futures_timer::Delay;
+use rand::Rng;
+use std::{
+    fmt, io,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+// How badly to treat connections, like
+// `drop=0.05,delay=50ms..200ms,reset=0.01`.
+//
+// - drop: the chance that a new connection (dialed or accepted) is
+//   refused outright
+// - delay: how long to hold back every write, picked at random from the
+//   range (or a fixed `delay=100ms`), which adds latency and jitter
+// - reset: the chance that a read or write breaks the connection, as if
+//   the other side had reset it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChaosConfig {
+    pub drop: f64,
+    pub delay: Option<(Duration, Duration)>,
+    pub reset: f64,
+}
+
+impl FromStr for ChaosConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = ChaosConfig::default();
+        for part in s.split(',').filter(|part| !part.is_empty()) {
+            let (name, value) = part.split_once('=').ok_or_else(|| {
+                format!("expected name=value, got {:?}", part)
+            })?;
+            match name {
+                "drop" => config.drop = parse_rate(value)?,
+                "reset" => config.reset = parse_rate(value)?,
+                "delay" => {
+                    let (low, high) = match value.split_once("..") {
+                        Some((low, high)) => {
+                            (parse_duration(low)?, parse_duration(high)?)
+                        }
+                        None => {
+                            let delay = parse_duration(value)?;
+                            (delay, delay)
+                        }
+                    };
+                    if low > high {
+                        return Err(format!(
+                            "empty delay range {:?}",
+                            value
+                        ));
+                    }
+                    config.delay = Some((low, high));
+                }
+                _ => {
+                    return Err(format!(
+                        "unknown chaos setting {:?}",
+                        name
+                    ))
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for ChaosConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "drop={},reset={}", self.drop, self.reset)?;
+        if let Some((low, high)) = self.delay {
+            write!(f, ",delay={:?}..{:?}", low, high)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("{:?} is not a rate between 0 and 1", s)),
+    }
+}
+
+// Parse a duration like `50ms`, `2s`, `10m` or `6h`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid =
+        || format!("{:?} is not a duration (like 50ms, 2s or 6h)", s);
+    if let Some(ms) = s.strip_suffix("ms") {
+        return ms
+            .parse()
+            .map(Duration::from_millis)
+            .map_err(|_| invalid());
+    }
+    let (number, seconds_per_unit) = match s.char_indices().last() {
+        Some((at, 's')) => (&s[..at], 1.0),
+        Some((at, 'm')) => (&s[..at], 60.0),
+        Some((at, 'h')) => (&s[..at], 3600.0),
+        _ => return Err(invalid()),
+    };
+    // Durations that don't fit (like 1e30s) are as invalid as NaN
+    number
+        .parse::<f64>()
+        .ok()
+        .filter(|number| *number >= 0.0)
+        .and_then(|number| {
+            Duration::try_from_secs_f64(number * seconds_per_unit).ok()
+        })
+        .ok_or_else(invalid)
+}
+
+// Roll the dice.
+pub fn happens(rate: f64) -> bool {
+    rate > 0.0 && seed::rng().gen::<f64>() < rate
+}
+
+// Decide what happens to a brand new connection. This is meant to be used
+// with `Transport::and_then`, on the raw (not yet upgraded) transport.
+pub fn connect<C>(
+    socket: C,
+    config: Arc<ChaosConfig>,
+) -> future::Ready<Result<ChaosSocket<C>, io::Error>> {
+    future::ready(if happens(config.drop) {
+        Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "connection dropped by chaos",
+        ))
+    } else {
+        Ok(ChaosSocket {
+            inner: socket,
+            config,
+            delay: None,
+            broken: false,
+        })
+    })
+}
+
+// A connection that gets delayed and reset according to a `ChaosConfig`.
+pub struct ChaosSocket<C> {
+    inner: C,
+    config: Arc<ChaosConfig>,
+    // The delay of the write in progress
+    delay: Option<Delay>,
+    // Once reset, a connection stays broken
+    broken: bool,
+}
+
+impl<C> ChaosSocket<C> {
+    // Break the connection if the dice say so.
+    fn maybe_reset(&mut self) -> io::Result<()> {
+        if !self.broken && happens(self.config.reset) {
+            self.broken = true;
+        }
+        if self.broken {
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "connection reset by chaos",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for ChaosSocket<C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let read = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.maybe_reset()?;
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for ChaosSocket<C> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if let Some((low, high)) = this.config.delay {
+            let delay = this.delay.get_or_insert_with(|| {
+                Delay::new(low + (high - low).mul_f64(seed::rng().gen()))
+            });
+            ready!(delay.poll_unpin(cx));
+        }
+        let written =
+            ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.delay = None;
+        this.maybe_reset()?;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        let cases = [
+            ("50ms", Duration::from_millis(50)),
+            ("2s", Duration::from_secs(2)),
+            ("1.5s", Duration::from_millis(1500)),
+            ("10m", Duration::from_secs(600)),
+            ("6h", Duration::from_secs(6 * 3600)),
+            ("0s", Duration::ZERO),
+        ];
+        for (s, duration) in cases {
+            assert_eq!(parse_duration(s), Ok(duration), "{}", s);
+        }
+    }
+
+    // None of these may panic
+    #[test]
+    fn not_durations() {
+        let cases = [
+            "",
+            "s",
+            "ms",
+            "5",
+            "5é",
+            "é",
+            "5x",
+            "-1s",
+            "NaNs",
+            "infs",
+            "-infs",
+            "1e30s",
+            "1e30h",
+            "99999999999999999999ms",
+            "1.5ms",
+            "two s",
+        ];
+        for s in cases {
+            assert!(parse_duration(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn chaos_configs() {
+        let config: ChaosConfig =
+            "drop=0.05,delay=50ms..200ms,reset=0.01".parse().unwrap();
+        assert_eq!(
+            config,
+            ChaosConfig {
+                drop: 0.05,
+                delay: Some((
+                    Duration::from_millis(50),
+                    Duration::from_millis(200)
+                )),
+                reset: 0.01,
+            }
+        );
+        for s in ["drop=2", "delay=2s..1s", "delay=1e30s", "what=1"] {
+            assert!(s.parse::<ChaosConfig>().is_err(), "{}", s);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use crate::output::Output;
+use libp2p::{
+    kad::{record::Key, GetRecordOk, QueryId, QueryResult},
+    PeerId,
+};
+use std::collections::HashMap;
+
+// The kademlia store keeps values of up to 64 KiB by default.
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 65536;
+
+// The smallest kademlia packet size there is any point in (the kademlia
+// default).
+const MIN_PACKET_SIZE: usize = 4096;
+
+// Room in a kademlia message for everything but the value (the key, the
+// publisher, and the protobuf framing).
+const PACKET_OVERHEAD: usize = 4096;
+
+// The value of an index record starts with this, followed by the number
+// of chunks and the length of the whole value, like
+// `nettest-chunks 16 1048576`.
+const INDEX_PREFIX: &str = "nettest-chunks ";
+
+// How big values may get, and what to do with the ones that are bigger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueLimits {
+    pub max_value_size: usize,
+    // Split values that are too big into chunk records (plus an index
+    // record under the original key), instead of refusing them
+    pub chunk: bool,
+}
+
+impl Default for ValueLimits {
+    fn default() -> Self {
+        ValueLimits {
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            chunk: false,
+        }
+    }
+}
+
+impl ValueLimits {
+    // The biggest kademlia message a node has to accept, so that records
+    // of the maximum size make it to the (remote) stores. Kademlia drops
+    // bigger messages without telling anybody why.
+    pub fn max_packet_size(&self) -> usize {
+        (self.max_value_size + PACKET_OVERHEAD).max(MIN_PACKET_SIZE)
+    }
+}
+
+// The key of chunk `i` of the value stored under `key`.
+pub fn chunk_key(key: &Key, i: usize) -> Key {
+    let mut chunk = key.to_vec();
+    chunk.extend_from_slice(format!("/chunk/{}", i).as_bytes());
+    Key::new(&chunk)
+}
+
+// The value of the index record of a value of `len` bytes, split into
+// `chunks` chunks.
+pub fn index_value(chunks: usize, len: usize) -> Vec<u8> {
+    format!("{}{} {}", INDEX_PREFIX, chunks, len).into_bytes()
+}
+
+// If `value` is an index record, the number of chunks and the length of
+// the whole value.
+pub fn parse_index(value: &[u8]) -> Option<(usize, usize)> {
+    let rest = std::str::from_utf8(value)
+        .ok()?
+        .strip_prefix(INDEX_PREFIX)?;
+    let (chunks, len) = rest.split_once(' ')?;
+    Some((chunks.parse().ok()?, len.parse().ok()?))
+}
+
+// Whether a transfer stores a chunked value, or fetches one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Put,
+    Get,
+}
+
+// A chunked value on its way into or out of the dht: one query per chunk
+// (and, for a PUT, one for the index record).
+#[derive(Debug)]
+pub struct Transfer {
+    pub direction: Direction,
+    pub key: Key,
+    // Who published the index record (for a GET)
+    pub publisher: Option<PeerId>,
+    pub output: Output,
+    // How many of the queries are still running
+    pub remaining: usize,
+    // The chunks fetched so far (for a GET)
+    pub chunks: Vec<Option<Vec<u8>>>,
+    // How long the whole value is
+    pub len: usize,
+    // What went wrong, if anything did
+    pub error: Option<String>,
+}
+
+impl Transfer {
+    pub fn new(
+        direction: Direction,
+        key: Key,
+        output: Output,
+        chunks: usize,
+        len: usize,
+    ) -> Self {
+        Transfer {
+            direction,
+            key,
+            publisher: None,
+            output,
+            remaining: 0,
+            chunks: vec![None; chunks],
+            len,
+            error: None,
+        }
+    }
+
+    // Put the fetched chunks back together (for a GET). Fails if any of
+    // them is missing, or they don't add up to the whole value.
+    pub fn reassemble(&mut self) -> Result<Vec<u8>, String> {
+        let mut value = Vec::with_capacity(self.len);
+        for (i, chunk) in self.chunks.iter_mut().enumerate() {
+            match chunk.take() {
+                Some(chunk) => value.extend_from_slice(&chunk),
+                None => return Err(format!("chunk {} is missing", i)),
+            }
+        }
+        if value.len() != self.len {
+            return Err(format!(
+                "the chunks add up to {} bytes instead of {}",
+                value.len(),
+                self.len
+            ));
+        }
+        Ok(value)
+    }
+}
+
+// The chunked transfers that are running, and which queries belong to
+// which of them.
+#[derive(Debug, Default)]
+pub struct Transfers {
+    next: usize,
+    transfers: HashMap<usize, Transfer>,
+    // The transfer and the chunk number of each query (the index record
+    // of a PUT has no chunk number)
+    queries: HashMap<QueryId, (usize, Option<usize>)>,
+}
+
+impl Transfers {
+    // Keep track of a transfer, whose queries get added with `add_query`.
+    pub fn start(&mut self, transfer: Transfer) -> usize {
+        let id = self.next;
+        self.next += 1;
+        self.transfers.insert(id, transfer);
+        id
+    }
+
+    // Count `query` as part of `transfer`.
+    pub fn add_query(
+        &mut self,
+        transfer: usize,
+        query: QueryId,
+        chunk: Option<usize>,
+    ) {
+        if let Some(t) = self.transfers.get_mut(&transfer) {
+            t.remaining += 1;
+            self.queries.insert(query, (transfer, chunk));
+        }
+    }
+
+    // Give up on a transfer before all its queries could be started. The
+    // ones that did start still run, but nobody hears about them.
+    pub fn abort(&mut self, transfer: usize) -> Option<Transfer> {
+        self.transfers.remove(&transfer)
+    }
+
+    pub fn owns(&self, query: &QueryId) -> bool {
+        self.queries.contains_key(query)
+    }
+
+    // Take in the result of one of the queries. Once the last query of a
+    // transfer is done, this hands back the whole transfer.
+    pub fn finish(
+        &mut self,
+        query: QueryId,
+        result: QueryResult,
+    ) -> Option<Transfer> {
+        let (id, chunk) = self.queries.remove(&query)?;
+        let transfer = self.transfers.get_mut(&id)?;
+        transfer.remaining -= 1;
+
+        let what = match chunk {
+            Some(i) => format!("chunk {}", i),
+            None => "the index record".to_string(),
+        };
+        match result {
+            QueryResult::PutRecord(Err(err)) => {
+                transfer
+                    .error
+                    .get_or_insert(format!("{}: {:?}", what, err));
+            }
+            QueryResult::GetRecord(Ok(GetRecordOk { records })) => {
+                let record = records.into_iter().next();
+                match (chunk, record) {
+                    (Some(i), Some(peer_record))
+                        if i < transfer.chunks.len() =>
+                    {
+                        transfer.chunks[i] = Some(peer_record.record.value)
+                    }
+                    _ => {
+                        transfer
+                            .error
+                            .get_or_insert(format!("{} is missing", what));
+                    }
+                }
+            }
+            QueryResult::GetRecord(Err(err)) => {
+                transfer
+                    .error
+                    .get_or_insert(format!("{}: {:?}", what, err));
+            }
+            _ => {}
+        }
+
+        if transfer.remaining == 0 {
+            self.transfers.remove(&id)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,441 @@
+use crate::{
+    config::{ClusterCommand, Opts},
+    control,
+    output::{Message, Output},
+    seed, simulate,
+};
+use async_std::task;
+use libp2p::{Multiaddr, PeerId};
+use rand::RngCore;
+use serde_json::{json, Value};
+use std::{
+    env,
+    error::Error,
+    fs,
+    io::{self, BufRead, BufReader, Read},
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{self, Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// How long node 0 has to start taking commands, before the others are
+// started (they bootstrap from it).
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The file in the directory of a cluster that says which nodes it has.
+const STATE_FILE: &str = "cluster.json";
+
+// `nettest cluster`: a cluster of nodes on this machine, each in a
+// process of its own (a daemon, with a control socket in --dir), for
+// what a simulation can't show, like real sockets, crashes, and nodes
+// being killed. `cluster start` gives every node a port of its own (from
+// --base-port on), an identity that follows from the seed (so that the
+// others can bootstrap from node 0 without asking it), and prints what
+// they all print, with the number of the node in front:
+//
+//   $ nettest cluster start -n 10 -- --replication-factor 3
+//   [0] Listening on "/ip4/127.0.0.1/tcp/4001"
+//   ...
+//
+// and from another terminal
+//
+//   $ nettest cluster exec 3 PUT k v
+//   $ nettest cluster partition 0,1,2,3,4 5,6,7,8,9
+//   $ nettest cluster heal
+//   $ nettest cluster stop
+//
+// mDNS is off on the nodes: they find each other through node 0.
+pub fn run(
+    opts: &Opts,
+    command: &ClusterCommand,
+) -> Result<(), Box<dyn Error>> {
+    match command {
+        ClusterCommand::Start {
+            nodes,
+            base_port,
+            dir,
+            options,
+        } => start(opts, *nodes, *base_port, dir, options),
+        ClusterCommand::Exec { node, command, dir } => {
+            exec(dir, *node, &command.join(" "))
+        }
+        ClusterCommand::Partition { groups, dir } => {
+            partition(dir, &groups.join(" "))
+        }
+        ClusterCommand::Heal { dir } => heal(dir),
+        ClusterCommand::Stop { dir } => stop(dir),
+    }
+}
+
+// A node of the cluster.
+#[derive(Debug, Clone)]
+struct Node {
+    peer_id: PeerId,
+    seed: u64,
+    listen: Multiaddr,
+    control: PathBuf,
+    pid: Option<u32>,
+}
+
+impl Node {
+    fn to_json(&self) -> Value {
+        json!({
+            "peer_id": self.peer_id.to_string(),
+            "seed": self.seed,
+            "listen": self.listen.to_string(),
+            "control": self.control,
+            "pid": self.pid,
+        })
+    }
+
+    fn from_json(node: &Value) -> Option<Self> {
+        Some(Node {
+            peer_id: node["peer_id"].as_str()?.parse().ok()?,
+            seed: node["seed"].as_u64()?,
+            listen: node["listen"].as_str()?.parse().ok()?,
+            control: node["control"].as_str()?.into(),
+            pid: node["pid"].as_u64().map(|pid| pid as u32),
+        })
+    }
+}
+
+fn start(
+    opts: &Opts,
+    nodes: usize,
+    base_port: u16,
+    dir: &Path,
+    options: &[String],
+) -> Result<(), Box<dyn Error>> {
+    if nodes == 0 {
+        return Err("a cluster needs at least one node".into());
+    }
+    if usize::from(base_port) + nodes > usize::from(u16::MAX) + 1 {
+        return Err(format!(
+            "there aren't {} ports from {} on",
+            nodes, base_port
+        )
+        .into());
+    }
+    if let Ok(running) = load(dir) {
+        if running.iter().any(|node| node.pid.is_some_and(alive)) {
+            return Err(format!(
+                "a cluster is already running in {} (cluster stop stops \
+                 it)",
+                dir.display()
+            )
+            .into());
+        }
+    }
+    fs::create_dir_all(dir)?;
+
+    // The seeds of the nodes follow from --seed (or a random one), and
+    // their identities from those
+    let first_seed = opts.seed.unwrap_or_else(|| seed::rng().next_u64());
+    let mut cluster: Vec<Node> = (0..nodes)
+        .map(|i| {
+            let seed = first_seed.wrapping_add(i as u64);
+            Node {
+                peer_id: PeerId::from(seed::keypair_for(seed).public()),
+                seed,
+                listen: format!(
+                    "/ip4/127.0.0.1/tcp/{}",
+                    base_port as usize + i
+                )
+                .parse()
+                .expect("a valid address"),
+                control: dir.join(format!("node-{}.sock", i)),
+                pid: None,
+            }
+        })
+        .collect();
+    let bootstrap =
+        format!("{}/p2p/{}", cluster[0].listen, cluster[0].peer_id);
+
+    // Ctrl-C (or SIGTERM) stops every node that was started, the way
+    // `cluster stop` does
+    let pids = Arc::new(Mutex::new(Vec::new()));
+    let started = pids.clone();
+    ctrlc::set_handler(move || {
+        Output::Terminal.info("Stopping the cluster");
+        for pid in started.lock().unwrap().iter() {
+            terminate(*pid);
+        }
+    })?;
+
+    let exe = env::current_exe()?;
+    let mut children = Vec::new();
+    for i in 0..nodes {
+        let node = &cluster[i];
+        // A control socket left over from the last run would look like
+        // node 0 is up already
+        let _ = fs::remove_file(&node.control);
+        let mut command = Command::new(&exe);
+        command
+            .arg("daemon")
+            .arg("--control")
+            .arg(&node.control)
+            .arg("--listen")
+            .arg(node.listen.to_string())
+            .arg("--seed")
+            .arg(node.seed.to_string())
+            .arg("--no-mdns");
+        if i > 0 {
+            command.arg("--bootstrap").arg(&bootstrap);
+        }
+        command
+            .args(options)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Ctrl-C stops the cluster through us (see below), and not
+            // every node at once
+            .process_group(0);
+        let mut child = command.spawn()?;
+        forward(i, child.stdout.take(), false);
+        forward(i, child.stderr.take(), true);
+        cluster[i].pid = Some(child.id());
+        pids.lock().unwrap().push(child.id());
+        children.push(child);
+
+        if i == 0 {
+            let node = &mut children[0];
+            if let Err(err) = wait_for_control(&cluster[0].control, node) {
+                terminate(node.id());
+                return Err(err.into());
+            }
+        }
+    }
+    save(dir, &cluster)?;
+    Output::Terminal.info(format!(
+        "Started {} nodes in {} (`nettest cluster exec <n> <command>` \
+         sends one a command, `nettest cluster stop` stops them)",
+        nodes,
+        dir.display()
+    ));
+
+    for (i, mut child) in children.into_iter().enumerate() {
+        let status = child.wait()?;
+        Output::Terminal.info(format!("[{}] Exited ({})", i, status));
+    }
+    let _ = fs::remove_file(dir.join(STATE_FILE));
+    Output::Terminal.info("The cluster stopped");
+    Ok(())
+}
+
+// Print every line of `stream` (what node `i` prints), with the number
+// of the node in front.
+fn forward(
+    i: usize,
+    stream: Option<impl Read + Send + 'static>,
+    errors: bool,
+) {
+    let stream = match stream {
+        Some(stream) => stream,
+        None => return,
+    };
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            match errors {
+                true => {
+                    Output::Terminal.error(format!("[{}] {}", i, line))
+                }
+                false => {
+                    Output::Terminal.info(format!("[{}] {}", i, line))
+                }
+            }
+        }
+    });
+}
+
+// Wait for the node to create its control socket (which is once it is
+// listening).
+fn wait_for_control(path: &Path, child: &mut Child) -> io::Result<()> {
+    let started = Instant::now();
+    while !path.exists() {
+        if let Some(status) = child.try_wait()? {
+            return Err(io::Error::other(format!(
+                "node 0 exited ({}) before it started",
+                status
+            )));
+        }
+        if started.elapsed() > START_TIMEOUT {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "node 0 didn't start taking commands",
+            ));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+fn exec(
+    dir: &Path,
+    node: usize,
+    command: &str,
+) -> Result<(), Box<dyn Error>> {
+    let cluster = load(dir)?;
+    let control = match cluster.get(node) {
+        Some(node) => &node.control,
+        None => {
+            return Err(format!(
+                "there is no node {} (there are {})",
+                node,
+                cluster.len()
+            )
+            .into())
+        }
+    };
+    let ok = task::block_on(control::send_command(control, command, None))
+        .map_err(|err| format!("node {}: {}", node, err))?;
+    if !ok {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+// Have every node ban the nodes of the other groups (which also takes
+// them out of its routing table, for `heal` to put back).
+fn partition(dir: &Path, groups: &str) -> Result<(), Box<dyn Error>> {
+    let cluster = load(dir)?;
+    let groups = simulate::parse_groups(groups, cluster.len())?;
+    let pairs = simulate::split_pairs(&groups, cluster.len());
+    let failed = pairs
+        .iter()
+        .filter(|(a, b)| {
+            let ban = format!("BAN {}", cluster[*b].peer_id);
+            !tell(&cluster, *a, &[ban])
+        })
+        .count();
+    Output::Terminal.info(format!(
+        "{} pairs of nodes can't reach each other now",
+        (pairs.len() - failed) / 2
+    ));
+    Ok(())
+}
+
+// Undo every ban of `partition`: every node unbans every other one, and
+// adds it to its routing table again.
+fn heal(dir: &Path) -> Result<(), Box<dyn Error>> {
+    let cluster = load(dir)?;
+    let mut healed = 0;
+    for a in 0..cluster.len() {
+        let commands: Vec<String> = (0..cluster.len())
+            .filter(|b| *b != a)
+            .flat_map(|b| {
+                let node = &cluster[b];
+                vec![
+                    format!("UNBAN {}", node.peer_id),
+                    format!(
+                        "ADD_ADDRESS {} {}",
+                        node.peer_id, node.listen
+                    ),
+                ]
+            })
+            .collect();
+        if tell(&cluster, a, &commands) {
+            healed += 1;
+        }
+    }
+    Output::Terminal.info(format!(
+        "Healed the cluster ({} of {} nodes can reach every other one)",
+        healed,
+        cluster.len()
+    ));
+    Ok(())
+}
+
+// Send node `i` the `commands`, and report what goes wrong. Returns
+// whether they all worked.
+fn tell(cluster: &[Node], i: usize, commands: &[String]) -> bool {
+    let control = &cluster[i].control;
+    for command in commands {
+        let replies = match task::block_on(control::ask(control, command))
+        {
+            Ok(replies) => replies,
+            Err(err) => {
+                Output::Terminal.error(format!("[{}] {}", i, err));
+                return false;
+            }
+        };
+        for reply in replies {
+            if let Message::Error(err) = reply {
+                Output::Terminal
+                    .error(format!("[{}] {}: {}", i, command, err));
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn stop(dir: &Path) -> Result<(), Box<dyn Error>> {
+    let cluster = load(dir)?;
+    let running: Vec<u32> = cluster
+        .iter()
+        .filter_map(|node| node.pid)
+        .filter(|pid| alive(*pid))
+        .collect();
+    for pid in &running {
+        terminate(*pid);
+    }
+    Output::Terminal.info(format!(
+        "Stopping {} nodes of the cluster in {}",
+        running.len(),
+        dir.display()
+    ));
+    Ok(())
+}
+
+fn save(dir: &Path, cluster: &[Node]) -> io::Result<()> {
+    let nodes: Vec<Value> = cluster.iter().map(Node::to_json).collect();
+    fs::write(
+        dir.join(STATE_FILE),
+        format!("{:#}\n", json!({ "nodes": nodes })),
+    )
+}
+
+fn load(dir: &Path) -> Result<Vec<Node>, Box<dyn Error>> {
+    let path = dir.join(STATE_FILE);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(format!(
+                "no cluster is running in {}",
+                dir.display()
+            )
+            .into())
+        }
+        Err(err) => {
+            return Err(format!("{}: {}", path.display(), err).into())
+        }
+    };
+    let state: Value = serde_json::from_str(&text)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    state["nodes"]
+        .as_array()
+        .and_then(|nodes| nodes.iter().map(Node::from_json).collect())
+        .ok_or_else(|| {
+            format!("{}: not a list of nodes", path.display()).into()
+        })
+}
+
+// Whether the process `pid` is still there.
+fn alive(pid: u32) -> bool {
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+// Ask the process `pid` to stop, the way the nodes like (they shut down
+// cleanly on SIGTERM).
+fn terminate(pid: u32) {
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{
+    api::ApiReply,
+    output::{Message, Output, OutputSink},
+};
+use futures::channel::oneshot;
+use libp2p::kad::{record::Key, QueryId, QueryResult, Quorum};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+// Who a GET is for: a command, or the HTTP api (LOAD included). GETs
+// only join the ones of their own kind, since each of those reports its
+// results its own way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Waiter {
+    Command,
+    Api,
+}
+
+// What a GET asks for: the key, and how many answers it takes.
+type Ask = (Waiter, Key, (u8, usize));
+
+fn quorum(quorum: Quorum) -> (u8, usize) {
+    match quorum {
+        Quorum::One => (0, 1),
+        Quorum::Majority => (1, 0),
+        Quorum::All => (2, 0),
+        Quorum::N(n) => (3, n.get()),
+    }
+}
+
+// The GETs that are running, for --coalesce-gets: a GET of a key that a
+// GET is already looking up (for the same quorum) waits for that one,
+// rather than starting a query of its own, and gets its results too. The
+// way a caching layer in front of the clients would have it, and LOAD
+// with a small keyspace sends a lot fewer queries.
+#[derive(Debug, Default)]
+pub struct Coalescer {
+    enabled: bool,
+    running: HashMap<Ask, QueryId>,
+    queries: HashMap<QueryId, Ask>,
+    // Where the results of GET commands go once others joined them
+    outputs: HashMap<QueryId, Arc<Fanout>>,
+    // The HTTP api requests that joined a GET of the api
+    replies: HashMap<QueryId, Vec<oneshot::Sender<ApiReply>>>,
+}
+
+impl Coalescer {
+    pub fn new(enabled: bool) -> Self {
+        Coalescer {
+            enabled,
+            ..Default::default()
+        }
+    }
+
+    // The GET that is already looking up `key`, if there is one to join.
+    pub fn running(
+        &self,
+        waiter: Waiter,
+        key: &Key,
+        of: Quorum,
+    ) -> Option<QueryId> {
+        if !self.enabled {
+            return None;
+        }
+        self.running
+            .get(&(waiter, key.clone(), quorum(of)))
+            .copied()
+    }
+
+    // A GET started, which others may join until it finishes.
+    pub fn started(
+        &mut self,
+        waiter: Waiter,
+        key: Key,
+        of: Quorum,
+        id: QueryId,
+    ) {
+        if !self.enabled {
+            return;
+        }
+        let ask = (waiter, key, quorum(of));
+        self.running.insert(ask.clone(), id);
+        self.queries.insert(id, ask);
+    }
+
+    // A GET command joins the one of `id`, which reports to `running`
+    // so far. Returns where the results go from now on (to both).
+    pub fn join_output(
+        &mut self,
+        id: QueryId,
+        running: Output,
+        output: Output,
+    ) -> Output {
+        let fanout = self
+            .outputs
+            .entry(id)
+            .or_insert_with(|| Arc::new(Fanout::new(running)));
+        fanout.add(output);
+        Output::Sink(fanout.clone())
+    }
+
+    // An api request joins the GET of `id`.
+    pub fn join_reply(
+        &mut self,
+        id: QueryId,
+        reply: oneshot::Sender<ApiReply>,
+    ) {
+        self.replies.entry(id).or_default().push(reply);
+    }
+
+    // The GET of `id` is done: nobody joins it anymore. Returns the api
+    // requests that wait for its result on top of its own.
+    pub fn finish(
+        &mut self,
+        id: &QueryId,
+    ) -> Vec<oneshot::Sender<ApiReply>> {
+        if let Some(ask) = self.queries.remove(id) {
+            self.running.remove(&ask);
+        }
+        self.outputs.remove(id);
+        self.replies.remove(id).unwrap_or_default()
+    }
+}
+
+// The same reply, for another api request that joined the GET (GETs
+// are all there is to join).
+pub fn copy_reply(reply: &ApiReply) -> Option<ApiReply> {
+    match reply {
+        ApiReply::Query(QueryResult::GetRecord(result), stats) => {
+            Some(ApiReply::Query(
+                QueryResult::GetRecord(result.clone()),
+                stats.clone(),
+            ))
+        }
+        ApiReply::Error(message) => Some(ApiReply::Error(message.clone())),
+        _ => None,
+    }
+}
+
+// The outputs of all the GET commands that wait for the same query.
+#[derive(Debug)]
+pub struct Fanout(Mutex<Vec<Output>>);
+
+impl Fanout {
+    fn new(output: Output) -> Self {
+        Fanout(Mutex::new(vec![output]))
+    }
+
+    fn add(&self, output: Output) {
+        self.0.lock().unwrap().push(output);
+    }
+}
+
+impl OutputSink for Fanout {
+    fn show(&self, message: &Message) {
+        for output in self.0.lock().unwrap().iter() {
+            output.show(message.clone());
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.0.lock().unwrap().iter().all(Output::is_closed)
+    }
+}
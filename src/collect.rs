@@ -0,0 +1,298 @@
+use crate::config::CollectArgs;
+use async_std::{io, net::TcpStream, task};
+use futures::{future, AsyncReadExt, AsyncWriteExt};
+use std::{
+    error::Error,
+    fs::{self, OpenOptions},
+    io::Write,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// The columns of the CSV snapshots, a row per node (and one of the whole
+// fleet, as "total") each round.
+const HEADER: &str =
+    "time_ms,node,up,ready,routing_peers,records,queries_ok,queries_failed";
+
+// What one scrape of a node found.
+#[derive(Debug, Clone, Default)]
+struct Scrape {
+    // Why the node couldn't be scraped, if it couldn't
+    down: Option<String>,
+    // Whether /healthz says it is ready
+    ready: bool,
+    // The peers in its routing table, the records in its store, and how
+    // its GETs and PUTs went, so far
+    routing: u64,
+    records: u64,
+    ok: u64,
+    failed: u64,
+}
+
+impl Scrape {
+    fn up(&self) -> bool {
+        self.down.is_none()
+    }
+}
+
+// Scrape the /metrics and /healthz of every node of a fleet (running with
+// --api) every so often, and print what they add up to: how many are up
+// and ready, the records all of them hold, how big their routing tables
+// are, and how many of all of their queries went through (in all, and
+// since the last round). With --csv, every round is appended to a file
+// too.
+pub fn run(args: &CollectArgs) -> Result<(), Box<dyn Error>> {
+    let nodes: Vec<String> = args
+        .nodes
+        .iter()
+        .map(|node| node.trim_start_matches("http://").to_string())
+        .filter(|node| !node.is_empty())
+        .collect();
+    if nodes.is_empty() {
+        return Err("--nodes: there are no nodes to collect from".into());
+    }
+    let mut csv = match &args.csv {
+        Some(path) => {
+            let fresh = fs::metadata(path).map_or(true, |m| m.len() == 0);
+            let mut file =
+                OpenOptions::new().create(true).append(true).open(path)?;
+            if fresh {
+                writeln!(file, "{}", HEADER)?;
+            }
+            Some(file)
+        }
+        None => None,
+    };
+
+    let started = Instant::now();
+    // The queries of the round before, for the rate since then
+    let mut before: Option<(u64, u64)> = None;
+    let mut round = 0;
+    loop {
+        round += 1;
+        let scrapes =
+            task::block_on(future::join_all(nodes.iter().map(|node| {
+                scrape(node, args.token.as_deref(), args.timeout)
+            })));
+        let elapsed = started.elapsed().as_secs();
+        let total = add_up(&scrapes);
+        let (up, ready) = (
+            scrapes.iter().filter(|s| s.up()).count(),
+            scrapes.iter().filter(|s| s.ready).count(),
+        );
+        let routing: Vec<u64> = scrapes
+            .iter()
+            .filter(|s| s.up())
+            .map(|s| s.routing)
+            .collect();
+        let (min, max) = (
+            routing.iter().min().copied().unwrap_or(0),
+            routing.iter().max().copied().unwrap_or(0),
+        );
+        let lately = match before {
+            Some((ok, failed))
+                if total.ok + total.failed > ok + failed =>
+            {
+                let n = (total.ok + total.failed) - (ok + failed);
+                let ok = total.ok.saturating_sub(ok);
+                format!(", {:.1}% since the last round", rate(ok, n))
+            }
+            _ => String::new(),
+        };
+        before = Some((total.ok, total.failed));
+        println!(
+            "after {}s: {} of {} nodes up, {} ready: {} records, {} peers \
+             in the routing tables ({} to {} a node), {} of {} queries \
+             ok ({:.1}%{})",
+            elapsed,
+            up,
+            nodes.len(),
+            ready,
+            total.records,
+            total.routing,
+            min,
+            max,
+            total.ok,
+            total.ok + total.failed,
+            rate(total.ok, total.ok + total.failed),
+            lately
+        );
+        let width = nodes.iter().map(String::len).max().unwrap_or(0);
+        for (node, scrape) in nodes.iter().zip(&scrapes) {
+            match &scrape.down {
+                Some(why) => {
+                    println!(
+                        "  {:width$}  DOWN ({})",
+                        node,
+                        why,
+                        width = width
+                    )
+                }
+                None => println!(
+                    "  {:width$}  {:9}  {:4} peers  {:6} records  {} \
+                     queries, {:.1}% ok",
+                    node,
+                    match scrape.ready {
+                        true => "ready",
+                        false => "not ready",
+                    },
+                    scrape.routing,
+                    scrape.records,
+                    scrape.ok + scrape.failed,
+                    rate(scrape.ok, scrape.ok + scrape.failed),
+                    width = width
+                ),
+            }
+        }
+
+        if let Some(csv) = &mut csv {
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_millis() as u64)
+                .unwrap_or(0);
+            let rows = nodes
+                .iter()
+                .map(String::as_str)
+                .zip(&scrapes)
+                .chain(Some(("total", &total)));
+            for (node, scrape) in rows {
+                writeln!(
+                    csv,
+                    "{},{},{},{},{},{},{},{}",
+                    time,
+                    node,
+                    scrape.up(),
+                    scrape.ready,
+                    scrape.routing,
+                    scrape.records,
+                    scrape.ok,
+                    scrape.failed
+                )?;
+            }
+            csv.flush()?;
+        }
+
+        if args.count.is_some_and(|count| round >= count) {
+            return Ok(());
+        }
+        std::thread::sleep(args.interval);
+    }
+}
+
+// The whole fleet, as one (of the nodes that are up).
+fn add_up(scrapes: &[Scrape]) -> Scrape {
+    let up: Vec<&Scrape> = scrapes.iter().filter(|s| s.up()).collect();
+    Scrape {
+        down: match up.is_empty() {
+            true => Some("all nodes are down".to_string()),
+            false => None,
+        },
+        ready: scrapes.iter().all(|s| s.ready),
+        routing: up.iter().map(|s| s.routing).sum(),
+        records: up.iter().map(|s| s.records).sum(),
+        ok: up.iter().map(|s| s.ok).sum(),
+        failed: up.iter().map(|s| s.failed).sum(),
+    }
+}
+
+// The share of `n` that `ok` is, in percent (all of nothing).
+fn rate(ok: u64, n: u64) -> f64 {
+    match n {
+        0 => 100.0,
+        n => ok as f64 * 100.0 / n as f64,
+    }
+}
+
+// Scrape `node` (host:port), within `timeout`.
+async fn scrape(
+    node: &str,
+    token: Option<&str>,
+    timeout: Duration,
+) -> Scrape {
+    let scraped = io::timeout(timeout, async {
+        let (status, metrics) = get(node, "/metrics", token).await?;
+        if status != 200 {
+            return Err(io::Error::other(format!(
+                "/metrics answered {}",
+                status
+            )));
+        }
+        // 503 (with what is missing) until the node is ready
+        let (status, _) = get(node, "/healthz", None).await?;
+        Ok((metrics, status == 200))
+    })
+    .await;
+    let (metrics, ready) = match scraped {
+        Ok(scraped) => scraped,
+        Err(err) => {
+            return Scrape {
+                down: Some(err.to_string()),
+                ..Scrape::default()
+            }
+        }
+    };
+    let mut scrape = Scrape {
+        ready,
+        ..Scrape::default()
+    };
+    for (name, labels, value) in samples(&metrics) {
+        match name {
+            "nettest_kbucket_entries" => scrape.routing += value as u64,
+            "nettest_records" => scrape.records = value as u64,
+            "nettest_queries_total" if labels.contains("\"ok\"") => {
+                scrape.ok += value as u64
+            }
+            "nettest_queries_total" => scrape.failed += value as u64,
+            _ => {}
+        }
+    }
+    scrape
+}
+
+// The samples of the Prometheus text `metrics`: the name of each, its
+// labels (as they are) and its value.
+fn samples(metrics: &str) -> impl Iterator<Item = (&str, &str, f64)> {
+    metrics
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (series, value) = line.rsplit_once(' ')?;
+            let value = value.parse().ok()?;
+            let (name, labels) = match series.find('{') {
+                Some(at) => series.split_at(at),
+                None => (series, ""),
+            };
+            Some((name, labels, value))
+        })
+}
+
+// GET `path` of `host`, with the bearer `token` if there is one. Returns
+// the status, and the body.
+async fn get(
+    host: &str,
+    path: &str,
+    token: Option<&str>,
+) -> io::Result<(u16, String)> {
+    let mut stream = TcpStream::connect(host).await?;
+    let authorization = match token {
+        Some(token) => format!("Authorization: Bearer {}\r\n", token),
+        None => String::new(),
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n",
+        path, host, authorization
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| io::Error::other("not an HTTP response"))?;
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| io::Error::other("not an HTTP response"))?;
+    Ok((status, body.to_string()))
+}
@@ -0,0 +1,1343 @@
+use crate::{
+    barrier::{self, Condition},
+    bootstrap,
+    chaos::parse_duration,
+    load::Workload,
+    rendezvous,
+    value::Compression,
+    watch,
+};
+use clap::ValueEnum;
+use libp2p::{kad::Quorum, multiaddr::Protocol, Multiaddr, PeerId};
+use std::{
+    collections::BTreeMap, fmt, num::NonZeroUsize, path::PathBuf,
+    str::FromStr, time::Duration,
+};
+
+// The commands there are.
+pub const COMMANDS: &[&str] = &[
+    "GET",
+    "PUT",
+    "PUT_CAS",
+    "CAS",
+    "MGET",
+    "MPUT",
+    "FETCH",
+    "WATCH",
+    "WATCH_PROVIDERS",
+    "UNWATCH",
+    "REPUBLISH",
+    "AUDIT",
+    "REGISTER",
+    "DISCOVER",
+    "NS",
+    "PEERS",
+    "BANDWIDTH",
+    "BAN",
+    "UNBAN",
+    "ROUTING",
+    "BUCKETS",
+    "BOOK",
+    "ADD_ADDRESS",
+    "REMOVE_PEER",
+    "DIAL",
+    "DIALS",
+    "DISCONNECT",
+    "WHOAMI",
+    "LOOKUP_PEER",
+    "ADDRS",
+    "BOOTSTRAP",
+    "WAIT_PEERS",
+    "WAIT_BOOTSTRAP",
+    "CLOSEST",
+    "CANCEL",
+    "LOCAL",
+    "LIST",
+    "SNAPSHOT",
+    "RESTORE",
+    "SEND",
+    "SENDFILE",
+    "TOPOLOGY",
+    "LOAD",
+    "CACHE",
+    "VERIFY",
+    "ANALYZE",
+    "TIME",
+    "ALIAS",
+];
+
+// The aliases every node knows, on top of those of --alias.
+const BUILTIN_ALIASES: &[(&str, &str)] = &[("g", "GET"), ("p", "PUT")];
+
+// How many routing table changes ROUTING shows, unless told otherwise.
+const ROUTING_CHANGES: usize = 20;
+
+// A command, as parsed from a line like `PUT foo bar compress=deflate`
+// (handler::handle_input_line does what it says). Keys are the way they
+// were typed, without the namespace.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Get {
+        key: String,
+        // How many peers have to answer (with more than one, they may
+        // disagree about the value)
+        quorum: Quorum,
+        // How long to wait for the answers, at most (kademlia's own
+        // --query-timeout aside)
+        timeout: Option<Duration>,
+    },
+    Put {
+        key: String,
+        value: Vec<u8>,
+        compress: Compress,
+        // How many peers have to store it
+        quorum: Quorum,
+    },
+    // The key is the hash of the value
+    PutCas {
+        value: Vec<u8>,
+        compress: Compress,
+        quorum: Quorum,
+    },
+    Cas {
+        key: String,
+        expected: u64,
+        value: Vec<u8>,
+        compress: Compress,
+        quorum: Quorum,
+    },
+    // GETs of all the keys at the same time, reported together
+    MGet(Vec<String>),
+    // PUTs of the records in a file (see batch::read_records), likewise
+    MPut(PathBuf),
+    Fetch {
+        key: String,
+        peer: PeerId,
+    },
+    Register {
+        namespace: String,
+        ttl: Duration,
+    },
+    Discover {
+        namespace: String,
+    },
+    Watch {
+        key: String,
+        interval: Duration,
+    },
+    // Watch a key through the announcements of its new versions (see
+    // --announce-updates)
+    WatchProviders {
+        key: String,
+        interval: Duration,
+    },
+    Unwatch {
+        key: String,
+    },
+    Republish,
+    // How the rounds of the auditor went
+    Audit,
+    // Without an alias, list them
+    Alias(Option<Alias>),
+    // Without a prefix, show the namespace; `Some(None)` (from `NS -`)
+    // leaves it altogether
+    Namespace(Option<Option<String>>),
+    // With `-v`, with the latencies of each peer too
+    Peers {
+        verbose: bool,
+    },
+    Bandwidth,
+    Ban(PeerId),
+    Unban(PeerId),
+    // The last changes to the routing table
+    Routing(usize),
+    Buckets,
+    // The whole address book, or what it has on one peer
+    Book(Option<PeerId>),
+    // The address is without the /p2p/<peer id> at the end
+    AddAddress {
+        peer: PeerId,
+        addr: Multiaddr,
+    },
+    RemovePeer(PeerId),
+    // The peer the address ended in, if it did (the address is without
+    // it, since the transports don't take it)
+    Dial {
+        addr: Multiaddr,
+        peer: Option<PeerId>,
+    },
+    // The dials going on, and the ones waiting for their turn
+    Dials,
+    Disconnect {
+        peer: PeerId,
+        // How long to keep the peer from connecting again
+        hold: Option<Duration>,
+    },
+    Whoami,
+    // The peer info the peer published about itself (see peerinfo)
+    LookupPeer(PeerId),
+    Addrs,
+    Bootstrap,
+    // Read no more commands from the terminal until there are enough
+    // peers (or a bootstrap went through), for at most `timeout`
+    Wait {
+        until: Condition,
+        timeout: Duration,
+    },
+    Closest(Target),
+    Cancel(Cancel),
+    // A single record, or all of them
+    Local(Option<String>),
+    // The keys in the index of a namespace (see --key-index): the
+    // current one without a namespace, and none with `LIST -`
+    List(Option<Option<String>>),
+    Snapshot(PathBuf),
+    Restore(PathBuf),
+    Send {
+        peer: PeerId,
+        text: String,
+    },
+    SendFile {
+        peer: PeerId,
+        path: PathBuf,
+    },
+    Topology(PathBuf),
+    Load(LoadAction),
+    // How the cache of --cache-size does, or (with `clear`) empty it
+    Cache {
+        clear: bool,
+    },
+    // Which of the closest peers to the key hold its record
+    Verify(String),
+    // How the keys in a file (or the local ones) spread over the peers
+    Analyze(Option<PathBuf>),
+}
+
+impl Command {
+    // Whether the command leaves the node, its peers and their stores as
+    // they were (see `auth::allows`).
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Get { .. }
+                | Command::MGet(_)
+                | Command::Fetch { .. }
+                | Command::Discover { .. }
+                | Command::Audit
+                | Command::Alias(None)
+                | Command::Namespace(None)
+                | Command::Peers { .. }
+                | Command::Bandwidth
+                | Command::Dials
+                | Command::Routing(_)
+                | Command::Buckets
+                | Command::Book(_)
+                | Command::Whoami
+                | Command::LookupPeer(_)
+                | Command::Addrs
+                | Command::Closest(_)
+                | Command::Local(_)
+                | Command::List(_)
+                | Command::Cache { clear: false }
+                | Command::Verify(_)
+                | Command::Analyze(_)
+        )
+    }
+}
+
+// How to compress the value of a PUT (or PUT_CAS, or CAS), from options
+// like `compress=deflate` (or `compress=none`, to turn off --compress).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compress {
+    // As --compress says
+    Default,
+    None,
+    With(Compression),
+}
+
+impl Compress {
+    // The compression to use, where `default` is --compress.
+    pub fn or(self, default: Option<Compression>) -> Option<Compression> {
+        match self {
+            Compress::Default => default,
+            Compress::None => None,
+            Compress::With(compression) => Some(compression),
+        }
+    }
+}
+
+// What CLOSEST looks up: a peer id as itself (the way peers find each
+// other), anything else as a record key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Peer(PeerId),
+    Key(String),
+}
+
+// What CANCEL stops: one of the running queries (by the number bare
+// CANCEL lists them with), or all of them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cancel {
+    List,
+    One(u64),
+    All,
+}
+
+// What LOAD does: start a workload, stop it, or (bare) tell how the
+// running one goes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadAction {
+    Start(Workload),
+    Stop,
+    Status,
+}
+
+// Why a line isn't a command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    UnknownCommand(String),
+    // An argument that isn't there, like "a key"
+    Missing(&'static str),
+    // An argument that is there, but isn't one, like "peer id"
+    Invalid(&'static str),
+    UnknownOption {
+        command: &'static str,
+        option: String,
+    },
+    // Everything else, like durations that aren't
+    Other(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnknownCommand(_) => {
+                write!(f, "Expected {}", COMMANDS.join(", "))
+            }
+            ParseError::Missing(what) => write!(f, "Expected {}", what),
+            ParseError::Invalid(what) => write!(f, "Invalid {}", what),
+            ParseError::UnknownOption { command, option } => {
+                write!(f, "Unknown {} option {:?}", command, option)
+            }
+            ParseError::Other(err) => f.write_str(err),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<String> for ParseError {
+    fn from(err: String) -> Self {
+        ParseError::Other(err)
+    }
+}
+
+// A short name for a command, like `pf=PUT foo`: a line that starts
+// with an alias stands for its command, with the rest of the line after
+// it (so `pf bar` is `PUT foo bar`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alias {
+    pub name: String,
+    pub command: String,
+}
+
+impl FromStr for Alias {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, command) = s.split_once('=').ok_or_else(|| {
+            format!("expected name=COMMAND, got {:?}", s)
+        })?;
+        if name.is_empty() || name.contains(' ') {
+            return Err(format!(
+                "{:?} can't be the name of an alias",
+                name
+            ));
+        }
+        if COMMANDS.contains(&name) {
+            return Err(format!("{} is a command already", name));
+        }
+        let first = command.split(' ').next().unwrap_or_default();
+        if !COMMANDS.contains(&first) {
+            return Err(format!(
+                "an alias has to stand for a command, not {:?}",
+                first
+            ));
+        }
+        Ok(Alias {
+            name: name.to_string(),
+            command: command.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Alias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.command)
+    }
+}
+
+// The aliases a node knows (the built-in ones, and those of --alias or
+// ALIAS, which can replace them), which commands are parsed with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aliases(BTreeMap<String, String>);
+
+impl Aliases {
+    pub fn new(aliases: &[Alias]) -> Self {
+        let mut table = Aliases(
+            BUILTIN_ALIASES
+                .iter()
+                .map(|(name, command)| {
+                    (name.to_string(), command.to_string())
+                })
+                .collect(),
+        );
+        for alias in aliases {
+            table.add(alias.clone());
+        }
+        table
+    }
+
+    pub fn add(&mut self, alias: Alias) {
+        self.0.insert(alias.name, alias.command);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Alias> + '_ {
+        self.0.iter().map(|(name, command)| Alias {
+            name: name.clone(),
+            command: command.clone(),
+        })
+    }
+
+    // Parse `line`, with its first word standing for a command if it is
+    // an alias.
+    pub fn parse(&self, line: &str) -> Result<Command, ParseError> {
+        let (name, rest) = match line.split_once(' ') {
+            Some((name, rest)) => (name, Some(rest)),
+            None => (line, None),
+        };
+        match (self.0.get(name), rest) {
+            (Some(command), Some(rest)) => {
+                format!("{} {}", command, rest).parse()
+            }
+            (Some(command), None) => command.parse(),
+            (None, _) => line.parse(),
+        }
+    }
+}
+
+impl Default for Aliases {
+    fn default() -> Self {
+        Aliases::new(&[])
+    }
+}
+
+impl FromStr for Command {
+    type Err = ParseError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut args = line.split(' ');
+        let name = args.next().unwrap_or_default();
+        let command = match name {
+            "GET" => {
+                let key = string(args.next(), "a key")?;
+                let mut quorum = Quorum::One;
+                let mut timeout = None;
+                for option in args {
+                    match option.split_once('=') {
+                        Some(("quorum", n)) => quorum = parse_quorum(n)?,
+                        Some(("timeout", t)) => {
+                            timeout = Some(parse_duration(t)?)
+                        }
+                        _ => return Err(unknown_option("GET", option)),
+                    }
+                }
+                Command::Get {
+                    key,
+                    quorum,
+                    timeout,
+                }
+            }
+            "PUT" => {
+                let key = string(args.next(), "a key")?;
+                let value = bytes(args.next(), "value")?;
+                let (compress, quorum) = put_options("PUT", args)?;
+                Command::Put {
+                    key,
+                    value,
+                    compress,
+                    quorum,
+                }
+            }
+            "PUT_CAS" => {
+                let value = bytes(args.next(), "value")?;
+                let (compress, quorum) = put_options("PUT_CAS", args)?;
+                Command::PutCas {
+                    value,
+                    compress,
+                    quorum,
+                }
+            }
+            "CAS" => {
+                let key = string(args.next(), "a key")?;
+                let expected =
+                    parse(args.next(), "the current version", "version")?;
+                let value = bytes(args.next(), "value")?;
+                let (compress, quorum) = put_options("CAS", args)?;
+                Command::Cas {
+                    key,
+                    expected,
+                    value,
+                    compress,
+                    quorum,
+                }
+            }
+            "MGET" => {
+                let keys: Vec<String> = args.map(String::from).collect();
+                if keys.is_empty() {
+                    return Err(ParseError::Missing("a key"));
+                }
+                Command::MGet(keys)
+            }
+            "MPUT" => Command::MPut(path(args.next())?),
+            "FETCH" => Command::Fetch {
+                key: string(args.next(), "a key")?,
+                peer: peer_id(args.next())?,
+            },
+            "REGISTER" => Command::Register {
+                namespace: string(args.next(), "a namespace")?,
+                ttl: match args.next() {
+                    Some(ttl) => parse_duration(ttl)?,
+                    None => rendezvous::DEFAULT_TTL,
+                },
+            },
+            "DISCOVER" => Command::Discover {
+                namespace: string(args.next(), "a namespace")?,
+            },
+            "WATCH" => Command::Watch {
+                key: string(args.next(), "a key")?,
+                interval: watch_interval(args.next())?,
+            },
+            "WATCH_PROVIDERS" => Command::WatchProviders {
+                key: string(args.next(), "a key")?,
+                interval: watch_interval(args.next())?,
+            },
+            "UNWATCH" => Command::Unwatch {
+                key: string(args.next(), "a key")?,
+            },
+            "ALIAS" => {
+                let rest: Vec<&str> = args.collect();
+                match rest.split_first() {
+                    Some((name, command)) if !command.is_empty() => {
+                        Command::Alias(Some(
+                            format!("{}={}", name, command.join(" "))
+                                .parse()?,
+                        ))
+                    }
+                    Some(_) => {
+                        return Err(ParseError::Missing("a command"))
+                    }
+                    None => Command::Alias(None),
+                }
+            }
+            "REPUBLISH" => Command::Republish,
+            "AUDIT" => Command::Audit,
+            "NS" => Command::Namespace(args.next().map(|prefix| {
+                Some(prefix).filter(|p| *p != "-").map(String::from)
+            })),
+            "PEERS" => Command::Peers {
+                verbose: match args.next() {
+                    Some("-v") => true,
+                    Some(option) => {
+                        return Err(unknown_option("PEERS", option))
+                    }
+                    None => false,
+                },
+            },
+            "BANDWIDTH" => Command::Bandwidth,
+            "BAN" => Command::Ban(peer_id(args.next())?),
+            "UNBAN" => Command::Unban(peer_id(args.next())?),
+            "ROUTING" => Command::Routing(match args.next() {
+                Some(n) => n.parse().map_err(|_| {
+                    ParseError::Missing("a number of changes")
+                })?,
+                None => ROUTING_CHANGES,
+            }),
+            "BUCKETS" => Command::Buckets,
+            "BOOK" => Command::Book(match args.next() {
+                Some(peer) => Some(peer_id(Some(peer))?),
+                None => None,
+            }),
+            "ADD_ADDRESS" => {
+                let peer = peer_id(args.next())?;
+                let mut addr = address(args.next())?;
+                // The routing table keeps addresses without the /p2p/<peer
+                // id> at the end
+                if let Some(Protocol::P2p(_)) = addr.iter().last() {
+                    match bootstrap::split_peer_addr(addr)? {
+                        (id, rest) if id == peer => addr = rest,
+                        _ => {
+                            return Err(ParseError::Other(
+                                "The address is of another peer".into(),
+                            ))
+                        }
+                    }
+                }
+                Command::AddAddress { peer, addr }
+            }
+            "REMOVE_PEER" => Command::RemovePeer(peer_id(args.next())?),
+            "DISCONNECT" => {
+                let peer = peer_id(args.next())?;
+                // Like `for=30s` (without it, the peer may come right
+                // back)
+                let mut hold = None;
+                for option in args {
+                    match option.split_once('=') {
+                        Some(("for", duration)) => {
+                            hold = Some(parse_duration(duration)?)
+                        }
+                        _ => {
+                            return Err(unknown_option(
+                                "DISCONNECT",
+                                option,
+                            ))
+                        }
+                    }
+                }
+                Command::Disconnect { peer, hold }
+            }
+            "WHOAMI" => Command::Whoami,
+            "LOOKUP_PEER" => Command::LookupPeer(peer_id(args.next())?),
+            "ADDRS" => Command::Addrs,
+            "BOOTSTRAP" => Command::Bootstrap,
+            "WAIT_PEERS" => {
+                let n = parse(
+                    args.next(),
+                    "a number of peers",
+                    "number of peers",
+                )?;
+                Command::Wait {
+                    until: Condition::Peers(n),
+                    timeout: wait_timeout("WAIT_PEERS", args)?,
+                }
+            }
+            "WAIT_BOOTSTRAP" => Command::Wait {
+                until: Condition::Bootstrap,
+                timeout: wait_timeout("WAIT_BOOTSTRAP", args)?,
+            },
+            "CLOSEST" => {
+                let arg = string(args.next(), "a key or a peer id")?;
+                Command::Closest(match arg.parse::<PeerId>() {
+                    Ok(peer) => Target::Peer(peer),
+                    Err(_) => Target::Key(arg),
+                })
+            }
+            "CANCEL" => Command::Cancel(match args.next() {
+                None => Cancel::List,
+                Some("ALL") => Cancel::All,
+                seq => Cancel::One(parse(
+                    seq,
+                    "a query number",
+                    "query number",
+                )?),
+            }),
+            "LOCAL" => Command::Local(args.next().map(String::from)),
+            "LIST" => Command::List(args.next().map(|prefix| {
+                Some(prefix).filter(|p| *p != "-").map(String::from)
+            })),
+            "LOAD" => Command::Load(match args.next() {
+                None => LoadAction::Status,
+                Some("start") => LoadAction::Start(Workload::parse(args)?),
+                Some("stop") => LoadAction::Stop,
+                Some(_) => {
+                    return Err(ParseError::Other(
+                        "Expected LOAD start, LOAD stop or LOAD".into(),
+                    ))
+                }
+            }),
+            "CACHE" => Command::Cache {
+                clear: match args.next() {
+                    None => false,
+                    Some(word) if word.eq_ignore_ascii_case("clear") => {
+                        true
+                    }
+                    Some(_) => {
+                        return Err(ParseError::Other(
+                            "Expected CACHE or CACHE clear".into(),
+                        ))
+                    }
+                },
+            },
+            "VERIFY" => Command::Verify(string(args.next(), "a key")?),
+            "ANALYZE" => Command::Analyze(args.next().map(PathBuf::from)),
+            "SNAPSHOT" => Command::Snapshot(path(args.next())?),
+            "RESTORE" => Command::Restore(path(args.next())?),
+            "SEND" => {
+                let peer = peer_id(args.next())?;
+                let text = args.collect::<Vec<_>>().join(" ");
+                if text.is_empty() {
+                    return Err(ParseError::Missing("a message"));
+                }
+                Command::Send { peer, text }
+            }
+            "SENDFILE" => {
+                let peer = peer_id(args.next())?;
+                // The rest is the path (which may have spaces in it)
+                let path = args.collect::<Vec<_>>().join(" ");
+                if path.is_empty() {
+                    return Err(ParseError::Missing("a file"));
+                }
+                Command::SendFile {
+                    peer,
+                    path: path.into(),
+                }
+            }
+            "TOPOLOGY" => Command::Topology(path(args.next())?),
+            "DIAL" => {
+                let addr = address(args.next())?;
+                match addr.iter().last() {
+                    Some(Protocol::P2p(_)) => {
+                        let (peer, addr) =
+                            bootstrap::split_peer_addr(addr)?;
+                        Command::Dial {
+                            addr,
+                            peer: Some(peer),
+                        }
+                    }
+                    _ => Command::Dial { addr, peer: None },
+                }
+            }
+            "DIALS" => Command::Dials,
+            _ => return Err(ParseError::UnknownCommand(name.to_string())),
+        };
+        Ok(command)
+    }
+}
+
+fn string(
+    arg: Option<&str>,
+    what: &'static str,
+) -> Result<String, ParseError> {
+    arg.map(String::from).ok_or(ParseError::Missing(what))
+}
+
+fn bytes(
+    arg: Option<&str>,
+    what: &'static str,
+) -> Result<Vec<u8>, ParseError> {
+    arg.map(|arg| arg.as_bytes().to_vec())
+        .ok_or(ParseError::Missing(what))
+}
+
+fn path(arg: Option<&str>) -> Result<PathBuf, ParseError> {
+    arg.map(PathBuf::from).ok_or(ParseError::Missing("a file"))
+}
+
+// Parse an argument that has to be there (`missing` says what it is),
+// and has to be valid (`invalid`, likewise).
+fn parse<T: FromStr>(
+    arg: Option<&str>,
+    missing: &'static str,
+    invalid: &'static str,
+) -> Result<T, ParseError> {
+    arg.ok_or(ParseError::Missing(missing))?
+        .parse()
+        .map_err(|_| ParseError::Invalid(invalid))
+}
+
+fn peer_id(arg: Option<&str>) -> Result<PeerId, ParseError> {
+    parse(arg, "a peer id", "peer id")
+}
+
+fn address(arg: Option<&str>) -> Result<Multiaddr, ParseError> {
+    parse(arg, "an address", "address")
+}
+
+// How often to look a watched key up (WATCH, WATCH_PROVIDERS).
+fn watch_interval(arg: Option<&str>) -> Result<Duration, ParseError> {
+    match arg {
+        Some(interval) => match parse_duration(interval)? {
+            interval if interval.as_millis() > 0 => Ok(interval),
+            _ => Err(ParseError::Other("The interval can't be 0".into())),
+        },
+        None => Ok(watch::DEFAULT_INTERVAL),
+    }
+}
+
+// How long WAIT_PEERS and WAIT_BOOTSTRAP wait for (`timeout=10s`).
+fn wait_timeout<'a>(
+    command: &'static str,
+    options: impl Iterator<Item = &'a str>,
+) -> Result<Duration, ParseError> {
+    let mut timeout = barrier::DEFAULT_TIMEOUT;
+    for option in options {
+        match option.split_once('=') {
+            Some(("timeout", t)) => timeout = parse_duration(t)?,
+            _ => return Err(unknown_option(command, option)),
+        }
+    }
+    Ok(timeout)
+}
+
+fn unknown_option(command: &'static str, option: &str) -> ParseError {
+    ParseError::UnknownOption {
+        command,
+        option: option.to_string(),
+    }
+}
+
+// Parse a quorum: a number of peers, `majority` or `all`.
+pub fn parse_quorum(s: &str) -> Result<Quorum, String> {
+    match s {
+        "majority" => Ok(Quorum::Majority),
+        "all" => Ok(Quorum::All),
+        _ => s
+            .parse::<NonZeroUsize>()
+            .map(|n| match n.get() {
+                1 => Quorum::One,
+                _ => Quorum::N(n),
+            })
+            .map_err(|_| {
+                format!(
+                    "{:?} is not a quorum (a number, majority or all)",
+                    s
+                )
+            }),
+    }
+}
+
+// Parse the options of a PUT (or PUT_CAS, or CAS).
+fn put_options<'a>(
+    command: &'static str,
+    options: impl Iterator<Item = &'a str>,
+) -> Result<(Compress, Quorum), ParseError> {
+    let mut compress = Compress::Default;
+    let mut quorum = Quorum::One;
+    for option in options {
+        match option.split_once('=') {
+            Some(("quorum", n)) => quorum = parse_quorum(n)?,
+            Some(("compress", "none")) => compress = Compress::None,
+            Some(("compress", name)) => {
+                let compression = Compression::from_str(name, true)
+                    .map_err(|_| {
+                        format!("Unknown compression {:?}", name)
+                    })?;
+                compress = Compress::With(compression);
+            }
+            _ => return Err(unknown_option(command, option)),
+        }
+    }
+    Ok((compress, quorum))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str =
+        "12D3KooWAoo99PJDVbDNmwhhSYPVJLcYgEQBripk3rtQFrYb1Das";
+    const ADDR: &str = "/ip4/127.0.0.1/tcp/4001";
+
+    fn peer() -> PeerId {
+        PEER.parse().unwrap()
+    }
+
+    fn addr() -> Multiaddr {
+        ADDR.parse().unwrap()
+    }
+
+    fn n(n: usize) -> Quorum {
+        Quorum::N(NonZeroUsize::new(n).unwrap())
+    }
+
+    fn get(
+        key: &str,
+        quorum: Quorum,
+        timeout: Option<Duration>,
+    ) -> Command {
+        Command::Get {
+            key: key.into(),
+            quorum,
+            timeout,
+        }
+    }
+
+    fn put(value: &str, compress: Compress, quorum: Quorum) -> Command {
+        Command::Put {
+            key: "foo".into(),
+            value: value.into(),
+            compress,
+            quorum,
+        }
+    }
+
+    // Every command, with each of its options
+    fn commands() -> Vec<(String, Command)> {
+        let secs = Duration::from_secs;
+        let cases = vec![
+            ("GET foo", get("foo", Quorum::One, None)),
+            (
+                "GET foo quorum=3 timeout=2s",
+                get("foo", n(3), Some(secs(2))),
+            ),
+            ("GET foo quorum=1", get("foo", Quorum::One, None)),
+            (
+                "GET foo quorum=majority",
+                get("foo", Quorum::Majority, None),
+            ),
+            (
+                "GET foo timeout=250ms quorum=all",
+                get("foo", Quorum::All, Some(Duration::from_millis(250))),
+            ),
+            ("PUT foo bar", put("bar", Compress::Default, Quorum::One)),
+            (
+                "PUT foo bar compress=deflate quorum=all",
+                put(
+                    "bar",
+                    Compress::With(Compression::Deflate),
+                    Quorum::All,
+                ),
+            ),
+            (
+                "PUT foo bar compress=Deflate",
+                put(
+                    "bar",
+                    Compress::With(Compression::Deflate),
+                    Quorum::One,
+                ),
+            ),
+            (
+                "PUT foo bar compress=none",
+                put("bar", Compress::None, Quorum::One),
+            ),
+            // Words are split on spaces, quotes and all: they are part of
+            // the value
+            (
+                "PUT foo \"bar\"",
+                put("\"bar\"", Compress::Default, Quorum::One),
+            ),
+            (
+                "PUT_CAS bar quorum=2",
+                Command::PutCas {
+                    value: b"bar".to_vec(),
+                    compress: Compress::Default,
+                    quorum: n(2),
+                },
+            ),
+            (
+                "CAS foo 3 bar compress=deflate",
+                Command::Cas {
+                    key: "foo".into(),
+                    expected: 3,
+                    value: b"bar".to_vec(),
+                    compress: Compress::With(Compression::Deflate),
+                    quorum: Quorum::One,
+                },
+            ),
+            (
+                "MGET a b c",
+                Command::MGet(vec!["a".into(), "b".into(), "c".into()]),
+            ),
+            ("MPUT records.txt", Command::MPut("records.txt".into())),
+            (
+                "FETCH foo <peer>",
+                Command::Fetch {
+                    key: "foo".into(),
+                    peer: peer(),
+                },
+            ),
+            (
+                "REGISTER app",
+                Command::Register {
+                    namespace: "app".into(),
+                    ttl: rendezvous::DEFAULT_TTL,
+                },
+            ),
+            (
+                "REGISTER app 10m",
+                Command::Register {
+                    namespace: "app".into(),
+                    ttl: secs(600),
+                },
+            ),
+            (
+                "DISCOVER app",
+                Command::Discover {
+                    namespace: "app".into(),
+                },
+            ),
+            (
+                "WATCH foo",
+                Command::Watch {
+                    key: "foo".into(),
+                    interval: watch::DEFAULT_INTERVAL,
+                },
+            ),
+            (
+                "WATCH foo 500ms",
+                Command::Watch {
+                    key: "foo".into(),
+                    interval: Duration::from_millis(500),
+                },
+            ),
+            (
+                "WATCH_PROVIDERS foo 2s",
+                Command::WatchProviders {
+                    key: "foo".into(),
+                    interval: secs(2),
+                },
+            ),
+            ("UNWATCH foo", Command::Unwatch { key: "foo".into() }),
+            ("REPUBLISH", Command::Republish),
+            ("AUDIT", Command::Audit),
+            ("ALIAS", Command::Alias(None)),
+            (
+                "ALIAS pf PUT foo",
+                Command::Alias(Some(Alias {
+                    name: "pf".into(),
+                    command: "PUT foo".into(),
+                })),
+            ),
+            ("NS", Command::Namespace(None)),
+            ("NS app", Command::Namespace(Some(Some("app".into())))),
+            ("NS -", Command::Namespace(Some(None))),
+            ("PEERS", Command::Peers { verbose: false }),
+            ("PEERS -v", Command::Peers { verbose: true }),
+            ("BANDWIDTH", Command::Bandwidth),
+            ("BAN <peer>", Command::Ban(peer())),
+            ("UNBAN <peer>", Command::Unban(peer())),
+            ("ROUTING", Command::Routing(ROUTING_CHANGES)),
+            ("ROUTING 5", Command::Routing(5)),
+            ("BUCKETS", Command::Buckets),
+            ("BOOK", Command::Book(None)),
+            ("BOOK <peer>", Command::Book(Some(peer()))),
+            (
+                "ADD_ADDRESS <peer> <addr>",
+                Command::AddAddress {
+                    peer: peer(),
+                    addr: addr(),
+                },
+            ),
+            // The /p2p/<peer id> at the end goes
+            (
+                "ADD_ADDRESS <peer> <addr>/p2p/<peer>",
+                Command::AddAddress {
+                    peer: peer(),
+                    addr: addr(),
+                },
+            ),
+            ("REMOVE_PEER <peer>", Command::RemovePeer(peer())),
+            (
+                "DIAL <addr>",
+                Command::Dial {
+                    addr: addr(),
+                    peer: None,
+                },
+            ),
+            (
+                "DIAL <addr>/p2p/<peer>",
+                Command::Dial {
+                    addr: addr(),
+                    peer: Some(peer()),
+                },
+            ),
+            ("DIALS", Command::Dials),
+            (
+                "DISCONNECT <peer>",
+                Command::Disconnect {
+                    peer: peer(),
+                    hold: None,
+                },
+            ),
+            (
+                "DISCONNECT <peer> for=30s",
+                Command::Disconnect {
+                    peer: peer(),
+                    hold: Some(secs(30)),
+                },
+            ),
+            ("WHOAMI", Command::Whoami),
+            ("LOOKUP_PEER <peer>", Command::LookupPeer(peer())),
+            ("ADDRS", Command::Addrs),
+            ("BOOTSTRAP", Command::Bootstrap),
+            (
+                "WAIT_PEERS 3",
+                Command::Wait {
+                    until: Condition::Peers(3),
+                    timeout: barrier::DEFAULT_TIMEOUT,
+                },
+            ),
+            (
+                "WAIT_PEERS 3 timeout=10s",
+                Command::Wait {
+                    until: Condition::Peers(3),
+                    timeout: secs(10),
+                },
+            ),
+            (
+                "WAIT_BOOTSTRAP timeout=1m",
+                Command::Wait {
+                    until: Condition::Bootstrap,
+                    timeout: secs(60),
+                },
+            ),
+            ("CLOSEST foo", Command::Closest(Target::Key("foo".into()))),
+            ("CLOSEST <peer>", Command::Closest(Target::Peer(peer()))),
+            ("CANCEL", Command::Cancel(Cancel::List)),
+            ("CANCEL 2", Command::Cancel(Cancel::One(2))),
+            ("CANCEL ALL", Command::Cancel(Cancel::All)),
+            ("LOCAL", Command::Local(None)),
+            ("LOCAL foo", Command::Local(Some("foo".into()))),
+            ("LIST", Command::List(None)),
+            ("LIST app", Command::List(Some(Some("app".into())))),
+            ("LIST -", Command::List(Some(None))),
+            ("SNAPSHOT s.json", Command::Snapshot("s.json".into())),
+            ("RESTORE s.json", Command::Restore("s.json".into())),
+            (
+                "SEND <peer> hello there",
+                Command::Send {
+                    peer: peer(),
+                    text: "hello there".into(),
+                },
+            ),
+            (
+                "SENDFILE <peer> my file.txt",
+                Command::SendFile {
+                    peer: peer(),
+                    path: "my file.txt".into(),
+                },
+            ),
+            ("TOPOLOGY t.txt", Command::Topology("t.txt".into())),
+            ("LOAD", Command::Load(LoadAction::Status)),
+            ("LOAD stop", Command::Load(LoadAction::Stop)),
+            (
+                "LOAD start puts=10/s keyspace=5",
+                Command::Load(LoadAction::Start(Workload {
+                    puts: 10.0,
+                    keyspace: 5,
+                    ..Workload::default()
+                })),
+            ),
+            ("CACHE", Command::Cache { clear: false }),
+            ("CACHE clear", Command::Cache { clear: true }),
+            ("CACHE CLEAR", Command::Cache { clear: true }),
+            ("VERIFY foo", Command::Verify("foo".into())),
+            ("ANALYZE", Command::Analyze(None)),
+            (
+                "ANALYZE keys.txt",
+                Command::Analyze(Some("keys.txt".into())),
+            ),
+        ];
+        cases
+            .into_iter()
+            .map(|(line, command)| {
+                (
+                    line.replace("<peer>", PEER).replace("<addr>", ADDR),
+                    command,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_every_command() {
+        for (line, command) in commands() {
+            assert_eq!(line.parse::<Command>(), Ok(command), "{}", line);
+        }
+    }
+
+    #[test]
+    fn every_command_is_tested() {
+        let tested: Vec<String> = commands()
+            .into_iter()
+            .map(|(line, _)| line.split(' ').next().unwrap().to_string())
+            .collect();
+        // TIME is in front of another command (see input::parse_line)
+        for name in COMMANDS.iter().filter(|name| **name != "TIME") {
+            assert!(tested.iter().any(|t| t == name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        use ParseError::*;
+        let other = PeerId::random();
+        let cases = vec![
+            ("", UnknownCommand("".into())),
+            ("FOO bar", UnknownCommand("FOO".into())),
+            ("get foo", UnknownCommand("get".into())),
+            ("GET", Missing("a key")),
+            ("PUT foo", Missing("value")),
+            ("CAS foo", Missing("the current version")),
+            ("MGET", Missing("a key")),
+            ("MPUT", Missing("a file")),
+            ("FETCH foo", Missing("a peer id")),
+            ("ALIAS pf", Missing("a command")),
+            ("BAN", Missing("a peer id")),
+            ("DIAL", Missing("an address")),
+            ("ROUTING lots", Missing("a number of changes")),
+            ("WAIT_PEERS", Missing("a number of peers")),
+            ("SEND <peer>", Missing("a message")),
+            ("SENDFILE <peer>", Missing("a file")),
+            ("BAN nobody", Invalid("peer id")),
+            ("DIAL nowhere", Invalid("address")),
+            ("CAS foo one bar", Invalid("version")),
+            ("WAIT_PEERS many", Invalid("number of peers")),
+            ("CANCEL some", Invalid("query number")),
+            (
+                "GET foo quorum",
+                UnknownOption {
+                    command: "GET",
+                    option: "quorum".into(),
+                },
+            ),
+            (
+                "PUT foo bar colour=red",
+                UnknownOption {
+                    command: "PUT",
+                    option: "colour=red".into(),
+                },
+            ),
+            (
+                "PEERS -x",
+                UnknownOption {
+                    command: "PEERS",
+                    option: "-x".into(),
+                },
+            ),
+            (
+                "DISCONNECT <peer> hold=1s",
+                UnknownOption {
+                    command: "DISCONNECT",
+                    option: "hold=1s".into(),
+                },
+            ),
+            (
+                "WAIT_BOOTSTRAP for=1s",
+                UnknownOption {
+                    command: "WAIT_BOOTSTRAP",
+                    option: "for=1s".into(),
+                },
+            ),
+        ];
+        for (line, err) in cases {
+            let line = line.replace("<peer>", PEER);
+            assert_eq!(line.parse::<Command>(), Err(err), "{}", line);
+        }
+
+        // The rest are explained in words
+        let others = vec![
+            "GET foo quorum=0".to_string(),
+            "PUT foo bar compress=zstd".into(),
+            "WATCH foo 0s".into(),
+            "LOAD go".into(),
+            "LOAD start puts=x".into(),
+            "CACHE empty".into(),
+            "ALIAS GET PUT foo".into(),
+            "ALIAS pf FOO".into(),
+            format!("ADD_ADDRESS {} {}/p2p/{}", PEER, ADDR, other),
+        ];
+        for line in others {
+            assert!(
+                matches!(line.parse::<Command>(), Err(Other(_))),
+                "{}",
+                line
+            );
+        }
+    }
+
+    // Durations that used to panic, wherever a command takes one
+    #[test]
+    fn bad_durations_are_errors() {
+        for duration in
+            ["5é", "é", "1e30s", "1e30h", "NaNs", "-1s", "infs"]
+        {
+            let lines = [
+                format!("GET foo timeout={}", duration),
+                format!("WATCH foo {}", duration),
+                format!("WATCH_PROVIDERS foo {}", duration),
+                format!("REGISTER app {}", duration),
+                format!("DISCONNECT {} for={}", PEER, duration),
+                format!("WAIT_PEERS 1 timeout={}", duration),
+                format!("WAIT_BOOTSTRAP timeout={}", duration),
+            ];
+            for line in lines {
+                assert!(
+                    matches!(
+                        line.parse::<Command>(),
+                        Err(ParseError::Other(_))
+                    ),
+                    "{}",
+                    line
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn error_messages() {
+        let cases = [
+            ("GET", "Expected a key"),
+            ("BAN nobody", "Invalid peer id"),
+            ("PEERS -x", "Unknown PEERS option \"-x\""),
+            ("WATCH foo 0s", "The interval can't be 0"),
+        ];
+        for (line, message) in cases {
+            let err = line.parse::<Command>().unwrap_err();
+            assert_eq!(err.to_string(), message, "{}", line);
+        }
+        let err = "FOO".parse::<Command>().unwrap_err().to_string();
+        assert!(err.starts_with("Expected GET, PUT, "), "{}", err);
+    }
+
+    #[test]
+    fn aliases() {
+        let pf = "pf=PUT foo".parse::<Alias>().unwrap();
+        let ls = "ls=LIST".parse::<Alias>().unwrap();
+        let aliases = Aliases::new(&[pf, ls]);
+        let cases = [
+            ("g foo", get("foo", Quorum::One, None)),
+            ("g foo quorum=2", get("foo", n(2), None)),
+            ("p foo bar", put("bar", Compress::Default, Quorum::One)),
+            ("pf bar", put("bar", Compress::Default, Quorum::One)),
+            ("ls", Command::List(None)),
+            ("GET foo", get("foo", Quorum::One, None)),
+        ];
+        for (line, command) in cases {
+            assert_eq!(aliases.parse(line), Ok(command), "{}", line);
+        }
+        assert_eq!(aliases.parse("pf"), Err(ParseError::Missing("value")));
+        assert_eq!(
+            aliases.parse("nope"),
+            Err(ParseError::UnknownCommand("nope".into()))
+        );
+
+        // --alias and ALIAS replace the built-in ones
+        let mut aliases = Aliases::default();
+        aliases.add("g=GET bar".parse().unwrap());
+        assert_eq!(aliases.parse("g"), Ok(get("bar", Quorum::One, None)));
+    }
+
+    #[test]
+    fn not_aliases() {
+        for s in ["pf", "=GET", "p f=GET", "GET=PUT foo", "x=FOO", "x="] {
+            assert!(s.parse::<Alias>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn quorums() {
+        let cases = [
+            ("1", Quorum::One),
+            ("3", n(3)),
+            ("majority", Quorum::Majority),
+            ("all", Quorum::All),
+        ];
+        for (s, quorum) in cases {
+            assert_eq!(parse_quorum(s), Ok(quorum), "{}", s);
+        }
+        for s in ["", "0", "-1", "most", "ALL", "1.5"] {
+            assert!(parse_quorum(s).is_err(), "{}", s);
+        }
+    }
+}
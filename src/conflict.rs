@@ -0,0 +1,102 @@
+use crate::{cas, value::Decoded};
+use clap::ValueEnum;
+use libp2p::{kad::PeerRecord, PeerId};
+
+// What to do when a GET finds different values under the same key (which
+// takes a quorum of more than one, like `GET foo quorum=3`).
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergeStrategy {
+    // Report the conflict, and don't pick any of the values
+    #[default]
+    Report,
+    // Pick the value that was stored last. Nodes with this strategy stamp
+    // the values they store with the time, and values without a stamp
+    // lose to the ones with one.
+    LastWriterWins,
+}
+
+// One of the values a GET found, and where it found it.
+#[derive(Debug)]
+pub struct Candidate {
+    // The first of the records with this value
+    pub record: usize,
+    pub value: Vec<u8>,
+    pub peers: Vec<Option<PeerId>>,
+    // When the value was last stored (in milliseconds since the epoch), if
+    // it carries a stamp
+    pub timestamp: Option<u64>,
+}
+
+// Group the records of a GET by value (after decoding, so the same value
+// stored by different nodes, or at different times, is no conflict).
+// `decoded` goes with `records`, record for record.
+pub fn candidates(
+    records: &[PeerRecord],
+    decoded: &[Decoded],
+) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for (i, (record, decoded)) in records.iter().zip(decoded).enumerate() {
+        let value = &record.record.value;
+        match candidates.iter_mut().find(|c| &c.value == value) {
+            Some(candidate) => {
+                candidate.peers.push(record.peer.clone());
+                candidate.timestamp =
+                    candidate.timestamp.max(decoded.timestamp);
+            }
+            None => candidates.push(Candidate {
+                record: i,
+                value: value.clone(),
+                peers: vec![record.peer.clone()],
+                timestamp: decoded.timestamp,
+            }),
+        }
+    }
+    candidates
+}
+
+// Which of the conflicting values wins, if the strategy picks one. Last
+// writer wins can't pick between values stored at the same time (or
+// without stamps).
+pub fn winner(
+    strategy: MergeStrategy,
+    candidates: &[Candidate],
+) -> Option<&Candidate> {
+    match strategy {
+        MergeStrategy::Report => None,
+        MergeStrategy::LastWriterWins => {
+            let latest = candidates.iter().max_by_key(|c| c.timestamp)?;
+            let ties = candidates
+                .iter()
+                .filter(|c| c.timestamp == latest.timestamp)
+                .count();
+            Some(latest)
+                .filter(|_| ties == 1 && latest.timestamp.is_some())
+        }
+    }
+}
+
+// Describe one of the values a GET found, with its hash (the PUT_CAS
+// key it would have) and the peers that returned it, like
+// `"a" (hash QmX...) from 12D3KooA..., this node, stored at 1602670000000`.
+pub fn describe(candidate: &Candidate) -> String {
+    let peers: Vec<String> = candidate
+        .peers
+        .iter()
+        .map(|peer| match peer {
+            Some(peer) => peer.to_string(),
+            // The local store answered
+            None => "this node".to_string(),
+        })
+        .collect();
+    let stored = match candidate.timestamp {
+        Some(timestamp) => format!(", stored at {}", timestamp),
+        None => String::new(),
+    };
+    format!(
+        "{:?} (hash {}) from {}{}",
+        String::from_utf8_lossy(&candidate.value),
+        cas::key_for(&candidate.value),
+        peers.join(", "),
+        stored
+    )
+}
@@ -0,0 +1,200 @@
+use crate::{
+    auth::{self, Access, Tokens},
+    handle::NodeHandle,
+    output::{Message, Output, ERROR_PREFIX},
+};
+use async_std::{
+    io::BufReader,
+    os::unix::net::{UnixListener, UnixStream},
+    prelude::*,
+    task,
+};
+use futures::channel::mpsc;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+// Start accepting control connections on a unix domain socket.
+//
+// The protocol is line based: a client connects, writes a single command
+// line (the same thing that would be typed into the terminal), and then
+// reads lines back until the node closes the connection, which happens
+// once the command (and any query it started) has finished.
+//
+// With --auth-tokens, the command line comes after a line of
+// `AUTH <token>`, and read-only tokens only get to run the commands that
+// change nothing (see `auth::allows`).
+//
+// The socket file is removed once the `Socket` this returns is dropped,
+// however the node stops.
+pub fn serve(
+    path: &Path,
+    commands: NodeHandle,
+    tokens: Option<Tokens>,
+) -> io::Result<Socket> {
+    // A socket file left behind by a previous run would make binding fail
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = task::block_on(UnixListener::bind(path))?;
+
+    task::spawn(async move {
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    task::spawn(handle_client(
+                        stream,
+                        commands.clone(),
+                        tokens.clone(),
+                    ));
+                }
+                Err(err) => {
+                    eprintln!("control: failed to accept: {}", err)
+                }
+            }
+        }
+    });
+
+    Ok(Socket(path.to_path_buf()))
+}
+
+// The file of a control socket, which goes away with this.
+#[derive(Debug)]
+pub struct Socket(PathBuf);
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0).ok();
+    }
+}
+
+// Read one command from a client, pass it to the node, and stream the
+// results back.
+async fn handle_client(
+    stream: UnixStream,
+    commands: NodeHandle,
+    tokens: Option<Tokens>,
+) {
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    if let Err(err) = reader.read_line(&mut line).await {
+        eprintln!("control: failed to read command: {}", err);
+        return;
+    }
+    let token = match line.trim_end().strip_prefix("AUTH ") {
+        Some(token) => {
+            let token = token.trim().to_string();
+            line.clear();
+            if let Err(err) = reader.read_line(&mut line).await {
+                eprintln!("control: failed to read command: {}", err);
+                return;
+            }
+            Some(token)
+        }
+        None => None,
+    };
+    // Without --auth-tokens, any token (or none) will do
+    let access = match (&tokens, token) {
+        (None, _) => Access::Write,
+        (Some(tokens), Some(token)) => match tokens.access(&token) {
+            Some(access) => access,
+            None => return refuse(&stream, "unknown token").await,
+        },
+        (Some(_), None) => {
+            return refuse(
+                &stream,
+                "this node takes a token (ctl --token)",
+            )
+            .await
+        }
+    };
+    if let Err(err) = auth::allows(access, line.trim_end()) {
+        return refuse(&stream, &err).await;
+    }
+
+    // The node keeps a copy of `reply_tx` for as long as the command is
+    // running, so `reply_rx` ends when the command is done.
+    let (reply_tx, mut reply_rx) = mpsc::unbounded();
+    let command = line.trim_end().to_string();
+    if commands
+        .command(command, Output::Client(reply_tx))
+        .await
+        .is_err()
+    {
+        // The node has stopped
+        return;
+    }
+
+    let mut stream = &stream;
+    while let Some(reply) = reply_rx.next().await {
+        if stream
+            .write_all(format!("{}\n", reply).as_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+async fn refuse(mut stream: &UnixStream, why: &str) {
+    let _ = stream
+        .write_all(format!("{}{}\n", ERROR_PREFIX, why).as_bytes())
+        .await;
+}
+
+// Send a command to a node over its control socket (with `token`, for
+// the nodes that take one), and print everything it sends back. Returns
+// whether the command succeeded.
+pub async fn send_command(
+    path: &Path,
+    command: &str,
+    token: Option<&str>,
+) -> io::Result<bool> {
+    let mut stream = UnixStream::connect(path).await?;
+    if let Some(token) = token {
+        stream
+            .write_all(format!("AUTH {}\n", token).as_bytes())
+            .await?;
+    }
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+
+    let mut ok = true;
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        match line.strip_prefix(ERROR_PREFIX) {
+            Some(error) => {
+                eprintln!("{}", error);
+                ok = false;
+            }
+            None => println!("{}", line),
+        }
+    }
+
+    Ok(ok)
+}
+
+// Send a command to a node over its control socket, and collect what it
+// sends back, rather than printing it.
+pub async fn ask(path: &Path, command: &str) -> io::Result<Vec<Message>> {
+    let mut stream = UnixStream::connect(path).await?;
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+
+    let mut replies = Vec::new();
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        replies.push(match line.strip_prefix(ERROR_PREFIX) {
+            Some(error) => Message::Error(error.to_string()),
+            None => Message::Info(line),
+        });
+    }
+    Ok(replies)
+}
@@ -0,0 +1,333 @@
+use crate::{
+    api::ApiReply,
+    behaviour::MyBehavior,
+    bootstrap,
+    config::{CrawlArgs, Opts},
+    filter::PeerFilter,
+    limits,
+    output::Output,
+    peerstore, seed, simulate,
+    transport::{self, Runtime, TransportKind},
+};
+use async_std::task;
+use futures::{channel::oneshot, future, FutureExt};
+use libp2p::{
+    kad::{GetClosestPeersError, GetClosestPeersOk, QueryResult},
+    Multiaddr, PeerId, Swarm,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashSet},
+    error::Error,
+    process,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+// Crawl the network --bootstrap leads to: look up the closest peers of
+// `lookups` random keys (`concurrency` at a time), from a node of our
+// own, and list every peer that turned up, with where it is reached. Its
+// size is estimated from how close to the keys the closest peers are
+// (the more peers there are, the closer they get), which also counts the
+// peers the lookups didn't happen to reach.
+pub fn run(opts: &Opts, args: &CrawlArgs) -> Result<(), Box<dyn Error>> {
+    if opts.bootstrap.is_empty() {
+        return Err("crawl needs a --bootstrap peer to start from".into());
+    }
+    if args.concurrency == 0 {
+        return Err("--concurrency must be at least 1".into());
+    }
+    let mut swarm = spawn(opts)?;
+    for entry in &opts.bootstrap {
+        match task::block_on(bootstrap::resolve(entry)) {
+            Ok(peers) => {
+                for (peer_id, addr) in peers {
+                    swarm.kademlia.add_address(&peer_id, addr);
+                }
+            }
+            Err(err) => {
+                Output::Terminal.error(format!("bootstrap: {}", err))
+            }
+        }
+    }
+    if swarm.known_peers().is_empty() {
+        return Err(
+            "none of the --bootstrap peers could be resolved".into()
+        );
+    }
+
+    println!(
+        "Crawling with {} lookups of random keys, {} at a time",
+        args.lookups, args.concurrency
+    );
+    let began = Instant::now();
+    let crawl = Crawl::default().run(&mut swarm, args);
+    let peers = crawl.peers(&mut swarm);
+    let reachable = peers.iter().filter(|peer| peer.reachable).count();
+    println!(
+        "Found {} peers ({} reachable) in {:.1?}, {} lookups failed",
+        peers.len(),
+        reachable,
+        began.elapsed(),
+        crawl.failed
+    );
+    match crawl.estimate() {
+        Some(estimate) => {
+            println!("Estimated network size: {:.0} peers", estimate)
+        }
+        None => println!("Too few peers to estimate the network size"),
+    }
+    for peer in &peers {
+        let addrs: Vec<String> =
+            peer.addrs.iter().map(|addr| addr.to_string()).collect();
+        println!(
+            "{} {}{}",
+            peer.peer_id,
+            if peer.reachable {
+                "reachable"
+            } else {
+                "unreachable"
+            },
+            match addrs.is_empty() {
+                true => ", no known addresses".to_string(),
+                false => format!(", at {}", addrs.join(", ")),
+            }
+        );
+    }
+
+    // In the format of --peerstore-path, which a node can start from
+    if let Some(path) = &args.peers {
+        let list: Vec<_> = peers
+            .iter()
+            .map(|peer| (peer.peer_id.clone(), peer.addrs.clone()))
+            .collect();
+        let count = peerstore::save(path, &list)?;
+        println!("Wrote {} peers to {}", count, path.display());
+    }
+    if let Some(expected) = args.expect {
+        if peers.len() < expected {
+            Output::Terminal.error(format!(
+                "Expected at least {} peers, found {}",
+                expected,
+                peers.len()
+            ));
+            process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+// The node that crawls (or that doctor dials from). It doesn't listen,
+// or publish anything.
+pub fn spawn(opts: &Opts) -> Result<Swarm<MyBehavior>, Box<dyn Error>> {
+    let local_key = seed::keypair();
+    let local_peer_id = PeerId::from(local_key.public());
+    let config = opts.transport_config()?;
+    let mut behaviour_config = opts.behaviour_config(&local_key);
+    behaviour_config.bandwidth = config.bandwidth.clone();
+    behaviour_config.negotiations = config.negotiations.clone();
+    behaviour_config.peer_info_interval = None;
+    behaviour_config.republish_interval = None;
+    let filter = PeerFilter::new(&opts.allow, &opts.deny).shared();
+    let kinds = opts.transports(TransportKind::Tcp);
+    let transport = transport::build_transport(
+        &kinds,
+        local_key,
+        config,
+        filter.clone(),
+    )?;
+    let behaviour = MyBehavior::new(
+        local_peer_id.clone(),
+        None,
+        filter,
+        behaviour_config,
+    );
+    Ok(limits::build_swarm(
+        transport,
+        behaviour,
+        local_peer_id,
+        &opts.connection_limits(),
+        Runtime::AsyncStd,
+    ))
+}
+
+// A peer the crawl found.
+#[derive(Debug, Clone)]
+struct Peer {
+    peer_id: PeerId,
+    addrs: Vec<Multiaddr>,
+    // Whether we got a connection to it
+    reachable: bool,
+}
+
+// A lookup that is running, and the key it is the closest peers of.
+struct Lookup {
+    key: Vec<u8>,
+    reply: oneshot::Receiver<ApiReply>,
+}
+
+#[derive(Debug, Default)]
+struct Crawl {
+    // Every peer the lookups turned up
+    found: HashSet<PeerId>,
+    // What each lookup makes of the size of the network
+    estimates: Vec<f64>,
+    failed: usize,
+}
+
+impl Crawl {
+    fn run(
+        mut self,
+        swarm: &mut Swarm<MyBehavior>,
+        args: &CrawlArgs,
+    ) -> Self {
+        let mut issued = 0;
+        let mut running: Vec<Lookup> =
+            Vec::with_capacity(args.concurrency);
+        task::block_on(future::poll_fn(|cx: &mut Context<'_>| loop {
+            while running.len() < args.concurrency && issued < args.lookups
+            {
+                // A key no peer has, anywhere in the key space
+                let key =
+                    PeerId::from(seed::keypair().public()).into_bytes();
+                let id = swarm.kademlia.get_closest_peers(key.clone());
+                let (tx, rx) = oneshot::channel();
+                swarm.api_pending.insert(id, tx);
+                running.push(Lookup { key, reply: rx });
+                issued += 1;
+            }
+
+            simulate::poll_all(std::slice::from_mut(swarm), cx);
+
+            let before = running.len();
+            let mut i = 0;
+            while i < running.len() {
+                match running[i].reply.poll_unpin(cx) {
+                    Poll::Ready(reply) => {
+                        let lookup = running.swap_remove(i);
+                        self.finish(&lookup.key, reply.ok());
+                    }
+                    Poll::Pending => i += 1,
+                }
+            }
+
+            if issued == args.lookups && running.is_empty() {
+                return Poll::Ready(());
+            }
+            if running.len() == before {
+                return Poll::Pending;
+            }
+        }));
+        self
+    }
+
+    // Take in what a lookup of `key` found. One that timed out still
+    // found peers, just maybe not the closest ones (so it isn't used for
+    // the estimate).
+    fn finish(&mut self, key: &[u8], reply: Option<ApiReply>) {
+        match reply {
+            Some(ApiReply::Query(
+                QueryResult::GetClosestPeers(Ok(GetClosestPeersOk {
+                    peers,
+                    ..
+                })),
+                _,
+            )) => {
+                if let Some(estimate) = estimate(key, &peers) {
+                    self.estimates.push(estimate);
+                }
+                self.found.extend(peers);
+            }
+            Some(ApiReply::Query(
+                QueryResult::GetClosestPeers(Err(
+                    GetClosestPeersError::Timeout { peers, .. },
+                )),
+                _,
+            )) => {
+                self.failed += 1;
+                self.found.extend(peers);
+            }
+            _ => self.failed += 1,
+        }
+    }
+
+    // The size of the network, as the median of what the lookups make of
+    // it.
+    fn estimate(&self) -> Option<f64> {
+        let mut estimates = self.estimates.clone();
+        if estimates.is_empty() {
+            return None;
+        }
+        estimates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        Some(estimates[estimates.len() / 2])
+    }
+
+    // Every peer that was found, by the lookups or in the routing table
+    // they filled, with the addresses the routing table and the address
+    // book have.
+    fn peers(&self, swarm: &mut Swarm<MyBehavior>) -> Vec<Peer> {
+        let mut peers: BTreeMap<String, Peer> = BTreeMap::new();
+        let mut add = |peer_id: PeerId, addrs: Vec<Multiaddr>| {
+            let peer = peers.entry(peer_id.to_string()).or_insert(Peer {
+                peer_id,
+                addrs: Vec::new(),
+                reachable: false,
+            });
+            for addr in addrs {
+                if !peer.addrs.contains(&addr) {
+                    peer.addrs.push(addr);
+                }
+            }
+        };
+        for (peer_id, addrs) in swarm.known_peers() {
+            add(peer_id, addrs);
+        }
+        for peer_id in &self.found {
+            add(peer_id.clone(), Vec::new());
+        }
+        let mut peers: Vec<Peer> = peers.into_values().collect();
+        for peer in &mut peers {
+            if let Some(entry) = swarm.book.get(&peer.peer_id) {
+                for addr in &entry.addrs {
+                    if !peer.addrs.contains(addr) {
+                        peer.addrs.push(addr.clone());
+                    }
+                }
+                peer.reachable = entry.seen.is_some();
+            }
+            peer.reachable |=
+                swarm.connections.is_connected(&peer.peer_id);
+        }
+        peers
+    }
+}
+
+// How many peers there are, going by how close to `key` its closest
+// `peers` are: with n peers spread evenly over the key space, the i-th
+// closest is i / (n + 1) of the way out, on average.
+fn estimate(key: &[u8], peers: &[PeerId]) -> Option<f64> {
+    let key = Sha256::digest(key);
+    let mut distances: Vec<f64> = peers
+        .iter()
+        .map(|peer| {
+            let hash = Sha256::digest(&peer.clone().into_bytes());
+            // The first 8 bytes are plenty for a share of the key space
+            let mut xor = [0u8; 8];
+            for (i, byte) in xor.iter_mut().enumerate() {
+                *byte = key[i] ^ hash[i];
+            }
+            u64::from_be_bytes(xor) as f64 / 2f64.powi(64)
+        })
+        .filter(|distance| *distance > 0.0)
+        .collect();
+    if distances.is_empty() {
+        return None;
+    }
+    distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let sum: f64 = distances
+        .iter()
+        .enumerate()
+        .map(|(i, distance)| (i + 1) as f64 / distance - 1.0)
+        .sum();
+    Some(sum / distances.len() as f64)
+}
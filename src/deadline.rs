@@ -0,0 +1,54 @@
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::kad::{record::Key, QueryId};
+use std::{
+    collections::{HashMap, HashSet},
+    task::Context,
+    time::Duration,
+};
+
+// How long the queries of some commands may take (like
+// `GET foo timeout=5s`), whatever kademlia's own timeout is. Once a
+// query is past its deadline, it is given up on: whoever started it
+// hears that it timed out, and what it finds after that is dropped.
+#[derive(Debug, Default)]
+pub struct Deadlines {
+    queries: HashMap<QueryId, (Key, Duration, Delay)>,
+    given_up: HashSet<QueryId>,
+}
+
+impl Deadlines {
+    pub fn add(&mut self, query: QueryId, key: Key, timeout: Duration) {
+        self.queries
+            .insert(query, (key, timeout, Delay::new(timeout)));
+    }
+
+    // The queries whose deadline just passed, with their key and how
+    // long they had. The timers wake the task once there are any.
+    pub fn expired(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Vec<(QueryId, Key, Duration)> {
+        let mut expired = Vec::new();
+        for (query, (_, _, timer)) in &mut self.queries {
+            if timer.poll_unpin(cx).is_ready() {
+                expired.push(*query);
+            }
+        }
+        expired
+            .into_iter()
+            .filter_map(|query| {
+                let (key, timeout, _) = self.queries.remove(&query)?;
+                self.given_up.insert(query);
+                Some((query, key, timeout))
+            })
+            .collect()
+    }
+
+    // Forget about a query that finished. Returns whether it was given
+    // up on, and its result is to be dropped.
+    pub fn finish(&mut self, query: &QueryId) -> bool {
+        self.queries.remove(query);
+        self.given_up.remove(query)
+    }
+}
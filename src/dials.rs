@@ -0,0 +1,156 @@
+use libp2p::{swarm::DialPeerCondition, PeerId};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+// How long a dial counts as going on without the swarm telling how it
+// went (it says nothing about the dials it doesn't make, like those to
+// a peer that connected in the meantime).
+const STALE_AFTER: Duration = Duration::from_secs(60);
+
+// How many dials may go on at once (--max-concurrent-dials), and how
+// many may wait for their turn (--max-queued-dials). Anything that isn't
+// given is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DialLimits {
+    pub max_concurrent: Option<usize>,
+    pub max_queued: Option<usize>,
+}
+
+// The dials kademlia (and the liveness probes) want to make, so many at
+// a time. When a whole cluster bootstraps at once, every node dials
+// every peer its lookups turn up, all at the same time; with a limit,
+// the dials over it wait in a queue, and once the queue is full too,
+// they don't happen at all (kademlia hears that they failed). A dial is
+// over once the peer is connected, or the swarm says it couldn't be.
+//
+// --max-pending-dials is the limit of the swarm itself, which fails the
+// dials over it instead; the dials of commands (DIAL) and of the
+// bootstrap and static peers don't go through the queue.
+#[derive(Debug, Default)]
+pub struct DialQueue {
+    limits: DialLimits,
+    // The dials going on, and since when
+    dialing: HashMap<PeerId, Instant>,
+    // The dials waiting, and since when
+    queued: VecDeque<(PeerId, DialPeerCondition, Instant)>,
+    connected: HashSet<PeerId>,
+    // How many dials went out, how many had to wait, and how many were
+    // dropped with the queue full
+    pub started: u64,
+    pub delayed: u64,
+    pub dropped: u64,
+}
+
+// What to do about a dial.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Admission {
+    Dial,
+    Wait,
+    Drop,
+    // The peer is connected already, or being dialed
+    Nothing,
+}
+
+impl DialQueue {
+    pub fn new(limits: DialLimits) -> Self {
+        DialQueue {
+            limits,
+            ..DialQueue::default()
+        }
+    }
+
+    pub fn limits(&self) -> DialLimits {
+        self.limits
+    }
+
+    // Someone wants to dial `peer`.
+    pub fn request(
+        &mut self,
+        peer: PeerId,
+        condition: DialPeerCondition,
+    ) -> Admission {
+        self.forget_stale();
+        if self.connected.contains(&peer)
+            || self.dialing.contains_key(&peer)
+            || self.queued.iter().any(|(queued, _, _)| *queued == peer)
+        {
+            return Admission::Nothing;
+        }
+        if self.has_room() {
+            self.started += 1;
+            self.dialing.insert(peer, Instant::now());
+            return Admission::Dial;
+        }
+        if let Some(max) = self.limits.max_queued {
+            if self.queued.len() >= max {
+                self.dropped += 1;
+                return Admission::Drop;
+            }
+        }
+        self.delayed += 1;
+        self.queued.push_back((peer, condition, Instant::now()));
+        Admission::Wait
+    }
+
+    // The next dial that waited, if it may go out now.
+    pub fn next_due(&mut self) -> Option<(PeerId, DialPeerCondition)> {
+        self.forget_stale();
+        while self.has_room() {
+            let (peer, condition, _) = self.queued.pop_front()?;
+            if self.connected.contains(&peer) {
+                continue;
+            }
+            self.started += 1;
+            self.dialing.insert(peer.clone(), Instant::now());
+            return Some((peer, condition));
+        }
+        None
+    }
+
+    pub fn connected(&mut self, peer: &PeerId) {
+        self.connected.insert(peer.clone());
+        self.dialing.remove(peer);
+        self.queued.retain(|(queued, _, _)| queued != peer);
+    }
+
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        self.connected.remove(peer);
+    }
+
+    // The swarm couldn't reach `peer`.
+    pub fn failed(&mut self, peer: &PeerId) {
+        self.dialing.remove(peer);
+    }
+
+    // The dials going on, and the ones waiting, with how long they have
+    // been at it, the oldest first.
+    pub fn dialing(&self) -> Vec<(PeerId, Duration)> {
+        let mut dialing: Vec<_> = self
+            .dialing
+            .iter()
+            .map(|(peer, since)| (peer.clone(), since.elapsed()))
+            .collect();
+        dialing.sort_by_key(|(_, age)| std::cmp::Reverse(*age));
+        dialing
+    }
+
+    pub fn queued(&self) -> Vec<(PeerId, Duration)> {
+        self.queued
+            .iter()
+            .map(|(peer, _, since)| (peer.clone(), since.elapsed()))
+            .collect()
+    }
+
+    fn has_room(&self) -> bool {
+        self.limits
+            .max_concurrent
+            .is_none_or(|max| self.dialing.len() < max)
+    }
+
+    fn forget_stale(&mut self) {
+        self.dialing
+            .retain(|_, since| since.elapsed() < STALE_AFTER);
+    }
+}
@@ -0,0 +1,167 @@
+use libp2p::{
+    core::{
+        connection::ConnectionId,
+        upgrade::{DeniedUpgrade, InboundUpgrade, OutboundUpgrade},
+        ConnectedPoint, Multiaddr, PeerId,
+    },
+    swarm::{
+        KeepAlive, NegotiatedSubstream, NetworkBehaviour,
+        NetworkBehaviourAction, NotifyHandler, PollParameters,
+        ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr,
+        SubstreamProtocol,
+    },
+};
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    error, fmt,
+    task::{Context, Poll},
+};
+
+// Closes all the connections to a peer. Swarm::ban_peer_id does too, but
+// (in libp2p 0.22) then nobody hears that they closed: the swarm keeps
+// counting them as established, and kademlia keeps thinking the peer is
+// connected. A connection that its handler closes goes away properly.
+#[derive(Debug, Default)]
+pub struct Disconnect {
+    // Peers whose handlers have yet to be told to close
+    to_close: VecDeque<PeerId>,
+}
+
+impl Disconnect {
+    pub fn close(&mut self, peer: PeerId) {
+        self.to_close.push_back(peer);
+    }
+}
+
+impl NetworkBehaviour for Disconnect {
+    type ProtocolsHandler = CloseHandler;
+    type OutEvent = Infallible;
+
+    fn new_handler(&mut self) -> CloseHandler {
+        CloseHandler::default()
+    }
+
+    fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+        Vec::new()
+    }
+
+    fn inject_connected(&mut self, _: &PeerId) {}
+
+    fn inject_disconnected(&mut self, _: &PeerId) {}
+
+    fn inject_connection_closed(
+        &mut self,
+        _: &PeerId,
+        _: &ConnectionId,
+        _: &ConnectedPoint,
+    ) {
+    }
+
+    fn inject_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: Infallible,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<(), Infallible>> {
+        match self.to_close.pop_front() {
+            Some(peer_id) => {
+                Poll::Ready(NetworkBehaviourAction::NotifyHandler {
+                    peer_id,
+                    handler: NotifyHandler::All,
+                    event: (),
+                })
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+// The handler has no protocol of its own (and doesn't keep connections
+// open). Told to (with an event), it closes its connection.
+#[derive(Debug, Default)]
+pub struct CloseHandler {
+    closing: bool,
+}
+
+impl ProtocolsHandler for CloseHandler {
+    type InEvent = ();
+    type OutEvent = Infallible;
+    type Error = Closed;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type OutboundOpenInfo = Infallible;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<DeniedUpgrade> {
+        SubstreamProtocol::new(DeniedUpgrade)
+    }
+
+    fn inject_fully_negotiated_inbound(
+        &mut self,
+        _: <DeniedUpgrade as InboundUpgrade<NegotiatedSubstream>>::Output,
+    ) {
+    }
+
+    fn inject_fully_negotiated_outbound(
+        &mut self,
+        _: <DeniedUpgrade as OutboundUpgrade<NegotiatedSubstream>>::Output,
+        _: Infallible,
+    ) {
+    }
+
+    fn inject_event(&mut self, _: ()) {
+        self.closing = true;
+    }
+
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Infallible,
+        _: ProtocolsHandlerUpgrErr<
+            <DeniedUpgrade as OutboundUpgrade<NegotiatedSubstream>>::Error,
+        >,
+    ) {
+        match info {}
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        KeepAlive::No
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<
+        ProtocolsHandlerEvent<
+            DeniedUpgrade,
+            Infallible,
+            Infallible,
+            Closed,
+        >,
+    > {
+        if self.closing {
+            self.closing = false;
+            return Poll::Ready(ProtocolsHandlerEvent::Close(Closed));
+        }
+        Poll::Pending
+    }
+}
+
+// Why the connection closed.
+#[derive(Debug)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "closed on purpose")
+    }
+}
+
+impl error::Error for Closed {}
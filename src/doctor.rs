@@ -0,0 +1,549 @@
+use crate::{
+    bootstrap,
+    config::{DoctorArgs, Opts},
+    crawl, portmap,
+    transport::TransportKind,
+};
+use async_std::task;
+use futures::{future, FutureExt};
+use futures_timer::Delay;
+use libp2p::{
+    core::ConnectedPoint, multiaddr::Protocol, swarm::SwarmEvent,
+    Multiaddr, PeerId, Swarm,
+};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    net::{Ipv4Addr, SocketAddr, TcpListener, ToSocketAddrs, UdpSocket},
+    process,
+    task::{Context, Poll},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Where mDNS queries go.
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+// Where UPnP gateways answer searches (SSDP).
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+
+// The seconds from 1900 (where NTP counts from) to 1970.
+const NTP_EPOCH_OFFSET: u64 = 2_208_988_800;
+
+// How far off the clock may be before it gets a warning, and before it
+// fails.
+const CLOCK_WARN: Duration = Duration::from_secs(1);
+const CLOCK_FAIL: Duration = Duration::from_secs(30);
+
+// How a check went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Pass,
+    // Worth knowing about, but the node works anyway
+    Warn,
+    Fail,
+    // Not checked, since the options don't need it
+    Skip,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+            Status::Skip => "SKIP",
+        })
+    }
+}
+
+// How the checks went so far. Each one is printed as it is done.
+#[derive(Debug, Default)]
+struct Report(Vec<Status>);
+
+impl Report {
+    fn add(
+        &mut self,
+        status: Status,
+        check: &str,
+        detail: impl fmt::Display,
+    ) {
+        println!("{:4}  {:<10} {}", status, check, detail);
+        self.0.push(status);
+    }
+
+    fn count(&self, status: Status) -> usize {
+        self.0.iter().filter(|s| **s == status).count()
+    }
+}
+
+// `nettest doctor`: check what the node needs of the machine and the
+// network, with the options it would run with (the ports of --listen
+// and --api-addr, mDNS, port mapping, the clock, and the --bootstrap
+// peers), and print how each check went. Exits with 1 if any failed.
+pub fn run(opts: &Opts, args: &DoctorArgs) -> Result<(), Box<dyn Error>> {
+    let mut report = Report::default();
+    check_ports(opts, &mut report);
+    check_mdns(opts, &mut report);
+    check_gateway(args, &mut report);
+    check_clock(args, &mut report);
+    check_bootstrap(opts, args, &mut report)?;
+
+    println!(
+        "{} passed, {} warnings, {} failed, {} skipped",
+        report.count(Status::Pass),
+        report.count(Status::Warn),
+        report.count(Status::Fail),
+        report.count(Status::Skip)
+    );
+    if report.count(Status::Fail) > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+// Whether the node can listen where it is told to. The default
+// addresses take any port, and a host without IPv6 (or IPv4) only
+// warrants a warning, as the node gets by with the other.
+fn check_ports(opts: &Opts, report: &mut Report) {
+    let defaults = opts.listen.is_empty();
+    let addrs: Vec<Multiaddr> = match defaults {
+        true => opts
+            .transports(TransportKind::Tcp)
+            .iter()
+            .flat_map(|kind| kind.listen_addrs())
+            .collect(),
+        false => opts.listen.clone(),
+    };
+    for addr in addrs {
+        let socket = match tcp_socket(&addr) {
+            Some(socket) => socket,
+            None => {
+                report.add(
+                    Status::Skip,
+                    "listen",
+                    format!("{} (only TCP ports get checked)", addr),
+                );
+                continue;
+            }
+        };
+        match TcpListener::bind(socket) {
+            Ok(listener) => {
+                let port = listener
+                    .local_addr()
+                    .map_or(socket.port(), |local| local.port());
+                report.add(
+                    Status::Pass,
+                    "listen",
+                    match socket.port() {
+                        0 => format!("{} (got port {})", addr, port),
+                        _ => addr.to_string(),
+                    },
+                );
+            }
+            Err(err) => report.add(
+                match defaults {
+                    true => Status::Warn,
+                    false => Status::Fail,
+                },
+                "listen",
+                format!("{}: {}", addr, err),
+            ),
+        }
+    }
+    if let Some(addr) = opts.api_addr {
+        match TcpListener::bind(addr) {
+            Ok(_) => report.add(Status::Pass, "api", addr),
+            Err(err) => report.add(
+                Status::Fail,
+                "api",
+                format!("{}: {}", addr, err),
+            ),
+        }
+    }
+    if opts.broadcast_discovery {
+        let addr = SocketAddr::from(([0, 0, 0, 0], opts.broadcast_port));
+        match UdpSocket::bind(addr) {
+            Ok(_) => report.add(Status::Pass, "broadcast", addr),
+            Err(err) => report.add(
+                Status::Fail,
+                "broadcast",
+                format!("{}: {}", addr, err),
+            ),
+        }
+    }
+}
+
+// The socket address of a TCP listen address, like
+// `/ip4/0.0.0.0/tcp/4001` (with or without /ws after it).
+fn tcp_socket(addr: &Multiaddr) -> Option<SocketAddr> {
+    let mut protocols = addr.iter();
+    match (protocols.next(), protocols.next()) {
+        (Some(Protocol::Ip4(ip)), Some(Protocol::Tcp(port))) => {
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        (Some(Protocol::Ip6(ip)), Some(Protocol::Tcp(port))) => {
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        _ => None,
+    }
+}
+
+// Whether mDNS can work: joining its multicast group, and sending to it.
+fn check_mdns(opts: &Opts, report: &mut Report) {
+    if opts.no_mdns {
+        report.add(Status::Skip, "mdns", "turned off with --no-mdns");
+        return;
+    }
+    let sent =
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|socket| {
+            socket
+                .join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+            socket.send_to(&mdns_query(), (MDNS_GROUP, MDNS_PORT))
+        });
+    match sent {
+        Ok(_) => report.add(
+            Status::Pass,
+            "mdns",
+            format!("joined {} and sent a query", MDNS_GROUP),
+        ),
+        Err(err) => report.add(
+            Status::Fail,
+            "mdns",
+            format!(
+                "multicast doesn't work here ({}): peers on the local \
+                 network won't be found (see --no-mdns)",
+                err
+            ),
+        ),
+    }
+}
+
+// A query for the PTR records of `_p2p._udp.local`, which is what the
+// libp2p nodes answer.
+fn mdns_query() -> Vec<u8> {
+    let mut query = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in ["_p2p", "_udp", "local"] {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    // The end of the name, type PTR, class IN
+    query.extend_from_slice(&[0, 0, 12, 0, 1]);
+    query
+}
+
+// Whether the router maps ports: with NAT-PMP, which --port-mapping
+// speaks, or with UPnP, which it doesn't (yet) but which tells that
+// there is a gateway to ask.
+fn check_gateway(args: &DoctorArgs, report: &mut Report) {
+    match task::block_on(portmap::external_address()) {
+        Ok(ip) => report.add(
+            Status::Pass,
+            "nat-pmp",
+            format!("the router answers, the external address is {}", ip),
+        ),
+        Err(err) => report.add(
+            Status::Warn,
+            "nat-pmp",
+            format!("{} (--port-mapping won't work)", err),
+        ),
+    }
+    match ssdp_search(args.timeout) {
+        Ok(Some((from, server))) => report.add(
+            Status::Pass,
+            "upnp",
+            format!("a gateway answered from {} ({})", from, server),
+        ),
+        Ok(None) => report.add(
+            Status::Warn,
+            "upnp",
+            format!("no gateway answered within {:?}", args.timeout),
+        ),
+        Err(err) => report.add(Status::Warn, "upnp", err),
+    }
+}
+
+// Ask for UPnP internet gateways, and return the first one to answer,
+// with what it says it runs.
+fn ssdp_search(
+    timeout: Duration,
+) -> std::io::Result<Option<(SocketAddr, String)>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_read_timeout(Some(timeout))?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: {}\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: {}\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+        SSDP_ADDR,
+        timeout.as_secs().max(1)
+    );
+    socket.send_to(search.as_bytes(), SSDP_ADDR)?;
+    let mut buf = [0; 2048];
+    match socket.recv_from(&mut buf) {
+        Ok((len, from)) => {
+            let response = String::from_utf8_lossy(&buf[..len]);
+            let server = response
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("server")
+                        .then(|| value.trim().to_string())
+                })
+                .unwrap_or_else(|| "no SERVER header".to_string());
+            Ok(Some((from, server)))
+        }
+        Err(err)
+            if matches!(
+                err.kind(),
+                std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::TimedOut
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// Whether the clock can be trusted: it is past the time this was
+// written, it doesn't jump while it is watched, and it agrees with an
+// NTP server (if one answers).
+fn check_clock(args: &DoctorArgs, report: &mut Report) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    // 2021-01-01
+    if now < Duration::from_secs(1_609_459_200) {
+        report.add(
+            Status::Fail,
+            "clock",
+            format!(
+                "the clock is in the past ({}s since 1970)",
+                now.as_secs()
+            ),
+        );
+        return;
+    }
+    let watch = Duration::from_millis(500);
+    let (wall, monotonic) = (SystemTime::now(), Instant::now());
+    thread::sleep(watch);
+    let wall = wall.elapsed().unwrap_or_default();
+    let drift = wall.abs_diff(monotonic.elapsed());
+    if drift > Duration::from_millis(100) {
+        report.add(
+            Status::Warn,
+            "clock",
+            format!(
+                "the wall clock moved {:.1?} in {:.1?} (something sets it)",
+                wall, watch
+            ),
+        );
+    } else {
+        report.add(Status::Pass, "clock", "the wall clock runs steadily");
+    }
+
+    let server = match &args.ntp_server {
+        Some(server) => server,
+        None => {
+            report.add(Status::Skip, "ntp", "no --ntp-server");
+            return;
+        }
+    };
+    match ntp_offset(server, args.timeout) {
+        Ok(offset) => {
+            let (ahead, by) = match offset >= 0.0 {
+                true => ("ahead of", Duration::from_secs_f64(offset)),
+                false => ("behind", Duration::from_secs_f64(-offset)),
+            };
+            let status = match by {
+                by if by > CLOCK_FAIL => Status::Fail,
+                by if by > CLOCK_WARN => Status::Warn,
+                _ => Status::Pass,
+            };
+            report.add(
+                status,
+                "ntp",
+                format!("the clock is {:.1?} {} {}", by, ahead, server),
+            );
+        }
+        Err(err) => report.add(
+            Status::Warn,
+            "ntp",
+            format!("couldn't ask {}: {}", server, err),
+        ),
+    }
+}
+
+// How far ahead of `server` the clock is, in seconds, with one SNTP
+// request (RFC 4330).
+fn ntp_offset(server: &str, timeout: Duration) -> std::io::Result<f64> {
+    let addr = server.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "no address")
+    })?;
+    let socket = UdpSocket::bind(match addr {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    })?;
+    socket.set_read_timeout(Some(timeout))?;
+    // Version 4, client mode
+    let mut request = [0u8; 48];
+    request[0] = 0x23;
+    let sent = unix_seconds();
+    socket.send_to(&request, addr)?;
+    let mut response = [0u8; 48];
+    let (len, _) = socket.recv_from(&mut response)?;
+    let received = unix_seconds();
+    if len < 48 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "short SNTP response",
+        ));
+    }
+    // The time the server sent its answer at
+    let seconds = u32::from_be_bytes([
+        response[40],
+        response[41],
+        response[42],
+        response[43],
+    ]) as u64;
+    let fraction = u32::from_be_bytes([
+        response[44],
+        response[45],
+        response[46],
+        response[47],
+    ]) as f64
+        / 2f64.powi(32);
+    let server_time =
+        seconds.saturating_sub(NTP_EPOCH_OFFSET) as f64 + fraction;
+    Ok((sent + received) / 2.0 - server_time)
+}
+
+fn unix_seconds() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+// Whether the --bootstrap peers resolve, and take connections from a
+// node of ours (the whole handshake, not just TCP).
+fn check_bootstrap(
+    opts: &Opts,
+    args: &DoctorArgs,
+    report: &mut Report,
+) -> Result<(), Box<dyn Error>> {
+    if opts.bootstrap.is_empty() {
+        report.add(Status::Skip, "bootstrap", "no --bootstrap peers");
+        return Ok(());
+    }
+    let mut peers = Vec::new();
+    for entry in &opts.bootstrap {
+        match task::block_on(bootstrap::resolve(entry)) {
+            Ok(resolved) if resolved.is_empty() => report.add(
+                Status::Fail,
+                "bootstrap",
+                format!("{} resolves to no peers", entry),
+            ),
+            Ok(resolved) => peers.extend(resolved),
+            Err(err) => report.add(
+                Status::Fail,
+                "bootstrap",
+                format!("{}: {}", entry, err),
+            ),
+        }
+    }
+    if peers.is_empty() {
+        return Ok(());
+    }
+    let mut swarm = crawl::spawn(opts)?;
+    // Each address is dialed on its own, since a peer may have several
+    let mut waiting: HashMap<Multiaddr, PeerId> = HashMap::new();
+    for (peer_id, addr) in peers {
+        match Swarm::dial_addr(&mut swarm, addr.clone()) {
+            Ok(()) => {
+                waiting.insert(addr, peer_id);
+            }
+            Err(err) => report.add(
+                Status::Fail,
+                "bootstrap",
+                format!("{}/p2p/{}: {:?}", addr, peer_id, err),
+            ),
+        }
+    }
+    let began = Instant::now();
+    let mut deadline = Delay::new(args.timeout);
+    task::block_on(future::poll_fn(|cx: &mut Context<'_>| {
+        loop {
+            let event = match Box::pin(swarm.next_event()).poll_unpin(cx) {
+                Poll::Ready(event) => event,
+                Poll::Pending => break,
+            };
+            let (addr, outcome) = match event {
+                SwarmEvent::ConnectionEstablished {
+                    peer_id,
+                    endpoint: ConnectedPoint::Dialer { address },
+                    ..
+                } => {
+                    let outcome = match waiting.get(&address) {
+                        Some(expected) if *expected != peer_id => {
+                            Err(format!(
+                                "answers as another peer, {}",
+                                peer_id
+                            ))
+                        }
+                        _ => Ok(()),
+                    };
+                    (address, outcome)
+                }
+                SwarmEvent::UnknownPeerUnreachableAddr {
+                    address,
+                    error,
+                } => (address, Err(error.to_string())),
+                _ => continue,
+            };
+            let peer_id = match waiting.remove(&addr) {
+                Some(peer_id) => peer_id,
+                None => continue,
+            };
+            match outcome {
+                Ok(()) => report.add(
+                    Status::Pass,
+                    "bootstrap",
+                    format!(
+                        "{}/p2p/{} (connected in {:.1?})",
+                        addr,
+                        peer_id,
+                        began.elapsed()
+                    ),
+                ),
+                Err(err) => report.add(
+                    Status::Fail,
+                    "bootstrap",
+                    format!("{}/p2p/{}: {}", addr, peer_id, err),
+                ),
+            }
+        }
+        if waiting.is_empty() {
+            return Poll::Ready(());
+        }
+        if deadline.poll_unpin(cx).is_ready() {
+            for (addr, peer_id) in waiting.drain() {
+                report.add(
+                    Status::Fail,
+                    "bootstrap",
+                    format!(
+                        "{}/p2p/{}: no connection within {:?}",
+                        addr, peer_id, args.timeout
+                    ),
+                );
+            }
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }));
+    Ok(())
+}
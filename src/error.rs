@@ -0,0 +1,43 @@
+use crate::command::ParseError;
+use libp2p::kad::record::store;
+use std::io;
+
+// What can go wrong in a node, for the code that uses nettest as a
+// library (the terminal just prints it). Failures that are part of an
+// experiment, like a record that doesn't validate, are reported where
+// they happen instead, and never stop the node.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    // Setting up the transports failed
+    #[error("transport: {0}")]
+    Transport(#[source] io::Error),
+    // The local store didn't take a record
+    #[error("{}", describe_store(.0))]
+    Store(store::Error),
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    // A PUT, GET, or any other query, failed
+    #[error("query failed: {0}")]
+    Query(String),
+    // A node of a testnet that was killed
+    #[error("node {0} is not running")]
+    NotRunning(usize),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<store::Error> for Error {
+    fn from(err: store::Error) -> Self {
+        Error::Store(err)
+    }
+}
+
+fn describe_store(err: &store::Error) -> &'static str {
+    match err {
+        store::Error::MaxRecords => "the store holds too many records",
+        store::Error::MaxProvidedKeys => {
+            "the store holds too many provided keys"
+        }
+        store::Error::ValueTooLarge => "the value is too large",
+    }
+}
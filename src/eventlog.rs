@@ -0,0 +1,71 @@
+use crate::timescale;
+use libp2p::PeerId;
+use serde_json::{json, Value};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, LineWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// The --event-log: every event the websocket clients hear about (plus
+// what happens to the local store), appended to a file as one JSON object
+// per line, like
+//   {"time": 1602678000123, "node": "12D3Koo...", "event": "peer_connected",
+//    "peer_id": "12D3Koo...", "address": "/ip4/1.2.3.4/tcp/4001"}
+// where the time is in milliseconds since the epoch. The nodes of a
+// simulation share the file, and with a --time-scale, there is a
+// "virtual_ms" too: the time on the clock of the simulation.
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    file: Arc<Mutex<LineWriter<File>>>,
+    node: Option<String>,
+}
+
+impl EventLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file =
+            OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(EventLog {
+            file: Arc::new(Mutex::new(LineWriter::new(file))),
+            node: None,
+        })
+    }
+
+    // The same log, for the events of `node`.
+    pub fn for_node(&self, node: &PeerId) -> Self {
+        EventLog {
+            file: self.file.clone(),
+            node: Some(node.to_string()),
+        }
+    }
+
+    // Append an event. The fields of `params` (an object) go next to the
+    // time, the node and the name of the event.
+    pub fn write(&self, event: &str, params: &Value) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or(0);
+        let mut line = json!({
+            "time": time,
+            "node": self.node,
+            "event": event,
+        });
+        if let Some(time) = timescale::now() {
+            line["virtual_ms"] = json!(time.as_millis() as u64);
+        }
+        if let (Some(line), Some(params)) =
+            (line.as_object_mut(), params.as_object())
+        {
+            for (name, value) in params {
+                line.insert(name.clone(), value.clone());
+            }
+        }
+        let mut file = self.file.lock().unwrap();
+        if let Err(err) = writeln!(file, "{}", line) {
+            eprintln!("event log: {}", err);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use futures::{channel::mpsc, Stream};
+use libp2p::{kad::QueryId, Multiaddr, PeerId};
+use std::time::Duration;
+
+// How many events a subscriber can fall behind by before it starts
+// missing some.
+pub const EVENT_BUFFER: usize = 256;
+
+// What a node tells the programs that embed it (see `events`), as the
+// notifications of the websocket api do, but typed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeEvent {
+    // We learned of a peer, and where it is. `via` is how ("mdns",
+    // "kademlia", "pex", ...).
+    PeerDiscovered {
+        peer: PeerId,
+        address: Multiaddr,
+        via: String,
+    },
+    // A record went into the local store: one of ours, or one that a peer
+    // put here (the key is without the namespace)
+    RecordStored {
+        key: String,
+        publisher: Option<PeerId>,
+    },
+    // A query of ours ended. `kind` is what it was, as in the
+    // `query_finished` notification (like "get_record").
+    QueryCompleted {
+        id: QueryId,
+        kind: &'static str,
+        ok: bool,
+        duration: Duration,
+    },
+    // A connection to a peer closed. `cause` is what closed it ("io",
+    // "idle" or "handler"), as in the `connection_closed` notification.
+    ConnectionClosed {
+        peer: PeerId,
+        address: Multiaddr,
+        connections_left: u32,
+        cause: &'static str,
+        error: String,
+    },
+    // The subscriber fell behind, and missed this many events
+    Lagged {
+        missed: u64,
+    },
+}
+
+// A subscriber, and how many events it missed since it last got one.
+#[derive(Debug)]
+struct Subscriber {
+    events: mpsc::Sender<NodeEvent>,
+    missed: u64,
+}
+
+// The programs that subscribed to the events of a node. Each of them has
+// a buffer of its own, so a slow one doesn't hold up the node (or the
+// others): once its buffer is full, it misses events, and finds out how
+// many with the next one it gets.
+#[derive(Debug, Default)]
+pub struct EventStreams {
+    subscribers: Vec<Subscriber>,
+}
+
+impl EventStreams {
+    pub fn subscribe(
+        &mut self,
+    ) -> impl Stream<Item = NodeEvent> + Send + Unpin {
+        let (events, rx) = mpsc::channel(EVENT_BUFFER);
+        self.subscribers.push(Subscriber { events, missed: 0 });
+        rx
+    }
+
+    pub fn is_listening(&self) -> bool {
+        !self.subscribers.is_empty()
+    }
+
+    // Hand `event` to every subscriber, forgetting the ones that have
+    // gone away.
+    pub fn emit(&mut self, event: NodeEvent) {
+        self.subscribers.retain_mut(|subscriber| {
+            if subscriber.missed > 0 {
+                let missed = subscriber.missed;
+                match subscriber
+                    .events
+                    .try_send(NodeEvent::Lagged { missed })
+                {
+                    Ok(()) => subscriber.missed = 0,
+                    Err(err) if err.is_disconnected() => return false,
+                    Err(_) => {}
+                }
+            }
+            if subscriber.missed > 0 {
+                subscriber.missed += 1;
+                return true;
+            }
+            match subscriber.events.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(err) if err.is_disconnected() => false,
+                Err(_) => {
+                    subscriber.missed += 1;
+                    true
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,321 @@
+use crate::{
+    bench::{self, Phase},
+    config::{BenchArgs, ExperimentArgs, Opts},
+    toml,
+};
+use libp2p::kad::{Quorum, K_VALUE};
+use std::{
+    error::Error,
+    fmt, fs,
+    io::{self, Write},
+    num::NonZeroUsize,
+};
+
+// How many peers a query asks at once, when the sweep doesn't say (and
+// neither does --parallelism). Kademlia's own default.
+const ALPHA: usize = 3;
+
+// The columns of the results, one row per run of a configuration.
+const HEADER: &str = "nodes,replication_factor,alpha,quorum,run,\
+    puts_ok,puts_failed,put_ops_per_s,put_p50_ms,put_p90_ms,put_p99_ms,\
+    put_requests,gets_ok,gets_failed,get_ops_per_s,get_p50_ms,get_p90_ms,\
+    get_p99_ms,get_requests";
+
+// `nettest experiment sweep.toml`: run the same workload (that of bench)
+// on a simulated network over and over, once for every combination of
+// the parameters the file sweeps, and write a row of CSV with the results
+// of each run. A file looks like
+//
+//   # What every run does
+//   [workload]
+//   puts = 200
+//   gets = 200
+//   value_size = 256
+//   concurrency = 16
+//   repeat = 3
+//
+//   # Every combination of these is a configuration
+//   [sweep]
+//   nodes = [10, 20, 40]
+//   replication_factor = [2, 5, 20]
+//   alpha = [1, 3]
+//   quorum = 1
+//
+// Everything in it is optional: what isn't there is as the command line
+// says (or as bench has it). The other options (like --fault) apply to
+// every run.
+pub fn run(
+    opts: &Opts,
+    args: &ExperimentArgs,
+) -> Result<(), Box<dyn Error>> {
+    let text = fs::read_to_string(&args.file)
+        .map_err(|err| format!("{}: {}", args.file.display(), err))?;
+    let experiment = Experiment::parse(&text)
+        .map_err(|err| format!("{}: {}", args.file.display(), err))?;
+    let configs = experiment.configurations(opts);
+
+    let mut out: Box<dyn Write> = match &args.csv {
+        Some(path) => Box::new(fs::File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    writeln!(out, "{}", HEADER)?;
+
+    let runs = configs.len() * experiment.repeat;
+    for (i, config) in configs.iter().enumerate() {
+        for run in 1..=experiment.repeat {
+            let n = i * experiment.repeat + run;
+            eprintln!("Run {} of {}: {}", n, runs, config);
+            let outcome = bench::measure(
+                &config.opts(opts),
+                &experiment.workload(config.nodes),
+                config.quorum(),
+                |_| {},
+            )?;
+            for phase in &[&outcome.puts, &outcome.gets] {
+                let summary = phase.to_string();
+                eprintln!(
+                    "  {}",
+                    summary.lines().next().unwrap_or_default()
+                );
+            }
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{}",
+                config.nodes,
+                config.replication_factor,
+                config.alpha,
+                config.quorum,
+                run,
+                columns(&outcome.puts),
+                columns(&outcome.gets)
+            )?;
+            out.flush()?;
+        }
+    }
+    if let Some(path) = &args.csv {
+        eprintln!("Wrote the results to {}", path.display());
+    }
+    Ok(())
+}
+
+// The results of one phase of a run, as columns.
+fn columns(phase: &Phase) -> String {
+    let result = &phase.result;
+    let ms = |fraction| result.percentile(fraction).as_secs_f64() * 1000.0;
+    format!(
+        "{},{},{:.1},{:.3},{:.3},{:.3},{:.2}",
+        result.succeeded,
+        result.failed,
+        result.throughput(),
+        ms(0.5),
+        ms(0.9),
+        ms(0.99),
+        phase.requests_per_query().0
+    )
+}
+
+// What a sweep file asks for.
+#[derive(Debug)]
+struct Experiment {
+    puts: usize,
+    gets: usize,
+    value_size: usize,
+    concurrency: usize,
+    repeat: usize,
+    // The values to sweep (None is what the command line says)
+    nodes: Vec<usize>,
+    replication_factor: Vec<Option<usize>>,
+    alpha: Vec<Option<usize>>,
+    quorum: Vec<usize>,
+}
+
+// A combination of the swept parameters.
+#[derive(Debug, Clone)]
+struct Configuration {
+    nodes: usize,
+    replication_factor: usize,
+    alpha: usize,
+    quorum: usize,
+}
+
+impl fmt::Display for Configuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} nodes, replication factor {}, alpha {}, quorum {}",
+            self.nodes, self.replication_factor, self.alpha, self.quorum
+        )
+    }
+}
+
+impl Configuration {
+    // The options to run with: those of the command line, but for the
+    // ones that are swept.
+    fn opts(&self, opts: &Opts) -> Opts {
+        let mut opts = opts.clone();
+        opts.replication_factor =
+            NonZeroUsize::new(self.replication_factor);
+        opts.parallelism = NonZeroUsize::new(self.alpha);
+        opts
+    }
+
+    fn quorum(&self) -> Quorum {
+        match NonZeroUsize::new(self.quorum) {
+            Some(n) if self.quorum > 1 => Quorum::N(n),
+            _ => Quorum::One,
+        }
+    }
+}
+
+impl Experiment {
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut experiment = Experiment {
+            puts: 100,
+            gets: 100,
+            value_size: 256,
+            concurrency: 16,
+            repeat: 1,
+            nodes: vec![10],
+            replication_factor: vec![None],
+            alpha: vec![None],
+            quorum: vec![1],
+        };
+        for entry in toml::parse(text).map_err(|err| err.to_string())? {
+            let toml::Setting {
+                line,
+                table,
+                key,
+                value,
+            } = match entry {
+                toml::Entry::Setting(setting) => setting,
+                toml::Entry::Table(..) => continue,
+            };
+            Value::from_toml(value)
+                .and_then(|value| experiment.set(&table, &key, &value))
+                .map_err(|err| format!("line {}: {}", line, err))?;
+        }
+        if experiment.repeat == 0 {
+            return Err("repeat can't be 0".into());
+        }
+        Ok(experiment)
+    }
+
+    fn set(
+        &mut self,
+        section: &str,
+        key: &str,
+        value: &Value,
+    ) -> Result<(), String> {
+        match (section, key) {
+            ("workload", "puts") => self.puts = value.one()?,
+            ("workload", "gets") => self.gets = value.one()?,
+            ("workload", "value_size") => self.value_size = value.one()?,
+            ("workload", "concurrency") => {
+                self.concurrency = value.one()?
+            }
+            ("workload", "repeat") => self.repeat = value.one()?,
+            ("sweep", "nodes") => self.nodes = value.all()?,
+            ("sweep", "replication_factor") => {
+                self.replication_factor =
+                    value.all()?.into_iter().map(Some).collect()
+            }
+            ("sweep", "alpha") => {
+                self.alpha = value.all()?.into_iter().map(Some).collect()
+            }
+            ("sweep", "quorum") => self.quorum = value.all()?,
+            ("", _) => {
+                return Err(format!(
+                    "{} is outside of [workload] and [sweep]",
+                    key
+                ))
+            }
+            _ => {
+                return Err(format!(
+                    "unknown setting {} in [{}]",
+                    key, section
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    // Every combination of the swept parameters, in the order of the
+    // file (the last one changes fastest).
+    fn configurations(&self, opts: &Opts) -> Vec<Configuration> {
+        let mut configs = Vec::new();
+        for &nodes in &self.nodes {
+            for k in &self.replication_factor {
+                for alpha in &self.alpha {
+                    for &quorum in &self.quorum {
+                        let k = k
+                            .or(opts.replication_factor.map(|k| k.get()))
+                            .unwrap_or(K_VALUE.get());
+                        let alpha = alpha
+                            .or(opts.parallelism.map(|a| a.get()))
+                            .unwrap_or(ALPHA);
+                        configs.push(Configuration {
+                            nodes,
+                            replication_factor: k,
+                            alpha,
+                            quorum,
+                        });
+                    }
+                }
+            }
+        }
+        configs
+    }
+
+    fn workload(&self, nodes: usize) -> BenchArgs {
+        BenchArgs {
+            puts: self.puts,
+            gets: self.gets,
+            value_size: self.value_size,
+            concurrency: self.concurrency,
+            nodes,
+            results: None,
+        }
+    }
+}
+
+// A value of a sweep file: a number, or a list of them.
+#[derive(Debug)]
+enum Value {
+    One(usize),
+    List(Vec<usize>),
+}
+
+impl Value {
+    fn from_toml(value: toml::Value) -> Result<Self, String> {
+        match value {
+            toml::Value::List(items) => items
+                .iter()
+                .map(|(_, item)| item.number().map(|n| n as usize))
+                .collect::<Result<_, _>>()
+                .map(Value::List),
+            value => value.number().map(|n| Value::One(n as usize)),
+        }
+    }
+
+    fn one(&self) -> Result<usize, String> {
+        match self {
+            Value::One(n) => Ok(*n),
+            Value::List(_) => Err("expected a number, not a list".into()),
+        }
+    }
+
+    // The values to sweep, none of which may be 0.
+    fn all(&self) -> Result<Vec<usize>, String> {
+        let all = match self {
+            Value::One(n) => vec![*n],
+            Value::List(list) if list.is_empty() => {
+                return Err("the list is empty".into())
+            }
+            Value::List(list) => list.clone(),
+        };
+        match all.contains(&0) {
+            true => Err("0 isn't a value to sweep".into()),
+            false => Ok(all),
+        }
+    }
+}
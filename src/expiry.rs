@@ -0,0 +1,234 @@
+use crate::peerinfo;
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::{
+    kad::{record::Key, QueryId, Record},
+    PeerId,
+};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+    task::Context,
+    time::{Duration, Instant},
+};
+
+// What to do about a record of ours that is about to expire, from
+// --renew: only tell (`notify`), or store it again with a new expiry
+// (`reput`), at most so many times (`reput:3`) before letting it lapse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Renew {
+    #[default]
+    Notify,
+    Reput(Option<u32>),
+}
+
+impl FromStr for Renew {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.split_once(':') {
+            None if s == "notify" => Ok(Renew::Notify),
+            None if s == "reput" => Ok(Renew::Reput(None)),
+            Some(("reput", n)) => n
+                .parse()
+                .ok()
+                .filter(|n| *n > 0)
+                .map(|n| Renew::Reput(Some(n)))
+                .ok_or_else(|| {
+                    format!("{:?} is not a number of times", n)
+                }),
+            _ => Err(format!(
+                "expected notify, reput or reput:N, not {:?}",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Renew {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Renew::Notify => write!(f, "notify"),
+            Renew::Reput(None) => write!(f, "reput"),
+            Renew::Reput(Some(n)) => write!(f, "reput:{}", n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExpiryConfig {
+    // How long before they expire records count as expiring
+    pub warn_before: Duration,
+    pub renew: Renew,
+    // How long the records that the node stores last (--record-ttl)
+    pub record_ttl: Duration,
+}
+
+// Something that happened to a record of ours, for the node to report.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expiry {
+    // It expires in this long, and gets stored again (or not)
+    Expiring {
+        key: Key,
+        left: Duration,
+        renew: bool,
+    },
+    // It was gone at the next look (it expired, or got removed)
+    Expired(Key),
+}
+
+// Keeps an eye on the records this node published, when nothing
+// republishes them (--republish-interval 0s), for applications that
+// renew their leases on their own: it tells when one is about to expire
+// (--expiry-warning), and then stores it again or not (--renew).
+#[derive(Debug)]
+pub struct ExpiryWatch {
+    config: ExpiryConfig,
+    timer: Delay,
+    // The records already reported as expiring, with the expiry they had
+    // then (a record that got a new one may be reported again)
+    warned: HashMap<Key, Instant>,
+    // How many times each record was stored again
+    renewals: HashMap<Key, u32>,
+    // The puts of the renewals
+    queries: HashMap<QueryId, Key>,
+    // When the copies of our records that the other nodes keep expire.
+    // Our own copy never does (kademlia leaves the records it stores for
+    // its own node without an expiry), so the watch goes by when the
+    // last put of each one went through.
+    published: HashMap<Key, Instant>,
+}
+
+impl ExpiryWatch {
+    pub fn new(config: ExpiryConfig) -> Self {
+        ExpiryWatch {
+            config,
+            timer: Delay::new(Duration::ZERO),
+            warned: HashMap::new(),
+            renewals: HashMap::new(),
+            queries: HashMap::new(),
+            published: HashMap::new(),
+        }
+    }
+
+    // A put of our record of `key` went through: the other nodes keep it
+    // for --record-ttl from now.
+    pub fn published(&mut self, key: Key) {
+        self.published
+            .insert(key, Instant::now() + self.config.record_ttl);
+    }
+
+    // How often to look: often enough to see a record before it expires.
+    fn interval(&self) -> Duration {
+        (self.config.warn_before / 4)
+            .clamp(Duration::from_millis(100), Duration::from_secs(10))
+    }
+
+    // Whether it is time to look at the records again.
+    pub fn due(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.timer.poll_unpin(cx).is_pending() {
+            return false;
+        }
+        self.timer = Delay::new(self.interval());
+        let _ = self.timer.poll_unpin(cx);
+        true
+    }
+
+    // Look at the `records` of the local store: what there is to report,
+    // and the records to store again.
+    pub fn check<'a>(
+        &mut self,
+        local: &PeerId,
+        records: impl Iterator<Item = Cow<'a, Record>>,
+    ) -> (Vec<Expiry>, Vec<Record>) {
+        let now = Instant::now();
+        let mut events = Vec::new();
+        let mut renew = Vec::new();
+        let mut present = HashSet::new();
+        // Our peer info is the node's to publish again, on its own
+        let peer_info = peerinfo::key_for(local);
+        for record in records {
+            let record = record.as_ref();
+            if record.publisher.as_ref() != Some(local)
+                || record.key == peer_info
+            {
+                continue;
+            }
+            let expires = match self
+                .published
+                .get(&record.key)
+                .copied()
+                .or(record.expires)
+            {
+                Some(expires) => expires,
+                None => continue,
+            };
+            if expires <= now {
+                // The nodes that had it let it go
+                self.published.remove(&record.key);
+                self.warned.remove(&record.key);
+                self.renewals.remove(&record.key);
+                events.push(Expiry::Expired(record.key.clone()));
+                continue;
+            }
+            present.insert(record.key.clone());
+            let left = expires.saturating_duration_since(now);
+            if left > self.config.warn_before
+                || self.warned.get(&record.key) == Some(&expires)
+            {
+                continue;
+            }
+            self.warned.insert(record.key.clone(), expires);
+            let renewals = self.renewals.get(&record.key).copied();
+            let renews = match self.config.renew {
+                Renew::Notify => false,
+                Renew::Reput(None) => true,
+                Renew::Reput(Some(max)) => renewals.unwrap_or(0) < max,
+            };
+            events.push(Expiry::Expiring {
+                key: record.key.clone(),
+                left,
+                renew: renews,
+            });
+            if renews {
+                *self.renewals.entry(record.key.clone()).or_default() += 1;
+                renew.push(Record {
+                    // Kademlia only sets a new expiry on records without
+                    // one
+                    expires: None,
+                    ..record.clone()
+                });
+            }
+        }
+        // The records that were expiring and are gone now
+        let gone: Vec<Key> = self
+            .warned
+            .keys()
+            .filter(|key| !present.contains(*key))
+            .cloned()
+            .collect();
+        for key in gone {
+            self.published.remove(&key);
+            self.warned.remove(&key);
+            self.renewals.remove(&key);
+            events.push(Expiry::Expired(key));
+        }
+        (events, renew)
+    }
+
+    pub fn add_query(&mut self, query: QueryId, key: Key) {
+        self.queries.insert(query, key);
+    }
+
+    // The key that the put of `query` renewed, if it was one of ours.
+    pub fn finish(&mut self, query: &QueryId) -> Option<Key> {
+        self.queries.remove(query)
+    }
+
+    // How many times the record of `key` was stored again, so far.
+    pub fn renewals(&self, key: &Key) -> u32 {
+        self.renewals.get(key).copied().unwrap_or(0)
+    }
+}
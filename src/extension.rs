@@ -0,0 +1,203 @@
+use libp2p::{
+    core::{
+        connection::{ConnectionId, ListenerId},
+        ConnectedPoint, Multiaddr, PeerId,
+    },
+    swarm::{
+        DummyBehaviour, IntoProtocolsHandler, NetworkBehaviour,
+        NetworkBehaviourAction, PollParameters, ProtocolsHandler,
+    },
+};
+use serde_json::Value;
+use std::{
+    error, fmt, io,
+    task::{Context, Poll},
+};
+
+// What an extension has to tell: an event for the subscribers of the
+// node, named `name` (the `method` of the notification), with `params`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtensionEvent {
+    pub name: String,
+    pub params: Value,
+}
+
+// How the events of an extension become those of the node (or none, for
+// the ones nobody needs to hear about).
+pub type EventMap<E> = Box<dyn FnMut(E) -> Option<ExtensionEvent> + Send>;
+
+// A behaviour of someone else's that runs in the node, next to its own
+// (see NodeBuilder::with_behaviour). It sees every connection, and its
+// events go to the node's subscribers, like those of the node itself.
+// Without one, there is DummyBehaviour, which does nothing.
+pub struct Extension<B: NetworkBehaviour> {
+    pub behaviour: B,
+    events: EventMap<B::OutEvent>,
+}
+
+impl<B: NetworkBehaviour> Extension<B> {
+    pub fn new(behaviour: B, events: EventMap<B::OutEvent>) -> Self {
+        Extension { behaviour, events }
+    }
+}
+
+impl Default for Extension<DummyBehaviour> {
+    fn default() -> Self {
+        Extension::new(DummyBehaviour::default(), Box::new(|_| None))
+    }
+}
+
+impl<B: NetworkBehaviour + fmt::Debug> fmt::Debug for Extension<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extension")
+            .field("behaviour", &self.behaviour)
+            .finish()
+    }
+}
+
+type HandlerEvent<B> = <<<B as NetworkBehaviour>::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::OutEvent;
+type HandlerIn<B> = <<<B as NetworkBehaviour>::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::InEvent;
+
+// Everything goes to the behaviour, and everything it does goes to the
+// swarm, but its events, which go through the map.
+impl<B: NetworkBehaviour> NetworkBehaviour for Extension<B> {
+    type ProtocolsHandler = B::ProtocolsHandler;
+    type OutEvent = ExtensionEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.behaviour.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.behaviour.addresses_of_peer(peer_id)
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.behaviour.inject_connected(peer_id)
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.behaviour.inject_disconnected(peer_id)
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.behaviour
+            .inject_connection_established(peer_id, connection, endpoint)
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.behaviour
+            .inject_connection_closed(peer_id, connection, endpoint)
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        old: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        self.behaviour
+            .inject_address_change(peer_id, connection, old, new)
+    }
+
+    fn inject_event(
+        &mut self,
+        peer_id: PeerId,
+        connection: ConnectionId,
+        event: HandlerEvent<B>,
+    ) {
+        self.behaviour.inject_event(peer_id, connection, event)
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        addr: &Multiaddr,
+        error: &dyn error::Error,
+    ) {
+        self.behaviour
+            .inject_addr_reach_failure(peer_id, addr, error)
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.behaviour.inject_dial_failure(peer_id)
+    }
+
+    fn inject_new_listen_addr(&mut self, addr: &Multiaddr) {
+        self.behaviour.inject_new_listen_addr(addr)
+    }
+
+    fn inject_expired_listen_addr(&mut self, addr: &Multiaddr) {
+        self.behaviour.inject_expired_listen_addr(addr)
+    }
+
+    fn inject_new_external_addr(&mut self, addr: &Multiaddr) {
+        self.behaviour.inject_new_external_addr(addr)
+    }
+
+    fn inject_listener_error(
+        &mut self,
+        id: ListenerId,
+        err: &(dyn error::Error + 'static),
+    ) {
+        self.behaviour.inject_listener_error(id, err)
+    }
+
+    fn inject_listener_closed(
+        &mut self,
+        id: ListenerId,
+        reason: Result<(), &io::Error>,
+    ) {
+        self.behaviour.inject_listener_closed(id, reason)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<HandlerIn<B>, ExtensionEvent>> {
+        use NetworkBehaviourAction::*;
+        loop {
+            let action = match self.behaviour.poll(cx, params) {
+                Poll::Ready(GenerateEvent(event)) => {
+                    match (self.events)(event) {
+                        Some(event) => GenerateEvent(event),
+                        // The behaviour may have more to do
+                        None => continue,
+                    }
+                }
+                Poll::Ready(DialAddress { address }) => {
+                    DialAddress { address }
+                }
+                Poll::Ready(DialPeer { peer_id, condition }) => {
+                    DialPeer { peer_id, condition }
+                }
+                Poll::Ready(NotifyHandler {
+                    peer_id,
+                    handler,
+                    event,
+                }) => NotifyHandler {
+                    peer_id,
+                    handler,
+                    event,
+                },
+                Poll::Ready(ReportObservedAddr { address }) => {
+                    ReportObservedAddr { address }
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+            return Poll::Ready(action);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use crate::{chaos, seed};
+use libp2p::kad::{handler::KademliaHandlerIn, QueryId};
+use rand::Rng;
+use std::{fmt, str::FromStr};
+
+// Which replies to kademlia requests to get wrong on purpose, like
+// `put-reply-drop=0.2,get-reply-corrupt=0.1`. Unlike --chaos, which
+// breaks whole connections, this hits single operations, so the other
+// side sees a peer that is up but doesn't answer, or answers wrong.
+//
+// - put-reply-drop: the chance that a PUT we stored isn't acknowledged
+// - get-reply-drop: the chance that a GET isn't answered
+// - get-reply-corrupt: the chance that the record a GET finds here has
+//   its value garbled on the way out
+//
+// Dropped replies have their substream reset, as if the reply got lost.
+// (A PUT reply only echoes the record, which the peer that sent it
+// doesn't look at, so there is nothing to corrupt in it.)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FaultConfig {
+    pub put_reply_drop: f64,
+    pub get_reply_drop: f64,
+    pub get_reply_corrupt: f64,
+}
+
+// What to do to a reply.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    Drop,
+    Corrupt,
+}
+
+impl FaultConfig {
+    // Roll the dice for `reply`, which is on its way to a peer.
+    pub fn pick(
+        &self,
+        reply: &KademliaHandlerIn<QueryId>,
+    ) -> Option<Fault> {
+        match reply {
+            KademliaHandlerIn::PutRecordRes { .. } => Some(Fault::Drop)
+                .filter(|_| chaos::happens(self.put_reply_drop)),
+            KademliaHandlerIn::GetRecordRes { record, .. } => {
+                if chaos::happens(self.get_reply_drop) {
+                    Some(Fault::Drop)
+                } else if record.is_some()
+                    && chaos::happens(self.get_reply_corrupt)
+                {
+                    Some(Fault::Corrupt)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+// Garble the value of the record in a GET reply.
+pub fn corrupt(reply: &mut KademliaHandlerIn<QueryId>) {
+    if let KademliaHandlerIn::GetRecordRes {
+        record: Some(record),
+        ..
+    } = reply
+    {
+        garble(&mut record.value);
+    }
+}
+
+// Flip the bits of a byte of `value` (or make up one, if it is empty).
+pub fn garble(value: &mut Vec<u8>) {
+    if value.is_empty() {
+        value.push(seed::rng().gen());
+    } else {
+        let i = seed::rng().gen_range(0, value.len());
+        value[i] ^= seed::rng().gen_range(1, u8::MAX);
+    }
+}
+
+impl FromStr for FaultConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = FaultConfig::default();
+        for part in s.split(',').filter(|part| !part.is_empty()) {
+            let (name, value) = part.split_once('=').ok_or_else(|| {
+                format!("expected name=value, got {:?}", part)
+            })?;
+            let rate = chaos::parse_rate(value)?;
+            match name {
+                "put-reply-drop" => config.put_reply_drop = rate,
+                "get-reply-drop" => config.get_reply_drop = rate,
+                "get-reply-corrupt" => config.get_reply_corrupt = rate,
+                _ => return Err(format!("unknown fault {:?}", name)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for FaultConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "put-reply-drop={},get-reply-drop={},get-reply-corrupt={}",
+            self.put_reply_drop,
+            self.get_reply_drop,
+            self.get_reply_corrupt
+        )
+    }
+}
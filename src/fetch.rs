@@ -0,0 +1,133 @@
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::{
+        upgrade::{read_one, write_one},
+        ProtocolName,
+    },
+    kad::record::Key,
+    request_response::{
+        ProtocolSupport, RequestResponse, RequestResponseCodec,
+        RequestResponseConfig,
+    },
+};
+use std::{io, iter, time::Duration};
+
+// The biggest key a peer may ask for.
+const MAX_KEY_SIZE: usize = 64 * 1024;
+
+// The biggest value we take from a peer.
+const MAX_VALUE_SIZE: usize = 64 * 1024 * 1024;
+
+// How long a fetch may take (big values on slow links take a while).
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The direct transfer protocol: ask a peer for the value it has stored
+// under a key, and get the whole value back over one stream, instead of
+// through dht records.
+pub type Fetch = RequestResponse<FetchCodec>;
+
+// Connections stay open for `idle_timeout` after the last fetch (or the
+// default 10 seconds).
+pub fn new(idle_timeout: Option<Duration>) -> Fetch {
+    let mut config = RequestResponseConfig::default();
+    config.set_request_timeout(FETCH_TIMEOUT);
+    if let Some(timeout) = idle_timeout {
+        config.set_connection_keep_alive(timeout);
+    }
+    RequestResponse::new(
+        FetchCodec,
+        iter::once((FetchProtocol, ProtocolSupport::Full)),
+        config,
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct FetchProtocol;
+
+impl ProtocolName for FetchProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/nettest/fetch/1.0"
+    }
+}
+
+// A request is the key, and a response is a flag byte (1 if the peer has
+// the value) followed by the value, each with a length prefix.
+#[derive(Debug, Clone)]
+pub struct FetchCodec;
+
+#[async_trait]
+impl RequestResponseCodec for FetchCodec {
+    type Protocol = FetchProtocol;
+    type Request = Key;
+    type Response = Option<Vec<u8>>;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &FetchProtocol,
+        io: &mut T,
+    ) -> io::Result<Key>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let key = read_one(io, MAX_KEY_SIZE).await.map_err(invalid)?;
+        Ok(Key::new(&key))
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &FetchProtocol,
+        io: &mut T,
+    ) -> io::Result<Option<Vec<u8>>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut response =
+            read_one(io, MAX_VALUE_SIZE + 1).await.map_err(invalid)?;
+        match response.first() {
+            Some(1) => {
+                response.remove(0);
+                Ok(Some(response))
+            }
+            Some(0) => Ok(None),
+            _ => Err(invalid("malformed fetch response")),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &FetchProtocol,
+        io: &mut T,
+        key: Key,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_one(io, key.as_ref()).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &FetchProtocol,
+        io: &mut T,
+        value: Option<Vec<u8>>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let response = match value {
+            Some(mut value) => {
+                value.insert(0, 1);
+                value
+            }
+            None => vec![0],
+        };
+        write_one(io, response).await
+    }
+}
+
+fn invalid(
+    err: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
@@ -0,0 +1,452 @@
+use crate::{bandwidth::format_bytes, output::Output};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::{
+        upgrade::{read_one, write_one},
+        ProtocolName,
+    },
+    request_response::{
+        OutboundFailure, ProtocolSupport, RequestId, RequestResponse,
+        RequestResponseCodec, RequestResponseConfig,
+    },
+    PeerId,
+};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fs::{self, File},
+    io, iter,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+// How much of a file goes into one request.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+// How many chunks of a file may be on their way (each over its own
+// substream) at once.
+const WINDOW: usize = 4;
+
+// How long a peer gets to take a chunk.
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The file transfer protocol: SENDFILE streams a file to a peer, a chunk
+// at a time, and the peer saves it into its --receive-dir (or refuses it,
+// without one). Every chunk is acknowledged, which is what the progress
+// and throughput reports go by.
+pub type FileTransfer = RequestResponse<FileCodec>;
+
+// Connections stay open for `idle_timeout` after the last chunk (or the
+// default 10 seconds).
+pub fn new(idle_timeout: Option<Duration>) -> FileTransfer {
+    let mut config = RequestResponseConfig::default();
+    config.set_request_timeout(CHUNK_TIMEOUT);
+    if let Some(timeout) = idle_timeout {
+        config.set_connection_keep_alive(timeout);
+    }
+    RequestResponse::new(
+        FileCodec,
+        iter::once((FileProtocol, ProtocolSupport::Full)),
+        config,
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct FileProtocol;
+
+impl ProtocolName for FileProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/nettest/file/1.0"
+    }
+}
+
+// A piece of a file: the transfer it belongs to (numbered by the sender),
+// the name and size of the whole file, and where the data goes in it.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub transfer: u64,
+    pub name: String,
+    pub total: u64,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+// The answer to a chunk: it was saved, or why it wasn't.
+pub type ChunkAck = Result<(), String>;
+
+// A chunk is the transfer, the size and the offset (8 bytes each, big
+// endian), the length of the name (2 bytes), the name and the data, in
+// one frame. An acknowledgement is an empty frame, or the reason the
+// chunk was refused.
+#[derive(Debug, Clone)]
+pub struct FileCodec;
+
+#[async_trait]
+impl RequestResponseCodec for FileCodec {
+    type Protocol = FileProtocol;
+    type Request = Chunk;
+    type Response = ChunkAck;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &FileProtocol,
+        io: &mut T,
+    ) -> io::Result<Chunk>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let frame = read_one(io, CHUNK_SIZE + 64 * 1024)
+            .await
+            .map_err(invalid)?;
+        parse_chunk(&frame).ok_or_else(|| invalid("malformed chunk"))
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &FileProtocol,
+        io: &mut T,
+    ) -> io::Result<ChunkAck>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let frame = read_one(io, 1024).await.map_err(invalid)?;
+        match frame.is_empty() {
+            true => Ok(Ok(())),
+            false => Ok(Err(String::from_utf8_lossy(&frame).into_owned())),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &FileProtocol,
+        io: &mut T,
+        chunk: Chunk,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let mut frame = Vec::with_capacity(26 + chunk.name.len());
+        frame.extend_from_slice(&chunk.transfer.to_be_bytes());
+        frame.extend_from_slice(&chunk.total.to_be_bytes());
+        frame.extend_from_slice(&chunk.offset.to_be_bytes());
+        frame.extend_from_slice(&(chunk.name.len() as u16).to_be_bytes());
+        frame.extend_from_slice(chunk.name.as_bytes());
+        frame.extend_from_slice(&chunk.data);
+        write_one(io, frame).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &FileProtocol,
+        io: &mut T,
+        ack: ChunkAck,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_one(io, ack.err().unwrap_or_default()).await
+    }
+}
+
+fn parse_chunk(frame: &[u8]) -> Option<Chunk> {
+    let number = |at: usize| -> Option<u64> {
+        Some(u64::from_be_bytes(frame.get(at..at + 8)?.try_into().ok()?))
+    };
+    let name_len =
+        u16::from_be_bytes(frame.get(24..26)?.try_into().ok()?) as usize;
+    let name = std::str::from_utf8(frame.get(26..26 + name_len)?).ok()?;
+    Some(Chunk {
+        transfer: number(0)?,
+        total: number(8)?,
+        offset: number(16)?,
+        name: name.to_string(),
+        data: frame[26 + name_len..].to_vec(),
+    })
+}
+
+// A file we are sending.
+#[derive(Debug)]
+struct Outgoing {
+    peer: PeerId,
+    file: File,
+    name: String,
+    total: u64,
+    // Where the next chunk starts, whether that's the end, and how much
+    // the peer has
+    next_offset: u64,
+    read_all: bool,
+    acked: u64,
+    in_flight: usize,
+    started: Instant,
+    // The last quarter that was reported
+    reported: u64,
+    output: Output,
+}
+
+// A file a peer is sending us.
+#[derive(Debug)]
+struct Incoming {
+    path: PathBuf,
+    file: File,
+    total: u64,
+    received: u64,
+    started: Instant,
+}
+
+// The files on their way, in both directions.
+#[derive(Debug, Default)]
+pub struct FileTransfers {
+    // Where received files go (nowhere, without one)
+    receive_dir: Option<PathBuf>,
+    next_transfer: u64,
+    outgoing: HashMap<u64, Outgoing>,
+    // The transfer each chunk we sent belongs to, and its size
+    requests: HashMap<RequestId, (u64, u64)>,
+    incoming: HashMap<(PeerId, u64), Incoming>,
+}
+
+impl FileTransfers {
+    pub fn new(receive_dir: Option<PathBuf>) -> Self {
+        FileTransfers {
+            receive_dir,
+            ..FileTransfers::default()
+        }
+    }
+
+    // Start sending the file at `path` to `peer` (for SENDFILE).
+    pub fn send(
+        &mut self,
+        protocol: &mut FileTransfer,
+        peer: PeerId,
+        path: &Path,
+        output: Output,
+    ) -> io::Result<()> {
+        let file = File::open(path)?;
+        let total = file.metadata()?.len();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "not a file")
+            })?;
+        output.info(format!(
+            "file: sending {} ({}) to {}",
+            name,
+            format_bytes(total),
+            peer
+        ));
+        let transfer = self.next_transfer;
+        self.next_transfer += 1;
+        self.outgoing.insert(
+            transfer,
+            Outgoing {
+                peer,
+                file,
+                name,
+                total,
+                next_offset: 0,
+                read_all: false,
+                acked: 0,
+                in_flight: 0,
+                started: Instant::now(),
+                reported: 0,
+                output,
+            },
+        );
+        self.pump(protocol, transfer);
+        Ok(())
+    }
+
+    // Send the next chunks of a transfer, as far as the window goes.
+    fn pump(&mut self, protocol: &mut FileTransfer, transfer: u64) {
+        let outgoing = match self.outgoing.get_mut(&transfer) {
+            Some(outgoing) => outgoing,
+            None => return,
+        };
+        // An empty file still takes one (empty) chunk
+        while outgoing.in_flight < WINDOW && !outgoing.read_all {
+            let len = (outgoing.total - outgoing.next_offset)
+                .min(CHUNK_SIZE as u64);
+            let mut data = vec![0; len as usize];
+            if let Err(err) = outgoing
+                .file
+                .read_exact_at(&mut data, outgoing.next_offset)
+            {
+                outgoing.output.error(format!(
+                    "file: failed to read {}: {}",
+                    outgoing.name, err
+                ));
+                self.forget(transfer);
+                return;
+            }
+            let chunk = Chunk {
+                transfer,
+                name: outgoing.name.clone(),
+                total: outgoing.total,
+                offset: outgoing.next_offset,
+                data,
+            };
+            let id = protocol.send_request(&outgoing.peer, chunk);
+            self.requests.insert(id, (transfer, len));
+            outgoing.next_offset += len;
+            outgoing.in_flight += 1;
+            outgoing.read_all = outgoing.next_offset >= outgoing.total;
+        }
+    }
+
+    // The peer answered one of our chunks.
+    pub fn acked(
+        &mut self,
+        protocol: &mut FileTransfer,
+        request: &RequestId,
+        ack: ChunkAck,
+    ) {
+        let (transfer, len) = match self.requests.remove(request) {
+            Some(sent) => sent,
+            None => return,
+        };
+        let outgoing = match self.outgoing.get_mut(&transfer) {
+            Some(outgoing) => outgoing,
+            None => return,
+        };
+        if let Err(why) = ack {
+            outgoing.output.error(format!(
+                "file: {} refused {}: {}",
+                outgoing.peer, outgoing.name, why
+            ));
+            self.forget(transfer);
+            return;
+        }
+        outgoing.in_flight -= 1;
+        outgoing.acked += len;
+        let elapsed = outgoing.started.elapsed();
+        let rate = format!(
+            "{}/s",
+            format_bytes(
+                (outgoing.acked as f64 / elapsed.as_secs_f64().max(1e-3))
+                    as u64
+            )
+        );
+        if outgoing.read_all && outgoing.in_flight == 0 {
+            outgoing.output.info(format!(
+                "file: sent {} ({}) to {} in {:.1?}, {}",
+                outgoing.name,
+                format_bytes(outgoing.total),
+                outgoing.peer,
+                elapsed,
+                rate
+            ));
+            self.outgoing.remove(&transfer);
+            return;
+        }
+        let quarter = outgoing.acked * 4 / outgoing.total;
+        if quarter > outgoing.reported {
+            outgoing.reported = quarter;
+            outgoing.output.info(format!(
+                "file: sent {}% of {}, {}",
+                quarter * 25,
+                outgoing.name,
+                rate
+            ));
+        }
+        self.pump(protocol, transfer);
+    }
+
+    // One of our chunks didn't make it, which ends the transfer.
+    pub fn failed(
+        &mut self,
+        request: &RequestId,
+        error: &OutboundFailure,
+    ) {
+        let transfer = match self.requests.remove(request) {
+            Some((transfer, _)) => transfer,
+            None => return,
+        };
+        if let Some(outgoing) = self.outgoing.get(&transfer) {
+            outgoing.output.error(format!(
+                "file: failed to send {} to {}: {:?}",
+                outgoing.name, outgoing.peer, error
+            ));
+        }
+        self.forget(transfer);
+    }
+
+    // Give up on a transfer (the chunks still on their way don't matter
+    // anymore).
+    fn forget(&mut self, transfer: u64) {
+        self.outgoing.remove(&transfer);
+        self.requests.retain(|_, (t, _)| *t != transfer);
+    }
+
+    // Save a chunk that a peer sent us.
+    pub fn receive(&mut self, peer: &PeerId, chunk: Chunk) -> ChunkAck {
+        let dir = self
+            .receive_dir
+            .as_ref()
+            .ok_or("this node doesn't take files")?;
+        let key = (peer.clone(), chunk.transfer);
+        if !self.incoming.contains_key(&key) {
+            // Only the name of the file, whatever path the peer put in
+            let name = Path::new(&chunk.name)
+                .file_name()
+                .ok_or("invalid file name")?;
+            let path = dir.join(name);
+            fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+            let file =
+                File::create(&path).map_err(|err| err.to_string())?;
+            Output::Terminal.info(format!(
+                "file: receiving {} ({}) from {}",
+                path.display(),
+                format_bytes(chunk.total),
+                peer
+            ));
+            self.incoming.insert(
+                key.clone(),
+                Incoming {
+                    path,
+                    file,
+                    total: chunk.total,
+                    received: 0,
+                    started: Instant::now(),
+                },
+            );
+        }
+        let incoming = self.incoming.get_mut(&key).expect("just inserted");
+        if chunk.offset + chunk.data.len() as u64 > incoming.total {
+            self.incoming.remove(&key);
+            return Err("the chunk goes past the end of the file".into());
+        }
+        if let Err(err) =
+            incoming.file.write_all_at(&chunk.data, chunk.offset)
+        {
+            self.incoming.remove(&key);
+            return Err(err.to_string());
+        }
+        incoming.received += chunk.data.len() as u64;
+        if incoming.received >= incoming.total {
+            let elapsed = incoming.started.elapsed();
+            Output::Terminal.info(format!(
+                "file: received {} ({}) from {} in {:.1?}, {}/s",
+                incoming.path.display(),
+                format_bytes(incoming.total),
+                peer,
+                elapsed,
+                format_bytes(
+                    (incoming.total as f64
+                        / elapsed.as_secs_f64().max(1e-3))
+                        as u64
+                )
+            ));
+            self.incoming.remove(&key);
+        }
+        Ok(())
+    }
+}
+
+fn invalid(
+    err: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
@@ -0,0 +1,141 @@
+use ipnet::IpNet;
+use libp2p::{
+    core::{multiaddr::Protocol, ConnectedPoint},
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+// A single allow or deny entry. An entry either names a specific peer,
+// or a range of IP addresses (a CIDR like `10.0.0.0/8`, or a single IP).
+#[derive(Debug, Clone)]
+pub enum FilterRule {
+    Peer(PeerId),
+    Net(IpNet),
+}
+
+impl FromStr for FilterRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(net) = s.parse::<IpNet>() {
+            return Ok(FilterRule::Net(net));
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(FilterRule::Net(IpNet::from(ip)));
+        }
+        match s.parse::<PeerId>() {
+            Ok(peer_id) => Ok(FilterRule::Peer(peer_id)),
+            Err(_) => Err(format!("{:?} is not a peer id or a CIDR", s)),
+        }
+    }
+}
+
+// Decides which peers this node is willing to talk to.
+//
+// A peer is allowed if it is not denied (neither by its id nor by the IP
+// it connects from), and, when an allowlist has been configured, if it
+// matches at least one allowlist entry.
+#[derive(Debug, Default)]
+pub struct PeerFilter {
+    allow_peers: HashSet<PeerId>,
+    allow_nets: Vec<IpNet>,
+    deny_peers: HashSet<PeerId>,
+    deny_nets: Vec<IpNet>,
+}
+
+// The filter is shared between the transport (which checks every new
+// connection) and the behaviour (which checks discovered addresses and
+// handles BAN), so it lives behind a lock.
+pub type SharedFilter = Arc<RwLock<PeerFilter>>;
+
+impl PeerFilter {
+    pub fn new(allow: &[FilterRule], deny: &[FilterRule]) -> Self {
+        let mut filter = PeerFilter::default();
+        for rule in allow {
+            match rule {
+                FilterRule::Peer(peer_id) => {
+                    filter.allow_peers.insert(peer_id.clone());
+                }
+                FilterRule::Net(net) => filter.allow_nets.push(*net),
+            }
+        }
+        for rule in deny {
+            match rule {
+                FilterRule::Peer(peer_id) => {
+                    filter.deny_peers.insert(peer_id.clone());
+                }
+                FilterRule::Net(net) => filter.deny_nets.push(*net),
+            }
+        }
+        filter
+    }
+
+    // Put the filter behind a lock so that it can be shared.
+    pub fn shared(self) -> SharedFilter {
+        Arc::new(RwLock::new(self))
+    }
+
+    // Block a peer from now on.
+    pub fn ban(&mut self, peer_id: PeerId) {
+        self.allow_peers.remove(&peer_id);
+        self.deny_peers.insert(peer_id);
+    }
+
+    // Whether a peer is blocked by its id.
+    pub fn denies(&self, peer_id: &PeerId) -> bool {
+        self.deny_peers.contains(peer_id)
+    }
+
+    // Stop blocking a peer.
+    pub fn unban(&mut self, peer_id: &PeerId) {
+        self.deny_peers.remove(peer_id);
+    }
+
+    // Check whether a peer, reachable at the given address, is allowed.
+    pub fn allows(&self, peer_id: &PeerId, addr: &Multiaddr) -> bool {
+        let ip = ip_of(addr);
+        let in_nets = |nets: &[IpNet]| {
+            ip.is_some_and(|ip| nets.iter().any(|net| net.contains(&ip)))
+        };
+
+        if self.deny_peers.contains(peer_id) || in_nets(&self.deny_nets) {
+            return false;
+        }
+
+        // Without an allowlist, everyone that isn't denied is allowed
+        if self.allow_peers.is_empty() && self.allow_nets.is_empty() {
+            return true;
+        }
+
+        self.allow_peers.contains(peer_id) || in_nets(&self.allow_nets)
+    }
+
+    // Check a freshly established connection.
+    pub fn allows_connection(
+        &self,
+        peer_id: &PeerId,
+        endpoint: &ConnectedPoint,
+    ) -> bool {
+        let addr = match endpoint {
+            ConnectedPoint::Dialer { address } => address,
+            ConnectedPoint::Listener { send_back_addr, .. } => {
+                send_back_addr
+            }
+        };
+        self.allows(peer_id, addr)
+    }
+}
+
+// Get the IP address out of a multiaddr (if it has one).
+fn ip_of(addr: &Multiaddr) -> Option<IpAddr> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    })
+}
@@ -0,0 +1,106 @@
+use crate::{api::ApiRequest, output::Output};
+use async_std::{
+    channel::{self, Receiver, Sender},
+    io, task,
+};
+use futures::{stream::BoxStream, StreamExt};
+use std::{
+    fmt,
+    task::{Context, Poll},
+};
+
+// How many submissions may wait for the swarm task, unless
+// --inbox-capacity says otherwise.
+pub const DEFAULT_CAPACITY: usize = 256;
+
+// Something a front-end hands to the swarm task.
+#[derive(Debug)]
+pub enum Submission {
+    // A command line, and where its output goes (the terminal, or a
+    // client of the control socket)
+    Command(String, Output),
+    // A request of the HTTP api, or of /rpc
+    Api(ApiRequest),
+}
+
+// The swarm task is gone (the node stopped), so nothing it was handed
+// will run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stopped;
+
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the node stopped")
+    }
+}
+
+impl std::error::Error for Stopped {}
+
+// How the front-ends (the terminal, the control socket, the HTTP api,
+// /rpc and LOAD) get the node to do anything: the swarm task owns the swarm,
+// and with it kademlia, and is the only one that touches it. Everyone
+// else submits commands and requests through a handle, which any number
+// of tasks can clone. There is room for so many submissions
+// (--inbox-capacity); once they are all taken, a submission waits for
+// the swarm task to catch up, rather than piling up.
+#[derive(Debug, Clone)]
+pub struct NodeHandle(Sender<Submission>);
+
+// Where the swarm task takes the submissions from.
+#[derive(Debug)]
+pub struct Inbox(Receiver<Submission>);
+
+impl NodeHandle {
+    pub fn new(capacity: usize) -> (NodeHandle, Inbox) {
+        let (tx, rx) = channel::bounded(capacity.max(1));
+        (NodeHandle(tx), Inbox(rx))
+    }
+
+    // Run the command `line`, with its output going to `output`. Waits
+    // while the inbox is full.
+    pub async fn command(
+        &self,
+        line: String,
+        output: Output,
+    ) -> Result<(), Stopped> {
+        self.submit(Submission::Command(line, output)).await
+    }
+
+    // The same, for a request of the api.
+    pub async fn api(&self, request: ApiRequest) -> Result<(), Stopped> {
+        self.submit(Submission::Api(request)).await
+    }
+
+    async fn submit(&self, submission: Submission) -> Result<(), Stopped> {
+        self.0.send(submission).await.map_err(|_| Stopped)
+    }
+}
+
+impl Inbox {
+    // The next submission, if there is one. `None` once every handle is
+    // gone.
+    pub fn poll_next(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Submission>> {
+        self.0.poll_next_unpin(cx)
+    }
+}
+
+// Submit the command lines of `lines` (stdin, or the prompt of the
+// dashboard) through `handle`, one after the other, until there are no
+// more lines (or the node stops). Returns the error that ended them, if
+// it was one.
+pub fn forward_lines(
+    mut lines: BoxStream<'static, io::Result<String>>,
+    handle: NodeHandle,
+) -> task::JoinHandle<io::Result<()>> {
+    task::spawn(async move {
+        while let Some(line) = lines.next().await {
+            if handle.command(line?, Output::Terminal).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
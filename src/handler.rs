@@ -1,58 +1,1007 @@
+use crate::{
+    addrbook, analyze,
+    bandwidth::format_bytes,
+    batch,
+    behaviour::MyBehavior,
+    cas::{self, Swap},
+    coalesce::Waiter,
+    command::{Cancel, Command, LoadAction, Target},
+    input::{self, InputFormat},
+    namespace::Namespace,
+    output::{Message, Output},
+    peerinfo, rendezvous, score, snapshot, timing, topology,
+};
 use libp2p::{
-    identity,
-    kad::{
-        record::{store::MemoryStore, Key},
-        Kademlia, Quorum, Record,
-    },
+    kad::{QueryId, Quorum, K_VALUE},
+    multiaddr::Protocol,
+    Multiaddr, Swarm,
 };
+use std::time::{Duration, Instant};
 
+// Parse `line` (as text, or as JSON with --input json) and run the
+// command (see `run`), or tell `output` why it isn't one. With `TIME ` in
+// front of it (`"time": true` in JSON, or with --timing), also tell how
+// long it took, once its query (if it started one) is done.
 pub fn handle_input_line(
-    kademlia: &mut Kademlia<MemoryStore>,
+    swarm: &mut Swarm<MyBehavior>,
     line: String,
+    output: Output,
 ) {
-    let mut args = line.split(" ");
-    match args.next() {
-        Some("GET") => {
-            let key = match args.next() {
-                Some(key) => Key::new(&key),
+    let started = Instant::now();
+    let (timed, line, command) = match swarm.input {
+        InputFormat::Text => {
+            let (timed, line) = match line.strip_prefix("TIME ") {
+                Some(command) => (true, command.to_string()),
+                None => (swarm.timing, line),
+            };
+            let command =
+                swarm.aliases.parse(&line).map_err(|err| err.to_string());
+            (timed, line, command)
+        }
+        InputFormat::Json => match input::parse_json(&line) {
+            Ok((command, timed)) => {
+                (timed || swarm.timing, line, Ok(command))
+            }
+            Err(err) => (false, line, Err(err)),
+        },
+    };
+    let mut outcome = match command {
+        Ok(command) => run(swarm, command, output.clone()),
+        Err(err) => CommandOutcome::failed(err),
+    };
+    if timed && !outcome.is_error() {
+        match outcome.query {
+            Some(id) => swarm.timings.start(
+                id,
+                line.clone(),
+                started,
+                output.clone(),
+            ),
+            None => outcome.info(timing::report(
+                &line,
+                started.elapsed(),
+                None,
+            )),
+        }
+    }
+    // Number the query, so that it can be cancelled
+    if let Some(id) = outcome.query {
+        swarm.in_flight.add(id, line);
+    }
+    output.show_all(outcome.messages);
+}
+
+// What running a command did right away: the query it started, if it
+// started one, and what it has to say about it. (The results of queries
+// come later, and go to the `output` that was passed to `run`.)
+#[derive(Debug, Default)]
+pub struct CommandOutcome {
+    pub query: Option<QueryId>,
+    pub messages: Vec<Message>,
+}
+
+impl CommandOutcome {
+    fn failed(message: impl Into<String>) -> Self {
+        let mut outcome = CommandOutcome::default();
+        outcome.error(message);
+        outcome
+    }
+
+    fn info(&mut self, message: impl Into<String>) {
+        self.messages.push(Message::Info(message.into()));
+    }
+
+    fn error(&mut self, message: impl Into<String>) {
+        self.messages.push(Message::Error(message.into()));
+    }
+
+    // Whether the command went wrong before it got anywhere.
+    pub fn is_error(&self) -> bool {
+        self.messages
+            .iter()
+            .any(|message| matches!(message, Message::Error(_)))
+    }
+}
+
+// Run `command`, without showing anything: the front end shows the
+// outcome the way it likes. The results of the queries the command
+// starts go to `output`.
+pub fn run(
+    swarm: &mut Swarm<MyBehavior>,
+    command: Command,
+    output: Output,
+) -> CommandOutcome {
+    let mut outcome = CommandOutcome::default();
+    match command {
+        Command::Get {
+            key,
+            quorum,
+            timeout,
+        } => {
+            let key = swarm.namespace.key(&key);
+            // The cache (of --cache-size) can only stand for one answer
+            if quorum == Quorum::One {
+                if let Some(messages) = swarm.get_cached(&key) {
+                    outcome.messages.extend(messages);
+                    return outcome;
+                }
+            }
+            // A GET of the same key that is running already does for
+            // this one too (with --coalesce-gets), unless this one has a
+            // deadline of its own
+            let running = match timeout {
+                Some(_) => None,
                 None => {
-                    eprintln!("expected a key");
-                    return;
+                    swarm.coalescer.running(Waiter::Command, &key, quorum)
                 }
             };
+            if let Some(id) =
+                running.filter(|id| swarm.pending.contains_key(id))
+            {
+                let running = swarm.pending.remove(&id).unwrap();
+                let output =
+                    swarm.coalescer.join_output(id, running, output);
+                swarm.pending.insert(id, output);
+                swarm.stats.gets_coalesced += 1;
+                outcome.query = Some(id);
+                return outcome;
+            }
+            let id = swarm.kademlia.get_record(&key, quorum);
+            swarm.stats.gets_issued += 1;
 
-            kademlia.get_record(&key, Quorum::One);
+            // Remember who asked, so that the result goes back to them
+            swarm.pending.insert(id, output);
+            match timeout {
+                Some(timeout) => swarm.deadlines.add(id, key, timeout),
+                None => swarm.coalescer.started(
+                    Waiter::Command,
+                    key,
+                    quorum,
+                    id,
+                ),
+            }
+            outcome.query = Some(id);
         }
-        Some("PUT") => {
-            let key = match args.next() {
-                Some(key) => Key::new(&key),
-                None => {
-                    eprintln!("Expected a key");
-                    return;
+        Command::Put {
+            key,
+            value,
+            compress,
+            quorum,
+        } => {
+            let key = swarm.namespace.key(&key);
+            let compress = compress.or(swarm.codec.compress);
+            let version = swarm.next_version(&key);
+            outcome.query = swarm
+                .put_value(key, value, compress, version, quorum, output);
+        }
+        Command::PutCas {
+            value,
+            compress,
+            quorum,
+        } => {
+            // The key is the hash of the value, so that whoever GETs it
+            // can tell whether they got the right value
+            let key = cas::key_for(&value);
+            outcome.info(format!("Content key: {}", key));
+            let key = swarm.namespace.key(key);
+            let compress = compress.or(swarm.codec.compress);
+            outcome.query = swarm
+                .put_value(key, value, compress, None, quorum, output);
+        }
+        Command::Cas {
+            key,
+            expected,
+            value,
+            compress,
+            quorum,
+        } => {
+            let key = swarm.namespace.key(&key);
+            let compress = compress.or(swarm.codec.compress);
+            outcome.query = Some(swarm.compare_and_swap(Swap {
+                key,
+                expected,
+                value,
+                compress,
+                quorum,
+                output,
+            }));
+        }
+        Command::MGet(keys) => {
+            let batch = swarm.batches.start("MGET", output);
+            for name in keys {
+                let key = swarm.namespace.key(&name);
+                let id = swarm.kademlia.get_record(&key, Quorum::One);
+                swarm.stats.gets_issued += 1;
+                swarm.batches.add(batch, name, id);
+            }
+            swarm.batches.close(batch);
+        }
+        Command::MPut(path) => {
+            let records = match batch::read_records(&path) {
+                Ok(records) => records,
+                Err(err) => {
+                    outcome.error(format!(
+                        "Couldn't read {}: {}",
+                        path.display(),
+                        err
+                    ));
+                    return outcome;
                 }
             };
+            outcome.info(format!(
+                "Storing {} records from {}",
+                records.len(),
+                path.display()
+            ));
+            let batch = swarm.batches.start("MPUT", output.clone());
+            for (name, value) in records {
+                let key = swarm.namespace.key(&name);
+                let compress = swarm.codec.compress;
+                let version = swarm.next_version(&key);
+                match swarm.put_value(
+                    key,
+                    value.into_bytes(),
+                    compress,
+                    version,
+                    Quorum::One,
+                    output.clone(),
+                ) {
+                    Some(id) => swarm.batches.add(batch, name, id),
+                    None => swarm.batches.skip(
+                        batch,
+                        name,
+                        "not stored as a single record (see above)".into(),
+                    ),
+                }
+            }
+            swarm.batches.close(batch);
+        }
+        Command::Fetch { key, peer } => {
+            let key = swarm.namespace.key(&key);
 
-            let value = match args.next() {
-                Some(value) => value.as_bytes().to_vec(),
+            // Ask the peer for the value directly (it is found through
+            // the dht's routing table, like any peer)
+            let id = swarm.fetch.send_request(&peer, key.clone());
+            swarm.fetching.insert(id, (key, output));
+        }
+        Command::Register { namespace, ttl } => {
+            // The announced addresses, if there are any, since the
+            // others are likely unreachable from outside
+            let mut addrs: Vec<Multiaddr> =
+                Swarm::external_addresses(swarm).cloned().collect();
+            if addrs.is_empty() {
+                addrs = listen_addrs(swarm);
+            }
+            if addrs.is_empty() {
+                outcome.error("Not listening on any address yet");
+                return outcome;
+            }
+            let request = rendezvous::Request::Register {
+                namespace,
+                addrs,
+                ttl,
+            };
+            if let Err(err) = send_rendezvous(swarm, request, output) {
+                outcome.error(err);
+            }
+        }
+        Command::Discover { namespace } => {
+            let request = rendezvous::Request::Discover { namespace };
+            if let Err(err) = send_rendezvous(swarm, request, output) {
+                outcome.error(err);
+            }
+        }
+        Command::Watch { key, interval } => {
+            let key = swarm.namespace.key(&key);
+
+            // Look the key up in the background, and report the changes
+            // to whoever is watching (until they UNWATCH the key, or go
+            // away)
+            let name = swarm.namespace.display(&key);
+            outcome
+                .info(format!("Watching {:?} every {:?}", name, interval));
+            swarm.watches.watch(key, name, interval, output);
+        }
+        Command::WatchProviders { key, interval } => {
+            let key = swarm.namespace.key(&key);
+
+            // Like WATCH, but the value is only fetched once a newer
+            // version is announced (by writers with --announce-updates)
+            let name = swarm.namespace.display(&key);
+            outcome.info(format!(
+                "Watching the announced versions of {:?} every {:?}",
+                name, interval
+            ));
+            swarm.provider_watches.watch(key, name, interval, output);
+        }
+        Command::Unwatch { key } => {
+            let key = swarm.namespace.key(&key);
+            let name = swarm.namespace.display(&key);
+            let watched = swarm.watches.unwatch(&key);
+            match swarm.provider_watches.unwatch(&key) {
+                Some((polls, fetches)) => outcome.info(format!(
+                    "Stopped watching the versions of {:?} ({} lookups of \
+                     their providers, {} fetches)",
+                    name, polls, fetches
+                )),
+                None if watched => {
+                    outcome.info(format!("Stopped watching {:?}", name))
+                }
+                None => outcome.error(format!("Not watching {:?}", name)),
+            }
+        }
+        Command::Republish => {
+            // The same as what happens every --republish-interval
+            let count = swarm.republish();
+            outcome.info(format!("Republishing {} records", count));
+        }
+        Command::Audit => match &swarm.auditor {
+            Some(auditor) => {
+                let mut rounds = auditor.history().peekable();
+                if rounds.peek().is_none() {
+                    outcome.info("No audits yet");
+                }
+                for round in rounds {
+                    outcome.info(format!(
+                        "{:.0?} ago: {}",
+                        round.started.elapsed(),
+                        round
+                    ));
+                }
+            }
+            None => outcome.error("Not auditing (see --audit-interval)"),
+        },
+        // Define an alias
+        Command::Alias(Some(alias)) => {
+            outcome.info(format!("Alias {}", alias));
+            swarm.aliases.add(alias);
+        }
+        Command::Alias(None) => {
+            for alias in swarm.aliases.iter() {
+                outcome.info(alias.to_string());
+            }
+        }
+        // Switch namespaces
+        Command::Namespace(Some(prefix)) => {
+            swarm.namespace = Namespace::new(prefix);
+            match swarm.namespace.prefix() {
+                Some(prefix) => {
+                    outcome.info(format!("Using namespace {:?}", prefix));
+                    // Records outside of --namespaces have no quota
+                    if !swarm.namespaces.quotas().is_empty()
+                        && swarm.namespaces.get(prefix).is_none()
+                    {
+                        outcome.info(format!(
+                            "{:?} isn't one of the --namespaces",
+                            prefix
+                        ));
+                    }
+                }
+                None => outcome.info("Not using a namespace"),
+            }
+        }
+        Command::Namespace(None) => {
+            match swarm.namespace.prefix() {
+                Some(prefix) => {
+                    outcome.info(format!("Namespace: {:?}", prefix))
+                }
+                None => outcome.info("No namespace"),
+            }
+            for usage in swarm.namespace_usage() {
+                outcome.info(format!("  {}", usage));
+            }
+        }
+        Command::Peers { verbose } => {
+            // The peers in the routing table, and the ones that aren't
+            // (anymore) but misbehaved
+            let mut peers = swarm.known_peers();
+            let scored: Vec<_> = swarm
+                .scores
+                .iter()
+                .map(|(peer_id, _)| peer_id.clone())
+                .filter(|peer_id| {
+                    !peers.iter().any(|(known, _)| known == peer_id)
+                })
+                .map(|peer_id| (peer_id, Vec::new()))
+                .collect();
+            peers.extend(scored);
+            outcome.info(format!("{} peers", peers.len()));
+            for (peer_id, addrs) in peers {
+                let score = match swarm.scores.get(&peer_id) {
+                    Some(score) => score.to_string(),
+                    None => format!("score {}", score::MAX_SCORE),
+                };
+                // (Swarm::connection_info panics on peers whose
+                // connections are being closed)
+                let connected = if swarm.connections.is_connected(&peer_id)
+                {
+                    "connected"
+                } else {
+                    "not connected"
+                };
+                let addrs: Vec<String> =
+                    addrs.iter().map(|addr| addr.to_string()).collect();
+                let at = if addrs.is_empty() {
+                    String::new()
+                } else {
+                    format!(", at {}", addrs.join(", "))
+                };
+                outcome.info(format!(
+                    "{} {}, {}{}",
+                    peer_id, connected, score, at
+                ));
+                if !verbose {
+                    continue;
+                }
+                let latency = swarm
+                    .kademlia
+                    .latencies
+                    .get(&peer_id)
+                    .cloned()
+                    .unwrap_or_default();
+                outcome.info(format!("  ping: {}", latency.ping));
+                outcome.info(format!("  kademlia: {}", latency.rpc));
+                // Which stack the peer ended up with
+                if let Some(negotiated) = swarm.negotiations.get(&peer_id)
+                {
+                    outcome.info(format!("  negotiated: {}", negotiated));
+                }
+                if let Some(identified) =
+                    swarm.observed.identified(&peer_id)
+                {
+                    outcome.info(format!(
+                        "  identify: {} ({}), speaks {}",
+                        identified.agent,
+                        identified.protocol_version,
+                        identified.protocols.join(", ")
+                    ));
+                }
+            }
+            if verbose {
+                for ((security, muxer), peers) in
+                    swarm.negotiations.combinations()
+                {
+                    outcome.info(format!(
+                        "{} and {}: negotiated with {} peers so far",
+                        security, muxer, peers
+                    ));
+                }
+            }
+        }
+        Command::Bandwidth => {
+            let (inbound, outbound) = swarm.bandwidth.total();
+            outcome.info(format!(
+                "{} in, {} out",
+                format_bytes(inbound),
+                format_bytes(outbound)
+            ));
+            for (peer_id, inbound, outbound) in swarm.bandwidth.peers() {
+                outcome.info(format!(
+                    "{}: {} in, {} out",
+                    peer_id,
+                    format_bytes(inbound),
+                    format_bytes(outbound)
+                ));
+            }
+        }
+        Command::Ban(peer_id) => {
+            // Block the peer in the filter (so that new connections and
+            // discoveries are refused), forget about it in the dht, and
+            // have the swarm close any open connections to it.
+            swarm.filter.write().unwrap().ban(peer_id.clone());
+            swarm.kademlia.remove_peer(&peer_id);
+            Swarm::ban_peer_id(swarm, peer_id.clone());
+            outcome.info(format!("Banned peer {}", peer_id));
+        }
+        Command::Unban(peer_id) => {
+            // The peer may connect again, but it has to be found again
+            // (or added with ADD_ADDRESS) to be in the routing table
+            swarm.filter.write().unwrap().unban(&peer_id);
+            Swarm::unban_peer_id(swarm, peer_id.clone());
+            outcome.info(format!("Unbanned peer {}", peer_id));
+        }
+        Command::Routing(n) => {
+            for (at, change) in swarm.routing_log.latest(n) {
+                outcome.info(format!(
+                    "{:.0?} ago: {}",
+                    at.elapsed(),
+                    change
+                ));
+            }
+        }
+        Command::Buckets => {
+            // How full each bucket of the routing table is (the empty
+            // ones are left out)
+            let buckets = swarm.buckets();
+            let peers: usize = buckets.iter().map(|b| b.entries()).sum();
+            let connected: usize =
+                buckets.iter().map(|b| b.connected).sum();
+            let pending = buckets.iter().filter(|b| b.pending).count();
+            outcome.info(format!(
+                "{} buckets, {} peers ({} connected), {} pending",
+                buckets.len(),
+                peers,
+                connected,
+                pending
+            ));
+            for bucket in &buckets {
+                outcome.info(format!(
+                    "{:>3} {} {:>2}/{}{}",
+                    bucket.index,
+                    bucket.bar(K_VALUE.get()),
+                    bucket.entries(),
+                    K_VALUE,
+                    if bucket.pending { ", 1 pending" } else { "" }
+                ));
+            }
+        }
+        Command::Book(peer) => {
+            let entries = match &peer {
+                Some(peer) => match swarm.book.get(peer) {
+                    Some(entry) => vec![(peer, entry)],
+                    None => {
+                        outcome.error(format!(
+                            "Peer {} isn't in the address book",
+                            peer
+                        ));
+                        return outcome;
+                    }
+                },
                 None => {
-                    eprintln!("Expected value");
-                    return;
+                    outcome.info(format!(
+                        "{} peers in the address book",
+                        swarm.book.len()
+                    ));
+                    swarm.book.entries()
                 }
             };
+            for (peer_id, entry) in entries {
+                let when = |time: Option<u64>| match time {
+                    Some(time) => format!("{:?} ago", addrbook::ago(time)),
+                    None => "never".to_string(),
+                };
+                let addrs: Vec<String> = entry
+                    .addrs
+                    .iter()
+                    .map(|addr| addr.to_string())
+                    .collect();
+                let at = if addrs.is_empty() {
+                    ", no known addresses".to_string()
+                } else {
+                    format!(", at {}", addrs.join(", "))
+                };
+                outcome.info(format!(
+                    "{} (via {}): seen {}, dialed {}{}",
+                    peer_id,
+                    entry.via.as_deref().unwrap_or("?"),
+                    when(entry.seen),
+                    when(entry.dialed),
+                    at
+                ));
+            }
+        }
+        Command::AddAddress {
+            peer: peer_id,
+            addr,
+        } => {
+            if peer_id == *Swarm::local_peer_id(swarm) {
+                outcome.error("That is this node");
+                return outcome;
+            }
 
-            let record = Record {
-                key,
-                value,
-                publisher: None,
-                expires: None,
-            };
+            // What add_address returns isn't exported, so the table has
+            // to tell
+            swarm.kademlia.add_address(&peer_id, addr.clone());
+            let added =
+                swarm.known_peers().into_iter().any(|(peer, addrs)| {
+                    peer == peer_id && addrs.contains(&addr)
+                });
+            if added {
+                outcome.info(format!(
+                    "Added {} at {} to the routing table",
+                    peer_id, addr
+                ));
+            } else {
+                outcome.error(format!(
+                    "The bucket of {} is full: it only gets in if a peer \
+                     there turns out to be gone (and no other peer waits \
+                     for that already)",
+                    peer_id
+                ));
+            }
+        }
+        Command::RemovePeer(peer_id) => {
+            match swarm.remove_peer(&peer_id) {
+                Some(addrs) => {
+                    let addrs: Vec<String> = addrs
+                        .iter()
+                        .map(|addr| addr.to_string())
+                        .collect();
+                    outcome.info(format!(
+                        "Removed {} (at {}) from the routing table",
+                        peer_id,
+                        addrs.join(", ")
+                    ));
+                }
+                None => outcome.error(format!(
+                    "{} isn't in the routing table",
+                    peer_id
+                )),
+            }
+        }
+        Command::Disconnect {
+            peer: peer_id,
+            hold,
+        } => {
+            // Unlike BAN, the peer stays in the routing table, so that
+            // kademlia finds out on its own that it is gone. While held
+            // off, it is banned in the filter (unless it already was).
+            if !swarm.connections.is_connected(&peer_id) && hold.is_none()
+            {
+                outcome.error(format!("Not connected to {}", peer_id));
+                return outcome;
+            }
+            swarm.connections.disconnect(peer_id.clone());
+            match hold {
+                Some(hold) => {
+                    if !swarm.filter.read().unwrap().denies(&peer_id) {
+                        swarm.filter.write().unwrap().ban(peer_id.clone());
+                        swarm.connections.hold(peer_id.clone(), hold);
+                    }
+                    outcome.info(format!(
+                        "Disconnecting from {} for {:?}",
+                        peer_id, hold
+                    ));
+                }
+                None => {
+                    outcome.info(format!("Disconnecting from {}", peer_id))
+                }
+            }
+        }
+        Command::Whoami => {
+            let peer_id = Swarm::local_peer_id(swarm).clone();
+            outcome.info(format!("Peer id: {}", peer_id));
+            outcome.info(format!(
+                "Public key: {}",
+                bs58::encode(
+                    swarm.public_key.clone().into_protobuf_encoding()
+                )
+                .into_string()
+            ));
 
-            kademlia
-                .put_record(record, Quorum::One)
-                .expect("Failed to store record locally");
+            // With the peer id at the end, ready for --bootstrap
+            let external: Vec<Multiaddr> =
+                Swarm::external_addresses(swarm).cloned().collect();
+            for (what, addrs) in [
+                ("Listening on", listen_addrs(swarm)),
+                ("Reachable at", external),
+            ] {
+                for addr in addrs {
+                    outcome.info(format!(
+                        "{} {}",
+                        what,
+                        addr.with(Protocol::P2p(peer_id.clone().into()))
+                    ));
+                }
+            }
+        }
+        Command::LookupPeer(peer_id) => {
+            let key = peerinfo::key_for(&peer_id);
+            let id = swarm.kademlia.get_record(&key, Quorum::One);
+            swarm.peer_lookups.insert(id, (peer_id, output));
+            outcome.query = Some(id);
+        }
+        Command::Addrs => {
+            // What the swarm has now: listeners on port 0 only get their
+            // address once they are bound
+            let listening = listen_addrs(swarm);
+            if listening.is_empty() {
+                outcome.info("Not listening on any address yet");
+            }
+            for addr in listening {
+                outcome.info(format!("Listening on {}", addr));
+            }
+            for addr in Swarm::external_addresses(swarm) {
+                outcome.info(format!("External address {}", addr));
+            }
+            // The ones too few peers agree on yet
+            let needed = swarm.observed.confirmations();
+            for (addr, peers) in swarm.observed.candidates() {
+                outcome.info(format!(
+                    "Observed at {} by {} of the {} peers it takes",
+                    addr, peers, needed
+                ));
+            }
+        }
+        Command::Bootstrap => match swarm.kademlia.bootstrap() {
+            Ok(id) => {
+                outcome.info("Bootstrapping");
+                swarm.pending.insert(id, output);
+                outcome.query = Some(id);
+            }
+            Err(_) => outcome.error("No known peers to bootstrap from"),
+        },
+        Command::Wait { until, timeout } => {
+            swarm.barriers.wait(until, timeout, output)
+        }
+        Command::Closest(target) => {
+            let key = match target {
+                Target::Peer(peer_id) => peer_id.into_bytes(),
+                Target::Key(key) => swarm.namespace.key(&key).to_vec(),
+            };
+            let id = swarm.kademlia.get_closest_peers(key);
+            swarm.pending.insert(id, output);
+            outcome.query = Some(id);
+        }
+        Command::Cancel(Cancel::List) => {
+            let queries: Vec<_> = swarm.in_flight.iter().collect();
+            if queries.is_empty() {
+                outcome.info("No queries running");
+            }
+            for (seq, query) in queries {
+                outcome.info(format!(
+                    "query {}: {} (running for {:.1?})",
+                    seq,
+                    query.command,
+                    query.started.elapsed()
+                ));
+            }
         }
-        _ => {
-            eprintln!("Expected GET or PUT");
+        Command::Cancel(Cancel::One(seq)) => match swarm.cancel(seq) {
+            Some(command) => outcome
+                .info(format!("Cancelled query {}: {}", seq, command)),
+            None => outcome.error(format!("No query {} running", seq)),
+        },
+        Command::Cancel(Cancel::All) => {
+            let seqs = swarm.in_flight.seqs();
+            for seq in &seqs {
+                swarm.cancel(*seq);
+            }
+            outcome.info(format!("Cancelled {} queries", seqs.len()));
         }
+        Command::Local(key) => match key {
+            Some(key) => {
+                let key = swarm.namespace.key(&key);
+                outcome.messages = swarm.show_local(&key);
+            }
+            None => outcome.messages = swarm.list_local(),
+        },
+        Command::List(prefix) => {
+            let namespace = match prefix {
+                Some(prefix) => Namespace::new(prefix),
+                None => swarm.namespace.clone(),
+            };
+            outcome.query = Some(swarm.list(&namespace, output));
+        }
+        Command::Snapshot(path) => {
+            let path = path.as_path();
+            let snapshot = swarm.snapshot();
+            match snapshot::save(path, &snapshot) {
+                Ok(()) => outcome.info(format!(
+                    "Saved {} records and {} provider records to {}",
+                    snapshot.records.len(),
+                    snapshot.providers.len(),
+                    path.display()
+                )),
+                Err(err) => outcome.error(format!(
+                    "Couldn't write {}: {}",
+                    path.display(),
+                    err
+                )),
+            }
+        }
+        Command::Restore(path) => {
+            let path = path.as_path();
+            match snapshot::load(path) {
+                Ok(snapshot) => {
+                    let stored = swarm.restore(&snapshot);
+                    outcome.info(format!(
+                        "Restored {} from {}",
+                        snapshot.restored(stored),
+                        path.display()
+                    ));
+                }
+                Err(err) => outcome.error(format!(
+                    "Couldn't read {}: {}",
+                    path.display(),
+                    err
+                )),
+            }
+        }
+        Command::Send {
+            peer: peer_id,
+            text,
+        } => {
+            let id = swarm.messaging.send_request(&peer_id, text);
+            swarm.sending.insert(id, (peer_id, Instant::now(), output));
+        }
+        Command::SendFile {
+            peer: peer_id,
+            path,
+        } => {
+            let swarm = &mut **swarm;
+            if let Err(err) = swarm.file_transfers.send(
+                &mut swarm.files,
+                peer_id,
+                &path,
+                output.clone(),
+            ) {
+                outcome.error(format!("{}: {}", path.display(), err));
+            }
+        }
+        Command::Topology(path) => {
+            let path = path.as_path();
+            let names = [(
+                Swarm::local_peer_id(swarm).clone(),
+                "this node".into(),
+            )];
+            match topology::write_dot(path, &names, &swarm.edges()) {
+                Ok(edges) => outcome.info(format!(
+                    "Wrote {} connections to {}",
+                    edges,
+                    path.display()
+                )),
+                Err(err) => {
+                    outcome.error(format!("{}: {}", path.display(), err))
+                }
+            }
+        }
+        Command::Analyze(path) => {
+            let keys = match path.as_deref().map(analyze::read_keys) {
+                Some(Ok(keys)) => Some(keys),
+                Some(Err(err)) => {
+                    outcome.error(format!(
+                        "Couldn't read {}: {}",
+                        path.unwrap_or_default().display(),
+                        err
+                    ));
+                    return outcome;
+                }
+                None => None,
+            };
+            outcome.messages = swarm.analyze(keys).report();
+        }
+        Command::Verify(name) => {
+            let key = swarm.namespace.key(&name);
+            let local = swarm.local_value(&key);
+            let id = swarm.kademlia.get_closest_peers(key.to_vec());
+            swarm.verifications.start(id, key, name, local, output);
+            // TIME and CANCEL go by the lookup (its report says how long
+            // all of it took)
+            outcome.query = Some(id);
+        }
+        Command::Load(LoadAction::Start(workload)) => {
+            match swarm.load.start(workload, output) {
+                Ok(()) => {
+                    outcome.info(format!("Started LOAD: {}", workload))
+                }
+                Err(err) => outcome.error(err),
+            }
+        }
+        Command::Load(LoadAction::Stop) => match swarm.load.stop() {
+            Some(summary) => outcome.info(summary),
+            None => outcome.error("No LOAD is running"),
+        },
+        Command::Cache { clear } => match (&mut swarm.cache, clear) {
+            (None, _) => {
+                outcome.error("There is no cache (see --cache-size)")
+            }
+            (Some(cache), true) => outcome.info(format!(
+                "Cleared {} records from the cache",
+                cache.clear()
+            )),
+            (Some(cache), false) => {
+                let stats = cache.stats();
+                let rate = match stats.hits + stats.misses {
+                    0 => 0.0,
+                    gets => stats.hits as f64 * 100.0 / gets as f64,
+                };
+                outcome.info(format!(
+                    "Cache: {} of {} records, fresh for {:?}; {} hits and {} \
+                     misses ({:.0}% hit)",
+                    stats.entries,
+                    cache.capacity(),
+                    cache.ttl(),
+                    stats.hits,
+                    stats.misses,
+                    rate
+                ));
+            }
+        },
+        Command::Load(LoadAction::Status) => match swarm.load.status() {
+            Some(status) => outcome.info(status),
+            None => outcome.error("No LOAD is running"),
+        },
+        Command::Dials => {
+            let queue = &swarm.kademlia.dials;
+            let limits = queue.limits();
+            let limit = |max: Option<usize>| match max {
+                Some(max) => format!(" (at most {})", max),
+                None => String::new(),
+            };
+            // (to the millisecond)
+            let ms = |age: Duration| {
+                Duration::from_millis(age.as_millis() as u64)
+            };
+            let dialing = queue.dialing();
+            let queued = queue.queued();
+            outcome.info(format!(
+                "{} dials going on{}, {} waiting{}",
+                dialing.len(),
+                limit(limits.max_concurrent),
+                queued.len(),
+                limit(limits.max_queued)
+            ));
+            for (peer, age) in dialing {
+                outcome.info(format!(
+                    "  dialing {} for {:?}",
+                    peer,
+                    ms(age)
+                ));
+            }
+            for (peer, age) in queued {
+                outcome.info(format!(
+                    "  waiting {} for {:?}",
+                    peer,
+                    ms(age)
+                ));
+            }
+            // The dials of DIAL don't wait
+            for addr in swarm.dials.keys() {
+                outcome.info(format!("  dialing {} (DIAL)", addr));
+            }
+            outcome.info(format!(
+                "{} dials so far, {} of them waited, and {} more were \
+                 dropped with the queue full",
+                queue.started, queue.delayed, queue.dropped
+            ));
+        }
+        // The peer we reach has to be the one the address was of, if
+        // it was of one
+        Command::Dial {
+            addr,
+            peer: expected,
+        } => {
+            if let Err(err) = Swarm::dial_addr(swarm, addr.clone()) {
+                outcome.error(format!("Couldn't dial {}: {}", addr, err));
+                return outcome;
+            }
+            outcome.info(format!("Dialing {}", addr));
+            swarm
+                .dials
+                .entry(addr)
+                .or_default()
+                .push_back((expected, output));
+        }
+    }
+    outcome
+}
+
+// The addresses the node is listening on.
+pub fn listen_addrs(swarm: &Swarm<MyBehavior>) -> Vec<Multiaddr> {
+    swarm.listen_addrs.clone()
+}
+
+// Send a rendezvous request to every rendezvous server.
+fn send_rendezvous(
+    swarm: &mut Swarm<MyBehavior>,
+    request: rendezvous::Request,
+    output: Output,
+) -> Result<(), String> {
+    let namespace = match &request {
+        rendezvous::Request::Register { namespace, .. }
+        | rendezvous::Request::Discover { namespace } => namespace.clone(),
+    };
+    if swarm.rendezvous_points.is_empty() {
+        return Err(
+            "No rendezvous servers (see --rendezvous-point)".to_string()
+        );
+    }
+    for peer_id in swarm.rendezvous_points.clone() {
+        let id = swarm.rendezvous.send_request(&peer_id, request.clone());
+        swarm
+            .rendezvous_pending
+            .insert(id, (namespace.clone(), output.clone()));
     }
+    Ok(())
 }
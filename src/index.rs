@@ -0,0 +1,102 @@
+use crate::{namespace::Namespace, output::Output};
+use libp2p::kad::{record::Key, QueryId};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+// How many copies of an index a lookup waits for. Nodes that update the
+// index at the same time can overwrite each other's keys, so a lookup
+// takes in every copy it can get, and puts them together.
+pub const QUORUM: usize = 3;
+
+// The key of the index of `namespace`: with --key-index, every PUT adds
+// its key to it, and LIST reads it. Kademlia can't list the keys it
+// stores, so this is the only way to find out which ones exist. It is
+// outside of every namespace, so that no PUT can overwrite it.
+pub fn key_for(namespace: &Namespace) -> Key {
+    let name = format!(
+        "/nettest/index/{}",
+        namespace.prefix().unwrap_or_default()
+    );
+    Key::new(&name)
+}
+
+// The keys in (a copy of) an index, one per line.
+pub fn parse(value: &[u8]) -> impl Iterator<Item = String> + '_ {
+    value
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+}
+
+pub fn encode(keys: &BTreeSet<String>) -> Vec<u8> {
+    let mut value = Vec::new();
+    for key in keys {
+        value.extend_from_slice(key.as_bytes());
+        value.push(b'\n');
+    }
+    value
+}
+
+// What a query of the index is for.
+#[derive(Debug)]
+pub enum Pending {
+    // Reading the index, to add our keys to it
+    Read(Key),
+    // Storing it again, with them
+    Write(Key),
+    // Reading it for a LIST of a namespace (by its name)
+    List(String, Output),
+}
+
+// The keys this node added to the indexes, and the queries of the
+// indexes that are running. Every update of an index adds all the keys
+// that this node ever put in it, and only ends once a lookup finds them
+// all there, so the ones that another node's update lost come back.
+#[derive(Debug, Default)]
+pub struct KeyIndex {
+    keys: HashMap<Key, BTreeSet<String>>,
+    queries: HashMap<QueryId, Pending>,
+    // The indexes being updated, and the ones that got a key since
+    // their update started (which then takes another one)
+    updating: HashSet<Key>,
+    stale: HashSet<Key>,
+}
+
+impl KeyIndex {
+    // Add `name` to `index`. Returns whether an update of the index has
+    // to start (there is one running otherwise, or the key is in there
+    // already).
+    pub fn add(&mut self, index: &Key, name: String) -> bool {
+        if !self.keys.entry(index.clone()).or_default().insert(name) {
+            return false;
+        }
+        if self.updating.contains(index) {
+            self.stale.insert(index.clone());
+            return false;
+        }
+        self.updating.insert(index.clone());
+        true
+    }
+
+    pub fn add_query(&mut self, query: QueryId, pending: Pending) {
+        self.queries.insert(query, pending);
+    }
+
+    pub fn finish(&mut self, query: &QueryId) -> Option<Pending> {
+        self.queries.remove(query)
+    }
+
+    // The keys this node put in `index`.
+    pub fn keys(&self, index: &Key) -> Option<&BTreeSet<String>> {
+        self.keys.get(index)
+    }
+
+    // An update of `index` is done (or failed). Returns whether it has
+    // to be updated again, for the keys that came in meanwhile.
+    pub fn updated(&mut self, index: &Key) -> bool {
+        if self.stale.remove(index) {
+            return true;
+        }
+        self.updating.remove(index);
+        false
+    }
+}
@@ -0,0 +1,91 @@
+use libp2p::kad::QueryId;
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::Instant,
+};
+
+// The queries that commands started and that are still running, for
+// CANCEL. They are numbered as they start, from 1 (bare CANCEL lists
+// them). A query that is cancelled is finished where it is, and what it
+// found by then is dropped: whoever started it hears that it was
+// cancelled instead.
+#[derive(Debug)]
+pub struct InFlight {
+    next_seq: u64,
+    queries: BTreeMap<u64, Query>,
+    cancelled: HashSet<QueryId>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub id: QueryId,
+    // The command line that started it
+    pub command: String,
+    pub started: Instant,
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        InFlight {
+            next_seq: 1,
+            queries: BTreeMap::new(),
+            cancelled: HashSet::new(),
+        }
+    }
+}
+
+impl InFlight {
+    // Returns the number of the query.
+    pub fn add(&mut self, id: QueryId, command: String) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let started = Instant::now();
+        self.queries.insert(
+            seq,
+            Query {
+                id,
+                command,
+                started,
+            },
+        );
+        seq
+    }
+
+    // The running queries, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &Query)> {
+        self.queries.iter().map(|(seq, query)| (*seq, query))
+    }
+
+    // Take query `seq` out, to be cancelled.
+    pub fn cancel(&mut self, seq: u64) -> Option<Query> {
+        let query = self.queries.remove(&seq)?;
+        self.cancelled.insert(query.id);
+        Some(query)
+    }
+
+    // `from` goes on as `to` (like the PUT of a CAS), under the same
+    // number.
+    pub fn hand_over(&mut self, from: &QueryId, to: QueryId) {
+        for query in self.queries.values_mut() {
+            if query.id == *from {
+                query.id = to;
+            }
+        }
+    }
+
+    // The numbers of all the running queries.
+    pub fn seqs(&self) -> Vec<u64> {
+        self.queries.keys().copied().collect()
+    }
+
+    // A query finished (or, if not `last`, one of its steps did, like
+    // those of a bootstrap). Returns whether it was cancelled, and its
+    // result is to be dropped.
+    pub fn finish(&mut self, id: &QueryId, last: bool) -> bool {
+        if !last {
+            return self.cancelled.contains(id);
+        }
+        self.queries.retain(|_, query| query.id != *id);
+        self.cancelled.remove(id)
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{
+    chaos::parse_duration,
+    command::{parse_quorum, Command, Compress, COMMANDS},
+    value::Compression,
+    watch,
+};
+use clap::ValueEnum;
+use libp2p::kad::Quorum;
+use serde_json::{Map, Value};
+use std::time::Duration;
+
+// What the lines that come in (from the terminal, or a control socket)
+// are.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    // Commands as they are typed, like `PUT foo bar`
+    #[default]
+    Text,
+    // A JSON object per line, like {"op": "put", "key": "foo", "value":
+    // "bar"}, for programs that drive a node (see `parse_json`)
+    Json,
+}
+
+// Parse a command of --input json. `op` is the command (in any case),
+// and the rest of the fields are its arguments:
+//   {"op": "get", "key": "k", "quorum": 2, "timeout": "5s"}
+//   {"op": "put", "key": "k", "value": "v", "quorum": "all",
+//    "compress": "deflate"}
+//   {"op": "put_cas", "value_b64": "aGVsbG8="}
+//   {"op": "cas", "key": "k", "expected": 3, "value": "v"}
+//   {"op": "watch", "key": "k", "interval": "1s"}
+// Values can have any bytes in them, as base64 in `value_b64` instead of
+// `value`. The other commands take the words they are typed with, as
+// `args`, like {"op": "peers", "args": ["-v"]}. With `"time": true`, the
+// command is timed, as with TIME. Returns the command, and whether it is
+// timed.
+pub fn parse_json(line: &str) -> Result<(Command, bool), String> {
+    let fields: Map<String, Value> = match serde_json::from_str(line) {
+        Ok(Value::Object(fields)) => fields,
+        Ok(_) => return Err("Expected a JSON object".into()),
+        Err(err) => return Err(format!("Invalid JSON: {}", err)),
+    };
+    let fields = Fields(fields);
+    let op = fields.string("op")?.to_ascii_uppercase();
+    let timed =
+        fields.0.get("time").and_then(Value::as_bool) == Some(true);
+    let command = match op.as_str() {
+        "GET" => Command::Get {
+            key: fields.string("key")?,
+            quorum: fields.quorum()?,
+            timeout: fields.duration("timeout")?,
+        },
+        "PUT" => Command::Put {
+            key: fields.string("key")?,
+            value: fields.value()?,
+            compress: fields.compress()?,
+            quorum: fields.quorum()?,
+        },
+        "PUT_CAS" => Command::PutCas {
+            value: fields.value()?,
+            compress: fields.compress()?,
+            quorum: fields.quorum()?,
+        },
+        "CAS" => Command::Cas {
+            key: fields.string("key")?,
+            expected: fields
+                .0
+                .get("expected")
+                .ok_or("Expected the current version (\"expected\")")?
+                .as_u64()
+                .ok_or("Invalid version")?,
+            value: fields.value()?,
+            compress: fields.compress()?,
+            quorum: fields.quorum()?,
+        },
+        "WATCH" => Command::Watch {
+            key: fields.string("key")?,
+            interval: fields.interval()?,
+        },
+        "WATCH_PROVIDERS" => Command::WatchProviders {
+            key: fields.string("key")?,
+            interval: fields.interval()?,
+        },
+        _ if COMMANDS.contains(&op.as_str()) => {
+            let mut line = op;
+            for arg in fields.args()? {
+                line.push(' ');
+                line.push_str(&arg);
+            }
+            line.parse().map_err(|err| format!("{}", err))?
+        }
+        _ => return Err(format!("Unknown op {:?}", op.to_lowercase())),
+    };
+    Ok((command, timed))
+}
+
+// The fields of a command object.
+struct Fields(Map<String, Value>);
+
+impl Fields {
+    fn string(&self, name: &str) -> Result<String, String> {
+        match self.0.get(name) {
+            Some(Value::String(s)) => Ok(s.clone()),
+            Some(_) => Err(format!("{:?} has to be a string", name)),
+            None => Err(format!("Expected {:?}", name)),
+        }
+    }
+
+    // The value, as a string in `value`, or as base64 in `value_b64`.
+    fn value(&self) -> Result<Vec<u8>, String> {
+        match (self.0.get("value"), self.0.get("value_b64")) {
+            (Some(Value::String(value)), None) => {
+                Ok(value.as_bytes().to_vec())
+            }
+            (None, Some(Value::String(value))) => base64::decode(value)
+                .map_err(|err| format!("Invalid value_b64: {}", err)),
+            (Some(_), Some(_)) => {
+                Err("Expected either \"value\" or \"value_b64\"".into())
+            }
+            (None, None) => {
+                Err("Expected \"value\" or \"value_b64\"".into())
+            }
+            _ => Err("The value has to be a string".into()),
+        }
+    }
+
+    // A number of peers, or a string that parse_quorum takes.
+    fn quorum(&self) -> Result<Quorum, String> {
+        match self.0.get("quorum") {
+            None => Ok(Quorum::One),
+            Some(Value::Number(n)) => parse_quorum(&n.to_string()),
+            Some(Value::String(s)) => parse_quorum(s),
+            Some(_) => Err("Invalid quorum".into()),
+        }
+    }
+
+    fn duration(&self, name: &str) -> Result<Option<Duration>, String> {
+        match self.0.get(name) {
+            None => Ok(None),
+            Some(Value::String(s)) => parse_duration(s).map(Some),
+            Some(_) => Err(format!(
+                "{:?} has to be a duration, like \"5s\"",
+                name
+            )),
+        }
+    }
+
+    fn interval(&self) -> Result<Duration, String> {
+        match self.duration("interval")? {
+            Some(interval) if interval.is_zero() => {
+                Err("The interval can't be 0".into())
+            }
+            Some(interval) => Ok(interval),
+            None => Ok(watch::DEFAULT_INTERVAL),
+        }
+    }
+
+    fn compress(&self) -> Result<Compress, String> {
+        match self.0.get("compress") {
+            None => Ok(Compress::Default),
+            Some(Value::String(s)) if s == "none" => Ok(Compress::None),
+            Some(Value::String(s)) => Compression::from_str(s, true)
+                .map(Compress::With)
+                .map_err(|_| format!("Unknown compression {:?}", s)),
+            Some(_) => Err("\"compress\" has to be a string".into()),
+        }
+    }
+
+    // The words of a command that is otherwise typed. They can't have
+    // spaces in them, since they are put back together into a line.
+    fn args(&self) -> Result<Vec<String>, String> {
+        let args = match self.0.get("args") {
+            None => return Ok(Vec::new()),
+            Some(Value::Array(args)) => args,
+            Some(_) => return Err("\"args\" has to be a list".into()),
+        };
+        args.iter()
+            .map(|arg| {
+                let arg = match arg {
+                    Value::String(s) => s.clone(),
+                    Value::Number(n) => n.to_string(),
+                    Value::Bool(b) => b.to_string(),
+                    _ => return Err(format!("Invalid argument {}", arg)),
+                };
+                match arg.contains(' ') || arg.is_empty() {
+                    true => Err(format!("Invalid argument {:?}", arg)),
+                    false => Ok(arg),
+                }
+            })
+            .collect()
+    }
+}
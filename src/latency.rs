@@ -0,0 +1,168 @@
+use libp2p::{
+    kad::{
+        handler::{KademliaHandlerEvent, KademliaHandlerIn},
+        QueryId,
+    },
+    PeerId,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+// The upper bounds of the buckets of a histogram, in milliseconds (the
+// last bucket takes everything slower).
+pub const BUCKETS_MS: [u64; 12] =
+    [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+
+// How long things took, in buckets of BUCKETS_MS.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    // Per bucket (not cumulative), with the slower ones last
+    pub counts: [u64; BUCKETS_MS.len() + 1],
+    pub sum: Duration,
+    pub max: Duration,
+}
+
+impl Histogram {
+    pub fn record(&mut self, took: Duration) {
+        let ms = took.as_secs_f64() * 1000.0;
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|&bound| ms <= bound as f64)
+            .unwrap_or(BUCKETS_MS.len());
+        self.counts[bucket] += 1;
+        self.sum += took;
+        self.max = self.max.max(took);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // The bucket bound that `q` of the samples are within (the maximum,
+    // for those slower than the last bound).
+    pub fn quantile(&self, q: f64) -> Duration {
+        let wanted = (self.count() as f64 * q).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= wanted {
+                return match BUCKETS_MS.get(i) {
+                    Some(&bound) => {
+                        Duration::from_millis(bound).min(self.max)
+                    }
+                    None => self.max,
+                };
+            }
+        }
+        self.max
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.count();
+        if count == 0 {
+            return f.write_str("no samples");
+        }
+        write!(
+            f,
+            "{} samples, mean {:.1?}, p50 {:.0?}, p90 {:.0?}, p99 {:.0?}, \
+             max {:.1?}",
+            count,
+            self.sum / count as u32,
+            self.quantile(0.5),
+            self.quantile(0.9),
+            self.quantile(0.99),
+            self.max
+        )
+    }
+}
+
+// The latencies of one peer: the round trips of pings, and how long its
+// answers to our kademlia requests took.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeerLatency {
+    pub ping: Histogram,
+    pub rpc: Histogram,
+}
+
+// The latencies of every peer the node talked to, so that the slow ones
+// stand out (PEERS -v, and /metrics). Kademlia doesn't time its requests,
+// so they are timed here, from the request going out to its answer
+// coming in, by peer and query (kademlia asks each peer once per query).
+#[derive(Debug, Default)]
+pub struct Latencies {
+    peers: HashMap<PeerId, PeerLatency>,
+    waiting: HashMap<(PeerId, QueryId), Instant>,
+}
+
+impl Latencies {
+    pub fn get(&self, peer: &PeerId) -> Option<&PeerLatency> {
+        self.peers.get(peer)
+    }
+
+    // The peers, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &PeerLatency)> {
+        self.peers.iter()
+    }
+
+    pub fn ping(&mut self, peer: &PeerId, rtt: Duration) {
+        self.peers.entry(peer.clone()).or_default().ping.record(rtt);
+    }
+
+    // A message went out to `peer`: start the clock if it is a request.
+    pub fn sent(
+        &mut self,
+        peer: &PeerId,
+        event: &KademliaHandlerIn<QueryId>,
+    ) {
+        use KademliaHandlerIn::*;
+        let query = match event {
+            FindNodeReq { user_data, .. }
+            | GetProvidersReq { user_data, .. }
+            | GetRecord { user_data, .. }
+            | PutRecord { user_data, .. } => *user_data,
+            _ => return,
+        };
+        self.waiting.insert((peer.clone(), query), Instant::now());
+    }
+
+    // A message came in from `peer`: stop the clock if it answers one of
+    // our requests. Errors (the request failed, or timed out) aren't
+    // answers, and aren't counted.
+    pub fn received(
+        &mut self,
+        peer: &PeerId,
+        event: &KademliaHandlerEvent<QueryId>,
+    ) {
+        use KademliaHandlerEvent::*;
+        let (query, answered) = match event {
+            FindNodeRes { user_data, .. }
+            | GetProvidersRes { user_data, .. }
+            | GetRecordRes { user_data, .. }
+            | PutRecordRes { user_data, .. } => (*user_data, true),
+            QueryError { user_data, .. } => (*user_data, false),
+            _ => return,
+        };
+        let sent = match self.waiting.remove(&(peer.clone(), query)) {
+            Some(sent) => sent,
+            None => return,
+        };
+        if answered {
+            self.peers
+                .entry(peer.clone())
+                .or_default()
+                .rpc
+                .record(sent.elapsed());
+        }
+    }
+
+    // The requests to `peer` won't be answered anymore (but what it took
+    // so far stays).
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        self.waiting.retain(|(waiting, _), _| waiting != peer);
+    }
+}
@@ -1 +1,98 @@
+pub mod addrbook;
+pub mod adversary;
+pub mod analyze;
+pub mod api;
+pub mod audit;
+pub mod auth;
+pub mod bandwidth;
+pub mod barrier;
+pub mod batch;
+pub mod beacon;
+pub mod behaviour;
+pub mod bench;
+pub mod bootstrap;
+pub mod broadcast;
+pub mod cache;
+pub mod capture;
+pub mod cas;
+pub mod chaos;
+pub mod chunk;
+pub mod cluster;
+pub mod coalesce;
+pub mod collect;
+pub mod command;
+pub mod config;
+pub mod conflict;
+pub mod control;
+pub mod crawl;
+pub mod deadline;
+pub mod dials;
+pub mod disconnect;
+pub mod doctor;
+pub mod error;
+pub mod eventlog;
+pub mod events;
+pub mod experiment;
+pub mod expiry;
+pub mod extension;
+pub mod fault;
+pub mod fetch;
+pub mod file;
+pub mod filter;
+pub mod handle;
 pub mod handler;
+pub mod index;
+pub mod inflight;
+pub mod input;
+pub mod latency;
+pub mod limits;
+pub mod liveness;
+pub mod load;
+pub mod msg;
+pub mod namespace;
+pub mod negotiation;
+pub mod node;
+pub mod observed;
+pub mod output;
+pub mod peerinfo;
+pub mod peerstore;
+pub mod pex;
+pub mod portmap;
+pub mod progress;
+pub mod provenance;
+pub mod queue;
+pub mod redial;
+pub mod rendezvous;
+pub mod republish;
+pub mod results;
+pub mod retry;
+pub mod routing;
+pub mod rpc;
+pub mod scenario;
+pub mod schema;
+pub mod score;
+pub mod seed;
+pub mod session;
+pub mod shape;
+pub mod simulate;
+pub mod snapshot;
+pub mod soak;
+pub mod socks;
+pub mod stats;
+pub mod storechurn;
+pub mod testnet;
+pub mod throttle;
+pub mod timescale;
+pub mod timing;
+pub mod toml;
+pub mod topology;
+pub mod trace;
+pub mod transport;
+pub mod tui;
+pub mod updates;
+pub mod validate;
+pub mod value;
+pub mod verify;
+pub mod watch;
+
+pub use error::Error;
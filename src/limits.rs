@@ -0,0 +1,294 @@
+use crate::{
+    bandwidth::Bandwidth,
+    behaviour::{MyBehavior, MyBehaviorWith},
+    output::Output,
+    stats::SessionStats,
+    transport::{BoxedTransport, Runtime},
+};
+use futures::FutureExt;
+use futures_timer::Delay;
+#[cfg(feature = "tokio")]
+use libp2p::core::Executor;
+use libp2p::{
+    swarm::{NetworkBehaviour, SwarmBuilder, SwarmEvent},
+    PeerId, Swarm,
+};
+use std::{
+    collections::HashMap,
+    task::Context,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "tokio")]
+use std::{future::Future, pin::Pin};
+
+// How often to look for idle connections.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// How many connections a node may have. Anything that isn't given is
+// unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    // Established connections, all together
+    pub max_connections: Option<usize>,
+    // Established connections that peers opened, and that we opened
+    pub max_inbound: Option<usize>,
+    pub max_outbound: Option<usize>,
+    // Established connections to the same peer
+    pub max_per_peer: Option<usize>,
+    // Connections that are still being set up (dials, and connections
+    // that peers are opening)
+    pub max_pending_dials: Option<usize>,
+    pub max_pending_incoming: Option<usize>,
+}
+
+// Build the swarm of a node. The swarm enforces the limits on pending
+// connections and connections per peer, and the behaviour enforces the
+// rest (see `Connections`). Its connections run on `runtime`, which has
+// to be what the transport was built for.
+pub fn build_swarm<B: NetworkBehaviour>(
+    transport: BoxedTransport,
+    behaviour: MyBehaviorWith<B>,
+    peer_id: PeerId,
+    limits: &ConnectionLimits,
+    runtime: Runtime,
+) -> Swarm<MyBehaviorWith<B>> {
+    let mut builder = SwarmBuilder::new(transport, behaviour, peer_id);
+    match runtime {
+        Runtime::AsyncStd => {}
+        #[cfg(feature = "tokio")]
+        Runtime::Tokio => {
+            builder = builder.executor(Box::new(TokioExecutor));
+        }
+    }
+    if let Some(n) = limits.max_per_peer {
+        builder = builder.peer_connection_limit(n);
+    }
+    if let Some(n) = limits.max_pending_dials {
+        builder = builder.outgoing_connection_limit(n);
+    }
+    if let Some(n) = limits.max_pending_incoming {
+        builder = builder.incoming_connection_limit(n);
+    }
+    builder.build()
+}
+
+// Spawns the connections of a swarm on the Tokio runtime it is polled in.
+#[cfg(feature = "tokio")]
+struct TokioExecutor;
+
+#[cfg(feature = "tokio")]
+impl Executor for TokioExecutor {
+    fn exec(&self, task: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        tokio::spawn(task);
+    }
+}
+
+// The established connections of a node (counted in the session stats),
+// and the ones that go over the limits. libp2p 0.22 only limits pending
+// connections (and connections per peer), so the others are closed right
+// after they are established.
+//
+// With an idle timeout, the connections of peers whose traffic stays
+// flat for that long get closed too. The FETCH and rendezvous protocols
+// would otherwise keep every connection open forever (their handlers
+// push their keep-alive deadline back whenever they are polled).
+#[derive(Debug, Default)]
+pub struct Connections {
+    limits: ConnectionLimits,
+    // Each peer's connections that it opened, and that we opened
+    peers: HashMap<PeerId, (usize, usize)>,
+    // Peers whose connections are due to be closed
+    to_close: Vec<PeerId>,
+    idle_timeout: Option<Duration>,
+    // Each connected peer's bytes, and when they last changed
+    activity: HashMap<PeerId, (u64, Instant)>,
+    // Fires when it is time to look for idle connections
+    idle_check: Option<Delay>,
+    // Peers that DISCONNECT keeps out, and until when
+    held: HashMap<PeerId, Instant>,
+    // Fires when the first of them may come back
+    hold_timer: Option<Delay>,
+}
+
+impl Connections {
+    pub fn new(
+        limits: ConnectionLimits,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        Connections {
+            limits,
+            idle_timeout,
+            idle_check: idle_timeout
+                .map(|_| Delay::new(IDLE_CHECK_INTERVAL)),
+            ..Connections::default()
+        }
+    }
+
+    pub fn is_connected(&self, peer: &PeerId) -> bool {
+        self.peers.contains_key(peer)
+    }
+
+    pub fn connected(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.keys()
+    }
+
+    // Have the connections to `peer` closed (for DISCONNECT).
+    pub fn disconnect(&mut self, peer: PeerId) {
+        self.to_close.push(peer);
+    }
+
+    // Remember to let `peer` (which DISCONNECT banned in the filter) back
+    // in after `hold`.
+    pub fn hold(&mut self, peer: PeerId, hold: Duration) {
+        self.held.insert(peer, Instant::now() + hold);
+        self.hold_timer = None;
+    }
+
+    // The held peers that may connect again.
+    fn take_released(&mut self, cx: &mut Context<'_>) -> Vec<PeerId> {
+        if let Some(timer) = &mut self.hold_timer {
+            if timer.poll_unpin(cx).is_pending() {
+                return Vec::new();
+            }
+        }
+        let now = Instant::now();
+        let mut released = Vec::new();
+        self.held.retain(|peer, until| {
+            if *until <= now {
+                released.push(peer.clone());
+            }
+            *until > now
+        });
+
+        // Wake up when the next hold is over
+        self.hold_timer =
+            self.held.values().min().map(|until| {
+                Delay::new(until.saturating_duration_since(now))
+            });
+        if let Some(timer) = &mut self.hold_timer {
+            if timer.poll_unpin(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+        released
+    }
+
+    // Have the connections of the peers that have been idle for too long
+    // closed.
+    fn close_idle(&mut self, bandwidth: &Bandwidth, cx: &mut Context<'_>) {
+        let (timeout, check) =
+            match (self.idle_timeout, &mut self.idle_check) {
+                (Some(timeout), Some(check)) => (timeout, check),
+                _ => return,
+            };
+        if check.poll_unpin(cx).is_pending() {
+            return;
+        }
+        *check = Delay::new(IDLE_CHECK_INTERVAL);
+        let _ = check.poll_unpin(cx);
+
+        let now = Instant::now();
+        let peers = &self.peers;
+        self.activity.retain(|peer, _| peers.contains_key(peer));
+        for peer in peers.keys() {
+            let bytes = bandwidth.bytes(peer);
+            let (last, since) =
+                self.activity.entry(peer.clone()).or_insert((bytes, now));
+            if *last != bytes {
+                *last = bytes;
+                *since = now;
+            } else if now.duration_since(*since) >= timeout {
+                *since = now;
+                self.to_close.push(peer.clone());
+            }
+        }
+    }
+
+    // Count the connections that open and close, and close the ones
+    // that go over a limit.
+    pub fn observe<T, E>(
+        &mut self,
+        event: &SwarmEvent<T, E>,
+        stats: &mut SessionStats,
+    ) {
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                ..
+            } => {
+                let counts =
+                    self.peers.entry(peer_id.clone()).or_default();
+                let (count, direction, limit) = if endpoint.is_listener() {
+                    (
+                        &mut counts.0,
+                        &mut stats.inbound_connections,
+                        self.limits.max_inbound,
+                    )
+                } else {
+                    (
+                        &mut counts.1,
+                        &mut stats.outbound_connections,
+                        self.limits.max_outbound,
+                    )
+                };
+                *count += 1;
+                *direction += 1;
+                let over_direction = limit.is_some_and(|l| *direction > l);
+                let total =
+                    stats.inbound_connections + stats.outbound_connections;
+                stats.peak_connections = stats.peak_connections.max(total);
+
+                let over_total =
+                    self.limits.max_connections.is_some_and(|l| total > l);
+                if over_total || over_direction {
+                    stats.connections_refused += 1;
+                    self.to_close.push(peer_id.clone());
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id, endpoint, ..
+            } => {
+                let counts = match self.peers.get_mut(peer_id) {
+                    Some(counts) => counts,
+                    None => return,
+                };
+                let (count, direction) = if endpoint.is_listener() {
+                    (&mut counts.0, &mut stats.inbound_connections)
+                } else {
+                    (&mut counts.1, &mut stats.outbound_connections)
+                };
+                if *count > 0 {
+                    *count -= 1;
+                    *direction -= 1;
+                }
+                if *counts == (0, 0) {
+                    self.peers.remove(peer_id);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Close the connections that went over a limit (or sat idle, or that
+// DISCONNECT asked to close), and let the peers that DISCONNECT held off
+// back in once it is time.
+pub fn enforce(swarm: &mut Swarm<MyBehavior>, cx: &mut Context<'_>) {
+    let bandwidth = swarm.bandwidth.clone();
+    swarm.connections.close_idle(&bandwidth, cx);
+    let to_close = std::mem::take(&mut swarm.connections.to_close);
+    if !to_close.is_empty() {
+        // The swarm has to be polled again for the handlers to hear
+        cx.waker().wake_by_ref();
+    }
+    for peer_id in to_close {
+        swarm.scores.closing(&peer_id);
+        swarm.disconnect.close(peer_id);
+    }
+    for peer_id in swarm.connections.take_released(cx) {
+        swarm.filter.write().unwrap().unban(&peer_id);
+        Output::Terminal
+            .info(format!("Peer {} may connect again", peer_id));
+    }
+}
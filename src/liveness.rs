@@ -0,0 +1,105 @@
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    task::Context,
+    time::{Duration, Instant},
+};
+
+// How long a peer that we dial to see whether it is still there has to
+// answer a ping.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(20);
+
+// The longest to wait between two looks for stale peers.
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// Which peers of the routing table are still there (with --cull-after):
+// a peer that didn't answer a ping (or connect) for that long is dialed,
+// and if it doesn't answer then either, it is taken out of the table.
+// Otherwise the peers that left for good (like laptops found through
+// mDNS) linger in the buckets, and queries keep waiting on them.
+#[derive(Debug)]
+pub struct Liveness {
+    window: Duration,
+    // When each peer last showed it is there (or was first seen, for the
+    // ones that haven't yet)
+    alive: HashMap<PeerId, Instant>,
+    // The stale peers that were dialed, and when
+    probed: HashMap<PeerId, Instant>,
+    timer: Delay,
+}
+
+// What to do about the stale peers.
+#[derive(Debug, Default)]
+pub struct Sweep {
+    // The ones to dial, to see whether they answer
+    pub probe: Vec<PeerId>,
+    // The ones that didn't, and how long it was since they last did
+    pub cull: Vec<(PeerId, Duration)>,
+}
+
+impl Liveness {
+    pub fn new(window: Duration) -> Self {
+        Liveness {
+            window,
+            alive: HashMap::new(),
+            probed: HashMap::new(),
+            timer: Delay::new(Self::check_interval(window)),
+        }
+    }
+
+    fn check_interval(window: Duration) -> Duration {
+        (window / 2).clamp(Duration::from_secs(1), MAX_CHECK_INTERVAL)
+    }
+
+    // `peer` answered a ping, or connected.
+    pub fn alive(&mut self, peer: &PeerId) {
+        self.alive.insert(peer.clone(), Instant::now());
+        self.probed.remove(peer);
+    }
+
+    // Whether it is time to look for stale peers. The timer wakes the
+    // task once it is.
+    pub fn due(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.timer.poll_unpin(cx).is_pending() {
+            return false;
+        }
+        self.timer = Delay::new(Self::check_interval(self.window));
+        let _ = self.timer.poll_unpin(cx);
+        true
+    }
+
+    // Go through the peers of the routing table, for the ones to probe
+    // and the ones to cull.
+    pub fn sweep(&mut self, peers: impl Iterator<Item = PeerId>) -> Sweep {
+        let now = Instant::now();
+        let mut sweep = Sweep::default();
+        let mut in_table = HashMap::new();
+        for peer in peers {
+            let alive = *self.alive.entry(peer.clone()).or_insert(now);
+            in_table.insert(peer.clone(), alive);
+            if now - alive < self.window {
+                continue;
+            }
+            match self.probed.get(&peer) {
+                None => {
+                    self.probed.insert(peer.clone(), now);
+                    sweep.probe.push(peer);
+                }
+                Some(probed) if now - *probed >= PROBE_TIMEOUT => {
+                    sweep.cull.push((peer, now - alive));
+                }
+                Some(_) => {}
+            }
+        }
+        // Forget the peers that left the table some other way (and the
+        // ones that are culled now)
+        for (peer, _) in &sweep.cull {
+            in_table.remove(peer);
+        }
+        self.probed.retain(|peer, _| in_table.contains_key(peer));
+        self.alive = in_table;
+        sweep
+    }
+}
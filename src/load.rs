@@ -0,0 +1,424 @@
+use crate::{
+    api::{ApiReply, ApiRequest},
+    handle::NodeHandle,
+    output::Output,
+    seed,
+};
+use async_std::task;
+use futures::{
+    channel::oneshot, future::BoxFuture, stream::FuturesUnordered,
+    FutureExt, StreamExt,
+};
+use futures_timer::Delay;
+use libp2p::kad::{record::Key, GetRecordError, QueryResult};
+use rand::{distributions::Alphanumeric, seq::SliceRandom, Rng};
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// How often a LOAD starts the operations that are due, and reports how
+// they went.
+const TICK: Duration = Duration::from_millis(100);
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// The key of record `n` of a LOAD.
+pub fn key_name(n: usize) -> String {
+    format!("load/{}", n)
+}
+
+// What a LOAD does, from options like
+// `puts=5/s gets=20/s keyspace=1000 value-size=128`: PUTs of random
+// values to random keys of the keyspace, and GETs of the keys it PUT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Workload {
+    // Operations per second
+    pub puts: f64,
+    pub gets: f64,
+    pub keyspace: usize,
+    pub value_size: usize,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Workload {
+            puts: 1.0,
+            gets: 5.0,
+            keyspace: 1000,
+            value_size: 128,
+        }
+    }
+}
+
+impl Workload {
+    pub fn parse<'a>(
+        options: impl Iterator<Item = &'a str>,
+    ) -> Result<Self, String> {
+        let mut workload = Workload::default();
+        for option in options {
+            let (name, value) =
+                option.split_once('=').ok_or_else(|| {
+                    format!(
+                        "Expected an option like puts=5/s, not {:?}",
+                        option
+                    )
+                })?;
+            match name {
+                "puts" => workload.puts = parse_rate(value)?,
+                "gets" => workload.gets = parse_rate(value)?,
+                "keyspace" => {
+                    workload.keyspace = value
+                        .parse()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| {
+                            format!("{:?} is not a number of keys", value)
+                        })?
+                }
+                "value-size" => {
+                    workload.value_size = value.parse().map_err(|_| {
+                        format!("{:?} is not a number of bytes", value)
+                    })?
+                }
+                _ => {
+                    return Err(format!("Unknown LOAD option {:?}", name))
+                }
+            }
+        }
+        Ok(workload)
+    }
+}
+
+impl fmt::Display for Workload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/s puts and {}/s gets of {} keys, with {} byte values",
+            self.puts, self.gets, self.keyspace, self.value_size
+        )
+    }
+}
+
+// A rate like `5/s`, `300/min` or `5` (per second), in operations per
+// second.
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let invalid = || format!("{:?} is not a rate (like 5/s)", s);
+    let (n, per) = s.split_once('/').unwrap_or((s, "s"));
+    let n = n
+        .parse::<f64>()
+        .ok()
+        .filter(|n| *n >= 0.0 && n.is_finite())
+        .ok_or_else(invalid)?;
+    match per {
+        "s" | "sec" => Ok(n),
+        "m" | "min" => Ok(n / 60.0),
+        "h" | "hour" => Ok(n / 3600.0),
+        _ => Err(invalid()),
+    }
+}
+
+// What an operation of a LOAD is: a PUT (of the key it is for), or a
+// GET.
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Put(usize),
+    Get,
+}
+
+// How the operations that finished went.
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    puts: u64,
+    puts_failed: u64,
+    gets: u64,
+    gets_missing: u64,
+    gets_failed: u64,
+    // GETs answered from the cache (see --cache-size)
+    gets_cached: u64,
+    took: Duration,
+}
+
+impl Counts {
+    fn finished(&self) -> u64 {
+        self.puts + self.gets
+    }
+
+    // For the reports, if there is a cache.
+    fn cached(&self) -> String {
+        match self.gets_cached {
+            0 => String::new(),
+            n => format!(", {} from the cache", n),
+        }
+    }
+
+    // Count what the node answered to `op`. A GET that the cache
+    // answered took no query at all (and so no time).
+    fn count(&mut self, op: Op, took: Duration, reply: Option<&ApiReply>) {
+        let result = match reply {
+            Some(ApiReply::Query(result, stats)) => {
+                if let (Op::Get, None) = (op, stats.duration()) {
+                    self.gets += 1;
+                    self.gets_cached += 1;
+                    return;
+                }
+                Some(result)
+            }
+            _ => None,
+        };
+        self.took += took;
+        match (op, result) {
+            (Op::Put(_), Some(QueryResult::PutRecord(Ok(_)))) => {
+                self.puts += 1
+            }
+            (Op::Put(_), _) => {
+                self.puts += 1;
+                self.puts_failed += 1;
+            }
+            (Op::Get, Some(QueryResult::GetRecord(Ok(_)))) => {
+                self.gets += 1
+            }
+            (
+                Op::Get,
+                Some(QueryResult::GetRecord(Err(
+                    GetRecordError::NotFound { .. },
+                ))),
+            ) => {
+                self.gets += 1;
+                self.gets_missing += 1;
+            }
+            (Op::Get, _) => {
+                self.gets += 1;
+                self.gets_failed += 1;
+            }
+        }
+    }
+}
+
+// What the task of a LOAD did all together, for LOAD and LOAD stop.
+#[derive(Debug, Default)]
+struct Progress {
+    total: Counts,
+    // The operations the node hasn't answered yet
+    running: usize,
+}
+
+#[derive(Debug)]
+struct Running {
+    workload: Workload,
+    output: Output,
+    started: Instant,
+    progress: Arc<Mutex<Progress>>,
+    // The task stops once this is gone
+    _stop: oneshot::Sender<()>,
+}
+
+impl Running {
+    fn summary(&self) -> String {
+        let t = self.progress.lock().unwrap().total;
+        format!(
+            "LOAD: {} puts ({} failed) and {} gets ({} not found, {} \
+             failed{}) in {:.1?}",
+            t.puts,
+            t.puts_failed,
+            t.gets,
+            t.gets_missing,
+            t.gets_failed,
+            t.cached(),
+            self.started.elapsed()
+        )
+    }
+}
+
+// The workload of LOAD, if one is running. It runs as a task of its own,
+// and goes through the handle of the node like the other front-ends do:
+// its PUTs and GETs are those of the api (with the cache and
+// --coalesce-gets), at the rates it was given, as fast as the inbox takes
+// them. It runs until LOAD stop (or until the control client that
+// started it goes away), reporting how it goes to whoever started it.
+// What the node didn't answer by then is dropped.
+#[derive(Debug, Default)]
+pub struct Load {
+    // Where its operations go (there is no inbox in a simulation)
+    handle: Option<NodeHandle>,
+    running: Option<Running>,
+}
+
+impl Load {
+    pub fn new(handle: NodeHandle) -> Self {
+        Load {
+            handle: Some(handle),
+            running: None,
+        }
+    }
+
+    pub fn start(
+        &mut self,
+        workload: Workload,
+        output: Output,
+    ) -> Result<(), &'static str> {
+        let handle = self
+            .handle
+            .clone()
+            .ok_or("LOAD needs the inbox of a node of its own")?;
+        if self.running().is_some() {
+            return Err("A LOAD is running already (see LOAD stop)");
+        }
+        let progress = Arc::new(Mutex::new(Progress::default()));
+        let (stop, stopped) = oneshot::channel();
+        task::spawn(run(
+            workload,
+            handle,
+            output.clone(),
+            progress.clone(),
+            stopped,
+        ));
+        self.running = Some(Running {
+            workload,
+            output,
+            started: Instant::now(),
+            progress,
+            _stop: stop,
+        });
+        Ok(())
+    }
+
+    // Stop the LOAD, with what it did all together.
+    pub fn stop(&mut self) -> Option<String> {
+        self.running()?;
+        self.running.take().map(|running| running.summary())
+    }
+
+    // What the LOAD is doing, and what it did so far.
+    pub fn status(&mut self) -> Option<String> {
+        self.running().map(|running| {
+            format!(
+                "{} ({}), {} queries running",
+                running.summary(),
+                running.workload,
+                running.progress.lock().unwrap().running
+            )
+        })
+    }
+
+    // The LOAD that is running (unless whoever started it went away).
+    fn running(&mut self) -> Option<&Running> {
+        if self.running.as_ref().is_some_and(|r| r.output.is_closed()) {
+            self.running = None;
+        }
+        self.running.as_ref()
+    }
+}
+
+// The task of a LOAD: every TICK, submit the operations that are due by
+// then, and count the answers that came in.
+async fn run(
+    workload: Workload,
+    handle: NodeHandle,
+    output: Output,
+    progress: Arc<Mutex<Progress>>,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let started = Instant::now();
+    let mut answers: FuturesUnordered<
+        BoxFuture<'static, (Op, Instant, Option<ApiReply>)>,
+    > = FuturesUnordered::new();
+    let (mut puts_started, mut gets_started) = (0, 0);
+    let mut window = Counts::default();
+    let mut window_started = Instant::now();
+    // The keys that were PUT (and so can be got)
+    let mut stored = Vec::new();
+    let mut stored_keys = HashSet::new();
+    loop {
+        Delay::new(TICK).await;
+        if !matches!(stopped.try_recv(), Ok(None)) || output.is_closed() {
+            return;
+        }
+        while let Some(Some((op, submitted, reply))) =
+            answers.next().now_or_never()
+        {
+            let took = submitted.elapsed();
+            window.count(op, took, reply.as_ref());
+            let mut progress = progress.lock().unwrap();
+            progress.total.count(op, took, reply.as_ref());
+            progress.running -= 1;
+            if let (
+                Op::Put(key),
+                Some(ApiReply::Query(QueryResult::PutRecord(Ok(_)), _)),
+            ) = (op, &reply)
+            {
+                if stored_keys.insert(key) {
+                    stored.push(key);
+                }
+            }
+        }
+
+        // Every REPORT_INTERVAL, what the LOAD did since the last report
+        let secs = window_started.elapsed().as_secs_f64();
+        if secs >= REPORT_INTERVAL.as_secs_f64() {
+            let per_query = match window.finished() {
+                0 => Duration::default(),
+                n => window.took / n as u32,
+            };
+            output.info(format!(
+                "LOAD: {:.1} puts/s ({} failed), {:.1} gets/s ({} not \
+                 found, {} failed{}), {:.1?} per query, {} running",
+                window.puts as f64 / secs,
+                window.puts_failed,
+                window.gets as f64 / secs,
+                window.gets_missing,
+                window.gets_failed,
+                window.cached(),
+                per_query,
+                answers.len()
+            ));
+            window = Counts::default();
+            window_started = Instant::now();
+        }
+
+        // What is due by now, at the rates of the workload (the rng
+        // can't be held on to while waiting for the inbox)
+        let secs = started.elapsed().as_secs_f64();
+        let mut due = Vec::new();
+        {
+            let mut rng = seed::rng();
+            while puts_started < (secs * workload.puts) as u64 {
+                puts_started += 1;
+                let n = rng.gen_range(0, workload.keyspace);
+                let value = (&mut rng)
+                    .sample_iter(Alphanumeric)
+                    .take(workload.value_size)
+                    .map(|c| c as u8)
+                    .collect();
+                let (reply, answer) = oneshot::channel();
+                let request = ApiRequest::PutRecord(key(n), value, reply);
+                due.push((Op::Put(n), request, answer));
+            }
+            while gets_started < (secs * workload.gets) as u64 {
+                gets_started += 1;
+                // Until something was PUT, there is nothing to get
+                if let Some(n) = stored.choose(&mut rng) {
+                    let (reply, answer) = oneshot::channel();
+                    let request = ApiRequest::GetRecord(key(*n), reply);
+                    due.push((Op::Get, request, answer));
+                }
+            }
+        }
+        for (op, request, answer) in due {
+            let submitted = Instant::now();
+            // Waits while the inbox is full
+            if handle.api(request).await.is_err() {
+                return;
+            }
+            progress.lock().unwrap().running += 1;
+            answers.push(
+                async move { (op, submitted, answer.await.ok()) }.boxed(),
+            );
+        }
+    }
+}
+
+fn key(n: usize) -> Key {
+    Key::new(&key_name(n))
+}
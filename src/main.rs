@@ -1,218 +1,799 @@
 use async_std::{io, task};
-use futures::prelude::*;
-use libp2p::{
-    build_development_transport, identity,
-    kad::{
-        record::store::MemoryStore, Kademlia, KademliaEvent, PeerRecord,
-        PutRecordOk, QueryResult, Record,
-    },
-    mdns::{Mdns, MdnsEvent},
-    swarm::NetworkBehaviourEventProcess,
-    NetworkBehaviour, PeerId, Swarm,
+use clap::Parser;
+use futures::{channel::mpsc, prelude::*, stream::BoxStream};
+use futures_timer::Delay;
+use libp2p::{swarm::SwarmEvent, PeerId, Swarm};
+use nettest::{
+    api,
+    behaviour::MyBehavior,
+    bench, bootstrap,
+    broadcast::Announcer,
+    cluster, collect,
+    config::{Command, Opts, ScenarioCommand},
+    control, crawl, doctor, experiment,
+    handle::{self, NodeHandle, Submission},
+    handler, limits,
+    load::Load,
+    node::NodeBuilder,
+    output::{self, Output},
+    peerstore, portmap,
+    queue::CommandQueue,
+    redial::StaticPeers,
+    scenario, score, seed, session, simulate, snapshot, soak,
+    transport::TransportKind,
+    tui,
 };
-use nettest::handler;
+use serde_json::json;
 use std::{
+    collections::VecDeque,
+    env,
     error::Error,
+    path::Path,
+    process,
     task::{Context, Poll},
+    time::Duration,
 };
 
+// How long to wait for in-flight queries to finish when shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
 fn main() -> Result<(), Box<dyn Error>> {
-    // Create a new key for this peer's identity
-    let local_key = identity::Keypair::generate_ed25519();
-    let local_peer_id = PeerId::from(local_key.public());
-
-    // Setup up an encrypted, DNS-enabled TCP transport over
-    // the Mplex protocol.
-    // TODO: Replace this with a manual, stable, upgraded transport
-    // like the one constructed in `transport.rs`
-    let transport = build_development_transport(local_key)?;
-
-    // Create a custom network behavior, combining Kademlia and mDNS
-    #[derive(NetworkBehaviour)]
-    struct MyBehavior {
-        kademlia: Kademlia<MemoryStore>,
-        mdns: Mdns, // TODO: Use bootstrapping here as well (for testing)
-    }
-
-    // Start implementing the necessary handlers for `MyBehavior`,
-    // which includes handlers for both mDNS and Kademlia
-    impl NetworkBehaviourEventProcess<MdnsEvent> for MyBehavior {
-        // Called when `mdns` (in a MyBehavior instance) produces an event.
-        fn inject_event(&mut self, event: MdnsEvent) {
-            // If the event is a discovery event (that is, if the event
-            // represents (a) peer(s) getting discovered, then do something with
-            // the IDs of the discovered peers). In this case, "something" is
-            // adding the addresses of those peers to the kademila dht (which
-            // is necessary for the dht to work properly).
-            if let MdnsEvent::Discovered(list_of_peers) = event {
-                // for every peer in the list of the peers that were just
-                // discovered, add that peer's identity information to the
-                // kad dht's list of identities.
-                for (peer_id, multiaddr) in list_of_peers {
-                    // println!(
-                    //     "mDNS: discovered peer {:?} {:?}",
-                    //     &peer_id, &multiaddr
-                    // );
-                    self.kademlia.add_address(&peer_id, multiaddr);
-                }
+    // Parse the command line options
+    let opts = Opts::parse();
+    output::set_terminal(opts.output.open()?);
+
+    // Run a recorded session again
+    if let Some(Command::Replay { file }) = &opts.command {
+        return replay(&opts, file);
+    }
+    let args: Vec<String> = env::args().collect();
+    start_session(&opts, &args)?;
+
+    // Run a whole network inside this process instead of a single node
+    if let Some(nodes) = opts.simulate {
+        return simulate::run(&opts, nodes);
+    }
+
+    match &opts.command {
+        // Run a node that reads commands from the terminal
+        None => run_node(&opts, None, None),
+
+        // Run a node that reads commands from a control socket
+        Some(Command::Daemon { control }) => {
+            run_node(&opts, Some(control), None)
+        }
+
+        // (Taken care of above)
+        Some(Command::Replay { .. }) => Ok(()),
+
+        // Benchmark a simulated network
+        Some(Command::Bench(args)) => bench::run(&opts, args),
+
+        // Soak test a simulated network
+        Some(Command::Soak(args)) => soak::run(&opts, args),
+
+        // Benchmark simulated networks of all sorts
+        Some(Command::Experiment(args)) => experiment::run(&opts, args),
+
+        // Find out who is in a network
+        Some(Command::Crawl(args)) => crawl::run(&opts, args),
+
+        // Check the machine and the network a node would run on
+        Some(Command::Doctor(args)) => doctor::run(&opts, args),
+
+        // Watch a fleet of nodes
+        Some(Command::Collect(args)) => collect::run(args),
+
+        // Run nodes in processes of their own
+        Some(Command::Cluster { command }) => cluster::run(&opts, command),
+
+        // Check how a simulated network behaves, step by step
+        Some(Command::Scenario {
+            command: ScenarioCommand::Run { file },
+        }) => match scenario::run(&opts, file) {
+            Err(err) if err.is::<scenario::Failed>() => {
+                Output::Terminal.error(err.to_string());
+                process::exit(1);
             }
+            result => result,
+        },
+
+        // Don't run a node at all, just talk to one that is running
+        Some(Command::Ctl {
+            control,
+            token,
+            command,
+        }) => {
+            let ok = task::block_on(control::send_command(
+                control,
+                &command.join(" "),
+                token.as_deref(),
+            ))?;
+            if !ok {
+                process::exit(1);
+            }
+            Ok(())
         }
     }
+}
 
-    impl NetworkBehaviourEventProcess<KademliaEvent> for MyBehavior {
-        // Called when `kademila` (in MyBehavior) produces an event.
-        fn inject_event(&mut self, message: KademliaEvent) {
-            // Kademlia DHTs have a few different "messages." A message is just
-            // the type of action that is being acted on the dht, such as getting
-            // a record or storing a record. Simply put, its just an event.
-            match message {
-                // If the event is a `QueryResult`, do something.
-                // A `QueryResult` is an event representing when a query to the
-                // dht has produced a result. Check out libp2p::kad::KademliaEvent
-                // for all the variants. Right now, we only care about the QueryResult
-                // event because that is all that this simple dht needs to support:
-                // putting and retrieving records.
-                KademliaEvent::QueryResult { id, result, stats } => {
-                    // The result here is an enum
-                    // with its own variants representing the types of query results
-                    // that are possible, such as the query being a PUT or a GET.
-                    // There are many things that you can do with a kad dht,
-                    // and queries are simply one of those things
-                    // (and there are different types of them!).
-                    match result {
-                        // If the query was a record being fetched (and it succeeded),
-                        QueryResult::GetRecord(Ok(ok)) => {
-                            // For each record that was fetched in all of the fetched
-                            // records...
-                            for PeerRecord {
-                                record: Record { key, value, .. },
-                                ..
-                            } in ok.records
-                            {
-                                // ... do something with the record (print it, in this case)
-                                println!(
-                                    "kad dht: got record {:?} {:?} with id {:?} and stats {:?}\n",
-                                    std::str::from_utf8(key.as_ref())
-                                        .unwrap(),
-                                    std::str::from_utf8(&value).unwrap(),
-                                    id, stats,
-                                );
-                            }
-                        }
-
-                        // If the query was a record being fetched (and it failed)
-                        QueryResult::GetRecord(Err(err)) => {
-                            eprintln!(
-                                "kad dht: failed to get record: {:?}",
-                                err
-                            );
-                        }
-
-                        // If the query was a record being stored (a put)
-                        QueryResult::PutRecord(Ok(PutRecordOk {
-                            key,
-                        })) => {
-                            println!(
-                                "kad dht: successfully put record {:?}",
-                                std::str::from_utf8(key.as_ref()).unwrap()
-                            );
-                        }
-
-                        // If the query was a record being stored (and it failed)
-                        QueryResult::PutRecord(Err(err)) => {
-                            eprintln!(
-                                "kad dht: failed to put record: {:?}",
-                                err
-                            );
-                        }
-                        _ => {} // We only care about getting and putting
-                    }
-                }
-                _ => {} // We only need to worry about queries to this dht
-            } // end big match
-        } // end method
-    } // end impl
-
-    // The custom network behavior implementation is finished. Now it is time to
-    // use it, which is done by building a `Swarm`.
-
-    // Create a swarm to manage peers and events on those peers.
-    // This manages the entire network as a whole.
-    let mut swarm = {
-        // Create a Kademlia behavior
-        let kademlia = {
-            let store = MemoryStore::new(local_peer_id.clone());
-            Kademlia::new(local_peer_id.clone(), store)
-        };
+// Seed everything that is random (--seed), and start recording the
+// session (--record) that runs with the command line `args`. A session
+// always has a seed, for the replay to run the same way.
+fn start_session(
+    opts: &Opts,
+    args: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let seed = match (opts.seed, &opts.record) {
+        (Some(seed), _) => Some(seed),
+        (None, Some(_)) => Some(rand::random()),
+        (None, None) => None,
+    };
+    if let Some(seed) = seed {
+        seed::set(seed);
+        Output::Terminal.info(format!("Using seed {}", seed));
+    }
+    if let (Some(path), Some(seed)) = (&opts.record, seed) {
+        session::start(path, args, seed).map_err(|err| {
+            format!("--record {}: {}", path.display(), err)
+        })?;
+        Output::Terminal
+            .info(format!("Recording the session to {}", path.display()));
+    }
+    Ok(())
+}
+
+// Run the session that --record recorded in `file` again: with the
+// command line it had (and its seed), on a fresh node or simulation,
+// with the same commands coming in at the same times.
+fn replay(outer: &Opts, file: &Path) -> Result<(), Box<dyn Error>> {
+    let session = session::load(file)
+        .map_err(|err| format!("{}: {}", file.display(), err))?;
+    let mut opts = Opts::try_parse_from(&session.args)?;
+    if let (None, Some(command)) = (opts.simulate, &opts.command) {
+        if !matches!(command, Command::Daemon { .. }) {
+            return Err(format!(
+                "{}: only the sessions of a node or a simulation can be \
+                 replayed",
+                file.display()
+            )
+            .into());
+        }
+    }
+    opts.seed = Some(session.seed);
+    // The replay gets recorded only if this one says so
+    opts.record = outer.record.clone();
+    Output::Terminal.info(format!(
+        "Replaying {} commands over {:?} from {}",
+        session.commands.len(),
+        session.end,
+        file.display()
+    ));
+    if session.skipped > 0 {
+        Output::Terminal.info(format!(
+            "({} api requests only looked, and don't come back)",
+            session.skipped
+        ));
+    }
+    start_session(&opts, &session.args)?;
+    let script = session::script(&session);
+    match opts.simulate {
+        Some(nodes) => simulate::run_with_input(&opts, nodes, script),
+        None => run_node(&opts, None, Some(script)),
+    }
+}
+
+// Run a node until it is shut down. Commands are read from the terminal,
+// or, when a control socket path is given, from clients of that socket
+// (and in a replay, they come from the `script` of the session).
+fn run_node(
+    opts: &Opts,
+    control_path: Option<&Path>,
+    script: Option<BoxStream<'static, io::Result<String>>>,
+) -> Result<(), Box<dyn Error>> {
+    // Set up the swarm: the transport, the behaviour, and the identity
+    // of the node
+    let mut swarm = NodeBuilder::new(opts).build()?;
+    let kinds = opts.transports(TransportKind::Tcp);
 
-        // Create a mdns behavior
-        let mdns = Mdns::new()?;
+    // Start out with a known dataset
+    if let Some(path) = &opts.restore {
+        let snapshot = snapshot::load(path).map_err(|err| {
+            format!("--restore {}: {}", path.display(), err)
+        })?;
+        let stored = swarm.restore(&snapshot);
+        Output::Terminal.info(format!(
+            "Restored {} from {}",
+            snapshot.restored(stored),
+            path.display()
+        ));
+    }
+
+    // Listen on all interfaces, IPv4 and IPv6, and whatever ports the OS
+    // assigns (or, in memory, on any free port), with every transport,
+    // unless told otherwise
+    let listen_addrs = match opts.listen.is_empty() {
+        true => {
+            kinds.iter().flat_map(|kind| kind.listen_addrs()).collect()
+        }
+        false => opts.listen.clone(),
+    };
+    let mut listeners = Vec::new();
+    for addr in listen_addrs {
+        listeners.push(Swarm::listen_on(&mut swarm, addr)?);
+    }
+
+    // The addresses peers can reach us at, which the listeners (behind a
+    // NAT, or in a container) can't tell
+    for addr in &opts.announce {
+        Swarm::add_external_address(&mut swarm, addr.clone());
+        Output::Terminal.info(format!("Announcing {}", addr));
+        if opts.rendezvous_server {
+            Output::Terminal.info(format!(
+                "Rendezvous server at {}/p2p/{}",
+                addr,
+                Swarm::local_peer_id(&swarm)
+            ));
+        }
+    }
+
+    // Get told when the process is asked to stop (Ctrl-C or SIGTERM).
+    // The signal handler runs on its own thread, so it just sends a
+    // message that the future below picks up.
+    let (shutdown_tx, mut shutdown_rx) = mpsc::unbounded();
+    // So does a node once its input is over: stdin closed, or the
+    // session that is replayed ended
+    let input_over = shutdown_tx.clone();
+    ctrlc::set_handler(move || {
+        let _ = shutdown_tx.unbounded_send(());
+    })?;
 
-        // Instantiate the custom network behavior `MyBehavior`
-        let behavior = MyBehavior { kademlia, mdns };
+    // Once shutting down, this is the deadline for in-flight queries
+    let mut shutdown: Option<Delay> = None;
 
-        // Create a new swarm with the transport, behavior, and local peer identity
-        Swarm::new(transport, behavior, local_peer_id)
+    // Commands (from the terminal and control clients) and requests of
+    // the HTTP api all arrive in this inbox
+    let (handle, mut inbox) = NodeHandle::new(opts.inbox_capacity);
+    // LOAD submits its GETs and PUTs there too
+    swarm.load = Load::new(handle.clone());
+    let tokens = opts.auth_tokens()?;
+    // (The socket file is removed when `_control` is dropped, on the
+    // way out of here)
+    let _control = match control_path {
+        Some(path) => {
+            let socket =
+                control::serve(path, handle.clone(), tokens.clone())?;
+            Output::Terminal
+                .info(format!("Accepting commands on {}", path.display()));
+            Some(socket)
+        }
+        None => None,
     };
 
-    // Listen on all interfaces and whatever port the OS assigns
-    Swarm::listen_on(&mut swarm, "/ip4/0.0.0.0/tcp/0".parse()?)?;
+    // With a concurrency limit, commands wait their turn in here
+    let mut queue = opts.command_concurrency.map(CommandQueue::new);
+
+    if let Some(addr) = opts.api_addr {
+        api::serve(addr, handle.clone(), opts.health_min_peers, tokens)?;
+        Output::Terminal
+            .info(format!("Serving the HTTP api on http://{}", addr));
+    }
 
-    let mut printed_listen = false;
+    // The peers to join the network through arrive on this channel,
+    // once their entries are resolved
+    let mut bootstrap = bootstrap::spawn(
+        opts.bootstrap.clone(),
+        Some(opts.bootstrap_interval).filter(|i| !i.is_zero()),
+    );
 
-    // Setup the stdin stream
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    // The peers that announce themselves on the local network arrive on
+    // this channel, with --broadcast-discovery
+    let (announcer, mut announced) = match opts.broadcast_discovery {
+        true => {
+            let port = opts.broadcast_port;
+            let (announcer, announced) = Announcer::spawn(
+                Swarm::local_peer_id(&swarm).clone(),
+                port,
+            )?;
+            Output::Terminal.info(format!(
+                "Announcing this node on UDP port {}",
+                port
+            ));
+            (Some(announcer), Some(announced))
+        }
+        false => (None, None),
+    };
+
+    // The port mapping arrives on this channel, once there is a port to
+    // map
+    let mut port_mapping: Option<
+        mpsc::UnboundedReceiver<portmap::Mapped>,
+    > = None;
+
+    // Dial the static peers, which we stay connected to
+    let mut static_peers =
+        StaticPeers::new(opts.static_peers()?, opts.reconnect_max_backoff);
+    for (peer_id, addr) in static_peers.iter() {
+        swarm.kademlia.add_address(peer_id, addr.clone());
+    }
+    let peer_ids: Vec<PeerId> = static_peers
+        .iter()
+        .map(|(peer_id, _)| peer_id.clone())
+        .collect();
+    for peer_id in peer_ids {
+        if Swarm::dial(&mut swarm, &peer_id).is_err() {
+            redial_later(&mut swarm, &mut static_peers, &peer_id, None);
+        }
+    }
+
+    // Rejoin the network through the peers we knew when we last stopped
+    if let Some(path) = &opts.peerstore_path {
+        let peers = peerstore::load(path).map_err(|err| {
+            format!("--peerstore-path {}: {}", path.display(), err)
+        })?;
+        // Only the ones the filter allows (it may have changed since)
+        let peers: Vec<_> = {
+            let filter = swarm.filter.read().unwrap();
+            peers
+                .into_iter()
+                .map(|(peer_id, mut addrs)| {
+                    addrs.retain(|addr| filter.allows(&peer_id, addr));
+                    (peer_id, addrs)
+                })
+                .filter(|(_, addrs)| !addrs.is_empty())
+                .collect()
+        };
+        for (peer_id, addrs) in &peers {
+            for addr in addrs {
+                swarm.kademlia.add_address(peer_id, addr.clone());
+            }
+        }
+        let sample = peerstore::dial_sample(&peers);
+        for peer_id in &sample {
+            Swarm::dial(&mut swarm, peer_id).ok();
+        }
+        if !peers.is_empty() {
+            Output::Terminal.info(format!(
+                "Loaded {} peers from {}, dialing {} of them",
+                peers.len(),
+                path.display(),
+                sample.len()
+            ));
+            swarm.kademlia.bootstrap().ok();
+        }
+    }
+    let peerstore_path = opts.peerstore_path.clone();
+
+    // Pick up the address book where the last run left it
+    if let Some(path) = &opts.address_book {
+        let n = swarm.book.open(path.clone()).map_err(|err| {
+            format!("--address-book {}: {}", path.display(), err)
+        })?;
+        if n > 0 {
+            Output::Terminal.info(format!(
+                "Loaded {} peers from the address book {}",
+                n,
+                path.display()
+            ));
+        }
+    }
+
+    // Setup the stdin stream (a daemon has no terminal to read from, and
+    // a server doesn't take commands at all), whose lines go to the inbox
+    let mut dashboard = None;
+    let replaying = script.is_some();
+    let stdin = match (control_path, opts.server) {
+        _ if replaying => script,
+        (None, false) if opts.tui => {
+            let (started, lines) = tui::Dashboard::start()?;
+            dashboard = Some(started);
+            Some(lines.map(Ok).boxed())
+        }
+        (None, false) => {
+            Some(io::BufReader::new(io::stdin()).lines().boxed())
+        }
+        _ => None,
+    };
+    let mut stdin =
+        stdin.map(|lines| handle::forward_lines(lines, handle.clone()));
+    // Whether stdin broke (and all of its lines are in the inbox)
+    let mut stdin_closed = false;
+    // The lines of the terminal that a WAIT_PEERS or WAIT_BOOTSTRAP holds
+    // back
+    let mut held: VecDeque<String> = VecDeque::new();
+    drop(handle);
+    if opts.server {
+        Output::Terminal
+            .info("Running as a server (not reading commands from stdin)");
+    }
 
     // Create a future to read lines from stdin. TODO: figure out why this
     // needs to be in a future.
     let handler_future = future::poll_fn(move |cx: &mut Context<'_>| {
-        // We want this to be in a loop so that it will always be reading from stdin
-        loop {
-            // Try to poll the next line from the stdin stream
-            match stdin.try_poll_next_unpin(cx)? {
-                // If stdin received a full line, handle it.
-                Poll::Ready(Some(line)) => {
-                    handler::handle_input_line(&mut swarm.kademlia, line)
+        // Check whether we have been asked to shut down
+        while let Poll::Ready(Some(())) = shutdown_rx.poll_next_unpin(cx) {
+            // A second signal means "stop right now"
+            if shutdown.is_some() {
+                save_peers(&mut swarm, peerstore_path.as_deref());
+                Output::Terminal.info(swarm.summary().to_string());
+                return Poll::Ready(Ok(()));
+            }
+
+            // Stop accepting new connections, and give the queries that
+            // are still running a little while to finish.
+            Output::Terminal
+                .info("Shutting down (send the signal again to quit now)");
+            for listener in &listeners {
+                Swarm::remove_listener(&mut swarm, *listener).ok();
+            }
+            shutdown = Some(Delay::new(SHUTDOWN_TIMEOUT));
+        }
+
+        // Once stdin broke, there is nothing more to come from it
+        if let Some(Poll::Ready(result)) =
+            stdin.as_mut().map(|stdin| stdin.poll_unpin(cx))
+        {
+            result?;
+            stdin = None;
+            stdin_closed = true;
+        }
+
+        // The lines of the terminal go on once a WAIT_PEERS or
+        // WAIT_BOOTSTRAP is over
+        while shutdown.is_none() && !swarm.barriers.is_waiting() {
+            match held.pop_front() {
+                Some(line) => submit_line(
+                    &mut swarm,
+                    &mut queue,
+                    line,
+                    Output::Terminal,
+                ),
+                None => break,
+            }
+        }
+
+        // Run every command and api request that was submitted (unless
+        // shutting down, when we no longer accept any)
+        while shutdown.is_none() {
+            let submission = match inbox.poll_next(cx) {
+                Poll::Ready(Some(submission)) => submission,
+                Poll::Ready(None) | Poll::Pending => break,
+            };
+            session::submitted(&submission);
+            match submission {
+                // The lines of the terminal wait for the barrier
+                Submission::Command(line, Output::Terminal)
+                    if swarm.barriers.is_waiting() || !held.is_empty() =>
+                {
+                    held.push_back(line)
+                }
+                Submission::Command(line, output) => {
+                    submit_line(&mut swarm, &mut queue, line, output)
                 }
+                Submission::Api(request) => {
+                    api::handle_request(&mut swarm, request)
+                }
+            }
+        }
+        // If stdin broke (or the session that is replayed is over)
+        if stdin_closed && held.is_empty() && shutdown.is_none() {
+            match replaying {
+                true => Output::Terminal.info("The replay is over"),
+                false => Output::Terminal.info("stdin closed"),
+            }
+            stdin_closed = false;
+            let _ = input_over.unbounded_send(());
+        }
 
-                // If stdin broke
-                Poll::Ready(None) => panic!("stdin closed"),
+        // Take in the peers that announced themselves
+        while let Some(Poll::Ready(Some((peer_id, addrs)))) =
+            announced.as_mut().map(|rx| rx.poll_next_unpin(cx))
+        {
+            for addr in addrs {
+                swarm.discovered(peer_id.clone(), addr, "broadcast");
+            }
+        }
 
-                // If there is no line that was entered, break out of the loop
-                Poll::Pending => break,
+        // Join the network through the bootstrap peers
+        while let Poll::Ready(Some((entry, peers))) =
+            bootstrap.poll_next_unpin(cx)
+        {
+            let peers = match peers {
+                Ok(peers) => peers,
+                Err(err) => {
+                    Output::Terminal.error(format!("bootstrap: {}", err));
+                    continue;
+                }
+            };
+            if bootstrap::is_dnsaddr(&entry) {
+                Output::Terminal.info(format!(
+                    "bootstrap: {} resolved to {} addresses",
+                    entry,
+                    peers.len()
+                ));
+            }
+            for (peer_id, addr) in peers {
+                if !swarm.filter.read().unwrap().allows(&peer_id, &addr) {
+                    continue;
+                }
+                swarm.notify_discovered(&peer_id, &addr, "bootstrap");
+                swarm.kademlia.add_address(&peer_id, addr.clone());
+                // Dial it again whenever we lose it
+                static_peers.add_bootstrap(peer_id, addr);
             }
+            swarm.kademlia.bootstrap().ok();
         }
 
-        // Poll the swarm
-        match swarm.poll_next_unpin(cx) {
-            // If an event happened on the swarm
-            Poll::Ready(Some(event)) => {
-                println!("AN EVENT IS HAPPENING");
-                println!("{:?}", event);
+        // Announce the address the router maps for us
+        while let Some(Poll::Ready(Some(mapped))) = port_mapping
+            .as_mut()
+            .map(|mapped| mapped.poll_next_unpin(cx))
+        {
+            match mapped {
+                Ok(addr) => {
+                    if !Swarm::external_addresses(&swarm)
+                        .any(|a| a == &addr)
+                    {
+                        Output::Terminal.info(format!(
+                            "Port mapping: reachable at {}",
+                            addr
+                        ));
+                    }
+                    Swarm::add_external_address(&mut swarm, addr);
+                }
+                Err(err) => Output::Terminal.error(err.to_string()),
             }
+        }
 
-            // Idk
-            Poll::Ready(None) => {
-                println!("READY STATUS");
-                return Poll::Ready(Ok(()));
+        // Start the queued commands that may run now
+        if let Some(queue) = &mut queue {
+            loop {
+                let ready = queue.poll(cx);
+                if ready.is_empty() {
+                    break;
+                }
+                for (line, output) in ready {
+                    handler::handle_input_line(&mut swarm, line, output);
+                }
+            }
+        }
+
+        // Dial the static and bootstrap peers we lost, once they are due
+        for (peer_id, addr, attempt) in static_peers.due(cx) {
+            swarm.subscribers.notify(
+                "reconnect_attempt",
+                json!({
+                    "peer_id": peer_id.to_string(),
+                    "address": addr.to_string(),
+                    "attempt": attempt,
+                }),
+            );
+            // Failed dials take addresses out of the routing table
+            swarm.kademlia.add_address(&peer_id, addr);
+            if Swarm::dial(&mut swarm, &peer_id).is_err() {
+                redial_later(
+                    &mut swarm,
+                    &mut static_peers,
+                    &peer_id,
+                    None,
+                );
+            }
+        }
+
+        // Poll the swarm until it has nothing more for us (`next_event`
+        // keeps no state of its own, so it is fine to make a new one each
+        // time)
+        loop {
+            let event = Box::pin(swarm.next_event()).poll_unpin(cx);
+            if let Poll::Ready(event) = &event {
+                swarm.observe(event);
             }
+            match event {
+                // Keep track of the static and bootstrap peers
+                Poll::Ready(SwarmEvent::ConnectionEstablished {
+                    peer_id,
+                    ..
+                }) => {
+                    if let Some((kind, attempts)) =
+                        static_peers.connected(&peer_id)
+                    {
+                        Output::Terminal.info(format!(
+                            "Reconnected to {} peer {} (after {} dials)",
+                            kind, peer_id, attempts
+                        ));
+                        swarm.subscribers.notify(
+                            "reconnected",
+                            json!({
+                                "peer_id": peer_id.to_string(),
+                                "kind": kind.to_string(),
+                                "attempts": attempts,
+                            }),
+                        );
+                    }
+                }
+                Poll::Ready(SwarmEvent::ConnectionClosed {
+                    peer_id,
+                    num_established: 0,
+                    ..
+                }) => redial_later(
+                    &mut swarm,
+                    &mut static_peers,
+                    &peer_id,
+                    None,
+                ),
 
-            // If nothing is happening in the swarm
-            Poll::Pending => {
-                if !printed_listen {
-                    // Just print that the node is listening.
-                    // Couldn't have done this right after Swarm::listen
-                    // because that is non-blocking. (i think)
-                    if let Some(a) = Swarm::listeners(&swarm).next() {
-                        println!("Listening on {:?}", a);
-                        printed_listen = true; // Only print this once
+                Poll::Ready(SwarmEvent::UnreachableAddr {
+                    peer_id,
+                    attempts_remaining: 0,
+                    error,
+                    ..
+                }) => redial_later(
+                    &mut swarm,
+                    &mut static_peers,
+                    &peer_id,
+                    Some(error.to_string()),
+                ),
+
+                Poll::Ready(SwarmEvent::NewListenAddr(addr)) => {
+                    Output::Terminal
+                        .info(format!("Listening on {:?}", addr));
+                    if let Some(announcer) = &announcer {
+                        announcer.set_addrs(handler::listen_addrs(&swarm));
+                    }
+                    if opts.server {
+                        Output::Terminal.info(format!(
+                            "Bootstrap from {}/p2p/{}",
+                            addr,
+                            Swarm::local_peer_id(&swarm)
+                        ));
+                    }
+                    // Map the first port the router could forward to us
+                    if opts.port_mapping && port_mapping.is_none() {
+                        port_mapping = portmap::mappable_port(&addr)
+                            .map(portmap::spawn);
+                    }
+                    // What the clients give to --rendezvous-point
+                    if opts.rendezvous_server {
+                        Output::Terminal.info(format!(
+                            "Rendezvous server at {}/p2p/{}",
+                            addr,
+                            Swarm::local_peer_id(&swarm)
+                        ));
                     }
                 }
+
+                // A host without IPv6 (or IPv4) can't listen on it, but
+                // may well have the other
+                Poll::Ready(SwarmEvent::ListenerClosed {
+                    reason: Err(err),
+                    ..
+                }) => Output::Terminal
+                    .error(format!("A listener failed: {}", err)),
+                Poll::Ready(_) => {}
+
+                // If nothing is happening in the swarm
+                Poll::Pending => break,
             }
         }
+
+        swarm.track_queries();
+        if let Some(dashboard) = &mut dashboard {
+            dashboard.poll(cx, &mut swarm);
+        }
+
+        // Ban the peers that misbehaved too much
+        score::enforce(&mut swarm, cx);
+        limits::enforce(&mut swarm, cx);
+
+        // If shutting down, quit once there is nothing left to wait for
+        if let Some(deadline) = &mut shutdown {
+            let in_flight = swarm.kademlia.iter_queries().count();
+            if in_flight == 0 || deadline.poll_unpin(cx).is_ready() {
+                if in_flight > 0 {
+                    Output::Terminal.info(format!(
+                        "Gave up on {} in-flight queries",
+                        in_flight
+                    ));
+                }
+
+                // The record store only lives in memory, but the routing
+                // table is kept, with --peerstore-path
+                save_peers(&mut swarm, peerstore_path.as_deref());
+                Output::Terminal.info(swarm.summary().to_string());
+                return Poll::Ready(Ok(()));
+            }
+        }
+
         Poll::Pending
     });
 
     // Start handling lines from stdin
     task::block_on(handler_future)
 }
+
+// Run the command `line`, or queue it with --command-concurrency.
+fn submit_line(
+    swarm: &mut Swarm<MyBehavior>,
+    queue: &mut Option<CommandQueue>,
+    line: String,
+    output: Output,
+) {
+    match queue {
+        Some(queue) => queue.push(line, output),
+        None => handler::handle_input_line(swarm, line, output),
+    }
+}
+
+// We lost `peer_id` (or couldn't reach it, because of `error`): if it is
+// a static or bootstrap peer, dial it again after a while.
+fn redial_later(
+    swarm: &mut Swarm<MyBehavior>,
+    static_peers: &mut StaticPeers,
+    peer_id: &PeerId,
+    error: Option<String>,
+) {
+    let redial = match static_peers.lost(peer_id) {
+        Some(redial) => redial,
+        None => return,
+    };
+    Output::Terminal.info(match &error {
+        Some(error) => format!(
+            "Couldn't reach {} peer {} ({}), trying again in {:.1?}",
+            redial.kind, peer_id, error, redial.wait
+        ),
+        None => format!(
+            "Lost {} peer {}, dialing it again in {:.1?}",
+            redial.kind, peer_id, redial.wait
+        ),
+    });
+    swarm.subscribers.notify(
+        "reconnect_scheduled",
+        json!({
+            "peer_id": peer_id.to_string(),
+            "kind": redial.kind.to_string(),
+            "attempt": redial.attempt,
+            "wait_ms": redial.wait.as_millis() as u64,
+            "error": error,
+        }),
+    );
+}
+
+// Save the routing table for the next run, with --peerstore-path (and
+// the address book, with --address-book).
+fn save_peers(swarm: &mut Swarm<MyBehavior>, path: Option<&Path>) {
+    save_book(swarm);
+    let path = match path {
+        Some(path) => path,
+        None => return,
+    };
+    match peerstore::save(path, &swarm.known_peers()) {
+        Ok(n) => Output::Terminal.info(format!(
+            "Saved {} peers to {}",
+            n,
+            path.display()
+        )),
+        Err(err) => Output::Terminal.error(format!(
+            "Couldn't save peers to {}: {}",
+            path.display(),
+            err
+        )),
+    }
+}
+
+fn save_book(swarm: &mut Swarm<MyBehavior>) {
+    let path = match swarm.book.path() {
+        Some(path) => path.display().to_string(),
+        None => return,
+    };
+    match swarm.book.save() {
+        Ok(n) => Output::Terminal.info(format!(
+            "Saved {} peers to the address book {}",
+            n.unwrap_or_default(),
+            path
+        )),
+        Err(err) => Output::Terminal.error(format!(
+            "Couldn't save the address book to {}: {}",
+            path, err
+        )),
+    }
+}
@@ -0,0 +1,115 @@
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::{
+        upgrade::{read_one, write_one},
+        ProtocolName,
+    },
+    request_response::{
+        ProtocolSupport, RequestResponse, RequestResponseCodec,
+        RequestResponseConfig,
+    },
+};
+use std::{io, iter, time::Duration};
+
+// The longest message we take from a peer.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+// The direct messaging protocol: send a peer a line of text, and get an
+// acknowledgement back once it has it. Nothing about it goes through the
+// dht, so it shows whether two peers can really talk to each other.
+pub type Messaging = RequestResponse<MessageCodec>;
+
+// Connections stay open for `idle_timeout` after the last message (or the
+// default 10 seconds).
+pub fn new(idle_timeout: Option<Duration>) -> Messaging {
+    let mut config = RequestResponseConfig::default();
+    if let Some(timeout) = idle_timeout {
+        config.set_connection_keep_alive(timeout);
+    }
+    RequestResponse::new(
+        MessageCodec,
+        iter::once((MessageProtocol, ProtocolSupport::Full)),
+        config,
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageProtocol;
+
+impl ProtocolName for MessageProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/nettest/msg/1.0"
+    }
+}
+
+// An acknowledgement: the peer got the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ack;
+
+// A request is the text, with a length prefix, and a response is an empty
+// frame.
+#[derive(Debug, Clone)]
+pub struct MessageCodec;
+
+#[async_trait]
+impl RequestResponseCodec for MessageCodec {
+    type Protocol = MessageProtocol;
+    type Request = String;
+    type Response = Ack;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &MessageProtocol,
+        io: &mut T,
+    ) -> io::Result<String>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let text =
+            read_one(io, MAX_MESSAGE_SIZE).await.map_err(invalid)?;
+        String::from_utf8(text).map_err(invalid)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &MessageProtocol,
+        io: &mut T,
+    ) -> io::Result<Ack>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_one(io, 0).await.map_err(invalid)?;
+        Ok(Ack)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &MessageProtocol,
+        io: &mut T,
+        text: String,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_one(io, text).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &MessageProtocol,
+        io: &mut T,
+        _: Ack,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_one(io, b"").await
+    }
+}
+
+fn invalid(
+    err: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
@@ -0,0 +1,249 @@
+use crate::toml::{self, Entry, Setting};
+use libp2p::kad::{
+    record::Key, AddProviderOk, GetProvidersOk, PutRecordOk, QueryResult,
+};
+use std::{fmt, fs, path::Path};
+
+// A prefix for all the keys a node uses, so that several experiments can
+// share a dht without stepping on each other's records: with the
+// namespace `exp1`, `PUT foo bar` stores the record under `exp1/foo`.
+// Keys are shown without the prefix again.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Namespace(Option<String>);
+
+impl Namespace {
+    pub fn new(prefix: Option<String>) -> Self {
+        Namespace(prefix.filter(|prefix| !prefix.is_empty()))
+    }
+
+    pub fn prefix(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+
+    // The key that `name` is stored under.
+    pub fn key(&self, name: impl AsRef<[u8]>) -> Key {
+        match &self.0 {
+            Some(prefix) => {
+                let mut key = prefix.as_bytes().to_vec();
+                key.push(b'/');
+                key.extend_from_slice(name.as_ref());
+                Key::new(&key)
+            }
+            None => Key::new(&name.as_ref()),
+        }
+    }
+
+    // The name of a key in this namespace (keys from outside of it are
+    // left as they are).
+    pub fn name<'a>(&self, key: &'a Key) -> &'a [u8] {
+        let key = key.as_ref();
+        match &self.0 {
+            Some(prefix) => key
+                .strip_prefix(prefix.as_bytes())
+                .and_then(|rest| rest.strip_prefix(b"/"))
+                .unwrap_or(key),
+            None => key,
+        }
+    }
+
+    // The name of a key, for printing.
+    pub fn display(&self, key: &Key) -> String {
+        String::from_utf8_lossy(self.name(key)).into_owned()
+    }
+
+    // Take the prefix off the key in a query result, for whoever gets to
+    // see the result.
+    pub fn strip(&self, result: &mut QueryResult) {
+        let key = match result {
+            QueryResult::PutRecord(Ok(PutRecordOk { key }))
+            | QueryResult::StartProviding(Ok(AddProviderOk { key }))
+            | QueryResult::GetProviders(Ok(GetProvidersOk {
+                key, ..
+            })) => key,
+            _ => return,
+        };
+        let name = Key::new(&self.name(key));
+        *key = name;
+    }
+}
+
+// A namespace that the node serves along with others (--namespaces),
+// with how much of the local store its records may take up, so that one
+// experiment can't crowd out the others on a shared fleet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NamespaceQuota {
+    pub name: String,
+    pub max_records: Option<usize>,
+    pub max_bytes: Option<usize>,
+}
+
+impl NamespaceQuota {
+    // Whether `key` is in this namespace.
+    pub fn holds(&self, key: &Key) -> bool {
+        key.as_ref()
+            .strip_prefix(self.name.as_bytes())
+            .is_some_and(|rest| rest.starts_with(b"/"))
+    }
+}
+
+// Read a --namespaces file: a table for every namespace, with its quota
+// (both are optional), like
+//   [exp1]
+//   max_records = 10_000
+//   max_bytes = 50_000_000
+//
+//   [exp2]
+pub fn read_quotas(path: &Path) -> Result<Vec<NamespaceQuota>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    let invalid = |line: usize, why: String| {
+        format!("{}:{}: {}", path.display(), line, why)
+    };
+    let entries =
+        toml::parse(&text).map_err(|err| invalid(err.line, err.why))?;
+    let mut quotas: Vec<NamespaceQuota> = Vec::new();
+    for entry in entries {
+        let Setting {
+            line, key, value, ..
+        } = match entry {
+            Entry::Table(line, name) => {
+                if name.is_empty() || name.contains('/') {
+                    return Err(invalid(
+                        line,
+                        format!(
+                            "{:?} isn't a namespace (it has to be a single \
+                             prefix, without slashes)",
+                            name
+                        ),
+                    ));
+                }
+                if quotas.iter().any(|quota| quota.name == name) {
+                    return Err(invalid(
+                        line,
+                        format!("{} comes up twice", name),
+                    ));
+                }
+                quotas.push(NamespaceQuota {
+                    name,
+                    ..NamespaceQuota::default()
+                });
+                continue;
+            }
+            Entry::Setting(setting) => setting,
+        };
+        let quota = quotas.last_mut().ok_or_else(|| {
+            invalid(line, "expected a [namespace] first".into())
+        })?;
+        let value =
+            value.number().map_err(|err| invalid(line, err))? as usize;
+        match key.as_str() {
+            "max_records" => quota.max_records = Some(value),
+            "max_bytes" => quota.max_bytes = Some(value),
+            other => {
+                return Err(invalid(
+                    line,
+                    format!("unknown setting {:?}", other),
+                ))
+            }
+        }
+    }
+    Ok(quotas)
+}
+
+// How the GETs and PUTs of a namespace went, for /metrics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryCounts {
+    pub puts: u64,
+    pub puts_failed: u64,
+    pub gets: u64,
+    pub gets_failed: u64,
+}
+
+// The namespaces of --namespaces, and the queries of each of them.
+#[derive(Debug, Default)]
+pub struct Namespaces {
+    quotas: Vec<NamespaceQuota>,
+    counts: Vec<QueryCounts>,
+}
+
+impl Namespaces {
+    pub fn new(quotas: Vec<NamespaceQuota>) -> Self {
+        Namespaces {
+            counts: vec![QueryCounts::default(); quotas.len()],
+            quotas,
+        }
+    }
+
+    pub fn quotas(&self) -> &[NamespaceQuota] {
+        &self.quotas
+    }
+
+    pub fn get(&self, name: &str) -> Option<&NamespaceQuota> {
+        self.quotas.iter().find(|quota| quota.name == name)
+    }
+
+    // Count a query of a GET or a PUT of `key` (if it is in one of the
+    // namespaces).
+    pub fn count(&mut self, key: &Key, result: &QueryResult) {
+        let i = match self.quotas.iter().position(|q| q.holds(key)) {
+            Some(i) => i,
+            None => return,
+        };
+        let counts = &mut self.counts[i];
+        match result {
+            QueryResult::PutRecord(put) => {
+                counts.puts += 1;
+                counts.puts_failed += put.is_err() as u64;
+            }
+            QueryResult::GetRecord(get) => {
+                counts.gets += 1;
+                counts.gets_failed += get.is_err() as u64;
+            }
+            _ => {}
+        }
+    }
+
+    // The namespaces, with their queries so far.
+    pub fn counts(
+        &self,
+    ) -> impl Iterator<Item = (&NamespaceQuota, QueryCounts)> {
+        self.quotas.iter().zip(self.counts.iter().copied())
+    }
+}
+
+// What a namespace of --namespaces holds in the local store, and what
+// its queries did, for NS and /metrics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamespaceUsage {
+    pub quota: NamespaceQuota,
+    pub records: usize,
+    pub bytes: usize,
+    // The records the store refused for being over the quota
+    pub refused: u64,
+    pub queries: QueryCounts,
+}
+
+impl fmt::Display for NamespaceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let of = |max: Option<usize>| match max {
+            Some(max) => format!(" of {}", max),
+            None => String::new(),
+        };
+        let q = &self.queries;
+        write!(
+            f,
+            "{}: {}{} records, {}{} bytes, {} refused; {} puts ({} \
+             failed), {} gets ({} failed)",
+            self.quota.name,
+            self.records,
+            of(self.quota.max_records),
+            self.bytes,
+            of(self.quota.max_bytes),
+            self.refused,
+            q.puts,
+            q.puts_failed,
+            q.gets,
+            q.gets_failed
+        )
+    }
+}
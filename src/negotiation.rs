@@ -0,0 +1,109 @@
+use libp2p::{core::ConnectedPoint, identify::IdentifyInfo, PeerId};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+// What the transport negotiated on the last connection to a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    // Whether the connection went through the private network handshake
+    // (--psk) first
+    pub pnet: bool,
+    pub security: &'static str,
+    pub muxer: &'static str,
+    // Whether we dialed the connection, or the peer did
+    pub dialer: bool,
+}
+
+impl fmt::Display for Negotiated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.pnet {
+            write!(f, "pnet, ")?;
+        }
+        write!(f, "{}, {}", self.security, self.muxer)?;
+        match self.dialer {
+            true => write!(f, " (dialed)"),
+            false => write!(f, " (accepted)"),
+        }
+    }
+}
+
+// What a peer told us about itself over identify.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identified {
+    pub agent: String,
+    pub protocol_version: String,
+    // The protocols it speaks, with their versions (like
+    // /ipfs/kad/1.0.0)
+    pub protocols: Vec<String>,
+}
+
+impl From<&IdentifyInfo> for Identified {
+    fn from(info: &IdentifyInfo) -> Self {
+        let mut protocols = info.protocols.clone();
+        protocols.sort();
+        Identified {
+            agent: info.agent_version.clone(),
+            protocol_version: info.protocol_version.clone(),
+            protocols,
+        }
+    }
+}
+
+// Which stack each peer we connected to ended up with: the security
+// protocol and the muxer the transport negotiated (with the pnet
+// handshake, or not), and what identify says about its versions. For
+// interop tests between nodes of different versions (or
+// implementations), to tell which combinations really got exercised.
+// What a peer negotiated last is kept after it disconnects.
+#[derive(Debug, Default)]
+pub struct Negotiations {
+    peers: Mutex<HashMap<PeerId, Negotiated>>,
+}
+
+// The transport records the negotiations, and the behaviour reports
+// them, so they are shared.
+pub type SharedNegotiations = Arc<Negotiations>;
+
+impl Negotiations {
+    // A connection to `peer` came up, with these protocols.
+    pub fn record(
+        &self,
+        peer: &PeerId,
+        endpoint: &ConnectedPoint,
+        pnet: bool,
+        security: &'static str,
+        muxer: &'static str,
+    ) {
+        let negotiated = Negotiated {
+            pnet,
+            security,
+            muxer,
+            dialer: endpoint.is_dialer(),
+        };
+        self.peers.lock().unwrap().insert(peer.clone(), negotiated);
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<Negotiated> {
+        self.peers.lock().unwrap().get(peer).cloned()
+    }
+
+    // How many peers negotiated each combination of security protocol
+    // and muxer, the most common first.
+    pub fn combinations(
+        &self,
+    ) -> Vec<((&'static str, &'static str), usize)> {
+        let mut counts: HashMap<(&'static str, &'static str), usize> =
+            HashMap::new();
+        for negotiated in self.peers.lock().unwrap().values() {
+            *counts
+                .entry((negotiated.security, negotiated.muxer))
+                .or_default() += 1;
+        }
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts
+    }
+}
@@ -0,0 +1,197 @@
+use crate::{
+    adversary::{self, Adversary},
+    behaviour::MyBehaviorWith,
+    config::Opts,
+    eventlog::EventLog,
+    extension::{Extension, ExtensionEvent},
+    filter::PeerFilter,
+    limits,
+    output::Output,
+    seed,
+    transport::{self, Runtime, TransportKind},
+};
+use libp2p::{
+    identity::Keypair,
+    mdns::Mdns,
+    swarm::{DummyBehaviour, NetworkBehaviour},
+    PeerId, Swarm,
+};
+use std::{collections::HashSet, error::Error};
+
+// Sets up the swarm of a node from its options, the way the `nettest`
+// binary does, for programs that embed a node. With `with_behaviour`,
+// they can run a behaviour of their own in it: its events go to the
+// subscribers of the node (`swarm.subscribers.add`), next to the node's
+// own.
+pub struct NodeBuilder<B: NetworkBehaviour = DummyBehaviour> {
+    opts: Opts,
+    keypair: Option<Keypair>,
+    runtime: Runtime,
+    extension: Extension<B>,
+}
+
+impl NodeBuilder {
+    pub fn new(opts: &Opts) -> Self {
+        NodeBuilder {
+            opts: opts.clone(),
+            keypair: None,
+            runtime: Runtime::default(),
+            extension: Extension::default(),
+        }
+    }
+}
+
+impl<B: NetworkBehaviour> NodeBuilder<B> {
+    // Use `keypair` as the identity of the node, instead of a new one
+    // (or, for an adversary, one close to its target).
+    pub fn keypair(mut self, keypair: Keypair) -> Self {
+        self.keypair = Some(keypair);
+        self
+    }
+
+    // Run the connections of the node on `runtime` (async-std, unless
+    // built with the `tokio` feature and told otherwise). The swarm has
+    // to be polled in a task of that runtime.
+    pub fn runtime(mut self, runtime: Runtime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    // Run `behaviour` in the node (instead of whatever was there before).
+    // `events` turns its events into those of the node, or drops them.
+    pub fn with_behaviour<C: NetworkBehaviour>(
+        self,
+        behaviour: C,
+        events: impl FnMut(C::OutEvent) -> Option<ExtensionEvent>
+            + Send
+            + 'static,
+    ) -> NodeBuilder<C> {
+        NodeBuilder {
+            opts: self.opts,
+            keypair: self.keypair,
+            runtime: self.runtime,
+            extension: Extension::new(behaviour, Box::new(events)),
+        }
+    }
+
+    // Build the swarm. It doesn't listen anywhere yet.
+    pub fn build(
+        self,
+    ) -> Result<Swarm<MyBehaviorWith<B>>, Box<dyn Error>> {
+        let opts = &self.opts;
+
+        // Create a new key for this peer's identity (an adversary takes
+        // one close to its target)
+        let target = opts
+            .target
+            .as_deref()
+            .filter(|_| opts.adversary.is_some())
+            .map(|target| {
+                adversary::target_bytes(target, &opts.namespace())
+            });
+        let local_key = match (self.keypair, &target) {
+            (Some(key), _) => key,
+            (None, Some(target)) => adversary::grind(target, 1).remove(0),
+            (None, None) => seed::keypair(),
+        };
+        let local_peer_id = PeerId::from(local_key.public());
+
+        // Read the swarm key (if any), and everything else the transport
+        // needs to know
+        let mut config = opts.transport_config()?;
+        config.runtime = self.runtime;
+        if let Some(psk) = &config.psk {
+            Output::Terminal.info(format!(
+                "Using private network with key fingerprint {}",
+                psk.fingerprint()
+            ));
+        }
+        if let Some(shape) = &config.shape {
+            Output::Terminal
+                .info(format!("Shaping connections to {}", shape));
+        }
+        if let Some(chaos) = &config.chaos {
+            Output::Terminal.info(format!("Injecting chaos: {}", chaos));
+        }
+        if let Some(faults) = &opts.fault {
+            Output::Terminal.info(format!("Injecting faults: {}", faults));
+        }
+
+        // What the behaviour does to values (which may involve signing
+        // them with our key). It reports the traffic the transport
+        // counts.
+        let mut behaviour_config = opts.behaviour_config(&local_key);
+        behaviour_config.bandwidth = config.bandwidth.clone();
+        behaviour_config.negotiations = config.negotiations.clone();
+        behaviour_config.event_log =
+            opts.event_log.as_deref().map(EventLog::open).transpose()?;
+        behaviour_config.namespaces = opts.namespace_quotas()?;
+        behaviour_config.expiry = opts.expiry_config(None)?;
+        behaviour_config.capture =
+            opts.capture.as_deref().map(EventLog::open).transpose()?;
+
+        // Build the allow/deny rules. The transport uses these to refuse
+        // connections, and the behaviour uses them to ignore discovered
+        // peers.
+        let filter = PeerFilter::new(&opts.allow, &opts.deny).shared();
+
+        // Setup up an encrypted, DNS-enabled TCP (or websocket) transport
+        // over the yamux (or mplex) protocol, or the in-memory one, which
+        // doesn't touch the network, or several of them. There is no
+        // relay, and so no hole punching (DCUtR) between NATed nodes:
+        // libp2p 0.22 has neither.
+        let kinds = opts.transports(TransportKind::Tcp);
+        let transport = transport::build_transport(
+            &kinds,
+            local_key,
+            config,
+            filter.clone(),
+        )?;
+
+        // Create a mdns behavior (which needs a real network), unless it
+        // would only fill the routing table with unrelated peers. It
+        // queries every 20 seconds, with a TTL of 5 minutes, which
+        // libp2p-mdns 0.20 doesn't let us change.
+        let networked =
+            kinds.iter().any(|kind| *kind != TransportKind::Memory);
+        let mdns = match networked {
+            true if !opts.no_mdns => Some(Mdns::new()?),
+            true => {
+                Output::Terminal.info("mDNS discovery is off");
+                None
+            }
+            false => None,
+        };
+
+        // Create a swarm to manage peers and events on those peers.
+        // This manages the entire network as a whole.
+        let behaviour = MyBehaviorWith::with_extension(
+            local_peer_id.clone(),
+            mdns,
+            filter,
+            behaviour_config,
+            self.extension,
+        );
+        let mut swarm = limits::build_swarm(
+            transport,
+            behaviour,
+            local_peer_id,
+            &opts.connection_limits(),
+            self.runtime,
+        );
+
+        // On its own, an adversary has no allies to point lookups to
+        if let Some(kind) = opts.adversary {
+            Output::Terminal.info(format!(
+                "Acting as an {} adversary of {}",
+                kind,
+                opts.target.as_deref().unwrap_or_default()
+            ));
+            swarm.kademlia.set_adversary(Some(Adversary {
+                kind,
+                allies: HashSet::new(),
+            }));
+        }
+        Ok(swarm)
+    }
+}
@@ -0,0 +1,275 @@
+use crate::{negotiation::Identified, peerinfo::AGENT};
+use libp2p::{
+    core::{
+        address_translation, connection::ConnectionId, ConnectedPoint,
+        Multiaddr, PeerId,
+    },
+    identify::{Identify, IdentifyEvent},
+    identity::PublicKey,
+    swarm::{
+        NetworkBehaviour, NetworkBehaviourAction, PollParameters,
+        ProtocolsHandler,
+    },
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    num::NonZeroUsize,
+    task::{Context, Poll},
+};
+
+// How many peers have to see the node at the same address before it
+// counts as an external one, unless told otherwise.
+pub const DEFAULT_CONFIRMATIONS: &str = "3";
+
+// The most addresses to keep track of that aren't confirmed yet (a peer
+// that reports a new one takes its vote from the old one, but peers that
+// went away keep theirs).
+const MAX_CANDIDATES: usize = 32;
+
+// An address enough peers saw the node at.
+#[derive(Debug, Clone)]
+pub struct Confirmed {
+    pub address: Multiaddr,
+    pub peers: usize,
+}
+
+// Identify, and the addresses peers saw the node at. Identify alone has
+// the swarm take every address a single peer reports as an external one,
+// which any peer can get wrong (or lie about). Here they only count as
+// external once `confirmations` different peers agree on one. Before
+// counting, each is translated to the port the node listens on: behind a
+// NAT, a connection we dialed shows up at whatever port the router
+// picked for it, and only the address gets through to us.
+pub struct ObservedAddrs {
+    inner: Identify,
+    confirmations: usize,
+    // The peers that reported each address, and what each of them
+    // reported last
+    candidates: HashMap<Multiaddr, HashSet<PeerId>>,
+    votes: HashMap<PeerId, Multiaddr>,
+    confirmed: Vec<Multiaddr>,
+    // Confirmed addresses the swarm has yet to hear about, and then the
+    // node
+    to_report: VecDeque<Confirmed>,
+    events: VecDeque<Confirmed>,
+    // What each peer said about itself last (see `negotiation`)
+    identified: HashMap<PeerId, Identified>,
+}
+
+impl ObservedAddrs {
+    pub fn new(
+        public_key: PublicKey,
+        confirmations: NonZeroUsize,
+    ) -> Self {
+        ObservedAddrs {
+            // The protocol version other libp2p nodes expect
+            inner: Identify::new(
+                "ipfs/0.1.0".to_string(),
+                AGENT.to_string(),
+                public_key,
+            ),
+            confirmations: confirmations.get(),
+            candidates: HashMap::new(),
+            votes: HashMap::new(),
+            confirmed: Vec::new(),
+            to_report: VecDeque::new(),
+            events: VecDeque::new(),
+            identified: HashMap::new(),
+        }
+    }
+
+    // The addresses that aren't confirmed yet, with how many peers saw
+    // each, the most seen first.
+    pub fn candidates(&self) -> Vec<(Multiaddr, usize)> {
+        let mut candidates: Vec<(Multiaddr, usize)> = self
+            .candidates
+            .iter()
+            .filter(|(addr, _)| !self.confirmed.contains(addr))
+            .map(|(addr, peers)| (addr.clone(), peers.len()))
+            .collect();
+        candidates.sort_by_key(|(_, peers)| std::cmp::Reverse(*peers));
+        candidates
+    }
+
+    pub fn confirmations(&self) -> usize {
+        self.confirmations
+    }
+
+    // What `peer` told us about itself over identify, if it did.
+    pub fn identified(&self, peer: &PeerId) -> Option<&Identified> {
+        self.identified.get(peer)
+    }
+
+    // `peer` saw us at `observed`.
+    fn observe(
+        &mut self,
+        peer: PeerId,
+        observed: &Multiaddr,
+        params: &impl PollParameters,
+    ) {
+        let listening: Vec<Multiaddr> =
+            params.listened_addresses().collect();
+        // With the port we listen on (the same for all the addresses we
+        // listen on, in practice)
+        let address = match listening
+            .iter()
+            .find_map(|listen| address_translation(listen, observed))
+        {
+            Some(address) => address,
+            None => return,
+        };
+        // Peers that reach one of the addresses we listen on anyway
+        // don't tell us anything new
+        if listening.contains(&address) {
+            return;
+        }
+        if let Some(old) = self.votes.insert(peer.clone(), address.clone())
+        {
+            if let Some(peers) = self.candidates.get_mut(&old) {
+                peers.remove(&peer);
+            }
+        }
+        self.candidates.retain(|_, peers| !peers.is_empty());
+        if self.candidates.len() >= MAX_CANDIDATES
+            && !self.candidates.contains_key(&address)
+        {
+            return;
+        }
+        let peers = self.candidates.entry(address.clone()).or_default();
+        peers.insert(peer);
+        let peers = peers.len();
+        if peers >= self.confirmations
+            && !self.confirmed.contains(&address)
+        {
+            self.confirmed.push(address.clone());
+            self.to_report.push_back(Confirmed { address, peers });
+        }
+    }
+}
+
+impl NetworkBehaviour for ObservedAddrs {
+    type ProtocolsHandler =
+        <Identify as NetworkBehaviour>::ProtocolsHandler;
+    type OutEvent = Confirmed;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.inner.inject_connected(peer_id)
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.inner.inject_disconnected(peer_id)
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.inner
+            .inject_connection_established(peer_id, connection, endpoint)
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.inner
+            .inject_connection_closed(peer_id, connection, endpoint)
+    }
+
+    fn inject_event(
+        &mut self,
+        peer_id: PeerId,
+        connection: ConnectionId,
+        event: <Self::ProtocolsHandler as ProtocolsHandler>::OutEvent,
+    ) {
+        self.inner.inject_event(peer_id, connection, event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<
+            <Self::ProtocolsHandler as ProtocolsHandler>::InEvent,
+            Confirmed,
+        >,
+    > {
+        loop {
+            // The swarm first, then whoever listens for the events
+            if let Some(confirmed) = self.events.pop_front() {
+                return Poll::Ready(
+                    NetworkBehaviourAction::GenerateEvent(confirmed),
+                );
+            }
+            if let Some(confirmed) = self.to_report.pop_front() {
+                let address = confirmed.address.clone();
+                self.events.push_back(confirmed);
+                return Poll::Ready(
+                    NetworkBehaviourAction::ReportObservedAddr { address },
+                );
+            }
+            match self.inner.poll(cx, params) {
+                Poll::Ready(NetworkBehaviourAction::GenerateEvent(
+                    IdentifyEvent::Received {
+                        peer_id,
+                        info,
+                        observed_addr,
+                    },
+                )) => {
+                    self.identified
+                        .insert(peer_id.clone(), Identified::from(&info));
+                    self.observe(peer_id, &observed_addr, params)
+                }
+                Poll::Ready(NetworkBehaviourAction::GenerateEvent(_)) => {}
+                // Only the confirmed ones get to the swarm
+                Poll::Ready(
+                    NetworkBehaviourAction::ReportObservedAddr { .. },
+                ) => {}
+                Poll::Ready(NetworkBehaviourAction::DialAddress {
+                    address,
+                }) => {
+                    return Poll::Ready(
+                        NetworkBehaviourAction::DialAddress { address },
+                    )
+                }
+                Poll::Ready(NetworkBehaviourAction::DialPeer {
+                    peer_id,
+                    condition,
+                }) => {
+                    return Poll::Ready(NetworkBehaviourAction::DialPeer {
+                        peer_id,
+                        condition,
+                    })
+                }
+                Poll::Ready(NetworkBehaviourAction::NotifyHandler {
+                    peer_id,
+                    handler,
+                    event,
+                }) => {
+                    return Poll::Ready(
+                        NetworkBehaviourAction::NotifyHandler {
+                            peer_id,
+                            handler,
+                            event,
+                        },
+                    )
+                }
+                Poll::Pending => break,
+            }
+        }
+        Poll::Pending
+    }
+}
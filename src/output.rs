@@ -0,0 +1,212 @@
+use futures::channel::mpsc;
+use serde_json::json;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, LineWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+// Where the output of a command goes. Commands typed into the terminal
+// print their results there, while commands sent over the control socket
+// have their results sent back to the client that sent them. Code that
+// drives nodes itself (like a test) can hand in a sink of its own.
+#[derive(Debug, Clone)]
+pub enum Output {
+    // The sink picked with --output (see `set_terminal`)
+    Terminal,
+    Client(mpsc::UnboundedSender<String>),
+    Sink(Arc<dyn OutputSink>),
+}
+
+impl Output {
+    // An output that collects everything, for reading it back in code.
+    pub fn channel() -> (Self, mpsc::UnboundedReceiver<Message>) {
+        let (tx, rx) = mpsc::unbounded();
+        (Output::Sink(Arc::new(ChannelSink(tx))), rx)
+    }
+
+    // Report something that went well.
+    pub fn info(&self, message: impl Into<String>) {
+        self.show(Message::Info(message.into()));
+    }
+
+    // Report `message`, the way its kind says.
+    pub fn show(&self, message: Message) {
+        match self {
+            Output::Terminal => terminal().show(&message),
+            Output::Client(tx) => {
+                // If the client went away, there is nobody to tell
+                let _ = tx.unbounded_send(match message {
+                    Message::Info(message) => message,
+                    Message::Error(message) => {
+                        format!("{}{}", ERROR_PREFIX, message)
+                    }
+                });
+            }
+            Output::Sink(sink) => sink.show(&message),
+        }
+    }
+
+    pub fn show_all(&self, messages: impl IntoIterator<Item = Message>) {
+        for message in messages {
+            self.show(message);
+        }
+    }
+
+    // Whether nobody is listening anymore (a control client went away).
+    pub fn is_closed(&self) -> bool {
+        match self {
+            Output::Terminal => false,
+            Output::Client(tx) => tx.is_closed(),
+            Output::Sink(sink) => sink.is_closed(),
+        }
+    }
+
+    // Report something that went wrong.
+    pub fn error(&self, message: impl Into<String>) {
+        self.show(Message::Error(message.into()));
+    }
+}
+
+// A line of what a command has to say, before it is shown anywhere.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Info(String),
+    Error(String),
+}
+
+// Lines sent to a control client that start with this are errors.
+pub const ERROR_PREFIX: &str = "error: ";
+
+// Somewhere for results and events to end up.
+pub trait OutputSink: fmt::Debug + Send + Sync {
+    fn show(&self, message: &Message);
+
+    // Whether showing more is pointless, because nobody reads it.
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+// The node's own output, and that of the commands typed into it.
+static TERMINAL: RwLock<Option<Arc<dyn OutputSink>>> = RwLock::new(None);
+
+// Send what is for the terminal to `sink` from now on.
+pub fn set_terminal(sink: Arc<dyn OutputSink>) {
+    *TERMINAL.write().unwrap() = Some(sink);
+}
+
+// Where what is for the terminal goes now.
+pub fn terminal() -> Arc<dyn OutputSink> {
+    TERMINAL
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| Arc::new(PrettySink))
+}
+
+// Lines on stdout, and errors on stderr (the default).
+#[derive(Debug)]
+pub struct PrettySink;
+
+impl OutputSink for PrettySink {
+    fn show(&self, message: &Message) {
+        match message {
+            Message::Info(message) => println!("{}", message),
+            Message::Error(message) => eprintln!("{}", message),
+        }
+    }
+}
+
+// One JSON object per line on stdout, like
+//   {"level": "error", "message": "Invalid peer id"}
+#[derive(Debug)]
+pub struct JsonSink;
+
+impl OutputSink for JsonSink {
+    fn show(&self, message: &Message) {
+        println!("{}", to_json(message));
+    }
+}
+
+fn to_json(message: &Message) -> serde_json::Value {
+    let (level, message) = match message {
+        Message::Info(message) => ("info", message),
+        Message::Error(message) => ("error", message),
+    };
+    json!({ "level": level, "message": message })
+}
+
+// Everything, in order, into a channel.
+#[derive(Debug)]
+pub struct ChannelSink(pub mpsc::UnboundedSender<Message>);
+
+impl OutputSink for ChannelSink {
+    fn show(&self, message: &Message) {
+        let _ = self.0.unbounded_send(message.clone());
+    }
+
+    fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
+}
+
+// Lines appended to a file, as JSON (errors can't go elsewhere there).
+#[derive(Debug)]
+pub struct FileSink(Mutex<LineWriter<File>>);
+
+impl FileSink {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file =
+            OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileSink(Mutex::new(LineWriter::new(file))))
+    }
+}
+
+impl OutputSink for FileSink {
+    fn show(&self, message: &Message) {
+        // Nowhere to report it if that fails
+        let _ = writeln!(self.0.lock().unwrap(), "{}", to_json(message));
+    }
+}
+
+// Which sink --output picks: `pretty`, `json`, or `file:<path>`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkSpec {
+    Pretty,
+    Json,
+    File(PathBuf),
+}
+
+impl SinkSpec {
+    pub fn open(&self) -> io::Result<Arc<dyn OutputSink>> {
+        Ok(match self {
+            SinkSpec::Pretty => Arc::new(PrettySink),
+            SinkSpec::Json => Arc::new(JsonSink),
+            SinkSpec::File(path) => Arc::new(FileSink::open(path)?),
+        })
+    }
+}
+
+impl FromStr for SinkSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(SinkSpec::Pretty),
+            "json" => Ok(SinkSpec::Json),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => {
+                    Ok(SinkSpec::File(path.into()))
+                }
+                _ => Err(format!(
+                    "expected pretty, json or file:<path>, got {:?}",
+                    s
+                )),
+            },
+        }
+    }
+}
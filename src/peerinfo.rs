@@ -0,0 +1,152 @@
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::{kad::record::Key, kad::QueryId, Multiaddr, PeerId};
+use serde_json::{json, Value};
+use std::{
+    collections::HashSet,
+    fmt,
+    task::Context,
+    time::{Duration, Instant},
+};
+
+// How often a node publishes its peer info, unless --peer-info-interval
+// says otherwise.
+pub const DEFAULT_INTERVAL: &str = "10m";
+
+// What the nodes say they are.
+pub const AGENT: &str = concat!("nettest/", env!("CARGO_PKG_VERSION"));
+
+// How long to wait before trying again, while there is nothing to
+// publish yet (or nobody to publish it to).
+const RETRY: Duration = Duration::from_secs(1);
+
+// The key a peer's info is under: `/nettest/peer/<peer id>` (outside of
+// any namespace, so that every node finds it).
+pub fn key_for(peer: &PeerId) -> Key {
+    Key::new(&format!("/nettest/peer/{}", peer))
+}
+
+// What a node publishes about itself, so that others can find out where
+// to dial it through the dht alone (LOOKUP_PEER). The value is JSON,
+// like
+//   {"agent": "nettest/0.1.0", "listen_addrs": ["/ip4/1.2.3.4/tcp/4001"],
+//    "uptime_s": 3600}
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    pub agent: String,
+    pub listen_addrs: Vec<Multiaddr>,
+    // How long the node had been running when it published this
+    pub uptime: Duration,
+}
+
+impl PeerInfo {
+    pub fn to_value(&self) -> Vec<u8> {
+        let addrs: Vec<String> =
+            self.listen_addrs.iter().map(|a| a.to_string()).collect();
+        json!({
+            "agent": self.agent,
+            "listen_addrs": addrs,
+            "uptime_s": self.uptime.as_secs(),
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    pub fn from_value(value: &[u8]) -> Result<Self, String> {
+        let value: Value = serde_json::from_slice(value)
+            .map_err(|err| format!("not JSON: {}", err))?;
+        let agent = value["agent"]
+            .as_str()
+            .ok_or("there is no agent")?
+            .to_string();
+        let listen_addrs = value["listen_addrs"]
+            .as_array()
+            .ok_or("there are no listen_addrs")?
+            .iter()
+            .map(|addr| {
+                addr.as_str()
+                    .and_then(|addr| addr.parse().ok())
+                    .ok_or_else(|| format!("{} isn't an address", addr))
+            })
+            .collect::<Result<_, _>>()?;
+        let uptime =
+            value["uptime_s"].as_u64().ok_or("there is no uptime")?;
+        Ok(PeerInfo {
+            agent,
+            listen_addrs,
+            uptime: Duration::from_secs(uptime),
+        })
+    }
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, up for {:?}", self.agent, self.uptime)
+    }
+}
+
+// Publishes the node's peer info every so often (so that it stays in
+// the dht, and its addresses stay current). The first time is as soon
+// as the node listens and knows some peers.
+#[derive(Debug)]
+pub struct Announcer {
+    interval: Option<Duration>,
+    timer: Option<Delay>,
+    started: Instant,
+    // The puts that haven't finished
+    queries: HashSet<QueryId>,
+}
+
+impl Announcer {
+    // Publish every `interval` (never, without one).
+    pub fn new(interval: Option<Duration>) -> Self {
+        Announcer {
+            interval,
+            timer: interval.map(|_| Delay::new(RETRY)),
+            started: Instant::now(),
+            queries: HashSet::new(),
+        }
+    }
+
+    // Whether it is time to publish. The timer wakes the task once it
+    // is.
+    pub fn due(&mut self, cx: &mut Context<'_>) -> bool {
+        let (timer, interval) = match (&mut self.timer, self.interval) {
+            (Some(timer), Some(interval)) => (timer, interval),
+            _ => return false,
+        };
+        if timer.poll_unpin(cx).is_pending() {
+            return false;
+        }
+        *timer = Delay::new(interval);
+        let _ = timer.poll_unpin(cx);
+        true
+    }
+
+    // There was nothing to publish: try again shortly, instead of after
+    // a whole interval.
+    pub fn retry_soon(&mut self, cx: &mut Context<'_>) {
+        if let Some(timer) = &mut self.timer {
+            *timer = Delay::new(RETRY);
+            let _ = timer.poll_unpin(cx);
+        }
+    }
+
+    // The info to publish, as of now.
+    pub fn info(&self, listen_addrs: Vec<Multiaddr>) -> PeerInfo {
+        PeerInfo {
+            agent: AGENT.to_string(),
+            listen_addrs,
+            uptime: self.started.elapsed(),
+        }
+    }
+
+    pub fn add_query(&mut self, query: QueryId) {
+        self.queries.insert(query);
+    }
+
+    // Whether `query` was one of the puts (it isn't anymore, after).
+    pub fn finish(&mut self, query: &QueryId) -> bool {
+        self.queries.remove(query)
+    }
+}
@@ -0,0 +1,98 @@
+use crate::seed;
+use libp2p::{Multiaddr, PeerId};
+use rand::seq::SliceRandom;
+use serde_json::{json, Value};
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+// How many of the saved peers to dial at startup (kademlia's bootstrap
+// then finds the rest).
+pub const DIAL_SAMPLE: usize = 8;
+
+// The routing table, saved at shutdown (with --peerstore-path) so that a
+// restarted node rejoins the DHT right away: one JSON object per peer and
+// line, like
+//   {"peer": "12D3Koo...", "addrs": ["/ip4/10.0.0.2/tcp/4001"]}
+
+// Write `peers` to `path` (through a temporary file, so that a crash
+// halfway doesn't lose the last one). Returns how many there were.
+pub fn save(
+    path: &Path,
+    peers: &[(PeerId, Vec<Multiaddr>)],
+) -> io::Result<usize> {
+    let partial = path.with_extension("partial");
+    let mut file = BufWriter::new(File::create(&partial)?);
+    let mut count = 0;
+    for (peer, addrs) in peers {
+        if addrs.is_empty() {
+            continue;
+        }
+        let line = json!({
+            "peer": peer.to_string(),
+            "addrs": addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+        });
+        writeln!(file, "{}", line)?;
+        count += 1;
+    }
+    file.flush()?;
+    drop(file);
+    fs::rename(&partial, path)?;
+    Ok(count)
+}
+
+// Read the peers saved at `path`, if anything was saved yet.
+pub fn load(path: &Path) -> io::Result<Vec<(PeerId, Vec<Multiaddr>)>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(Vec::new())
+        }
+        Err(err) => return Err(err),
+    };
+    let mut peers = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let peer = parse_peer(&line).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", n + 1, err),
+            )
+        })?;
+        peers.push(peer);
+    }
+    Ok(peers)
+}
+
+// The peers to dial at startup, picked at random.
+pub fn dial_sample(peers: &[(PeerId, Vec<Multiaddr>)]) -> Vec<PeerId> {
+    peers
+        .choose_multiple(&mut seed::rng(), DIAL_SAMPLE)
+        .map(|(peer, _)| peer.clone())
+        .collect()
+}
+
+fn parse_peer(line: &str) -> Result<(PeerId, Vec<Multiaddr>), String> {
+    let line: Value =
+        serde_json::from_str(line).map_err(|err| err.to_string())?;
+    let peer = line["peer"].as_str().ok_or("expected a peer")?;
+    let peer = peer
+        .parse::<PeerId>()
+        .map_err(|_| format!("invalid peer id {:?}", peer))?;
+    let addrs = line["addrs"]
+        .as_array()
+        .ok_or("expected a list of addresses")?
+        .iter()
+        .map(|addr| {
+            let addr = addr.as_str().ok_or("addresses should be text")?;
+            addr.parse::<Multiaddr>()
+                .map_err(|_| format!("invalid address {:?}", addr))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((peer, addrs))
+}
@@ -0,0 +1,198 @@
+use crate::seed;
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite, FutureExt};
+use futures_timer::Delay;
+use libp2p::{
+    core::{
+        upgrade::{read_one, write_one},
+        ProtocolName,
+    },
+    request_response::{
+        ProtocolSupport, RequestResponse, RequestResponseCodec,
+        RequestResponseConfig,
+    },
+    Multiaddr, PeerId,
+};
+use rand::seq::SliceRandom;
+use serde_json::{json, Value};
+use std::{io, iter, task::Context, time::Duration};
+
+// How many peers go into one exchange.
+const SAMPLE_SIZE: usize = 16;
+
+// The biggest sample we take from a peer.
+const MAX_SAMPLE_SIZE: usize = 64 * 1024;
+
+// Peer exchange (with --pex-interval): every so often, each connected
+// peer gets a random sample of the peers in our routing table, and
+// answers with a sample of its own. Both sides add what they hear to
+// kademlia, so nodes find each other without mDNS or a bootstrap node
+// that knows everybody.
+pub type Pex = RequestResponse<PexCodec>;
+
+// Peers, and their addresses.
+pub type Sample = Vec<(PeerId, Vec<Multiaddr>)>;
+
+// Connections stay open for `idle_timeout` after the last exchange (or
+// the default 10 seconds).
+pub fn new(idle_timeout: Option<Duration>) -> Pex {
+    let mut config = RequestResponseConfig::default();
+    if let Some(timeout) = idle_timeout {
+        config.set_connection_keep_alive(timeout);
+    }
+    RequestResponse::new(
+        PexCodec,
+        iter::once((PexProtocol, ProtocolSupport::Full)),
+        config,
+    )
+}
+
+// Pick at most SAMPLE_SIZE of `peers` at random, leaving out `to` (who
+// knows about itself).
+pub fn sample(peers: &[(PeerId, Vec<Multiaddr>)], to: &PeerId) -> Sample {
+    let others = peers
+        .iter()
+        .filter(|(peer, addrs)| peer != to && !addrs.is_empty())
+        .collect::<Vec<_>>();
+    others
+        .choose_multiple(&mut seed::rng(), SAMPLE_SIZE)
+        .map(|&peer| peer.clone())
+        .collect()
+}
+
+// When it is time for the next round of exchanges.
+#[derive(Debug)]
+pub struct PexTimer {
+    interval: Option<Duration>,
+    timer: Option<Delay>,
+}
+
+impl PexTimer {
+    // Exchange peers every `interval` (never, without one).
+    pub fn new(interval: Option<Duration>) -> Self {
+        PexTimer {
+            interval,
+            timer: interval.map(Delay::new),
+        }
+    }
+
+    // Whether it is time for the next round. The timer wakes the task
+    // once it is.
+    pub fn due(&mut self, cx: &mut Context<'_>) -> bool {
+        let (timer, interval) = match (&mut self.timer, self.interval) {
+            (Some(timer), Some(interval)) => (timer, interval),
+            _ => return false,
+        };
+        if timer.poll_unpin(cx).is_pending() {
+            return false;
+        }
+        *timer = Delay::new(interval);
+        let _ = timer.poll_unpin(cx);
+        true
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PexProtocol;
+
+impl ProtocolName for PexProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/nettest/pex/1.0"
+    }
+}
+
+// Samples go both ways as a JSON array, like
+//   [{"peer": "12D3Koo...", "addrs": ["/ip4/1.2.3.4/tcp/4001"]}]
+// with a length prefix. Entries that don't parse are left out.
+#[derive(Debug, Clone)]
+pub struct PexCodec;
+
+#[async_trait]
+impl RequestResponseCodec for PexCodec {
+    type Protocol = PexProtocol;
+    type Request = Sample;
+    type Response = Sample;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &PexProtocol,
+        io: &mut T,
+    ) -> io::Result<Sample>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_sample(io).await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &PexProtocol,
+        io: &mut T,
+    ) -> io::Result<Sample>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_sample(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &PexProtocol,
+        io: &mut T,
+        sample: Sample,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_one(io, encode(&sample)).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &PexProtocol,
+        io: &mut T,
+        sample: Sample,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_one(io, encode(&sample)).await
+    }
+}
+
+async fn read_sample<T>(io: &mut T) -> io::Result<Sample>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let frame = read_one(io, MAX_SAMPLE_SIZE)
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let entries: Vec<Value> = serde_json::from_slice(&frame)?;
+    Ok(entries.iter().filter_map(decode_entry).collect())
+}
+
+fn decode_entry(entry: &Value) -> Option<(PeerId, Vec<Multiaddr>)> {
+    let peer = entry["peer"].as_str()?.parse().ok()?;
+    let addrs = entry["addrs"]
+        .as_array()?
+        .iter()
+        .filter_map(|addr| addr.as_str()?.parse().ok())
+        .collect();
+    Some((peer, addrs))
+}
+
+fn encode(sample: &[(PeerId, Vec<Multiaddr>)]) -> Vec<u8> {
+    let entries = sample
+        .iter()
+        .map(|(peer, addrs)| {
+            json!({
+                "peer": peer.to_string(),
+                "addrs": addrs
+                    .iter()
+                    .map(|addr| addr.to_string())
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect::<Vec<_>>();
+    Value::Array(entries).to_string().into_bytes()
+}
@@ -0,0 +1,188 @@
+use async_std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    task,
+};
+use futures::channel::mpsc;
+use futures_timer::Delay;
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use std::{fs, time::Duration};
+
+// Where routers take NAT-PMP requests.
+const NAT_PMP_PORT: u16 = 5351;
+
+// How long a mapping lasts before it has to be renewed (the lifetime the
+// RFC recommends).
+const LIFETIME: Duration = Duration::from_secs(2 * 60 * 60);
+
+// How long to wait for the first answer. Every retry waits twice as long.
+const FIRST_TIMEOUT: Duration = Duration::from_millis(250);
+
+// How often to ask before giving up on the router.
+const ATTEMPTS: usize = 5;
+
+// What mapping a port came up with: the address peers outside the NAT
+// reach us at.
+pub type Mapped = Result<Multiaddr, String>;
+
+// Ask the router, with NAT-PMP (RFC 6886), to forward a TCP port on its
+// external address to `port` here, and keep renewing the mapping. Every
+// time the router answers (or doesn't), the outcome arrives on the
+// channel. The first failure ends it.
+pub fn spawn(port: u16) -> mpsc::UnboundedReceiver<Mapped> {
+    let (tx, rx) = mpsc::unbounded();
+    task::spawn(async move {
+        loop {
+            let mapped = map(port).await;
+            let lifetime =
+                mapped.as_ref().ok().map(|(_, lifetime)| *lifetime);
+            let mapped = mapped
+                .map(|(addr, _)| addr)
+                .map_err(|err| format!("port mapping: {}", err));
+            if tx.unbounded_send(mapped).is_err() {
+                return;
+            }
+            match lifetime {
+                // Renew the mapping halfway through its lifetime
+                Some(lifetime) => Delay::new(lifetime / 2).await,
+                None => return,
+            }
+        }
+    });
+    rx
+}
+
+// Whether a listen address is worth mapping: a TCP port on a (private)
+// IPv4 address, which is what routers do NAT for.
+pub fn mappable_port(addr: &Multiaddr) -> Option<u16> {
+    let mut protocols = addr.iter();
+    match (protocols.next(), protocols.next()) {
+        (Some(Protocol::Ip4(ip)), Some(Protocol::Tcp(port)))
+            if ip.is_private() =>
+        {
+            Some(port)
+        }
+        _ => None,
+    }
+}
+
+// The external address of the router, if it answers NAT-PMP requests
+// (for doctor).
+pub async fn external_address() -> io::Result<Ipv4Addr> {
+    let gateway = default_gateway()?;
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket
+        .connect(SocketAddr::new(gateway.into(), NAT_PMP_PORT))
+        .await?;
+    // Version 0, opcode 0
+    let response = ask(&socket, &[0, 0], 12).await?;
+    Ok(Ipv4Addr::new(
+        response[8],
+        response[9],
+        response[10],
+        response[11],
+    ))
+}
+
+// Map `port`, and tell how long the mapping lasts.
+async fn map(port: u16) -> io::Result<(Multiaddr, Duration)> {
+    let gateway = default_gateway()?;
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket
+        .connect(SocketAddr::new(gateway.into(), NAT_PMP_PORT))
+        .await?;
+
+    // The external address: version 0, opcode 0
+    let response = ask(&socket, &[0, 0], 12).await?;
+    let ip = Ipv4Addr::new(
+        response[8],
+        response[9],
+        response[10],
+        response[11],
+    );
+
+    // The mapping: version 0, opcode 2 (TCP), two reserved bytes, the
+    // internal port, the external port we would like and the lifetime
+    let mut request = vec![0, 2, 0, 0];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&(LIFETIME.as_secs() as u32).to_be_bytes());
+    let response = ask(&socket, &request, 16).await?;
+    let external = u16::from_be_bytes([response[10], response[11]]);
+    let lifetime = u32::from_be_bytes([
+        response[12],
+        response[13],
+        response[14],
+        response[15],
+    ]);
+
+    let addr = Multiaddr::from(ip).with(Protocol::Tcp(external));
+    Ok((addr, Duration::from_secs(lifetime.into())))
+}
+
+// Send `request` until the router answers it, and check the answer: it
+// is `len` bytes long, answers the opcode of the request (plus 128) and
+// has a result code of 0.
+async fn ask(
+    socket: &UdpSocket,
+    request: &[u8],
+    len: usize,
+) -> io::Result<Vec<u8>> {
+    let mut timeout = FIRST_TIMEOUT;
+    let mut buf = vec![0; 16];
+    for _ in 0..ATTEMPTS {
+        socket.send(request).await?;
+        let received =
+            match io::timeout(timeout, socket.recv(&mut buf)).await {
+                Ok(received) => received,
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                    timeout *= 2;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+        if received < len || buf[1] != request[1] + 128 {
+            return Err(invalid("malformed NAT-PMP response"));
+        }
+        return match u16::from_be_bytes([buf[2], buf[3]]) {
+            0 => Ok(buf[..len].to_vec()),
+            1 => {
+                Err(invalid("the router doesn't speak NAT-PMP version 0"))
+            }
+            2 => Err(invalid("the router refused to map ports")),
+            3 => Err(invalid("the router has no external address")),
+            4 => Err(invalid("the router is out of ports")),
+            code => Err(invalid(format!("the router answered {}", code))),
+        };
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "the router doesn't answer NAT-PMP requests",
+    ))
+}
+
+// The gateway of the default route, from /proc/net/route (whose columns
+// are the interface, the destination and the gateway, as hex numbers in
+// host byte order).
+fn default_gateway() -> io::Result<Ipv4Addr> {
+    fs::read_to_string("/proc/net/route")?
+        .lines()
+        .skip(1)
+        .find_map(|line| {
+            let mut columns = line.split_whitespace().skip(1);
+            match (columns.next(), columns.next()) {
+                (Some("00000000"), Some(gateway)) => {
+                    let gateway = u32::from_str_radix(gateway, 16).ok()?;
+                    Some(Ipv4Addr::from(gateway.to_le_bytes()))
+                }
+                _ => None,
+            }
+        })
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no default gateway")
+        })
+}
+
+fn invalid(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
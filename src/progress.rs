@@ -0,0 +1,65 @@
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::kad::{QueryId, QueryStats};
+use std::{collections::HashMap, task::Context, time::Duration};
+
+// How long a query runs before it reports how far it got, and how often
+// after that, unless --progress-after and --progress-interval say
+// otherwise.
+pub const DEFAULT_AFTER: &str = "5s";
+pub const DEFAULT_INTERVAL: &str = "5s";
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgressConfig {
+    pub after: Duration,
+    pub interval: Duration,
+}
+
+// The progress of the queries of commands that take long: every so
+// often, each one that has been running for longer than `after` says how
+// many peers it asked, and how many of them answered, or failed to, so
+// far. So a slow GET can be told from a stuck one.
+#[derive(Debug)]
+pub struct Progress {
+    config: ProgressConfig,
+    timer: Delay,
+    // How many peers each query had heard from, at its last report
+    heard: HashMap<QueryId, u32>,
+}
+
+impl Progress {
+    pub fn new(config: ProgressConfig) -> Self {
+        Progress {
+            config,
+            timer: Delay::new(config.interval),
+            heard: HashMap::new(),
+        }
+    }
+
+    pub fn after(&self) -> Duration {
+        self.config.after
+    }
+
+    // Whether it is time to report. The timer wakes the task once it is.
+    pub fn due(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.timer.poll_unpin(cx).is_pending() {
+            return false;
+        }
+        self.timer = Delay::new(self.config.interval);
+        let _ = self.timer.poll_unpin(cx);
+        true
+    }
+
+    // Note where `query` is now. Returns whether it heard from any peer
+    // since its last report (or whether it ever did, at its first).
+    pub fn update(&mut self, query: QueryId, stats: &QueryStats) -> bool {
+        let heard = stats.num_successes() + stats.num_failures();
+        let before = self.heard.insert(query, heard).unwrap_or(0);
+        heard > before
+    }
+
+    // Forget the queries that finished.
+    pub fn retain(&mut self, running: impl Fn(&QueryId) -> bool) {
+        self.heard.retain(|query, _| running(query));
+    }
+}
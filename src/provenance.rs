@@ -0,0 +1,122 @@
+use libp2p::{
+    kad::{
+        handler::{KademliaHandlerEvent, KademliaHandlerIn},
+        QueryId,
+    },
+    PeerId,
+};
+use std::{collections::HashMap, fmt};
+
+// Where the record of a GET came from: the local store, or a peer, and
+// how far down the lookup that peer was.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Provenance {
+    Local,
+    // The peer, and how many hops away it was (1 for the peers of our
+    // own routing table, 2 for the ones they told us about, and so on),
+    // if we know
+    Peer(PeerId, Option<u32>),
+}
+
+impl Provenance {
+    pub fn source(&self) -> &'static str {
+        match self {
+            Provenance::Local => "local",
+            Provenance::Peer(..) => "network",
+        }
+    }
+
+    pub fn hops(&self) -> Option<u32> {
+        match self {
+            Provenance::Local => Some(0),
+            Provenance::Peer(_, hops) => *hops,
+        }
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Provenance::Local => write!(f, "the local store"),
+            Provenance::Peer(peer, Some(1)) => {
+                write!(f, "{} (1 hop)", peer)
+            }
+            Provenance::Peer(peer, Some(hops)) => {
+                write!(f, "{} ({} hops)", peer, hops)
+            }
+            Provenance::Peer(peer, None) => write!(f, "{}", peer),
+        }
+    }
+}
+
+// How many hops away each peer a GET heard of was.
+#[derive(Debug, Default)]
+pub struct Hops(HashMap<PeerId, u32>);
+
+impl Hops {
+    // Where a record that `peer` served came from (None is the local
+    // store, the way kademlia tells).
+    pub fn provenance(&self, peer: Option<&PeerId>) -> Provenance {
+        match peer {
+            Some(peer) => {
+                Provenance::Peer(peer.clone(), self.0.get(peer).copied())
+            }
+            None => Provenance::Local,
+        }
+    }
+}
+
+// The lookup paths of the GETs that are running. Kademlia keeps no track
+// of who led it to which peer, so it is followed here, from the requests
+// going out and the closer peers coming back: the peers kademlia starts
+// with are one hop away, and each peer that an answer names is one hop
+// further than the peer that named it (the first to do so).
+#[derive(Debug, Default)]
+pub struct Paths {
+    queries: HashMap<QueryId, Hops>,
+}
+
+impl Paths {
+    // A message went out to `peer`: a GET asks it, one hop away unless
+    // an earlier answer named it.
+    pub fn sent(
+        &mut self,
+        peer: &PeerId,
+        event: &KademliaHandlerIn<QueryId>,
+    ) {
+        if let KademliaHandlerIn::GetRecord { user_data, .. } = event {
+            let hops = self.queries.entry(*user_data).or_default();
+            hops.0.entry(peer.clone()).or_insert(1);
+        }
+    }
+
+    // A message came in from `peer`: the peers its answer names are one
+    // hop further away.
+    pub fn received(
+        &mut self,
+        peer: &PeerId,
+        event: &KademliaHandlerEvent<QueryId>,
+    ) {
+        let (closer_peers, query) = match event {
+            KademliaHandlerEvent::GetRecordRes {
+                closer_peers,
+                user_data,
+                ..
+            } => (closer_peers, user_data),
+            _ => return,
+        };
+        let hops = match self.queries.get_mut(query) {
+            Some(hops) => hops,
+            None => return,
+        };
+        let next = hops.0.get(peer).copied().unwrap_or(1) + 1;
+        for closer in closer_peers {
+            hops.0.entry(closer.node_id.clone()).or_insert(next);
+        }
+    }
+
+    // The GET is done: what its path was.
+    pub fn finish(&mut self, query: &QueryId) -> Hops {
+        self.queries.remove(query).unwrap_or_default()
+    }
+}
@@ -0,0 +1,100 @@
+use crate::output::{Output, ERROR_PREFIX};
+use futures::{channel::mpsc, StreamExt};
+use std::{
+    collections::VecDeque,
+    task::{Context, Poll},
+};
+
+// The commands of a node run with --command-concurrency: at most `limit`
+// of them run at once, and the others wait their turn, in order. Commands
+// are numbered as they come in, starting at 1, and every line of their
+// output starts with the number (`#3 kad dht: got record ...`), as does
+// the line that says they are done (`#3 done`, or `#3 failed`, if they
+// reported an error).
+//
+// A command counts as running until nothing holds on to its output
+// anymore (the query it started finished, and so on), which is also how
+// control clients know. So a WATCH keeps its place until UNWATCH.
+#[derive(Debug)]
+pub struct CommandQueue {
+    limit: usize,
+    next_seq: u64,
+    waiting: VecDeque<(u64, String, Output)>,
+    running: Vec<Running>,
+}
+
+#[derive(Debug)]
+struct Running {
+    seq: u64,
+    // What the command reports, on its way to `output`
+    replies: mpsc::UnboundedReceiver<String>,
+    output: Output,
+    failed: bool,
+}
+
+impl CommandQueue {
+    pub fn new(limit: usize) -> Self {
+        CommandQueue {
+            limit: limit.max(1),
+            next_seq: 1,
+            waiting: VecDeque::new(),
+            running: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, line: String, output: Output) {
+        self.waiting.push_back((self.next_seq, line, output));
+        self.next_seq += 1;
+    }
+
+    // Pass on what the running commands reported, and hand out the
+    // commands that may start now, with the output to run them with. Run
+    // them, and call this again until it hands out nothing (a command
+    // may be done as soon as it started).
+    pub fn poll(&mut self, cx: &mut Context<'_>) -> Vec<(String, Output)> {
+        self.running.retain_mut(|running| loop {
+            match running.replies.poll_next_unpin(cx) {
+                Poll::Ready(Some(line)) => {
+                    match line.strip_prefix(ERROR_PREFIX) {
+                        Some(error) => {
+                            running.failed = true;
+                            running.output.error(format!(
+                                "#{} {}",
+                                running.seq, error
+                            ));
+                        }
+                        None => running
+                            .output
+                            .info(format!("#{} {}", running.seq, line)),
+                    }
+                }
+                Poll::Ready(None) => {
+                    let outcome =
+                        if running.failed { "failed" } else { "done" };
+                    running
+                        .output
+                        .info(format!("#{} {}", running.seq, outcome));
+                    return false;
+                }
+                Poll::Pending => return true,
+            }
+        });
+
+        let mut ready = Vec::new();
+        while self.running.len() < self.limit {
+            let (seq, line, output) = match self.waiting.pop_front() {
+                Some(command) => command,
+                None => break,
+            };
+            let (tx, replies) = mpsc::unbounded();
+            self.running.push(Running {
+                seq,
+                replies,
+                output,
+                failed: false,
+            });
+            ready.push((line, Output::Client(tx)));
+        }
+        ready
+    }
+}
@@ -0,0 +1,153 @@
+use crate::seed;
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::{Multiaddr, PeerId};
+use rand::Rng;
+use std::{collections::HashMap, fmt, task::Context, time::Duration};
+
+// How long to wait before dialing a peer we lost, at first.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+// The longest to wait between two dials of a peer we lost, unless
+// --reconnect-max-backoff says otherwise.
+pub const DEFAULT_MAX_BACKOFF: &str = "5m";
+
+// How far each wait may be off the doubling, either way, so that the
+// nodes that lost the same peer (when it restarted) don't all dial it
+// again at the same time.
+const JITTER: f64 = 0.25;
+
+// Why we stay connected to a peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    // It is in --peers-file
+    Static,
+    // It is one of the --bootstrap peers
+    Bootstrap,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Static => "static",
+            Kind::Bootstrap => "bootstrap",
+        })
+    }
+}
+
+// The peers of --peers-file and --bootstrap, which we stay connected to:
+// whenever we lose one of them (or fail to reach it), it is dialed again,
+// after a wait that doubles with each try that fails (up to
+// --reconnect-max-backoff). Without it, a long-running node drifts into
+// isolation once its bootstrap peer restarts.
+#[derive(Debug)]
+pub struct StaticPeers {
+    peers: HashMap<PeerId, (Multiaddr, Kind)>,
+    max_backoff: Duration,
+    // How long to wait before the next dial of each peer
+    backoff: HashMap<PeerId, Duration>,
+    // How many dials of each peer there were since it was last connected
+    attempts: HashMap<PeerId, u32>,
+    // The peers that are due to be dialed again
+    timers: HashMap<PeerId, Delay>,
+}
+
+// A dial of a peer we lost, coming up.
+#[derive(Debug, Clone, Copy)]
+pub struct Redial {
+    pub kind: Kind,
+    // The number of the dial since the peer was lost, from 1
+    pub attempt: u32,
+    pub wait: Duration,
+}
+
+impl StaticPeers {
+    pub fn new(
+        peers: Vec<(PeerId, Multiaddr)>,
+        max_backoff: Duration,
+    ) -> Self {
+        StaticPeers {
+            peers: peers
+                .into_iter()
+                .map(|(peer, addr)| (peer, (addr, Kind::Static)))
+                .collect(),
+            max_backoff: max_backoff.max(MIN_BACKOFF),
+            backoff: HashMap::new(),
+            attempts: HashMap::new(),
+            timers: HashMap::new(),
+        }
+    }
+
+    // Stay connected to the bootstrap peer `peer` too (at `addr`, which
+    // may have changed, for those of a /dnsaddr).
+    pub fn add_bootstrap(&mut self, peer: PeerId, addr: Multiaddr) {
+        let entry = self
+            .peers
+            .entry(peer)
+            .or_insert_with(|| (addr.clone(), Kind::Bootstrap));
+        if entry.1 == Kind::Bootstrap {
+            entry.0 = addr;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &Multiaddr)> {
+        self.peers.iter().map(|(peer, (addr, _))| (peer, addr))
+    }
+
+    // We are connected to `peer` (again), so the next time we lose it,
+    // the wait starts over. Returns how many dials it took, if it was
+    // one of ours that we had lost.
+    pub fn connected(&mut self, peer: &PeerId) -> Option<(Kind, u32)> {
+        self.backoff.remove(peer);
+        self.timers.remove(peer);
+        let attempts = self.attempts.remove(peer)?;
+        let (_, kind) = self.peers.get(peer)?;
+        Some((*kind, attempts))
+    }
+
+    // We lost `peer`, or failed to reach it: dial it again later. Gives
+    // the wait (and which dial it is), if `peer` is one of ours.
+    pub fn lost(&mut self, peer: &PeerId) -> Option<Redial> {
+        let (_, kind) = self.peers.get(peer)?;
+        if self.timers.contains_key(peer) {
+            return None;
+        }
+        let backoff = *self.backoff.get(peer).unwrap_or(&MIN_BACKOFF);
+        self.backoff
+            .insert(peer.clone(), (backoff * 2).min(self.max_backoff));
+        let wait = backoff
+            .mul_f64(seed::rng().gen_range(1.0 - JITTER, 1.0 + JITTER));
+        self.timers.insert(peer.clone(), Delay::new(wait));
+        let attempt = self.attempts.entry(peer.clone()).or_insert(0);
+        *attempt += 1;
+        Some(Redial {
+            kind: *kind,
+            attempt: *attempt,
+            wait,
+        })
+    }
+
+    // The peers whose wait is over, their addresses, and which dial of
+    // them this is.
+    pub fn due(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Vec<(PeerId, Multiaddr, u32)> {
+        let due: Vec<PeerId> = self
+            .timers
+            .iter_mut()
+            .filter_map(|(peer, timer)| {
+                Some(peer.clone())
+                    .filter(|_| timer.poll_unpin(cx).is_ready())
+            })
+            .collect();
+        due.into_iter()
+            .filter_map(|peer| {
+                self.timers.remove(&peer);
+                let (addr, _) = self.peers.get(&peer)?.clone();
+                let attempt = *self.attempts.get(&peer).unwrap_or(&1);
+                Some((peer, addr, attempt))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,400 @@
+use crate::{behaviour::MyBehaviorWith, score::Offense};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::{
+        upgrade::{read_one, write_one},
+        ProtocolName,
+    },
+    request_response::{
+        ProtocolSupport, RequestResponse, RequestResponseCodec,
+        RequestResponseConfig, RequestResponseEvent,
+        RequestResponseMessage,
+    },
+    swarm::{NetworkBehaviour as Behaviour, NetworkBehaviourEventProcess},
+    Multiaddr, PeerId,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io, iter,
+    time::{Duration, Instant},
+};
+
+// The biggest request or response there is any point in.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+// How long a registration lasts, unless REGISTER says otherwise.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+
+// The longest a registration may last.
+const MAX_TTL: Duration = Duration::from_secs(72 * 60 * 60);
+
+// The most peers a discovery returns.
+const MAX_PEERS: usize = 1000;
+
+// Discovery beyond the local network: peers register themselves with a
+// rendezvous server (a node started with --rendezvous-server) under a
+// namespace, and ask it for the other peers of a namespace.
+//
+// libp2p 0.22 has no rendezvous protocol, so this is one of our own,
+// like the libp2p one in spirit.
+pub type Rendezvous = RequestResponse<RendezvousCodec>;
+
+// Connections stay open for `idle_timeout` after the last request (or
+// the default 10 seconds).
+pub fn new(idle_timeout: Option<Duration>) -> Rendezvous {
+    let mut config = RequestResponseConfig::default();
+    if let Some(timeout) = idle_timeout {
+        config.set_connection_keep_alive(timeout);
+    }
+    RequestResponse::new(
+        RendezvousCodec,
+        iter::once((RendezvousProtocol, ProtocolSupport::Full)),
+        config,
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct RendezvousProtocol;
+
+impl ProtocolName for RendezvousProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/nettest/rendezvous/1.0"
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    // Put the sender in `namespace`, at these addresses, for `ttl`
+    Register {
+        namespace: String,
+        addrs: Vec<Multiaddr>,
+        ttl: Duration,
+    },
+    // Ask for the peers in `namespace`
+    Discover {
+        namespace: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    // The registration lasts this long
+    Registered(Duration),
+    // The peers in the namespace, and their addresses
+    Peers(Vec<(PeerId, Vec<Multiaddr>)>),
+    // The server doesn't do what was asked (or isn't a server at all)
+    Refused(String),
+}
+
+// The registrations a rendezvous server keeps.
+#[derive(Debug, Default)]
+pub struct Registrations {
+    namespaces:
+        HashMap<String, HashMap<PeerId, (Vec<Multiaddr>, Instant)>>,
+}
+
+impl Registrations {
+    // Answer a request from `peer`.
+    pub fn handle(&mut self, peer: PeerId, request: Request) -> Response {
+        let now = Instant::now();
+        match request {
+            Request::Register {
+                namespace,
+                addrs,
+                ttl,
+            } => {
+                if addrs.is_empty() {
+                    return Response::Refused("no addresses".into());
+                }
+                let ttl = ttl.min(MAX_TTL);
+                self.namespaces
+                    .entry(namespace)
+                    .or_default()
+                    .insert(peer, (addrs, now + ttl));
+                Response::Registered(ttl)
+            }
+            Request::Discover { namespace } => {
+                let peers = match self.namespaces.get_mut(&namespace) {
+                    Some(peers) => peers,
+                    None => return Response::Peers(Vec::new()),
+                };
+                peers.retain(|_, (_, expires)| *expires > now);
+                Response::Peers(
+                    peers
+                        .iter()
+                        .filter(|(p, _)| **p != peer)
+                        .take(MAX_PEERS)
+                        .map(|(p, (addrs, _))| (p.clone(), addrs.clone()))
+                        .collect(),
+                )
+            }
+        }
+    }
+}
+
+// Messages are JSON, with a length prefix.
+#[derive(Debug, Clone)]
+pub struct RendezvousCodec;
+
+#[async_trait]
+impl RequestResponseCodec for RendezvousCodec {
+    type Protocol = RendezvousProtocol;
+    type Request = Request;
+    type Response = Response;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &RendezvousProtocol,
+        io: &mut T,
+    ) -> io::Result<Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let message = read_json(io).await?;
+        let namespace = message["namespace"]
+            .as_str()
+            .ok_or_else(|| invalid("no namespace"))?
+            .to_string();
+        match message["type"].as_str() {
+            Some("register") => Ok(Request::Register {
+                namespace,
+                addrs: addrs_from_json(&message["addrs"]),
+                ttl: Duration::from_secs(
+                    message["ttl"].as_u64().unwrap_or_default(),
+                ),
+            }),
+            Some("discover") => Ok(Request::Discover { namespace }),
+            _ => Err(invalid("unknown rendezvous request")),
+        }
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &RendezvousProtocol,
+        io: &mut T,
+    ) -> io::Result<Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let message = read_json(io).await?;
+        match message["type"].as_str() {
+            Some("registered") => {
+                Ok(Response::Registered(Duration::from_secs(
+                    message["ttl"].as_u64().unwrap_or_default(),
+                )))
+            }
+            Some("peers") => {
+                let peers = message["peers"]
+                    .as_array()
+                    .ok_or_else(|| invalid("no peers"))?
+                    .iter()
+                    .filter_map(|peer| {
+                        let peer_id =
+                            peer["peer_id"].as_str()?.parse().ok()?;
+                        Some((peer_id, addrs_from_json(&peer["addrs"])))
+                    })
+                    .collect();
+                Ok(Response::Peers(peers))
+            }
+            Some("refused") => Ok(Response::Refused(
+                message["reason"].as_str().unwrap_or_default().to_string(),
+            )),
+            _ => Err(invalid("unknown rendezvous response")),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &RendezvousProtocol,
+        io: &mut T,
+        request: Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let message = match request {
+            Request::Register {
+                namespace,
+                addrs,
+                ttl,
+            } => json!({
+                "type": "register",
+                "namespace": namespace,
+                "addrs": addrs_to_json(&addrs),
+                "ttl": ttl.as_secs(),
+            }),
+            Request::Discover { namespace } => {
+                json!({ "type": "discover", "namespace": namespace })
+            }
+        };
+        write_one(io, message.to_string()).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &RendezvousProtocol,
+        io: &mut T,
+        response: Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let message = match response {
+            Response::Registered(ttl) => {
+                json!({ "type": "registered", "ttl": ttl.as_secs() })
+            }
+            Response::Peers(peers) => json!({
+                "type": "peers",
+                "peers": peers
+                    .iter()
+                    .map(|(peer_id, addrs)| json!({
+                        "peer_id": peer_id.to_string(),
+                        "addrs": addrs_to_json(addrs),
+                    }))
+                    .collect::<Vec<_>>(),
+            }),
+            Response::Refused(reason) => {
+                json!({ "type": "refused", "reason": reason })
+            }
+        };
+        write_one(io, message.to_string()).await
+    }
+}
+
+async fn read_json<T>(io: &mut T) -> io::Result<Value>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let message = read_one(io, MAX_MESSAGE_SIZE).await.map_err(invalid)?;
+    serde_json::from_slice(&message).map_err(invalid)
+}
+
+fn addrs_to_json(addrs: &[Multiaddr]) -> Vec<String> {
+    addrs.iter().map(|addr| addr.to_string()).collect()
+}
+
+// Addresses that don't parse are left out.
+fn addrs_from_json(addrs: &Value) -> Vec<Multiaddr> {
+    addrs
+        .as_array()
+        .map(|addrs| {
+            addrs
+                .iter()
+                .filter_map(|addr| addr.as_str()?.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn invalid(
+    err: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+impl<B: Behaviour>
+    NetworkBehaviourEventProcess<RequestResponseEvent<Request, Response>>
+    for MyBehaviorWith<B>
+{
+    // Called when `rendezvous` produces an event: a peer registers with
+    // us or asks who else did, or a server answered us.
+    fn inject_event(
+        &mut self,
+        event: RequestResponseEvent<Request, Response>,
+    ) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request { request, channel },
+            } => {
+                let response = match &mut self.registrations {
+                    Some(registrations) => {
+                        registrations.handle(peer, request)
+                    }
+                    None => Response::Refused(
+                        "not a rendezvous server".to_string(),
+                    ),
+                };
+                self.rendezvous.send_response(channel, response);
+            }
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+            } => {
+                let (namespace, output) =
+                    match self.rendezvous_pending.remove(&request_id) {
+                        Some(pending) => pending,
+                        None => return,
+                    };
+                match response {
+                    Response::Registered(ttl) => output.info(format!(
+                        "rendezvous: registered in {:?} with {} for {:?}",
+                        namespace, peer, ttl
+                    )),
+                    Response::Peers(peers) => {
+                        output.info(format!(
+                            "rendezvous: {} knows {} peers in {:?}",
+                            peer,
+                            peers.len(),
+                            namespace
+                        ));
+                        for (peer_id, addrs) in peers {
+                            output.info(format!(
+                                "rendezvous: discovered peer {} {:?}",
+                                peer_id, addrs
+                            ));
+                            for addr in addrs {
+                                if !self
+                                    .filter
+                                    .read()
+                                    .unwrap()
+                                    .allows(&peer_id, &addr)
+                                {
+                                    continue;
+                                }
+                                self.stats
+                                    .peers_discovered
+                                    .insert(peer_id.clone());
+                                self.notify_discovered(
+                                    &peer_id,
+                                    &addr,
+                                    "rendezvous",
+                                );
+                                self.kademlia.add_address(&peer_id, addr);
+                            }
+                        }
+                    }
+                    Response::Refused(why) => output.error(format!(
+                        "rendezvous: {} refused the request for {:?}: \
+                             {}",
+                        peer, namespace, why
+                    )),
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                self.scores.penalize(&peer, Offense::FailedRequest);
+                if let Some((_, output)) =
+                    self.rendezvous_pending.remove(&request_id)
+                {
+                    output.error(format!(
+                        "rendezvous: failed to reach {}: {:?}",
+                        peer, error
+                    ));
+                }
+            }
+            // The peer that asked will find out on its own
+            RequestResponseEvent::InboundFailure { .. } => {}
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::kad::{QueryId, QueryResult};
+use std::{
+    collections::HashSet,
+    task::Context,
+    time::{Duration, Instant},
+};
+
+// How often a node stores its own records again, unless
+// --republish-interval says otherwise.
+pub const DEFAULT_INTERVAL: &str = "1h";
+
+// Stores the records that this node published again every so often, with
+// a new expiry. Kademlia does republish them (once a day), but it never
+// pushes their expiry back, so they disappear from the publisher's own
+// store after a while, and from the rest of the network with it.
+#[derive(Debug)]
+pub struct Republisher {
+    interval: Option<Duration>,
+    timer: Option<Delay>,
+    // When the timer fires
+    next: Instant,
+    // The puts of the current round
+    queries: HashSet<QueryId>,
+    refreshed: usize,
+    failed: usize,
+}
+
+impl Republisher {
+    // Republish every `interval` (never, without one).
+    pub fn new(interval: Option<Duration>) -> Self {
+        Republisher {
+            interval,
+            timer: interval.map(Delay::new),
+            next: Instant::now() + interval.unwrap_or_default(),
+            queries: HashSet::new(),
+            refreshed: 0,
+            failed: 0,
+        }
+    }
+
+    // Whether it is time for the next round. The timer wakes the task
+    // once it is.
+    pub fn due(&mut self, cx: &mut Context<'_>) -> bool {
+        let (timer, interval) = match (&mut self.timer, self.interval) {
+            (Some(timer), Some(interval)) => (timer, interval),
+            _ => return false,
+        };
+        if timer.poll_unpin(cx).is_pending() {
+            return false;
+        }
+        *timer = Delay::new(interval);
+        self.next = Instant::now() + interval;
+        let _ = timer.poll_unpin(cx);
+        true
+    }
+
+    // Bring the next round `by` closer, as if that much time had passed
+    // (for ADVANCE).
+    pub fn advance(&mut self, by: Duration) {
+        if let Some(timer) = &mut self.timer {
+            let now = Instant::now();
+            self.next = self.next.checked_sub(by).unwrap_or(now);
+            *timer = Delay::new(self.next.saturating_duration_since(now));
+        }
+    }
+
+    pub fn add_query(&mut self, query: QueryId) {
+        self.queries.insert(query);
+    }
+
+    pub fn owns(&self, query: &QueryId) -> bool {
+        self.queries.contains(query)
+    }
+
+    // Count the result of one of the puts. Once the last one of the round
+    // is in, this returns how many records were refreshed, and how many
+    // couldn't be.
+    pub fn finish(
+        &mut self,
+        query: QueryId,
+        result: &QueryResult,
+    ) -> Option<(usize, usize)> {
+        if !self.queries.remove(&query) {
+            return None;
+        }
+        match result {
+            QueryResult::PutRecord(Ok(_)) => self.refreshed += 1,
+            _ => self.failed += 1,
+        }
+        if !self.queries.is_empty() {
+            return None;
+        }
+        let counts = (self.refreshed, self.failed);
+        self.refreshed = 0;
+        self.failed = 0;
+        Some(counts)
+    }
+}
@@ -0,0 +1,182 @@
+use serde_json::{json, Map, Value};
+use std::{
+    fs, io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// How one kind of operation (the PUTs, or the GETs) of a bench or soak
+// run went.
+#[derive(Debug, Clone)]
+pub struct OpResult {
+    pub op: &'static str,
+    pub succeeded: usize,
+    pub failed: usize,
+    // How long the operations took, all together
+    pub elapsed: Duration,
+    // Of every operation that finished, fastest first
+    latencies: Vec<Duration>,
+}
+
+impl OpResult {
+    pub fn new(op: &'static str) -> Self {
+        OpResult {
+            op,
+            succeeded: 0,
+            failed: 0,
+            elapsed: Duration::default(),
+            latencies: Vec::new(),
+        }
+    }
+
+    pub fn count(&mut self, ok: bool, latency: Duration) {
+        if ok {
+            self.succeeded += 1;
+        } else {
+            self.failed += 1;
+        }
+        let at = self.latencies.partition_point(|l| *l <= latency);
+        self.latencies.insert(at, latency);
+    }
+
+    pub fn total(&self) -> usize {
+        self.succeeded + self.failed
+    }
+
+    // The latency that `fraction` of the operations were faster than.
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::default();
+        }
+        let index =
+            ((self.latencies.len() - 1) as f64 * fraction) as usize;
+        self.latencies[index]
+    }
+
+    // The operations per second.
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.total() as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+
+    // The share of the operations that failed.
+    pub fn error_rate(&self) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            n => self.failed as f64 / n as f64,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let ms = |fraction| millis(self.percentile(fraction));
+        json!({
+            "op": self.op,
+            "succeeded": self.succeeded,
+            "failed": self.failed,
+            "error_rate": self.error_rate(),
+            "elapsed_ms": millis(self.elapsed),
+            "ops_per_s": self.throughput(),
+            "latency_ms": {
+                "p50": ms(0.5),
+                "p90": ms(0.9),
+                "p99": ms(0.99),
+                "max": ms(1.0),
+            },
+        })
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// Write the results of a run of `command` (bench or soak) with `params`
+// (a JSON object, like {"nodes": 10}) to `path`, with --results. A path
+// that ends in .json gets one JSON object, with the parameters, when the
+// run finished, and a result per kind of operation. Anything else gets
+// CSV: a row per kind of operation, with the parameters in the first
+// columns.
+pub fn write(
+    path: &Path,
+    command: &str,
+    params: Value,
+    ops: &[OpResult],
+) -> io::Result<()> {
+    let params = match params {
+        Value::Object(params) => params,
+        _ => Map::new(),
+    };
+    let json = path.extension().is_some_and(|ext| ext == "json");
+    let text = if json {
+        let finished = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let results = json!({
+            "command": command,
+            "version": env!("CARGO_PKG_VERSION"),
+            "finished": finished,
+            "params": params,
+            "results": ops.iter().map(OpResult::to_json).collect::<Vec<_>>(),
+        });
+        format!("{:#}\n", results)
+    } else {
+        csv(command, &params, ops)
+    };
+    fs::write(path, text)
+}
+
+fn csv(
+    command: &str,
+    params: &Map<String, Value>,
+    ops: &[OpResult],
+) -> String {
+    let mut header = vec!["command".to_string()];
+    header.extend(params.keys().cloned());
+    header.extend(
+        [
+            "op",
+            "succeeded",
+            "failed",
+            "error_rate",
+            "elapsed_ms",
+            "ops_per_s",
+            "p50_ms",
+            "p90_ms",
+            "p99_ms",
+            "max_ms",
+        ]
+        .iter()
+        .map(|column| column.to_string()),
+    );
+    let mut text = header.join(",");
+    text.push('\n');
+    for op in ops {
+        let mut row = vec![command.to_string()];
+        row.extend(params.values().map(|value| match value {
+            Value::String(s) => s.clone(),
+            value => value.to_string(),
+        }));
+        let ms =
+            |fraction| format!("{:.3}", millis(op.percentile(fraction)));
+        row.extend(vec![
+            op.op.to_string(),
+            op.succeeded.to_string(),
+            op.failed.to_string(),
+            format!("{:.4}", op.error_rate()),
+            format!("{:.1}", millis(op.elapsed)),
+            format!("{:.1}", op.throughput()),
+            ms(0.5),
+            ms(0.9),
+            ms(0.99),
+            ms(1.0),
+        ]);
+        text.push_str(&row.join(","));
+        text.push('\n');
+    }
+    text
+}
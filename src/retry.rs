@@ -0,0 +1,137 @@
+use crate::{api::ApiReply, output::Output};
+use futures::{channel::oneshot, FutureExt};
+use futures_timer::Delay;
+use libp2p::kad::{PutRecordError, QueryId, Quorum, Record};
+use std::{collections::HashMap, task::Context, time::Duration};
+
+// How long to wait before the first retry of a PUT, unless
+// --put-retry-backoff says otherwise. Every retry after it waits twice as
+// long as the one before.
+pub const DEFAULT_BACKOFF: &str = "1s";
+
+// When to try a failed PUT again (--put-retries and the rest).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    // How many times a PUT is tried in all
+    pub max_attempts: u32,
+    pub backoff: Duration,
+    // Only retry PUTs that didn't reach enough peers, not the ones that
+    // timed out
+    pub quorum_only: bool,
+}
+
+impl RetryPolicy {
+    // How long to wait before trying a PUT that failed with `err` on its
+    // `attempt`th try again, if it is to be tried again.
+    fn wait(
+        &self,
+        attempt: u32,
+        err: &PutRecordError,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        if self.quorum_only
+            && !matches!(err, PutRecordError::QuorumFailed { .. })
+        {
+            return None;
+        }
+        Some(self.backoff * 2u32.saturating_pow(attempt - 1))
+    }
+}
+
+// A PUT that failed, waiting to be tried again.
+#[derive(Debug)]
+pub struct Retry {
+    pub record: Record,
+    pub quorum: Quorum,
+    // The number of the try that is coming up
+    pub attempt: u32,
+    pub output: Output,
+    // The api request that waits for it, if it came from the api
+    pub reply: Option<oneshot::Sender<ApiReply>>,
+    timer: Delay,
+}
+
+// The PUTs that may be tried again, and the ones waiting for it.
+#[derive(Debug)]
+pub struct Retries {
+    policy: RetryPolicy,
+    queries: HashMap<QueryId, (Record, Quorum, u32)>,
+    waiting: Vec<Retry>,
+}
+
+impl Retries {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Retries {
+            policy,
+            queries: HashMap::new(),
+            waiting: Vec::new(),
+        }
+    }
+
+    // Keep `record` around (with the `quorum` it is stored at), in case
+    // the PUT `query` (its `attempt`th try) fails.
+    pub fn track(
+        &mut self,
+        query: QueryId,
+        record: Record,
+        quorum: Quorum,
+        attempt: u32,
+    ) {
+        self.queries.insert(query, (record, quorum, attempt));
+    }
+
+    // A PUT finished. Unless it failed (with `err`) and is to be tried
+    // again, that is the end of it. Otherwise this returns its record
+    // and quorum, the number of the next try, and how long to wait for
+    // it.
+    pub fn finish(
+        &mut self,
+        query: &QueryId,
+        err: Option<&PutRecordError>,
+    ) -> Option<(Record, Quorum, u32, Duration)> {
+        let (record, quorum, attempt) = self.queries.remove(query)?;
+        let wait = self.policy.wait(attempt, err?)?;
+        Some((record, quorum, attempt + 1, wait))
+    }
+
+    // Try `record` again (for the `attempt`th time) after `wait`.
+    pub fn schedule(
+        &mut self,
+        record: Record,
+        quorum: Quorum,
+        attempt: u32,
+        wait: Duration,
+        output: Output,
+        reply: Option<oneshot::Sender<ApiReply>>,
+    ) {
+        self.waiting.push(Retry {
+            record,
+            quorum,
+            attempt,
+            output,
+            reply,
+            timer: Delay::new(wait),
+        });
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.policy.max_attempts
+    }
+
+    // The PUTs whose wait is over. The timers wake the task once there
+    // are any.
+    pub fn due(&mut self, cx: &mut Context<'_>) -> Vec<Retry> {
+        let mut due = Vec::new();
+        let mut i = 0;
+        while i < self.waiting.len() {
+            if self.waiting[i].timer.poll_unpin(cx).is_ready() {
+                due.push(self.waiting.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        due
+    }
+}
@@ -0,0 +1,248 @@
+use libp2p::{kad::KademliaEvent, Multiaddr, PeerId};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{borrow::Borrow, collections::VecDeque, fmt, time::Instant};
+
+// How many changes ROUTING remembers.
+const LOG_SIZE: usize = 1000;
+
+// Something that happened to the routing table.
+#[derive(Debug, Clone)]
+pub enum RoutingChange {
+    // A peer went into the table, or its addresses changed. If its bucket
+    // was full, it took the place of another peer.
+    Updated {
+        peer: PeerId,
+        addresses: Vec<Multiaddr>,
+        evicted: Option<PeerId>,
+    },
+    // A peer connected, but we know no address it could be put in the
+    // table with
+    Unroutable {
+        peer: PeerId,
+    },
+    // A peer connected that could go into the table, but nettest doesn't
+    // insert peers by hand
+    Routable {
+        peer: PeerId,
+        address: Multiaddr,
+    },
+    // A peer connected that goes into the table if one of the peers in
+    // its (full) bucket turns out to be gone
+    Pending {
+        peer: PeerId,
+        address: Multiaddr,
+    },
+    // A peer was taken out of the table by hand
+    Removed {
+        peer: PeerId,
+    },
+}
+
+impl RoutingChange {
+    // The change a kademlia event stands for, if it is about the routing
+    // table.
+    pub fn from_event(event: &KademliaEvent) -> Option<Self> {
+        Some(match event {
+            KademliaEvent::RoutingUpdated {
+                peer,
+                addresses,
+                old_peer,
+            } => RoutingChange::Updated {
+                peer: peer.clone(),
+                addresses: addresses.iter().cloned().collect(),
+                evicted: old_peer.clone(),
+            },
+            KademliaEvent::UnroutablePeer { peer } => {
+                RoutingChange::Unroutable { peer: peer.clone() }
+            }
+            KademliaEvent::RoutablePeer { peer, address } => {
+                RoutingChange::Routable {
+                    peer: peer.clone(),
+                    address: address.clone(),
+                }
+            }
+            KademliaEvent::PendingRoutablePeer { peer, address } => {
+                RoutingChange::Pending {
+                    peer: peer.clone(),
+                    address: address.clone(),
+                }
+            }
+            KademliaEvent::QueryResult { .. } => return None,
+        })
+    }
+
+    // The notification the websocket clients get about it.
+    pub fn notification(&self) -> (&'static str, Value) {
+        match self {
+            RoutingChange::Updated {
+                peer,
+                addresses,
+                evicted,
+            } => (
+                "routing_updated",
+                json!({
+                    "peer_id": peer.to_string(),
+                    "addresses": addresses
+                        .iter()
+                        .map(|addr| addr.to_string())
+                        .collect::<Vec<_>>(),
+                    "evicted": evicted.as_ref().map(|p| p.to_string()),
+                }),
+            ),
+            RoutingChange::Unroutable { peer } => {
+                ("unroutable_peer", json!({ "peer_id": peer.to_string() }))
+            }
+            RoutingChange::Routable { peer, address } => (
+                "routable_peer",
+                json!({
+                    "peer_id": peer.to_string(),
+                    "address": address.to_string(),
+                }),
+            ),
+            RoutingChange::Pending { peer, address } => (
+                "pending_routable_peer",
+                json!({
+                    "peer_id": peer.to_string(),
+                    "address": address.to_string(),
+                }),
+            ),
+            RoutingChange::Removed { peer } => {
+                ("routing_removed", json!({ "peer_id": peer.to_string() }))
+            }
+        }
+    }
+}
+
+impl fmt::Display for RoutingChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoutingChange::Updated {
+                peer,
+                addresses,
+                evicted,
+            } => {
+                let addresses: Vec<String> = addresses
+                    .iter()
+                    .map(|addr| addr.to_string())
+                    .collect();
+                write!(f, "{} is in, at {}", peer, addresses.join(", "))?;
+                if let Some(evicted) = evicted {
+                    write!(f, " (instead of {})", evicted)?;
+                }
+                Ok(())
+            }
+            RoutingChange::Unroutable { peer } => {
+                write!(f, "{} connected, but has no known address", peer)
+            }
+            RoutingChange::Routable { peer, address } => {
+                write!(f, "{} could go in, at {}", peer, address)
+            }
+            RoutingChange::Pending { peer, address } => write!(
+                f,
+                "{} waits for room in its bucket, at {}",
+                peer, address
+            ),
+            RoutingChange::Removed { peer } => {
+                write!(f, "{} was removed", peer)
+            }
+        }
+    }
+}
+
+// The latest changes to the routing table, for ROUTING.
+#[derive(Debug, Default)]
+pub struct RoutingLog {
+    changes: VecDeque<(Instant, RoutingChange)>,
+}
+
+impl RoutingLog {
+    pub fn push(&mut self, change: RoutingChange) {
+        if self.changes.len() == LOG_SIZE {
+            self.changes.pop_front();
+        }
+        self.changes.push_back((Instant::now(), change));
+    }
+
+    // The last `n` changes, oldest first.
+    pub fn latest(
+        &self,
+        n: usize,
+    ) -> impl Iterator<Item = &(Instant, RoutingChange)> {
+        self.changes
+            .iter()
+            .skip(self.changes.len().saturating_sub(n))
+    }
+}
+
+// Sort peers by their distance to a key, closest first, and tell how many
+// leading bits each shares with it (the more, the closer). Kademlia
+// measures distances between the sha256 hashes of keys and peer ids.
+pub fn by_distance(key: &[u8], peers: Vec<PeerId>) -> Vec<(PeerId, u32)> {
+    let key = Sha256::digest(key);
+    let mut peers: Vec<(Vec<u8>, PeerId)> = peers
+        .into_iter()
+        .map(|peer| {
+            let hash = Sha256::digest(peer.as_bytes());
+            let distance =
+                key.iter().zip(hash.iter()).map(|(a, b)| a ^ b).collect();
+            (distance, peer)
+        })
+        .collect();
+    peers.sort();
+    peers
+        .into_iter()
+        .map(|(distance, peer)| (peer, leading_zeros(&distance)))
+        .collect()
+}
+
+// How full a k-bucket of the routing table is.
+#[derive(Debug, Clone)]
+pub struct BucketStats {
+    // The peers in bucket i share 255 - i leading bits with us
+    pub index: u32,
+    pub connected: usize,
+    pub disconnected: usize,
+    // Whether a peer waits for a place in the (full) bucket
+    pub pending: bool,
+}
+
+impl BucketStats {
+    pub fn entries(&self) -> usize {
+        self.connected + self.disconnected
+    }
+
+    // The bucket as a bar of `k` places: # for a connected peer, o for
+    // one that isn't, and . for a free place.
+    pub fn bar(&self, k: usize) -> String {
+        let free = k.saturating_sub(self.entries());
+        format!(
+            "{}{}{}",
+            "#".repeat(self.connected),
+            "o".repeat(self.disconnected),
+            ".".repeat(free)
+        )
+    }
+}
+
+// Which bucket of `local`'s routing table `peer` goes in. Kademlia hashes
+// the canonical form of peer ids (the one `Borrow` gives), which isn't
+// always their bytes.
+pub fn bucket_index(local: &PeerId, peer: &PeerId) -> u32 {
+    let local = Sha256::digest(Borrow::<[u8]>::borrow(local));
+    let peer = Sha256::digest(Borrow::<[u8]>::borrow(peer));
+    let distance: Vec<u8> =
+        local.iter().zip(peer.iter()).map(|(a, b)| a ^ b).collect();
+    255u32.saturating_sub(leading_zeros(&distance))
+}
+
+fn leading_zeros(bytes: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in bytes {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
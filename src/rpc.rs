@@ -0,0 +1,251 @@
+use crate::{
+    api::{self, ApiRequest, ApiSender},
+    auth::Access,
+    eventlog::EventLog,
+    session::Recorder,
+};
+use async_std::task;
+use futures::{channel::mpsc, StreamExt};
+use libp2p::kad::record::Key;
+use serde_json::{json, Value};
+use tide::{Request, StatusCode};
+use tide_websockets::{Message, WebSocketConnection};
+
+// JSON-RPC 2.0 over a websocket, served by the HTTP api on `/rpc`.
+//
+// Requests look like
+//   {"jsonrpc": "2.0", "id": 1, "method": "get_record",
+//    "params": {"key": "foo"}}
+// and take the same methods as the REST routes: `get_record` (key),
+// `put_record` (key, value), `provide` (key), `get_providers` (key) and
+// `peers`. Replies can come back in any order, since queries take a while.
+// With a read-only token (see --auth-tokens), `put_record` and `provide`
+// are refused.
+//
+// On top of that, the node pushes notifications (requests without an id)
+// for things that happen on the swarm:
+//   peer_connected    {peer_id, address}
+//   peer_disconnected {peer_id}
+//   connection_established {peer_id, address, direction, connections}
+//   connection_closed {peer_id, address, direction, connections, cause,
+//                      error}
+//   incoming_connection_error {local_address, address, error}
+//   dialing           {peer_id}
+//   dial_failed       {peer_id, address, error, attempts_remaining}
+//   reconnect_scheduled {peer_id, kind, attempt, wait_ms, error}
+//   reconnect_attempt {peer_id, address, attempt}
+//   reconnected       {peer_id, kind, attempts}
+//   record_received   {key, value, from}
+//   peer_discovered   {peer_id, address, via}
+//   query_started     {id, kind, key}
+//   query_finished    {id, kind, ok, stats}
+//   routing_updated   {peer_id, addresses, evicted}
+//   unroutable_peer   {peer_id}
+//   routable_peer     {peer_id, address}
+//   pending_routable_peer {peer_id, address}
+//   peer_culled       {peer_id, silent_ms}
+//   store_gc          {records, providers, took_ms}
+//   record_expiring   {key, expires_in_ms, renew, renewals}
+//   record_expired    {key}
+//   record_renewed    {key, ok, error}
+//   message_received  {peer_id, text}
+pub async fn handle_socket(
+    req: Request<ApiSender>,
+    conn: WebSocketConnection,
+) -> tide::Result<()> {
+    let requests = req.state().clone();
+    // Without --auth-tokens, there is nothing to keep anyone from
+    let access = req.ext::<Access>().copied().unwrap_or(Access::Write);
+
+    // Forward the node's notifications to the client
+    let (events_tx, mut events_rx) = mpsc::unbounded();
+    if requests
+        .api(ApiRequest::Subscribe(events_tx))
+        .await
+        .is_err()
+    {
+        return Ok(());
+    }
+    let events_conn = conn.clone();
+    task::spawn(async move {
+        while let Some(event) = events_rx.next().await {
+            if events_conn.send_string(event).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut incoming = conn.clone();
+    while let Some(message) = incoming.next().await {
+        if let Message::Text(text) = message? {
+            task::spawn(handle_message(
+                requests.clone(),
+                access,
+                conn.clone(),
+                text,
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Answer a single JSON-RPC request.
+async fn handle_message(
+    requests: ApiSender,
+    access: Access,
+    conn: WebSocketConnection,
+    text: String,
+) {
+    let reply = match serde_json::from_str::<Value>(&text) {
+        Ok(request) => {
+            let id = request.get("id").cloned().unwrap_or(Value::Null);
+            match call(&requests, access, &request).await {
+                Ok(result) => {
+                    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+                }
+                Err(error) => {
+                    json!({ "jsonrpc": "2.0", "id": id, "error": error })
+                }
+            }
+        }
+        Err(err) => json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": { "code": -32700, "message": err.to_string() },
+        }),
+    };
+    let _ = conn.send_string(reply.to_string()).await;
+}
+
+// Run the method a request asks for. The error is a JSON-RPC error object.
+async fn call(
+    requests: &ApiSender,
+    access: Access,
+    request: &Value,
+) -> Result<Value, Value> {
+    let method = request.get("method").and_then(Value::as_str);
+    if matches!(method, Some("put_record" | "provide"))
+        && access < Access::Write
+    {
+        return Err(json!({
+            "code": -32001,
+            "message": "this token is read-only",
+        }));
+    }
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+    let param = |name: &str| {
+        params.get(name).and_then(Value::as_str).ok_or_else(|| {
+            json!({
+                "code": -32602,
+                "message": format!("missing string param `{}`", name),
+            })
+        })
+    };
+
+    let (status, body) = match method {
+        Some("get_record") => {
+            let key = Key::new(&param("key")?);
+            api::ask_node(requests, |reply| {
+                ApiRequest::GetRecord(key, reply)
+            })
+            .await
+        }
+        Some("put_record") => {
+            let key = Key::new(&param("key")?);
+            let value = param("value")?.as_bytes().to_vec();
+            api::ask_node(requests, |reply| {
+                ApiRequest::PutRecord(key, value, reply)
+            })
+            .await
+        }
+        Some("provide") => {
+            let key = Key::new(&param("key")?);
+            api::ask_node(requests, |reply| {
+                ApiRequest::Provide(key, reply)
+            })
+            .await
+        }
+        Some("get_providers") => {
+            let key = Key::new(&param("key")?);
+            api::ask_node(requests, |reply| {
+                ApiRequest::GetProviders(key, reply)
+            })
+            .await
+        }
+        Some("peers") => api::ask_node(requests, ApiRequest::Peers).await,
+        _ => {
+            return Err(json!({
+                "code": -32601,
+                "message": "method not found",
+            }))
+        }
+    };
+
+    if status == StatusCode::Ok {
+        Ok(body)
+    } else {
+        let message = body
+            .get("error")
+            .and_then(Value::as_str)
+            .unwrap_or("request failed")
+            .to_string();
+        Err(json!({
+            "code": -32000,
+            "message": message,
+            "data": body,
+        }))
+    }
+}
+
+// The websocket clients that want to hear about swarm events, and the
+// --event-log, which hears about all of them too.
+#[derive(Debug, Default)]
+pub struct Subscribers {
+    clients: Vec<mpsc::UnboundedSender<String>>,
+    log: Option<EventLog>,
+    // The session being recorded (--record)
+    recorder: Option<Recorder>,
+}
+
+impl Subscribers {
+    pub fn add(&mut self, events: mpsc::UnboundedSender<String>) {
+        self.clients.push(events);
+    }
+
+    pub fn set_log(&mut self, log: EventLog) {
+        self.log = Some(log);
+    }
+
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    // Whether anybody hears about events at all.
+    pub fn is_listening(&self) -> bool {
+        !self.clients.is_empty()
+            || self.log.is_some()
+            || self.recorder.is_some()
+    }
+
+    // Push a notification to every client, forgetting the ones that have
+    // gone away.
+    pub fn notify(&mut self, method: &str, params: Value) {
+        if let Some(log) = &self.log {
+            log.write(method, &params);
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.write(method, &params);
+        }
+        if self.clients.is_empty() {
+            return;
+        }
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+        })
+        .to_string();
+        self.clients
+            .retain(|tx| tx.unbounded_send(notification.clone()).is_ok());
+    }
+}
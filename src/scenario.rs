@@ -0,0 +1,633 @@
+use crate::{
+    chaos::parse_duration,
+    config::Opts,
+    output::Output,
+    testnet::{self, Testnet},
+    toml::{self, Entry, Setting, Value},
+};
+use async_std::task;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, fs,
+    num::NonZeroUsize,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+// How long to wait between two tries of a GET that has a while to
+// succeed.
+const RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+// `nettest scenario run conformance.toml`: start a network (inside this
+// process, like --simulate), go through the steps of the file one by
+// one, and stop at the first one that fails, with an exit status of 1.
+// A file looks like
+//
+//   nodes = 10
+//   replication_factor = 3   # optional, like alpha
+//
+//   steps = [
+//     "node 2 PUT k v",
+//     "node 7 GET k expects v within 5s",
+//     "kill node 2",
+//     "GET k still succeeds",
+//     "partition 0 1 2 | 3 4 5 6 7 8 9",
+//     "node 9 PUT other value",
+//     "node 0 GET other expects nothing",
+//     "heal",
+//     "wait 2s",
+//   ]
+//
+// A step without a node runs on the first node that is still running.
+// A GET succeeds if it finds the key (`succeeds` says so explicitly),
+// `expects` a value or `nothing`, and `within` gives it that long to, by
+// trying again. A PUT succeeds if the put does. The other options (like
+// --fault or --seed) apply to the whole network.
+pub fn run(opts: &Opts, path: &Path) -> Result<(), Box<dyn Error>> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    let scenario = Scenario::parse(&text)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    let mut opts = opts.clone();
+    if let Some(k) = scenario.replication_factor {
+        opts.replication_factor = Some(k);
+    }
+    if let Some(alpha) = scenario.alpha {
+        opts.parallelism = Some(alpha);
+    }
+
+    let net = task::block_on(testnet::spawn_with(opts, scenario.nodes))?;
+    Output::Terminal.info(format!(
+        "Started {} nodes for {}",
+        scenario.nodes,
+        path.display()
+    ));
+    let mut killed = HashMap::new();
+    let total = scenario.steps.len();
+    for (i, (line, step)) in scenario.steps.iter().enumerate() {
+        let started = Instant::now();
+        let result = task::block_on(step.run(&net, i + 1, &mut killed));
+        let took = started.elapsed();
+        match result {
+            Ok(()) => Output::Terminal
+                .info(format!("ok   {} ({:.1?})", step, took)),
+            Err(err) => {
+                Output::Terminal.error(format!(
+                    "FAIL {} ({:.1?}): {}",
+                    step, took, err
+                ));
+                return Err(Failed {
+                    step: i + 1,
+                    steps: total,
+                    line: *line,
+                }
+                .into());
+            }
+        }
+    }
+    Output::Terminal.info(format!("All {} steps passed", total));
+    Ok(())
+}
+
+// A step of a scenario failed (and said why).
+#[derive(Debug)]
+pub struct Failed {
+    pub step: usize,
+    pub steps: usize,
+    pub line: usize,
+}
+
+impl fmt::Display for Failed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The scenario failed at step {} of {} (line {})",
+            self.step, self.steps, self.line
+        )
+    }
+}
+
+impl Error for Failed {}
+
+// What a scenario file says.
+#[derive(Debug)]
+struct Scenario {
+    nodes: usize,
+    replication_factor: Option<NonZeroUsize>,
+    alpha: Option<NonZeroUsize>,
+    // The steps, with the number of their line
+    steps: Vec<(usize, Step)>,
+}
+
+// One step of a scenario.
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Put {
+        node: Option<usize>,
+        key: String,
+        value: String,
+    },
+    Get {
+        node: Option<usize>,
+        key: String,
+        expect: Expect,
+        within: Option<Duration>,
+    },
+    Kill(usize),
+    Partition(Vec<Vec<usize>>),
+    Heal,
+    Wait(Duration),
+}
+
+// What a GET has to find.
+#[derive(Debug, Clone, PartialEq)]
+enum Expect {
+    // Any value
+    Found,
+    Value(String),
+    Nothing,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on = |node: &Option<usize>| match node {
+            Some(node) => format!("node {} ", node),
+            None => String::new(),
+        };
+        match self {
+            Step::Put { node, key, value } => {
+                write!(f, "{}PUT {} {}", on(node), key, value)
+            }
+            Step::Get {
+                node,
+                key,
+                expect,
+                within,
+            } => {
+                write!(f, "{}GET {}", on(node), key)?;
+                match expect {
+                    Expect::Found => write!(f, " succeeds")?,
+                    Expect::Value(value) => {
+                        write!(f, " expects {}", value)?
+                    }
+                    Expect::Nothing => write!(f, " expects nothing")?,
+                }
+                match within {
+                    Some(within) => write!(f, " within {:?}", within),
+                    None => Ok(()),
+                }
+            }
+            Step::Kill(node) => write!(f, "kill node {}", node),
+            Step::Partition(groups) => {
+                let groups: Vec<String> = groups
+                    .iter()
+                    .map(|group| {
+                        let nodes: Vec<String> =
+                            group.iter().map(|n| n.to_string()).collect();
+                        nodes.join(" ")
+                    })
+                    .collect();
+                write!(f, "partition {}", groups.join(" | "))
+            }
+            Step::Heal => write!(f, "heal"),
+            Step::Wait(wait) => write!(f, "wait {:?}", wait),
+        }
+    }
+}
+
+impl Step {
+    fn parse(text: &str) -> Result<Self, String> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let keyword = |i: usize, word: &str| {
+            words.get(i).is_some_and(|w| w.eq_ignore_ascii_case(word))
+        };
+        let number = |word: Option<&&str>| {
+            word.and_then(|word| word.parse::<usize>().ok()).ok_or_else(
+                || format!("expected a node number in {:?}", text),
+            )
+        };
+        if keyword(0, "kill") && keyword(1, "node") && words.len() == 3 {
+            return Ok(Step::Kill(number(words.get(2))?));
+        }
+        if keyword(0, "heal") && words.len() == 1 {
+            return Ok(Step::Heal);
+        }
+        if keyword(0, "wait") && words.len() == 2 {
+            return Ok(Step::Wait(parse_duration(words[1])?));
+        }
+        if keyword(0, "partition") {
+            let groups = words[1..]
+                .split(|word| *word == "|")
+                .map(|group| {
+                    group
+                        .iter()
+                        .map(|node| number(Some(node)))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if groups.len() < 2 || groups.iter().any(Vec::is_empty) {
+                return Err(format!(
+                    "expected groups of nodes like `partition 0 1 | 2 3`, \
+                     got {:?}",
+                    text
+                ));
+            }
+            return Ok(Step::Partition(groups));
+        }
+
+        // The rest are queries, of some node
+        let (node, words) = match keyword(0, "node") {
+            true => (Some(number(words.get(1))?), &words[2..]),
+            false => (None, &words[..]),
+        };
+        let command = words.first().map(|word| word.to_ascii_uppercase());
+        match (command.as_deref(), words) {
+            (Some("PUT"), [_, key, value @ ..]) if !value.is_empty() => {
+                Ok(Step::Put {
+                    node,
+                    key: key.to_string(),
+                    value: value.join(" "),
+                })
+            }
+            (Some("GET"), [_, key, rest @ ..]) => {
+                let (rest, within) = match rest {
+                    [rest @ .., within, wait]
+                        if within.eq_ignore_ascii_case("within") =>
+                    {
+                        (rest, Some(parse_duration(wait)?))
+                    }
+                    _ => (rest, None),
+                };
+                let expect = match rest {
+                    [] => Expect::Found,
+                    [still, succeeds]
+                        if still.eq_ignore_ascii_case("still")
+                            && succeeds
+                                .eq_ignore_ascii_case("succeeds") =>
+                    {
+                        Expect::Found
+                    }
+                    [succeeds]
+                        if succeeds.eq_ignore_ascii_case("succeeds") =>
+                    {
+                        Expect::Found
+                    }
+                    [expects, nothing]
+                        if expects.eq_ignore_ascii_case("expects")
+                            && nothing.eq_ignore_ascii_case("nothing") =>
+                    {
+                        Expect::Nothing
+                    }
+                    [expects, value @ ..]
+                        if expects.eq_ignore_ascii_case("expects")
+                            && !value.is_empty() =>
+                    {
+                        Expect::Value(value.join(" "))
+                    }
+                    _ => {
+                        return Err(format!(
+                        "expected `GET <key> [expects <value>] [within \
+                             <duration>]`, got {:?}",
+                        text
+                    ))
+                    }
+                };
+                Ok(Step::Get {
+                    node,
+                    key: key.to_string(),
+                    expect,
+                    within,
+                })
+            }
+            _ => Err(format!("unknown step {:?}", text)),
+        }
+    }
+
+    // Run the step, which is step number `step`, on `net`. `killed` has
+    // the nodes that earlier steps killed, and which steps those were.
+    async fn run(
+        &self,
+        net: &Testnet,
+        step: usize,
+        killed: &mut HashMap<usize, usize>,
+    ) -> Result<(), String> {
+        let nodes = net.nodes().len();
+        match self {
+            Step::Put { node, key, value } => {
+                let node = net.node(pick(*node, nodes, killed)?);
+                node.put(key, value.as_bytes())
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+            Step::Get {
+                node,
+                key,
+                expect,
+                within,
+            } => {
+                let node = net.node(pick(*node, nodes, killed)?);
+                let deadline = Instant::now() + within.unwrap_or_default();
+                loop {
+                    let found = node
+                        .get(key)
+                        .await
+                        .map_err(|err| err.to_string())?;
+                    let failed = match (expect, &found) {
+                        (Expect::Found, Some(_)) => None,
+                        (Expect::Nothing, None) => None,
+                        (Expect::Value(value), Some(found))
+                            if found == value.as_bytes() =>
+                        {
+                            None
+                        }
+                        (_, found) => Some(match found {
+                            Some(found) => format!(
+                                "got {:?}",
+                                String::from_utf8_lossy(found)
+                            ),
+                            None => "found nothing".to_string(),
+                        }),
+                    };
+                    match failed {
+                        None => return Ok(()),
+                        Some(err) if Instant::now() >= deadline => {
+                            return Err(err)
+                        }
+                        Some(_) => task::sleep(RETRY_INTERVAL).await,
+                    }
+                }
+            }
+            Step::Kill(node) => {
+                let node = pick(Some(*node), nodes, killed)?;
+                net.node(node).kill().await;
+                killed.insert(node, step);
+                Ok(())
+            }
+            // Killed nodes may be in a partition (they are kept apart
+            // from the others once they are back)
+            Step::Partition(groups) => {
+                for node in groups.iter().flatten() {
+                    exists(*node, nodes)?;
+                }
+                let groups: Vec<&[usize]> =
+                    groups.iter().map(Vec::as_slice).collect();
+                net.partition(&groups).await;
+                Ok(())
+            }
+            Step::Heal => {
+                net.heal().await;
+                Ok(())
+            }
+            Step::Wait(wait) => {
+                task::sleep(*wait).await;
+                Ok(())
+            }
+        }
+    }
+}
+
+// The node a step runs on: `node` if it is given (which had better be
+// running), or else the first that is still running, of `nodes`.
+fn pick(
+    node: Option<usize>,
+    nodes: usize,
+    killed: &HashMap<usize, usize>,
+) -> Result<usize, String> {
+    match node {
+        Some(node) => match killed.get(&exists(node, nodes)?) {
+            Some(step) => {
+                Err(format!("node {} was killed by step {}", node, step))
+            }
+            None => Ok(node),
+        },
+        None => (0..nodes)
+            .find(|node| !killed.contains_key(node))
+            .ok_or_else(|| "every node was killed".to_string()),
+    }
+}
+
+fn exists(node: usize, nodes: usize) -> Result<usize, String> {
+    match node < nodes {
+        true => Ok(node),
+        false => {
+            Err(format!("there is no node {} (there are {})", node, nodes))
+        }
+    }
+}
+
+impl Scenario {
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut scenario = Scenario {
+            nodes: 0,
+            replication_factor: None,
+            alpha: None,
+            steps: Vec::new(),
+        };
+        for entry in toml::parse(text).map_err(|err| err.to_string())? {
+            let Setting {
+                line, key, value, ..
+            } = match entry {
+                Entry::Setting(setting) => setting,
+                Entry::Table(line, name) => {
+                    return Err(format!(
+                        "line {}: unexpected table [{}]",
+                        line, name
+                    ))
+                }
+            };
+            let number = || {
+                value
+                    .number()
+                    .map(|n| n as usize)
+                    .map_err(|err| format!("line {}: {}", line, err))
+            };
+            match key.as_str() {
+                "nodes" => scenario.nodes = number()?,
+                "replication_factor" => {
+                    scenario.replication_factor =
+                        NonZeroUsize::new(number()?)
+                }
+                "alpha" => scenario.alpha = NonZeroUsize::new(number()?),
+                "steps" => {
+                    let steps = match &value {
+                        Value::List(steps) => steps,
+                        _ => {
+                            return Err(format!(
+                                "line {}: expected a list of steps",
+                                line
+                            ))
+                        }
+                    };
+                    for (line, step) in steps {
+                        let step = step.string().map_err(|err| {
+                            format!("line {}: {}", line, err)
+                        })?;
+                        scenario.add_step(*line, step)?;
+                    }
+                }
+                key => {
+                    return Err(format!(
+                        "line {}: unknown setting {}",
+                        line, key
+                    ))
+                }
+            }
+        }
+        if scenario.nodes == 0 {
+            return Err("expected `nodes = <how many>`".into());
+        }
+        if scenario.steps.is_empty() {
+            return Err("there are no steps".into());
+        }
+        Ok(scenario)
+    }
+
+    fn add_step(&mut self, line: usize, text: &str) -> Result<(), String> {
+        let step = Step::parse(text)
+            .map_err(|err| format!("line {}: {}", line, err))?;
+        self.steps.push((line, step));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks() {
+        let killed: HashMap<usize, usize> = [(0, 3), (2, 5)].into();
+        assert_eq!(pick(Some(1), 4, &killed), Ok(1));
+        assert_eq!(pick(None, 4, &killed), Ok(1));
+        assert_eq!(
+            pick(Some(2), 4, &killed),
+            Err("node 2 was killed by step 5".into())
+        );
+        assert_eq!(
+            pick(Some(4), 4, &killed),
+            Err("there is no node 4 (there are 4)".into())
+        );
+        let killed: HashMap<usize, usize> = [(0, 1), (1, 2)].into();
+        assert_eq!(
+            pick(None, 2, &killed),
+            Err("every node was killed".into())
+        );
+    }
+
+    #[test]
+    fn steps() {
+        let cases = [
+            (
+                "node 2 PUT k a value",
+                Step::Put {
+                    node: Some(2),
+                    key: "k".into(),
+                    value: "a value".into(),
+                },
+            ),
+            (
+                "node 7 GET k expects v within 5s",
+                Step::Get {
+                    node: Some(7),
+                    key: "k".into(),
+                    expect: Expect::Value("v".into()),
+                    within: Some(Duration::from_secs(5)),
+                },
+            ),
+            (
+                "GET k still succeeds",
+                Step::Get {
+                    node: None,
+                    key: "k".into(),
+                    expect: Expect::Found,
+                    within: None,
+                },
+            ),
+            (
+                "get k expects nothing",
+                Step::Get {
+                    node: None,
+                    key: "k".into(),
+                    expect: Expect::Nothing,
+                    within: None,
+                },
+            ),
+            (
+                "GET k within 500ms",
+                Step::Get {
+                    node: None,
+                    key: "k".into(),
+                    expect: Expect::Found,
+                    within: Some(Duration::from_millis(500)),
+                },
+            ),
+            ("kill node 2", Step::Kill(2)),
+            (
+                "partition 0 1 | 2",
+                Step::Partition(vec![vec![0, 1], vec![2]]),
+            ),
+            ("heal", Step::Heal),
+            ("wait 2s", Step::Wait(Duration::from_secs(2))),
+        ];
+        for (text, step) in cases {
+            assert_eq!(Step::parse(text), Ok(step), "{}", text);
+        }
+        for text in [
+            "",
+            "kill node",
+            "kill node two",
+            "partition 0 1",
+            "partition 0 | | 1",
+            "wait forever",
+            "PUT k",
+            "GET",
+            "GET k expects",
+            "GET k hopefully",
+            "GET k within 1e30s",
+            "node PUT k v",
+            "DELETE k",
+        ] {
+            assert!(Step::parse(text).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn scenarios() {
+        let scenario = Scenario::parse(
+            "nodes = 3\nalpha = 2\nsteps = [\n  \"heal\",\n  \"wait 1s\",\n]",
+        )
+        .unwrap();
+        assert_eq!(scenario.nodes, 3);
+        assert_eq!(scenario.alpha, NonZeroUsize::new(2));
+        assert_eq!(scenario.replication_factor, None);
+        assert_eq!(
+            scenario.steps,
+            vec![(4, Step::Heal), (5, Step::Wait(Duration::from_secs(1)))]
+        );
+
+        let cases = [
+            ("steps = [\"heal\"]", "expected `nodes = <how many>`"),
+            ("nodes = 3", "there are no steps"),
+            (
+                "nodes = 3\nsteps = \"heal\"",
+                "line 2: expected a list of steps",
+            ),
+            ("nodes = 3\nsteps = [1]", "line 2: expected a string, not 1"),
+            ("nodes = 3\n[table]", "line 2: unexpected table [table]"),
+            ("nodes = 3\nwhat = 1", "line 2: unknown setting what"),
+            (
+                "nodes = 3\nsteps = [\n\"fly\"]",
+                "line 3: unknown step \"fly\"",
+            ),
+        ];
+        for (text, err) in cases {
+            assert_eq!(
+                Scenario::parse(text).unwrap_err(),
+                err,
+                "{:?}",
+                text
+            );
+        }
+    }
+}
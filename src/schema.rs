@@ -0,0 +1,412 @@
+use serde_json::{Map, Value};
+use std::{fs, path::PathBuf};
+
+// The keywords of JSON Schema that `Schema` checks. The ones that only
+// describe the schema are fine too, but a schema with any other keyword
+// is refused, rather than half checked.
+const KEYWORDS: &[&str] = &[
+    "type",
+    "enum",
+    "const",
+    "required",
+    "properties",
+    "additionalProperties",
+    "items",
+    "minItems",
+    "maxItems",
+    "minLength",
+    "maxLength",
+    "minimum",
+    "maximum",
+];
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+];
+
+// A JSON schema that values have to match (`--validate schema=PATH`).
+// Only the basics of JSON Schema are there: types, enums and constants,
+// the properties of objects (and whether there can be others), the items
+// of arrays, and the bounds on lengths and numbers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schema {
+    pub path: PathBuf,
+    schema: Value,
+}
+
+impl Schema {
+    // Read the schema in the file at `path`.
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let text = fs::read_to_string(&path)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        let schema: Value = serde_json::from_str(&text)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        check_schema(&schema, "")
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(Schema { path, schema })
+    }
+
+    // Why `value` doesn't match the schema, if it doesn't.
+    pub fn check(&self, value: &Value) -> Result<(), String> {
+        check(&self.schema, value, "")
+    }
+}
+
+// Make sure that a (sub)schema only uses the keywords that get checked.
+fn check_schema(schema: &Value, at: &str) -> Result<(), String> {
+    let fields = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(fields) => fields,
+        _ => {
+            return Err(format!("the schema at {:?} isn't an object", at))
+        }
+    };
+    for (keyword, value) in fields {
+        if ANNOTATIONS.contains(&keyword.as_str()) {
+            continue;
+        }
+        if !KEYWORDS.contains(&keyword.as_str()) {
+            return Err(format!(
+                "{:?} (at {:?}) isn't supported (only {})",
+                keyword,
+                at,
+                KEYWORDS.join(", ")
+            ));
+        }
+        match keyword.as_str() {
+            "properties" => {
+                for (name, schema) in
+                    value.as_object().into_iter().flatten()
+                {
+                    check_schema(schema, &format!("{}/{}", at, name))?;
+                }
+            }
+            "additionalProperties" | "items" => {
+                check_schema(value, &format!("{}/{}", at, keyword))?
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn check(schema: &Value, value: &Value, at: &str) -> Result<(), String> {
+    let fields = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => {
+            return Err(format!("{}: nothing is allowed here", place(at)))
+        }
+        Value::Object(fields) => fields,
+        _ => return Ok(()),
+    };
+    let fail = |why: String| Err(format!("{}: {}", place(at), why));
+
+    if let Some(kinds) = fields.get("type") {
+        let kinds: Vec<&str> = match kinds {
+            Value::String(kind) => vec![kind.as_str()],
+            Value::Array(kinds) => {
+                kinds.iter().filter_map(Value::as_str).collect()
+            }
+            _ => Vec::new(),
+        };
+        if !kinds.iter().any(|kind| is_type(value, kind)) {
+            return fail(format!("expected {}", kinds.join(" or ")));
+        }
+    }
+    if let Some(Value::Array(options)) = fields.get("enum") {
+        if !options.contains(value) {
+            return fail(format!("{} isn't one of {:?}", value, options));
+        }
+    }
+    if let Some(constant) = fields.get("const") {
+        if constant != value {
+            return fail(format!("expected {}", constant));
+        }
+    }
+
+    match value {
+        Value::Object(object) => check_object(fields, object, at)?,
+        Value::Array(items) => {
+            bounds(fields, "minItems", "maxItems", items.len(), "items")
+                .or_else(fail)?;
+            if let Some(schema) = fields.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    check(schema, item, &format!("{}/{}", at, i))?;
+                }
+            }
+        }
+        Value::String(s) => {
+            let length = s.chars().count();
+            bounds(fields, "minLength", "maxLength", length, "characters")
+                .or_else(fail)?;
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or_default();
+            if let Some(min) =
+                fields.get("minimum").and_then(Value::as_f64)
+            {
+                if n < min {
+                    return fail(format!("{} is less than {}", n, min));
+                }
+            }
+            if let Some(max) =
+                fields.get("maximum").and_then(Value::as_f64)
+            {
+                if n > max {
+                    return fail(format!("{} is more than {}", n, max));
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn check_object(
+    fields: &Map<String, Value>,
+    object: &Map<String, Value>,
+    at: &str,
+) -> Result<(), String> {
+    for name in fields
+        .get("required")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+    {
+        if !object.contains_key(name) {
+            return Err(format!("{}: {:?} is missing", place(at), name));
+        }
+    }
+    let properties = fields.get("properties").and_then(Value::as_object);
+    for (name, value) in object {
+        let at = format!("{}/{}", at, name);
+        match properties.and_then(|properties| properties.get(name)) {
+            Some(schema) => check(schema, value, &at)?,
+            None => {
+                if let Some(schema) = fields.get("additionalProperties") {
+                    check(schema, value, &at)?
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whether `value` is of the JSON Schema type `kind`.
+fn is_type(value: &Value, kind: &str) -> bool {
+    match kind {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64()
+                || value.is_u64()
+                || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => false,
+    }
+}
+
+// Check a length against the bounds `min` and `max` of the schema.
+fn bounds(
+    fields: &Map<String, Value>,
+    min: &str,
+    max: &str,
+    length: usize,
+    what: &str,
+) -> Result<(), String> {
+    let bound = |name| fields.get(name).and_then(Value::as_u64);
+    if let Some(min) = bound(min) {
+        if (length as u64) < min {
+            return Err(format!(
+                "{} {}, fewer than {}",
+                length, what, min
+            ));
+        }
+    }
+    if let Some(max) = bound(max) {
+        if length as u64 > max {
+            return Err(format!("{} {}, more than {}", length, what, max));
+        }
+    }
+    Ok(())
+}
+
+// Where in the value something is, for the errors.
+fn place(at: &str) -> String {
+    match at {
+        "" => "the value".to_string(),
+        at => format!("the value at {}", at),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema(schema: Value) -> Schema {
+        check_schema(&schema, "").unwrap();
+        Schema {
+            path: PathBuf::new(),
+            schema,
+        }
+    }
+
+    fn user() -> Schema {
+        schema(json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "title": "A user",
+            "type": "object",
+            "required": ["name"],
+            "properties": {
+                "name": { "type": "string", "minLength": 1, "maxLength": 4 },
+                "age": { "type": "integer", "minimum": 0, "maximum": 150 },
+                "role": { "enum": ["admin", "user"] },
+                "version": { "const": 1 },
+                "tags": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "minItems": 1,
+                    "maxItems": 2,
+                },
+                "note": { "type": ["string", "null"] },
+            },
+            "additionalProperties": false,
+        }))
+    }
+
+    #[test]
+    fn accepted() {
+        let user = user();
+        for value in [
+            json!({ "name": "ann" }),
+            json!({ "name": "ann", "age": 30, "role": "admin" }),
+            json!({ "name": "åsa", "age": 30.0, "version": 1 }),
+            json!({ "name": "ann", "tags": ["a", "b"], "note": null }),
+            json!({ "name": "ann", "note": "hi" }),
+        ] {
+            assert_eq!(user.check(&value), Ok(()), "{}", value);
+        }
+        for value in [json!(null), json!(1), json!({ "any": [] })] {
+            assert_eq!(schema(json!({})).check(&value), Ok(()));
+            assert_eq!(schema(json!(true)).check(&value), Ok(()));
+        }
+    }
+
+    #[test]
+    fn rejected() {
+        let user = user();
+        let cases = [
+            (json!([]), "the value: expected object"),
+            (json!({}), "the value: \"name\" is missing"),
+            (json!({ "name": 1 }), "the value at /name: expected string"),
+            (
+                json!({ "name": "" }),
+                "the value at /name: 0 characters, fewer than 1",
+            ),
+            (
+                json!({ "name": "annie" }),
+                "the value at /name: 5 characters, more than 4",
+            ),
+            (
+                json!({ "name": "ann", "age": 1.5 }),
+                "the value at /age: expected integer",
+            ),
+            (
+                json!({ "name": "ann", "age": -1 }),
+                "the value at /age: -1 is less than 0",
+            ),
+            (
+                json!({ "name": "ann", "age": 151 }),
+                "the value at /age: 151 is more than 150",
+            ),
+            (
+                json!({ "name": "ann", "role": "root" }),
+                "the value at /role: \"root\" isn't one of \
+                 [String(\"admin\"), String(\"user\")]",
+            ),
+            (
+                json!({ "name": "ann", "version": 2 }),
+                "the value at /version: expected 1",
+            ),
+            (
+                json!({ "name": "ann", "tags": [] }),
+                "the value at /tags: 0 items, fewer than 1",
+            ),
+            (
+                json!({ "name": "ann", "tags": ["a", "b", "c"] }),
+                "the value at /tags: 3 items, more than 2",
+            ),
+            (
+                json!({ "name": "ann", "tags": ["a", 2] }),
+                "the value at /tags/1: expected string",
+            ),
+            (
+                json!({ "name": "ann", "note": 1 }),
+                "the value at /note: expected string or null",
+            ),
+            (
+                json!({ "name": "ann", "other": 1 }),
+                "the value at /other: nothing is allowed here",
+            ),
+        ];
+        for (value, err) in cases {
+            assert_eq!(
+                user.check(&value),
+                Err(err.to_string()),
+                "{}",
+                value
+            );
+        }
+        assert!(schema(json!(false)).check(&json!(null)).is_err());
+    }
+
+    #[test]
+    fn unsupported_schemas() {
+        let cases = [
+            (json!(1), "the schema at \"\" isn't an object"),
+            (json!({ "pattern": "a*" }), "\"pattern\" (at \"\")"),
+            (
+                json!({ "properties": { "a": { "oneOf": [] } } }),
+                "\"oneOf\" (at \"/a\")",
+            ),
+            (
+                json!({ "items": { "properties": { "b": [] } } }),
+                "the schema at \"/items/b\" isn't an object",
+            ),
+            (
+                json!({ "additionalProperties": { "format": "date" } }),
+                "\"format\" (at \"/additionalProperties\")",
+            ),
+        ];
+        for (schema, err) in cases {
+            let got = check_schema(&schema, "").unwrap_err();
+            assert!(got.starts_with(err), "{}: {}", schema, got);
+        }
+    }
+
+    #[test]
+    fn schema_files() {
+        let path = std::env::temp_dir()
+            .join(format!("nettest-schema-{}.json", std::process::id()));
+        fs::write(&path, r#"{ "type": "string" }"#).unwrap();
+        let schema = Schema::load(path.clone()).unwrap();
+        assert_eq!(schema.check(&json!("a")), Ok(()));
+        fs::write(&path, r#"{ "type": "#).unwrap();
+        assert!(Schema::load(path.clone()).is_err());
+        fs::write(&path, r#"{ "not": {} }"#).unwrap();
+        assert!(Schema::load(path.clone()).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(Schema::load(path).is_err());
+    }
+}
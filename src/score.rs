@@ -0,0 +1,268 @@
+use crate::{behaviour::MyBehavior, output::Output};
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::{swarm::SwarmEvent, PeerId, Swarm};
+use std::{
+    collections::HashMap,
+    fmt,
+    task::Context,
+    time::{Duration, Instant},
+};
+
+// What a peer starts out with, and can't get above.
+pub const MAX_SCORE: f64 = 100.0;
+
+// How many points a peer earns back every minute.
+const RECOVERY_PER_MINUTE: f64 = 2.0;
+
+// Connections that close sooner than this count as churn.
+const SHORT_CONNECTION: Duration = Duration::from_secs(5);
+
+// How long a ban lasts, unless --ban-cooldown says otherwise.
+pub const DEFAULT_COOLDOWN: &str = "10m";
+
+// Something a peer did that costs it points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offense {
+    // We couldn't reach it
+    FailedDial,
+    // It didn't answer a FETCH or rendezvous request
+    FailedRequest,
+    // It sent us a record that doesn't decode (or whose signature or
+    // content hash is wrong)
+    BadRecord,
+    // Its connection closed right after opening
+    Churn,
+}
+
+impl Offense {
+    fn penalty(self) -> f64 {
+        match self {
+            Offense::FailedDial => 5.0,
+            Offense::FailedRequest => 5.0,
+            Offense::BadRecord => 25.0,
+            Offense::Churn => 2.0,
+        }
+    }
+}
+
+// When to ban a peer, and for how long.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BanPolicy {
+    // Peers whose score drops below this get banned
+    pub threshold: f64,
+    pub cooldown: Duration,
+}
+
+// How a peer has behaved.
+#[derive(Debug, Clone)]
+pub struct PeerScore {
+    score: f64,
+    updated: Instant,
+    pub failed_dials: u64,
+    pub failed_requests: u64,
+    pub bad_records: u64,
+    pub churn: u64,
+    // When the peer's ban is over, if it is banned
+    pub banned_until: Option<Instant>,
+}
+
+impl PeerScore {
+    fn new() -> Self {
+        PeerScore {
+            score: MAX_SCORE,
+            updated: Instant::now(),
+            failed_dials: 0,
+            failed_requests: 0,
+            bad_records: 0,
+            churn: 0,
+            banned_until: None,
+        }
+    }
+
+    // The score, with the points earned back since the last offense.
+    pub fn score(&self) -> f64 {
+        let minutes = self.updated.elapsed().as_secs_f64() / 60.0;
+        (self.score + minutes * RECOVERY_PER_MINUTE).min(MAX_SCORE)
+    }
+}
+
+impl fmt::Display for PeerScore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "score {:.0} ({} failed dials, {} failed requests, {} bad \
+             records, {} short connections)",
+            self.score(),
+            self.failed_dials,
+            self.failed_requests,
+            self.bad_records,
+            self.churn
+        )?;
+        if let Some(until) = self.banned_until {
+            let left = until.saturating_duration_since(Instant::now());
+            write!(f, ", banned for {:.0?}", left)?;
+        }
+        Ok(())
+    }
+}
+
+// The scores of the peers that did something wrong, and the bans that
+// follow from them (with --ban-threshold). The failures of kademlia
+// queries aren't counted, because kademlia doesn't say which peers
+// they were down to.
+#[derive(Debug, Default)]
+pub struct Scores {
+    peers: HashMap<PeerId, PeerScore>,
+    policy: Option<BanPolicy>,
+    // When each connected peer connected
+    connected: HashMap<PeerId, Instant>,
+    // Peers that are due to be banned
+    to_ban: Vec<PeerId>,
+    // Fires when the next ban is over
+    timer: Option<Delay>,
+}
+
+impl Scores {
+    pub fn new(policy: Option<BanPolicy>) -> Self {
+        Scores {
+            policy,
+            ..Scores::default()
+        }
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<&PeerScore> {
+        self.peers.get(peer)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &PeerScore)> {
+        self.peers.iter()
+    }
+
+    // Take points from `peer`, and have it banned if that takes it below
+    // the threshold.
+    pub fn penalize(&mut self, peer: &PeerId, offense: Offense) {
+        let score = self
+            .peers
+            .entry(peer.clone())
+            .or_insert_with(PeerScore::new);
+        score.score = score.score() - offense.penalty();
+        score.updated = Instant::now();
+        match offense {
+            Offense::FailedDial => score.failed_dials += 1,
+            Offense::FailedRequest => score.failed_requests += 1,
+            Offense::BadRecord => score.bad_records += 1,
+            Offense::Churn => score.churn += 1,
+        }
+
+        if let Some(policy) = self.policy {
+            if score.banned_until.is_none()
+                && score.score < policy.threshold
+            {
+                score.banned_until =
+                    Some(Instant::now() + policy.cooldown);
+                self.to_ban.push(peer.clone());
+            }
+        }
+    }
+
+    // Keep an eye on the connections, for dials that fail and for churn.
+    pub fn observe<T, E>(&mut self, event: &SwarmEvent<T, E>) {
+        match event {
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                self.connected
+                    .entry(peer_id.clone())
+                    .or_insert_with(Instant::now);
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                if let Some(since) = self.connected.remove(peer_id) {
+                    if since.elapsed() < SHORT_CONNECTION {
+                        self.penalize(peer_id, Offense::Churn);
+                    }
+                }
+            }
+            SwarmEvent::UnreachableAddr {
+                peer_id,
+                attempts_remaining: 0,
+                ..
+            } => self.penalize(peer_id, Offense::FailedDial),
+            _ => {}
+        }
+    }
+
+    // We are closing the connections of `peer` ourselves, which doesn't
+    // make it churn.
+    pub fn closing(&mut self, peer: &PeerId) {
+        self.connected.remove(peer);
+    }
+
+    // The peers that are due to be banned.
+    fn take_bans(&mut self, cx: &mut Context<'_>) -> Vec<PeerId> {
+        let bans = std::mem::take(&mut self.to_ban);
+        if !bans.is_empty() {
+            self.schedule(cx);
+        }
+        bans
+    }
+
+    // The peers whose ban is over. They start over with a full score.
+    fn take_unbans(&mut self, cx: &mut Context<'_>) -> Vec<PeerId> {
+        let over = match &mut self.timer {
+            Some(timer) => timer.poll_unpin(cx).is_ready(),
+            None => false,
+        };
+        if !over {
+            return Vec::new();
+        }
+        let now = Instant::now();
+        let mut unbans = Vec::new();
+        for (peer, score) in self.peers.iter_mut() {
+            if score.banned_until.is_some_and(|until| until <= now) {
+                *score = PeerScore::new();
+                unbans.push(peer.clone());
+            }
+        }
+        self.schedule(cx);
+        unbans
+    }
+
+    // Wake up when the next ban is over.
+    fn schedule(&mut self, cx: &mut Context<'_>) {
+        let now = Instant::now();
+        self.timer = self
+            .peers
+            .values()
+            .filter_map(|score| score.banned_until)
+            .min()
+            .map(|until| Delay::new(until.saturating_duration_since(now)));
+        if let Some(timer) = &mut self.timer {
+            if timer.poll_unpin(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+    }
+}
+
+// Ban the peers whose score dropped too low (as BAN does), and let the
+// ones whose ban is over back in.
+pub fn enforce(swarm: &mut Swarm<MyBehavior>, cx: &mut Context<'_>) {
+    for peer_id in swarm.scores.take_bans(cx) {
+        if let Some(score) = swarm.scores.get(&peer_id) {
+            Output::Terminal
+                .info(format!("Banning peer {}: {}", peer_id, score));
+        }
+        swarm.filter.write().unwrap().ban(peer_id.clone());
+        swarm.kademlia.remove_peer(&peer_id);
+        Swarm::ban_peer_id(swarm, peer_id);
+    }
+    for peer_id in swarm.scores.take_unbans(cx) {
+        Output::Terminal
+            .info(format!("The ban of peer {} is over", peer_id));
+        swarm.filter.write().unwrap().unban(&peer_id);
+        Swarm::unban_peer_id(swarm, peer_id);
+    }
+}
@@ -0,0 +1,72 @@
+use libp2p::identity::{self, ed25519};
+use rand::{rngs::StdRng, Error, RngCore, SeedableRng};
+use std::sync::Mutex;
+
+// Where a run gets its randomness from: the system, unless --seed was
+// given. With a seed, the identities of the nodes, the chaos that is
+// injected and the workloads of soak follow from it, so that a run that
+// failed can be run again the same way. (Trace ids, DNS query ids and
+// the nonces of encrypted values stay random: nothing depends on them,
+// and a nonce must never repeat.)
+static SEEDED: Mutex<Option<StdRng>> = Mutex::new(None);
+
+// Make the rest of the run follow from `seed`.
+pub fn set(seed: u64) {
+    *SEEDED.lock().unwrap() = Some(StdRng::seed_from_u64(seed));
+}
+
+// A source of randomness, to use instead of `rand::thread_rng()`: it is
+// the seeded one if there is a seed.
+pub fn rng() -> SeedRng {
+    SeedRng
+}
+
+// A new identity (which is the same on every run with the same seed).
+pub fn keypair() -> identity::Keypair {
+    keypair_from(&mut rng())
+}
+
+// The identity that a node run with `--seed seed` has (its first
+// keypair), without touching the seed of this run.
+pub fn keypair_for(seed: u64) -> identity::Keypair {
+    keypair_from(&mut StdRng::seed_from_u64(seed))
+}
+
+fn keypair_from(rng: &mut impl RngCore) -> identity::Keypair {
+    let mut bytes = [0u8; 32];
+    rng.fill_bytes(&mut bytes);
+    let secret = ed25519::SecretKey::from_bytes(&mut bytes)
+        .expect("any 32 bytes make an ed25519 key");
+    identity::Keypair::Ed25519(secret.into())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SeedRng;
+
+impl RngCore for SeedRng {
+    fn next_u32(&mut self) -> u32 {
+        match SEEDED.lock().unwrap().as_mut() {
+            Some(seeded) => seeded.next_u32(),
+            None => rand::thread_rng().next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match SEEDED.lock().unwrap().as_mut() {
+            Some(seeded) => seeded.next_u64(),
+            None => rand::thread_rng().next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match SEEDED.lock().unwrap().as_mut() {
+            Some(seeded) => seeded.fill_bytes(dest),
+            None => rand::thread_rng().fill_bytes(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
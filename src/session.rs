@@ -0,0 +1,221 @@
+use crate::{
+    api::ApiRequest, eventlog::EventLog, handle::Submission,
+    output::Output,
+};
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use futures_timer::Delay;
+use libp2p::PeerId;
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// The session being recorded (--record), if there is one.
+static RECORDING: Mutex<Option<Recorder>> = Mutex::new(None);
+
+// Where a session gets recorded: in the format of the --event-log, with
+// the time since the session started in "t_ms", like
+//   {"time": 1602678000123, "node": null, "event": "command",
+//    "t_ms": 1520, "source": "terminal", "line": "PUT foo bar"}
+// The first line is the command line the session ran with
+// ("session", with its "args" and "seed"), then come the commands as
+// they came in, and everything the nodes told the websocket clients
+// about, as they did.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    log: EventLog,
+    started: Instant,
+}
+
+impl Recorder {
+    // The same session, for the events of `node`.
+    pub fn for_node(&self, node: &PeerId) -> Self {
+        Recorder {
+            log: self.log.for_node(node),
+            started: self.started,
+        }
+    }
+
+    pub fn write(&self, event: &str, params: &Value) {
+        let mut params = params.clone();
+        if let Some(object) = params.as_object_mut() {
+            let t = self.started.elapsed().as_millis() as u64;
+            object.insert("t_ms".to_string(), json!(t));
+        }
+        self.log.write(event, &params);
+    }
+}
+
+// Start recording the session to `path` (whatever was there goes): it
+// runs with the command line `args`, and `seed`, which makes it run the
+// same way again.
+pub fn start(path: &Path, args: &[String], seed: u64) -> io::Result<()> {
+    fs::write(path, "")?;
+    let recorder = Recorder {
+        log: EventLog::open(path)?,
+        started: Instant::now(),
+    };
+    recorder.write("session", &json!({ "args": args, "seed": seed }));
+    *RECORDING.lock().unwrap() = Some(recorder);
+    Ok(())
+}
+
+// The session being recorded, for the nodes to record their events in.
+pub fn recorder() -> Option<Recorder> {
+    RECORDING.lock().unwrap().clone()
+}
+
+// Something came in for the swarm task.
+pub fn submitted(submission: &Submission) {
+    match submission {
+        Submission::Command(line, Output::Terminal) => {
+            command("terminal", line)
+        }
+        Submission::Command(line, _) => command("control", line),
+        Submission::Api(request) => api_request(request),
+    }
+}
+
+// A command line came in, from the terminal or the control socket.
+pub fn command(source: &str, line: &str) {
+    if let Some(recorder) = RECORDING.lock().unwrap().as_ref() {
+        recorder
+            .write("command", &json!({ "source": source, "line": line }));
+    }
+}
+
+// A request of the api came in: it is recorded as the command that does
+// the same, for the replay. The rest (the ones that only look, and PUTs
+// of values that aren't a line of text) only get noted.
+pub fn api_request(request: &ApiRequest) {
+    let recorder = match RECORDING.lock().unwrap().clone() {
+        Some(recorder) => recorder,
+        None => return,
+    };
+    let word = |bytes: &[u8]| {
+        String::from_utf8(bytes.to_vec())
+            .ok()
+            .filter(|word| !word.is_empty() && !word.contains(' '))
+    };
+    let line = match request {
+        ApiRequest::GetRecord(key, _) => {
+            word(key.as_ref()).map(|key| format!("GET {}", key))
+        }
+        ApiRequest::PutRecord(key, value, _) => {
+            match (word(key.as_ref()), String::from_utf8(value.clone())) {
+                (Some(key), Ok(value)) if !value.contains('\n') => {
+                    Some(format!("PUT {} {}", key, value))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    match line {
+        Some(line) => recorder
+            .write("command", &json!({ "source": "api", "line": line })),
+        None => {
+            let kind = format!("{:?}", request);
+            let kind = kind.split('(').next().unwrap_or_default();
+            recorder.write("api_request", &json!({ "kind": kind }));
+        }
+    }
+}
+
+// A recorded session, as `nettest replay` runs it again.
+#[derive(Debug, Clone)]
+pub struct Session {
+    // The command line it ran with (the program name first)
+    pub args: Vec<String>,
+    pub seed: u64,
+    // The commands, and when they came in
+    pub commands: Vec<(Duration, String)>,
+    // When the last thing was recorded
+    pub end: Duration,
+    // How many events the nodes recorded, and how many api requests
+    // there were that can't be replayed
+    pub events: usize,
+    pub skipped: usize,
+}
+
+pub fn load(path: &Path) -> Result<Session, String> {
+    let file = fs::File::open(path).map_err(|err| err.to_string())?;
+    let mut lines = BufReader::new(file).lines().enumerate();
+    let at = |i: usize, err: String| format!("line {}: {}", i + 1, err);
+    let header: Value = match lines.next() {
+        Some((i, line)) => {
+            let line = line.map_err(|err| at(i, err.to_string()))?;
+            serde_json::from_str(&line)
+                .map_err(|err| at(i, err.to_string()))?
+        }
+        None => return Err("the session is empty".to_string()),
+    };
+    if header["event"] != "session" {
+        return Err("this isn't a session that --record wrote".to_string());
+    }
+    let args = header["args"]
+        .as_array()
+        .and_then(|args| {
+            args.iter()
+                .map(|arg| arg.as_str().map(String::from))
+                .collect::<Option<Vec<_>>>()
+        })
+        .ok_or("the session has no command line")?;
+    let seed = header["seed"].as_u64().ok_or("the session has no seed")?;
+    let mut session = Session {
+        args,
+        seed,
+        commands: Vec::new(),
+        end: Duration::ZERO,
+        events: 0,
+        skipped: 0,
+    };
+    for (i, line) in lines {
+        let line = line.map_err(|err| at(i, err.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Value = serde_json::from_str(&line)
+            .map_err(|err| at(i, err.to_string()))?;
+        let t = entry["t_ms"].as_u64().map(Duration::from_millis);
+        // A line of another run that appended to the same file
+        let t = t.ok_or_else(|| at(i, "there is no t_ms".to_string()))?;
+        session.end = session.end.max(t);
+        match (entry["event"].as_str(), entry["line"].as_str()) {
+            (Some("command"), Some(command)) => {
+                session.commands.push((t, command.to_string()))
+            }
+            (Some("api_request"), _) => session.skipped += 1,
+            _ => session.events += 1,
+        }
+    }
+    session.commands.sort_by_key(|(t, _)| *t);
+    Ok(session)
+}
+
+// The commands of `session` as lines of input, each one when it came in
+// (from now on), and then nothing until the session ended.
+pub fn script(
+    session: &Session,
+) -> BoxStream<'static, io::Result<String>> {
+    let started = Instant::now();
+    let end = session.end;
+    let commands = stream::iter(session.commands.clone()).then(
+        move |(t, line)| async move {
+            Delay::new(t.saturating_sub(started.elapsed())).await;
+            Ok(line)
+        },
+    );
+    let linger = stream::once(async move {
+        Delay::new(end.saturating_sub(started.elapsed())).await;
+    })
+    .filter_map(|()| async { None });
+    commands.chain(linger).boxed()
+}
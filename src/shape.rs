@@ -0,0 +1,200 @@
+use crate::chaos::parse_duration;
+use futures::{future, AsyncRead, AsyncWrite, FutureExt};
+use futures_timer::Delay;
+use std::{
+    collections::VecDeque,
+    fmt, io,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+// Stop reading from a connection once this much data is waiting to be
+// let through, so that a fast sender can't make us buffer without limit.
+const MAX_BUFFERED: usize = 1024 * 1024;
+
+// What a connection should look like, like `1mbit,80ms`: a throughput
+// limit and a one-way latency (either can be left out).
+//
+// Shaping applies to the data a node receives, on every connection, so a
+// simulation where all nodes are shaped is shaped in both directions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShapeConfig {
+    // Bytes per second
+    pub rate: Option<u64>,
+    pub latency: Duration,
+}
+
+impl FromStr for ShapeConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = ShapeConfig::default();
+        for part in s.split(',').filter(|part| !part.is_empty()) {
+            match parse_duration(part) {
+                Ok(latency) => config.latency = latency,
+                Err(_) => config.rate = Some(parse_rate(part)?),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for ShapeConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rate {
+            Some(rate) => write!(f, "{} bytes/s", rate)?,
+            None => write!(f, "unlimited")?,
+        }
+        write!(f, ", {:?} latency", self.latency)
+    }
+}
+
+// Parse a throughput like `1mbit` (bits per second) or `100kb` (bytes per
+// second) into bytes per second.
+fn parse_rate(s: &str) -> Result<u64, String> {
+    let invalid =
+        || format!("{:?} is not a rate (like 1mbit) or a latency", s);
+    let (number, unit) =
+        s.split_at(s.find(|c: char| c.is_alphabetic()).unwrap_or(s.len()));
+    let bits_per_second = match unit.to_ascii_lowercase().as_str() {
+        "bit" => 1.0,
+        "kbit" => 1e3,
+        "mbit" => 1e6,
+        "gbit" => 1e9,
+        "b" => 8.0,
+        "kb" => 8e3,
+        "mb" => 8e6,
+        "gb" => 8e9,
+        _ => return Err(invalid()),
+    };
+    match number.parse::<f64>() {
+        Ok(number) if number > 0.0 => {
+            Ok(((number * bits_per_second / 8.0) as u64).max(1))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+// Wrap a brand new connection. This is meant to be used with
+// `Transport::and_then`, on the raw (not yet upgraded) transport.
+pub fn connect<C>(
+    socket: C,
+    config: Arc<ShapeConfig>,
+) -> future::Ready<Result<ShapedSocket<C>, io::Error>> {
+    future::ready(Ok(ShapedSocket {
+        inner: socket,
+        config,
+        queue: VecDeque::new(),
+        buffered: 0,
+        link_free: Instant::now(),
+        timer: None,
+        eof: false,
+    }))
+}
+
+// A connection whose incoming data is let through at the configured rate,
+// and only once the configured latency has passed.
+pub struct ShapedSocket<C> {
+    inner: C,
+    config: Arc<ShapeConfig>,
+    // Data read from the connection, along with when it "arrives"
+    queue: VecDeque<(Instant, Vec<u8>)>,
+    buffered: usize,
+    // When the simulated link is done with the data before
+    link_free: Instant,
+    // Wakes us up when the next piece of data arrives
+    timer: Option<Delay>,
+    eof: bool,
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for ShapedSocket<C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        // Take in everything the connection has for us, and work out when
+        // each piece gets through the simulated link
+        let mut chunk = [0; 8192];
+        while !this.eof && this.buffered < MAX_BUFFERED {
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(0)) => this.eof = true,
+                Poll::Ready(Ok(read)) => {
+                    let now = Instant::now();
+                    let start = this.link_free.max(now);
+                    this.link_free = match this.config.rate {
+                        Some(rate) => {
+                            start
+                                + Duration::from_secs_f64(
+                                    read as f64 / rate as f64,
+                                )
+                        }
+                        None => start,
+                    };
+                    let arrives = this.link_free + this.config.latency;
+                    this.queue
+                        .push_back((arrives, chunk[..read].to_vec()));
+                    this.buffered += read;
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => break,
+            }
+        }
+
+        // Hand out whatever has arrived
+        loop {
+            let (arrives, data) = match this.queue.front_mut() {
+                Some(front) => front,
+                None if this.eof => return Poll::Ready(Ok(0)),
+                None => return Poll::Pending,
+            };
+
+            let now = Instant::now();
+            if *arrives > now {
+                let timer = this.timer.insert(Delay::new(*arrives - now));
+                if timer.poll_unpin(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                continue;
+            }
+
+            let read = buf.len().min(data.len());
+            buf[..read].copy_from_slice(&data[..read]);
+            data.drain(..read);
+            if data.is_empty() {
+                this.queue.pop_front();
+            }
+            this.buffered -= read;
+            return Poll::Ready(Ok(read));
+        }
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for ShapedSocket<C> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
@@ -0,0 +1,555 @@
+use crate::{
+    adversary::{self, Adversary},
+    behaviour::MyBehavior,
+    chaos::parse_duration,
+    config::Opts,
+    eventlog::EventLog,
+    filter::PeerFilter,
+    handler, limits,
+    output::{self, Output, ERROR_PREFIX},
+    score, seed, session,
+    timescale::{self, TimeScale, TimelineSink},
+    topology,
+    transport::{self, Runtime, TransportKind},
+};
+use async_std::{io, task};
+use futures::{channel::mpsc, prelude::*, stream::BoxStream};
+use libp2p::{
+    identity::Keypair, multiaddr::Protocol, Multiaddr, PeerId, Swarm,
+};
+use std::{
+    collections::HashSet,
+    error::Error,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+// Run a network of `nodes` nodes inside this process (see `spawn`), and
+// hand it commands from the terminal.
+//
+// Lines from the terminal go to node 0, unless they start with `@<n>`,
+// like `@3 PUT foo bar`. Everything a node prints is prefixed with its
+// number. The exceptions are a plain `TOPOLOGY <file>`, which writes the
+// connections of the whole network (`@0 TOPOLOGY` still writes node 0's),
+// `ADVANCE <duration>` (see `advance`), and `PARTITION {0,1,2} {3,4,5}`
+// and `HEAL` (see `partition`).
+//
+// With a --time-scale, the maintenance of the nodes runs that much
+// faster, and every line starts with the virtual time, which is what
+// counts: a record with a TTL of 36h expires at +36h00m00s (after 36
+// minutes of real time, at 60x).
+pub fn run(opts: &Opts, nodes: usize) -> Result<(), Box<dyn Error>> {
+    let stdin = io::BufReader::new(io::stdin()).lines().boxed();
+    run_with_input(opts, nodes, stdin)
+}
+
+// The same, with the lines coming from `lines` (the commands of a
+// session that `nettest replay` runs again) instead of the terminal.
+pub fn run_with_input(
+    opts: &Opts,
+    nodes: usize,
+    mut lines: BoxStream<'static, io::Result<String>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut swarms = spawn(opts, nodes)?;
+    for (i, swarm) in swarms.iter().enumerate() {
+        Output::Terminal.info(format!(
+            "node {}: {} on {}",
+            i,
+            Swarm::local_peer_id(swarm),
+            Swarm::listeners(swarm).next().unwrap()
+        ));
+    }
+    Output::Terminal.info(format!("Simulating {} nodes", nodes));
+    if let Some(shape) = &opts.shape {
+        Output::Terminal.info(format!("Shaping connections to {}", shape));
+    }
+    if let Some(chaos) = &opts.chaos {
+        Output::Terminal.info(format!("Injecting chaos: {}", chaos));
+    }
+    if let Some(scale) = opts.time_scale {
+        Output::Terminal.info(format!(
+            "Running the clock at {} (an hour of maintenance takes {:?})",
+            scale,
+            scale.compress(Duration::from_secs(3600))
+        ));
+        output::set_terminal(Arc::new(TimelineSink(output::terminal())));
+    }
+
+    // How far ADVANCE has moved the clock
+    let mut advanced = Duration::from_secs(0);
+    // The pairs of nodes that PARTITION keeps apart
+    let mut apart = HashSet::new();
+    let simulation = future::poll_fn(move |cx: &mut Context<'_>| {
+        // Route every line from the terminal to the node it is meant for
+        // (while no node has a WAIT_PEERS or WAIT_BOOTSTRAP going)
+        while !swarms.iter().any(|swarm| swarm.barriers.is_waiting()) {
+            match lines.try_poll_next_unpin(cx)? {
+                Poll::Ready(Some(line)) => {
+                    session::command("terminal", &line);
+                    if let Some(path) = line.strip_prefix("TOPOLOGY ") {
+                        write_topology(&mut swarms, path.trim());
+                        continue;
+                    }
+                    if let Some(groups) = line.strip_prefix("PARTITION ") {
+                        let peers = peer_ids(&swarms);
+                        match parse_groups(groups, nodes) {
+                            Ok(groups) => {
+                                let pairs = split_pairs(&groups, nodes);
+                                for &(a, b) in &pairs {
+                                    keep_apart(&mut swarms[a], &peers[b]);
+                                }
+                                Output::Terminal.info(format!(
+                                    "Partitioned the network into {} \
+                                     groups ({} pairs of nodes can't reach \
+                                     each other)",
+                                    groups.len() + left_out_group(&groups, nodes),
+                                    pairs.len() / 2
+                                ));
+                                apart.extend(pairs);
+                            }
+                            Err(err) => Output::Terminal
+                                .error(format!("PARTITION: {}", err)),
+                        }
+                        continue;
+                    }
+                    if line.trim() == "HEAL" {
+                        let peers = peer_ids(&swarms);
+                        let pairs = apart.len() / 2;
+                        for (a, b) in apart.drain() {
+                            bring_together(&mut swarms[a], &peers[b]);
+                        }
+                        Output::Terminal.info(format!(
+                            "Healed the network ({} pairs of nodes can \
+                             reach each other again)",
+                            pairs
+                        ));
+                        continue;
+                    }
+                    if let Some(by) = line.strip_prefix("ADVANCE ") {
+                        match parse_duration(by.trim()) {
+                            Ok(by) => {
+                                advanced += by;
+                                timescale::advance(by);
+                                advance(
+                                    &mut swarms,
+                                    by,
+                                    advanced,
+                                    opts.time_scale,
+                                );
+                            }
+                            Err(err) => Output::Terminal
+                                .error(format!("ADVANCE: {}", err)),
+                        }
+                        continue;
+                    }
+                    let (node, command) = route(&line);
+                    match swarms.get_mut(node) {
+                        Some(swarm) => handler::handle_input_line(
+                            swarm,
+                            command.to_string(),
+                            node_output(node),
+                        ),
+                        None => Output::Terminal.error(format!(
+                            "There is no node {} (there are {})",
+                            node, nodes
+                        )),
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => break,
+            }
+        }
+
+        poll_all(&mut swarms, cx);
+        Poll::Pending
+    });
+
+    task::block_on(simulation)
+}
+
+// Start a network of `nodes` nodes inside this process. By default the
+// nodes talk over the memory transport, so nothing touches the real
+// network. With `--transport tcp` they use loopback TCP instead, which
+// exercises the real stack. mDNS is off either way (the nodes already
+// know each other).
+//
+// Every node starts out knowing the node before it (the first one knows
+// the last one), and then bootstraps, which is enough for all of them to
+// end up in the same dht. The nodes are listening once this returns,
+// but the bootstrapping is still going on.
+pub fn spawn(
+    opts: &Opts,
+    nodes: usize,
+) -> Result<Vec<Swarm<MyBehavior>>, Box<dyn Error>> {
+    let adversaries = match opts.adversary {
+        Some(_) => {
+            opts.adversaries.unwrap_or(nodes.div_ceil(4)).min(nodes)
+        }
+        None => 0,
+    };
+    let mut keys: Vec<Keypair> =
+        (0..nodes - adversaries).map(|_| seed::keypair()).collect();
+    let target = opts
+        .target
+        .as_deref()
+        .map(|target| adversary::target_bytes(target, &opts.namespace()));
+    if let Some(target) = &target {
+        keys.extend(adversary::grind(target, adversaries));
+    }
+    let mut swarms = spawn_with_keys(opts, keys)?;
+    if let (Some(kind), Some(target)) = (opts.adversary, &target) {
+        set_adversaries(&mut swarms, kind, target, nodes - adversaries);
+    }
+    Ok(swarms)
+}
+
+// Turn the nodes from `first` on into adversaries (which know each
+// other), and say how close to the target they got, next to the honest
+// nodes.
+fn set_adversaries(
+    swarms: &mut [Swarm<MyBehavior>],
+    kind: adversary::AdversaryKind,
+    target: &[u8],
+    first: usize,
+) {
+    let peers: Vec<PeerId> = swarms
+        .iter()
+        .map(|swarm| Swarm::local_peer_id(swarm).clone())
+        .collect();
+    let allies: HashSet<PeerId> = peers[first..].iter().cloned().collect();
+    for swarm in &mut swarms[first..] {
+        swarm.kademlia.set_adversary(Some(Adversary {
+            kind,
+            allies: allies.clone(),
+        }));
+    }
+
+    let mut closest: Vec<usize> = (0..peers.len()).collect();
+    closest.sort_by_key(|&i| adversary::distance(target, &peers[i]));
+    let closest: Vec<String> = closest
+        .into_iter()
+        .map(|i| match i >= first {
+            true => format!("{}*", i),
+            false => i.to_string(),
+        })
+        .collect();
+    Output::Terminal.info(format!(
+        "Nodes {} to {} are {} adversaries. The nodes closest to the \
+         target first (* for the adversaries): {}",
+        first,
+        peers.len() - 1,
+        kind,
+        closest.join(", ")
+    ));
+}
+
+// The same as `spawn`, with a node for each of the given identities.
+pub fn spawn_with_keys(
+    opts: &Opts,
+    keys: Vec<Keypair>,
+) -> Result<Vec<Swarm<MyBehavior>>, Box<dyn Error>> {
+    spawn_numbered(opts, keys, 0)
+}
+
+// The same, with the nodes numbered from `first` on (see `start_node`),
+// so that networks in the same process don't share memory addresses.
+pub fn spawn_numbered(
+    opts: &Opts,
+    keys: Vec<Keypair>,
+    first: usize,
+) -> Result<Vec<Swarm<MyBehavior>>, Box<dyn Error>> {
+    if keys.is_empty() {
+        return Err("a simulation needs at least one node".into());
+    }
+
+    if let Some(scale) = opts.time_scale {
+        timescale::start(scale);
+    }
+    let nodes = keys.len();
+    let mut swarms = keys
+        .into_iter()
+        .enumerate()
+        .map(|(i, key)| start_node(opts, key, first + i))
+        .collect::<Result<Vec<_>, _>>()?;
+    let addrs = listen_addrs(&mut swarms);
+
+    // Tell every node about the one before it, and let them find the
+    // rest of the network by bootstrapping
+    for i in 0..nodes {
+        let previous = (i + nodes - 1) % nodes;
+        if previous != i {
+            let peer_id = Swarm::local_peer_id(&swarms[previous]).clone();
+            swarms[i]
+                .kademlia
+                .add_address(&peer_id, addrs[previous].clone());
+        }
+        swarms[i].kademlia.bootstrap().ok();
+    }
+
+    Ok(swarms)
+}
+
+// Start node number `i` of a simulation. It is listening (or about to),
+// but doesn't know anybody yet.
+pub fn start_node(
+    opts: &Opts,
+    key: Keypair,
+    i: usize,
+) -> Result<Swarm<MyBehavior>, Box<dyn Error>> {
+    let kinds = opts.transports(TransportKind::Memory);
+    let config = opts.transport_config()?;
+    let peer_id = PeerId::from(key.public());
+    let mut behaviour_config = opts.simulated_behaviour_config(&key);
+    behaviour_config.bandwidth = config.bandwidth.clone();
+    behaviour_config.negotiations = config.negotiations.clone();
+    // Every node appends to the same file
+    behaviour_config.event_log =
+        opts.event_log.as_deref().map(EventLog::open).transpose()?;
+    behaviour_config.namespaces = opts.namespace_quotas()?;
+    behaviour_config.expiry = opts.expiry_config(opts.time_scale)?;
+    behaviour_config.capture =
+        opts.capture.as_deref().map(EventLog::open).transpose()?;
+
+    // Each node has its own filter, so that a BAN only affects the node it
+    // was sent to.
+    let filter = PeerFilter::new(&opts.allow, &opts.deny).shared();
+    let transport =
+        transport::build_transport(&kinds, key, config, filter.clone())?;
+    let behaviour =
+        MyBehavior::new(peer_id.clone(), None, filter, behaviour_config);
+    let limits = opts.connection_limits();
+    let mut swarm = limits::build_swarm(
+        transport,
+        behaviour,
+        peer_id,
+        &limits,
+        Runtime::AsyncStd,
+    );
+    for kind in kinds {
+        let addr: Multiaddr = match kind {
+            // Node `i` always gets the same address, even when restarted
+            TransportKind::Memory => Protocol::Memory(i as u64 + 1).into(),
+            TransportKind::Tcp => "/ip4/127.0.0.1/tcp/0".parse()?,
+            TransportKind::Ws => "/ip4/127.0.0.1/tcp/0/ws".parse()?,
+        };
+        Swarm::listen_on(&mut swarm, addr)?;
+    }
+    Ok(swarm)
+}
+
+// Wait for every node to have an actual address (TCP listeners only learn
+// their port once they are running), and return them.
+pub fn listen_addrs(swarms: &mut [Swarm<MyBehavior>]) -> Vec<Multiaddr> {
+    task::block_on(future::poll_fn(|cx| {
+        for swarm in swarms.iter_mut() {
+            while Swarm::listeners(swarm).next().is_none() {
+                match Box::pin(swarm.next_event()).poll_unpin(cx) {
+                    Poll::Ready(_) => {}
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+        }
+        Poll::Ready(
+            swarms
+                .iter()
+                .map(|swarm| {
+                    Swarm::listeners(swarm).next().unwrap().clone()
+                })
+                .collect(),
+        )
+    }))
+}
+
+// Drive every node until none of them has anything left to do right now.
+pub fn poll_all(swarms: &mut [Swarm<MyBehavior>], cx: &mut Context<'_>) {
+    for swarm in swarms {
+        loop {
+            let event = Box::pin(swarm.next_event()).poll_unpin(cx);
+            match event {
+                Poll::Ready(event) => swarm.observe(&event),
+                Poll::Pending => break,
+            }
+        }
+        swarm.track_queries();
+        score::enforce(swarm, cx);
+        limits::enforce(swarm, cx);
+    }
+}
+
+// Write the connections between all the nodes to `path`, with the nodes
+// named by their number.
+fn write_topology(swarms: &mut [Swarm<MyBehavior>], path: &str) {
+    let names = swarms
+        .iter()
+        .enumerate()
+        .map(|(i, swarm)| {
+            (Swarm::local_peer_id(swarm).clone(), format!("node {}", i))
+        })
+        .collect::<Vec<_>>();
+    let edges = swarms
+        .iter()
+        .flat_map(|swarm| swarm.edges())
+        .collect::<Vec<_>>();
+    match topology::write_dot(path.as_ref(), &names, &edges) {
+        Ok(edges) => Output::Terminal.info(format!(
+            "Wrote {} connections between {} nodes to {}",
+            edges,
+            names.len(),
+            path
+        )),
+        Err(err) => Output::Terminal.error(format!("{}: {}", path, err)),
+    }
+}
+
+// Move the clock of every node `by` forward, in a way: the records and
+// provider records all get that much older (and the ones that expire are
+// gone), and republishing comes that much sooner. That is enough to see
+// what expiry and republishing do without waiting for hours. Kademlia's
+// own jobs (replicating the records, announcing providers again) still
+// run on the real clock. With a --time-scale, `by` is virtual time, and
+// the nodes (whose clocks are the real one) advance by that much less.
+fn advance(
+    swarms: &mut [Swarm<MyBehavior>],
+    by: Duration,
+    total: Duration,
+    scale: Option<TimeScale>,
+) {
+    let real = scale.map_or(by, |scale| scale.compress(by));
+    let (mut records, mut providers) = (0, 0);
+    for swarm in swarms {
+        let (r, p) = swarm.advance(real);
+        records += r;
+        providers += p;
+    }
+    Output::Terminal.info(format!(
+        "Advanced the clock by {:?} ({:?} in all): {} records and {} \
+         provider records expired",
+        by, total, records, providers
+    ));
+}
+
+fn peer_ids(swarms: &[Swarm<MyBehavior>]) -> Vec<PeerId> {
+    swarms
+        .iter()
+        .map(|swarm| Swarm::local_peer_id(swarm).clone())
+        .collect()
+}
+
+// Groups of node numbers, like `{0,1,2} {3,4}` (or `0,1,2 3,4`), for
+// PARTITION. A node can only be in one of them.
+pub fn parse_groups(
+    text: &str,
+    nodes: usize,
+) -> Result<Vec<Vec<usize>>, String> {
+    let text = text.replace('}', "} ").replace(", ", ",");
+    let mut seen = HashSet::new();
+    let groups = text
+        .split_whitespace()
+        .map(|group| {
+            group
+                .trim_start_matches('{')
+                .trim_end_matches('}')
+                .split(',')
+                .filter(|node| !node.is_empty())
+                .map(|node| {
+                    let node = node
+                        .trim()
+                        .parse::<usize>()
+                        .map_err(|_| format!("{:?} isn't a node", node))?;
+                    if node >= nodes {
+                        return Err(format!(
+                            "there is no node {} (there are {})",
+                            node, nodes
+                        ));
+                    }
+                    if !seen.insert(node) {
+                        return Err(format!(
+                            "node {} is in two groups",
+                            node
+                        ));
+                    }
+                    Ok(node)
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .filter(|group| !matches!(group, Ok(group) if group.is_empty()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if groups.is_empty() {
+        return Err(
+            "expected groups of nodes, like {0,1,2} {3,4,5}".into()
+        );
+    }
+    Ok(groups)
+}
+
+// The (ordered) pairs of nodes that `groups` split: those in different
+// groups. The nodes left out of every group make up one of their own.
+pub fn split_pairs(
+    groups: &[Vec<usize>],
+    nodes: usize,
+) -> Vec<(usize, usize)> {
+    let group_of = |node: usize| {
+        groups.iter().position(|group| group.contains(&node))
+    };
+    let mut pairs = Vec::new();
+    for a in 0..nodes {
+        for b in 0..nodes {
+            if a != b && group_of(a) != group_of(b) {
+                pairs.push((a, b));
+            }
+        }
+    }
+    pairs
+}
+
+// 1 if some nodes are in none of `groups` (they are a group too).
+fn left_out_group(groups: &[Vec<usize>], nodes: usize) -> usize {
+    let grouped: usize = groups.iter().map(Vec::len).sum();
+    (grouped < nodes) as usize
+}
+
+// Have `swarm` refuse `peer`, and close the connections to it, the way
+// DISCONNECT does (so that kademlia hears about it, unlike with the ban of
+// the swarm), without taking it out of the routing table, so that the two
+// find each other again once they are brought together.
+pub fn keep_apart(swarm: &mut Swarm<MyBehavior>, peer: &PeerId) {
+    swarm.filter.write().unwrap().ban(peer.clone());
+    swarm.connections.disconnect(peer.clone());
+}
+
+// Undo `keep_apart`.
+pub fn bring_together(swarm: &mut Swarm<MyBehavior>, peer: &PeerId) {
+    swarm.filter.write().unwrap().unban(peer);
+}
+
+// Split a line into the node it is for, and the command itself.
+fn route(line: &str) -> (usize, &str) {
+    if let Some(rest) = line.strip_prefix('@') {
+        let (node, command) =
+            rest.split_at(rest.find(' ').unwrap_or(rest.len()));
+        if let Ok(node) = node.parse() {
+            return (node, command.trim_start());
+        }
+    }
+    (0, line)
+}
+
+// Where the output of a command sent to a node goes: the terminal, with
+// the number of the node in front.
+fn node_output(node: usize) -> Output {
+    let (tx, mut rx) = mpsc::unbounded::<String>();
+    task::spawn(async move {
+        while let Some(line) = rx.next().await {
+            match line.strip_prefix(ERROR_PREFIX) {
+                Some(error) => {
+                    Output::Terminal.error(format!("[{}] {}", node, error))
+                }
+                None => {
+                    Output::Terminal.info(format!("[{}] {}", node, line))
+                }
+            }
+        }
+    });
+    Output::Client(tx)
+}
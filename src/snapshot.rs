@@ -0,0 +1,174 @@
+use libp2p::{
+    kad::{record::ProviderRecord, Record},
+    PeerId,
+};
+use serde_json::{json, Value};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// A snapshot of the local store (from SNAPSHOT, for RESTORE and
+// --restore): one JSON object per record and line, like
+//   {"key": "foo", "value": "bar", "publisher": "12D3Koo...",
+//    "expires": 1602678000123}
+// and one per provider record, like
+//   {"key": "foo", "provider": "12D3Koo...", "expires": 1602678000123}
+// Keys and values that aren't text are written as {"base58": "..."}, and
+// the expiry is in milliseconds since the epoch (or null for never), so
+// that records expire on time across restarts. Only the key and the value
+// (or the provider) are required, which makes datasets easy to write by
+// hand.
+//
+// Snapshots are the only place a dataset outlives its node: the local
+// store is kademlia's memory store, and there is no store on disk (like
+// sled) to move datasets into.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    pub records: Vec<Record>,
+    pub providers: Vec<ProviderRecord>,
+}
+
+impl Snapshot {
+    // How much of it `stored` (records, and provider records) took, like
+    // "3 of 4 records and 1 of 1 provider records".
+    pub fn restored(&self, stored: (usize, usize)) -> String {
+        format!(
+            "{} of {} records and {} of {} provider records",
+            stored.0,
+            self.records.len(),
+            stored.1,
+            self.providers.len()
+        )
+    }
+}
+
+// Write `snapshot` to `path`.
+pub fn save(path: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for record in &snapshot.records {
+        let line = json!({
+            "key": encode_bytes(record.key.as_ref()),
+            "value": encode_bytes(&record.value),
+            "publisher": record.publisher.as_ref().map(|p| p.to_string()),
+            "expires": record.expires.map(to_epoch_millis),
+        });
+        writeln!(file, "{}", line)?;
+    }
+    for provider in &snapshot.providers {
+        let line = json!({
+            "key": encode_bytes(provider.key.as_ref()),
+            "provider": provider.provider.to_string(),
+            "expires": provider.expires.map(to_epoch_millis),
+        });
+        writeln!(file, "{}", line)?;
+    }
+    file.flush()
+}
+
+// Read the records of a snapshot, leaving out the ones that expired in
+// the meantime.
+pub fn load(path: &Path) -> io::Result<Snapshot> {
+    let mut snapshot = Snapshot::default();
+    let now = Instant::now();
+    for (n, line) in BufReader::new(File::open(path)?).lines().enumerate()
+    {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", n + 1, err),
+            )
+        };
+        let line: Value = serde_json::from_str(&line)
+            .map_err(|err| invalid(err.to_string()))?;
+        if line.get("provider").is_some() {
+            let provider = parse_provider(&line).map_err(invalid)?;
+            if !provider.is_expired(now) {
+                snapshot.providers.push(provider);
+            }
+            continue;
+        }
+        let record = parse_record(&line).map_err(invalid)?;
+        if !record.is_expired(now) {
+            snapshot.records.push(record);
+        }
+    }
+    Ok(snapshot)
+}
+
+fn parse_record(line: &Value) -> Result<Record, String> {
+    let key = decode_bytes(&line["key"]).ok_or("expected a key")?;
+    let value = decode_bytes(&line["value"]).ok_or("expected a value")?;
+    let mut record = Record::new(key, value);
+    record.publisher = match &line["publisher"] {
+        Value::Null => None,
+        Value::String(peer) => Some(
+            peer.parse::<PeerId>()
+                .map_err(|_| format!("invalid publisher {:?}", peer))?,
+        ),
+        _ => return Err("the publisher should be a peer id".to_string()),
+    };
+    record.expires = parse_expires(&line["expires"])?;
+    Ok(record)
+}
+
+fn parse_provider(line: &Value) -> Result<ProviderRecord, String> {
+    let key = decode_bytes(&line["key"]).ok_or("expected a key")?;
+    let provider = line["provider"]
+        .as_str()
+        .and_then(|peer| peer.parse::<PeerId>().ok())
+        .ok_or("the provider should be a peer id")?;
+    let mut record = ProviderRecord::new(key, provider);
+    record.expires = parse_expires(&line["expires"])?;
+    Ok(record)
+}
+
+fn parse_expires(expires: &Value) -> Result<Option<Instant>, String> {
+    match expires {
+        Value::Null => Ok(None),
+        expires => Ok(Some(from_epoch_millis(
+            expires.as_u64().ok_or("expires should be a number")?,
+        ))),
+    }
+}
+
+fn encode_bytes(bytes: &[u8]) -> Value {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => json!(text),
+        Err(_) => json!({ "base58": bs58::encode(bytes).into_string() }),
+    }
+}
+
+fn decode_bytes(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::String(text) => Some(text.clone().into_bytes()),
+        Value::Object(fields) => {
+            bs58::decode(fields.get("base58")?.as_str()?)
+                .into_vec()
+                .ok()
+        }
+        _ => None,
+    }
+}
+
+// Instants only mean something within one run, so expiries are written
+// as times of day.
+fn to_epoch_millis(at: Instant) -> u64 {
+    let now = SystemTime::now();
+    let at = now + at.saturating_duration_since(Instant::now());
+    at.duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn from_epoch_millis(millis: u64) -> Instant {
+    let at = UNIX_EPOCH + Duration::from_millis(millis);
+    let left = at.duration_since(SystemTime::now()).unwrap_or_default();
+    Instant::now() + left
+}
@@ -0,0 +1,288 @@
+use crate::{
+    api::ApiReply,
+    behaviour::MyBehavior,
+    config::{Opts, SoakArgs},
+    results::{self, OpResult},
+    seed, simulate,
+};
+use async_std::task;
+use futures::{channel::oneshot, future, FutureExt};
+use futures_timer::Delay;
+use libp2p::{
+    identity::Keypair,
+    kad::{record::Key, QueryResult, Quorum, Record},
+    Swarm,
+};
+use rand::{seq::SliceRandom, Rng};
+use serde_json::json;
+use std::{
+    error::Error,
+    fmt,
+    str::FromStr,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+// How often nodes leave and rejoin, like `0.1/min`: the share of the
+// nodes that churn each second, minute or hour.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChurnRate {
+    pub fraction: f64,
+    pub per: Duration,
+}
+
+impl FromStr for ChurnRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || format!("{:?} is not a churn rate (like 0.1/min)", s);
+        let (fraction, per) = s.split_once('/').ok_or_else(invalid)?;
+        let fraction = fraction
+            .parse::<f64>()
+            .ok()
+            .filter(|fraction| *fraction >= 0.0)
+            .ok_or_else(invalid)?;
+        let per = match per {
+            "s" | "sec" => Duration::from_secs(1),
+            "m" | "min" => Duration::from_secs(60),
+            "h" | "hour" => Duration::from_secs(3600),
+            _ => return Err(invalid()),
+        };
+        Ok(ChurnRate { fraction, per })
+    }
+}
+
+// What a finished query was for.
+enum Op {
+    Put(usize),
+    Get,
+}
+
+// Soak test a simulated network: for `duration`, PUT a new record and GET
+// a random one that was PUT before on every tick, while nodes leave and
+// come back (with the same identity, but an empty store) at the churn
+// rate. Every report interval, print how many of the GETs found their
+// record, which is the availability of the records over time.
+pub fn run(opts: &Opts, args: &SoakArgs) -> Result<(), Box<dyn Error>> {
+    let keys: Vec<Keypair> =
+        (0..args.nodes).map(|_| seed::keypair()).collect();
+    let mut swarms = simulate::spawn_with_keys(opts, keys.clone())?;
+    println!(
+        "Soaking {} nodes for {:?}, churning {} of them every {:?}",
+        args.nodes, args.duration, args.churn.fraction, args.churn.per
+    );
+
+    let began = Instant::now();
+    let mut rng = seed::rng();
+    let mut tick = Delay::new(args.interval);
+    let mut running: Vec<(Op, Instant, oneshot::Receiver<ApiReply>)> =
+        Vec::new();
+
+    // Records whose PUT succeeded (so that a GET should find them)
+    let mut stored: Vec<usize> = Vec::new();
+    let mut next_record = 0;
+
+    // How many nodes are due to churn (the fraction carries over)
+    let mut churn_due = 0.0;
+    let churn_per_tick = args.churn.fraction * args.nodes as f64
+        / args.churn.per.as_secs_f64()
+        * args.interval.as_secs_f64();
+
+    let mut window = Window::new(began);
+    let mut total = Window::new(began);
+    let mut puts = OpResult::new("PUTs");
+    let mut gets = OpResult::new("GETs");
+
+    let soak = future::poll_fn(
+        |cx: &mut Context<'_>| -> Poll<Result<(), Box<dyn Error>>> {
+            loop {
+                while tick.poll_unpin(cx).is_ready() {
+                    tick = Delay::new(args.interval);
+
+                    if began.elapsed() >= args.duration {
+                        return Poll::Ready(Ok(()));
+                    }
+
+                    // Some nodes leave, and come right back
+                    churn_due += churn_per_tick;
+                    while churn_due >= 1.0 {
+                        churn_due -= 1.0;
+                        let node = rng.gen_range(0, args.nodes);
+                        rejoin(opts, &mut swarms, &keys, node)?;
+                        window.churned += 1;
+                        total.churned += 1;
+                    }
+
+                    // The steady workload
+                    let node = rng.gen_range(0, args.nodes);
+                    let record = Record {
+                        key: key(next_record),
+                        value: vec![b'x'; args.value_size],
+                        publisher: None,
+                        expires: None,
+                    };
+                    if let Ok(id) = swarms[node]
+                        .kademlia
+                        .put_record(record, Quorum::One)
+                    {
+                        let (tx, rx) = oneshot::channel();
+                        swarms[node].api_pending.insert(id, tx);
+                        running.push((
+                            Op::Put(next_record),
+                            Instant::now(),
+                            rx,
+                        ));
+                    }
+                    next_record += 1;
+
+                    if let Some(&n) = stored.choose(&mut rng) {
+                        let node = rng.gen_range(0, args.nodes);
+                        let id = swarms[node]
+                            .kademlia
+                            .get_record(&key(n), Quorum::One);
+                        let (tx, rx) = oneshot::channel();
+                        swarms[node].api_pending.insert(id, tx);
+                        running.push((Op::Get, Instant::now(), rx));
+                    }
+
+                    if window.started.elapsed() >= args.report {
+                        println!("[{:>8.0?}] {}", began.elapsed(), window);
+                        window = Window::new(Instant::now());
+                    }
+                }
+
+                simulate::poll_all(&mut swarms, cx);
+
+                // Collect whatever finished
+                let before = running.len();
+                let mut i = 0;
+                while i < running.len() {
+                    match running[i].2.poll_unpin(cx) {
+                        Poll::Ready(reply) => {
+                            let (op, started, _) = running.swap_remove(i);
+                            let ok = match reply {
+                                Ok(ApiReply::Query(
+                                    QueryResult::PutRecord(result),
+                                    _,
+                                )) => result.is_ok(),
+                                Ok(ApiReply::Query(
+                                    QueryResult::GetRecord(result),
+                                    _,
+                                )) => result.is_ok(),
+                                _ => false,
+                            };
+                            match op {
+                                Op::Put(n) => {
+                                    puts.count(ok, started.elapsed());
+                                    if ok {
+                                        stored.push(n);
+                                    }
+                                }
+                                Op::Get => {
+                                    gets.count(ok, started.elapsed());
+                                    window.count_get(ok);
+                                    total.count_get(ok);
+                                }
+                            }
+                        }
+                        Poll::Pending => i += 1,
+                    }
+                }
+                if running.len() == before {
+                    return Poll::Pending;
+                }
+            }
+        },
+    );
+    task::block_on(soak)?;
+
+    println!("Soak finished after {:.0?}: {}", began.elapsed(), total);
+    if let Some(path) = &args.results {
+        puts.elapsed = began.elapsed();
+        gets.elapsed = began.elapsed();
+        let params = json!({
+            "nodes": args.nodes,
+            "duration_s": args.duration.as_secs_f64(),
+            "churn": format!("{}/{}s", args.churn.fraction, args.churn.per.as_secs()),
+            "interval_ms": args.interval.as_millis() as u64,
+            "value_size": args.value_size,
+            "churned": total.churned,
+        });
+        results::write(path, "soak", params, &[puts, gets])?;
+        println!("Wrote the results to {}", path.display());
+    }
+    Ok(())
+}
+
+// Replace a node with a fresh one that has the same identity (and, with
+// the memory transport, the same address), and let it find its way back
+// into the network through some other node.
+fn rejoin(
+    opts: &Opts,
+    swarms: &mut Vec<Swarm<MyBehavior>>,
+    keys: &[Keypair],
+    node: usize,
+) -> Result<(), Box<dyn Error>> {
+    // The old node has to be gone before the new one can take its address
+    drop(swarms.remove(node));
+    let mut swarm = simulate::start_node(opts, keys[node].clone(), node)?;
+
+    let others: Vec<_> = (0..swarms.len()).collect();
+    if let Some(&other) = others.choose(&mut seed::rng()) {
+        let peer_id = Swarm::local_peer_id(&swarms[other]).clone();
+        if let Some(addr) = Swarm::listeners(&swarms[other]).next() {
+            swarm.kademlia.add_address(&peer_id, addr.clone());
+        }
+        swarm.kademlia.bootstrap().ok();
+    }
+    swarms.insert(node, swarm);
+    Ok(())
+}
+
+// The key of the `n`th record.
+fn key(n: usize) -> Key {
+    Key::new(&format!("soak-{}", n))
+}
+
+// What happened over some stretch of the soak.
+struct Window {
+    started: Instant,
+    gets: u64,
+    found: u64,
+    churned: u64,
+}
+
+impl Window {
+    fn new(started: Instant) -> Self {
+        Window {
+            started,
+            gets: 0,
+            found: 0,
+            churned: 0,
+        }
+    }
+
+    fn count_get(&mut self, found: bool) {
+        self.gets += 1;
+        if found {
+            self.found += 1;
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let availability = if self.gets > 0 {
+            100.0 * self.found as f64 / self.gets as f64
+        } else {
+            100.0
+        };
+        write!(
+            f,
+            "availability {:.1}% ({}/{} GETs found their record), {} \
+             nodes churned",
+            availability, self.found, self.gets, self.churned
+        )
+    }
+}
@@ -0,0 +1,287 @@
+use crate::validate::GcStats;
+use libp2p::{
+    kad::{QueryResult, QueryStats},
+    PeerId,
+};
+use std::{
+    collections::HashSet,
+    fmt,
+    time::{Duration, Instant},
+};
+
+// What happened to the connections of the node, all together (for
+// /metrics, and to tell flapping connections from steady ones).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionEvents {
+    pub established_inbound: u64,
+    pub established_outbound: u64,
+    // By why they closed: the connection failed (or the other end hung
+    // up), went idle, or one of the protocols gave up on it
+    pub closed_io: u64,
+    pub closed_idle: u64,
+    pub closed_handler: u64,
+    // Connections that peers opened to us, but failed before they were
+    // established
+    pub incoming_errors: u64,
+    pub dials: u64,
+    // Addresses that dials couldn't reach
+    pub dial_failures: u64,
+}
+
+impl ConnectionEvents {
+    pub fn closed(&self) -> u64 {
+        self.closed_io + self.closed_idle + self.closed_handler
+    }
+}
+
+// Counters for everything interesting that happened during this run of
+// the node. These are printed when the node shuts down.
+#[derive(Debug)]
+pub struct SessionStats {
+    started: Instant,
+
+    pub gets_issued: u64,
+    pub gets_succeeded: u64,
+    pub gets_failed: u64,
+    // GETs that waited for one of the same key instead (see
+    // --coalesce-gets)
+    pub gets_coalesced: u64,
+    // GETs answered from the cache (see --cache-size)
+    pub gets_cached: u64,
+
+    pub puts_issued: u64,
+    pub puts_succeeded: u64,
+    pub puts_failed: u64,
+
+    // What the GETs and PUTs took, all together: the requests they sent
+    // to peers, the ones of those that failed, and the time
+    pub lookups: u64,
+    pub lookup_requests: u64,
+    pub lookup_failures: u64,
+    pub lookup_time: Duration,
+
+    // Every peer we have heard of (mDNS keeps re-announcing the same ones)
+    pub peers_discovered: HashSet<PeerId>,
+    // Taken out of the routing table for not answering pings
+    pub peers_culled: u64,
+    // Queries that took longer than --slow-query
+    pub slow_queries: u64,
+
+    // The connections that are open, the most there were at once, and
+    // how many were closed for going over the limits
+    pub inbound_connections: usize,
+    pub outbound_connections: usize,
+    pub peak_connections: usize,
+    pub connections_refused: u64,
+    pub connection_events: ConnectionEvents,
+
+    // What the local store held, refused (by the validators, for being
+    // full and for publishers over their quotas) and evicted (see
+    // MyBehavior::summary)
+    pub records_stored: usize,
+    pub records_rejected: u64,
+    pub records_refused_full: u64,
+    pub records_evicted: u64,
+    pub records_refused_quota: u64,
+    // What was removed from the store for being past its expiry
+    pub store_gc: GcStats,
+
+    // The kademlia requests of peers that went over the inbound limits,
+    // and were refused or held back
+    pub inbound_rejected: u64,
+    pub inbound_held_back: u64,
+
+    // The replies that --fault dropped, and corrupted
+    pub replies_dropped: u64,
+    pub replies_corrupted: u64,
+    pub replies_tampered: u64,
+    // The records --store-churn garbled, and removed
+    pub records_overwritten: u64,
+    pub records_deleted: u64,
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        SessionStats {
+            started: Instant::now(),
+            gets_issued: 0,
+            gets_succeeded: 0,
+            gets_failed: 0,
+            gets_coalesced: 0,
+            gets_cached: 0,
+            puts_issued: 0,
+            puts_succeeded: 0,
+            puts_failed: 0,
+            lookups: 0,
+            lookup_requests: 0,
+            lookup_failures: 0,
+            lookup_time: Duration::default(),
+            peers_discovered: HashSet::new(),
+            peers_culled: 0,
+            slow_queries: 0,
+            inbound_connections: 0,
+            outbound_connections: 0,
+            peak_connections: 0,
+            connections_refused: 0,
+            connection_events: ConnectionEvents::default(),
+            records_stored: 0,
+            records_rejected: 0,
+            records_refused_full: 0,
+            records_evicted: 0,
+            records_refused_quota: 0,
+            store_gc: GcStats::default(),
+            inbound_rejected: 0,
+            inbound_held_back: 0,
+            replies_dropped: 0,
+            replies_corrupted: 0,
+            replies_tampered: 0,
+            records_overwritten: 0,
+            records_deleted: 0,
+        }
+    }
+
+    // Count the outcome of a finished query.
+    pub fn count_result(&mut self, result: &QueryResult) {
+        match result {
+            QueryResult::GetRecord(Ok(_)) => self.gets_succeeded += 1,
+            QueryResult::GetRecord(Err(_)) => self.gets_failed += 1,
+            QueryResult::PutRecord(Ok(_)) => self.puts_succeeded += 1,
+            QueryResult::PutRecord(Err(_)) => self.puts_failed += 1,
+            _ => {}
+        }
+    }
+
+    // Count what a finished GET or PUT took.
+    pub fn count_lookup(
+        &mut self,
+        result: &QueryResult,
+        stats: &QueryStats,
+    ) {
+        match result {
+            QueryResult::GetRecord(_) | QueryResult::PutRecord(_) => {
+                self.lookups += 1;
+                self.lookup_requests += stats.num_requests() as u64;
+                self.lookup_failures += stats.num_failures() as u64;
+                self.lookup_time += stats.duration().unwrap_or_default();
+            }
+            _ => {}
+        }
+    }
+
+    // How long the node has been running for.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for SessionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Session summary:")?;
+        writeln!(f, "  uptime:           {:.1?}", self.uptime())?;
+        write!(
+            f,
+            "  GETs:             {} issued, {} succeeded, {} failed",
+            self.gets_issued, self.gets_succeeded, self.gets_failed
+        )?;
+        if self.gets_coalesced > 0 {
+            write!(f, ", {} coalesced", self.gets_coalesced)?;
+        }
+        if self.gets_cached > 0 {
+            write!(f, ", {} from the cache", self.gets_cached)?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "  PUTs:             {} issued, {} succeeded, {} failed",
+            self.puts_issued, self.puts_succeeded, self.puts_failed
+        )?;
+        if self.lookups > 0 {
+            let per_lookup = |n: u64| n as f64 / self.lookups as f64;
+            writeln!(
+                f,
+                "  lookups:          {:.1?}, {:.1} requests ({:.1} failed) \
+                 on average",
+                self.lookup_time / self.lookups as u32,
+                per_lookup(self.lookup_requests),
+                per_lookup(self.lookup_failures)
+            )?;
+        }
+        if self.slow_queries > 0 {
+            writeln!(
+                f,
+                "  slow queries:     {} over --slow-query",
+                self.slow_queries
+            )?;
+        }
+        writeln!(
+            f,
+            "  connections:      {} open ({} inbound), {} at most, {} over \
+             the limits",
+            self.inbound_connections + self.outbound_connections,
+            self.inbound_connections,
+            self.peak_connections,
+            self.connections_refused
+        )?;
+        let events = &self.connection_events;
+        writeln!(
+            f,
+            "  connection churn: {} opened ({} inbound), {} closed ({} by \
+             i/o errors, {} idle, {} by protocols), {} incoming failed, {} \
+             dials ({} addresses unreachable)",
+            events.established_inbound + events.established_outbound,
+            events.established_inbound,
+            events.closed(),
+            events.closed_io,
+            events.closed_idle,
+            events.closed_handler,
+            events.incoming_errors,
+            events.dials,
+            events.dial_failures
+        )?;
+        writeln!(
+            f,
+            "  store:            {} records, {} refused by the validators, \
+             {} refused while full, {} over quota, {} evicted",
+            self.records_stored,
+            self.records_rejected,
+            self.records_refused_full,
+            self.records_refused_quota,
+            self.records_evicted
+        )?;
+        let gc = &self.store_gc;
+        writeln!(
+            f,
+            "  expired:          {} records and {} provider records \
+             removed ({} collections, the last one took {:.1?})",
+            gc.records, gc.providers, gc.runs, gc.took
+        )?;
+        writeln!(
+            f,
+            "  inbound requests: {} refused, {} held back over the limits",
+            self.inbound_rejected, self.inbound_held_back
+        )?;
+        writeln!(
+            f,
+            "  injected faults:  {} replies dropped, {} corrupted, {} \
+             tampered with by the adversary, {} records overwritten and \
+             {} deleted in the store",
+            self.replies_dropped,
+            self.replies_corrupted,
+            self.replies_tampered,
+            self.records_overwritten,
+            self.records_deleted
+        )?;
+        write!(
+            f,
+            "  peers discovered: {} ({} culled for not answering pings)",
+            self.peers_discovered.len(),
+            self.peers_culled
+        )
+    }
+}
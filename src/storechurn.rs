@@ -0,0 +1,130 @@
+use crate::chaos::{self, parse_duration, parse_rate};
+use futures::FutureExt;
+use futures_timer::Delay;
+use std::{fmt, str::FromStr, task::Context, time::Duration};
+
+// How often the store gets hit, unless the spec says otherwise.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+// What --store-churn does to the local store, like
+// `overwrite=0.05,delete=0.02,every=30s`: every so often, each record
+// has a chance to get its value garbled (overwrite), and a chance to be
+// gone (delete), as on a flaky disk. Nothing tells the rest of the
+// network, so it is up to republishing, reads with a quorum and the
+// auditor to notice, and repair it.
+//
+// - overwrite: the chance that a record has a byte of its value flipped
+//   (it keeps its key, publisher and expiry, and skips the validators)
+// - delete: the chance that a record is removed
+// - every: how often (10s by default)
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreChurnConfig {
+    pub overwrite: f64,
+    pub delete: f64,
+    pub interval: Duration,
+}
+
+impl Default for StoreChurnConfig {
+    fn default() -> Self {
+        StoreChurnConfig {
+            overwrite: 0.0,
+            delete: 0.0,
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+}
+
+impl FromStr for StoreChurnConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = StoreChurnConfig::default();
+        for part in s.split(',').filter(|part| !part.is_empty()) {
+            let (name, value) = part.split_once('=').ok_or_else(|| {
+                format!("expected name=value, got {:?}", part)
+            })?;
+            match name {
+                "overwrite" => config.overwrite = parse_rate(value)?,
+                "delete" => config.delete = parse_rate(value)?,
+                "every" => {
+                    config.interval = parse_duration(value)?;
+                    if config.interval.is_zero() {
+                        return Err("every has to be more than 0s".into());
+                    }
+                }
+                _ => return Err(format!("unknown churn {:?}", name)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for StoreChurnConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "overwrite={},delete={},every={:?}",
+            self.overwrite, self.delete, self.interval
+        )
+    }
+}
+
+// What happened to a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Churned {
+    Overwritten,
+    Deleted,
+}
+
+impl fmt::Display for Churned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Churned::Overwritten => write!(f, "overwrote"),
+            Churned::Deleted => write!(f, "deleted"),
+        }
+    }
+}
+
+// Hits the store every so often (see `StoreChurnConfig`).
+#[derive(Debug)]
+pub struct StoreChurn {
+    config: StoreChurnConfig,
+    timer: Delay,
+    // How many records were garbled, and removed, so far
+    pub overwritten: u64,
+    pub deleted: u64,
+}
+
+impl StoreChurn {
+    pub fn new(config: StoreChurnConfig) -> Self {
+        StoreChurn {
+            timer: Delay::new(config.interval),
+            config,
+            overwritten: 0,
+            deleted: 0,
+        }
+    }
+
+    // Whether it is time to hit the store again.
+    pub fn due(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.timer.poll_unpin(cx).is_pending() {
+            return false;
+        }
+        self.timer = Delay::new(self.config.interval);
+        let _ = self.timer.poll_unpin(cx);
+        true
+    }
+
+    // Roll the dice for a record.
+    pub fn pick(&mut self) -> Option<Churned> {
+        if chaos::happens(self.config.delete) {
+            self.deleted += 1;
+            Some(Churned::Deleted)
+        } else if chaos::happens(self.config.overwrite) {
+            self.overwritten += 1;
+            Some(Churned::Overwritten)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use crate::{
+    api::{self, ApiReply, ApiRequest},
+    behaviour::MyBehavior,
+    config::Opts,
+    seed, simulate, Error,
+};
+use async_std::task;
+use clap::Parser;
+use futures::{
+    channel::{mpsc, oneshot},
+    future, StreamExt,
+};
+use libp2p::{
+    kad::{record::Key, GetRecordError, QueryResult},
+    PeerId, Swarm,
+};
+use std::{
+    collections::HashSet,
+    io, slice,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+    thread,
+};
+
+// The number of the next node to start, across all the networks of the
+// process (the memory transport has one address space for all of them).
+static NEXT_NODE: AtomicUsize = AtomicUsize::new(1000);
+
+// A network of nodes inside this process, for the integration tests of
+// other crates:
+//
+//   let net = nettest::testnet::spawn(4).await?;
+//   net.node(0).put("foo", "bar").await?;
+//   assert_eq!(net.node(3).get("foo").await?, Some(b"bar".to_vec()));
+//
+// The nodes run on a thread of their own (like in --simulate, over the
+// memory transport), until the network and all of its nodes are dropped.
+#[derive(Debug)]
+pub struct Testnet {
+    nodes: Vec<TestNode>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+// One of the nodes of a `Testnet`.
+#[derive(Debug, Clone)]
+pub struct TestNode {
+    index: usize,
+    peer_id: PeerId,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+// What the thread of the network is asked to do.
+#[derive(Debug)]
+enum Command {
+    Api(usize, ApiRequest),
+    Kill(usize, oneshot::Sender<()>),
+    // The groups of nodes that can only reach each other
+    Partition(Vec<Vec<usize>>, oneshot::Sender<()>),
+    Heal(oneshot::Sender<()>),
+}
+
+// Start `nodes` nodes with the default options, and wait for them to
+// finish bootstrapping.
+pub async fn spawn(nodes: usize) -> Result<Testnet, Error> {
+    let opts = Opts::try_parse_from(["nettest"]).expect("no options");
+    spawn_with(opts, nodes).await
+}
+
+// The same, with the given options (for the store limits, timeouts and
+// the like).
+pub async fn spawn_with(
+    opts: Opts,
+    nodes: usize,
+) -> Result<Testnet, Error> {
+    let (commands, commands_rx) = mpsc::unbounded();
+    let (ready, ready_rx) = oneshot::channel();
+    let first = NEXT_NODE.fetch_add(nodes, Ordering::SeqCst);
+    thread::spawn(move || {
+        let keys = (0..nodes).map(|_| seed::keypair()).collect();
+        match simulate::spawn_numbered(&opts, keys, first) {
+            Ok(swarms) => task::block_on(run(swarms, commands_rx, ready)),
+            Err(err) => {
+                let err = io::Error::other(err.to_string());
+                let _ = ready.send(Err(Error::Transport(err)));
+            }
+        }
+    });
+    let peer_ids = ready_rx.await.map_err(|_| {
+        Error::Query("the network stopped while starting".into())
+    })??;
+    let nodes = peer_ids
+        .into_iter()
+        .enumerate()
+        .map(|(index, peer_id)| TestNode {
+            index,
+            peer_id,
+            commands: commands.clone(),
+        })
+        .collect();
+    Ok(Testnet { nodes, commands })
+}
+
+impl Testnet {
+    pub fn nodes(&self) -> &[TestNode] {
+        &self.nodes
+    }
+
+    // Node number `index`, which panics if there are fewer nodes than
+    // that (like indexing `nodes()` does).
+    pub fn node(&self, index: usize) -> &TestNode {
+        &self.nodes[index]
+    }
+
+    // Split the network: the nodes of each group can only reach each
+    // other from now on (the nodes left out make up a group of their
+    // own). Connections across groups are closed.
+    pub async fn partition(&self, groups: &[&[usize]]) {
+        let groups = groups.iter().map(|group| group.to_vec()).collect();
+        self.ask(|done| Command::Partition(groups, done)).await
+    }
+
+    // Let every node reach every other one again.
+    pub async fn heal(&self) {
+        self.ask(Command::Heal).await
+    }
+
+    async fn ask(
+        &self,
+        command: impl FnOnce(oneshot::Sender<()>) -> Command,
+    ) {
+        let (done, done_rx) = oneshot::channel();
+        if self.commands.unbounded_send(command(done)).is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+}
+
+impl TestNode {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    // Store `value` under `key` in the DHT.
+    pub async fn put(
+        &self,
+        key: &str,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let key = Key::new(&key);
+        let value = value.into();
+        match self
+            .ask(|reply| ApiRequest::PutRecord(key, value, reply))
+            .await?
+        {
+            ApiReply::Query(QueryResult::PutRecord(Ok(_)), _) => Ok(()),
+            ApiReply::Query(QueryResult::PutRecord(Err(err)), _) => {
+                Err(Error::Query(format!("{:?}", err)))
+            }
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    // Look `key` up in the DHT (None if no peer has it).
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let key = Key::new(&key);
+        match self.ask(|reply| ApiRequest::GetRecord(key, reply)).await? {
+            ApiReply::Query(QueryResult::GetRecord(Ok(ok)), _) => Ok(ok
+                .records
+                .into_iter()
+                .next()
+                .map(|record| record.record.value)),
+            ApiReply::Query(
+                QueryResult::GetRecord(Err(GetRecordError::NotFound {
+                    ..
+                })),
+                _,
+            ) => Ok(None),
+            ApiReply::Query(QueryResult::GetRecord(Err(err)), _) => {
+                Err(Error::Query(format!("{:?}", err)))
+            }
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    // Stop the node, for good (its connections close, and whatever asks
+    // it anything afterwards gets an error).
+    pub async fn kill(&self) {
+        let (done, done_rx) = oneshot::channel();
+        let kill = Command::Kill(self.index, done);
+        if self.commands.unbounded_send(kill).is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+
+    async fn ask(
+        &self,
+        request: impl FnOnce(oneshot::Sender<ApiReply>) -> ApiRequest,
+    ) -> Result<ApiReply, Error> {
+        let (reply, reply_rx) = oneshot::channel();
+        let command = Command::Api(self.index, request(reply));
+        let not_running = Error::NotRunning(self.index);
+        if self.commands.unbounded_send(command).is_err() {
+            return Err(not_running);
+        }
+        match reply_rx.await.map_err(|_| not_running)? {
+            ApiReply::Error(err) => Err(Error::Query(err)),
+            reply => Ok(reply),
+        }
+    }
+}
+
+fn unexpected(reply: ApiReply) -> Error {
+    Error::Query(format!("unexpected reply {:?}", reply))
+}
+
+// Drive the nodes: bootstrap them, say when they are done, and then do
+// what the handles ask until they are all dropped.
+async fn run(
+    swarms: Vec<Swarm<MyBehavior>>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    ready: oneshot::Sender<Result<Vec<PeerId>, Error>>,
+) {
+    let peer_ids: Vec<PeerId> = swarms
+        .iter()
+        .map(|swarm| Swarm::local_peer_id(swarm).clone())
+        .collect();
+    let mut swarms: Vec<Option<Swarm<MyBehavior>>> =
+        swarms.into_iter().map(Some).collect();
+    let mut ready = Some(ready);
+    // The pairs of nodes a partition keeps apart
+    let mut apart: HashSet<(usize, usize)> = HashSet::new();
+
+    future::poll_fn(|cx: &mut Context<'_>| loop {
+        for swarm in swarms.iter_mut().flatten() {
+            simulate::poll_all(slice::from_mut(swarm), cx);
+        }
+        if ready.is_some() {
+            let bootstrapping = swarms.iter_mut().flatten().any(|swarm| {
+                swarm.kademlia.iter_queries().next().is_some()
+            });
+            if bootstrapping {
+                return Poll::Pending;
+            }
+            let _ = ready.take().unwrap().send(Ok(peer_ids.clone()));
+        }
+
+        match commands.poll_next_unpin(cx) {
+            Poll::Ready(Some(Command::Api(node, request))) => {
+                // A killed node drops the request, which its handle
+                // hears about
+                if let Some(Some(swarm)) = swarms.get_mut(node) {
+                    api::handle_request(swarm, request);
+                }
+            }
+            Poll::Ready(Some(Command::Kill(node, done))) => {
+                if let Some(swarm) = swarms.get_mut(node) {
+                    *swarm = None;
+                }
+                let _ = done.send(());
+            }
+            Poll::Ready(Some(Command::Partition(groups, done))) => {
+                for (a, b) in simulate::split_pairs(&groups, swarms.len())
+                {
+                    if let Some(swarm) = &mut swarms[a] {
+                        simulate::keep_apart(swarm, &peer_ids[b]);
+                    }
+                    apart.insert((a, b));
+                }
+                let _ = done.send(());
+            }
+            Poll::Ready(Some(Command::Heal(done))) => {
+                for (a, b) in apart.drain() {
+                    if let Some(swarm) = &mut swarms[a] {
+                        simulate::bring_together(swarm, &peer_ids[b]);
+                    }
+                }
+                let _ = done.send(());
+            }
+            // Every handle is gone
+            Poll::Ready(None) => return Poll::Ready(()),
+            Poll::Pending => return Poll::Pending,
+        }
+    })
+    .await
+}
@@ -0,0 +1,742 @@
+use crate::{
+    adversary::Adversary,
+    capture::Capture,
+    dials::{Admission, DialLimits, DialQueue},
+    fault::{self, Fault, FaultConfig},
+    latency::Latencies,
+    output::Output,
+    provenance::Paths,
+    validate::ValidatingStore,
+};
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::{
+    core::{
+        connection::{ConnectionId, ListenerId},
+        upgrade::{NegotiationError, UpgradeError},
+        ConnectedPoint, Multiaddr, PeerId,
+    },
+    kad::{
+        handler::{
+            KademliaHandler, KademliaHandlerConfig, KademliaHandlerEvent,
+            KademliaHandlerIn, KademliaHandlerQueryErr, KademliaRequestId,
+        },
+        record::Key,
+        Kademlia, KademliaEvent, PutRecordContext, QueryId, QueryInfo,
+        QueryResult,
+    },
+    swarm::{
+        DialPeerCondition, NetworkBehaviour, NetworkBehaviourAction,
+        NotifyHandler, PollParameters, ProtocolsHandlerUpgrErr,
+    },
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error, io,
+    ops::{Deref, DerefMut},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+// The most requests to hold back at once, with `delay` (the ones after
+// that are refused).
+const MAX_DELAYED: usize = 256;
+
+// The lowest rate there may be a limit of: one request every 100
+// seconds. Anything slower is as good as refusing every request.
+const MIN_RATE: f64 = 0.01;
+
+// How many requests a second peers may send us, each and all together,
+// and whether to hold the others back until they may (rather than
+// refuse them).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InboundLimits {
+    pub per_peer: Option<f64>,
+    pub global: Option<f64>,
+    pub delay: bool,
+}
+
+// Parse a rate of --inbound-rate (or --inbound-rate-per-peer), which
+// has to be at least MIN_RATE.
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s
+        .parse()
+        .map_err(|_| format!("{:?} isn't a number of requests", s))?;
+    if !rate.is_finite() {
+        return Err(format!("{} isn't a finite rate", s));
+    }
+    if rate < MIN_RATE {
+        return Err(format!(
+            "{} is less than the lowest rate, {} requests a second",
+            s, MIN_RATE
+        ));
+    }
+    Ok(rate)
+}
+
+// Kademlia, with limits on the requests peers send it. A request over a
+// limit is refused (its substream is reset, so the peer hears about it
+// right away), or with `delay`, held back until it fits. Everything else
+// goes straight through, and Deref gets to the Kademlia inside.
+//
+// Kademlia puts every peer it connects to in its routing table, whether
+// or not the peer speaks its protocol. Peers that turn out not to (from
+// another DHT, with another --network-id) are taken out of it again, and
+// kept out.
+//
+// In client mode, the connection handlers turn down what peers ask
+// (peers see that as us not speaking the protocol), and we only query.
+//
+// A GET that finds a record caches it at the closest peer that didn't
+// have it, unless caching is off; how those puts went is kept for the
+// event log.
+//
+// With --fault, some of the replies we send get lost or garbled.
+//
+// With --capture, every message that goes through is written down (see
+// `capture`).
+//
+// The time each peer takes to answer our requests goes into `latencies`,
+// and the path each GET takes into `paths`.
+//
+// The dials kademlia wants to make go through `dials`, which may hold
+// them back (see `DialQueue`).
+pub struct Throttled {
+    inner: Kademlia<ValidatingStore>,
+    limits: InboundLimits,
+    global: Bucket,
+    peers: HashMap<PeerId, Bucket>,
+    delayed: VecDeque<(PeerId, ConnectionId, Request)>,
+    // Fires when the first held back request may go through
+    timer: Option<Delay>,
+    // The resets to send
+    resets: VecDeque<(PeerId, ConnectionId, Request)>,
+    // In client mode, how to set up the connection handlers
+    client: Option<KademliaHandlerConfig>,
+    // The peers that don't speak our kademlia protocol
+    foreign: HashSet<PeerId>,
+    caching: bool,
+    // The records being cached, by query
+    caches: HashMap<QueryId, Key>,
+    cached: VecDeque<CacheOutcome>,
+    faults: Option<FaultConfig>,
+    adversary: Option<Adversary>,
+    capture: Option<Capture>,
+    pub latencies: Latencies,
+    pub paths: Paths,
+    pub dials: DialQueue,
+    // The dials of others (see `dial`) that may go out
+    to_dial: VecDeque<PeerId>,
+    // How many requests were refused, and held back
+    pub rejected: u64,
+    pub held_back: u64,
+    // How many replies were dropped, and corrupted, on purpose
+    pub replies_dropped: u64,
+    pub replies_corrupted: u64,
+    // How many replies an adversary left something out of
+    pub replies_tampered: u64,
+}
+
+type Request = KademliaHandlerEvent<QueryId>;
+
+// How caching a record at a peer went. The peer isn't known when the
+// query never got an answer.
+#[derive(Debug)]
+pub struct CacheOutcome {
+    pub key: Key,
+    pub peer: Option<PeerId>,
+    pub error: Option<String>,
+}
+
+impl Throttled {
+    pub fn new(
+        inner: Kademlia<ValidatingStore>,
+        limits: InboundLimits,
+    ) -> Self {
+        Throttled {
+            inner,
+            limits,
+            global: Bucket::new(limits.global),
+            peers: HashMap::new(),
+            delayed: VecDeque::new(),
+            timer: None,
+            resets: VecDeque::new(),
+            client: None,
+            foreign: HashSet::new(),
+            caching: true,
+            caches: HashMap::new(),
+            cached: VecDeque::new(),
+            faults: None,
+            adversary: None,
+            capture: None,
+            latencies: Latencies::default(),
+            paths: Paths::default(),
+            dials: DialQueue::default(),
+            to_dial: VecDeque::new(),
+            rejected: 0,
+            held_back: 0,
+            replies_dropped: 0,
+            replies_corrupted: 0,
+            replies_tampered: 0,
+        }
+    }
+
+    // Only query the DHT from now on, with connection handlers set up by
+    // `handler` (which doesn't allow listening).
+    pub fn client_mode(&mut self, handler: KademliaHandlerConfig) {
+        self.client = Some(handler);
+    }
+
+    pub fn is_client(&self) -> bool {
+        self.client.is_some()
+    }
+
+    // Which replies to get wrong on purpose.
+    pub fn set_faults(&mut self, faults: Option<FaultConfig>) {
+        self.faults = faults;
+    }
+
+    // Work against the other nodes, the way `adversary` does.
+    pub fn set_adversary(&mut self, adversary: Option<Adversary>) {
+        self.adversary = adversary;
+    }
+
+    // Where to write down the messages, if anywhere.
+    pub fn set_capture(&mut self, capture: Option<Capture>) {
+        self.capture = capture;
+    }
+
+    // How many dials may go on at once, and wait.
+    pub fn set_dial_limits(&mut self, limits: DialLimits) {
+        self.dials = DialQueue::new(limits);
+    }
+
+    // Dial `peer` (unless connected), in turn with the dials of kademlia.
+    pub fn dial(&mut self, peer: PeerId) {
+        let condition = DialPeerCondition::Disconnected;
+        if self.dials.request(peer.clone(), condition) == Admission::Dial {
+            self.to_dial.push_back(peer);
+        }
+    }
+
+    // Whether GETs cache the records they find.
+    pub fn set_caching(&mut self, caching: bool) {
+        self.caching = caching;
+    }
+
+    // How the puts of found records at the closest peers went, since
+    // last asked.
+    pub fn cache_outcomes(&mut self) -> Vec<CacheOutcome> {
+        self.cached.drain(..).collect()
+    }
+
+    // Kademlia starts caching a record as the GET that found it ends.
+    // Keep track of those puts, or with caching off, stop them before
+    // they send anything.
+    fn start_caching(&mut self) {
+        for mut query in self.inner.iter_queries_mut() {
+            let key = match query.info() {
+                QueryInfo::PutRecord {
+                    context: PutRecordContext::Cache,
+                    record,
+                    ..
+                } => record.key.clone(),
+                _ => continue,
+            };
+            if !self.caching {
+                query.finish();
+            } else {
+                self.caches.entry(query.id()).or_insert(key);
+            }
+        }
+    }
+
+    // Note how the put of a record being cached went, if `event` says.
+    fn cache_answered(&mut self, peer: &PeerId, event: &Request) {
+        let (id, error) = match event {
+            KademliaHandlerEvent::PutRecordRes { user_data, .. } => {
+                (user_data, None)
+            }
+            KademliaHandlerEvent::QueryError { error, user_data } => {
+                (user_data, Some(error.to_string()))
+            }
+            _ => return,
+        };
+        if let Some(key) = self.caches.remove(id) {
+            self.cached.push_back(CacheOutcome {
+                key,
+                peer: Some(peer.clone()),
+                error,
+            });
+        }
+    }
+
+    // The puts that ended without an answer (kademlia gave up on them).
+    fn cache_timed_out(&mut self) {
+        let inner = &self.inner;
+        let cached = &mut self.cached;
+        self.caches.retain(|id, key| {
+            if inner.query(id).is_some() {
+                return true;
+            }
+            cached.push_back(CacheOutcome {
+                key: key.clone(),
+                peer: None,
+                error: Some("no answer".to_string()),
+            });
+            false
+        });
+    }
+
+    // Whether `peer` turned out not to speak our kademlia protocol.
+    pub fn is_foreign(&self, peer: &PeerId) -> bool {
+        self.foreign.contains(peer)
+    }
+
+    // Drop `peer` from the routing table, for good.
+    fn drop_foreign(&mut self, peer: &PeerId) {
+        self.inner.remove_peer(peer);
+        if self.foreign.insert(peer.clone()) {
+            Output::Terminal.info(format!(
+                "kad dht: {} doesn't speak {}, dropped it from the routing \
+                 table",
+                peer,
+                String::from_utf8_lossy(self.inner.protocol_name())
+            ));
+        }
+    }
+
+    // Whether `peer` may send a request now. If so, it counts against
+    // both limits.
+    fn admit(&mut self, peer: &PeerId, now: Instant) -> bool {
+        let rate = self.limits.per_peer;
+        let bucket = self
+            .peers
+            .entry(peer.clone())
+            .or_insert_with(|| Bucket::new(rate));
+        bucket.refill(rate, now);
+        self.global.refill(self.limits.global, now);
+        if bucket.tokens < 1.0 || self.global.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        self.global.tokens -= 1.0;
+        true
+    }
+
+    // Let the held back requests through that fit now (in order, for
+    // each peer), and set the timer for the next one.
+    fn release(&mut self, cx: &mut Context<'_>) {
+        if self.delayed.is_empty() {
+            return;
+        }
+        if let Some(timer) = &mut self.timer {
+            if timer.poll_unpin(cx).is_pending() {
+                return;
+            }
+        }
+        let now = Instant::now();
+        let mut waiting = VecDeque::new();
+        let mut blocked: Vec<PeerId> = Vec::new();
+        while let Some((peer, connection, request)) =
+            self.delayed.pop_front()
+        {
+            if blocked.contains(&peer) || !self.admit(&peer, now) {
+                blocked.push(peer.clone());
+                waiting.push_back((peer, connection, request));
+                continue;
+            }
+            self.inner.inject_event(peer, connection, request);
+        }
+        self.delayed = waiting;
+        if !self.delayed.is_empty() {
+            // Tokens come back at the lower of the two rates, at worst
+            let rate = [self.limits.per_peer, self.limits.global]
+                .iter()
+                .flatten()
+                .fold(f64::INFINITY, |a, &b| a.min(b));
+            let mut timer =
+                Delay::new(Duration::from_secs_f64(1.0 / rate));
+            let _ = timer.poll_unpin(cx);
+            self.timer = Some(timer);
+        }
+    }
+}
+
+impl Deref for Throttled {
+    type Target = Kademlia<ValidatingStore>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for Throttled {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+// The requests that peers send (the rest of what handlers report are
+// answers to our own requests).
+fn is_request(event: &Request) -> bool {
+    matches!(
+        event,
+        KademliaHandlerEvent::FindNodeReq { .. }
+            | KademliaHandlerEvent::GetProvidersReq { .. }
+            | KademliaHandlerEvent::AddProvider { .. }
+            | KademliaHandlerEvent::GetRecord { .. }
+            | KademliaHandlerEvent::PutRecord { .. }
+    )
+}
+
+// The request that `reply` answers, for the replies --fault drops.
+fn reply_request_id(
+    reply: &KademliaHandlerIn<QueryId>,
+) -> Option<KademliaRequestId> {
+    match reply {
+        KademliaHandlerIn::PutRecordRes { request_id, .. }
+        | KademliaHandlerIn::GetRecordRes { request_id, .. } => {
+            Some(request_id.clone())
+        }
+        _ => None,
+    }
+}
+
+// Whether a request of ours failed because the peer has no protocol in
+// common with us.
+fn is_unsupported(event: &Request) -> bool {
+    matches!(
+        event,
+        KademliaHandlerEvent::QueryError {
+            error: KademliaHandlerQueryErr::Upgrade(
+                ProtocolsHandlerUpgrErr::Upgrade(UpgradeError::Select(
+                    NegotiationError::Failed
+                ))
+            ),
+            ..
+        }
+    )
+}
+
+// A token bucket, holding up to a second's worth of requests.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Option<f64>) -> Self {
+        Bucket {
+            tokens: rate.map_or(f64::INFINITY, |rate| rate.max(1.0)),
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: Option<f64>, now: Instant) {
+        let rate = match rate {
+            Some(rate) => rate,
+            None => return,
+        };
+        let elapsed = now.saturating_duration_since(self.refilled);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate)
+            .min(rate.max(1.0));
+        self.refilled = now;
+    }
+}
+
+impl NetworkBehaviour for Throttled {
+    type ProtocolsHandler =
+        <Kademlia<ValidatingStore> as NetworkBehaviour>::ProtocolsHandler;
+    type OutEvent = KademliaEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        match &self.client {
+            Some(config) => KademliaHandler::new(config.clone()),
+            None => self.inner.new_handler(),
+        }
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.dials.connected(peer_id);
+        self.inner.inject_connected(peer_id)
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.dials.disconnected(peer_id);
+        self.peers.remove(peer_id);
+        self.delayed.retain(|(peer, _, _)| peer != peer_id);
+        self.latencies.disconnected(peer_id);
+        self.inner.inject_disconnected(peer_id)
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.inner
+            .inject_connection_established(peer_id, connection, endpoint);
+        if self.is_foreign(peer_id) {
+            self.inner.remove_peer(peer_id);
+        }
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.delayed.retain(|(_, c, _)| c != connection);
+        self.inner
+            .inject_connection_closed(peer_id, connection, endpoint)
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        old: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        self.inner
+            .inject_address_change(peer_id, connection, old, new)
+    }
+
+    fn inject_event(
+        &mut self,
+        peer_id: PeerId,
+        connection: ConnectionId,
+        event: Request,
+    ) {
+        if let Some(capture) = &self.capture {
+            capture.inbound(&peer_id, connection, &event);
+        }
+        self.latencies.received(&peer_id, &event);
+        self.paths.received(&peer_id, &event);
+        if is_unsupported(&event) {
+            // Kademlia still has to hear about it, to finish the query
+            self.drop_foreign(&peer_id);
+        }
+        if !self.caches.is_empty() {
+            self.cache_answered(&peer_id, &event);
+        }
+        if !is_request(&event) || self.admit(&peer_id, Instant::now()) {
+            return self.inner.inject_event(peer_id, connection, event);
+        }
+        if self.limits.delay && self.delayed.len() < MAX_DELAYED {
+            self.held_back += 1;
+            self.delayed.push_back((peer_id, connection, event));
+        } else {
+            self.rejected += 1;
+            self.resets.push_back((peer_id, connection, event));
+        }
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        addr: &Multiaddr,
+        error: &dyn error::Error,
+    ) {
+        self.inner.inject_addr_reach_failure(peer_id, addr, error)
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.dials.failed(peer_id);
+        self.inner.inject_dial_failure(peer_id)
+    }
+
+    fn inject_new_listen_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_new_listen_addr(addr)
+    }
+
+    fn inject_expired_listen_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_expired_listen_addr(addr)
+    }
+
+    fn inject_new_external_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_new_external_addr(addr)
+    }
+
+    fn inject_listener_error(
+        &mut self,
+        id: ListenerId,
+        err: &(dyn error::Error + 'static),
+    ) {
+        self.inner.inject_listener_error(id, err)
+    }
+
+    fn inject_listener_closed(
+        &mut self,
+        id: ListenerId,
+        reason: Result<(), &io::Error>,
+    ) {
+        self.inner.inject_listener_closed(id, reason)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<KademliaHandlerIn<QueryId>, KademliaEvent>,
+    > {
+        // A refused request without an id (AddProvider) has no substream
+        // to reset, and is just dropped
+        while let Some((peer_id, connection, request)) =
+            self.resets.pop_front()
+        {
+            let request_id = match request {
+                KademliaHandlerEvent::FindNodeReq {
+                    request_id, ..
+                }
+                | KademliaHandlerEvent::GetProvidersReq {
+                    request_id,
+                    ..
+                }
+                | KademliaHandlerEvent::GetRecord { request_id, .. }
+                | KademliaHandlerEvent::PutRecord { request_id, .. } => {
+                    request_id
+                }
+                _ => continue,
+            };
+            let event = KademliaHandlerIn::Reset(request_id);
+            if let Some(capture) = &self.capture {
+                capture.outbound(&peer_id, Some(connection), &event);
+            }
+            return Poll::Ready(NetworkBehaviourAction::NotifyHandler {
+                peer_id,
+                handler: NotifyHandler::One(connection),
+                event,
+            });
+        }
+        self.release(cx);
+        if !self.caches.is_empty() {
+            self.cache_timed_out();
+        }
+        // The dials that waited for their turn, or that others wanted
+        let next = match self.to_dial.pop_front() {
+            Some(peer_id) => {
+                Some((peer_id, DialPeerCondition::Disconnected))
+            }
+            None => self.dials.next_due(),
+        };
+        if let Some((peer_id, condition)) = next {
+            return Poll::Ready(NetworkBehaviourAction::DialPeer {
+                peer_id,
+                condition,
+            });
+        }
+        let mut polled = loop {
+            match self.inner.poll(cx, params) {
+                Poll::Ready(NetworkBehaviourAction::DialPeer {
+                    peer_id,
+                    condition,
+                }) => match self.dials.request(peer_id.clone(), condition)
+                {
+                    Admission::Dial => {
+                        break Poll::Ready(
+                            NetworkBehaviourAction::DialPeer {
+                                peer_id,
+                                condition,
+                            },
+                        )
+                    }
+                    // For the query to go on without the peer
+                    Admission::Drop => {
+                        self.inner.inject_dial_failure(&peer_id)
+                    }
+                    Admission::Wait | Admission::Nothing => {}
+                },
+                polled => break polled,
+            }
+        };
+        if let Poll::Ready(NetworkBehaviourAction::NotifyHandler {
+            event,
+            ..
+        }) = &mut polled
+        {
+            match self
+                .faults
+                .as_ref()
+                .and_then(|faults| faults.pick(event))
+            {
+                Some(Fault::Drop) => {
+                    self.replies_dropped += 1;
+                    if let Some(request_id) = reply_request_id(event) {
+                        *event = KademliaHandlerIn::Reset(request_id);
+                    }
+                }
+                Some(Fault::Corrupt) => {
+                    self.replies_corrupted += 1;
+                    fault::corrupt(event);
+                }
+                None => {}
+            }
+            if let Some(adversary) = &self.adversary {
+                if adversary.tamper(event) {
+                    self.replies_tampered += 1;
+                }
+            }
+        }
+        if let Poll::Ready(NetworkBehaviourAction::NotifyHandler {
+            peer_id,
+            event,
+            ..
+        }) = &polled
+        {
+            self.latencies.sent(peer_id, event);
+            self.paths.sent(peer_id, event);
+        }
+        // What goes out, faults and all
+        if let (
+            Some(capture),
+            Poll::Ready(NetworkBehaviourAction::NotifyHandler {
+                peer_id,
+                handler,
+                event,
+            }),
+        ) = (&self.capture, &polled)
+        {
+            let connection = match handler {
+                NotifyHandler::One(connection) => Some(*connection),
+                NotifyHandler::Any => None,
+                NotifyHandler::All => None,
+            };
+            capture.outbound(peer_id, connection, event);
+        }
+        if let Poll::Ready(NetworkBehaviourAction::GenerateEvent(
+            KademliaEvent::QueryResult {
+                result: QueryResult::GetRecord(Ok(_)),
+                ..
+            },
+        )) = &polled
+        {
+            self.start_caching();
+        }
+        polled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates() {
+        assert_eq!(parse_rate("100"), Ok(100.0));
+        assert_eq!(parse_rate("0.5"), Ok(0.5));
+        assert_eq!(parse_rate("0.01"), Ok(MIN_RATE));
+        for s in ["0", "0.001", "1e-300", "-1", "NaN", "inf", "ten", ""] {
+            assert!(parse_rate(s).is_err(), "{}", s);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use crate::output::{Message, OutputSink};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// How much faster than the real clock the maintenance of a simulation
+// runs (--time-scale), like `60` (or `60x`): an hour of refreshing,
+// republishing and expiring takes a minute.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeScale(f64);
+
+impl TimeScale {
+    pub fn factor(self) -> f64 {
+        self.0
+    }
+
+    // How long `interval` of virtual time takes on the real clock.
+    pub fn compress(self, interval: Duration) -> Duration {
+        interval.div_f64(self.0)
+    }
+}
+
+impl FromStr for TimeScale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let factor = s
+            .trim_end_matches('x')
+            .parse::<f64>()
+            .map_err(|_| format!("{:?} isn't a factor, like 60", s))?;
+        if !factor.is_finite() || factor <= 0.0 {
+            return Err(format!("the time scale has to be over 0: {}", s));
+        }
+        Ok(TimeScale(factor))
+    }
+}
+
+impl fmt::Display for TimeScale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x", self.0)
+    }
+}
+
+// The virtual clock of a simulation with a --time-scale: how much time the
+// nodes think has passed since it started (the real time, times the
+// scale, plus whatever ADVANCE added). There is one per process, for the
+// output and the event log to report on.
+#[derive(Debug)]
+struct Clock {
+    scale: TimeScale,
+    started: Instant,
+    advanced: Duration,
+}
+
+static CLOCK: Mutex<Option<Clock>> = Mutex::new(None);
+
+// Start the virtual clock, unless it is already running (when nodes are
+// restarted, they keep the time of the simulation).
+pub fn start(scale: TimeScale) {
+    let mut clock = CLOCK.lock().unwrap();
+    if clock.is_none() {
+        *clock = Some(Clock {
+            scale,
+            started: Instant::now(),
+            advanced: Duration::from_secs(0),
+        });
+    }
+}
+
+// The virtual time since the simulation started, if there is a clock.
+pub fn now() -> Option<Duration> {
+    let clock = CLOCK.lock().unwrap();
+    let clock = clock.as_ref()?;
+    Some(
+        clock.started.elapsed().mul_f64(clock.scale.factor())
+            + clock.advanced,
+    )
+}
+
+// Move the virtual clock `by` forward (for ADVANCE).
+pub fn advance(by: Duration) {
+    if let Some(clock) = CLOCK.lock().unwrap().as_mut() {
+        clock.advanced += by;
+    }
+}
+
+// A virtual time, like `+1h02m03s`.
+pub fn format(time: Duration) -> String {
+    let secs = time.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    match hours {
+        0 => format!("+{}m{:02}s", minutes, secs),
+        _ => format!("+{}h{:02}m{:02}s", hours, minutes, secs),
+    }
+}
+
+// The terminal of a simulation with a --time-scale, with the virtual time
+// in front of every line, like
+//   [+1h02m03s] [2] republish: refreshed 3 records (0 failed)
+#[derive(Debug)]
+pub struct TimelineSink(pub Arc<dyn OutputSink>);
+
+impl OutputSink for TimelineSink {
+    fn show(&self, message: &Message) {
+        let time = match now() {
+            Some(time) => time,
+            None => return self.0.show(message),
+        };
+        self.0.show(&match message {
+            Message::Info(line) => {
+                Message::Info(format!("[{}] {}", format(time), line))
+            }
+            Message::Error(line) => {
+                Message::Error(format!("[{}] {}", format(time), line))
+            }
+        });
+    }
+}
@@ -0,0 +1,104 @@
+use crate::output::Output;
+use libp2p::kad::{
+    BootstrapError, BootstrapOk, QueryId, QueryResult, QueryStats,
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+// The commands that are timed (with TIME in front of them, or all of
+// them, with --timing), from when they were issued to their final result,
+// by the query they started. The time goes to whoever ran the command,
+// after the result, like
+//   TIME GET foo: 23.4ms (12 requests, 11 succeeded, 1 failed, the
+//   query took 21.0ms)
+#[derive(Debug, Default)]
+pub struct Timings {
+    queries: HashMap<QueryId, Timed>,
+}
+
+#[derive(Debug)]
+struct Timed {
+    command: String,
+    started: Instant,
+    output: Output,
+}
+
+impl Timings {
+    pub fn start(
+        &mut self,
+        query: QueryId,
+        command: String,
+        started: Instant,
+        output: Output,
+    ) {
+        let timed = Timed {
+            command,
+            started,
+            output,
+        };
+        self.queries.insert(query, timed);
+    }
+
+    // `query` produced a result. Returns where to send its time, and the
+    // time, once it is done (a bootstrap takes several steps).
+    pub fn finish(
+        &mut self,
+        query: &QueryId,
+        result: &QueryResult,
+        stats: &QueryStats,
+    ) -> Option<(Output, String)> {
+        let steps_left = match result {
+            QueryResult::Bootstrap(Ok(BootstrapOk {
+                num_remaining,
+                ..
+            })) => *num_remaining,
+            QueryResult::Bootstrap(Err(BootstrapError::Timeout {
+                num_remaining,
+                ..
+            })) => num_remaining.unwrap_or(0),
+            _ => 0,
+        };
+        if steps_left > 0 {
+            return None;
+        }
+        let timed = self.queries.remove(query)?;
+        let time =
+            report(&timed.command, timed.started.elapsed(), Some(stats));
+        Some((timed.output, time))
+    }
+
+    // `from` goes on as `to` (a CAS stores the new version once its
+    // lookup is done), and the command is timed until `to` finishes.
+    pub fn hand_over(&mut self, from: &QueryId, to: QueryId) {
+        if let Some(timed) = self.queries.remove(from) {
+            self.queries.insert(to, timed);
+        }
+    }
+}
+
+// How long `command` took, with what its query did, if it started one.
+pub fn report(
+    command: &str,
+    took: Duration,
+    stats: Option<&QueryStats>,
+) -> String {
+    let stats = match stats {
+        Some(stats) => stats,
+        None => return format!("TIME {}: {:.1?}", command, took),
+    };
+    let query = match stats.duration() {
+        Some(duration) => format!(", the query took {:.1?}", duration),
+        None => String::new(),
+    };
+    format!(
+        "TIME {}: {:.1?} ({} requests, {} succeeded, {} failed{})",
+        command,
+        took,
+        stats.num_requests(),
+        stats.num_successes(),
+        stats.num_failures(),
+        query
+    )
+}
@@ -0,0 +1,390 @@
+use std::{fmt, iter::Peekable, str::Chars};
+
+// The part of TOML that the files of nettest use (sweep files, scenarios
+// and --namespaces): [tables], and settings that are numbers (like
+// `10_000`), strings ("double" or 'single' quoted) or lists of them,
+// which may go over several lines. Comments start with `#`, anywhere
+// outside of strings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    // A [table] starts, on a line
+    Table(usize, String),
+    Setting(Setting),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    // The line it starts on
+    pub line: usize,
+    // The [table] it is in ("" before the first one)
+    pub table: String,
+    pub key: String,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Number(u64),
+    String(String),
+    // With the line each item is on
+    List(Vec<(usize, Value)>),
+}
+
+impl Value {
+    pub fn number(&self) -> Result<u64, String> {
+        match self {
+            Value::Number(n) => Ok(*n),
+            Value::String(s) => Err(format!("{:?} isn't a number", s)),
+            Value::List(_) => Err("expected a number, not a list".into()),
+        }
+    }
+
+    pub fn string(&self) -> Result<&str, String> {
+        match self {
+            Value::String(s) => Ok(s),
+            Value::Number(n) => {
+                Err(format!("expected a string, not {}", n))
+            }
+            Value::List(_) => Err("expected a string, not a list".into()),
+        }
+    }
+}
+
+// What is wrong with a file, and on which line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub line: usize,
+    pub why: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.why)
+    }
+}
+
+// The tables and settings of `text`, in order.
+pub fn parse(text: &str) -> Result<Vec<Entry>, Error> {
+    let mut reader = Reader {
+        chars: text.chars().peekable(),
+        line: 1,
+    };
+    let mut entries = Vec::new();
+    let mut table = String::new();
+    loop {
+        reader.skip_blank();
+        let line = reader.line;
+        match reader.chars.peek().copied() {
+            None => return Ok(entries),
+            Some('[') => {
+                reader.chars.next();
+                let name = reader.until(']', "expected `]`")?;
+                table = name.trim().trim_matches('"').to_string();
+                entries.push(Entry::Table(line, table.clone()));
+            }
+            Some(_) => {
+                let key = reader.until('=', "expected `name = value`")?;
+                let key = key.trim();
+                if key.is_empty()
+                    || !key.chars().all(|c| {
+                        c.is_ascii_alphanumeric() || "_-.".contains(c)
+                    })
+                {
+                    return Err(reader.error(format!(
+                        "{:?} isn't the name of a setting",
+                        key
+                    )));
+                }
+                let value = reader.value()?;
+                entries.push(Entry::Setting(Setting {
+                    line,
+                    table: table.clone(),
+                    key: key.to_string(),
+                    value,
+                }));
+            }
+        }
+        reader.end_of_line()?;
+    }
+}
+
+struct Reader<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl Reader<'_> {
+    fn error(&self, why: impl Into<String>) -> Error {
+        Error {
+            line: self.line,
+            why: why.into(),
+        }
+    }
+
+    // Skip spaces, comments and line breaks.
+    fn skip_blank(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                ' ' | '\t' | '\r' => {}
+                '\n' => self.line += 1,
+                '#' => {
+                    while self.chars.next_if(|c| *c != '\n').is_some() {}
+                    continue;
+                }
+                _ => return,
+            }
+            self.chars.next();
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while self
+            .chars
+            .next_if(|c| matches!(c, ' ' | '\t' | '\r'))
+            .is_some()
+        {}
+    }
+
+    // Nothing but a comment may follow a table or a setting, on its line.
+    fn end_of_line(&mut self) -> Result<(), Error> {
+        self.skip_spaces();
+        match self.chars.peek().copied() {
+            None | Some('\n') | Some('#') => Ok(()),
+            Some(c) => Err(self.error(format!("unexpected {:?}", c))),
+        }
+    }
+
+    // The text up to `end` (which is skipped), on this line.
+    fn until(&mut self, end: char, why: &str) -> Result<String, Error> {
+        let mut text = String::new();
+        loop {
+            match self.chars.next() {
+                Some(c) if c == end => return Ok(text),
+                None | Some('\n') => return Err(self.error(why)),
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        self.skip_spaces();
+        let line = self.line;
+        match self.chars.peek().copied() {
+            Some('"') => {
+                self.chars.next();
+                let mut s = String::new();
+                loop {
+                    match self.chars.next() {
+                        Some('"') => return Ok(Value::String(s)),
+                        Some('\\') => match self.chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(c) if c != '\n' => s.push(c),
+                            _ => {
+                                return Err(self.error("unfinished string"))
+                            }
+                        },
+                        None | Some('\n') => {
+                            return Err(self.error("unfinished string"))
+                        }
+                        Some(c) => s.push(c),
+                    }
+                }
+            }
+            Some('\'') => {
+                self.chars.next();
+                self.until('\'', "unfinished string").map(Value::String)
+            }
+            Some('[') => {
+                self.chars.next();
+                let never_closed = Error {
+                    line,
+                    why: "the list is never closed".into(),
+                };
+                let mut items = Vec::new();
+                loop {
+                    self.skip_blank();
+                    match self.chars.peek().copied() {
+                        None => return Err(never_closed),
+                        Some(']') => break,
+                        Some(_) => {}
+                    }
+                    items.push((self.line, self.value()?));
+                    self.skip_blank();
+                    match self.chars.peek().copied() {
+                        None => return Err(never_closed),
+                        Some(',') => {
+                            self.chars.next();
+                        }
+                        Some(']') => break,
+                        Some(c) => {
+                            return Err(self.error(format!(
+                                "unexpected {:?} in the list",
+                                c
+                            )))
+                        }
+                    }
+                }
+                self.chars.next();
+                Ok(Value::List(items))
+            }
+            None | Some('\n') | Some('#') => {
+                Err(self.error("expected a value"))
+            }
+            Some(_) => {
+                let mut word = String::new();
+                while let Some(c) = self.chars.next_if(|c| {
+                    !c.is_whitespace() && !matches!(c, ',' | ']' | '#')
+                }) {
+                    word.push(c);
+                }
+                match word.replace('_', "").parse() {
+                    Ok(n) => Ok(Value::Number(n)),
+                    Err(_) => {
+                        Err(self
+                            .error(format!("{:?} isn't a number", word)))
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setting(
+        line: usize,
+        table: &str,
+        key: &str,
+        value: Value,
+    ) -> Entry {
+        Entry::Setting(Setting {
+            line,
+            table: table.to_string(),
+            key: key.to_string(),
+            value,
+        })
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    fn error(text: &str) -> String {
+        parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn files() {
+        let text = r#"
+# A sweep
+nodes = 10_000   # a comment
+name = "a \"quoted\" \\ string\n"
+other = 'single # not a comment'
+
+[table.one]
+list = [1, "two", 'three']
+lines = [
+  "a",   # the first
+  "b",
+]
+[ "quoted" ]
+nested = [[], [1]]
+empty = []
+"#;
+        assert_eq!(
+            parse(text).unwrap(),
+            vec![
+                setting(3, "", "nodes", Value::Number(10_000)),
+                setting(4, "", "name", string("a \"quoted\" \\ string\n")),
+                setting(5, "", "other", string("single # not a comment")),
+                Entry::Table(7, "table.one".into()),
+                setting(
+                    8,
+                    "table.one",
+                    "list",
+                    Value::List(vec![
+                        (8, Value::Number(1)),
+                        (8, string("two")),
+                        (8, string("three")),
+                    ])
+                ),
+                setting(
+                    9,
+                    "table.one",
+                    "lines",
+                    Value::List(vec![
+                        (10, string("a")),
+                        (11, string("b"))
+                    ])
+                ),
+                Entry::Table(13, "quoted".into()),
+                setting(
+                    14,
+                    "quoted",
+                    "nested",
+                    Value::List(vec![
+                        (14, Value::List(vec![])),
+                        (14, Value::List(vec![(14, Value::Number(1))])),
+                    ])
+                ),
+                setting(15, "quoted", "empty", Value::List(vec![])),
+            ]
+        );
+        assert_eq!(parse("").unwrap(), vec![]);
+        assert_eq!(parse("# only a comment").unwrap(), vec![]);
+        assert_eq!(
+            parse("a-b.c_d=1").unwrap(),
+            vec![setting(1, "", "a-b.c_d", Value::Number(1))]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("[table", "line 1: expected `]`"),
+            ("[table]]", "line 1: unexpected ']'"),
+            ("nodes", "line 1: expected `name = value`"),
+            ("\n\n = 1", "line 3: \"\" isn't the name of a setting"),
+            ("a b = 1", "line 1: \"a b\" isn't the name of a setting"),
+            ("nodes =", "line 1: expected a value"),
+            ("nodes = # none", "line 1: expected a value"),
+            ("nodes = ten", "line 1: \"ten\" isn't a number"),
+            ("nodes = -1", "line 1: \"-1\" isn't a number"),
+            (
+                "nodes = 99999999999999999999",
+                "line 1: \"99999999999999999999\" isn't a number",
+            ),
+            ("nodes = 1 2", "line 1: unexpected '2'"),
+            ("name = \"open", "line 1: unfinished string"),
+            ("name = \"open\nclosed\"", "line 1: unfinished string"),
+            ("name = \"escaped\\", "line 1: unfinished string"),
+            ("name = 'open", "line 1: unfinished string"),
+            ("list = [1, 2", "line 1: the list is never closed"),
+            ("list = [\n1,\n2\n", "line 1: the list is never closed"),
+            ("list = [1 2]", "line 1: unexpected '2' in the list"),
+            ("list = [1,,2]", "line 1: \"\" isn't a number"),
+        ];
+        for (text, err) in cases {
+            assert_eq!(error(text), err, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(Value::Number(3).number(), Ok(3));
+        assert_eq!(
+            string("3").number(),
+            Err("\"3\" isn't a number".into())
+        );
+        assert!(Value::List(vec![]).number().is_err());
+        assert_eq!(string("a").string(), Ok("a"));
+        assert_eq!(
+            Value::Number(3).string(),
+            Err("expected a string, not 3".into())
+        );
+        assert!(Value::List(vec![]).string().is_err());
+    }
+}
@@ -0,0 +1,82 @@
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::Duration,
+};
+
+// A connection between two peers, with the round trip time that ping
+// last measured over it (if it has yet).
+#[derive(Debug, Clone)]
+pub struct Edge {
+    pub from: PeerId,
+    pub to: PeerId,
+    pub rtt: Option<Duration>,
+}
+
+// Write the connection graph to `path` in the DOT language of Graphviz
+// (`dot -Tsvg topology.dot > topology.svg`), one edge per pair of
+// connected peers, labelled with its round trip time. Both ends of a
+// connection usually report it, so the round trip times of an edge are
+// averaged. `names` labels some of the peers (the others go by the end
+// of their id). Returns how many edges there were.
+pub fn write_dot(
+    path: &Path,
+    names: &[(PeerId, String)],
+    edges: &[Edge],
+) -> io::Result<usize> {
+    // Each pair once, whichever way round it came
+    let mut pairs: HashMap<(String, String), Vec<Duration>> =
+        HashMap::new();
+    let mut order = Vec::new();
+    for edge in edges {
+        let (from, to) = (edge.from.to_string(), edge.to.to_string());
+        let pair = if from < to { (from, to) } else { (to, from) };
+        let rtts = pairs.entry(pair.clone()).or_insert_with(|| {
+            order.push(pair);
+            Vec::new()
+        });
+        rtts.extend(edge.rtt);
+    }
+
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "graph nettest {{")?;
+    writeln!(file, "    node [shape=box, fontname=monospace];")?;
+    let names = names
+        .iter()
+        .map(|(peer, name)| (peer.to_string(), name.as_str()))
+        .collect::<HashMap<_, _>>();
+    let mut peers = order
+        .iter()
+        .flat_map(|(a, b)| vec![a.clone(), b.clone()])
+        .chain(names.keys().cloned())
+        .collect::<Vec<_>>();
+    peers.sort();
+    peers.dedup();
+    for peer in &peers {
+        let label = match names.get(peer) {
+            Some(name) => name.to_string(),
+            None => format!("…{}", &peer[peer.len().saturating_sub(6)..]),
+        };
+        writeln!(file, "    \"{}\" [label=\"{}\"];", peer, label)?;
+    }
+    for pair in &order {
+        let rtts = &pairs[pair];
+        match rtts.len() {
+            0 => writeln!(file, "    \"{}\" -- \"{}\";", pair.0, pair.1)?,
+            n => {
+                let rtt = rtts.iter().sum::<Duration>() / n as u32;
+                writeln!(
+                    file,
+                    "    \"{}\" -- \"{}\" [label=\"{:.1?}\"];",
+                    pair.0, pair.1, rtt
+                )?
+            }
+        }
+    }
+    writeln!(file, "}}")?;
+    file.flush()?;
+    Ok(order.len())
+}
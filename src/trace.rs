@@ -0,0 +1,221 @@
+use crate::output::Output;
+use async_std::{io, net::TcpStream, prelude::*, task};
+use futures::{channel::mpsc, StreamExt};
+use futures_timer::Delay;
+use libp2p::PeerId;
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How long spans wait for others to go out with.
+const BATCH_DELAY: Duration = Duration::from_secs(2);
+
+// How long the collector gets to take a batch.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Tracing of kademlia queries (with --otlp-endpoint): every query that
+// finishes becomes a span, from when kademlia started it to its result,
+// with what it was about, the peers that answered and the query stats as
+// attributes. Spans go to an OpenTelemetry collector (or Jaeger, which
+// takes OTLP too) over OTLP/HTTP, as JSON, a batch every couple of
+// seconds.
+//
+// Each node is an instance (service.instance.id is its peer id) of the
+// "nettest" service. Kademlia messages have no room for a trace context,
+// so the spans of different nodes don't share traces: look them up by
+// the key attribute instead.
+#[derive(Debug, Clone)]
+pub struct Tracer {
+    spans: mpsc::UnboundedSender<Value>,
+}
+
+// A finished query, to make a span of.
+#[derive(Debug)]
+pub struct QuerySpan {
+    pub kind: &'static str,
+    pub key: Option<String>,
+    // The peers that answered: the ones that had the record, the closest
+    // ones, and so on
+    pub peers: Vec<PeerId>,
+    pub error: Option<String>,
+    pub requests: u32,
+    pub successes: u32,
+    pub failures: u32,
+    // How long ago the query started
+    pub duration: Duration,
+}
+
+impl Tracer {
+    // Start exporting the spans of `node` to the collector at `endpoint`
+    // (like http://localhost:4318).
+    pub fn new(endpoint: &str, node: &PeerId) -> Result<Self, String> {
+        let (host, path) = parse_endpoint(endpoint)?;
+        let (spans, rx) = mpsc::unbounded();
+        task::spawn(export(host, path, node.to_string(), rx));
+        Ok(Tracer { spans })
+    }
+
+    pub fn record(&self, span: QuerySpan) {
+        let end = SystemTime::now();
+        let start = end - span.duration;
+        let peers = span
+            .peers
+            .iter()
+            .map(|peer| json!({ "stringValue": peer.to_string() }))
+            .collect::<Vec<_>>();
+        let mut attributes = vec![
+            attribute("kad.query", json!({ "stringValue": span.kind })),
+            attribute(
+                "kad.requests",
+                json!({ "intValue": span.requests.to_string() }),
+            ),
+            attribute(
+                "kad.successes",
+                json!({ "intValue": span.successes.to_string() }),
+            ),
+            attribute(
+                "kad.failures",
+                json!({ "intValue": span.failures.to_string() }),
+            ),
+            attribute(
+                "kad.peers",
+                json!({ "arrayValue": { "values": peers } }),
+            ),
+        ];
+        if let Some(key) = &span.key {
+            attributes
+                .push(attribute("kad.key", json!({ "stringValue": key })));
+        }
+        let status = match &span.error {
+            // STATUS_CODE_OK and STATUS_CODE_ERROR
+            None => json!({ "code": 1 }),
+            Some(error) => json!({ "code": 2, "message": error }),
+        };
+        let _ = self.spans.unbounded_send(json!({
+            "traceId": random_hex(16),
+            "spanId": random_hex(8),
+            "name": format!("kad {}", span.kind),
+            // SPAN_KIND_CLIENT
+            "kind": 3,
+            "startTimeUnixNano": unix_nanos(start),
+            "endTimeUnixNano": unix_nanos(end),
+            "attributes": attributes,
+            "status": status,
+        }));
+    }
+}
+
+// Send the spans off in batches, for as long as the node runs.
+async fn export(
+    host: String,
+    path: String,
+    node: String,
+    mut spans: mpsc::UnboundedReceiver<Value>,
+) {
+    let mut failing = false;
+    while let Some(span) = spans.next().await {
+        Delay::new(BATCH_DELAY).await;
+        let mut batch = vec![span];
+        while let Ok(span) = spans.try_recv() {
+            batch.push(span);
+        }
+        let resource = vec![
+            attribute("service.name", json!({ "stringValue": "nettest" })),
+            attribute(
+                "service.instance.id",
+                json!({ "stringValue": node }),
+            ),
+        ];
+        let body = json!({
+            "resourceSpans": [{
+                "resource": { "attributes": resource },
+                "scopeSpans": [{
+                    "scope": { "name": "nettest" },
+                    "spans": batch,
+                }],
+            }],
+        });
+        let sent =
+            io::timeout(EXPORT_TIMEOUT, post(&host, &path, &body)).await;
+        // Only say so when it starts (or stops) failing
+        match sent {
+            Ok(()) if failing => {
+                Output::Terminal
+                    .info(format!("otlp: exporting to {} again", host));
+                failing = false;
+            }
+            Err(err) if !failing => {
+                Output::Terminal.error(format!(
+                    "otlp: failed to export spans to {}: {}",
+                    host, err
+                ));
+                failing = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+// POST `body` as JSON, and check that the collector took it.
+async fn post(host: &str, path: &str, body: &Value) -> io::Result<()> {
+    let body = body.to_string();
+    let mut stream = TcpStream::connect(host).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let status_line = String::from_utf8_lossy(&response)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    match status_line.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "the collector answered {:?}",
+            status_line
+        ))),
+    }
+}
+
+// Split http://host:port into the address to connect to, and the path to
+// post to (/v1/traces, unless the endpoint has one).
+fn parse_endpoint(endpoint: &str) -> Result<(String, String), String> {
+    let rest = endpoint.strip_prefix("http://").ok_or_else(|| {
+        format!("{}: only http:// endpoints are supported", endpoint)
+    })?;
+    let (host, path) = match rest.find('/') {
+        Some(at) if rest.len() > at + 1 => rest.split_at(at),
+        Some(at) => (&rest[..at], "/v1/traces"),
+        None => (rest, "/v1/traces"),
+    };
+    let host = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:80", host),
+    };
+    Ok((host, path.to_string()))
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+fn random_hex(len: usize) -> String {
+    (0..len)
+        .map(|_| format!("{:02x}", rand::random::<u8>()))
+        .collect()
+}
+
+// OTLP/JSON writes 64-bit numbers as strings.
+fn unix_nanos(at: SystemTime) -> String {
+    at.duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos())
+        .unwrap_or(0)
+        .to_string()
+}
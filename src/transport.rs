@@ -1,33 +1,509 @@
+use crate::{
+    bandwidth::SharedBandwidth,
+    chaos::{self, ChaosConfig},
+    filter::SharedFilter,
+    negotiation::SharedNegotiations,
+    shape::{self, ShapeConfig},
+    socks::{ProxyConfig, Socks5},
+    Error,
+};
+use clap::ValueEnum;
+use futures::{future, AsyncRead, AsyncWrite, StreamExt};
+#[cfg(feature = "tokio")]
+use libp2p::tcp::TokioTcpConfig;
 use libp2p::{
-    core::upgrade, identity::Keypair, secio::SecioConfig, tcp::TcpConfig,
-    yamux, Multiaddr, Transport,
+    core::{
+        either::{EitherError, EitherOutput},
+        muxing::StreamMuxerBox,
+        transport::{
+            boxed::Boxed, timeout::TransportTimeout, ListenerEvent,
+            MemoryTransport, TransportError,
+        },
+        upgrade,
+        upgrade::SelectUpgrade,
+        ConnectedPoint,
+    },
+    dns::DnsConfig,
+    identity::Keypair,
+    mplex::MplexConfig,
+    multiaddr::Protocol,
+    noise::{self, NoiseConfig, X25519Spec},
+    pnet::{PnetConfig, PreSharedKey},
+    secio::SecioConfig,
+    tcp::TcpConfig,
+    websocket::WsConfig,
+    yamux, Multiaddr, PeerId, Transport,
 };
+use std::{io, sync::Arc, time::Duration};
+
+// How long setting a connection up may take (dialing, the security
+// handshake and the muxer negotiation), unless --upgrade-timeout says
+// otherwise.
+pub const DEFAULT_UPGRADE_TIMEOUT: &str = "20s";
+
+// The type of every transport built in this module. Boxing the transport
+// hides the (very long) concrete type, and lets the optional layers (like
+// pnet) produce the same type as the plain stack.
+pub type BoxedTransport = Boxed<(PeerId, StreamMuxerBox), io::Error>;
+
+// A way for nodes to reach each other. Browser nodes speak Ws, but
+// nettest itself doesn't build for wasm32: the core runs on async-std
+// threads, and has TCP, mDNS, the api and the control socket in it.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportKind {
+    Tcp,
+    // Websockets (over TCP), which browser nodes speak
+    Ws,
+    // Only reaches nodes in the same process, and needs no networking
+    Memory,
+}
+
+impl TransportKind {
+    // Where a node listens by default: every interface, IPv4 and IPv6,
+    // on ports the OS picks (or any free memory port).
+    pub fn listen_addrs(self) -> Vec<Multiaddr> {
+        let addrs: &[&str] = match self {
+            TransportKind::Tcp => &["/ip4/0.0.0.0/tcp/0", "/ip6/::/tcp/0"],
+            TransportKind::Ws => {
+                &["/ip4/0.0.0.0/tcp/0/ws", "/ip6/::/tcp/0/ws"]
+            }
+            TransportKind::Memory => &["/memory/0"],
+        };
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+}
+
+// How connections get authenticated and encrypted. Both ends have to
+// use the same protocol. There is no TLS: libp2p 0.22 has no
+// libp2p-tls.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Security {
+    // Noise, with the XX handshake
+    #[default]
+    Noise,
+    // secio, which is deprecated, but still what old nodes speak
+    Secio,
+}
+
+// What runs the sockets of the TCP transports, and the connections of a
+// node. The rest of the node (like the HTTP api, or the control socket)
+// has tasks of its own on async-std, which runs them on its own threads,
+// so it doesn't need to be the runtime of whoever embeds the node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Runtime {
+    // async-std sockets, with the connections on a thread pool of the
+    // swarm's own
+    #[default]
+    AsyncStd,
+    // Tokio sockets, with the connections spawned on the Tokio runtime
+    // that the swarm is polled in
+    #[cfg(feature = "tokio")]
+    Tokio,
+}
+
+// Everything about a node's transport that can be configured.
+#[derive(Debug, Clone, Default)]
+pub struct TransportConfig {
+    // Only talk to nodes with the same swarm key
+    pub psk: Option<PreSharedKey>,
+    // Mistreat connections on purpose (see `chaos`)
+    pub chaos: Option<ChaosConfig>,
+    // Limit throughput and add latency (see `shape`)
+    pub shape: Option<ShapeConfig>,
+    // Where to count the traffic of each peer
+    pub bandwidth: SharedBandwidth,
+    // Where to record what each connection negotiated
+    pub negotiations: SharedNegotiations,
+    pub security: Security,
+    // Dial through a SOCKS5 proxy (see `socks`)
+    pub proxy: Option<ProxyConfig>,
+    pub runtime: Runtime,
+    pub tcp: TcpOptions,
+    // How long the raw connection of a dial may take to come up (or
+    // however long the OS gives it)
+    pub dial_timeout: Option<Duration>,
+    // How long a connection may take to be set up, all together
+    pub upgrade_timeout: Duration,
+}
+
+// What the TCP sockets (websockets included) are set up with.
+// TODO: reuse the listening port for dials (SO_REUSEPORT), and make the
+// listen backlog configurable. libp2p-tcp 0.20 sets neither (the backlog
+// is always 1024), so these have to wait for a libp2p upgrade.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpOptions {
+    // TCP_NODELAY: send small writes right away, rather than batching them
+    pub nodelay: bool,
+    // The IP TTL of the sockets (or the OS's)
+    pub ttl: Option<u32>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        TcpOptions {
+            nodelay: true,
+            ttl: None,
+        }
+    }
+}
+
+// Build the transport used by a node. This is a manual version of
+// `libp2p::build_development_transport`: any combination of DNS-enabled
+// TCP, websockets over that TCP, and the in-memory transport, upgraded
+// with noise (or secio) for authentication and yamux or mplex for
+// multiplexing. A node with several of them dials each address with the
+// one that understands it, and can listen on all of them.
+//
+// If a pre-shared key is given, every raw connection is first wrapped in
+// a private network (pnet) handshake, so only nodes holding the same
+// swarm key can even begin the noise (or secio) negotiation.
+//
+// Once a connection is authenticated (and so the remote peer id is
+// known), it is checked against the filter and dropped if the peer isn't
+// allowed. Otherwise its traffic gets counted from then on.
+//
+// Underneath everything else, the raw connections can be shaped to look
+// like a slow link, and then mistreated by chaos. The TCP ones (websockets
+// included) may also be dialed through a SOCKS5 proxy.
+pub fn build_transport(
+    kinds: &[TransportKind],
+    keypair: Keypair,
+    config: TransportConfig,
+    filter: SharedFilter,
+) -> Result<BoxedTransport, Error> {
+    let mut kinds = kinds.iter();
+    let first = kinds.next().ok_or_else(|| {
+        Error::Transport(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no transport to use",
+        ))
+    })?;
+    let proxy = config.proxy.as_ref();
+    let runtime = config.runtime;
+    let tcp = config.tcp;
+    let mut base = raw_transport(*first, proxy, runtime, tcp)
+        .map_err(Error::Transport)?;
+    for kind in kinds {
+        base = base
+            .or_transport(
+                raw_transport(*kind, proxy, runtime, tcp)
+                    .map_err(Error::Transport)?,
+            )
+            .map(|socket, _| match socket {
+                EitherOutput::First(socket)
+                | EitherOutput::Second(socket) => socket,
+            })
+            .map_err(|err| match err {
+                EitherError::A(err) | EitherError::B(err) => err,
+            })
+            .boxed();
+    }
+    base = OwnVersion(base).boxed();
+
+    // Dials that don't get through in time fail, rather than holding up
+    // a query until the OS gives up on them
+    if let Some(timeout) = config.dial_timeout {
+        base = box_raw(TransportTimeout::with_outgoing_timeout(
+            base, timeout,
+        ));
+    }
+
+    Ok(add_shape(base, config, keypair, filter))
+}
 
-fn get_transport_example() {
-    // Before doing anything, create a new keypair for this node's
-    // identity
-    let keypair = Keypair::generate_ed25519();
+// A raw connection, over any of the transports.
+pub trait Socket: AsyncRead + AsyncWrite + Unpin + Send {}
 
-    // Create a default tcp transport
-    let base_tcp = TcpConfig::new();
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Socket for T {}
 
-    // Upgrade the transport to use secio, for authentication, and
-    // yamux, for multiplexing. But first, create the default configurations
-    // for these protocols.
-    let secio_conf = SecioConfig::new(keypair); // Default secio config
+// A transport that just produces sockets. Boxing the sockets too lets
+// transports with different kinds of sockets be combined.
+type RawTransport = Boxed<Box<dyn Socket>, io::Error>;
+
+fn raw_transport(
+    kind: TransportKind,
+    proxy: Option<&ProxyConfig>,
+    runtime: Runtime,
+    options: TcpOptions,
+) -> io::Result<RawTransport> {
+    // A tcp transport that can also resolve /dns4 and /dns6 addresses,
+    // unless the proxy does that (and the dialing)
+    let tcp = || -> io::Result<RawTransport> {
+        let tcp = match runtime {
+            Runtime::AsyncStd => {
+                let mut tcp = TcpConfig::new().nodelay(options.nodelay);
+                if let Some(ttl) = options.ttl {
+                    tcp = tcp.ttl(ttl);
+                }
+                box_raw(DnsConfig::new(tcp)?)
+            }
+            #[cfg(feature = "tokio")]
+            Runtime::Tokio => {
+                let mut tcp =
+                    TokioTcpConfig::new().nodelay(options.nodelay);
+                if let Some(ttl) = options.ttl {
+                    tcp = tcp.ttl(ttl);
+                }
+                box_raw(DnsConfig::new(tcp)?)
+            }
+        };
+        Ok(match proxy {
+            Some(proxy) => Socks5::new(proxy.clone(), tcp).boxed(),
+            None => tcp,
+        })
+    };
+    Ok(match kind {
+        TransportKind::Tcp => tcp()?,
+        TransportKind::Ws => box_raw(WsConfig::new(tcp()?)),
+        // Only reaches other nodes in the same process (`/memory/<port>`
+        // addresses)
+        TransportKind::Memory => box_raw(MemoryTransport),
+    })
+}
+
+fn box_raw<T, C>(transport: T) -> RawTransport
+where
+    T: Transport<Output = C> + Clone + Send + Sync + 'static,
+    T::Error: Send + Sync + 'static,
+    T::Listener: Send,
+    T::ListenerUpgrade: Send,
+    T::Dial: Send,
+    C: Socket + 'static,
+{
+    transport
+        .map(|socket, _| Box::new(socket) as Box<dyn Socket>)
+        .map_err(io::Error::other)
+        .boxed()
+}
+
+// Each of the functions below adds one optional layer on top of the raw
+// transport (if the config asks for it), and passes the result on to the
+// next one.
+
+fn add_shape<T, C>(
+    transport: T,
+    mut config: TransportConfig,
+    keypair: Keypair,
+    filter: SharedFilter,
+) -> BoxedTransport
+where
+    T: Transport<Output = C> + Clone + Send + Sync + 'static,
+    T::Error: Send + Sync + 'static,
+    T::Listener: Send,
+    T::ListenerUpgrade: Send,
+    T::Dial: Send,
+    C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match config.shape.take() {
+        Some(shape) => {
+            let shape = Arc::new(shape);
+            add_chaos(
+                transport.and_then(move |socket, _| {
+                    shape::connect(socket, shape.clone())
+                }),
+                config,
+                keypair,
+                filter,
+            )
+        }
+        None => add_chaos(transport, config, keypair, filter),
+    }
+}
+
+fn add_chaos<T, C>(
+    transport: T,
+    mut config: TransportConfig,
+    keypair: Keypair,
+    filter: SharedFilter,
+) -> BoxedTransport
+where
+    T: Transport<Output = C> + Clone + Send + Sync + 'static,
+    T::Error: Send + Sync + 'static,
+    T::Listener: Send,
+    T::ListenerUpgrade: Send,
+    T::Dial: Send,
+    C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match config.chaos.take() {
+        Some(chaos) => {
+            let chaos = Arc::new(chaos);
+            add_psk(
+                transport.and_then(move |socket, _| {
+                    chaos::connect(socket, chaos.clone())
+                }),
+                config,
+                keypair,
+                filter,
+            )
+        }
+        None => add_psk(transport, config, keypair, filter),
+    }
+}
+
+// Negotiate the private network before anything else happens on the
+// connection.
+fn add_psk<T, C>(
+    transport: T,
+    config: TransportConfig,
+    keypair: Keypair,
+    filter: SharedFilter,
+) -> BoxedTransport
+where
+    T: Transport<Output = C> + Clone + Send + Sync + 'static,
+    T::Error: Send + Sync + 'static,
+    T::Listener: Send,
+    T::ListenerUpgrade: Send,
+    T::Dial: Send,
+    C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match config.psk {
+        Some(psk) => upgrade_transport(
+            transport.and_then(move |socket, _| {
+                PnetConfig::new(psk).handshake(socket)
+            }),
+            keypair,
+            filter,
+            &config,
+        ),
+        None => upgrade_transport(transport, keypair, filter, &config),
+    }
+}
+
+// Upgrade a raw transport (one that just produces sockets) to an
+// authenticated and multiplexed transport that the swarm can use.
+fn upgrade_transport<T, C>(
+    transport: T,
+    keypair: Keypair,
+    filter: SharedFilter,
+    config: &TransportConfig,
+) -> BoxedTransport
+where
+    T: Transport<Output = C> + Clone + Send + Sync + 'static,
+    T::Error: Send + Sync + 'static,
+    T::Listener: Send,
+    T::ListenerUpgrade: Send,
+    T::Dial: Send,
+    C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // First, create the default configurations for the protocols
     let yamux_conf = yamux::Config::default(); // Default yamux config
+    let mplex_conf = MplexConfig::new(); // Default mplex config
+    let muxers = SelectUpgrade::new(yamux_conf, mplex_conf);
+    let bandwidth = config.bandwidth.clone();
+    let negotiations = config.negotiations.clone();
+    let pnet = config.psk.is_some();
+    let security_name = match config.security {
+        Security::Noise => "noise",
+        Security::Secio => "secio",
+    };
+    let record = move |peer: &PeerId, endpoint: &ConnectedPoint, muxer| {
+        negotiations.record(peer, endpoint, pnet, security_name, muxer)
+    };
+
+    // Negotiate noise (or secio) as the authentication protocol, and
+    // yamux (or mplex) as the multiplexing protocol. The two branches
+    // build different types, so each is boxed.
+    let transport = transport.upgrade(upgrade::Version::V1);
+    let authenticated: BoxedTransport = match config.security {
+        Security::Noise => {
+            let keys = noise::Keypair::<X25519Spec>::new()
+                .into_authentic(&keypair)
+                .expect("ed25519 keys can always sign a noise key");
+            transport
+                .authenticate(NoiseConfig::xx(keys).into_authenticated())
+                .multiplex(muxers)
+                .map(move |(peer, muxer), endpoint| {
+                    record(&peer, &endpoint, muxer_name(&muxer));
+                    (peer, StreamMuxerBox::new(muxer))
+                })
+                .map_err(io::Error::other)
+                .boxed()
+        }
+        Security::Secio => transport
+            .authenticate(SecioConfig::new(keypair))
+            .multiplex(muxers)
+            .map(move |(peer, muxer), endpoint| {
+                record(&peer, &endpoint, muxer_name(&muxer));
+                (peer, StreamMuxerBox::new(muxer))
+            })
+            .map_err(io::Error::other)
+            .boxed(),
+    };
+
+    authenticated
+        .and_then(move |(peer, muxer), endpoint| {
+            // Refuse the connection if the filter doesn't allow the peer
+            let allowed =
+                filter.read().unwrap().allows_connection(&peer, &endpoint);
+            future::ready(if allowed {
+                let muxer = bandwidth.count(&peer, muxer);
+                Ok((peer, muxer))
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("peer {} is not allowed", peer),
+                ))
+            })
+        })
+        .timeout(config.upgrade_timeout)
+        .map_err(io::Error::other)
+        .boxed()
+}
 
-    // Actually upgrade the transport from the base tcp layer
-    let transport = base_tcp
-        .upgrade(upgrade::Version::V1) // Upgrade the network
-        .authenticate(secio_conf) // Negotiate secio as the authentication protocol
-        .multiplex(yamux_conf); // Negotiate yamux as the multiplexing protocol
+// Which of the muxers (see `upgrade_transport`) got negotiated.
+fn muxer_name<A, B>(muxer: &EitherOutput<A, B>) -> &'static str {
+    match muxer {
+        EitherOutput::First(_) => "yamux",
+        EitherOutput::Second(_) => "mplex",
+    }
+}
+
+// A transport that only reports the listen addresses of the IP version
+// it was asked to listen on. A TCP listener on either wildcard address
+// reports the addresses of every interface, IPv4 and IPv6, and it only
+// listens on the ones of its own version.
+#[derive(Clone)]
+struct OwnVersion(RawTransport);
+
+impl Transport for OwnVersion {
+    type Output = Box<dyn Socket>;
+    type Error = io::Error;
+    type Listener = <RawTransport as Transport>::Listener;
+    type ListenerUpgrade = <RawTransport as Transport>::ListenerUpgrade;
+    type Dial = <RawTransport as Transport>::Dial;
 
-    // Parse a multiaddr of the peer to connect to
-    let addr: Multiaddr = "/ip4/192.168.1.208/tcp/20000"
-        .parse()
-        .expect("invalid multiaddr");
+    fn listen_on(
+        self,
+        addr: Multiaddr,
+    ) -> Result<Self::Listener, TransportError<io::Error>> {
+        let version = ip_version(&addr);
+        let listener = self.0.listen_on(addr)?;
+        Ok(listener
+            .filter(move |event| {
+                future::ready(match event {
+                    Ok(ListenerEvent::NewAddress(addr))
+                    | Ok(ListenerEvent::AddressExpired(addr)) => {
+                        version.is_none() || ip_version(addr) == version
+                    }
+                    _ => true,
+                })
+            })
+            .boxed())
+    }
+
+    fn dial(
+        self,
+        addr: Multiaddr,
+    ) -> Result<Self::Dial, TransportError<io::Error>> {
+        self.0.dial(addr)
+    }
+}
 
-    // Dial the peer (this returns a future)
-    let _conn = transport.dial(addr).unwrap();
+// Which IP version an address is (4 or 6), unless it isn't an IP address.
+fn ip_version(addr: &Multiaddr) -> Option<u8> {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(_)) => Some(4),
+        Some(Protocol::Ip6(_)) => Some(6),
+        _ => None,
+    }
 }
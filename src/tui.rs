@@ -0,0 +1,411 @@
+use crate::{
+    behaviour::MyBehavior,
+    output::{self, Message, OutputSink},
+};
+use futures::{channel::mpsc, FutureExt};
+use futures_timer::Delay;
+use libp2p::{kad::K_VALUE, Swarm};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    task::Context,
+    thread,
+    time::{Duration, Instant},
+};
+
+// How often the dashboard looks for something to redraw, and how often
+// it redraws anyway (for the numbers that change on their own).
+const TICK: Duration = Duration::from_millis(100);
+const REFRESH: Duration = Duration::from_secs(1);
+
+// How many lines of output the dashboard keeps.
+const LOG_LINES: usize = 500;
+
+// --tui: a dashboard of the node, redrawn in place, instead of output
+// that scrolls by. It shows the peers (with their round trip times), how
+// full the buckets of the routing table are, the latest queries, the
+// local store, the latest output, and the command being typed.
+//
+// It is drawn with plain ANSI escapes, on the alternate screen, with the
+// terminal in non-canonical mode so that typing doesn't get in the way
+// of redrawing.
+pub struct Dashboard {
+    log: Arc<LogPanel>,
+    input: Arc<Mutex<Vec<u8>>>,
+    terminal: Terminal,
+    tick: Delay,
+    drawn: Option<Instant>,
+}
+
+impl Dashboard {
+    // Take over the terminal. The commands typed come out of the
+    // receiver, one line at a time.
+    pub fn start() -> io::Result<(Self, mpsc::UnboundedReceiver<String>)> {
+        let terminal = Terminal::enter()?;
+        let log = Arc::new(LogPanel::default());
+        output::set_terminal(log.clone());
+
+        let input = Arc::new(Mutex::new(Vec::new()));
+        let (tx, rx) = mpsc::unbounded();
+        let typing = input.clone();
+        let typed = log.clone();
+        thread::spawn(move || read_keys(&typing, &typed, &tx));
+
+        let dashboard = Dashboard {
+            log,
+            input,
+            terminal,
+            tick: Delay::new(TICK),
+            drawn: None,
+        };
+        Ok((dashboard, rx))
+    }
+
+    // Redraw, if there is anything new to show.
+    pub fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        swarm: &mut Swarm<MyBehavior>,
+    ) {
+        while self.tick.poll_unpin(cx).is_ready() {
+            self.tick.reset(TICK);
+            let stale =
+                self.drawn.is_none_or(|drawn| drawn.elapsed() >= REFRESH);
+            if stale || self.log.take_changed() {
+                self.draw(swarm);
+                self.drawn = Some(Instant::now());
+            }
+        }
+    }
+
+    fn draw(&self, swarm: &mut Swarm<MyBehavior>) {
+        let (rows, cols) = self.terminal.size();
+        let mut screen = Screen::new(cols);
+
+        let peers = swarm.known_peers();
+        let connected = peers
+            .iter()
+            .filter(|(peer, _)| swarm.connections.is_connected(peer))
+            .count();
+        screen.title(format!(
+            "nettest {} | {} peers ({} connected) | {} queries running",
+            Swarm::local_peer_id(swarm),
+            peers.len(),
+            connected,
+            swarm.kademlia.iter_queries().count()
+        ));
+
+        screen.section("Peers");
+        let mut lines = Vec::new();
+        for (peer, addrs) in &peers {
+            let rtt = match swarm.rtt(peer) {
+                Some(rtt) => format!("{:>7.1?}", rtt),
+                None => format!("{:>7}", "-"),
+            };
+            let state = if swarm.connections.is_connected(peer) {
+                "connected"
+            } else {
+                "         "
+            };
+            let addr = addrs.first().map(|a| a.to_string());
+            lines.push(format!(
+                "{} {} {} {}",
+                rtt,
+                state,
+                peer,
+                addr.unwrap_or_default()
+            ));
+        }
+        screen.lines(lines, 8);
+
+        screen.section("Buckets");
+        let lines = swarm
+            .buckets()
+            .iter()
+            .map(|bucket| {
+                format!(
+                    "{:>3} {} {:>2}/{}{}",
+                    bucket.index,
+                    bucket.bar(K_VALUE.get()),
+                    bucket.entries(),
+                    K_VALUE,
+                    if bucket.pending { ", 1 pending" } else { "" }
+                )
+            })
+            .collect();
+        screen.lines(lines, 6);
+
+        screen.section("Recent queries");
+        let lines = swarm
+            .recent_queries
+            .iter()
+            .rev()
+            .map(|query| {
+                format!(
+                    "{:>7.0?} ago {:<18} {:<6} in {:.0?}",
+                    query.finished.elapsed(),
+                    query.kind,
+                    if query.ok { "ok" } else { "failed" },
+                    query.duration
+                )
+            })
+            .collect();
+        screen.lines(lines, 6);
+
+        screen.section("Local store");
+        let lines = swarm
+            .list_local()
+            .into_iter()
+            .skip(1)
+            .map(|message| match message {
+                Message::Info(line) | Message::Error(line) => line,
+            })
+            .collect();
+        screen.lines(lines, 6);
+
+        // The rest of the screen goes to the output, but for the prompt
+        screen.section("Output");
+        let room = rows.saturating_sub(screen.rows() + 1);
+        screen.log(&self.log.latest(room));
+
+        let input = self.input.lock().unwrap();
+        let prompt = format!("> {}", String::from_utf8_lossy(&input));
+        self.terminal.show(&screen.finish(rows, &prompt));
+    }
+}
+
+impl Drop for Dashboard {
+    // Leave the last of the output on the normal screen, where it stays.
+    fn drop(&mut self) {
+        self.terminal.leave();
+        output::set_terminal(Arc::new(output::PrettySink));
+        for message in self.log.latest(20) {
+            output::PrettySink.show(&message);
+        }
+    }
+}
+
+// Read what is typed, a key at a time, and hand over each line.
+fn read_keys(
+    input: &Mutex<Vec<u8>>,
+    log: &LogPanel,
+    lines: &mpsc::UnboundedSender<String>,
+) {
+    let stdin = io::stdin();
+    for byte in stdin.lock().bytes() {
+        let byte = match byte {
+            Ok(byte) => byte,
+            Err(_) => break,
+        };
+        let mut input = input.lock().unwrap();
+        match byte {
+            b'\n' | b'\r' => {
+                let line = String::from_utf8_lossy(&input).into_owned();
+                input.clear();
+                if lines.unbounded_send(line).is_err() {
+                    break;
+                }
+            }
+            // Backspace (the whole character, if it takes many bytes)
+            0x7f | 0x08 => {
+                while let Some(byte) = input.pop() {
+                    if byte & 0xc0 != 0x80 {
+                        break;
+                    }
+                }
+            }
+            // Ctrl-D on an empty line
+            0x04 if input.is_empty() => break,
+            byte if byte >= 0x20 || byte == b'\t' => input.push(byte),
+            _ => {}
+        }
+        log.touch();
+    }
+    // Like the end of stdin
+    lines.close_channel();
+}
+
+// The output of the node, kept for the dashboard.
+#[derive(Debug, Default)]
+struct LogPanel {
+    lines: Mutex<(VecDeque<Message>, bool)>,
+}
+
+impl LogPanel {
+    fn latest(&self, n: usize) -> Vec<Message> {
+        let lines = &self.lines.lock().unwrap().0;
+        lines
+            .iter()
+            .skip(lines.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+
+    // Whether something changed since the last time.
+    fn take_changed(&self) -> bool {
+        std::mem::take(&mut self.lines.lock().unwrap().1)
+    }
+
+    fn touch(&self) {
+        self.lines.lock().unwrap().1 = true;
+    }
+}
+
+impl OutputSink for LogPanel {
+    fn show(&self, message: &Message) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.0.len() == LOG_LINES {
+            lines.0.pop_front();
+        }
+        lines.0.push_back(message.clone());
+        lines.1 = true;
+    }
+}
+
+// A frame of the dashboard, as it is put together.
+struct Screen {
+    cols: usize,
+    text: Vec<String>,
+}
+
+impl Screen {
+    fn new(cols: usize) -> Self {
+        Screen {
+            cols,
+            text: Vec::new(),
+        }
+    }
+
+    fn rows(&self) -> usize {
+        self.text.len()
+    }
+
+    fn fit(&self, line: &str) -> String {
+        line.chars().take(self.cols).collect()
+    }
+
+    fn title(&mut self, title: String) {
+        let line =
+            format!("\x1b[7m{:<1$}\x1b[0m", self.fit(&title), self.cols);
+        self.text.push(line);
+    }
+
+    fn section(&mut self, name: &str) {
+        let line = format!("\x1b[1m{}\x1b[0m", self.fit(name));
+        self.text.push(line);
+    }
+
+    // At most `max` of `lines`, saying how many were left out.
+    fn lines(&mut self, lines: Vec<String>, max: usize) {
+        if lines.is_empty() {
+            self.text.push("  (none)".into());
+            return;
+        }
+        let shown = if lines.len() > max { max - 1 } else { max };
+        for line in lines.iter().take(shown) {
+            let line = self.fit(&format!("  {}", line));
+            self.text.push(line);
+        }
+        if lines.len() > shown {
+            let more = format!("  ... {} more", lines.len() - shown);
+            self.text.push(more);
+        }
+    }
+
+    fn log(&mut self, messages: &[Message]) {
+        for message in messages {
+            let line = match message {
+                Message::Info(line) => self.fit(&format!("  {}", line)),
+                Message::Error(line) => format!(
+                    "\x1b[31m{}\x1b[0m",
+                    self.fit(&format!("  {}", line))
+                ),
+            };
+            self.text.push(line);
+        }
+    }
+
+    // The whole frame, with the prompt on the last row.
+    fn finish(mut self, rows: usize, prompt: &str) -> String {
+        self.text.truncate(rows.saturating_sub(1));
+        while self.text.len() + 1 < rows {
+            self.text.push(String::new());
+        }
+        let prompt = self.fit(prompt);
+        // Clear each line as it is written over, rather than the whole
+        // screen first, which flickers
+        let mut frame = String::from("\x1b[H");
+        for line in &self.text {
+            frame.push_str(line);
+            frame.push_str("\x1b[K\r\n");
+        }
+        frame.push_str(&prompt);
+        frame.push_str("\x1b[K");
+        frame
+    }
+}
+
+// The terminal, while the dashboard has it.
+struct Terminal {
+    saved: libc::termios,
+}
+
+impl Terminal {
+    fn enter() -> io::Result<Self> {
+        // SAFETY: termios is plain data, filled in by tcgetattr
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } != 0
+        {
+            return Err(io::Error::other(
+                "--tui needs stdin to be a terminal",
+            ));
+        }
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw)
+        } != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        // The alternate screen, without the cursor
+        print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+        io::stdout().flush()?;
+        Ok(Terminal { saved })
+    }
+
+    // The rows and columns of the terminal.
+    fn size(&self) -> (usize, usize) {
+        // SAFETY: winsize is plain data, filled in by the ioctl
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        let ok = unsafe {
+            libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size)
+        } == 0;
+        if ok && size.ws_row > 0 && size.ws_col > 0 {
+            (size.ws_row as usize, size.ws_col as usize)
+        } else {
+            (24, 80)
+        }
+    }
+
+    fn show(&self, frame: &str) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(frame.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    fn leave(&self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        unsafe {
+            libc::tcsetattr(
+                libc::STDIN_FILENO,
+                libc::TCSANOW,
+                &self.saved,
+            );
+        }
+    }
+}
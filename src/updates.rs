@@ -0,0 +1,257 @@
+use crate::{behaviour::MyBehaviorWith, output::Output, value::Decoded};
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::{
+    kad::{
+        record::Key, GetProvidersOk, GetRecordError, GetRecordOk, QueryId,
+        QueryResult, Quorum,
+    },
+    swarm::NetworkBehaviour as Behaviour,
+    PeerId,
+};
+use std::{
+    collections::HashMap,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+// The key that announces `version` of the record under `key`: with
+// --announce-updates, whoever stores that version also provides
+// `<key>/updates/<version>`. Provider records can't change, but a new
+// one can turn up, so a reader that has version n only has to look for
+// the providers of version n + 1, which is a lot cheaper than fetching
+// the value.
+pub fn key_for(key: &Key, version: u64) -> Key {
+    let mut bytes = key.to_vec();
+    bytes.extend_from_slice(format!("/updates/{}", version).as_bytes());
+    Key::new(&bytes)
+}
+
+// What a watch does next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    // Fetch the value, to find out which version it is at
+    Fetch,
+    // Look for the providers of the next version
+    Poll(u64),
+}
+
+// A key whose updates are watched through their announcements
+// (WATCH_PROVIDERS), instead of by fetching its value over and over.
+#[derive(Debug)]
+struct Watch {
+    // The key as the user knows it (without the namespace)
+    name: String,
+    interval: Duration,
+    output: Output,
+    // The version of the value we have, or `None` before the first fetch
+    version: Option<u64>,
+    // Whether a newer version was announced, and is to be fetched
+    announced: bool,
+    timer: Delay,
+    // Whether a lookup is running
+    querying: bool,
+    // How many lookups of the providers were needed, for how many fetches
+    polls: u64,
+    fetches: u64,
+}
+
+// The keys watched through their providers, and the lookups that are
+// running for them.
+#[derive(Debug, Default)]
+pub struct ProviderWatches {
+    watches: HashMap<Key, Watch>,
+    queries: HashMap<QueryId, Key>,
+}
+
+impl ProviderWatches {
+    // Start watching `key` (instead of whoever watched it before), with
+    // the value fetched right away.
+    pub fn watch(
+        &mut self,
+        key: Key,
+        name: String,
+        interval: Duration,
+        output: Output,
+    ) {
+        self.watches.insert(
+            key,
+            Watch {
+                name,
+                interval,
+                output,
+                version: None,
+                announced: false,
+                timer: Delay::new(Duration::from_secs(0)),
+                querying: false,
+                polls: 0,
+                fetches: 0,
+            },
+        );
+    }
+
+    // Stop watching `key`. Returns how many lookups of the providers and
+    // fetches of the value the watch took, if it was watched.
+    pub fn unwatch(&mut self, key: &Key) -> Option<(u64, u64)> {
+        self.watches
+            .remove(key)
+            .map(|watch| (watch.polls, watch.fetches))
+    }
+
+    // The keys that are due for another lookup, and which one. The
+    // timers of the others wake the task once they are due. Watches
+    // whose output went away are dropped.
+    pub fn due(&mut self, cx: &mut Context<'_>) -> Vec<(Key, Step)> {
+        self.watches.retain(|_, watch| !watch.output.is_closed());
+        let mut due = Vec::new();
+        for (key, watch) in &mut self.watches {
+            if watch.querying {
+                continue;
+            }
+            if let Poll::Ready(()) = watch.timer.poll_unpin(cx) {
+                watch.timer = Delay::new(watch.interval);
+                let _ = watch.timer.poll_unpin(cx);
+                watch.querying = true;
+                let step = match watch.version {
+                    Some(version) if !watch.announced => {
+                        watch.polls += 1;
+                        Step::Poll(version + 1)
+                    }
+                    _ => {
+                        watch.fetches += 1;
+                        Step::Fetch
+                    }
+                };
+                due.push((key.clone(), step));
+            }
+        }
+        due
+    }
+
+    pub fn add_query(&mut self, key: Key, query: QueryId) {
+        self.queries.insert(query, key);
+    }
+
+    pub fn owns(&self, query: &QueryId) -> bool {
+        self.queries.contains_key(query)
+    }
+
+    // Take in the result of a lookup: a fetch of the value (decoded, at
+    // `version`), or of the providers of the next version. An
+    // announcement gets the value fetched again right away. Lookups that
+    // didn't get an answer are tried again at the next interval, and so
+    // is a fetch that still got the old version (the announcement can get
+    // to the peers before the record does).
+    pub fn finish(
+        &mut self,
+        query: QueryId,
+        result: &QueryResult,
+        version: Option<u64>,
+    ) {
+        let watch = match self
+            .queries
+            .remove(&query)
+            .and_then(|key| self.watches.get_mut(&key))
+        {
+            Some(watch) => watch,
+            None => return,
+        };
+        watch.querying = false;
+        let name = &watch.name;
+
+        match result {
+            QueryResult::GetRecord(Ok(GetRecordOk { records })) => {
+                let value = match records.first() {
+                    Some(record) => &record.record.value,
+                    None => return,
+                };
+                // Values that weren't stored with a version are at 0, as
+                // for CAS
+                let version = version.unwrap_or(0);
+                if watch.version.is_some_and(|known| known >= version) {
+                    return;
+                }
+                watch.output.info(format!(
+                    "watch providers: record {:?} {} version {}: {:?}",
+                    name,
+                    match watch.version {
+                        Some(_) => "changed to",
+                        None => "is at",
+                    },
+                    version,
+                    String::from_utf8_lossy(value)
+                ));
+                watch.version = Some(version);
+                watch.announced = false;
+            }
+            QueryResult::GetRecord(Err(GetRecordError::NotFound {
+                ..
+            })) if watch.version.is_none() => {
+                watch.output.info(format!(
+                    "watch providers: record {:?} doesn't exist yet",
+                    name
+                ));
+                watch.version = Some(0);
+            }
+            QueryResult::GetProviders(Ok(GetProvidersOk {
+                providers,
+                ..
+            })) => {
+                let provider: Option<&PeerId> = providers.iter().next();
+                if let (Some(provider), Some(version)) =
+                    (provider, watch.version.filter(|_| !watch.announced))
+                {
+                    watch.output.info(format!(
+                        "watch providers: {} announced version {} of {:?}",
+                        provider,
+                        version + 1,
+                        name
+                    ));
+                    watch.announced = true;
+                    watch.timer = Delay::new(Duration::from_secs(0));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<B: Behaviour> MyBehaviorWith<B> {
+    // Start the lookups of the keys watched through their providers that
+    // are due. Returns whether there were any.
+    pub fn look_up_provider_watches(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> bool {
+        let due = self.provider_watches.due(cx);
+        let started = !due.is_empty();
+        for (key, step) in due {
+            let id = match step {
+                Step::Fetch => self.kademlia.get_record(&key, Quorum::One),
+                Step::Poll(version) => {
+                    self.kademlia.get_providers(key_for(&key, version))
+                }
+            };
+            self.provider_watches.add_query(key, id);
+        }
+        started
+    }
+
+    // Take in the result of `id` (with the records it found, `decoded`),
+    // if it is the lookup of a key watched through its providers.
+    // Returns whether it was.
+    pub fn finish_provider_watch_lookup(
+        &mut self,
+        id: QueryId,
+        result: &QueryResult,
+        decoded: &[(String, Decoded)],
+    ) -> bool {
+        if !self.provider_watches.owns(&id) {
+            return false;
+        }
+        let version =
+            decoded.first().and_then(|(_, decoded)| decoded.version);
+        self.provider_watches.finish(id, result, version);
+        true
+    }
+}
@@ -0,0 +1,714 @@
+use crate::{
+    eventlog::EventLog,
+    fault,
+    namespace::NamespaceQuota,
+    output::Output,
+    schema::Schema,
+    storechurn::Churned,
+    value::{self, Signature},
+};
+use clap::ValueEnum;
+use libp2p::{
+    kad::{
+        record::{
+            store::{self, MemoryStore, MemoryStoreConfig, RecordStore},
+            Key,
+        },
+        ProviderRecord, Record,
+    },
+    PeerId,
+};
+use serde_json::json;
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+// Decides whether a record may go into the local store. Every record
+// does, whether we PUT it ourselves or a peer asks us to store it (or
+// replicates it to us).
+//
+// This is the hook for application-specific storage policies: implement
+// it, and add it to a node with
+// `swarm.kademlia.store_mut().add_validator(...)`.
+pub trait RecordValidator: Send + 'static {
+    // Why the record has to be refused, if it does.
+    fn validate(&self, record: &Record) -> Result<(), String>;
+}
+
+// The validators that come with nettest, as given to --validate:
+//
+// - max-size=BYTES: values can't be bigger than this
+// - key-prefix=PREFIX: keys have to start with this
+// - utf8: values have to be text
+// - json: values have to be JSON
+// - schema=PATH: values have to be JSON that matches the schema in the
+//   file (see `Schema` for what it can check)
+// - signed: values have to carry a valid signature by their publisher
+//   (see --sign)
+//
+// Values are checked as they are stored, so a value that is compressed
+// or encrypted isn't text (or JSON) anymore, and the chunks of a chunked
+// value aren't signed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Validator {
+    MaxSize(usize),
+    KeyPrefix(String),
+    Utf8,
+    Json,
+    Schema(Schema),
+    Signed,
+}
+
+impl FromStr for Validator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some(("max-size", size)) => size
+                .parse()
+                .map(Validator::MaxSize)
+                .map_err(|_| format!("{:?} is not a size in bytes", size)),
+            Some(("key-prefix", prefix)) => {
+                Ok(Validator::KeyPrefix(prefix.to_string()))
+            }
+            Some(("schema", path)) => {
+                Schema::load(path.into()).map(Validator::Schema)
+            }
+            None if s == "utf8" => Ok(Validator::Utf8),
+            None if s == "json" => Ok(Validator::Json),
+            None if s == "signed" => Ok(Validator::Signed),
+            _ => Err(format!(
+                "unknown validator {:?} (expected max-size=BYTES, \
+                 key-prefix=PREFIX, utf8, json, schema=PATH or signed)",
+                s
+            )),
+        }
+    }
+}
+
+impl RecordValidator for Validator {
+    fn validate(&self, record: &Record) -> Result<(), String> {
+        match self {
+            Validator::MaxSize(max) if record.value.len() > *max => {
+                Err(format!(
+                    "the value is {} bytes, more than {}",
+                    record.value.len(),
+                    max
+                ))
+            }
+            Validator::KeyPrefix(prefix)
+                if !record.key.as_ref().starts_with(prefix.as_bytes()) =>
+            {
+                Err(format!("the key doesn't start with {:?}", prefix))
+            }
+            Validator::Utf8 => std::str::from_utf8(&record.value)
+                .map(|_| ())
+                .map_err(|_| "the value isn't text".to_string()),
+            Validator::Json => {
+                serde_json::from_slice::<serde_json::Value>(&record.value)
+                    .map(|_| ())
+                    .map_err(|err| {
+                        format!("the value isn't JSON: {}", err)
+                    })
+            }
+            Validator::Schema(schema) => {
+                let value = serde_json::from_slice(&record.value)
+                    .map_err(|err| {
+                        format!("the value isn't JSON: {}", err)
+                    })?;
+                schema.check(&value)
+            }
+            Validator::Signed => {
+                match value::signature(
+                    &record.key,
+                    record.publisher.as_ref(),
+                    &record.value,
+                ) {
+                    Signature::Valid(_) => Ok(()),
+                    Signature::Unsigned => {
+                        Err("the value isn't signed".to_string())
+                    }
+                    Signature::Invalid(why) => {
+                        Err(format!("bad signature: {}", why))
+                    }
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// What a full store does with a record under a new key.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Eviction {
+    // Refuse it (what kademlia's memory store does)
+    #[default]
+    Refuse,
+    // Make room by dropping the record that was stored or read the
+    // longest time ago
+    Lru,
+}
+
+// How much the local store holds (anything that isn't given is up to
+// kademlia's memory store), and what happens once it is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreConfig {
+    pub max_records: Option<usize>,
+    pub max_providers_per_key: Option<usize>,
+    pub max_provided_keys: Option<usize>,
+    pub eviction: Eviction,
+    // How much of the store each publisher (other than this node) may
+    // take up, in records and in bytes of values
+    pub max_records_per_publisher: Option<usize>,
+    pub max_bytes_per_publisher: Option<usize>,
+    // How often to remove what expired
+    pub gc_interval: Option<Duration>,
+}
+
+// How often the expired records are removed from the store, unless
+// --store-gc-interval says otherwise.
+pub const DEFAULT_GC_INTERVAL: &str = "1m";
+
+// What removing the expired records from the store did, over the run.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GcStats {
+    pub runs: u64,
+    pub records: u64,
+    pub providers: u64,
+    // How long the last run took
+    pub took: Duration,
+}
+
+impl StoreConfig {
+    // The config of the memory store, which only takes values *smaller*
+    // than `max_value_bytes`.
+    pub fn memory_store_config(
+        &self,
+        max_value_bytes: usize,
+    ) -> MemoryStoreConfig {
+        let default = MemoryStoreConfig::default();
+        MemoryStoreConfig {
+            max_records: self.max_records.unwrap_or(default.max_records),
+            max_value_bytes,
+            max_providers_per_key: self
+                .max_providers_per_key
+                .unwrap_or(default.max_providers_per_key),
+            max_provided_keys: self
+                .max_provided_keys
+                .unwrap_or(default.max_provided_keys),
+        }
+    }
+}
+
+// A memory store that only takes the records that all of its validators
+// accept.
+pub struct ValidatingStore {
+    inner: MemoryStore,
+    validators: Vec<Box<dyn RecordValidator>>,
+    // How many records were refused (by the validators, and because the
+    // store was full), and how many made room for others
+    pub rejected: u64,
+    pub refused_full: u64,
+    pub evicted: u64,
+    pub refused_quota: u64,
+    pub gc: GcStats,
+    local_peer_id: PeerId,
+    max_records: usize,
+    eviction: Eviction,
+    max_records_per_publisher: Option<usize>,
+    max_bytes_per_publisher: Option<usize>,
+    // When each record was last stored or read, for Eviction::Lru (`get`
+    // only borrows the store)
+    last_used: RefCell<HashMap<Key, u64>>,
+    clock: Cell<u64>,
+    // The keys that we know providers of (the memory store only lists
+    // the ones we provide ourselves)
+    provider_keys: HashSet<Key>,
+    // The namespaces with quotas of their own (--namespaces), and how
+    // many records each of them had refused
+    namespaces: Vec<NamespaceQuota>,
+    pub refused_namespace: HashMap<String, u64>,
+    // Where to log what is stored and removed
+    event_log: Option<EventLog>,
+    // The records stored since the last `take_stored`, once somebody
+    // wants to hear about them
+    stored: Option<Vec<(Key, Option<PeerId>)>>,
+}
+
+impl ValidatingStore {
+    pub fn new(
+        local_peer_id: PeerId,
+        config: &StoreConfig,
+        max_value_bytes: usize,
+    ) -> Self {
+        let memory = config.memory_store_config(max_value_bytes);
+        ValidatingStore {
+            max_records: memory.max_records,
+            inner: MemoryStore::with_config(local_peer_id.clone(), memory),
+            local_peer_id,
+            validators: Vec::new(),
+            rejected: 0,
+            refused_full: 0,
+            evicted: 0,
+            refused_quota: 0,
+            gc: GcStats::default(),
+            eviction: config.eviction,
+            max_records_per_publisher: config.max_records_per_publisher,
+            max_bytes_per_publisher: config.max_bytes_per_publisher,
+            last_used: RefCell::new(HashMap::new()),
+            clock: Cell::new(0),
+            provider_keys: HashSet::new(),
+            namespaces: Vec::new(),
+            refused_namespace: HashMap::new(),
+            event_log: None,
+            stored: None,
+        }
+    }
+
+    // Remember that the record under `key` was just used.
+    fn touch(&self, key: &Key) {
+        if self.eviction == Eviction::Lru {
+            let now = self.clock.get() + 1;
+            self.clock.set(now);
+            self.last_used.borrow_mut().insert(key.clone(), now);
+        }
+    }
+
+    // Make room for a record under `key`, if it is new and the store is
+    // full, by dropping the least recently used record.
+    fn make_room(&mut self, key: &Key) {
+        if self.eviction != Eviction::Lru
+            || self.inner.get(key).is_some()
+            || self.inner.records().count() < self.max_records
+        {
+            return;
+        }
+        let last_used = self.last_used.get_mut();
+        let oldest = self
+            .inner
+            .records()
+            .map(|r| {
+                let used = last_used.get(&r.key).copied().unwrap_or(0);
+                (used, r.key.clone())
+            })
+            .min_by_key(|(used, _)| *used);
+        if let Some((_, oldest)) = oldest {
+            last_used.remove(&oldest);
+            self.inner.remove(&oldest);
+            self.evicted += 1;
+            self.log("record_evicted", &oldest, None);
+        }
+    }
+
+    // Why the publisher of `record` may not store it here, if it is over
+    // its quota (a record that replaces one of its own only counts for
+    // the difference). Records without a publisher share one quota.
+    fn over_quota(&self, record: &Record) -> Option<String> {
+        if (self.max_records_per_publisher.is_none()
+            && self.max_bytes_per_publisher.is_none())
+            || record.publisher.as_ref() == Some(&self.local_peer_id)
+        {
+            return None;
+        }
+        let (records, bytes) = self
+            .inner
+            .records()
+            .filter(|r| {
+                r.publisher == record.publisher && r.key != record.key
+            })
+            .fold((0, 0), |(records, bytes), r| {
+                (records + 1, bytes + r.value.len())
+            });
+        let publisher = match &record.publisher {
+            Some(peer) => peer.to_string(),
+            None => "no publisher".to_string(),
+        };
+        if let Some(max) = self.max_records_per_publisher {
+            if records >= max {
+                return Some(format!(
+                    "{} already has {} records here (see \
+                     --store-max-records-per-publisher)",
+                    publisher, records
+                ));
+            }
+        }
+        if let Some(max) = self.max_bytes_per_publisher {
+            if bytes + record.value.len() > max {
+                return Some(format!(
+                    "{} already has {} bytes here, and this would make it \
+                     {} (see --store-max-bytes-per-publisher)",
+                    publisher,
+                    bytes,
+                    bytes + record.value.len()
+                ));
+            }
+        }
+        None
+    }
+
+    // The namespace of `record`, and why it may not take it, if it is
+    // over its quota (like `over_quota`, a record that replaces one only
+    // counts for the difference).
+    fn over_namespace_quota(
+        &self,
+        record: &Record,
+    ) -> Option<(String, String)> {
+        let quota = self
+            .namespaces
+            .iter()
+            .find(|quota| quota.holds(&record.key))?;
+        let (records, bytes) =
+            self.usage_without(quota, Some(&record.key));
+        if let Some(max) = quota.max_records {
+            if records >= max {
+                return Some((
+                    quota.name.clone(),
+                    format!(
+                        "namespace {} already has {} records here (see \
+                         --namespaces)",
+                        quota.name, records
+                    ),
+                ));
+            }
+        }
+        if let Some(max) = quota.max_bytes {
+            if bytes + record.value.len() > max {
+                return Some((
+                    quota.name.clone(),
+                    format!(
+                        "namespace {} already has {} bytes here, and this \
+                         would make it {} (see --namespaces)",
+                        quota.name,
+                        bytes,
+                        bytes + record.value.len()
+                    ),
+                ));
+            }
+        }
+        None
+    }
+
+    // How many records of `quota`'s namespace the store holds, and how
+    // many bytes of values.
+    pub fn usage(&self, quota: &NamespaceQuota) -> (usize, usize) {
+        self.usage_without(quota, None)
+    }
+
+    fn usage_without(
+        &self,
+        quota: &NamespaceQuota,
+        key: Option<&Key>,
+    ) -> (usize, usize) {
+        self.inner
+            .records()
+            .filter(|r| quota.holds(&r.key) && Some(&r.key) != key)
+            .fold((0, 0), |(records, bytes), r| {
+                (records + 1, bytes + r.value.len())
+            })
+    }
+
+    pub fn set_namespaces(&mut self, namespaces: Vec<NamespaceQuota>) {
+        self.namespaces = namespaces;
+    }
+
+    pub fn set_event_log(&mut self, log: EventLog) {
+        self.event_log = Some(log);
+    }
+
+    // Keep the keys (and publishers) of the records that get stored from
+    // now on, for `take_stored`.
+    pub fn track_stored(&mut self) {
+        self.stored.get_or_insert_with(Vec::new);
+    }
+
+    pub fn take_stored(&mut self) -> Vec<(Key, Option<PeerId>)> {
+        self.stored.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn log(&self, event: &str, key: &Key, why: Option<&str>) {
+        if let Some(log) = &self.event_log {
+            log.write(
+                event,
+                &json!({
+                    "key": String::from_utf8_lossy(key.as_ref()),
+                    "why": why,
+                }),
+            );
+        }
+    }
+
+    // Make every record and provider record `by` older, as if that much
+    // time had passed (for ADVANCE, in simulations), and remove the ones
+    // that expire. Returns how many records, and provider records,
+    // expired.
+    pub fn age(&mut self, by: Duration) -> (usize, usize) {
+        let now = Instant::now();
+        let older = |expires: Instant| {
+            expires.checked_sub(by).filter(|expires| *expires > now)
+        };
+
+        let records: Vec<Record> = self
+            .inner
+            .records()
+            .filter(|record| record.expires.is_some())
+            .map(|record| record.into_owned())
+            .collect();
+        let mut expired = 0;
+        for mut record in records {
+            match older(record.expires.unwrap()) {
+                Some(expires) => {
+                    record.expires = Some(expires);
+                    self.inner.put(record).ok();
+                }
+                None => {
+                    expired += 1;
+                    self.log(
+                        "record_removed",
+                        &record.key,
+                        Some("expired"),
+                    );
+                    self.last_used.get_mut().remove(&record.key);
+                    self.inner.remove(&record.key);
+                }
+            }
+        }
+
+        let mut expired_providers = 0;
+        for key in self.provider_keys.clone() {
+            for mut provider in self.inner.providers(&key) {
+                let expires = match provider.expires {
+                    Some(expires) => expires,
+                    None => continue,
+                };
+                match older(expires) {
+                    Some(expires) => {
+                        provider.expires = Some(expires);
+                        self.inner.add_provider(provider).ok();
+                    }
+                    None => {
+                        expired_providers += 1;
+                        self.remove_provider(&key, &provider.provider);
+                    }
+                }
+            }
+        }
+        (expired, expired_providers)
+    }
+
+    // Remove the records and provider records that expired. Kademlia
+    // only does when they are asked for (or republished), so otherwise
+    // they keep taking up room, until the store is full of them. Returns
+    // how many records, and provider records, were removed.
+    pub fn collect_expired(&mut self) -> (usize, usize) {
+        let started = Instant::now();
+        let expired: Vec<Key> = self
+            .inner
+            .records()
+            .filter(|record| record.is_expired(started))
+            .map(|record| record.key.clone())
+            .collect();
+        for key in &expired {
+            self.log("record_removed", key, Some("expired"));
+            self.last_used.get_mut().remove(key);
+            self.inner.remove(key);
+        }
+
+        let mut expired_providers = 0;
+        for key in self.provider_keys.clone() {
+            for provider in self.inner.providers(&key) {
+                if provider.is_expired(started) {
+                    expired_providers += 1;
+                    self.remove_provider(&key, &provider.provider);
+                }
+            }
+        }
+
+        self.gc.runs += 1;
+        self.gc.records += expired.len() as u64;
+        self.gc.providers += expired_providers as u64;
+        self.gc.took = started.elapsed();
+        (expired.len(), expired_providers)
+    }
+
+    // Hit the records that `pick` picks, the way --store-churn does (see
+    // `storechurn`): garble their values, past the validators, or remove
+    // them. Returns what happened to which records.
+    pub fn churn(
+        &mut self,
+        mut pick: impl FnMut() -> Option<Churned>,
+    ) -> Vec<(Key, Churned)> {
+        let records: Vec<Record> = self
+            .inner
+            .records()
+            .map(|record| record.into_owned())
+            .collect();
+        let mut churned = Vec::new();
+        for mut record in records {
+            let key = record.key.clone();
+            match pick() {
+                Some(Churned::Overwritten) => {
+                    self.log(
+                        "record_corrupted",
+                        &key,
+                        Some("store churn"),
+                    );
+                    fault::garble(&mut record.value);
+                    self.inner.put(record).ok();
+                    churned.push((key, Churned::Overwritten));
+                }
+                Some(Churned::Deleted) => {
+                    self.log("record_removed", &key, Some("store churn"));
+                    self.last_used.get_mut().remove(&key);
+                    self.inner.remove(&key);
+                    churned.push((key, Churned::Deleted));
+                }
+                None => {}
+            }
+        }
+        churned
+    }
+
+    // Every provider record, ours and those of other peers.
+    pub fn all_providers(&self) -> Vec<ProviderRecord> {
+        self.provider_keys
+            .iter()
+            .flat_map(|key| self.inner.providers(key))
+            .collect()
+    }
+
+    pub fn add_validator(&mut self, validator: impl RecordValidator) {
+        self.validators.push(Box::new(validator));
+    }
+
+    // Why the store would refuse `record`, if it would.
+    pub fn check(&self, record: &Record) -> Result<(), String> {
+        self.validators
+            .iter()
+            .try_for_each(|validator| validator.validate(record))
+    }
+}
+
+impl fmt::Debug for ValidatingStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValidatingStore")
+            .field("validators", &self.validators.len())
+            .field("rejected", &self.rejected)
+            .field("refused_full", &self.refused_full)
+            .field("evicted", &self.evicted)
+            .field("refused_quota", &self.refused_quota)
+            .finish()
+    }
+}
+
+impl<'a> RecordStore<'a> for ValidatingStore {
+    type RecordsIter = <MemoryStore as RecordStore<'a>>::RecordsIter;
+    type ProvidedIter = <MemoryStore as RecordStore<'a>>::ProvidedIter;
+
+    fn get(&'a self, k: &Key) -> Option<Cow<'a, Record>> {
+        let record = self.inner.get(k);
+        if record.is_some() {
+            self.touch(k);
+        }
+        record
+    }
+
+    fn put(&'a mut self, r: Record) -> store::Result<()> {
+        if let Err(why) = self.check(&r) {
+            self.rejected += 1;
+            self.log("record_refused", &r.key, Some(&why));
+            Output::Terminal.error(format!(
+                "store: refused record {:?}: {}",
+                String::from_utf8_lossy(r.key.as_ref()),
+                why
+            ));
+            // The store has no error for this, and kademlia only cares
+            // that the record wasn't stored
+            return Err(store::Error::ValueTooLarge);
+        }
+        let key = r.key.clone();
+        let publisher = r.publisher.clone();
+        if let Some(why) = self.over_quota(&r) {
+            self.refused_quota += 1;
+            self.log("record_refused", &key, Some(&why));
+            Output::Terminal.error(format!(
+                "store: refused record {:?}: {}",
+                String::from_utf8_lossy(key.as_ref()),
+                why
+            ));
+            return Err(store::Error::MaxRecords);
+        }
+        if let Some((namespace, why)) = self.over_namespace_quota(&r) {
+            *self.refused_namespace.entry(namespace).or_default() += 1;
+            self.log("record_refused", &key, Some(&why));
+            Output::Terminal.error(format!(
+                "store: refused record {:?}: {}",
+                String::from_utf8_lossy(key.as_ref()),
+                why
+            ));
+            return Err(store::Error::MaxRecords);
+        }
+        self.make_room(&key);
+        if let Err(err) = self.inner.put(r) {
+            if let store::Error::MaxRecords = err {
+                self.refused_full += 1;
+                let why = format!(
+                    "the store is full ({} records, see --store-max-records)",
+                    self.max_records
+                );
+                self.log("record_refused", &key, Some(&why));
+                Output::Terminal.error(format!(
+                    "store: refused record {:?}: {}",
+                    String::from_utf8_lossy(key.as_ref()),
+                    why
+                ));
+            }
+            return Err(err);
+        }
+        self.touch(&key);
+        self.log("record_stored", &key, None);
+        if let Some(stored) = &mut self.stored {
+            stored.push((key, publisher));
+        }
+        Ok(())
+    }
+
+    fn remove(&'a mut self, k: &Key) {
+        self.log("record_removed", k, None);
+        self.last_used.get_mut().remove(k);
+        self.inner.remove(k)
+    }
+
+    fn records(&'a self) -> Self::RecordsIter {
+        self.inner.records()
+    }
+
+    fn add_provider(
+        &'a mut self,
+        record: ProviderRecord,
+    ) -> store::Result<()> {
+        let key = record.key.clone();
+        self.inner.add_provider(record)?;
+        self.provider_keys.insert(key);
+        Ok(())
+    }
+
+    fn providers(&'a self, key: &Key) -> Vec<ProviderRecord> {
+        self.inner.providers(key)
+    }
+
+    fn provided(&'a self) -> Self::ProvidedIter {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&'a mut self, k: &Key, p: &PeerId) {
+        self.inner.remove_provider(k, p);
+        if self.inner.providers(k).is_empty() {
+            self.provider_keys.remove(k);
+        }
+    }
+}
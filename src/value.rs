@@ -0,0 +1,750 @@
+use crate::{cas, chunk::DEFAULT_MAX_VALUE_SIZE, conflict::MergeStrategy};
+use aes_gcm::{
+    aead::{Aead, NewAead},
+    Aes256Gcm,
+};
+use clap::ValueEnum;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use hmac::{Hmac, Mac, NewMac};
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    kad::record::Key,
+    PeerId,
+};
+use sha2::Sha256;
+use std::{
+    fmt,
+    io::{Read, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Values can be transformed on their way into the dht (compressed, with
+// --compress, encrypted, with --record-key, and signed, with --sign), and
+// carry a version (when they are stored with CAS) and the time they were
+// stored (with `--merge last-writer-wins`). A
+// transformed value starts with this, followed by a byte of flags that
+// say what was done to the rest of it, so that GET knows how to undo it.
+// Values without the header are stored as they are.
+const MAGIC: &[u8] = b"\0nt";
+
+// The flags
+const DEFLATE: u8 = 1;
+const ENCRYPTED: u8 = 2;
+const SIGNED: u8 = 4;
+const VERSIONED: u8 = 8;
+const TIMESTAMPED: u8 = 16;
+
+// A versioned value starts (before it is compressed) with its version,
+// and a timestamped one with its timestamp (after the version, if it has
+// both), each eight bytes long.
+
+// An encrypted value starts (after the header) with the nonce it was
+// encrypted with.
+const NONCE_SIZE: usize = 12;
+
+// How hard deriving the record key from the passphrase is. Every node
+// derives it once, when it starts.
+const KEY_ITERATIONS: u32 = 10_000;
+
+// Every node has to come up with the same key for the same passphrase, so
+// the salt is fixed.
+const KEY_SALT: &[u8] = b"nettest record key";
+
+// Room for the version and the time that a value may carry, on top of
+// the value itself.
+const STAMPS_SIZE: usize = 16;
+
+// How to compress values. Deflate is the only compression there is
+// (flate2 is all this build has of compression crates).
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Deflate,
+}
+
+// The AES-256 key values are encrypted with.
+#[derive(Clone)]
+pub struct RecordKey([u8; 32]);
+
+impl RecordKey {
+    // Derive the key from a passphrase, with PBKDF2-HMAC-SHA256.
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        RecordKey(pbkdf2(passphrase.as_bytes(), KEY_SALT, KEY_ITERATIONS))
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(&self.0.into())
+    }
+}
+
+// PBKDF2-HMAC-SHA256, for a 32 byte key (which is exactly one block of
+// it).
+fn pbkdf2(passphrase: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let prf = || {
+        Hmac::<Sha256>::new_varkey(passphrase)
+            .expect("HMAC takes keys of any size")
+    };
+    let mut mac = prf();
+    mac.update(salt);
+    mac.update(&1u32.to_be_bytes());
+    let mut block = mac.finalize().into_bytes();
+
+    let mut key = [0; 32];
+    key.copy_from_slice(&block);
+    for _ in 1..iterations {
+        let mut mac = prf();
+        mac.update(&block);
+        block = mac.finalize().into_bytes();
+        for (k, b) in key.iter_mut().zip(block.iter()) {
+            *k ^= b;
+        }
+    }
+    key
+}
+
+// Keep the key out of debug output.
+impl fmt::Debug for RecordKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RecordKey(..)")
+    }
+}
+
+// The keypair of the node, to sign values with.
+#[derive(Clone)]
+pub struct Signer(pub Keypair);
+
+impl fmt::Debug for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Signer({})", self.0.public().into_peer_id())
+    }
+}
+
+// Whether a fetched value was signed, and by whom.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Signature {
+    Unsigned,
+    Valid(PeerId),
+    Invalid(String),
+}
+
+// A value as it was before it was stored.
+#[derive(Debug, Clone)]
+pub struct Decoded {
+    pub value: Vec<u8>,
+    pub signature: Signature,
+    // The version of a value that was stored with CAS
+    pub version: Option<u64>,
+    // When the value was stored (in milliseconds since the epoch), if
+    // the node that stored it said
+    pub timestamp: Option<u64>,
+}
+
+impl Decoded {
+    // A value that was stored as it is.
+    pub fn plain(value: Vec<u8>) -> Self {
+        Decoded {
+            value,
+            signature: Signature::Unsigned,
+            version: None,
+            timestamp: None,
+        }
+    }
+}
+
+// What a node does to the values it stores, and undoes to the ones it
+// fetches.
+#[derive(Debug, Clone)]
+pub struct ValueCodec {
+    // The compression of values that don't ask for any in particular
+    pub compress: Option<Compression>,
+    // Encrypt every value with this key (and decrypt the ones that were)
+    pub key: Option<RecordKey>,
+    // Sign every value with the node's keypair
+    pub signer: Option<Signer>,
+    // Refuse values that aren't signed, or whose signature doesn't check
+    // out (otherwise they only get a warning)
+    pub require_signed: bool,
+    // How to settle the conflicting values a GET finds (nodes that go by
+    // the last writer stamp every value with the time it is stored)
+    pub merge: MergeStrategy,
+    // How big a value may get once it is decompressed (--max-value-size),
+    // so that a small record can't inflate into gigabytes
+    pub max_value_size: usize,
+}
+
+impl Default for ValueCodec {
+    fn default() -> Self {
+        ValueCodec {
+            compress: None,
+            key: None,
+            signer: None,
+            require_signed: false,
+            merge: MergeStrategy::default(),
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+        }
+    }
+}
+
+impl ValueCodec {
+    // Get a value ready to be stored under `key`, along with its
+    // `version` (if it has one) and the time, compressed with `compress` (which is
+    // usually the default, `self.compress`), then encrypted, and then
+    // signed.
+    pub fn encode(
+        &self,
+        key: &Key,
+        value: Vec<u8>,
+        compress: Option<Compression>,
+        version: Option<u64>,
+    ) -> Vec<u8> {
+        let mut flags = 0;
+        let mut prefix = Vec::new();
+        if let Some(version) = version {
+            prefix.extend_from_slice(&version.to_be_bytes());
+            flags |= VERSIONED;
+        }
+        if self.merge == MergeStrategy::LastWriterWins {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_millis() as u64);
+            prefix.extend_from_slice(&now.to_be_bytes());
+            flags |= TIMESTAMPED;
+        }
+        prefix.extend(value);
+        let mut body = prefix;
+        if let Some(Compression::Deflate) = compress {
+            let mut encoder = DeflateEncoder::new(
+                Vec::new(),
+                flate2::Compression::default(),
+            );
+            // Writing to a Vec can't fail
+            encoder.write_all(&body).unwrap();
+            body = encoder.finish().unwrap();
+            flags |= DEFLATE;
+        }
+        if let Some(record_key) = &self.key {
+            let nonce: [u8; NONCE_SIZE] = rand::random();
+            let mut sealed = nonce.to_vec();
+            sealed.extend(
+                record_key
+                    .cipher()
+                    .encrypt(&nonce.into(), &body[..])
+                    .expect("AES-GCM encrypts values of any size"),
+            );
+            body = sealed;
+            flags |= ENCRYPTED;
+        }
+        let signature = self.signer.as_ref().map(|Signer(keypair)| {
+            flags |= SIGNED;
+            let signature = keypair
+                .sign(&signed_message(key, flags, &body))
+                .expect("ed25519 signing doesn't fail");
+            (keypair.public().into_protobuf_encoding(), signature)
+        });
+        if flags == 0 {
+            return body;
+        }
+
+        let mut encoded = MAGIC.to_vec();
+        encoded.push(flags);
+        if let Some((public_key, signature)) = signature {
+            put_field(&mut encoded, &public_key);
+            put_field(&mut encoded, &signature);
+        }
+        encoded.extend(body);
+        encoded
+    }
+
+    // Recover the original value from one that was stored under `key` by
+    // `publisher` (if we know who that was). This fails if the value is
+    // encrypted and we don't have the right key, or if it was tampered
+    // with, or if it isn't properly signed and signatures are required, or
+    // if it doesn't match its content-addressed key.
+    pub fn decode(
+        &self,
+        key: &Key,
+        publisher: Option<&PeerId>,
+        value: Vec<u8>,
+    ) -> Result<Decoded, String> {
+        self.decode_up_to(key, publisher, value, self.max_value_size)
+    }
+
+    // The same, for a value that may decompress to as much as `max_size`
+    // bytes: the values of --chunk can be any size.
+    pub fn decode_up_to(
+        &self,
+        key: &Key,
+        publisher: Option<&PeerId>,
+        value: Vec<u8>,
+        max_size: usize,
+    ) -> Result<Decoded, String> {
+        let flags = match value.strip_prefix(MAGIC) {
+            Some([flags, ..]) => *flags,
+            _ => return self.check(key, Decoded::plain(value)),
+        };
+        let known = DEFLATE | ENCRYPTED | SIGNED | VERSIONED | TIMESTAMPED;
+        if flags & !known != 0 {
+            return Err(format!("unknown value flags {:#04x}", flags));
+        }
+        let mut rest = &value[MAGIC.len() + 1..];
+
+        let signature = if flags & SIGNED != 0 {
+            let public_key = take_field(&mut rest)?;
+            let signature = take_field(&mut rest)?;
+            verify(key, publisher, flags, public_key, signature, rest)
+        } else {
+            Signature::Unsigned
+        };
+        let mut body = rest.to_vec();
+
+        if flags & ENCRYPTED != 0 {
+            let key = self.key.as_ref().ok_or(
+                "the value is encrypted, and there is no --record-key",
+            )?;
+            if body.len() < NONCE_SIZE {
+                return Err("the encrypted value is truncated".into());
+            }
+            let mut nonce = [0; NONCE_SIZE];
+            nonce.copy_from_slice(&body[..NONCE_SIZE]);
+            let sealed = &body[NONCE_SIZE..];
+            body = key.cipher().decrypt(&nonce.into(), sealed).map_err(
+                |_| {
+                    "integrity check failed (the --record-key is wrong, \
+                     or the value was tampered with)"
+                        .to_string()
+                },
+            )?;
+        }
+        if flags & DEFLATE != 0 {
+            let max_size = max_size.saturating_add(STAMPS_SIZE);
+            let mut decoded = Vec::new();
+            DeflateDecoder::new(&body[..])
+                .take((max_size as u64).saturating_add(1))
+                .read_to_end(&mut decoded)
+                .map_err(|err| format!("bad deflate data: {}", err))?;
+            if decoded.len() > max_size {
+                return Err(format!(
+                    "the value decompresses to more than {} bytes (see \
+                     --max-value-size)",
+                    max_size - STAMPS_SIZE
+                ));
+            }
+            body = decoded;
+        }
+        let version = match flags & VERSIONED {
+            0 => None,
+            _ => Some(take_u64(&mut body, "versioned")?),
+        };
+        let timestamp = match flags & TIMESTAMPED {
+            0 => None,
+            _ => Some(take_u64(&mut body, "timestamped")?),
+        };
+        self.check(
+            key,
+            Decoded {
+                value: body,
+                signature,
+                version,
+                timestamp,
+            },
+        )
+    }
+
+    // Refuse values that aren't what their key says they are, and the ones
+    // that aren't properly signed, if we have to.
+    fn check(
+        &self,
+        key: &Key,
+        decoded: Decoded,
+    ) -> Result<Decoded, String> {
+        cas::check(key, &decoded.value)?;
+        match &decoded.signature {
+            Signature::Unsigned if self.require_signed => {
+                Err("the value isn't signed".into())
+            }
+            Signature::Invalid(why) if self.require_signed => {
+                Err(format!("bad signature: {}", why))
+            }
+            _ => Ok(decoded),
+        }
+    }
+}
+
+// Check the signature of a value stored under `key` by `publisher`,
+// without decoding (or being able to decode) the rest of it.
+pub fn signature(
+    key: &Key,
+    publisher: Option<&PeerId>,
+    value: &[u8],
+) -> Signature {
+    match value.strip_prefix(MAGIC) {
+        Some([flags, rest @ ..]) if flags & SIGNED != 0 => {
+            let mut rest = rest;
+            match (take_field(&mut rest), take_field(&mut rest)) {
+                (Ok(public_key), Ok(signature)) => verify(
+                    key, publisher, *flags, public_key, signature, rest,
+                ),
+                _ => Signature::Invalid(
+                    "the signed value is truncated".into(),
+                ),
+            }
+        }
+        _ => Signature::Unsigned,
+    }
+}
+
+// What the signature of a value stored under `key` covers: the key, and
+// the value as it is stored (so that it can be checked without
+// decrypting anything).
+fn signed_message(key: &Key, flags: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = (key.as_ref().len() as u32).to_be_bytes().to_vec();
+    message.extend_from_slice(key.as_ref());
+    message.push(flags);
+    message.extend_from_slice(body);
+    message
+}
+
+// Check the signature of a value, and that it was made by whoever
+// published the record.
+fn verify(
+    key: &Key,
+    publisher: Option<&PeerId>,
+    flags: u8,
+    public_key: &[u8],
+    signature: &[u8],
+    body: &[u8],
+) -> Signature {
+    let public_key = match PublicKey::from_protobuf_encoding(public_key) {
+        Ok(public_key) => public_key,
+        Err(_) => return Signature::Invalid("bad public key".into()),
+    };
+    if !public_key.verify(&signed_message(key, flags, body), signature) {
+        return Signature::Invalid("the value was tampered with".into());
+    }
+    let signer = public_key.into_peer_id();
+    match publisher {
+        Some(publisher) if *publisher != signer => {
+            Signature::Invalid(format!(
+                "signed by {}, but published by {}",
+                signer, publisher
+            ))
+        }
+        _ => Signature::Valid(signer),
+    }
+}
+
+// Take a number off the start of a (versioned or timestamped) value.
+fn take_u64(body: &mut Vec<u8>, what: &str) -> Result<u64, String> {
+    if body.len() < 8 {
+        return Err(format!("the {} value is truncated", what));
+    }
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&body[..8]);
+    body.drain(..8);
+    Ok(u64::from_be_bytes(bytes))
+}
+
+// Signature fields are the length (two bytes) followed by the bytes.
+fn put_field(out: &mut Vec<u8>, field: &[u8]) {
+    out.extend_from_slice(&(field.len() as u16).to_be_bytes());
+    out.extend_from_slice(field);
+}
+
+fn take_field<'a>(rest: &mut &'a [u8]) -> Result<&'a [u8], String> {
+    let truncated = || "the signed value is truncated".to_string();
+    if rest.len() < 2 {
+        return Err(truncated());
+    }
+    let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+    if rest.len() < 2 + len {
+        return Err(truncated());
+    }
+    let field = &rest[2..2 + len];
+    *rest = &rest[2 + len..];
+    Ok(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // The codec with every combination of the flags, and the version to
+    // encode with.
+    fn codecs() -> Vec<(u8, ValueCodec, Option<u64>)> {
+        let key = RecordKey::from_passphrase("hunter2");
+        let signer = Signer(Keypair::generate_ed25519());
+        (0..32)
+            .map(|flags| {
+                let codec = ValueCodec {
+                    compress: Some(Compression::Deflate)
+                        .filter(|_| flags & DEFLATE != 0),
+                    key: Some(key.clone())
+                        .filter(|_| flags & ENCRYPTED != 0),
+                    signer: Some(signer.clone())
+                        .filter(|_| flags & SIGNED != 0),
+                    merge: match flags & TIMESTAMPED {
+                        0 => MergeStrategy::Report,
+                        _ => MergeStrategy::LastWriterWins,
+                    },
+                    ..ValueCodec::default()
+                };
+                let version = Some(7).filter(|_| flags & VERSIONED != 0);
+                (flags, codec, version)
+            })
+            .collect()
+    }
+
+    fn encode(codec: &ValueCodec, version: Option<u64>) -> Vec<u8> {
+        let value = b"a value, a value, a value".to_vec();
+        codec.encode(&Key::new(&"key"), value, codec.compress, version)
+    }
+
+    fn decode(
+        codec: &ValueCodec,
+        value: Vec<u8>,
+    ) -> Result<Decoded, String> {
+        codec.decode(&Key::new(&"key"), None, value)
+    }
+
+    #[test]
+    fn pbkdf2_vectors() {
+        let vectors: &[(&str, &str, u32, &str)] = &[
+            (
+                "password",
+                "salt",
+                1,
+                "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b",
+            ),
+            (
+                "password",
+                "salt",
+                2,
+                "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43",
+            ),
+            (
+                "password",
+                "salt",
+                4096,
+                "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a",
+            ),
+            (
+                "passwordPASSWORDpassword",
+                "saltSALTsaltSALTsaltSALTsaltSALTsalt",
+                4096,
+                "348c89dbcbd32b2f32d814b8116e84cf2b17347ebc1800181c4e2a1fb8dd53e1",
+            ),
+        ];
+        for (passphrase, salt, iterations, key) in vectors {
+            let derived = pbkdf2(
+                passphrase.as_bytes(),
+                salt.as_bytes(),
+                *iterations,
+            );
+            assert_eq!(
+                hex(&derived),
+                *key,
+                "{} {}",
+                passphrase,
+                iterations
+            );
+        }
+    }
+
+    // Nodes that disagree about this can't read each other's values
+    #[test]
+    fn record_keys() {
+        assert_eq!(
+            hex(&RecordKey::from_passphrase("hunter2").0),
+            "c367d9387b1431c9e97422c4a7cb8307a3889251db5b8a521e4eee372deac603"
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        for (flags, codec, version) in codecs() {
+            let encoded = encode(&codec, version);
+            match flags {
+                0 => assert_eq!(encoded, b"a value, a value, a value"),
+                _ => {
+                    assert_eq!(&encoded[..4], &[b'\0', b'n', b't', flags])
+                }
+            }
+            let decoded = decode(&codec, encoded).unwrap();
+            assert_eq!(decoded.value, b"a value, a value, a value");
+            assert_eq!(decoded.version, version);
+            assert_eq!(
+                decoded.timestamp.is_some(),
+                flags & TIMESTAMPED != 0
+            );
+            match &codec.signer {
+                Some(Signer(keypair)) => assert_eq!(
+                    decoded.signature,
+                    Signature::Valid(keypair.public().into_peer_id())
+                ),
+                None => assert_eq!(decoded.signature, Signature::Unsigned),
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_ciphertext() {
+        for (flags, codec, version) in codecs() {
+            if flags & ENCRYPTED == 0 || flags & SIGNED != 0 {
+                continue;
+            }
+            let mut encoded = encode(&codec, version);
+            *encoded.last_mut().unwrap() ^= 1;
+            let err = decode(&codec, encoded).unwrap_err();
+            assert!(err.starts_with("integrity check failed"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn encrypted_without_the_key() {
+        let codec = ValueCodec {
+            key: Some(RecordKey::from_passphrase("hunter2")),
+            ..ValueCodec::default()
+        };
+        let encoded = encode(&codec, None);
+        assert!(decode(&ValueCodec::default(), encoded.clone()).is_err());
+        let wrong = ValueCodec {
+            key: Some(RecordKey::from_passphrase("hunter3")),
+            ..ValueCodec::default()
+        };
+        assert!(decode(&wrong, encoded).is_err());
+    }
+
+    #[test]
+    fn bad_signatures() {
+        for (flags, codec, version) in codecs() {
+            if flags & SIGNED == 0 {
+                continue;
+            }
+            let strict = ValueCodec {
+                require_signed: true,
+                ..codec.clone()
+            };
+            // A changed last byte of the body, and first byte of the
+            // signature (which comes after the public key)
+            let encoded = encode(&codec, version);
+            let body = encoded.len() - 1;
+            let public_key =
+                u16::from_be_bytes([encoded[4], encoded[5]]) as usize;
+            let signature = MAGIC.len() + 1 + 2 + public_key + 2;
+            for at in [body, signature] {
+                let mut tampered = encoded.clone();
+                tampered[at] ^= 1;
+                assert_eq!(
+                    signature_of(&tampered),
+                    Signature::Invalid(
+                        "the value was tampered with".into()
+                    ),
+                    "flags {:#04x}, byte {}",
+                    flags,
+                    at
+                );
+                assert!(decode(&strict, tampered).is_err());
+            }
+
+            // Signed by someone other than the publisher
+            let someone = PeerId::random();
+            let decoded = strict.decode(
+                &Key::new(&"key"),
+                Some(&someone),
+                encoded.clone(),
+            );
+            assert!(decoded.unwrap_err().contains("but published by"));
+
+            // Stored under another key
+            let decoded =
+                strict.decode(&Key::new(&"other"), None, encoded);
+            assert!(decoded.is_err());
+        }
+    }
+
+    fn signature_of(value: &[u8]) -> Signature {
+        signature(&Key::new(&"key"), None, value)
+    }
+
+    #[test]
+    fn unsigned_values_when_signatures_are_required() {
+        let strict = ValueCodec {
+            require_signed: true,
+            ..ValueCodec::default()
+        };
+        let encoded = encode(&ValueCodec::default(), None);
+        assert!(decode(&strict, encoded).is_err());
+    }
+
+    // No prefix of a value decodes as the whole of it (a signed one has
+    // a bad signature, which is only an error with --require-signed), and
+    // none of them panic
+    #[test]
+    fn truncated_values() {
+        for (flags, codec, version) in codecs() {
+            let encoded = encode(&codec, version);
+            for len in 0..encoded.len() {
+                let truncated = encoded[..len].to_vec();
+                let _ = signature_of(&truncated);
+                if let Ok(decoded) = decode(&codec, truncated) {
+                    assert!(
+                        decoded.value != b"a value, a value, a value"
+                            || matches!(
+                                decoded.signature,
+                                Signature::Invalid(_)
+                            ),
+                        "flags {:#04x}, {} bytes: {:?}",
+                        flags,
+                        len,
+                        decoded
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_headers() {
+        let codec = ValueCodec::default();
+        for value in [
+            &b"\0nt\x04"[..],
+            b"\0nt\x04\x00",
+            b"\0nt\x04\x00\x05abc",
+            b"\0nt\x04\x00\x01a\x00\x09abc",
+            b"\0nt\x02\x00",
+            b"\0nt\x08\x00\x00\x00",
+            b"\0nt\x10",
+        ] {
+            assert!(
+                decode(&codec, value.to_vec()).is_err(),
+                "{:?}",
+                value
+            );
+        }
+        let err = decode(&codec, b"\0nt\x40".to_vec()).unwrap_err();
+        assert_eq!(err, "unknown value flags 0x40");
+    }
+
+    #[test]
+    fn value_bombs() {
+        let codec = ValueCodec {
+            compress: Some(Compression::Deflate),
+            ..ValueCodec::default()
+        };
+        let bomb = vec![0; 4096];
+        let encoded =
+            codec.encode(&Key::new(&"key"), bomb, codec.compress, None);
+        assert!(encoded.len() < 100);
+        let small = ValueCodec {
+            max_value_size: 1024,
+            ..codec
+        };
+        let err = decode(&small, encoded).unwrap_err();
+        assert!(
+            err.starts_with("the value decompresses to more than 1024")
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use crate::output::{Message, Output};
+use libp2p::{
+    kad::{record::Key, QueryId},
+    request_response::RequestId,
+    PeerId,
+};
+use std::{collections::HashMap, time::Instant};
+
+// What a peer said when asked for a record straight (over fetch).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Holding {
+    // It has the record, with this value
+    Holds(Vec<u8>),
+    Missing,
+    // It couldn't be asked
+    Unreachable(String),
+}
+
+// A VERIFY of a key: the closest peers to it, and whether each of them
+// holds its record. A PUT that reached its quorum may still have left
+// most of the closest peers without a copy (the quorum counts whoever
+// answered first), and this is how to find out.
+#[derive(Debug)]
+struct Verification {
+    key: Key,
+    name: String,
+    started: Instant,
+    // What our own store has, to tell copies that differ from it
+    local: Option<Vec<u8>>,
+    peers: Vec<(PeerId, Option<Holding>)>,
+    left: usize,
+    output: Output,
+}
+
+impl Verification {
+    fn report(&self) -> Vec<Message> {
+        let holders = self
+            .peers
+            .iter()
+            .filter(|(_, holding)| {
+                matches!(holding, Some(Holding::Holds(_)))
+            })
+            .count();
+        let mut messages = vec![Message::Info(format!(
+            "VERIFY {}: {} of the {} closest peers hold it, in {:.1?}",
+            self.name,
+            holders,
+            self.peers.len(),
+            self.started.elapsed()
+        ))];
+        messages.push(Message::Info(format!(
+            "  this node: {}",
+            match self.local {
+                Some(_) => "holds it",
+                None => "doesn't have it",
+            }
+        )));
+        for (peer, holding) in &self.peers {
+            messages.push(match holding {
+                Some(Holding::Holds(value))
+                    if self.local.as_ref().is_some_and(|l| l != value) =>
+                {
+                    Message::Error(format!(
+                        "  {}: holds a different value ({} bytes)",
+                        peer,
+                        value.len()
+                    ))
+                }
+                Some(Holding::Holds(value)) => Message::Info(format!(
+                    "  {}: holds it ({} bytes)",
+                    peer,
+                    value.len()
+                )),
+                Some(Holding::Missing) => {
+                    Message::Error(format!("  {}: doesn't have it", peer))
+                }
+                Some(Holding::Unreachable(why)) => Message::Error(
+                    format!("  {}: couldn't be asked ({})", peer, why),
+                ),
+                None => Message::Error(format!("  {}: no answer", peer)),
+            });
+        }
+        messages
+    }
+}
+
+// The VERIFYs that are running: first the lookup of the closest peers,
+// then a fetch of the record from every one of them.
+#[derive(Debug, Default)]
+pub struct Verifications {
+    verifications: HashMap<u64, Verification>,
+    lookups: HashMap<QueryId, u64>,
+    // Which peer of which verification each fetch asks
+    requests: HashMap<RequestId, (u64, usize)>,
+    next: u64,
+}
+
+impl Verifications {
+    // Start verifying `key` (typed as `name`), once `lookup` finds the
+    // closest peers.
+    pub fn start(
+        &mut self,
+        lookup: QueryId,
+        key: Key,
+        name: String,
+        local: Option<Vec<u8>>,
+        output: Output,
+    ) {
+        let id = self.next;
+        self.next += 1;
+        self.lookups.insert(lookup, id);
+        self.verifications.insert(
+            id,
+            Verification {
+                key,
+                name,
+                started: Instant::now(),
+                local,
+                peers: Vec::new(),
+                left: 0,
+                output,
+            },
+        );
+    }
+
+    pub fn owns(&self, lookup: &QueryId) -> bool {
+        self.lookups.contains_key(lookup)
+    }
+
+    // Drop the verification that `lookup` is for (it was cancelled).
+    // Returns where its report would have gone.
+    pub fn cancel(&mut self, lookup: &QueryId) -> Option<Output> {
+        let id = self.lookups.remove(lookup)?;
+        let verification = self.verifications.remove(&id)?;
+        Some(verification.output)
+    }
+
+    // The lookup found `peers`. Returns the verification, and the key
+    // to ask each of them for (with `add_request`), or None if there is
+    // nobody to ask, and the verification got reported as it is.
+    pub fn found(
+        &mut self,
+        lookup: &QueryId,
+        peers: &[PeerId],
+    ) -> Option<(u64, Key)> {
+        let id = self.lookups.remove(lookup)?;
+        let verification = self.verifications.get_mut(&id)?;
+        verification.peers =
+            peers.iter().map(|peer| (peer.clone(), None)).collect();
+        if verification.peers.is_empty() {
+            self.report(id);
+            return None;
+        }
+        Some((id, verification.key.clone()))
+    }
+
+    // Peer `i` of verification `id` is being asked with `request`.
+    pub fn add_request(&mut self, id: u64, i: usize, request: RequestId) {
+        if let Some(verification) = self.verifications.get_mut(&id) {
+            verification.left += 1;
+            self.requests.insert(request, (id, i));
+        }
+    }
+
+    // The answer to `request`, if it was one of ours. Returns whether it
+    // was. Once every peer answered, the verification is reported.
+    pub fn answer(
+        &mut self,
+        request: &RequestId,
+        holding: Holding,
+    ) -> bool {
+        let (id, i) = match self.requests.remove(request) {
+            Some(request) => request,
+            None => return false,
+        };
+        if let Some(verification) = self.verifications.get_mut(&id) {
+            verification.peers[i].1 = Some(holding);
+            verification.left -= 1;
+            if verification.left == 0 {
+                self.report(id);
+            }
+        }
+        true
+    }
+
+    fn report(&mut self, id: u64) {
+        if let Some(verification) = self.verifications.remove(&id) {
+            verification.output.show_all(verification.report());
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use crate::{behaviour::MyBehaviorWith, output::Output};
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::{
+    kad::{
+        record::Key, GetRecordError, GetRecordOk, QueryId, QueryResult,
+        Quorum,
+    },
+    swarm::NetworkBehaviour as Behaviour,
+};
+use std::{
+    collections::HashMap,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+// How often a watched key is looked up, unless WATCH says otherwise.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+// A key that gets looked up over and over, to see whether its value
+// changes.
+#[derive(Debug)]
+struct Watch {
+    // The key as the user knows it (without the namespace)
+    name: String,
+    interval: Duration,
+    output: Output,
+    // The value of the last lookup that got an answer (`Some(None)` if
+    // the record wasn't there), or `None` before the first one
+    last: Option<Option<Vec<u8>>>,
+    timer: Delay,
+    // Whether a lookup is running
+    querying: bool,
+}
+
+// The watched keys, and the lookups that are running for them.
+#[derive(Debug, Default)]
+pub struct Watches {
+    watches: HashMap<Key, Watch>,
+    queries: HashMap<QueryId, Key>,
+}
+
+impl Watches {
+    // Start watching `key` (instead of whoever watched it before), with
+    // the first lookup right away.
+    pub fn watch(
+        &mut self,
+        key: Key,
+        name: String,
+        interval: Duration,
+        output: Output,
+    ) {
+        self.watches.insert(
+            key,
+            Watch {
+                name,
+                interval,
+                output,
+                last: None,
+                timer: Delay::new(Duration::from_secs(0)),
+                querying: false,
+            },
+        );
+    }
+
+    // Stop watching `key`. Returns whether it was watched.
+    pub fn unwatch(&mut self, key: &Key) -> bool {
+        self.watches.remove(key).is_some()
+    }
+
+    // The keys that are due for another lookup. The timers of the others
+    // wake the task once they are due. Watches whose output went away
+    // (the control client disconnected) are dropped.
+    pub fn due(&mut self, cx: &mut Context<'_>) -> Vec<Key> {
+        self.watches.retain(|_, watch| !watch.output.is_closed());
+        let mut due = Vec::new();
+        for (key, watch) in &mut self.watches {
+            if watch.querying {
+                continue;
+            }
+            if let Poll::Ready(()) = watch.timer.poll_unpin(cx) {
+                watch.timer = Delay::new(watch.interval);
+                // Get the new timer to wake the task
+                let _ = watch.timer.poll_unpin(cx);
+                watch.querying = true;
+                due.push(key.clone());
+            }
+        }
+        due
+    }
+
+    pub fn add_query(&mut self, key: Key, query: QueryId) {
+        self.queries.insert(query, key);
+    }
+
+    pub fn owns(&self, query: &QueryId) -> bool {
+        self.queries.contains_key(query)
+    }
+
+    // Take in the result of a lookup, and report the record if it
+    // changed. Lookups that didn't get an answer (they timed out, or
+    // none of the records could be decoded) don't count. A chunked value
+    // shows up as its index record, so only changes in its size are
+    // noticed.
+    pub fn finish(&mut self, query: QueryId, result: &QueryResult) {
+        let watch = match self
+            .queries
+            .remove(&query)
+            .and_then(|key| self.watches.get_mut(&key))
+        {
+            Some(watch) => watch,
+            // Nobody watches the key anymore
+            None => return,
+        };
+        watch.querying = false;
+
+        let value = match result {
+            QueryResult::GetRecord(Ok(GetRecordOk { records })) => {
+                match records.first() {
+                    Some(record) => Some(record.record.value.clone()),
+                    None => return,
+                }
+            }
+            QueryResult::GetRecord(Err(GetRecordError::NotFound {
+                ..
+            })) => None,
+            _ => return,
+        };
+
+        if watch.last.as_ref() == Some(&value) {
+            return;
+        }
+        let name = &watch.name;
+        match (&watch.last, &value) {
+            (None, Some(value)) => watch.output.info(format!(
+                "watch: record {:?} is {:?}",
+                name,
+                String::from_utf8_lossy(value)
+            )),
+            (None, None) => watch
+                .output
+                .info(format!("watch: record {:?} doesn't exist", name)),
+            (Some(None), Some(value)) => watch.output.info(format!(
+                "watch: record {:?} appeared: {:?}",
+                name,
+                String::from_utf8_lossy(value)
+            )),
+            (Some(Some(_)), Some(value)) => watch.output.info(format!(
+                "watch: record {:?} changed to {:?}",
+                name,
+                String::from_utf8_lossy(value)
+            )),
+            (Some(Some(_)), None) => watch
+                .output
+                .info(format!("watch: record {:?} disappeared", name)),
+            // Still not there
+            (Some(None), None) => {}
+        }
+        watch.last = Some(value);
+    }
+}
+
+impl<B: Behaviour> MyBehaviorWith<B> {
+    // Start the lookups of the watched keys that are due. Returns whether
+    // there were any.
+    pub fn look_up_watched(&mut self, cx: &mut Context<'_>) -> bool {
+        let due = self.watches.due(cx);
+        let started = !due.is_empty();
+        for key in due {
+            let id = self.kademlia.get_record(&key, Quorum::One);
+            self.watches.add_query(key, id);
+        }
+        started
+    }
+
+    // Take in the result of `id`, if it is the lookup of a watched key.
+    // Returns whether it was.
+    pub fn finish_watch_lookup(
+        &mut self,
+        id: QueryId,
+        result: &QueryResult,
+    ) -> bool {
+        if !self.watches.owns(&id) {
+            return false;
+        }
+        self.watches.finish(id, result);
+        true
+    }
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use common::Node;
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Make an HTTP request of the api at `addr`, and return the body of the
+// answer.
+fn request(addr: &str, method: &str, path: &str, body: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    match response.split_once("\r\n\r\n") {
+        Some((_, body)) => body.to_string(),
+        None => panic!("not an HTTP response: {:?}", response),
+    }
+}
+
+// Ask /healthz until the api is up, and says the node is listening (it
+// can't be ready: it has no peers to bootstrap from).
+fn wait_until_listening(addr: &str) {
+    let started = Instant::now();
+    loop {
+        if TcpStream::connect(addr).is_ok() {
+            let health = request(addr, "GET", "/healthz", "");
+            if health.contains("\"listening\":true") {
+                return;
+            }
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "the node never started listening"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// A PUT over the api makes the cached value of the key old: the GET after
+// it gets the new one.
+#[test]
+fn put_replaces_the_cached_value() {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let addr = format!("127.0.0.1:{}", port);
+    let node = Node::start(&["--api-addr", &addr, "--cache-size", "16"]);
+    wait_until_listening(&addr);
+
+    request(&addr, "PUT", "/records/foo", "old");
+    let got = request(&addr, "GET", "/records/foo", "");
+    assert!(got.contains("old"), "{}", got);
+    request(&addr, "PUT", "/records/foo", "new");
+    let got = request(&addr, "GET", "/records/foo", "");
+    assert!(got.contains("new"), "{}", got);
+    node.finish();
+}
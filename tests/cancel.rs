@@ -0,0 +1,65 @@
+mod common;
+
+use common::Node;
+use std::net::TcpListener;
+
+// A peer that takes connections, and never says a word, so that queries
+// that go to it hang until they time out.
+const SILENT_PEER: &str =
+    "12D3KooWAoo99PJDVbDNmwhhSYPVJLcYgEQBripk3rtQFrYb1Das";
+
+// The queries of a LIST, and of a GET that timed out, are over: CANCEL
+// has nothing to list anymore.
+#[test]
+fn finished_queries_are_not_listed() {
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = silent.local_addr().unwrap().port();
+
+    let mut node = Node::start(&[]);
+    node.run("LIST");
+    node.wait_for("list: found no index");
+    node.run(&format!(
+        "ADD_ADDRESS {} /ip4/127.0.0.1/tcp/{}",
+        SILENT_PEER, port
+    ));
+    node.run("GET foo timeout=300ms");
+    node.wait_for("GET \"foo\" timed out");
+    node.run("CANCEL");
+    node.wait_for("No queries running");
+    node.finish();
+}
+
+// A CAS and a VERIFY are queries like any other: CANCEL lists them, and
+// whoever ran them hears that they were cancelled.
+#[test]
+fn cas_and_verify_can_be_cancelled() {
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = silent.local_addr().unwrap().port();
+
+    let mut node = Node::start(&[]);
+    node.run(&format!(
+        "ADD_ADDRESS {} /ip4/127.0.0.1/tcp/{}",
+        SILENT_PEER, port
+    ));
+    node.run("CAS foo 0 bar");
+    node.run("VERIFY foo");
+    node.run("CANCEL");
+    node.wait_for("query 2: VERIFY foo");
+    node.run("CANCEL ALL");
+    node.wait_for("Cancelled 2 queries");
+    let output = node.finish();
+
+    assert!(output.contains("query 1: CAS foo 0 bar"), "{}", output);
+    assert!(output.contains("query 2: VERIFY foo"), "{}", output);
+    assert!(output.contains("Cancelled 2 queries"), "{}", output);
+    assert!(
+        output.contains("query 1 (CAS foo 0 bar) cancelled"),
+        "{}",
+        output
+    );
+    assert!(
+        output.contains("query 2 (VERIFY foo) cancelled"),
+        "{}",
+        output
+    );
+}
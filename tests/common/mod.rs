@@ -0,0 +1,99 @@
+// Runs a node of this build, with its commands on stdin, the way a user
+// would. (Each test file has a copy of this, and not all of them use
+// every part of it.)
+#![allow(dead_code)]
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// How long a node gets to print what a test waits for.
+const WAIT: Duration = Duration::from_secs(10);
+
+pub struct Node {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    // All the node printed so far, stdout and stderr together
+    output: Arc<Mutex<String>>,
+    readers: Vec<JoinHandle<()>>,
+}
+
+impl Node {
+    // A node listening on a port of its own, with `args` on top. It is
+    // listening once this returns.
+    pub fn start(args: &[&str]) -> Node {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_nettest"))
+            .args(["--listen", "/ip4/127.0.0.1/tcp/0"])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("the node starts");
+        let stdin = child.stdin.take();
+        let output = Arc::new(Mutex::new(String::new()));
+        let readers = vec![
+            read(child.stdout.take().unwrap(), output.clone()),
+            read(child.stderr.take().unwrap(), output.clone()),
+        ];
+        let node = Node {
+            child,
+            stdin,
+            output,
+            readers,
+        };
+        node.wait_for("Listening on");
+        node
+    }
+
+    // Type `line`.
+    pub fn run(&mut self, line: &str) {
+        let stdin = self.stdin.as_mut().expect("stdin is open");
+        writeln!(stdin, "{}", line).expect("the node reads stdin");
+    }
+
+    // Wait for the node to print `text`.
+    pub fn wait_for(&self, text: &str) {
+        let started = Instant::now();
+        while !self.output.lock().unwrap().contains(text) {
+            if started.elapsed() > WAIT {
+                panic!(
+                    "the node never printed {:?}: {}",
+                    text,
+                    self.output.lock().unwrap()
+                );
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Close stdin, which shuts the node down, and return all it printed.
+    pub fn finish(mut self) -> String {
+        drop(self.stdin.take());
+        let status = self.child.wait().unwrap();
+        for reader in self.readers.drain(..) {
+            reader.join().unwrap();
+        }
+        let output = self.output.lock().unwrap().clone();
+        assert!(status.success(), "the node failed: {}", output);
+        output
+    }
+}
+
+// Copy the lines of `from` to `output`, until there are no more.
+fn read(
+    from: impl Read + Send + 'static,
+    output: Arc<Mutex<String>>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for line in BufReader::new(from).lines() {
+            let mut output = output.lock().unwrap();
+            output.push_str(&line.unwrap_or_default());
+            output.push('\n');
+        }
+    })
+}
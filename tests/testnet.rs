@@ -0,0 +1,36 @@
+use async_std::task;
+use nettest::testnet;
+
+// What a value PUT on one node of a network looks like from the others.
+#[test]
+fn put_and_get() {
+    task::block_on(async {
+        let net = testnet::spawn(4).await.unwrap();
+        net.node(0).put("foo", "bar").await.unwrap();
+        for node in &net.nodes()[1..] {
+            assert_eq!(
+                node.get("foo").await.unwrap(),
+                Some(b"bar".to_vec())
+            );
+        }
+        assert_eq!(net.node(3).get("nothing").await.unwrap(), None);
+
+        // A killed node can't be asked anything, but the others still
+        // find what it stored
+        net.node(0).kill().await;
+        assert!(net.node(0).get("foo").await.is_err());
+        assert_eq!(
+            net.node(2).get("foo").await.unwrap(),
+            Some(b"bar".to_vec())
+        );
+    })
+}
+
+#[test]
+#[should_panic]
+fn no_such_node() {
+    task::block_on(async {
+        let net = testnet::spawn(2).await.unwrap();
+        net.node(2);
+    })
+}